@@ -439,6 +439,19 @@ impl ImageLayout {
         DEFAULT_PARTITION_PLAN
     }
 
+    // The images we build, and the snapshots registered from them, are only as large as the
+    // partitions they hold; the publish sizes below are for the volumes created from them.  For
+    // the "unified" plan there is no data image, so its size is returned as -1.
+    pub fn image_sizes_gib(&self) -> (i32, i32) {
+        let os_image_size_gib = self.os_image_size_gib.0;
+        let data_image_size_gib = self.data_image_size_gib.0;
+
+        match self.partition_plan {
+            PartitionPlan::Split => (os_image_size_gib.into(), data_image_size_gib.into()),
+            PartitionPlan::Unified => ((os_image_size_gib + data_image_size_gib).into(), -1),
+        }
+    }
+
     // At publish time we will need specific sizes for the OS image and the (optional) data image.
     // The sizes returned by this function depend on the image layout, and whether the publish
     // image hint is larger than the required minimum size.
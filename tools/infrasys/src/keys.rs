@@ -24,6 +24,8 @@ pub async fn create_keys(signing_key_config: &mut SigningKeyConfig) -> Result<()
                 .await?;
         }
         SigningKeyConfig::ssm { .. } => (),
+        SigningKeyConfig::secret { .. } => (),
+        SigningKeyConfig::pkcs11 { .. } => (),
     }
     Ok(())
 }
@@ -59,6 +61,8 @@ pub fn check_signing_key_config(signing_key_config: &SigningKeyConfig) -> Result
             };
         }
         SigningKeyConfig::ssm { .. } => (),
+        SigningKeyConfig::secret { .. } => (),
+        SigningKeyConfig::pkcs11 { .. } => (),
     }
     Ok(())
 }
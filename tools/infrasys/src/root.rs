@@ -98,6 +98,8 @@ pub fn add_keys(
             key_id,
         )?,
         SigningKeyConfig::ssm { .. } => (),
+        SigningKeyConfig::secret { .. } => (),
+        SigningKeyConfig::pkcs11 { .. } => (),
     }
     Ok(())
 }
@@ -201,6 +203,8 @@ pub fn sign_root(signing_key_config: &SigningKeyConfig, filepath: &str) -> Resul
             }
         }
         SigningKeyConfig::ssm { .. } => (),
+        SigningKeyConfig::secret { .. } => (),
+        SigningKeyConfig::pkcs11 { .. } => (),
     }
     Ok(())
 }
@@ -0,0 +1,25 @@
+//! The azure module owns the definition of our Azure configuration.
+use serde::{Deserialize, Serialize};
+
+/// Azure-specific infrastructure configuration
+///
+/// Fields are optional here so that a config without them can still be loaded; the subcommands
+/// that need a field fail if it's missing.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AzureConfig {
+    /// The subscription to use, rather than the Azure CLI's default
+    pub subscription: Option<String>,
+    /// The resource group holding the storage account and gallery
+    pub resource_group: Option<String>,
+    /// The storage account and container to which VHDs are uploaded
+    pub storage_account: Option<String>,
+    pub storage_container: Option<String>,
+    /// The Azure Compute Gallery (formerly Shared Image Gallery) to publish images to
+    pub gallery: Option<String>,
+    /// The publisher recorded on image definitions created in the gallery
+    pub publisher: Option<String>,
+    /// The regions to replicate image versions to; the first is the gallery's own region
+    #[serde(default)]
+    pub regions: Vec<String>,
+}
@@ -0,0 +1,23 @@
+//! The gcp module owns the definition of our Google Cloud configuration.
+use serde::{Deserialize, Serialize};
+
+/// GCP-specific infrastructure configuration
+///
+/// Fields are optional here so that a config without them can still be loaded; the subcommands
+/// that need a field fail if it's missing.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GcpConfig {
+    /// The project that owns the images, rather than the gcloud CLI's default
+    pub project: Option<String>,
+    /// The Cloud Storage bucket to which image tarballs are uploaded
+    pub bucket: Option<String>,
+    /// Where image data is stored, like "us" or "us-central1"; GCP picks the closest
+    /// multi-region to the bucket if unset
+    pub storage_location: Option<String>,
+    /// The image family new images are added to, so that users can ask for its latest image
+    pub family: Option<String>,
+    /// Projects that are allowed to use the images, in addition to the owning project
+    #[serde(default)]
+    pub share_with_projects: Vec<String>,
+}
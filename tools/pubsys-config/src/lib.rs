@@ -1,21 +1,26 @@
 //! The config module owns the definition and loading process for our configuration sources.
+pub mod azure;
+pub mod gcp;
 pub mod vmware;
 
+use crate::azure::AzureConfig;
+use crate::gcp::GcpConfig;
 use crate::vmware::VmwareConfig;
 use chrono::Duration;
 use log::info;
 use parse_datetime::parse_offset;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize};
-use snafu::{OptionExt, ResultExt};
-use std::collections::{HashMap, VecDeque};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::convert::TryFrom;
-use std::fs;
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::{env, fs};
 use url::Url;
 
 /// Configuration needed to load and create repos
-#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct InfraConfig {
     // Repo subcommand config
@@ -26,17 +31,45 @@ pub struct InfraConfig {
 
     // Config for VMware specific subcommands
     pub vmware: Option<VmwareConfig>,
+
+    // Config for Azure specific subcommands
+    pub azure: Option<AzureConfig>,
+
+    // Config for GCP specific subcommands
+    pub gcp: Option<GcpConfig>,
+
+    // Where to send notifications about subcommands starting and finishing
+    pub notify: Option<NotifyConfig>,
+
+    // Tests to start after AMIs or SSM parameters are published
+    pub tests: Option<TestsConfig>,
+
+    // Named environments, like `env.prod`, whose values replace the ones above when selected
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, toml::Value>,
 }
 
 impl InfraConfig {
-    /// Deserializes an InfraConfig from a given path
+    /// Deserializes an InfraConfig from a given path.  Files ending in `.yaml`, `.yml`, or `.json`
+    /// are read as YAML, which JSON is a subset of; anything else is read as TOML.
+    ///
+    /// The file can list other config files to build on, like `include = ["common-infra.toml"]`,
+    /// with paths relative to the including file.  Included files are merged in order, so later
+    /// ones take precedence over earlier ones, and the including file takes precedence over all of
+    /// them.  Tables are merged key by key; any other value, including a list, is replaced whole.
     pub fn from_path<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
         let infra_config_str = fs::read_to_string(path).context(error::FileSnafu { path })?;
-        toml::from_str(&infra_config_str).context(error::InvalidTomlSnafu { path })
+        let includes: Includes = parse_config_str(path, &infra_config_str)?;
+        if includes.include.is_empty() {
+            return parse_config_str(path, &infra_config_str);
+        }
+        read_with_includes(path, &mut Vec::new())?
+            .try_into()
+            .context(error::InvalidTomlSnafu { path })
     }
 
     /// Deserializes an InfraConfig from a Infra.lock file at a given path
@@ -49,6 +82,11 @@ impl InfraConfig {
         serde_yaml::from_str(&infra_config_str).context(error::InvalidLockSnafu { path })
     }
 
+    /// Serializes the config in the format of an Infra.lock file
+    pub fn to_lock_string(&self) -> Result<String> {
+        serde_yaml::to_string(self).context(error::SerializeLockSnafu)
+    }
+
     /// Deserializes an InfraConfig from a given path, if it exists, otherwise builds a default
     /// config
     pub fn from_path_or_default<P>(path: P) -> Result<Self>
@@ -62,19 +100,107 @@ impl InfraConfig {
         }
     }
 
-    /// Deserializes an InfraConfig from Infra.lock, if it exists, otherwise uses Infra.toml
+    /// Deserializes an InfraConfig from Infra.lock, if it exists, otherwise uses Infra.toml, or
+    /// the YAML or JSON file at the given path
     /// If the default flag is true, will create a default config if Infra.toml doesn't exist
+    /// Values can be overridden with environment variables; see `with_env_overrides`.
     pub fn from_path_or_lock(path: &Path, default: bool) -> Result<Self> {
+        Self::from_path_or_lock_in_env(path, default, None)
+    }
+
+    /// Like `from_path_or_lock`, but if an environment name is given, the values of that named
+    /// environment are applied to the config before any environment variable overrides.
+    pub fn from_path_or_lock_in_env(
+        path: &Path,
+        default: bool,
+        env_name: Option<&str>,
+    ) -> Result<Self> {
         let lock_path = Self::compute_lock_path(path)?;
-        if lock_path.exists() {
+        let infra_config = if lock_path.exists() {
             info!("Found infra config at path: {}", lock_path.display());
-            Self::from_lock_path(lock_path)
+            Self::from_lock_path(lock_path)?
         } else if default {
-            Self::from_path_or_default(path)
+            Self::from_path_or_default(path)?
         } else {
             info!("Found infra config at path: {}", path.display());
-            Self::from_path(path)
+            Self::from_path(path)?
+        };
+        let infra_config = match env_name {
+            Some(env_name) => infra_config.in_env(env_name)?,
+            None => infra_config,
+        };
+        let infra_config = infra_config.with_env_overrides(env::vars())?;
+        if let Some(aws) = &infra_config.aws {
+            aws.check_partitions()?;
+        }
+        Ok(infra_config)
+    }
+
+    /// Applies the values of the named environment from the `env` section, so that one file can
+    /// describe several accounts, like `[env.dev]` and `[env.prod]`.  Tables are merged, so an
+    /// environment only needs to give the values that differ: `[env.prod.aws]` with only a `role`
+    /// changes `aws.role` and keeps the rest of `aws`.
+    pub fn in_env(self, env_name: &str) -> Result<Self> {
+        let overlay = match self.env.get(env_name) {
+            Some(overlay) => overlay.clone(),
+            None => {
+                let mut available: Vec<&str> = self.env.keys().map(String::as_str).collect();
+                available.sort_unstable();
+                return error::UnknownEnvSnafu {
+                    env_name,
+                    available: available.join(", "),
+                }
+                .fail();
+            }
+        };
+        info!("Using infra config environment '{}'", env_name);
+
+        let mut config = toml::Value::try_from(&self).context(error::SerializeSnafu)?;
+        merge_value(&mut config, overlay);
+        config
+            .try_into()
+            .context(error::InvalidEnvSnafu { env_name })
+    }
+
+    /// Overrides values of the config with the given environment variables, so that CI can vary
+    /// them without templating Infra.toml.  The name of each variable is `PUBSYS_` followed by the
+    /// path to the value, in upper case, with its parts separated by double underscores, since
+    /// keys have single underscores: `PUBSYS_AWS__ROLE` sets `aws.role`, and
+    /// `PUBSYS_REPO__DEFAULT__METADATA_BASE_URL` sets the `metadata_base_url` of `repo.default`.
+    /// Each value is parsed as TOML if it can be, and is otherwise a string; values for lists, like
+    /// `PUBSYS_AWS__REGIONS`, can also be given as comma-separated strings.  Variables without a
+    /// separator, like `PUBSYS_FOO`, are ignored, since other tools can share the prefix, while a
+    /// path that isn't in the config, like `PUBSYS_AWZ__ROLE`, fails to load.
+    pub fn with_env_overrides<I>(self, vars: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                name.strip_prefix(ENV_OVERRIDE_PREFIX)
+                    .filter(|path| path.contains(ENV_OVERRIDE_SEPARATOR))
+                    .map(|path| (path.to_lowercase(), value))
+            })
+            .collect();
+        if overrides.is_empty() {
+            return Ok(self);
         }
+        // Apply them in a consistent order, in case one overrides part of another.
+        overrides.sort();
+
+        let mut config = toml::Value::try_from(&self).context(error::SerializeSnafu)?;
+        for (path, value) in &overrides {
+            info!(
+                "Overriding infra config value from {}{}",
+                ENV_OVERRIDE_PREFIX,
+                path.to_uppercase()
+            );
+            set_override(&mut config, path, value).context(error::EnvOverrideSnafu {
+                name: format!("{}{}", ENV_OVERRIDE_PREFIX, path.to_uppercase()),
+            })?;
+        }
+        config.try_into().context(error::InvalidEnvOverrideSnafu)
     }
 
     /// Looks for a file named `Infra.lock` in the same directory as the file named by
@@ -104,6 +230,125 @@ impl InfraConfig {
     }
 }
 
+/// The `include` list at the top of a config file, naming other config files it builds on
+#[derive(Deserialize)]
+struct Includes {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+}
+
+/// Parses the text of the config file at the given path, as YAML if the file ends in `.yaml`,
+/// `.yml`, or `.json`, and otherwise as TOML.
+fn parse_config_str<T>(path: &Path, config_str: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml") | Some("yml") | Some("json") => {
+            serde_yaml::from_str(config_str).context(error::InvalidYamlSnafu { path })
+        }
+        _ => toml::from_str(config_str).context(error::InvalidTomlSnafu { path }),
+    }
+}
+
+/// Reads the config file at the given path, with the files it includes merged underneath it.
+/// `including` holds the files that (indirectly) include this one, so we can catch cycles.
+fn read_with_includes(path: &Path, including: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    let canonical_path = fs::canonicalize(path).context(error::FileSnafu { path })?;
+    ensure!(
+        !including.contains(&canonical_path),
+        error::IncludeCycleSnafu { path }
+    );
+    let config_str = fs::read_to_string(path).context(error::FileSnafu { path })?;
+    let mut config: toml::Value = parse_config_str(path, &config_str)?;
+    let includes: Vec<PathBuf> = match config.as_table_mut().and_then(|t| t.remove("include")) {
+        Some(includes) => includes
+            .try_into()
+            .context(error::InvalidIncludeSnafu { path })?,
+        None => Vec::new(),
+    };
+
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    including.push(canonical_path);
+    for include in includes {
+        let include_path = parent.join(include);
+        info!("Including infra config from '{}'", include_path.display());
+        merge_value(&mut merged, read_with_includes(&include_path, including)?);
+    }
+    including.pop();
+
+    merge_value(&mut merged, config);
+    Ok(merged)
+}
+
+/// Merges the overlay into the base value.  Tables are merged key by key, and any other value in
+/// the overlay replaces the one in the base.
+fn merge_value(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Environment variables starting with this prefix override values from Infra.toml
+const ENV_OVERRIDE_PREFIX: &str = "PUBSYS_";
+
+/// Separates the parts of the path in an environment override's name
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+
+/// Sets the value at the given path in the config, creating tables along it as needed.  Returns
+/// None if the path has an empty part, or runs through a value that isn't a table.
+fn set_override(config: &mut toml::Value, path: &str, value: &str) -> Option<()> {
+    let (tables, key) = path.rsplit_once(ENV_OVERRIDE_SEPARATOR)?;
+    let mut table = config.as_table_mut()?;
+    for part in tables.split(ENV_OVERRIDE_SEPARATOR) {
+        if part.is_empty() {
+            return None;
+        }
+        table = table
+            .entry(part)
+            .or_insert(toml::Value::Table(Default::default()))
+            .as_table_mut()?;
+    }
+    if key.is_empty() {
+        return None;
+    }
+    let parsed = parse_override(table.get(key), value);
+    table.insert(key.to_string(), parsed);
+    Some(())
+}
+
+/// Parses an override as TOML, if it can be, otherwise as a string.  If it replaces a list, a
+/// comma-separated string is split into a list of strings.
+fn parse_override(existing: Option<&toml::Value>, value: &str) -> toml::Value {
+    if let Ok(toml::Value::Table(mut table)) = toml::from_str(&format!("value = {}", value)) {
+        if let Some(parsed) = table.remove("value") {
+            return parsed;
+        }
+    }
+    match existing {
+        Some(toml::Value::Array(_)) => toml::Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect(),
+        ),
+        _ => toml::Value::String(value.to_string()),
+    }
+}
+
 /// S3-specific TUF infrastructure configuration
 #[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct S3Config {
@@ -115,6 +360,95 @@ pub struct S3Config {
     pub bucket_name: Option<String>,
 }
 
+/// Where to send notifications when subcommands start, succeed, or fail.  Every destination gets
+/// every notification that the filters allow.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// The subcommands to send notifications about, like "ami" or "promote-ssm"; all of them if
+    /// empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subcommands: Vec<String>,
+    /// The events to send notifications about; all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<NotifyEvent>,
+    /// SNS topics to publish notifications to, with the credentials from the `aws` section
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sns: Vec<SnsNotifyConfig>,
+    /// Slack incoming webhooks to post notifications to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slack: Vec<SlackNotifyConfig>,
+    /// HTTPS endpoints to POST notifications to, as JSON
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhook: Vec<WebhookNotifyConfig>,
+}
+
+/// The points in a subcommand's run that notifications are sent at
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyEvent {
+    Start,
+    Success,
+    Failure,
+}
+
+/// An SNS topic that notifications are published to
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SnsNotifyConfig {
+    pub topic_arn: String,
+}
+
+/// A Slack incoming webhook that notifications are posted to, as a line of text
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlackNotifyConfig {
+    pub webhook_url: Url,
+}
+
+/// An HTTPS endpoint that notifications are POSTed to, as JSON
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookNotifyConfig {
+    pub url: Url,
+    /// Headers to add to each request, like an Authorization header the endpoint requires
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+/// Tests to start once a build's AMIs or SSM parameters are published, by running a command, like
+/// one that submits a testsys run, or by POSTing to an HTTPS endpoint, or both.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TestsConfig {
+    /// The subcommands to start tests after, "ami" and/or "ssm"; only "ssm" if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// A program and its arguments, like `["testsys", "run", "file", "tests.yaml"]`, which gets
+    /// the build and its AMI IDs in BOTTLEROCKET_TEST_* environment variables
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// An HTTPS endpoint to POST the build and its AMI IDs to, as JSON
+    pub webhook: Option<TestsWebhookConfig>,
+    /// Wait for the tests to finish, failing the subcommand if they fail, and tag the AMIs with
+    /// the result so `promote-ssm --require-tests` can check it.  The command's exit status is the
+    /// result; the webhook has to respond with a `status_url` to poll.
+    #[serde(default)]
+    pub wait: bool,
+    /// How long to wait for the tests to finish, in minutes; 120 if not given
+    pub timeout_minutes: Option<u64>,
+}
+
+/// An HTTPS endpoint that starts tests
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TestsWebhookConfig {
+    pub url: Url,
+    /// Headers to add to each request, like an Authorization header the endpoint requires
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
 /// AWS-specific infrastructure configuration
 #[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
 #[serde(deny_unknown_fields)]
@@ -127,6 +461,347 @@ pub struct AwsConfig {
     pub region: HashMap<String, AwsRegionConfig>,
     pub ssm_prefix: Option<String>,
     pub s3: Option<HashMap<String, S3Config>>,
+    pub publishing: Option<AwsPublishingConfig>,
+    /// Send requests to this URL rather than to the AWS endpoints, like when testing against
+    /// LocalStack
+    pub endpoint_url: Option<Url>,
+    /// Per-service endpoint URLs, keyed by service name like "ec2" or "s3", which take precedence
+    /// over `endpoint_url`
+    #[serde(default)]
+    pub endpoint_urls: HashMap<String, Url>,
+    /// Send requests to the services' FIPS endpoints, for environments with FIPS compliance
+    /// requirements; URLs in `endpoint_url` and `endpoint_urls` still take precedence
+    #[serde(default)]
+    pub use_fips_endpoints: bool,
+    /// Send requests to the services' dual-stack endpoints, which accept IPv6 as well as IPv4, for
+    /// hosts without IPv4 connectivity; URLs in `endpoint_url` and `endpoint_urls` still take
+    /// precedence
+    #[serde(default)]
+    pub use_dualstack_endpoints: bool,
+    /// Send every AWS request through this HTTP proxy rather than the one from the HTTPS_PROXY
+    /// environment variable; hosts in NO_PROXY are still reached directly
+    pub proxy: Option<Url>,
+    /// Per-subcommand region lists, keyed by subcommand name with underscores, like
+    /// "validate_ami", which take precedence over `regions` for that subcommand
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub command_regions: HashMap<String, VecDeque<String>>,
+    /// Per-variant configuration, keyed by variant name, like "aws-k8s-1.24"
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variant: HashMap<String, AwsVariantConfig>,
+    /// Per-architecture configuration, keyed by architecture, like "aarch64"
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub arch: HashMap<String, AwsArchConfig>,
+    /// Tags applied to every resource pubsys creates, like AMIs, snapshots, SSM parameters, and
+    /// repo files in S3
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+    /// Templates for the names and descriptions of the AMIs pubsys registers
+    pub ami: Option<AwsAmiConfig>,
+    /// EC2 Image Builder resources that `pubsys image-builder` points at newly published AMIs
+    pub image_builder: Option<AwsImageBuilderConfig>,
+    /// KMS keys that encrypt the AMIs pubsys copies to other regions, which `pubsys kms` creates
+    /// or validates
+    pub ami_kms: Option<AwsAmiKmsConfig>,
+    /// Per-partition credentials, keyed by partition name, like "aws-cn", which are used in place
+    /// of `profile` and `role` for regions in that partition
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub partition: HashMap<String, AwsPartitionConfig>,
+    /// How every AWS client retries failed requests, in place of the SDK's defaults
+    pub retry: Option<AwsRetryConfig>,
+    /// How long every AWS client waits for requests before giving up
+    pub timeout: Option<AwsTimeoutConfig>,
+    /// Options for assuming `role`, like an external ID or MFA
+    pub assume_role: Option<AwsAssumeRoleConfig>,
+    /// The most requests per second pubsys sends to each service in each region, keyed by service
+    /// name like "ec2" or "ssm", shared by every request in an operation
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub rate_limits: HashMap<String, NonZeroU32>,
+    /// The most AWS requests pubsys has in flight at once, across all services and regions; by
+    /// default, each subcommand chooses its own concurrency
+    pub max_concurrency: Option<NonZeroUsize>,
+    /// A name added to the user agent of every AWS request, like one including a pipeline run ID,
+    /// so CloudTrail events can be traced to the run that made them
+    pub app_name: Option<String>,
+    /// Page sizes for the paginated requests pubsys sends, in place of the services' defaults
+    pub page_size: Option<AwsPageSizeConfig>,
+}
+
+impl AwsConfig {
+    /// Returns a copy of this config that uses the credentials of the publishing account, if one
+    /// is configured, rather than those of the account where AMIs are built.
+    pub fn publishing_config(&self) -> Option<AwsConfig> {
+        self.publishing.as_ref().map(|publishing| AwsConfig {
+            role: publishing.role.clone(),
+            profile: publishing.profile.clone(),
+            region: publishing.region.clone(),
+            assume_role: publishing.assume_role.clone(),
+            publishing: None,
+            // Credentials for other partitions belong to the build account.
+            partition: HashMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Returns the URL to which requests for the given service should be sent, if it's not the
+    /// service's usual AWS endpoint.
+    pub fn service_endpoint_url(&self, service: &str) -> Option<&Url> {
+        self.endpoint_urls
+            .get(service)
+            .or(self.endpoint_url.as_ref())
+    }
+
+    /// Returns the credentials to use for the given region's partition, if they differ from
+    /// `profile` and `role`.
+    pub fn partition_config(&self, region: &str) -> Option<&AwsPartitionConfig> {
+        self.partition.get(partition_for_region(region))
+    }
+
+    /// Checks that regions from partitions other than the first region's have their own
+    /// credentials in `partition`, since credentials from one partition can't be used in another.
+    /// Without this, a mixed list fails partway through a run with signing errors.
+    pub fn check_partitions(&self) -> Result<()> {
+        let mut command_regions: Vec<_> = self.command_regions.iter().collect();
+        command_regions.sort_by_key(|(command, _)| *command);
+        let mut all_regions = self
+            .regions
+            .iter()
+            .chain(command_regions.into_iter().flat_map(|(_, regions)| regions));
+        let base_partition = match all_regions.next() {
+            Some(region) => partition_for_region(region),
+            None => return Ok(()),
+        };
+
+        let mut missing: BTreeSet<&str> = BTreeSet::new();
+        for region in all_regions {
+            let partition = partition_for_region(region);
+            if partition != base_partition && !self.partition.contains_key(partition) {
+                missing.insert(region);
+            }
+        }
+        ensure!(
+            missing.is_empty(),
+            error::MixedPartitionsSnafu {
+                regions: missing.into_iter().collect::<Vec<_>>().join(", "),
+                base_partition,
+            }
+        );
+        Ok(())
+    }
+
+    /// Returns the regions the given subcommand should use: its list from `command_regions` if it
+    /// has a non-empty one, otherwise `regions`.
+    pub fn regions_for(&self, command: &str) -> &VecDeque<String> {
+        self.command_regions
+            .get(command)
+            .filter(|regions| !regions.is_empty())
+            .unwrap_or(&self.regions)
+    }
+
+    /// Returns the SSM prefix for parameters of the given variant and architecture.  The most
+    /// specific one given is used: the variant's prefix for the architecture, the variant's
+    /// prefix, the architecture's prefix, and then `ssm_prefix`.
+    pub fn ssm_prefix_for(&self, variant: &str, arch: &str) -> &str {
+        let variant_config = self.variant.get(variant);
+        variant_config
+            .and_then(|v| v.arch.get(arch))
+            .and_then(|a| a.ssm_prefix.as_deref())
+            .or_else(|| variant_config.and_then(|v| v.ssm_prefix.as_deref()))
+            .or_else(|| self.arch.get(arch).and_then(|a| a.ssm_prefix.as_deref()))
+            .or(self.ssm_prefix.as_deref())
+            .unwrap_or("")
+    }
+
+    /// Returns every SSM prefix in the config, for when parameters of any variant and
+    /// architecture are wanted.
+    pub fn all_ssm_prefixes(&self) -> BTreeSet<&str> {
+        let variant_prefixes = self.variant.values().flat_map(|v| {
+            v.arch
+                .values()
+                .filter_map(|a| a.ssm_prefix.as_deref())
+                .chain(v.ssm_prefix.as_deref())
+        });
+        let arch_prefixes = self.arch.values().filter_map(|a| a.ssm_prefix.as_deref());
+        variant_prefixes
+            .chain(arch_prefixes)
+            .chain(Some(self.ssm_prefix.as_deref().unwrap_or("")))
+            .collect()
+    }
+}
+
+/// AWS configuration specific to one variant
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsVariantConfig {
+    /// Prefix for the variant's SSM parameters, in place of `aws.ssm_prefix`
+    pub ssm_prefix: Option<String>,
+    /// Configuration for the variant on one architecture, keyed by architecture
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub arch: HashMap<String, AwsArchConfig>,
+}
+
+/// AWS configuration specific to one architecture
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsArchConfig {
+    /// Prefix for the architecture's SSM parameters, in place of `aws.ssm_prefix`
+    pub ssm_prefix: Option<String>,
+}
+
+/// Templates for AMI names and descriptions, rendered with the build context, like
+/// "{variant}-{arch}-v{image_version}"
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsAmiConfig {
+    /// Template for the AMI name, used unless a name is given on the command line
+    pub name: Option<String>,
+    /// Template for the AMI description, used unless a description is given on the command line
+    pub description: Option<String>,
+}
+
+/// EC2 Image Builder resources for customers who build their own images on top of ours.  ARNs
+/// are regional, so "{region}" in them is replaced with each region's name.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsImageBuilderConfig {
+    /// ARNs of the components the image recipe runs on top of the Bottlerocket AMI
+    #[serde(default)]
+    pub components: Vec<String>,
+    /// ARN of the infrastructure configuration the image pipeline builds with
+    pub infrastructure_configuration: Option<String>,
+    /// Cron expression for the image pipeline's schedule, like "cron(0 0 * * ? *)"; the pipeline
+    /// only runs when started if unset
+    pub schedule: Option<String>,
+}
+
+/// Customer managed KMS keys for encrypting AMIs.  `pubsys kms` creates or validates a key with
+/// `alias` in each region, and records the keys' ARNs in `key_arns` in Infra.lock.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsAmiKmsConfig {
+    /// The alias of the key in each region, like "alias/bottlerocket-ami"
+    pub alias: Option<String>,
+    /// IDs of the accounts, besides the key's own, that may use the key through EBS, so that
+    /// encrypted AMIs can be shared with them
+    #[serde(default)]
+    pub share_accounts: Vec<String>,
+    /// ARNs of the keys, keyed by region name; the `ami` subcommand encrypts the copies it makes
+    /// in these regions with them
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub key_arns: HashMap<String, String>,
+}
+
+/// Credentials for the account that should own published AMIs, if it's not the account in which
+/// they're built
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsPublishingConfig {
+    pub role: Option<String>,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub region: HashMap<String, AwsRegionConfig>,
+    pub assume_role: Option<AwsAssumeRoleConfig>,
+}
+
+/// Options for assuming a role, which the role's trust policy may require
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsAssumeRoleConfig {
+    /// The external ID that the account owning `role` requires in order to assume it
+    pub external_id: Option<String>,
+    /// The name of each role session, which shows in CloudTrail; defaults to "pubsys"
+    pub session_name: Option<String>,
+    /// Tags given to each role session, which the roles' policies can check
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub session_tags: HashMap<String, String>,
+    /// The serial number or ARN of the MFA device that `role` requires; pubsys prompts for a code
+    /// from it when it first needs credentials, and again if they expire
+    pub mfa_serial: Option<String>,
+}
+
+/// Retry settings shared by every AWS client; unset values keep the SDK's defaults
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsRetryConfig {
+    /// The most times a request is attempted, including the first attempt
+    pub max_attempts: Option<NonZeroU32>,
+    pub mode: Option<AwsRetryMode>,
+    /// How long to wait before the first retry, in milliseconds; later retries back off from it
+    pub initial_backoff_ms: Option<u64>,
+}
+
+/// Timeouts shared by every AWS client; without them, a request to a slow region can wait forever
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsTimeoutConfig {
+    /// How long to wait to connect to the service, in milliseconds
+    pub connect_timeout_ms: Option<NonZeroU64>,
+    /// How long to wait for each read of a response, in milliseconds
+    pub read_timeout_ms: Option<NonZeroU64>,
+    /// How long to wait for a whole operation, including retries, in milliseconds
+    pub operation_timeout_ms: Option<NonZeroU64>,
+}
+
+/// Page sizes for paginated requests; smaller pages use less memory per request, and larger pages
+/// need fewer requests
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsPageSizeConfig {
+    /// The most images in each page of an EC2 DescribeImages response, from 5 to 1000
+    pub describe_images: Option<NonZeroU32>,
+    /// The most parameters in each page of an SSM GetParametersByPath response, from 1 to 10
+    pub get_parameters_by_path: Option<NonZeroU32>,
+    /// The most objects in each page of an S3 ListObjectsV2 response, from 1 to 1000
+    pub list_objects: Option<NonZeroU32>,
+}
+
+/// How AWS clients decide when to retry
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AwsRetryMode {
+    /// Retry throttling and transient errors with exponential backoff
+    Standard,
+    /// Like standard, but also slow down requests when the service is throttling them
+    Adaptive,
+}
+
+impl std::str::FromStr for AwsRetryMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "standard" => Ok(AwsRetryMode::Standard),
+            "adaptive" => Ok(AwsRetryMode::Adaptive),
+            _ => error::RetryModeSnafu { mode: s }.fail(),
+        }
+    }
+}
+
+/// Credentials for the regions of one AWS partition
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AwsPartitionConfig {
+    /// The named profile with credentials for an account in the partition
+    pub profile: String,
+    /// If specified, we assume this role, from the partition's account, before making API calls
+    pub role: Option<String>,
+    /// Options for assuming `role`; the session name and tags default to those in
+    /// `aws.assume_role`
+    pub assume_role: Option<AwsAssumeRoleConfig>,
+}
+
+/// Returns the name of the AWS partition containing the given region, like "aws-cn".
+pub fn partition_for_region(region: &str) -> &'static str {
+    if region.starts_with("cn-") {
+        "aws-cn"
+    } else if region.starts_with("us-gov-") {
+        "aws-us-gov"
+    } else if region.starts_with("us-isob-") {
+        "aws-iso-b"
+    } else if region.starts_with("us-iso-") {
+        "aws-iso"
+    } else {
+        "aws"
+    }
 }
 
 /// AWS region-specific configuration
@@ -134,6 +809,27 @@ pub struct AwsConfig {
 #[serde(deny_unknown_fields)]
 pub struct AwsRegionConfig {
     pub role: Option<String>,
+    /// The external ID that the account owning `role` requires in order to assume it
+    pub external_id: Option<String>,
+    /// The serial number or ARN of the MFA device that `role` requires
+    pub mfa_serial: Option<String>,
+    /// The name of the role session, in place of the one in `aws.assume_role`
+    pub session_name: Option<String>,
+    /// Tags given to the role session, in place of the ones in `aws.assume_role`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub session_tags: HashMap<String, String>,
+}
+
+impl AwsRegionConfig {
+    /// Returns the options for assuming the region's role.
+    pub fn assume_role(&self) -> AwsAssumeRoleConfig {
+        AwsAssumeRoleConfig {
+            external_id: self.external_id.clone(),
+            session_name: self.session_name.clone(),
+            session_tags: self.session_tags.clone(),
+            mfa_serial: self.mfa_serial.clone(),
+        }
+    }
 }
 
 /// Location of signing keys
@@ -154,6 +850,22 @@ pub enum SigningKeyConfig {
     ssm {
         parameter: String,
     },
+    /// A private key in PEM format, stored in AWS Secrets Manager or as an SSM SecureString
+    /// parameter, which is fetched with the credentials from the `aws` section
+    secret {
+        /// ARN of the secret or parameter; the key is fetched from the region in the ARN
+        arn: String,
+    },
+    pkcs11 {
+        /// Path to the PKCS#11 module for the token, like opensc-pkcs11.so or libykcs11.so
+        module_path: PathBuf,
+        token_label: String,
+        key_label: String,
+        /// Path to the key's RSA public key, in PEM format
+        public_key_path: PathBuf,
+        /// Environment variable holding the user PIN; if not given, we're prompted for it
+        pin_env: Option<String>,
+    },
 }
 
 /// AWS region-specific configuration
@@ -169,6 +881,18 @@ pub struct KMSKeyConfig {
     pub key_stack_arns: HashMap<String, String>,
 }
 
+impl KMSKeyConfig {
+    /// Returns the ID of the only key in `available_keys`, if there is exactly one.  This lets a
+    /// key created by infrasys be used for signing without also listing it as the `key_id`.
+    pub fn single_available_key(&self) -> Option<&String> {
+        if self.available_keys.len() == 1 {
+            self.available_keys.keys().next()
+        } else {
+            None
+        }
+    }
+}
+
 impl TryFrom<SigningKeyConfig> for Url {
     type Error = ();
     fn try_from(key: SigningKeyConfig) -> std::result::Result<Self, Self::Error> {
@@ -176,8 +900,15 @@ impl TryFrom<SigningKeyConfig> for Url {
             SigningKeyConfig::file { path } => Url::from_file_path(path),
             // We don't support passing profiles to tough in the name of the key/parameter, so for
             // KMS and SSM we prepend a slash if there isn't one present.
-            SigningKeyConfig::kms { key_id, .. } => {
-                let mut key_id = key_id.unwrap_or_default();
+            SigningKeyConfig::kms { key_id, config } => {
+                let mut key_id = key_id
+                    .or_else(|| {
+                        config
+                            .as_ref()
+                            .and_then(KMSKeyConfig::single_available_key)
+                            .cloned()
+                    })
+                    .unwrap_or_default();
                 key_id = if key_id.starts_with('/') {
                     key_id.to_string()
                 } else {
@@ -193,6 +924,9 @@ impl TryFrom<SigningKeyConfig> for Url {
                 };
                 Url::parse(&format!("aws-ssm://{}", parameter)).map_err(|_| ())
             }
+            // tough has no URL scheme for PKCS#11 keys or keys in Secrets Manager, and its SSM
+            // scheme doesn't use the credentials from Infra.toml.
+            SigningKeyConfig::secret { .. } | SigningKeyConfig::pkcs11 { .. } => Err(()),
         }
     }
 }
@@ -204,16 +938,61 @@ pub struct RepoConfig {
     pub root_role_url: Option<Url>,
     pub root_role_sha512: Option<String>,
     pub signing_keys: Option<SigningKeyConfig>,
+    /// Further keys that sign the repo alongside `signing_keys`, for roles that need more than
+    /// one signature
+    pub additional_signing_keys: Option<Vec<SigningKeyConfig>>,
+    /// How many signatures each role needs before the repo is written
+    pub signature_thresholds: Option<SignatureThresholds>,
     pub root_keys: Option<SigningKeyConfig>,
     pub metadata_base_url: Option<Url>,
     pub targets_url: Option<Url>,
     pub file_hosting_config_name: Option<String>,
+    /// CloudFront distributions serving the repo, whose cached metadata is invalidated after
+    /// `upload-repo`
+    pub cloudfront_distribution_ids: Option<Vec<String>>,
     pub root_key_threshold: Option<NonZeroUsize>,
+    /// validate-repo fails if the repo serves a root.json older than this version
+    pub min_root_version: Option<NonZeroU64>,
     pub pub_key_threshold: Option<NonZeroUsize>,
+    /// Delegated targets roles, by role name
+    pub delegations: Option<HashMap<String, DelegationConfig>>,
+}
+
+impl RepoConfig {
+    /// Returns `signing_keys` followed by any `additional_signing_keys`.
+    pub fn all_signing_keys(&self) -> impl Iterator<Item = &SigningKeyConfig> {
+        self.signing_keys
+            .iter()
+            .chain(self.additional_signing_keys.iter().flatten())
+    }
+}
+
+/// The number of signatures required for each non-root role; roles that aren't listed need as
+/// many as root.json requires
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SignatureThresholds {
+    pub targets: Option<NonZeroU64>,
+    pub snapshot: Option<NonZeroU64>,
+    pub timestamp: Option<NonZeroU64>,
+}
+
+/// A delegated targets role, which lets another publisher sign their own targets into the repo
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DelegationConfig {
+    /// Path patterns of the targets the role is trusted to sign, like "my-kit/*"
+    pub paths: Vec<String>,
+    /// Paths to the role's public keys, in TUF key JSON format
+    pub keys: Vec<PathBuf>,
+    /// How many of the keys must sign the role's metadata; defaults to 1
+    pub threshold: Option<NonZeroU64>,
+    /// Where the publisher hosts the signed role metadata, as <name>.json
+    pub metadata_url: Url,
 }
 
 /// How long it takes for each metadata type to expire
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RepoExpirationPolicy {
     #[serde(deserialize_with = "deserialize_offset")]
@@ -253,9 +1032,34 @@ mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub enum Error {
+        #[snafu(display("Can't apply {}: it doesn't name a config value", name))]
+        EnvOverride { name: String },
+
         #[snafu(display("Failed to read '{}': {}", path.display(), source))]
         File { path: PathBuf, source: io::Error },
 
+        #[snafu(display("Config file '{}' includes itself", path.display()))]
+        IncludeCycle { path: PathBuf },
+
+        #[snafu(display("Invalid config for environment '{}': {}", env_name, source))]
+        InvalidEnv {
+            env_name: String,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Invalid config after applying environment overrides: {}", source))]
+        InvalidEnvOverride { source: toml::de::Error },
+
+        #[snafu(display(
+            "Invalid include list in '{}', expected a list of paths: {}",
+            path.display(),
+            source
+        ))]
+        InvalidInclude {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
         #[snafu(display("Invalid config file at '{}': {}", path.display(), source))]
         InvalidToml {
             path: PathBuf,
@@ -268,12 +1072,93 @@ mod error {
             source: serde_yaml::Error,
         },
 
+        #[snafu(display("Invalid config file at '{}': {}", path.display(), source))]
+        InvalidYaml {
+            path: PathBuf,
+            source: serde_yaml::Error,
+        },
+
         #[snafu(display("Missing config: {}", what))]
         MissingConfig { what: String },
 
+        #[snafu(display(
+            "Regions {} aren't in the {} partition of the first region, and need credentials of \
+             their own in aws.partition",
+            regions,
+            base_partition
+        ))]
+        MixedPartitions {
+            regions: String,
+            base_partition: String,
+        },
+
         #[snafu(display("Failed to get parent of path: {}", path.display()))]
         Parent { path: PathBuf },
+
+        #[snafu(display("Unknown retry mode '{}'; use 'standard' or 'adaptive'", mode))]
+        RetryMode { mode: String },
+
+        #[snafu(display("Failed to serialize config: {}", source))]
+        Serialize { source: toml::ser::Error },
+
+        #[snafu(display("Failed to serialize lock file: {}", source))]
+        SerializeLock { source: serde_yaml::Error },
+
+        #[snafu(display(
+            "No environment named '{}' in the env section; available: [{}]",
+            env_name,
+            available
+        ))]
+        UnknownEnv { env_name: String, available: String },
     }
 }
 pub use error::Error;
 pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::InfraConfig;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_override_sets_value() {
+        let config = InfraConfig::default()
+            .with_env_overrides(vars(&[
+                ("PUBSYS_AWS__ROLE", "arn:aws:iam::123:role/r"),
+                ("PUBSYS_AWS__SSM_PREFIX", "/my/prefix"),
+            ]))
+            .unwrap();
+        let aws = config.aws.unwrap();
+        assert_eq!(aws.role.as_deref(), Some("arn:aws:iam::123:role/r"));
+        assert_eq!(aws.ssm_prefix.as_deref(), Some("/my/prefix"));
+    }
+
+    #[test]
+    fn env_override_unknown_path() {
+        for name in [
+            "PUBSYS_AWZ__ROLE",
+            "PUBSYS_AWS__ROLE__",
+            "PUBSYS_AWS____ROLE",
+        ] {
+            let result = InfraConfig::default().with_env_overrides(vars(&[(name, "x")]));
+            assert!(result.is_err(), "{} was applied", name);
+        }
+    }
+
+    #[test]
+    fn unrelated_env_vars_ignored() {
+        let config = InfraConfig::default()
+            .with_env_overrides(vars(&[
+                ("PUBSYS_FOO", "bar"),
+                ("PUBSYS_TEST_REQUEST", "{}"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config, InfraConfig::default());
+    }
+}
@@ -51,6 +51,9 @@ pub struct DatacenterBuilder {
     pub network: Option<String>,
     pub folder: Option<String>,
     pub resource_pool: Option<String>,
+    /// If set, the OVA is uploaded to this content library as an OVF template, rather than
+    /// imported into the folder as a VM
+    pub content_library: Option<String>,
 }
 
 /// Helper macro for retrieving a field from another struct if the field in `self` is `None`
@@ -74,6 +77,9 @@ impl DatacenterBuilder {
             network: get_env(GOVC_NETWORK),
             folder: get_env(GOVC_FOLDER),
             resource_pool: get_env(GOVC_RESOURCE_POOL),
+            // govc has no environment variable for a content library; it's always given as an
+            // argument, so it only comes from config.
+            content_library: None,
         }
     }
 
@@ -87,6 +93,7 @@ impl DatacenterBuilder {
             network: field_or!(self, network, other),
             folder: field_or!(self, folder, other),
             resource_pool: field_or!(self, resource_pool, other),
+            content_library: field_or!(self, content_library, other),
         }
     }
 
@@ -103,6 +110,7 @@ impl DatacenterBuilder {
             network: get_or_err(self.network, "vSphere network")?,
             folder: get_or_err(self.folder, "vSphere folder")?,
             resource_pool: get_or_err(self.resource_pool, "vSphere resource pool")?,
+            content_library: self.content_library,
         })
     }
 }
@@ -116,6 +124,7 @@ pub struct Datacenter {
     pub network: String,
     pub folder: String,
     pub resource_pool: String,
+    pub content_library: Option<String>,
 }
 
 /// VMware infrastructure credentials for all datacenters
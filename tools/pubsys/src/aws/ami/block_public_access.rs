@@ -0,0 +1,151 @@
+//! The block_public_access module owns checking and disabling EC2 Image Block Public Access, a
+//! regional account setting that prevents AMIs from being made public.
+//!
+//! The version of the EC2 SDK we use predates these API calls, so we make signed requests to the
+//! EC2 Query API directly, using the credentials from the regional client config.
+
+use crate::aws::query::{self, Endpoint};
+use crate::aws::rate_limit::RateLimits;
+use aws_config::SdkConfig;
+use aws_sdk_ec2::Region;
+use log::{debug, info};
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{OptionExt, ResultExt};
+use std::time::Duration;
+
+const EC2_API_VERSION: &str = "2016-11-15";
+
+/// The state reported by EC2 when Image Block Public Access is disabled.
+const UNBLOCKED_STATE: &str = "unblocked";
+
+// Disabling the setting can take up to ten minutes to take effect.
+const MAX_DISABLE_ATTEMPTS: u16 = 120;
+const SECONDS_BETWEEN_ATTEMPTS: u64 = 5;
+
+/// Returns whether Image Block Public Access is enabled in the given region.
+pub(crate) async fn is_blocked(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
+    region: &Region,
+) -> Result<bool> {
+    let state = get_state(client_config, pubsys_aws_config, rate_limits, region).await?;
+    debug!("Image Block Public Access state in {}: {}", region, state);
+    Ok(state != UNBLOCKED_STATE)
+}
+
+/// Disables Image Block Public Access in the given region, and waits until EC2 reports that the
+/// setting is no longer blocking public sharing.
+pub(crate) async fn disable(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
+    region: &Region,
+) -> Result<()> {
+    ec2_query(
+        client_config,
+        pubsys_aws_config,
+        rate_limits,
+        region,
+        "DisableImageBlockPublicAccess",
+    )
+    .await?;
+
+    for attempt in 1..=MAX_DISABLE_ATTEMPTS {
+        if !is_blocked(client_config, pubsys_aws_config, rate_limits, region).await? {
+            info!("Disabled Image Block Public Access in {}", region);
+            return Ok(());
+        }
+        if attempt % 12 == 1 {
+            info!(
+                "Waiting for Image Block Public Access to be disabled in {}... (attempt {} of {})",
+                region, attempt, MAX_DISABLE_ATTEMPTS
+            );
+        }
+        tokio::time::sleep(Duration::from_secs(SECONDS_BETWEEN_ATTEMPTS)).await;
+    }
+
+    error::DisableTimeoutSnafu {
+        region: region.as_ref(),
+        max_attempts: MAX_DISABLE_ATTEMPTS,
+    }
+    .fail()
+}
+
+/// Returns the Image Block Public Access state in the given region, for example
+/// "block-new-sharing" or "unblocked".
+async fn get_state(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
+    region: &Region,
+) -> Result<String> {
+    let request_type = "GetImageBlockPublicAccessState";
+    let response = ec2_query(
+        client_config,
+        pubsys_aws_config,
+        rate_limits,
+        region,
+        request_type,
+    )
+    .await?;
+    query::find_element(&response, "imageBlockPublicAccessState").context(
+        error::MissingInResponseSnafu {
+            request_type,
+            missing: "imageBlockPublicAccessState",
+        },
+    )
+}
+
+/// Sends a parameterless EC2 Query API request for the given action in the given region.
+async fn ec2_query(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
+    region: &Region,
+    action: &str,
+) -> Result<String> {
+    query::send(
+        client_config,
+        rate_limits,
+        &Endpoint::ec2(region).with_pubsys_config(pubsys_aws_config),
+        action,
+        EC2_API_VERSION,
+        &[],
+    )
+    .await
+    .context(error::QuerySnafu {
+        action,
+        region: region.as_ref(),
+    })
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Image Block Public Access in {} was not disabled within {} attempts",
+            region,
+            max_attempts
+        ))]
+        DisableTimeout { region: String, max_attempts: u16 },
+
+        #[snafu(display("Response to {} was missing {}", request_type, missing))]
+        MissingInResponse {
+            request_type: String,
+            missing: String,
+        },
+
+        #[snafu(display("Failed to call {} in {}: {}", action, region, source))]
+        Query {
+            action: String,
+            region: String,
+            source: crate::aws::query::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
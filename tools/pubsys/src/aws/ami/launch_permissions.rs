@@ -1,3 +1,5 @@
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
 use aws_sdk_ec2::{model::LaunchPermission, Client as Ec2Client};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
@@ -5,14 +7,20 @@ use snafu::ResultExt;
 /// Returns the launch permissions for the given AMI
 pub(crate) async fn get_launch_permissions(
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
     region: &str,
     ami_id: &str,
 ) -> Result<Vec<LaunchPermissionDef>> {
-    let ec2_response = ec2_client
-        .describe_image_attribute()
-        .image_id(ami_id)
-        .attribute(aws_sdk_ec2::model::ImageAttributeName::LaunchPermission)
-        .send()
+    let ec2_response = rate_limits
+        .rate_limited(
+            EC2,
+            region,
+            ec2_client
+                .describe_image_attribute()
+                .image_id(ami_id)
+                .attribute(aws_sdk_ec2::model::ImageAttributeName::LaunchPermission)
+                .send(),
+        )
         .await
         .context(error::DescribeImageAttributeSnafu {
             ami_id,
@@ -31,7 +39,7 @@ pub(crate) async fn get_launch_permissions(
 
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq, Hash)]
 #[serde(rename_all = "lowercase")]
-pub(crate) enum LaunchPermissionDef {
+pub enum LaunchPermissionDef {
     /// The name of the group
     Group(String),
 
@@ -80,7 +88,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error describing AMI {} in {}: {}", ami_id, region, source))]
         DescribeImageAttribute {
             ami_id: String,
@@ -93,6 +101,15 @@ mod error {
         InvalidLaunchPermission { launch_permission: LaunchPermission },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeImageAttribute { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
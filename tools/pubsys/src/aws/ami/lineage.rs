@@ -0,0 +1,143 @@
+//! The lineage module records where each regional AMI came from, so that provenance audits can
+//! tie any AMI back to the image and snapshots that were originally registered.
+
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
+use aws_sdk_ec2::model::Tag;
+use aws_sdk_ec2::Client as Ec2Client;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+const SOURCE_REGION_TAG: &str = "pubsys:source-region";
+const SOURCE_IMAGE_ID_TAG: &str = "pubsys:source-image-id";
+const SOURCE_SNAPSHOT_IDS_TAG: &str = "pubsys:source-snapshot-ids";
+
+/// The originally registered AMI that a regional AMI was derived from.  For the AMI registered in
+/// the base region, this refers to the AMI itself.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq, Hash)]
+pub struct Lineage {
+    pub source_region: String,
+    pub source_image_id: String,
+    pub source_snapshot_ids: Vec<String>,
+}
+
+impl Lineage {
+    /// Returns the tags that record this lineage on an AMI.
+    fn tags(&self) -> Vec<Tag> {
+        vec![
+            Tag::builder()
+                .key(SOURCE_REGION_TAG)
+                .value(&self.source_region)
+                .build(),
+            Tag::builder()
+                .key(SOURCE_IMAGE_ID_TAG)
+                .value(&self.source_image_id)
+                .build(),
+            Tag::builder()
+                .key(SOURCE_SNAPSHOT_IDS_TAG)
+                .value(self.source_snapshot_ids.join(","))
+                .build(),
+        ]
+    }
+}
+
+/// Tags the given AMI with its lineage.
+pub(crate) async fn tag_image(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &str,
+    image_id: &str,
+    lineage: &Lineage,
+) -> Result<()> {
+    rate_limits
+        .rate_limited(
+            EC2,
+            region,
+            ec2_client
+                .create_tags()
+                .resources(image_id)
+                .set_tags(Some(lineage.tags()))
+                .send(),
+        )
+        .await
+        .context(error::CreateTagsSnafu { image_id, region })?;
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::CreateTagsError;
+    use aws_sdk_ec2::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Failed to tag AMI {} in {} with its lineage: {}",
+            image_id,
+            region,
+            source
+        ))]
+        CreateTags {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(SdkError<CreateTagsError>, Box::new)))]
+            source: Box<SdkError<CreateTagsError>>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::CreateTags { .. } => Some(Kind::AwsApi),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lineage;
+
+    #[test]
+    fn lineage_tags() {
+        let lineage = Lineage {
+            source_region: "us-west-2".to_string(),
+            source_image_id: "ami-123".to_string(),
+            source_snapshot_ids: vec!["snap-1".to_string(), "snap-2".to_string()],
+        };
+        let tags: Vec<(String, String)> = lineage
+            .tags()
+            .into_iter()
+            .map(|tag| {
+                (
+                    tag.key().unwrap().to_string(),
+                    tag.value().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                ("pubsys:source-region".to_string(), "us-west-2".to_string()),
+                ("pubsys:source-image-id".to_string(), "ami-123".to_string()),
+                (
+                    "pubsys:source-snapshot-ids".to_string(),
+                    "snap-1,snap-2".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn old_amis_json() {
+        // AMI data written before lineage was recorded should still be readable.
+        let image: crate::aws::ami::Image = serde_json::from_str(
+            r#"{"id": "ami-123", "name": "bottlerocket", "public": true, "launch_permissions": []}"#,
+        )
+        .unwrap();
+        assert_eq!(image.lineage, None);
+    }
+}
@@ -1,16 +1,35 @@
 //! The ami module owns the 'ami' subcommand and controls the process of registering and copying
 //! EC2 AMIs.
 
-pub(crate) mod launch_permissions;
+pub(crate) mod block_public_access;
+pub mod launch_permissions;
+pub mod lineage;
+mod name;
 pub(crate) mod public;
-mod register;
+pub(crate) mod register;
 mod snapshot;
 pub(crate) mod wait;
 
 use crate::aws::ami::launch_permissions::get_launch_permissions;
+use crate::aws::ami::lineage::{tag_image, Lineage};
+use crate::aws::ami::name::ami_names;
 use crate::aws::ami::public::ami_is_public;
-use crate::aws::publish_ami::{get_snapshots, modify_image, modify_snapshots, ModifyOptions};
-use crate::aws::{client::build_client_config, parse_arch, region_from_string};
+use crate::aws::check_permissions::{self, Operation};
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::publish_ami::{
+    get_snapshots, modify_image, modify_rate_limiter, modify_snapshots, plan_image_permissions,
+    ModifyOptions,
+};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::ssm::BuildContext;
+use crate::aws::tags::tag_ec2_resources;
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::notify::BuildInfo;
+use crate::plan::{Grantees, Mutation};
+use crate::progress::progress_bar;
+use crate::run_state::RunState;
+use crate::test_trigger::{self, TestRequest};
 use crate::Args;
 use aws_sdk_ebs::Client as EbsClient;
 use aws_sdk_ec2::error::CopyImageError;
@@ -29,14 +48,20 @@ use register::{get_ami_id, register_image, RegisteredIds};
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::{clap, StructOpt};
 use wait::wait_for_ami;
 
+/// The kind of work tracked and journaled for AMI copies, which are named by target region
+const AMI_COPIES: &str = "AMI copies";
+
+/// Stands in for the ID of the AMI a dry run would register, in the changes it shows after that
+const UNREGISTERED_IMAGE_ID: &str = "(new AMI)";
+
 /// Builds Bottlerocket AMIs using latest build artifacts
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct AmiArgs {
+pub struct AmiArgs {
     /// Path to the image containing the os volume
     #[structopt(short = "o", long, parse(from_os_str))]
     os_image: PathBuf,
@@ -53,14 +78,18 @@ pub(crate) struct AmiArgs {
     #[structopt(short = "a", long, parse(try_from_str = parse_arch))]
     arch: ArchitectureValues,
 
-    /// The desired AMI name
+    /// The desired AMI name, in place of the aws.ami.name template in Infra.toml
     #[structopt(short = "n", long)]
-    name: String,
+    name: Option<String>,
 
-    /// The desired AMI description
+    /// The desired AMI description, in place of the aws.ami.description template in Infra.toml
     #[structopt(long)]
     description: Option<String>,
 
+    /// The AMI name to use if neither --name nor an aws.ami.name template is given
+    #[structopt(long)]
+    default_name: Option<String>,
+
     /// Don't display progress bars
     #[structopt(long)]
     no_progress: bool,
@@ -72,37 +101,153 @@ pub(crate) struct AmiArgs {
     /// If specified, save created regional AMI IDs in JSON at this path.
     #[structopt(long)]
     ami_output: Option<PathBuf>,
+
+    /// Check that the configured credentials have the permissions needed before starting
+    #[structopt(long)]
+    preflight: bool,
+
+    #[structopt(flatten)]
+    build_info: BuildInfo,
 }
 
-/// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, ami_args: &AmiArgs) -> Result<()> {
-    match _run(args, ami_args).await {
-        Ok(amis) => {
-            // Write the AMI IDs to file if requested
-            if let Some(ref path) = ami_args.ami_output {
-                write_amis(path, &amis).context(error::WriteAmisSnafu { path })?;
-            }
-            Ok(())
+/// The images `build` registers, and where it copies them
+#[derive(Debug, Clone)]
+pub struct AmiOptions {
+    /// Path to the image containing the os volume
+    pub os_image: PathBuf,
+    /// Path to the image containing the data volume, if there is one
+    pub data_image: Option<PathBuf>,
+    /// Path to the variant manifest
+    pub variant_manifest: PathBuf,
+    /// The architecture of the images
+    pub arch: ArchitectureValues,
+    /// The AMI name, in place of the aws.ami.name template in Infra.toml
+    pub name: Option<String>,
+    /// The AMI description, in place of the aws.ami.description template in Infra.toml
+    pub description: Option<String>,
+    /// The AMI name to use if neither `name` nor an aws.ami.name template is given
+    pub default_name: Option<String>,
+    /// The variant and version of the build, which name templates can use
+    pub variant: Option<String>,
+    pub version: Option<String>,
+    /// Regions to register the AMI in, the first of which is the one it's copied from; the
+    /// regions in Infra.toml are used if this is empty
+    pub regions: Vec<String>,
+    /// Check that the configured credentials have the permissions needed before starting
+    pub preflight: bool,
+    /// Don't display progress bars
+    pub no_progress: bool,
+}
+
+impl From<&AmiArgs> for AmiOptions {
+    fn from(args: &AmiArgs) -> Self {
+        Self {
+            os_image: args.os_image.clone(),
+            data_image: args.data_image.clone(),
+            variant_manifest: args.variant_manifest.clone(),
+            arch: args.arch.clone(),
+            name: args.name.clone(),
+            description: args.description.clone(),
+            default_name: args.default_name.clone(),
+            variant: args.build_info.variant.clone(),
+            version: args.build_info.version.clone(),
+            regions: args.regions.clone(),
+            preflight: args.preflight,
+            no_progress: args.no_progress,
         }
+    }
+}
+
+/// Common entrypoint from main(); returns the AMI in each region, keyed by region name
+pub async fn run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>> {
+    run_with_options(
+        args,
+        &AmiOptions::from(ami_args),
+        ami_args.ami_output.as_deref(),
+    )
+    .await
+}
+
+/// Builds the AMIs, then saves them to `ami_output` if given, starts any configured tests, and
+/// records them for the run's notification, as the 'ami' subcommand does.
+pub(crate) async fn run_with_options(
+    args: &Args,
+    options: &AmiOptions,
+    ami_output: Option<&Path>,
+) -> Result<HashMap<String, Image>> {
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let mut result = match args.infra_config(true).context(error::ConfigSnafu) {
+        Ok(infra_config) => build_with_state(&args.state, &infra_config, options).await,
         Err(e) => Err(e),
+    };
+    // Nothing changed if we only planned, and the copies don't have IDs to write yet.
+    if args.state.plan.planning() {
+        return result;
+    }
+
+    // Write the AMI IDs to file if requested
+    if let (Ok(amis), Some(path)) = (&result, ami_output) {
+        if let Err(e) = write_amis(path, amis) {
+            result = Err(e).context(error::WriteAmisSnafu { path });
+        }
+    }
+
+    // Start any tests configured to run on the new AMIs.
+    if let Ok(amis) = &result {
+        let request = TestRequest {
+            subcommand: "ami",
+            variant: options.variant.as_deref(),
+            version: options.version.as_deref(),
+            arch: options.arch.as_ref(),
+            amis: amis
+                .iter()
+                .map(|(region, image)| (region.clone(), image.id.clone()))
+                .collect(),
+        };
+        if let Err(e) = test_trigger::trigger(args, &request).await {
+            result = Err(e).context(error::TestsSnafu);
+        }
     }
+
+    // The notification sent when the run finishes lists the AMIs.
+    let build_info = BuildInfo {
+        variant: options.variant.clone(),
+        version: options.version.clone(),
+    };
+    args.state
+        .published
+        .record(&build_info, result.as_ref().ok());
+    result
+}
+
+/// Registers the AMI in the first region, if it isn't already, and copies it to the others.  Returns
+/// the AMI in each region, keyed by region name.
+pub async fn build(
+    infra_config: &InfraConfig,
+    options: &AmiOptions,
+) -> Result<HashMap<String, Image>> {
+    build_with_state(&RunState::default(), infra_config, options).await
 }
 
-async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>> {
+/// Builds the AMIs like `build`, planning, journaling, and tracing in the given run.
+pub(crate) async fn build_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &AmiOptions,
+) -> Result<HashMap<String, Image>> {
     let mut amis = HashMap::new();
 
-    // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
-    let aws = infra_config.aws.unwrap_or_default();
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let mut regions = if !ami_args.regions.is_empty() {
-        ami_args.regions.clone()
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let mut regions = if !options.regions.is_empty() {
+        options.regions.clone()
     } else {
-        aws.regions.clone().into()
+        aws.regions_for("ami").clone().into()
     }
     .into_iter()
     .map(|name| region_from_string(&name))
@@ -115,30 +260,66 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
         }
     );
 
+    // Templates are rendered with the build context, so they need the variant and version.
+    let build_context = match (&options.variant, &options.version) {
+        (Some(variant), Some(version)) => Some(BuildContext {
+            variant,
+            arch: options.arch.as_ref(),
+            image_version: version,
+        }),
+        _ => None,
+    };
+    let names = ami_names(
+        options.name.as_deref(),
+        options.description.as_deref(),
+        options.default_name.as_deref(),
+        build_context.as_ref(),
+        &aws,
+    )
+    .context(error::AmiNameSnafu)?;
+    let name = &names.name;
+
+    if options.preflight {
+        check_permissions::preflight(Operation::Ami, &aws, &regions)
+            .await
+            .context(error::PreflightSnafu)?;
+    }
+
     // We register in this base region first, then copy from there to any other regions.
     let base_region = regions.remove(0);
 
     // Build EBS client for snapshot management, and EC2 client for registration
     let client_config = build_client_config(&base_region, &base_region, &aws).await;
 
-    let base_ebs_client = EbsClient::new(&client_config);
+    let base_ebs_client = EbsClient::from_pubsys_config(&client_config, &aws);
 
-    let base_ec2_client = Ec2Client::new(&client_config);
+    let base_ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
 
     // Check if the AMI already exists, in which case we can use the existing ID, otherwise we
     // register a new one.
     let maybe_id = get_ami_id(
-        &ami_args.name,
-        &ami_args.arch,
+        name,
+        &options.arch,
         &base_region,
         &base_ec2_client,
+        &rate_limits,
     )
     .await
     .context(error::GetAmiIdSnafu {
-        name: &ami_args.name,
-        arch: ami_args.arch.as_ref(),
+        name,
+        arch: options.arch.as_ref(),
         region: base_region.as_ref(),
     })?;
+    // Registering uploads snapshots, which can't wait for a plan to be applied.  A dry run records
+    // the registration anyway, and goes on as if it had been made, so it can show the rest.
+    let unregistered = maybe_id.is_none() && state.plan.dry_run();
+    ensure!(
+        maybe_id.is_some() || unregistered || !state.plan.planning(),
+        error::PlanUnregisteredSnafu {
+            name,
+            region: base_region.as_ref(),
+        }
+    );
 
     // If the AMI does not exist yet, `public` should be false and `launch_permissions` empty
     let mut public = false;
@@ -147,9 +328,9 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     let (ids_of_image, already_registered) = if let Some(found_id) = maybe_id {
         warn!(
             "Found '{}' already registered in {}: {}",
-            ami_args.name, base_region, found_id
+            name, base_region, found_id
         );
-        let snapshot_ids = get_snapshots(&found_id, &base_region, &base_ec2_client)
+        let snapshot_ids = get_snapshots(&found_id, &base_region, &base_ec2_client, &rate_limits)
             .await
             .context(error::GetSnapshotsSnafu {
                 image_id: &found_id,
@@ -160,44 +341,98 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
             snapshot_ids,
         };
 
-        public = ami_is_public(&base_ec2_client, base_region.as_ref(), &found_id)
-            .await
-            .context(error::IsAmiPublicSnafu {
-                image_id: found_id.clone(),
-                region: base_region.to_string(),
-            })?;
+        public = ami_is_public(
+            &base_ec2_client,
+            &rate_limits,
+            base_region.as_ref(),
+            &found_id,
+        )
+        .await
+        .context(error::IsAmiPublicSnafu {
+            image_id: found_id.clone(),
+            region: base_region.to_string(),
+        })?;
 
-        launch_permissions =
-            get_launch_permissions(&base_ec2_client, base_region.as_ref(), &found_id)
-                .await
-                .context(error::DescribeImageAttributeSnafu {
-                    image_id: found_id,
-                    region: base_region.to_string(),
-                })?;
+        launch_permissions = get_launch_permissions(
+            &base_ec2_client,
+            &rate_limits,
+            base_region.as_ref(),
+            &found_id,
+        )
+        .await
+        .context(error::DescribeImageAttributeSnafu {
+            image_id: found_id,
+            region: base_region.to_string(),
+        })?;
 
         (found_ids, true)
+    } else if unregistered {
+        state.plan.add(Mutation::RegisterImage {
+            region: base_region.to_string(),
+            name: name.to_string(),
+            arch: options.arch.as_ref().to_string(),
+        });
+        let new_ids = RegisteredIds {
+            image_id: UNREGISTERED_IMAGE_ID.to_string(),
+            snapshot_ids: Vec::new(),
+        };
+        (new_ids, false)
     } else {
-        let new_ids = register_image(ami_args, &base_region, base_ebs_client, &base_ec2_client)
+        let new_ids = state
+            .tracer
+            .traced(
+                "register_image",
+                Some(base_region.as_ref()),
+                register_image(
+                    options,
+                    &names,
+                    &base_region,
+                    base_ebs_client,
+                    &base_ec2_client,
+                    &rate_limits,
+                ),
+            )
             .await
             .context(error::RegisterImageSnafu {
-                name: &ami_args.name,
-                arch: ami_args.arch.as_ref(),
+                name,
+                arch: options.arch.as_ref(),
                 region: base_region.as_ref(),
             })?;
         info!(
             "Registered AMI '{}' in {}: {}",
-            ami_args.name, base_region, new_ids.image_id
+            name, base_region, new_ids.image_id
         );
+        let resource_ids: Vec<String> = std::iter::once(new_ids.image_id.clone())
+            .chain(new_ids.snapshot_ids.iter().cloned())
+            .collect();
+        tag_ec2_resources(
+            &base_ec2_client,
+            &rate_limits,
+            base_region.as_ref(),
+            &resource_ids,
+            &aws,
+        )
+        .await
+        .context(error::TagSnafu)?;
         (new_ids, false)
     };
 
+    // Every AMI we copy is derived from the one in the base region, so record it as the source of
+    // all of them, including itself.
+    let lineage = Lineage {
+        source_region: base_region.as_ref().to_string(),
+        source_image_id: ids_of_image.image_id.clone(),
+        source_snapshot_ids: ids_of_image.snapshot_ids.clone(),
+    };
+
     amis.insert(
         base_region.as_ref().to_string(),
         Image::new(
             &ids_of_image.image_id,
-            &ami_args.name,
+            name,
             Some(public),
             Some(launch_permissions),
+            &lineage,
         ),
     );
 
@@ -207,20 +442,29 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     }
 
     // Wait for AMI to be available so it can be copied
-    let successes_required = if already_registered { 1 } else { 3 };
-    wait_for_ami(
-        &ids_of_image.image_id,
-        &base_region,
-        &base_region,
-        "available",
-        successes_required,
-        &aws,
-    )
-    .await
-    .context(error::WaitAmiSnafu {
-        id: &ids_of_image.image_id,
-        region: base_region.as_ref(),
-    })?;
+    if !unregistered {
+        let successes_required = if already_registered { 1 } else { 3 };
+        state
+            .tracer
+            .traced(
+                "wait_for_ami",
+                Some(base_region.as_ref()),
+                wait_for_ami(
+                    &ids_of_image.image_id,
+                    &base_region,
+                    &base_region,
+                    "available",
+                    successes_required,
+                    &aws,
+                    &rate_limits,
+                ),
+            )
+            .await
+            .context(error::WaitAmiSnafu {
+                id: &ids_of_image.image_id,
+                region: base_region.as_ref(),
+            })?;
+    }
 
     // For every other region, initiate copy-image calls.
 
@@ -232,7 +476,7 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     // Get the account ID used in the base region; we don't need to grant to it so we can remove it
     // from the list.
     let client_config = build_client_config(&base_region, &base_region, &aws).await;
-    let base_sts_client = StsClient::new(&client_config);
+    let base_sts_client = StsClient::from_pubsys_config(&client_config, &aws);
 
     let response = base_sts_client.get_caller_identity().send().await.context(
         error::GetCallerIdentitySnafu {
@@ -257,12 +501,16 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
             organizational_unit_arns: Vec::new(),
         };
 
+        let rate_limiter = modify_rate_limiter();
         modify_snapshots(
+            &state.plan,
             &modify_options,
             &OperationType::Add,
             &ids_of_image.snapshot_ids,
             &base_ec2_client,
             &base_region,
+            &rate_limiter,
+            &rate_limits,
         )
         .await
         .context(error::GrantAccessSnafu {
@@ -270,17 +518,46 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
             region: base_region.as_ref(),
         })?;
 
-        modify_image(
-            &modify_options,
-            &OperationType::Add,
-            &ids_of_image.image_id,
-            &base_ec2_client,
-        )
-        .await
-        .context(error::GrantImageAccessSnafu {
-            thing: "image",
-            region: base_region.as_ref(),
-        })?;
+        if unregistered {
+            // There are no launch permissions to read on an AMI that isn't registered yet.
+            state.plan.add(Mutation::ModifyImagePermissions {
+                region: base_region.to_string(),
+                image_id: ids_of_image.image_id.clone(),
+                operation: OperationType::Add.as_str().to_string(),
+                grantees: Grantees::from(&modify_options),
+                current: Vec::new(),
+            });
+        } else if state.plan.planning() {
+            plan_image_permissions(
+                &state.plan,
+                &modify_options,
+                &OperationType::Add,
+                &ids_of_image.image_id,
+                &base_ec2_client,
+                &base_region,
+                &rate_limits,
+            )
+            .await
+            .context(error::GrantAccessSnafu {
+                thing: "image",
+                region: base_region.as_ref(),
+            })?;
+        } else {
+            modify_image(
+                &modify_options,
+                &OperationType::Add,
+                &ids_of_image.image_id,
+                &base_ec2_client,
+                &base_region,
+                &rate_limiter,
+                &rate_limits,
+            )
+            .await
+            .context(error::GrantImageAccessSnafu {
+                thing: "image",
+                region: base_region.as_ref(),
+            })?;
+        }
     }
 
     // Next, make EC2 clients so we can fetch and copy AMIs.  We make a map storing our regional
@@ -288,16 +565,25 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     let mut ec2_clients = HashMap::with_capacity(regions.len());
     for region in regions.iter() {
         let client_config = build_client_config(region, &base_region, &aws).await;
-        let ec2_client = Ec2Client::new(&client_config);
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
         ec2_clients.insert(region.clone(), ec2_client);
     }
 
-    // First, we check if the AMI already exists in each region.
+    // First, we check if the AMI already exists in each region.  A journal from an earlier run
+    // already tells us about the copies it made.
     info!("Checking whether AMIs already exist in target regions");
     let mut get_requests = Vec::with_capacity(regions.len());
     for region in regions.iter() {
+        if let Some(Some(id)) = state.journal.detail(AMI_COPIES, region.as_ref()) {
+            info!("Journal records '{}' as copied to {}: {}", name, region, id);
+            amis.insert(
+                region.as_ref().to_string(),
+                Image::new(&id, name, Some(false), Some(vec![]), &lineage),
+            );
+            continue;
+        }
         let ec2_client = &ec2_clients[region];
-        let get_request = get_ami_id(&ami_args.name, &ami_args.arch, region, ec2_client);
+        let get_request = get_ami_id(name, &options.arch, region, ec2_client, &rate_limits);
         let info_future = ready(region.clone());
         get_requests.push(join(info_future, get_request));
     }
@@ -309,16 +595,13 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     let mut copy_requests = Vec::with_capacity(regions.len());
     for (region, get_response) in get_responses {
         let get_response = get_response.context(error::GetAmiIdSnafu {
-            name: &ami_args.name,
-            arch: ami_args.arch.as_ref(),
+            name,
+            arch: options.arch.as_ref(),
             region: region.as_ref(),
         })?;
         if let Some(id) = get_response {
-            info!(
-                "Found '{}' already registered in {}: {}",
-                ami_args.name, region, id
-            );
-            let public = ami_is_public(&ec2_clients[&region], region.as_ref(), &id)
+            info!("Found '{}' already registered in {}: {}", name, region, id);
+            let public = ami_is_public(&ec2_clients[&region], &rate_limits, region.as_ref(), &id)
                 .await
                 .context(error::IsAmiPublicSnafu {
                     image_id: id.clone(),
@@ -326,7 +609,7 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
                 })?;
 
             let launch_permissions =
-                get_launch_permissions(&ec2_clients[&region], region.as_ref(), &id)
+                get_launch_permissions(&ec2_clients[&region], &rate_limits, region.as_ref(), &id)
                     .await
                     .context(error::DescribeImageAttributeSnafu {
                         region: region.as_ref(),
@@ -335,27 +618,55 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
 
             amis.insert(
                 region.as_ref().to_string(),
-                Image::new(&id, &ami_args.name, Some(public), Some(launch_permissions)),
+                Image::new(&id, name, Some(public), Some(launch_permissions), &lineage),
             );
             continue;
         }
 
         let ec2_client = &ec2_clients[&region];
         let base_region = base_region.to_owned();
+        // If `pubsys kms` recorded a key for the region, the copy is encrypted with it.
+        let kms_key_arn = aws
+            .ami_kms
+            .as_ref()
+            .and_then(|ami_kms| ami_kms.key_arns.get(region.as_ref()))
+            .cloned();
+        if state.plan.planning() {
+            state.plan.add(Mutation::CopyImage {
+                region: region.to_string(),
+                source_region: base_region.to_string(),
+                source_image_id: ids_of_image.image_id.clone(),
+                name: name.clone(),
+                description: names.description.clone(),
+                arch: options.arch.as_ref().to_string(),
+                kms_key_id: kms_key_arn,
+                lineage: lineage.clone(),
+            });
+            continue;
+        }
         let copy_future = ec2_client
             .copy_image()
-            .set_description(ami_args.description.clone())
-            .set_name(Some(ami_args.name.clone()))
+            .set_description(Some(names.description.clone()))
+            .set_name(Some(name.clone()))
             .set_source_image_id(Some(ids_of_image.image_id.clone()))
             .set_source_region(Some(base_region.as_ref().to_string()))
+            .set_encrypted(kms_key_arn.as_ref().map(|_| true))
+            .set_kms_key_id(kms_key_arn)
             .send();
+        let copy_future = state
+            .tracer
+            .traced("copy_image", Some(region.as_ref()), copy_future);
+        let copy_future = rate_limits.rate_limited(EC2, region.as_ref(), copy_future);
 
         // Store the region so we can output it to the user
         let region_future = ready(region.clone());
         // Let the user know the copy is starting, when this future goes to run
         let message_future =
             lazy(move |_| info!("Starting copy from {} to {}", base_region, region));
-        copy_requests.push(message_future.then(|_| join(region_future, copy_future)));
+        copy_requests.push((
+            region.to_string(),
+            message_future.then(|_| join(region_future, copy_future)),
+        ));
     }
 
     // If all target regions already have the AMI, we're done.
@@ -368,7 +679,10 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     // (We still use buffer_unordered, rather than something like join_all, to retain some control
     // over the number of requests going out in case we need it later, but this will effectively
     // spin through all regions quickly because the requests return before any copying is done.)
-    let request_stream = stream::iter(copy_requests).buffer_unordered(4);
+    let progress_bar = progress_bar(options.no_progress, copy_requests.len(), "Starting copies");
+    let request_stream = stream::iter(state.interrupt.tracked(AMI_COPIES, copy_requests))
+        .buffer_unordered(4)
+        .inspect(|_| progress_bar.inc(1));
     // Run through the stream and collect results into a list.
     let copy_responses: Vec<(
         Region,
@@ -382,19 +696,47 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
         match copy_response {
             Ok(success) => {
                 if let Some(image_id) = success.image_id {
-                    info!(
-                        "Registered AMI '{}' in {}: {}",
-                        ami_args.name, region, image_id,
-                    );
+                    info!("Registered AMI '{}' in {}: {}", name, region, image_id,);
+                    // Record where the copy came from on the AMI itself, so it can be audited
+                    // without our AMI data.
+                    if let Err(e) = tag_image(
+                        &ec2_clients[&region],
+                        &rate_limits,
+                        region.as_ref(),
+                        &image_id,
+                        &lineage,
+                    )
+                    .await
+                    {
+                        saw_error = true;
+                        error!("{}", e);
+                    }
+                    // The copy's snapshots don't exist until the copy is done, so only the AMI
+                    // gets the default tags here.
+                    if let Err(e) = tag_ec2_resources(
+                        &ec2_clients[&region],
+                        &rate_limits,
+                        region.as_ref(),
+                        &[image_id.clone()],
+                        &aws,
+                    )
+                    .await
+                    {
+                        saw_error = true;
+                        error!("{}", e);
+                    }
+                    state
+                        .journal
+                        .record(AMI_COPIES, region.as_ref(), Some(&image_id));
                     amis.insert(
                         region.as_ref().to_string(),
-                        Image::new(&image_id, &ami_args.name, Some(false), Some(vec![])),
+                        Image::new(&image_id, name, Some(false), Some(vec![]), &lineage),
                     );
                 } else {
                     saw_error = true;
                     error!(
                         "Registered AMI '{}' in {} but didn't receive an AMI ID!",
-                        ami_args.name, region,
+                        name, region,
                     );
                 }
             }
@@ -418,11 +760,14 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
 /// struct holds the information we save about each AMI.  The `ssm` subcommand uses this
 /// information to populate templates representing SSM parameter names and values.
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq, Hash)]
-pub(crate) struct Image {
-    pub(crate) id: String,
-    pub(crate) name: String,
-    pub(crate) public: Option<bool>,
-    pub(crate) launch_permissions: Option<Vec<LaunchPermissionDef>>,
+pub struct Image {
+    pub id: String,
+    pub name: String,
+    pub public: Option<bool>,
+    pub launch_permissions: Option<Vec<LaunchPermissionDef>>,
+    /// The originally registered AMI this one was derived from; missing from older AMI data
+    #[serde(default)]
+    pub lineage: Option<Lineage>,
 }
 
 impl Image {
@@ -431,12 +776,14 @@ impl Image {
         name: &str,
         public: Option<bool>,
         launch_permissions: Option<Vec<LaunchPermissionDef>>,
+        lineage: &Lineage,
     ) -> Self {
         Self {
             id: id.to_string(),
             name: name.to_string(),
             public,
             launch_permissions,
+            lineage: Some(lineage.clone()),
         }
     }
 }
@@ -454,7 +801,7 @@ async fn get_account_ids(
     let mut sts_clients = HashMap::with_capacity(regions.len());
     for region in regions.iter() {
         let client_config = build_client_config(region, base_region, pubsys_aws_config).await;
-        let sts_client = StsClient::new(&client_config);
+        let sts_client = StsClient::from_pubsys_config(&client_config, pubsys_aws_config);
         sts_clients.insert(region.clone(), sts_client);
     }
 
@@ -491,7 +838,7 @@ async fn get_account_ids(
 }
 
 mod error {
-    use crate::aws::{ami, publish_ami};
+    use crate::aws::{ami, check_permissions, publish_ami};
     use aws_sdk_ec2::error::ModifyImageAttributeError;
     use aws_sdk_ec2::model::LaunchPermission;
     use aws_sdk_ec2::types::SdkError;
@@ -503,10 +850,15 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Some AMIs failed to copy, see above"))]
         AmiCopy,
 
+        #[snafu(display("Failed to decide AMI name: {}", source))]
+        AmiName {
+            source: crate::aws::ami::name::Error,
+        },
+
         #[snafu(display("Error reading config: {}", source))]
         Config { source: pubsys_config::Error },
 
@@ -592,6 +944,16 @@ mod error {
             missing: String,
         },
 
+        #[snafu(display(
+            "Can't plan for '{}', which isn't registered in {}; run ami for only that region first",
+            name,
+            region
+        ))]
+        PlanUnregistered { name: String, region: String },
+
+        #[snafu(display("Permission check failed: {}", source))]
+        Preflight { source: check_permissions::Error },
+
         #[snafu(display("Error registering {} {} in {}: {}", arch, name, region, source))]
         RegisterImage {
             name: String,
@@ -600,6 +962,12 @@ mod error {
             source: ami::register::Error,
         },
 
+        #[snafu(display("{}", source))]
+        Tag { source: crate::aws::tags::Error },
+
+        #[snafu(display("{}", source))]
+        Tests { source: crate::test_trigger::Error },
+
         #[snafu(display("AMI '{}' in {} did not become available: {}", id, region, source))]
         WaitAmi {
             id: String,
@@ -614,9 +982,19 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 
 use self::launch_permissions::LaunchPermissionDef;
 
 use super::publish_ami::write_amis;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::GetCallerIdentity { .. } | Self::GrantImageAccess { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! The name module decides the name and description of the AMIs we register, which can come from
+//! the command line or from templates in Infra.toml rendered with the build context.
+
+use crate::aws::ssm::BuildContext;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{OptionExt, ResultExt};
+use tinytemplate::TinyTemplate;
+
+/// The name and description to give the AMIs we register
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct AmiNames {
+    pub(crate) name: String,
+    pub(crate) description: String,
+}
+
+/// Decides the AMI name and description.  Values given on the command line win, then the templates
+/// from `aws.ami` in Infra.toml, and then the default name given on the command line.  Without a
+/// description or template for one, the description matches the name.
+pub(crate) fn ami_names(
+    name: Option<&str>,
+    description: Option<&str>,
+    default_name: Option<&str>,
+    build_context: Option<&BuildContext<'_>>,
+    pubsys_aws_config: &PubsysAwsConfig,
+) -> Result<AmiNames> {
+    let templates = pubsys_aws_config.ami.clone().unwrap_or_default();
+
+    let name = match (name, &templates.name) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(template)) => render(template, build_context)?,
+        (None, None) => default_name.context(error::MissingNameSnafu)?.to_string(),
+    };
+
+    let description = match (description, &templates.description) {
+        (Some(description), _) => description.to_string(),
+        (None, Some(template)) => render(template, build_context)?,
+        (None, None) => name.clone(),
+    };
+
+    Ok(AmiNames { name, description })
+}
+
+/// Renders a name or description template with the build context, which we only have if we were
+/// given the variant and version.
+fn render(template: &str, build_context: Option<&BuildContext<'_>>) -> Result<String> {
+    let build_context = build_context.context(error::MissingBuildContextSnafu { template })?;
+    let mut tt = TinyTemplate::new();
+    tt.add_template("ami", template)
+        .context(error::AddTemplateSnafu { template })?;
+    tt.render("ami", build_context)
+        .context(error::RenderTemplateSnafu { template })
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error building template from '{}': {}", template, source))]
+        AddTemplate {
+            template: String,
+            source: tinytemplate::error::Error,
+        },
+
+        #[snafu(display(
+            "Rendering AMI template '{}' requires --variant and --version",
+            template
+        ))]
+        MissingBuildContext { template: String },
+
+        #[snafu(display(
+            "No AMI name given; specify --name, aws.ami.name in Infra.toml, or --default-name"
+        ))]
+        MissingName,
+
+        #[snafu(display("Failed to render template '{}': {}", template, source))]
+        RenderTemplate {
+            template: String,
+            source: tinytemplate::error::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{ami_names, AmiNames};
+    use crate::aws::ssm::BuildContext;
+    use pubsys_config::{AwsAmiConfig, AwsConfig as PubsysAwsConfig};
+
+    fn build_context() -> BuildContext<'static> {
+        BuildContext {
+            variant: "aws-k8s-1.24",
+            arch: "x86_64",
+            image_version: "1.13.0-abcdef12",
+        }
+    }
+
+    #[test]
+    fn templates_render_with_build_context() {
+        let mut aws = PubsysAwsConfig::default();
+        aws.ami = Some(AwsAmiConfig {
+            name: Some("mydistro-{variant}-{arch}-v{image_version}".to_string()),
+            description: Some("My Distro {variant} for {arch}".to_string()),
+        });
+        let names = ami_names(
+            None,
+            None,
+            Some("bottlerocket-default"),
+            Some(&build_context()),
+            &aws,
+        )
+        .unwrap();
+        assert_eq!(
+            names,
+            AmiNames {
+                name: "mydistro-aws-k8s-1.24-x86_64-v1.13.0-abcdef12".to_string(),
+                description: "My Distro aws-k8s-1.24 for x86_64".to_string(),
+            }
+        );
+
+        // Names given on the command line win over templates.
+        let names = ami_names(Some("given"), None, None, Some(&build_context()), &aws).unwrap();
+        assert_eq!(names.name, "given");
+        assert_eq!(names.description, "My Distro aws-k8s-1.24 for x86_64");
+    }
+
+    #[test]
+    fn default_name_without_templates() {
+        let aws = PubsysAwsConfig::default();
+        let names = ami_names(None, None, Some("bottlerocket-default"), None, &aws).unwrap();
+        assert_eq!(
+            names,
+            AmiNames {
+                name: "bottlerocket-default".to_string(),
+                description: "bottlerocket-default".to_string(),
+            }
+        );
+        assert!(ami_names(None, None, None, None, &aws).is_err());
+    }
+}
@@ -1,16 +1,24 @@
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
 use aws_sdk_ec2::Client as Ec2Client;
 use snafu::{ensure, OptionExt, ResultExt};
 
 /// Returns whether or not the given AMI ID refers to a public AMI.
 pub(crate) async fn ami_is_public(
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
     region: &str,
     ami_id: &str,
 ) -> Result<bool> {
-    let ec2_response = ec2_client
-        .describe_images()
-        .image_ids(ami_id.to_string())
-        .send()
+    let ec2_response = rate_limits
+        .rate_limited(
+            EC2,
+            region,
+            ec2_client
+                .describe_images()
+                .image_ids(ami_id.to_string())
+                .send(),
+        )
         .await
         .context(error::DescribeImagesSnafu {
             ami_id: ami_id.to_string(),
@@ -44,7 +52,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error describing AMI {} in {}: {}", ami_id, region, source))]
         DescribeImages {
             ami_id: String,
@@ -60,5 +68,14 @@ mod error {
         TooManyImages { ami_id: String, region: String },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use super::{snapshot::snapshot_from_image, AmiArgs};
+use super::{name::AmiNames, snapshot::snapshot_from_image, AmiOptions};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
 use aws_sdk_ebs::Client as EbsClient;
 use aws_sdk_ec2::model::{
     ArchitectureValues, BlockDeviceMapping, EbsBlockDevice, Filter, VolumeType,
@@ -27,43 +29,44 @@ pub(crate) struct RegisteredIds {
 /// Helper for `register_image`.  Inserts registered snapshot IDs into `cleanup_snapshot_ids` so
 /// they can be cleaned up on failure if desired.
 async fn _register_image(
-    ami_args: &AmiArgs,
+    options: &AmiOptions,
+    names: &AmiNames,
     region: &Region,
     ebs_client: EbsClient,
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
     cleanup_snapshot_ids: &mut Vec<String>,
 ) -> Result<RegisteredIds> {
-    let variant_manifest = manifest::ManifestInfo::new(&ami_args.variant_manifest).context(
+    let variant_manifest = manifest::ManifestInfo::new(&options.variant_manifest).context(
         error::LoadVariantManifestSnafu {
-            path: &ami_args.variant_manifest,
+            path: &options.variant_manifest,
         },
     )?;
 
     let image_layout = variant_manifest
         .image_layout()
         .context(error::MissingImageLayoutSnafu {
-            path: &ami_args.variant_manifest,
+            path: &options.variant_manifest,
         })?;
 
     let (os_volume_size, data_volume_size) = image_layout.publish_image_sizes_gib();
 
     debug!("Uploading images into EBS snapshots in {}", region);
     let uploader = SnapshotUploader::new(ebs_client);
-    let os_snapshot =
-        snapshot_from_image(&ami_args.os_image, &uploader, None, ami_args.no_progress)
-            .await
-            .context(error::SnapshotSnafu {
-                path: &ami_args.os_image,
-                region: region.as_ref(),
-            })?;
+    let os_snapshot = snapshot_from_image(&options.os_image, &uploader, None, options.no_progress)
+        .await
+        .context(error::SnapshotSnafu {
+            path: &options.os_image,
+            region: region.as_ref(),
+        })?;
     cleanup_snapshot_ids.push(os_snapshot.clone());
 
     let mut data_snapshot = None;
-    if let Some(data_image) = &ami_args.data_image {
-        let snapshot = snapshot_from_image(data_image, &uploader, None, ami_args.no_progress)
+    if let Some(data_image) = &options.data_image {
+        let snapshot = snapshot_from_image(data_image, &uploader, None, options.no_progress)
             .await
             .context(error::SnapshotSnafu {
-                path: &ami_args.os_image,
+                path: &options.os_image,
                 region: region.as_ref(),
             })?;
         cleanup_snapshot_ids.push(snapshot.clone());
@@ -118,17 +121,22 @@ async fn _register_image(
     }
 
     info!("Making register image call in {}", region);
-    let register_response = ec2_client
-        .register_image()
-        .set_architecture(Some(ami_args.arch.clone()))
-        .set_block_device_mappings(Some(block_device_mappings))
-        .set_description(ami_args.description.clone())
-        .set_ena_support(Some(ENA))
-        .set_name(Some(ami_args.name.clone()))
-        .set_root_device_name(Some(ROOT_DEVICE_NAME.to_string()))
-        .set_sriov_net_support(Some(SRIOV.to_string()))
-        .set_virtualization_type(Some(VIRT_TYPE.to_string()))
-        .send()
+    let register_response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .register_image()
+                .set_architecture(Some(options.arch.clone()))
+                .set_block_device_mappings(Some(block_device_mappings))
+                .set_description(Some(names.description.clone()))
+                .set_ena_support(Some(ENA))
+                .set_name(Some(names.name.clone()))
+                .set_root_device_name(Some(ROOT_DEVICE_NAME.to_string()))
+                .set_sriov_net_support(Some(SRIOV.to_string()))
+                .set_virtualization_type(Some(VIRT_TYPE.to_string()))
+                .send(),
+        )
         .await
         .context(error::RegisterImageSnafu {
             region: region.as_ref(),
@@ -154,28 +162,37 @@ async fn _register_image(
 /// Uploads the given images into snapshots and registers an AMI using them as its block device
 /// mapping.  Deletes snapshots on failure.
 pub(crate) async fn register_image(
-    ami_args: &AmiArgs,
+    options: &AmiOptions,
+    names: &AmiNames,
     region: &Region,
     ebs_client: EbsClient,
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
 ) -> Result<RegisteredIds> {
-    info!("Registering '{}' in {}", ami_args.name, region);
+    info!("Registering '{}' in {}", names.name, region);
     let mut cleanup_snapshot_ids = Vec::new();
     let register_result = _register_image(
-        ami_args,
+        options,
+        names,
         region,
         ebs_client,
         ec2_client,
+        rate_limits,
         &mut cleanup_snapshot_ids,
     )
     .await;
 
     if register_result.is_err() {
         for snapshot_id in cleanup_snapshot_ids {
-            if let Err(e) = ec2_client
-                .delete_snapshot()
-                .set_snapshot_id(Some(snapshot_id.clone()))
-                .send()
+            if let Err(e) = rate_limits
+                .rate_limited(
+                    EC2,
+                    region.as_ref(),
+                    ec2_client
+                        .delete_snapshot()
+                        .set_snapshot_id(Some(snapshot_id.clone()))
+                        .send(),
+                )
                 .await
             {
                 warn!(
@@ -194,32 +211,38 @@ pub(crate) async fn get_ami_id<S>(
     arch: &ArchitectureValues,
     region: &Region,
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
 ) -> Result<Option<String>>
 where
     S: Into<String>,
 {
-    let describe_response = ec2_client
-        .describe_images()
-        .set_owners(Some(vec!["self".to_string()]))
-        .set_filters(Some(vec![
-            Filter::builder()
-                .set_name(Some("name".to_string()))
-                .set_values(Some(vec![name.into()]))
-                .build(),
-            Filter::builder()
-                .set_name(Some("architecture".to_string()))
-                .set_values(Some(vec![arch.as_ref().to_string()]))
-                .build(),
-            Filter::builder()
-                .set_name(Some("image-type".to_string()))
-                .set_values(Some(vec!["machine".to_string()]))
-                .build(),
-            Filter::builder()
-                .set_name(Some("virtualization-type".to_string()))
-                .set_values(Some(vec![VIRT_TYPE.to_string()]))
-                .build(),
-        ]))
-        .send()
+    let describe_response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .describe_images()
+                .set_owners(Some(vec!["self".to_string()]))
+                .set_filters(Some(vec![
+                    Filter::builder()
+                        .set_name(Some("name".to_string()))
+                        .set_values(Some(vec![name.into()]))
+                        .build(),
+                    Filter::builder()
+                        .set_name(Some("architecture".to_string()))
+                        .set_values(Some(vec![arch.as_ref().to_string()]))
+                        .build(),
+                    Filter::builder()
+                        .set_name(Some("image-type".to_string()))
+                        .set_values(Some(vec!["machine".to_string()]))
+                        .build(),
+                    Filter::builder()
+                        .set_name(Some("virtualization-type".to_string()))
+                        .set_values(Some(vec![VIRT_TYPE.to_string()]))
+                        .build(),
+                ]))
+                .send(),
+        )
         .await
         .context(error::DescribeImagesSnafu {
             region: region.as_ref(),
@@ -258,7 +281,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Failed to describe images in {}: {}", region, source))]
         DescribeImages {
             region: String,
@@ -300,5 +323,14 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeImages { .. } | Self::RegisterImage { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -48,7 +48,7 @@ mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     #[allow(clippy::large_enum_variant)]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Invalid image path '{}'", path.display()))]
         InvalidImagePath { path: PathBuf },
 
@@ -61,5 +61,5 @@ mod error {
         UploadSnapshot { source: coldsnap::UploadError },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
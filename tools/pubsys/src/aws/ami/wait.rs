@@ -1,4 +1,6 @@
-use crate::aws::client::build_client_config;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
 use aws_sdk_ec2::model::ImageState;
 use aws_sdk_ec2::{Client as Ec2Client, Region};
 use log::info;
@@ -16,6 +18,7 @@ pub(crate) async fn wait_for_ami(
     state: &str,
     successes_required: u8,
     pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
 ) -> Result<()> {
     let mut successes = 0;
     let max_attempts = 90;
@@ -37,11 +40,16 @@ pub(crate) async fn wait_for_ami(
         // Use a new client each time so we have more confidence that different endpoints can see
         // the new AMI.
         let client_config = build_client_config(region, sts_region, pubsys_aws_config).await;
-        let ec2_client = Ec2Client::new(&client_config);
-        let describe_response = ec2_client
-            .describe_images()
-            .set_image_ids(Some(vec![id.to_string()]))
-            .send()
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, pubsys_aws_config);
+        let describe_response = rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                ec2_client
+                    .describe_images()
+                    .set_image_ids(Some(vec![id.to_string()]))
+                    .send(),
+            )
             .await
             .context(error::DescribeImagesSnafu {
                 region: region.as_ref(),
@@ -109,7 +117,7 @@ mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     #[allow(clippy::large_enum_variant)]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Failed to describe images in {}: {}", region, source))]
         DescribeImages {
             region: String,
@@ -136,5 +144,14 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! The check_permissions module owns the 'check-permissions' subcommand and the `--preflight`
+//! checks of other subcommands.  It asks IAM to simulate the API calls an operation will make, so
+//! that a missing permission is found before a long publishing run starts rather than partway
+//! through it.  The subcommand is also available as 'check-iam', and ends with the list of exact
+//! actions that are missing, ready to add to a policy.
+
+pub(crate) mod simulate;
+
+use crate::aws::client::build_client_config;
+use crate::aws::secrets::{self, SecretService};
+use crate::aws::{proxy, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_config::SdkConfig;
+use aws_sdk_ec2::Region;
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
+use pubsys_config::{AwsConfig as PubsysAwsConfig, KMSKeyConfig, SigningKeyConfig};
+use serde::{Deserialize, Serialize};
+use serde_plain::{derive_display_from_serialize, derive_fromstr_from_deserialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeSet;
+use structopt::{clap, StructOpt};
+
+/// Checks that the configured credentials allow the API calls made by other subcommands
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct CheckPermissionsArgs {
+    /// Subcommands to check permissions for: ami, publish-ami, ssm, repo
+    #[structopt(long, use_delimiter = true, default_value = "ami,publish-ami,ssm,repo")]
+    operations: Vec<Operation>,
+
+    /// Regions to check, the first will be used as the base for copying AMIs
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Use this named repo infrastructure from Infra.toml when checking the repo signing key
+    #[structopt(long, default_value = "default")]
+    repo: String,
+}
+
+/// The subcommands whose permissions we know how to check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    Ami,
+    PublishAmi,
+    Ssm,
+    Repo,
+}
+
+derive_display_from_serialize!(Operation);
+derive_fromstr_from_deserialize!(Operation);
+
+impl Operation {
+    /// Returns the API actions the operation calls in the given region.  AMIs are registered in
+    /// the base region and copied to the others, so they need different permissions.
+    fn regional_actions(&self, is_base_region: bool) -> &'static [&'static str] {
+        match (self, is_base_region) {
+            (Operation::Ami, true) => &[
+                "ebs:StartSnapshot",
+                "ebs:PutSnapshotBlock",
+                "ebs:CompleteSnapshot",
+                "ec2:DescribeSnapshots",
+                "ec2:DeleteSnapshot",
+                "ec2:RegisterImage",
+                "ec2:DescribeImages",
+                "ec2:DescribeImageAttribute",
+                "ec2:ModifyImageAttribute",
+                "ec2:ModifySnapshotAttribute",
+                "sts:GetCallerIdentity",
+            ],
+            (Operation::Ami, false) => &[
+                "ec2:CopyImage",
+                "ec2:CreateTags",
+                "ec2:DescribeImages",
+                "ec2:DescribeImageAttribute",
+                "sts:GetCallerIdentity",
+            ],
+            (Operation::PublishAmi, _) => &[
+                "ec2:DescribeImages",
+                "ec2:DescribeImageAttribute",
+                "ec2:ModifyImageAttribute",
+                "ec2:ModifySnapshotAttribute",
+                "ec2:DescribeSnapshotAttribute",
+                "ec2:GetImageBlockPublicAccessState",
+            ],
+            (Operation::Ssm, _) => &[
+                "ec2:DescribeImages",
+                "ssm:GetParameters",
+                "ssm:PutParameter",
+            ],
+            // Repo operations only talk to AWS to sign, which is checked per signing key.
+            (Operation::Repo, _) => &[],
+        }
+    }
+
+    /// Returns the further API actions the operation calls in the given region to apply the
+    /// default tags from `aws.tags`.  (Copied AMIs are tagged with their lineage regardless.)
+    fn tagging_actions(&self, is_base_region: bool) -> &'static [&'static str] {
+        match (self, is_base_region) {
+            (Operation::Ami, true) => &["ec2:CreateTags"],
+            (Operation::Ssm, _) => &["ssm:AddTagsToResource"],
+            _ => &[],
+        }
+    }
+}
+
+/// An API action that the principal in a region isn't allowed to call.
+#[derive(Debug)]
+struct Denial {
+    /// The region, or another description of where the call would be made
+    location: String,
+    principal: String,
+    action: String,
+    decision: String,
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, check_args: &CheckPermissionsArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !check_args.regions.is_empty() {
+        check_args.regions.clone()
+    } else {
+        aws.regions_for("check_permissions").clone().into()
+    }
+    .into_iter()
+    .map(|name| region_from_string(&name))
+    .collect::<Vec<Region>>();
+
+    let mut denials = Vec::new();
+    for operation in &check_args.operations {
+        info!("Checking permissions for '{}'", operation);
+        if *operation == Operation::Repo {
+            let signing_key_configs = infra_config
+                .repo
+                .as_ref()
+                .and_then(|repo_section| repo_section.get(&check_args.repo))
+                .map(|repo_config| repo_config.all_signing_keys().collect::<Vec<_>>())
+                .unwrap_or_default();
+            if signing_key_configs.is_empty() {
+                info!(
+                    "Repo '{}' has no signing key in Infra.toml, so no permissions are needed",
+                    check_args.repo
+                );
+            }
+            for signing_key_config in signing_key_configs {
+                denials.extend(check_signing_key(signing_key_config, &aws).await?);
+            }
+        } else {
+            denials.extend(check_regions(*operation, &aws, &regions).await?);
+        }
+    }
+
+    report(denials)
+}
+
+/// Checks that the configured credentials allow the API calls the given operation will make in
+/// the given regions, failing if any would be denied.  The first region is the base region.
+pub(crate) async fn preflight(
+    operation: Operation,
+    aws: &PubsysAwsConfig,
+    regions: &[Region],
+) -> Result<()> {
+    info!("Checking permissions for '{}' before starting", operation);
+    report(check_regions(operation, aws, regions).await?)
+}
+
+/// Checks that the configured credentials allow signing with the given repo signing key, failing
+/// if any calls would be denied.
+pub(crate) async fn preflight_signing_key(
+    signing_key_config: &SigningKeyConfig,
+    aws: &PubsysAwsConfig,
+) -> Result<()> {
+    info!("Checking permissions for the repo signing key before starting");
+    report(check_signing_key(signing_key_config, aws).await?)
+}
+
+/// Logs each denied action, then the distinct actions that are missing, and fails if there were
+/// any.
+fn report(denials: Vec<Denial>) -> Result<()> {
+    if denials.is_empty() {
+        info!("All required permissions are allowed");
+        return Ok(());
+    }
+    for denial in &denials {
+        error!(
+            "{}: {} is not allowed to call {} ({})",
+            denial.location, denial.principal, denial.action, denial.decision
+        );
+    }
+    error!("Missing actions: {}", missing_actions(&denials).join(", "));
+    error::DeniedSnafu {
+        count: denials.len(),
+    }
+    .fail()
+}
+
+/// Returns each denied action once, sorted, so they can be added to a policy as is.
+fn missing_actions(denials: &[Denial]) -> Vec<&str> {
+    let actions: BTreeSet<&str> = denials
+        .iter()
+        .map(|denial| denial.action.as_str())
+        .collect();
+    actions.into_iter().collect()
+}
+
+/// Simulates the API calls made by the given operation in each of the given regions, in parallel.
+async fn check_regions(
+    operation: Operation,
+    aws: &PubsysAwsConfig,
+    regions: &[Region],
+) -> Result<Vec<Denial>> {
+    let base_region = regions.first().context(error::MissingConfigSnafu {
+        missing: "aws.regions",
+    })?;
+
+    let mut requests = Vec::with_capacity(regions.len());
+    for region in regions {
+        let is_base_region = region == base_region;
+        let mut actions = operation.regional_actions(is_base_region).to_vec();
+        if !aws.tags.is_empty() {
+            actions.extend(operation.tagging_actions(is_base_region));
+        }
+        requests.push(check_region(aws, region, base_region, actions));
+    }
+    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let responses: Vec<Result<Vec<Denial>>> = request_stream.collect().await;
+
+    let mut denials = Vec::new();
+    for response in responses {
+        denials.extend(response?);
+    }
+    Ok(denials)
+}
+
+/// Simulates the given API calls for the principal pubsys will use in the given region.
+async fn check_region(
+    aws: &PubsysAwsConfig,
+    region: &Region,
+    base_region: &Region,
+    actions: Vec<&str>,
+) -> Result<Vec<Denial>> {
+    if actions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let client_config = build_client_config(region, base_region, aws).await;
+    let principal = infra_principal(&client_config, aws, region, region.as_ref()).await?;
+
+    check(
+        &client_config,
+        aws,
+        region.as_ref(),
+        &principal,
+        &actions,
+        &[],
+    )
+    .await
+}
+
+/// Returns the principal that makes calls in the given region with the credentials from
+/// Infra.toml.
+async fn infra_principal(
+    client_config: &SdkConfig,
+    aws: &PubsysAwsConfig,
+    region: &Region,
+    location: &str,
+) -> Result<String> {
+    // If we're assuming a role, the last one in the chain is the one making the calls; otherwise
+    // ask STS who we are.
+    let configured_role = aws
+        .region
+        .get(region.as_ref())
+        .and_then(|r| r.role.clone())
+        .or_else(|| aws.role.clone());
+    match configured_role {
+        Some(role) => Ok(role),
+        None => simulate::principal_arn(client_config, aws, region)
+            .await
+            .context(error::SimulateSnafu { location }),
+    }
+}
+
+/// Simulates the calls made when signing with the given repo signing key.  Other than keys stored
+/// as AWS secrets, these use the default credentials, like the key sources in tough, rather than
+/// any roles from Infra.toml.
+async fn check_signing_key(
+    signing_key_config: &SigningKeyConfig,
+    aws: &PubsysAwsConfig,
+) -> Result<Vec<Denial>> {
+    let (region, actions, resource_arns): (Option<&String>, Vec<&str>, Vec<String>) =
+        match signing_key_config {
+            SigningKeyConfig::file { .. } => {
+                info!("Repo signing key is a local file, so no permissions are needed");
+                return Ok(Vec::new());
+            }
+            SigningKeyConfig::kms { key_id, config } => {
+                let key_id = key_id
+                    .as_ref()
+                    .or_else(|| config.as_ref().and_then(KMSKeyConfig::single_available_key))
+                    .context(error::MissingConfigSnafu {
+                        missing: "key_id, or a single key in available_keys",
+                    })?;
+                let region = config
+                    .as_ref()
+                    .and_then(|config| config.available_keys.get(key_id));
+                // Aliases and bare key IDs can't be given as resources, so those keys are checked
+                // against any resource.
+                let resource_arns = if key_id.starts_with("arn:") {
+                    vec![key_id.clone()]
+                } else {
+                    Vec::new()
+                };
+                (region, vec!["kms:Sign", "kms:GetPublicKey"], resource_arns)
+            }
+            SigningKeyConfig::ssm { .. } => {
+                (None, vec!["ssm:GetParameter", "kms:Decrypt"], Vec::new())
+            }
+            SigningKeyConfig::secret { arn } => return check_secret_key(arn, aws).await,
+            SigningKeyConfig::pkcs11 { .. } => {
+                info!("Repo signing key is in a PKCS#11 token, so no permissions are needed");
+                return Ok(Vec::new());
+            }
+        };
+
+    // Signing keys don't use the AWS settings from Infra.toml, including its endpoint URLs.
+    let aws = PubsysAwsConfig::default();
+    let mut loader = aws_config::from_env();
+    if let Some(region) = region {
+        loader = loader.region(region_from_string(region));
+    }
+    if let Some(connector) = proxy::http_connector(&aws) {
+        loader = loader.http_connector(connector);
+    }
+    let client_config: SdkConfig = loader.load().await;
+    let region = client_config
+        .region()
+        .cloned()
+        .context(error::MissingRegionSnafu)?;
+
+    let location = format!("signing key in {}", region);
+    let principal = simulate::principal_arn(&client_config, &aws, &region)
+        .await
+        .context(error::SimulateSnafu {
+            location: &location,
+        })?;
+
+    check(
+        &client_config,
+        &aws,
+        &location,
+        &principal,
+        &actions,
+        &resource_arns,
+    )
+    .await
+}
+
+/// Simulates the calls made when fetching a signing key stored as an AWS secret, with the
+/// credentials from Infra.toml.
+async fn check_secret_key(arn: &str, aws: &PubsysAwsConfig) -> Result<Vec<Denial>> {
+    let (service, region) = secrets::parse_secret_arn(arn).context(error::SecretSnafu)?;
+    let action = match service {
+        SecretService::SecretsManager => "secretsmanager:GetSecretValue",
+        SecretService::Ssm => "ssm:GetParameter",
+    };
+    let region = region_from_string(region);
+    let client_config = build_client_config(&region, &region, aws).await;
+    let location = format!("signing key in {}", region);
+    let principal = infra_principal(&client_config, aws, &region, &location).await?;
+
+    check(
+        &client_config,
+        aws,
+        &location,
+        &principal,
+        &[action],
+        &[arn.to_string()],
+    )
+    .await
+}
+
+/// Simulates the given API calls for the given principal, returning any that would be denied.
+async fn check(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    location: &str,
+    principal: &str,
+    actions: &[&str],
+    resource_arns: &[String],
+) -> Result<Vec<Denial>> {
+    trace!("Simulating {:?} for {} in {}", actions, principal, location);
+    let decisions = simulate::simulate(
+        client_config,
+        pubsys_aws_config,
+        principal,
+        actions,
+        resource_arns,
+    )
+    .await
+    .context(error::SimulateSnafu { location })?;
+
+    let mut denials = Vec::new();
+    for decision in decisions {
+        if decision.allowed() {
+            continue;
+        }
+        denials.push(Denial {
+            location: location.to_string(),
+            principal: principal.to_string(),
+            action: decision.action,
+            decision: decision.decision,
+        });
+    }
+    Ok(denials)
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("{} required permissions are not allowed, see above", count))]
+        Denied { count: usize },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("No region is configured for the repo signing key; set AWS_REGION"))]
+        MissingRegion,
+
+        #[snafu(display("{}", source))]
+        Secret { source: crate::aws::secrets::Error },
+
+        #[snafu(display("Failed to check permissions for {}: {}", location, source))]
+        Simulate {
+            location: String,
+            source: super::simulate::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } | Self::MissingRegion => Some(Kind::Config),
+            Self::Denied { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{missing_actions, Denial};
+
+    fn denial(location: &str, action: &str) -> Denial {
+        Denial {
+            location: location.to_string(),
+            principal: "arn:aws:iam::111122223333:role/publish".to_string(),
+            action: action.to_string(),
+            decision: "implicitDeny".to_string(),
+        }
+    }
+
+    #[test]
+    fn lists_each_missing_action_once() {
+        let denials = vec![
+            denial("us-west-2", "ssm:PutParameter"),
+            denial("us-east-1", "ssm:PutParameter"),
+            denial("us-east-1", "ec2:CopyImage"),
+        ];
+        assert_eq!(
+            missing_actions(&denials),
+            vec!["ec2:CopyImage", "ssm:PutParameter"]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! The simulate module finds the IAM principal that pubsys is acting as, and asks IAM whether that
+//! principal is allowed to make a given set of API calls, using SimulatePrincipalPolicy.
+
+use crate::aws::client::ServiceClient;
+use crate::exit_code::{Classify, Kind};
+use aws_config::SdkConfig;
+use aws_sdk_ec2::Region;
+use aws_sdk_iam::model::EvaluationResult;
+use aws_sdk_iam::Client as IamClient;
+use aws_sdk_sts::Client as StsClient;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{OptionExt, ResultExt};
+
+/// The decision IAM returns for an action the principal is allowed to call.
+const ALLOWED_DECISION: &str = "allowed";
+
+/// The result of simulating a single API action.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Decision {
+    pub(crate) action: String,
+    /// One of "allowed", "explicitDeny", or "implicitDeny"
+    pub(crate) decision: String,
+}
+
+impl Decision {
+    pub(crate) fn allowed(&self) -> bool {
+        self.decision == ALLOWED_DECISION
+    }
+}
+
+/// Returns the ARN of the IAM principal behind the given client config.  Assumed-role sessions
+/// are mapped back to their role, since IAM can only simulate policies for users and roles.
+/// Session ARNs don't include the role's path, so the role's ARN is looked up with GetRole.
+pub(crate) async fn principal_arn(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    region: &Region,
+) -> Result<String> {
+    let response = StsClient::from_pubsys_config(client_config, pubsys_aws_config)
+        .get_caller_identity()
+        .send()
+        .await
+        .context(error::GetCallerIdentitySnafu {
+            region: region.as_ref(),
+        })?;
+    let arn = response.arn.context(error::MissingInResponseSnafu {
+        request_type: "GetCallerIdentity",
+        missing: "arn",
+    })?;
+    let role_name = match role_name_from_session(&arn) {
+        Some(role_name) => role_name,
+        None => return Ok(arn),
+    };
+
+    let response = IamClient::from_pubsys_config(client_config, pubsys_aws_config)
+        .get_role()
+        .role_name(role_name)
+        .send()
+        .await
+        .context(error::GetRoleSnafu {
+            role_name,
+            session_arn: &arn,
+        })?;
+    response
+        .role
+        .and_then(|role| role.arn)
+        .context(error::MissingInResponseSnafu {
+            request_type: "GetRole",
+            missing: "role.arn",
+        })
+}
+
+/// Returns the name of the role behind an assumed-role session ARN like
+/// `arn:aws:sts::111122223333:assumed-role/name/session`.  Returns None if the given ARN isn't a
+/// session ARN.
+fn role_name_from_session(arn: &str) -> Option<&str> {
+    let fields: Vec<&str> = arn.splitn(6, ':').collect();
+    if fields.len() != 6 || fields[2] != "sts" {
+        return None;
+    }
+    fields[5].strip_prefix("assumed-role/")?.split('/').next()
+}
+
+/// Asks IAM whether the given principal may call the given actions on the given resources.  If no
+/// resources are given, the actions are checked against all resources.
+pub(crate) async fn simulate(
+    client_config: &SdkConfig,
+    pubsys_aws_config: &PubsysAwsConfig,
+    principal_arn: &str,
+    actions: &[&str],
+    resource_arns: &[String],
+) -> Result<Vec<Decision>> {
+    let response = IamClient::from_pubsys_config(client_config, pubsys_aws_config)
+        .simulate_principal_policy()
+        .policy_source_arn(principal_arn)
+        .set_action_names(Some(actions.iter().map(|a| a.to_string()).collect()))
+        .set_resource_arns((!resource_arns.is_empty()).then(|| resource_arns.to_vec()))
+        .max_items(1000)
+        .send()
+        .await
+        .context(error::SimulateSnafu {
+            principal: principal_arn,
+        })?;
+
+    parse_decisions(response.evaluation_results().unwrap_or_default())
+}
+
+/// Pulls the action name and decision out of each evaluation result in a SimulatePrincipalPolicy
+/// response.  A result missing either is an error, rather than being paired with another's.
+fn parse_decisions(results: &[EvaluationResult]) -> Result<Vec<Decision>> {
+    let request_type = "SimulatePrincipalPolicy";
+    results
+        .iter()
+        .map(|result| {
+            let action = result
+                .eval_action_name()
+                .context(error::MissingInResponseSnafu {
+                    request_type,
+                    missing: "EvalActionName",
+                })?;
+            let decision = result
+                .eval_decision()
+                .context(error::MissingInResponseSnafu {
+                    request_type,
+                    missing: format!("EvalDecision for {}", action),
+                })?;
+            Ok(Decision {
+                action: action.to_string(),
+                decision: decision.as_str().to_string(),
+            })
+        })
+        .collect()
+}
+
+mod error {
+    use aws_sdk_iam::error::{GetRoleError, SimulatePrincipalPolicyError};
+    use aws_sdk_sts::error::GetCallerIdentityError;
+    use aws_sdk_sts::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error getting caller identity in {}: {}", region, source))]
+        GetCallerIdentity {
+            region: String,
+            source: SdkError<GetCallerIdentityError>,
+        },
+
+        #[snafu(display(
+            "Failed to get role '{}' to find the path of the role behind '{}'; allow iam:GetRole \
+             or give the role's ARN in Infra.toml: {}",
+            role_name,
+            session_arn,
+            source
+        ))]
+        GetRole {
+            role_name: String,
+            session_arn: String,
+            source: SdkError<GetRoleError>,
+        },
+
+        #[snafu(display("Response to {} was missing {}", request_type, missing))]
+        MissingInResponse {
+            request_type: String,
+            missing: String,
+        },
+
+        #[snafu(display("Failed to simulate policy for {}: {}", principal, source))]
+        Simulate {
+            principal: String,
+            source: SdkError<SimulatePrincipalPolicyError>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::GetCallerIdentity { .. } | Self::GetRole { .. } | Self::Simulate { .. } => {
+                Some(Kind::AwsApi)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_decisions, role_name_from_session, Decision};
+    use aws_sdk_iam::model::{EvaluationResult, PolicyEvaluationDecisionType};
+
+    #[test]
+    fn session_to_role() {
+        assert_eq!(
+            role_name_from_session("arn:aws:sts::111122223333:assumed-role/publisher/pubsys"),
+            Some("publisher")
+        );
+        assert_eq!(
+            role_name_from_session("arn:aws-cn:sts::111122223333:assumed-role/publisher/pubsys"),
+            Some("publisher")
+        );
+    }
+
+    #[test]
+    fn non_session_arns() {
+        assert_eq!(
+            role_name_from_session("arn:aws:iam::111122223333:user/builder"),
+            None
+        );
+        assert_eq!(
+            role_name_from_session("arn:aws:sts::111122223333:federated-user/builder"),
+            None
+        );
+    }
+
+    #[test]
+    fn decisions() {
+        let results = vec![
+            EvaluationResult::builder()
+                .eval_action_name("ec2:RegisterImage")
+                .eval_resource_name("*")
+                .eval_decision(PolicyEvaluationDecisionType::Allowed)
+                .build(),
+            EvaluationResult::builder()
+                .eval_action_name("ec2:CopyImage")
+                .eval_resource_name("*")
+                .eval_decision(PolicyEvaluationDecisionType::ImplicitDeny)
+                .build(),
+        ];
+        let decisions = parse_decisions(&results).unwrap();
+        assert_eq!(
+            decisions,
+            vec![
+                Decision {
+                    action: "ec2:RegisterImage".to_string(),
+                    decision: "allowed".to_string(),
+                },
+                Decision {
+                    action: "ec2:CopyImage".to_string(),
+                    decision: "implicitDeny".to_string(),
+                },
+            ]
+        );
+        assert!(decisions[0].allowed());
+        assert!(!decisions[1].allowed());
+    }
+
+    #[test]
+    fn incomplete_decisions() {
+        // A result without a decision isn't paired with the next result's.
+        let results = vec![
+            EvaluationResult::builder()
+                .eval_action_name("ec2:RegisterImage")
+                .build(),
+            EvaluationResult::builder()
+                .eval_action_name("ec2:CopyImage")
+                .eval_decision(PolicyEvaluationDecisionType::Allowed)
+                .build(),
+        ];
+        assert!(parse_decisions(&results).is_err());
+    }
+}
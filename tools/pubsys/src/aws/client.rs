@@ -1,71 +1,524 @@
-use aws_config::default_provider::credentials::default_provider;
+use crate::aws::proxy;
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
 use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::provider_config::ProviderConfig;
 use aws_config::sts::AssumeRoleProvider;
 use aws_config::SdkConfig;
-use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_credential_types::provider::{self, future, ProvideCredentials, SharedCredentialsProvider};
+use aws_credential_types::Credentials;
+use aws_sdk_sts::model::Tag;
+use aws_sdk_sts::Client as StsClient;
+use aws_smithy_types::retry::{RetryConfig, RetryConfigBuilder, RetryMode};
+use aws_smithy_types::timeout::TimeoutConfig;
+use aws_types::app_name::AppName;
 use aws_types::region::Region;
-use pubsys_config::AwsConfig as PubsysAwsConfig;
+use lazy_static::lazy_static;
+use log::warn;
+use pubsys_config::{
+    partition_for_region, AwsAssumeRoleConfig, AwsConfig as PubsysAwsConfig, AwsRetryConfig,
+    AwsRetryMode, AwsTimeoutConfig,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
 
-/// Create an AWS client config using the given regions and pubsys config.
+/// The name of role sessions, unless a session name is given
+const DEFAULT_SESSION_NAME: &str = "pubsys";
+
+/// Session credentials are refreshed this long before they expire, so they don't expire mid-call
+const SESSION_EXPIRY_BUFFER: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    /// Credentials shared by every client, so that regional clients don't each assume the same
+    /// roles, and the user is prompted for an MFA code once rather than for each region.  We share
+    /// credentials rather than providers because a provider's STS client belongs to the async
+    /// runtime it was first used in, and pubsys can use more than one runtime in a run.
+    static ref CREDENTIALS: std::sync::Mutex<HashMap<CredentialsKey, CredentialsSlot>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// The latest credentials for a key, once fetched
+type CredentialsSlot = Arc<Mutex<Option<Credentials>>>;
+
+/// An AWS SDK client for a single service, which can be sent to a custom endpoint URL given in
+/// Infra.toml, like when testing against LocalStack, or to the service's FIPS or dual-stack
+/// endpoints.
+pub(crate) trait ServiceClient: Sized {
+    /// The service's key in the `endpoint_urls` table of Infra.toml
+    const SERVICE: &'static str;
+
+    /// Builds a client from the given client config that sends requests to the service's endpoint
+    /// URL from the pubsys config, if one is given, otherwise to its FIPS or dual-stack endpoint if
+    /// the pubsys config asks for them.
+    fn from_pubsys_config(sdk_config: &SdkConfig, pubsys_aws_config: &PubsysAwsConfig) -> Self;
+}
+
+macro_rules! impl_service_client {
+    ($sdk:ident, $service:literal) => {
+        impl ServiceClient for $sdk::Client {
+            const SERVICE: &'static str = $service;
+
+            fn from_pubsys_config(
+                sdk_config: &SdkConfig,
+                pubsys_aws_config: &PubsysAwsConfig,
+            ) -> Self {
+                let mut builder = $sdk::config::Builder::from(sdk_config);
+                if pubsys_aws_config.use_fips_endpoints {
+                    builder = builder.use_fips(true);
+                }
+                if pubsys_aws_config.use_dualstack_endpoints {
+                    builder = builder.use_dual_stack(true);
+                }
+                if let Some(url) = pubsys_aws_config.service_endpoint_url(Self::SERVICE) {
+                    builder = builder.endpoint_url(url.as_str());
+                }
+                Self::from_conf(builder.build())
+            }
+        }
+    };
+}
+
+impl_service_client!(aws_sdk_cloudfront, "cloudfront");
+impl_service_client!(aws_sdk_ebs, "ebs");
+impl_service_client!(aws_sdk_ec2, "ec2");
+impl_service_client!(aws_sdk_iam, "iam");
+impl_service_client!(aws_sdk_imagebuilder, "imagebuilder");
+impl_service_client!(aws_sdk_kms, "kms");
+impl_service_client!(aws_sdk_s3, "s3");
+impl_service_client!(aws_sdk_secretsmanager, "secretsmanager");
+impl_service_client!(aws_sdk_sns, "sns");
+impl_service_client!(aws_sdk_ssm, "ssm");
+impl_service_client!(aws_sdk_sts, "sts");
+
+/// A role to assume, what the role's account requires in order to assume it, and how to name and
+/// tag the session
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AssumeRole {
+    role: String,
+    external_id: Option<String>,
+    /// The MFA device whose code the role requires, if any
+    mfa_serial: Option<String>,
+    session_name: String,
+    session_tags: BTreeMap<String, String>,
+}
+
+impl AssumeRole {
+    /// Returns the role with the given options for assuming it.  The session name and tags default
+    /// to those in `aws.assume_role`, which apply to every role.
+    fn new(role: String, options: AwsAssumeRoleConfig, defaults: &AwsAssumeRoleConfig) -> Self {
+        let session_tags = if options.session_tags.is_empty() {
+            defaults.session_tags.clone()
+        } else {
+            options.session_tags
+        };
+        Self {
+            role,
+            external_id: options.external_id,
+            mfa_serial: options.mfa_serial,
+            session_name: options
+                .session_name
+                .or_else(|| defaults.session_name.clone())
+                .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_string()),
+            session_tags: session_tags.into_iter().collect(),
+        }
+    }
+}
+
+/// Create an AWS client config using the given regions and pubsys config.  If the region is in a
+/// partition with its own credentials in the pubsys config, those are used, and STS is called in
+/// the region itself if the given STS region is in another partition.
 pub(crate) async fn build_client_config(
     region: &Region,
     sts_region: &Region,
     pubsys_aws_config: &PubsysAwsConfig,
 ) -> SdkConfig {
-    let maybe_profile = pubsys_aws_config.profile.clone();
-    let maybe_role = pubsys_aws_config.role.clone();
-    let maybe_regional_role = pubsys_aws_config
-        .region
-        .get(region.as_ref())
-        .and_then(|r| r.role.clone());
-    let base_provider = base_provider(&maybe_profile).await;
-
-    let config = match (&maybe_role, &maybe_regional_role) {
-        (None, None) => aws_config::from_env().credentials_provider(base_provider),
-        _ => {
-            let assume_roles = maybe_role.iter().chain(maybe_regional_role.iter()).cloned();
-            let provider =
-                build_provider(sts_region, assume_roles.clone(), base_provider.clone()).await;
-            aws_config::from_env().credentials_provider(provider)
-        }
+    let assume_role_config = pubsys_aws_config.assume_role.clone().unwrap_or_default();
+    let (maybe_profile, maybe_role) = match pubsys_aws_config.partition_config(region.as_ref()) {
+        Some(partition) => (
+            Some(partition.profile.clone()),
+            partition.role.clone().map(|role| {
+                let options = partition.assume_role.clone().unwrap_or_default();
+                AssumeRole::new(role, options, &assume_role_config)
+            }),
+        ),
+        None => (
+            pubsys_aws_config.profile.clone(),
+            pubsys_aws_config
+                .role
+                .clone()
+                .map(|role| AssumeRole::new(role, assume_role_config.clone(), &assume_role_config)),
+        ),
     };
+    // Credentials can't be used outside their partition, including with STS.
+    let sts_region =
+        if partition_for_region(sts_region.as_ref()) == partition_for_region(region.as_ref()) {
+            sts_region
+        } else {
+            region
+        };
+    // A region can have its own role, like one in the account that manages an opt-in region.
+    let maybe_regional_role = pubsys_aws_config.region.get(region.as_ref()).and_then(|r| {
+        r.role
+            .clone()
+            .map(|role| AssumeRole::new(role, r.assume_role(), &assume_role_config))
+    });
+    let assume_roles = maybe_role.into_iter().chain(maybe_regional_role);
+    let provider = build_provider(
+        region,
+        sts_region,
+        &maybe_profile,
+        assume_roles,
+        pubsys_aws_config,
+    )
+    .await;
+
+    let mut config = aws_config::from_env()
+        .credentials_provider(provider)
+        .region(region.clone());
+    if let Some(retry) = &pubsys_aws_config.retry {
+        config = config.retry_config(retry_config(retry));
+    }
+    if let Some(timeout) = &pubsys_aws_config.timeout {
+        config = config.timeout_config(timeout_config(timeout));
+    }
+    if let Some(app_name) = app_name(pubsys_aws_config) {
+        config = config.app_name(app_name);
+    }
+    if let Some(connector) = proxy::http_connector(pubsys_aws_config) {
+        config = config.http_connector(connector);
+    }
+    config.load().await
+}
 
-    config.region(region.clone()).load().await
+/// Returns the app name from the pubsys config in the form the SDK takes it.  The name only
+/// labels requests, so if it's invalid we warn rather than failing the run.
+pub(crate) fn app_name(pubsys_aws_config: &PubsysAwsConfig) -> Option<AppName> {
+    let name = pubsys_aws_config.app_name.as_ref()?;
+    match AppName::new(name.clone()) {
+        Ok(app_name) => Some(app_name),
+        Err(e) => {
+            warn!("Not adding app name '{}' to AWS requests: {}", name, e);
+            None
+        }
+    }
 }
 
-/// Chains credentials providers to assume the given roles in order.
-/// The region given should be the one in which you want to talk to STS to get temporary
+/// Builds the SDK's retry config from the retry settings in the pubsys config, so every client
+/// retries the same way.  Settings that aren't given keep the SDK's defaults.
+fn retry_config(retry: &AwsRetryConfig) -> RetryConfig {
+    let mut builder = RetryConfigBuilder::new();
+    builder
+        .set_max_attempts(retry.max_attempts.map(NonZeroU32::get))
+        .set_mode(retry.mode.map(|mode| match mode {
+            AwsRetryMode::Standard => RetryMode::Standard,
+            AwsRetryMode::Adaptive => RetryMode::Adaptive,
+        }))
+        .set_initial_backoff(retry.initial_backoff_ms.map(Duration::from_millis));
+    builder.build()
+}
+
+/// Builds the SDK's timeout config from the timeouts in the pubsys config.  Timeouts that aren't
+/// given are left unset, as they are by default.
+fn timeout_config(timeout: &AwsTimeoutConfig) -> TimeoutConfig {
+    let millis = |ms: Option<NonZeroU64>| ms.map(|ms| Duration::from_millis(ms.get()));
+    let mut builder = TimeoutConfig::builder();
+    builder
+        .set_connect_timeout(millis(timeout.connect_timeout_ms))
+        .set_read_timeout(millis(timeout.read_timeout_ms))
+        .set_operation_timeout(millis(timeout.operation_timeout_ms));
+    builder.build()
+}
+
+/// Chains credentials providers to assume the given roles in order, starting from the given
+/// profile or the default credentials.  Each step of the chain shares its credentials with every
+/// other chain that starts the same way in the same partition.
+/// The STS region given should be the one in which you want to talk to STS to get temporary
 /// credentials, not the region in which you want to talk to a service endpoint like EC2.  This is
 /// needed because you may be assuming a role in an opt-in region from an account that has not
 /// opted-in to that region, and you need to get session credentials from an STS endpoint in a
 /// region to which you have access in the base account
 async fn build_provider(
+    region: &Region,
     sts_region: &Region,
-    assume_roles: impl Iterator<Item = String>,
-    base_provider: SharedCredentialsProvider,
+    maybe_profile: &Option<String>,
+    assume_roles: impl Iterator<Item = AssumeRole>,
+    pubsys_aws_config: &PubsysAwsConfig,
 ) -> SharedCredentialsProvider {
-    let mut provider = base_provider;
+    let mut key = CredentialsKey {
+        partition: partition_for_region(region.as_ref()),
+        profile: maybe_profile.clone(),
+        roles: Vec::new(),
+    };
+    let provider_config = proxy::provider_config(pubsys_aws_config);
+    let mut provider = CachedProvider::shared(
+        key.clone(),
+        base_provider(maybe_profile, &provider_config).await,
+    );
     for assume_role in assume_roles {
-        provider = SharedCredentialsProvider::new(
-            AssumeRoleProvider::builder(assume_role)
+        key.roles.push(assume_role.clone());
+        // The SDK's provider can't tag sessions, send MFA codes, or use STS's FIPS endpoints, so we
+        // call STS ourselves when those are needed.
+        if assume_role.session_tags.is_empty()
+            && assume_role.mfa_serial.is_none()
+            && !pubsys_aws_config.use_fips_endpoints
+        {
+            let mut builder = AssumeRoleProvider::builder(assume_role.role)
+                .configure(&provider_config)
                 .region(sts_region.clone())
-                .session_name("pubsys")
-                .build(provider.clone()),
-        )
+                .session_name(assume_role.session_name);
+            if let Some(external_id) = assume_role.external_id {
+                builder = builder.external_id(external_id);
+            }
+            let role_provider = SharedCredentialsProvider::new(builder.build(provider.clone()));
+            provider = CachedProvider::shared(key.clone(), role_provider);
+        } else {
+            let mut sts_config = aws_config::from_env()
+                .region(sts_region.clone())
+                .credentials_provider(provider.clone())
+                .use_fips(pubsys_aws_config.use_fips_endpoints);
+            if let Some(app_name) = app_name(pubsys_aws_config) {
+                sts_config = sts_config.app_name(app_name);
+            }
+            if let Some(connector) = proxy::http_connector(pubsys_aws_config) {
+                sts_config = sts_config.http_connector(connector);
+            }
+            let sts_config = sts_config.load().await;
+            let role_provider = SharedCredentialsProvider::new(SessionProvider {
+                sts_client: StsClient::from_pubsys_config(&sts_config, pubsys_aws_config),
+                assume_role,
+            });
+            provider = CachedProvider::shared(key.clone(), role_provider);
+        }
     }
     provider
 }
 
+/// Everything that decides the credentials a chain of providers gives; chains with the same key
+/// share credentials.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CredentialsKey {
+    /// Credentials can't be used outside their partition
+    partition: &'static str,
+    profile: Option<String>,
+    /// The roles assumed, in order
+    roles: Vec<AssumeRole>,
+}
+
+/// Gives the shared credentials for its key, fetching them from the wrapped provider only if no
+/// other client has, or if they're about to expire.
+#[derive(Debug)]
+struct CachedProvider {
+    key: CredentialsKey,
+    inner: SharedCredentialsProvider,
+}
+
+impl CachedProvider {
+    fn shared(key: CredentialsKey, inner: SharedCredentialsProvider) -> SharedCredentialsProvider {
+        SharedCredentialsProvider::new(Self { key, inner })
+    }
+
+    async fn credentials(&self) -> provider::Result {
+        let slot = CREDENTIALS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(self.key.clone())
+            .or_default()
+            .clone();
+        // Hold the key's lock while fetching, so that other clients wait for these credentials
+        // rather than fetching their own, or prompting for their own MFA code.
+        let mut cached = slot.lock().await;
+        if let Some(credentials) = cached.as_ref().filter(|c| is_fresh(c)) {
+            return Ok(credentials.clone());
+        }
+        let credentials = self.inner.provide_credentials().await?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
+
+impl ProvideCredentials for CachedProvider {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(self.credentials())
+    }
+}
+
+/// Returns whether the given credentials can still be used for a while.
+fn is_fresh(credentials: &Credentials) -> bool {
+    credentials
+        .expiry()
+        .map(|expiry| expiry > SystemTime::now() + SESSION_EXPIRY_BUFFER)
+        .unwrap_or(true)
+}
+
+/// Assumes a role with session tags, an MFA code, or STS's FIPS endpoints, which the SDK's
+/// AssumeRoleProvider doesn't support.
+#[derive(Debug)]
+struct SessionProvider {
+    sts_client: StsClient,
+    assume_role: AssumeRole,
+}
+
+impl SessionProvider {
+    async fn credentials(&self) -> provider::Result {
+        let token_code = match &self.assume_role.mfa_serial {
+            Some(mfa_serial) => {
+                Some(prompt_mfa_code(mfa_serial).map_err(CredentialsError::provider_error)?)
+            }
+            None => None,
+        };
+        let tags = self
+            .assume_role
+            .session_tags
+            .iter()
+            .map(|(key, value)| Tag::builder().key(key).value(value).build())
+            .collect::<Vec<_>>();
+        let output = self
+            .sts_client
+            .assume_role()
+            .role_arn(&self.assume_role.role)
+            .role_session_name(&self.assume_role.session_name)
+            .set_external_id(self.assume_role.external_id.clone())
+            .set_tags((!tags.is_empty()).then_some(tags))
+            .set_serial_number(self.assume_role.mfa_serial.clone())
+            .set_token_code(token_code)
+            .send()
+            .await
+            .map_err(CredentialsError::provider_error)?;
+
+        let session = output
+            .credentials()
+            .ok_or_else(|| CredentialsError::unhandled("STS AssumeRole returned no credentials"))?;
+        Ok(Credentials::new(
+            session.access_key_id().unwrap_or_default(),
+            session.secret_access_key().unwrap_or_default(),
+            session.session_token().map(str::to_string),
+            session
+                .expiration()
+                .and_then(|expiration| SystemTime::try_from(*expiration).ok()),
+            "pubsys",
+        ))
+    }
+}
+
+impl ProvideCredentials for SessionProvider {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(self.credentials())
+    }
+}
+
+/// Prompts on the terminal for a code from the given MFA device.
+fn prompt_mfa_code(mfa_serial: &str) -> io::Result<String> {
+    eprint!("Enter the MFA code for {}: ", mfa_serial);
+    io::stderr().flush()?;
+    let mut code = String::new();
+    io::stdin().read_line(&mut code)?;
+    let code = code.trim().to_string();
+    if code.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("no MFA code given for {}", mfa_serial),
+        ));
+    }
+    Ok(code)
+}
+
 /// If the user specified a profile, use that, otherwise use the default
-/// credentials mechanisms.
-async fn base_provider(maybe_profile: &Option<String>) -> SharedCredentialsProvider {
+/// credentials mechanisms.  Profiles are read from ~/.aws/config as well as ~/.aws/credentials, so
+/// they can use IAM Identity Center (SSO), after `aws sso login`, or a `credential_process`, rather
+/// than long-lived access keys.  The given provider config holds the connector they use for STS
+/// and SSO.
+async fn base_provider(
+    maybe_profile: &Option<String>,
+    provider_config: &ProviderConfig,
+) -> SharedCredentialsProvider {
     if let Some(profile) = maybe_profile {
         SharedCredentialsProvider::new(
             ProfileFileCredentialsProvider::builder()
+                .configure(provider_config)
                 .profile_name(profile)
                 .build(),
         )
     } else {
-        SharedCredentialsProvider::new(default_provider().await)
+        SharedCredentialsProvider::new(
+            DefaultCredentialsChain::builder()
+                .configure(provider_config.clone())
+                .build()
+                .await,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_fresh, AssumeRole, DEFAULT_SESSION_NAME, SESSION_EXPIRY_BUFFER};
+    use aws_credential_types::Credentials;
+    use pubsys_config::AwsAssumeRoleConfig;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn assume_role_options() {
+        let role = "arn:aws-cn:iam::111122223333:role/publish".to_string();
+        let defaults = AwsAssumeRoleConfig {
+            external_id: Some("global-id".to_string()),
+            session_name: Some("release".to_string()),
+            session_tags: HashMap::from([("team".to_string(), "os".to_string())]),
+            mfa_serial: Some("arn:aws:iam::444455556666:mfa/me".to_string()),
+        };
+
+        // Only the session name and tags carry over to roles with their own options.
+        let assume_role = AssumeRole::new(role.clone(), AwsAssumeRoleConfig::default(), &defaults);
+        assert_eq!(assume_role.external_id, None);
+        assert_eq!(assume_role.mfa_serial, None);
+        assert_eq!(assume_role.session_name, "release");
+        assert_eq!(assume_role.session_tags["team"], "os");
+
+        let options = AwsAssumeRoleConfig {
+            external_id: Some("china-id".to_string()),
+            session_name: Some("china-release".to_string()),
+            session_tags: HashMap::from([("team".to_string(), "china".to_string())]),
+            mfa_serial: None,
+        };
+        let assume_role = AssumeRole::new(role.clone(), options, &defaults);
+        assert_eq!(assume_role.external_id.as_deref(), Some("china-id"));
+        assert_eq!(assume_role.session_name, "china-release");
+        assert_eq!(assume_role.session_tags["team"], "china");
+
+        let assume_role = AssumeRole::new(
+            role,
+            AwsAssumeRoleConfig::default(),
+            &AwsAssumeRoleConfig::default(),
+        );
+        assert_eq!(assume_role.session_name, DEFAULT_SESSION_NAME);
+    }
+
+    #[test]
+    fn credentials_freshness() {
+        let expiring = |after: Duration| {
+            Credentials::new(
+                "AKID",
+                "secret",
+                None,
+                Some(SystemTime::now() + after),
+                "test",
+            )
+        };
+        assert!(is_fresh(&expiring(Duration::from_secs(3600))));
+        // Credentials about to expire are fetched again rather than shared.
+        assert!(!is_fresh(&expiring(SESSION_EXPIRY_BUFFER / 2)));
+        // Long-lived keys never expire.
+        assert!(is_fresh(&Credentials::new(
+            "AKID", "secret", None, None, "test"
+        )));
     }
 }
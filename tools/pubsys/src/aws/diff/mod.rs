@@ -0,0 +1,503 @@
+//! The diff module owns the 'diff' subcommand, which compares two AMI data files, like the
+//! amis.json written by the 'ami' subcommand, or two expected parameters files, like the ones
+//! given to 'validate-ssm', and prints what was added, removed, or changed in each region.
+//!
+//! Given one file, it's compared against what's live in AWS: the AMIs with the file's IDs, or the
+//! parameters with the file's names.  Only what the file records is compared, so parameters that
+//! exist in AWS but aren't in the file aren't listed.
+
+use crate::aws::ami::launch_permissions::get_launch_permissions;
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::region_from_string;
+use crate::aws::ssm::{ssm, SsmKey};
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use log::{info, trace};
+use pubsys_config::AwsConfig;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+
+/// The compared values of each region, by name, like a parameter name or "image_id"
+type Entries = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Compares AMI data or expected parameters files with each other or with live state
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct DiffArgs {
+    /// The file to compare from
+    #[structopt(parse(from_os_str))]
+    from: PathBuf,
+
+    /// The file to compare to; if not given, the first file is compared to live state in AWS
+    #[structopt(parse(from_os_str))]
+    to: Option<PathBuf>,
+
+    /// What the files hold, "amis" or "parameters"; by default, it's found from their contents
+    #[structopt(long)]
+    kind: Option<Kind>,
+
+    /// Print the differences as JSON instead of a table
+    #[structopt(long)]
+    json: bool,
+}
+
+/// What a compared file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// AMI data, a map of region to image, like amis.json
+    Amis,
+    /// Expected parameters, a map of region to parameter names and values
+    Parameters,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "amis" => Ok(Kind::Amis),
+            "parameters" => Ok(Kind::Parameters),
+            _ => Err(format!("unknown kind '{}'; use 'amis' or 'parameters'", s)),
+        }
+    }
+}
+
+/// How an entry differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One difference
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Difference {
+    region: String,
+    name: String,
+    change: Change,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Tabled)]
+struct DifferenceRow {
+    region: String,
+    name: String,
+    change: String,
+    from: String,
+    to: String,
+}
+
+impl From<&Difference> for DifferenceRow {
+    fn from(difference: &Difference) -> Self {
+        Self {
+            region: difference.region.clone(),
+            name: difference.name.clone(),
+            change: serde_plain::to_string(&difference.change).unwrap_or_default(),
+            from: difference.from.clone().unwrap_or_default(),
+            to: difference.to.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Reads the file, finding what it holds if the kind isn't given.
+fn read(path: &Path, kind: Option<Kind>) -> Result<(Kind, serde_json::Value)> {
+    let value: serde_json::Value =
+        serde_json::from_reader(File::open(path).context(error::ReadFileSnafu { path })?)
+            .context(error::ParseFileSnafu { path })?;
+    let kind = match kind {
+        Some(kind) => kind,
+        // Both have an object for each region, but only AMI data has an image ID in it.
+        None => value
+            .as_object()
+            .and_then(|regions| regions.values().next())
+            .and_then(|region| region.as_object())
+            .map(|region| {
+                if region.contains_key("id") {
+                    Kind::Amis
+                } else {
+                    Kind::Parameters
+                }
+            })
+            .context(error::UnknownKindSnafu { path })?,
+    };
+    Ok((kind, value))
+}
+
+/// Returns the compared values of the given AMI data.
+fn ami_entries(images: &BTreeMap<String, Image>) -> Entries {
+    images
+        .iter()
+        .map(|(region, image)| {
+            let mut entries = BTreeMap::new();
+            entries.insert("image_id".to_string(), image.id.clone());
+            entries.insert("name".to_string(), image.name.clone());
+            if let Some(public) = image.public {
+                entries.insert("public".to_string(), public.to_string());
+            }
+            if let Some(launch_permissions) = &image.launch_permissions {
+                let mut permissions: Vec<String> = launch_permissions
+                    .iter()
+                    .filter_map(|permission| serde_json::to_string(permission).ok())
+                    .collect();
+                permissions.sort();
+                entries.insert("launch_permissions".to_string(), permissions.join(", "));
+            }
+            (region.clone(), entries)
+        })
+        .collect()
+}
+
+/// Parses the given file contents into the compared values.
+fn entries(kind: Kind, value: serde_json::Value, path: &Path) -> Result<Entries> {
+    match kind {
+        Kind::Amis => {
+            let images: BTreeMap<String, Image> =
+                serde_json::from_value(value).context(error::ParseFileSnafu { path })?;
+            Ok(ami_entries(&images))
+        }
+        Kind::Parameters => serde_json::from_value(value).context(error::ParseFileSnafu { path }),
+    }
+}
+
+/// Lists the differences between the two sets of entries, by region and name.
+fn differences(from: &Entries, to: &Entries) -> Vec<Difference> {
+    let empty = BTreeMap::new();
+    let mut differences = Vec::new();
+    let mut regions: Vec<&String> = from.keys().chain(to.keys()).collect();
+    regions.sort();
+    regions.dedup();
+    for region in regions {
+        let from_entries = from.get(region).unwrap_or(&empty);
+        let to_entries = to.get(region).unwrap_or(&empty);
+        let mut names: Vec<&String> = from_entries.keys().chain(to_entries.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let from_value = from_entries.get(name);
+            let to_value = to_entries.get(name);
+            let change = match (from_value, to_value) {
+                (Some(a), Some(b)) if a == b => continue,
+                (Some(_), Some(_)) => Change::Changed,
+                (Some(_), None) => Change::Removed,
+                (None, _) => Change::Added,
+            };
+            differences.push(Difference {
+                region: region.clone(),
+                name: name.clone(),
+                change,
+                from: from_value.cloned(),
+                to: to_value.cloned(),
+            });
+        }
+    }
+    differences
+}
+
+/// Fetches the live state of what the given entries record.
+async fn live_entries(aws: &AwsConfig, kind: Kind, recorded: &Entries) -> Result<Entries> {
+    let base_region = region_from_string(aws.regions_for("diff").get(0).context(
+        error::MissingConfigSnafu {
+            missing: "aws.regions",
+        },
+    )?);
+
+    let rate_limits = RateLimits::new(aws);
+    let mut live = Entries::new();
+    match kind {
+        Kind::Amis => {
+            for (region_name, recorded_entries) in recorded {
+                let region = region_from_string(region_name);
+                let client_config = build_client_config(&region, &base_region, aws).await;
+                let ec2_client = Ec2Client::from_pubsys_config(&client_config, aws);
+                let image_id = &recorded_entries["image_id"];
+                if let Some(image) = live_image(
+                    &ec2_client,
+                    &rate_limits,
+                    &region,
+                    image_id,
+                    recorded_entries,
+                )
+                .await?
+                {
+                    live.insert(region_name.clone(), ami_entries_of(&image, region_name));
+                }
+            }
+        }
+        Kind::Parameters => {
+            let mut ssm_clients = HashMap::with_capacity(recorded.len());
+            let mut keys = Vec::new();
+            for (region_name, recorded_entries) in recorded {
+                let region = region_from_string(region_name);
+                let client_config = build_client_config(&region, &base_region, aws).await;
+                ssm_clients.insert(
+                    region.clone(),
+                    SsmClient::from_pubsys_config(&client_config, aws),
+                );
+                keys.extend(
+                    recorded_entries
+                        .keys()
+                        .map(|name| SsmKey::new(region.clone(), name.clone())),
+                );
+            }
+            info!("Fetching {} parameters", keys.len());
+            for (key, value) in ssm::get_parameters(&keys, &ssm_clients, &rate_limits)
+                .await
+                .context(error::FetchSsmSnafu)?
+            {
+                live.entry(key.region.to_string())
+                    .or_default()
+                    .insert(key.name, value);
+            }
+        }
+    }
+    Ok(live)
+}
+
+/// Returns the compared values of one image.
+fn ami_entries_of(image: &Image, region: &str) -> BTreeMap<String, String> {
+    ami_entries(&BTreeMap::from([(region.to_string(), image.clone())]))
+        .remove(region)
+        .unwrap_or_default()
+}
+
+/// Describes the AMI with the given ID, if it still exists, fetching the fields the recorded
+/// entries have.
+async fn live_image(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &Region,
+    image_id: &str,
+    recorded: &BTreeMap<String, String>,
+) -> Result<Option<Image>> {
+    info!("Describing {} in {}", image_id, region);
+    let response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .describe_images()
+                .image_ids(image_id)
+                .include_deprecated(true)
+                .send(),
+        )
+        .await
+        .context(error::DescribeImagesSnafu {
+            image_id,
+            region: region.as_ref(),
+        })?;
+    let image = match response.images().unwrap_or_default().first() {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+    let launch_permissions = if recorded.contains_key("launch_permissions") {
+        Some(
+            get_launch_permissions(ec2_client, rate_limits, region.as_ref(), image_id)
+                .await
+                .context(error::LaunchPermissionsSnafu {
+                    image_id,
+                    region: region.as_ref(),
+                })?,
+        )
+    } else {
+        None
+    };
+    Ok(Some(Image {
+        id: image_id.to_string(),
+        name: image.name().unwrap_or_default().to_string(),
+        public: image.public().filter(|_| recorded.contains_key("public")),
+        launch_permissions,
+        lineage: None,
+    }))
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, diff_args: &DiffArgs) -> Result<()> {
+    let (kind, from_value) = read(&diff_args.from, diff_args.kind)?;
+    let from = entries(kind, from_value, &diff_args.from)?;
+
+    let to = match &diff_args.to {
+        Some(to_path) => {
+            let (to_kind, to_value) = read(to_path, diff_args.kind)?;
+            ensure!(
+                to_kind == kind,
+                error::MismatchedKindsSnafu {
+                    from: &diff_args.from,
+                    to: to_path
+                }
+            );
+            entries(kind, to_value, to_path)?
+        }
+        None => {
+            // If a lock file exists, use that, otherwise use Infra.toml
+            let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+            trace!("Parsed infra config: {:#?}", infra_config);
+            let aws = infra_config.aws.unwrap_or_default();
+            live_entries(&aws, kind, &from).await?
+        }
+    };
+
+    let differences = differences(&from, &to);
+    if diff_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&differences).context(error::SerializeSnafu)?
+        );
+    } else if differences.is_empty() {
+        info!("No differences");
+    } else {
+        println!(
+            "{}",
+            Table::new(differences.iter().map(DifferenceRow::from))
+        );
+    }
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::DescribeImagesError;
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to describe {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeImages {
+            image_id: String,
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("Failed to fetch SSM parameters: {}", source))]
+        FetchSsm { source: crate::aws::ssm::ssm::Error },
+
+        #[snafu(display(
+            "Failed to get launch permissions of {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        LaunchPermissions {
+            image_id: String,
+            region: String,
+            source: crate::aws::ami::launch_permissions::Error,
+        },
+
+        #[snafu(display(
+            "'{}' and '{}' hold different kinds of data; give --kind to compare them anyway",
+            from.display(),
+            to.display()
+        ))]
+        MismatchedKinds { from: PathBuf, to: PathBuf },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to parse '{}': {}", path.display(), source))]
+        ParseFile {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        ReadFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize differences: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display(
+            "Can't tell whether '{}' holds AMIs or parameters; give --kind",
+            path.display()
+        ))]
+        UnknownKind { path: PathBuf },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{differences, entries, read, Change, Kind};
+    use std::io::Write;
+
+    #[test]
+    fn finds_kind() {
+        let mut amis = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            amis,
+            r#"{{"us-west-2": {{"id": "ami-1", "name": "a", "public": false}}}}"#
+        )
+        .unwrap();
+        let (kind, value) = read(amis.path(), None).unwrap();
+        assert_eq!(kind, Kind::Amis);
+        let amis_entries = entries(kind, value, amis.path()).unwrap();
+        assert_eq!(amis_entries["us-west-2"]["image_id"], "ami-1");
+        assert_eq!(amis_entries["us-west-2"]["public"], "false");
+        assert!(!amis_entries["us-west-2"].contains_key("launch_permissions"));
+
+        let mut parameters = tempfile::NamedTempFile::new().unwrap();
+        write!(parameters, r#"{{"us-west-2": {{"/a/image_id": "ami-1"}}}}"#).unwrap();
+        assert_eq!(read(parameters.path(), None).unwrap().0, Kind::Parameters);
+    }
+
+    #[test]
+    fn lists_differences() {
+        let from = serde_json::from_str(
+            r#"{"us-west-2": {"/a": "1", "/b": "2"}, "us-east-1": {"/a": "1"}}"#,
+        )
+        .unwrap();
+        let to =
+            serde_json::from_str(r#"{"us-west-2": {"/a": "1", "/b": "3", "/c": "4"}}"#).unwrap();
+        let differences = differences(&from, &to);
+        let summary: Vec<(&str, &str, Change)> = differences
+            .iter()
+            .map(|d| (d.region.as_str(), d.name.as_str(), d.change))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("us-east-1", "/a", Change::Removed),
+                ("us-west-2", "/b", Change::Changed),
+                ("us-west-2", "/c", Change::Added),
+            ]
+        );
+        assert_eq!(differences[1].from.as_deref(), Some("2"));
+        assert_eq!(differences[1].to.as_deref(), Some("3"));
+    }
+}
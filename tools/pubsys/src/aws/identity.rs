@@ -0,0 +1,133 @@
+//! The identity module checks which AWS account and principal pubsys will act as, before any
+//! subcommand runs, so that a misconfigured profile or role can't publish into the wrong account.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::region_from_string;
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_sdk_sts::Client as StsClient;
+use log::info;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{ensure, OptionExt, ResultExt};
+
+/// The region in which we call STS if no regions are configured
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// An AWS account and the principal acting in it
+#[derive(Debug)]
+struct Identity {
+    account: String,
+    arn: String,
+}
+
+/// Logs the account and principal of the configured credentials, and of the publishing account's
+/// credentials, if any.  If `--expected-account` was given, fails unless the configured credentials
+/// are for that account.
+pub(crate) async fn verify(args: &Args) -> Result<()> {
+    // Subcommands that work without an infra config use the default credentials, so we do too.
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
+    let aws = infra_config.aws.unwrap_or_default();
+
+    let identity = caller_identity(&aws).await?;
+    info!(
+        "AWS credentials are for {} in account {}",
+        identity.arn, identity.account
+    );
+    if let Some(publishing_aws) = aws.publishing_config() {
+        let publishing_identity = caller_identity(&publishing_aws).await?;
+        info!(
+            "Publishing account credentials are for {} in account {}",
+            publishing_identity.arn, publishing_identity.account
+        );
+    }
+
+    if let Some(expected_account) = &args.expected_account {
+        ensure!(
+            &identity.account == expected_account,
+            error::UnexpectedAccountSnafu {
+                expected: expected_account,
+                actual: identity.account,
+                arn: identity.arn,
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Calls STS GetCallerIdentity with the given config's credentials, in its first region.
+async fn caller_identity(pubsys_aws_config: &PubsysAwsConfig) -> Result<Identity> {
+    let region = region_from_string(
+        pubsys_aws_config
+            .regions
+            .front()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_REGION),
+    );
+    let client_config = build_client_config(&region, &region, pubsys_aws_config).await;
+    let response = StsClient::from_pubsys_config(&client_config, pubsys_aws_config)
+        .get_caller_identity()
+        .send()
+        .await
+        .context(error::GetCallerIdentitySnafu {
+            region: region.as_ref(),
+        })?;
+    Ok(Identity {
+        account: response
+            .account
+            .context(error::MissingInResponseSnafu { missing: "account" })?,
+        arn: response
+            .arn
+            .context(error::MissingInResponseSnafu { missing: "arn" })?,
+    })
+}
+
+mod error {
+    use aws_sdk_sts::error::GetCallerIdentityError;
+    use aws_sdk_sts::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to get caller identity in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        GetCallerIdentity {
+            region: String,
+            source: SdkError<GetCallerIdentityError>,
+        },
+
+        #[snafu(display("Response to GetCallerIdentity was missing {}", missing))]
+        MissingInResponse { missing: String },
+
+        #[snafu(display(
+            "AWS credentials are for {} in account {}, not the expected account {}",
+            arn,
+            actual,
+            expected
+        ))]
+        UnexpectedAccount {
+            expected: String,
+            actual: String,
+            arn: String,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::GetCallerIdentity { .. } => Some(Kind::AwsApi),
+            Self::UnexpectedAccount { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
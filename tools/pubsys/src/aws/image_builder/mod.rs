@@ -0,0 +1,476 @@
+//! The image_builder module owns the 'image-builder' subcommand, which points EC2 Image Builder
+//! at newly published AMIs, for customers who build their own images on top of ours.  In each
+//! region, it registers an image recipe whose parent image is the region's AMI, with the version
+//! as the recipe version, then creates the image pipeline that builds from the recipe, or updates
+//! it to use the new recipe.
+//!
+//! Recipes are immutable, so running this again for the same version reuses the recipe registered
+//! the first time.
+
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::region_from_string;
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_config::SdkConfig;
+use aws_sdk_ec2::Region;
+use aws_sdk_imagebuilder::model::{
+    ComponentConfiguration, Filter, ImageRecipeSummary, Ownership, PipelineStatus, Schedule,
+};
+use aws_sdk_imagebuilder::Client as ImageBuilderClient;
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
+use pubsys_config::{AwsConfig as PubsysAwsConfig, AwsImageBuilderConfig};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::iter::FromIterator;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// Regions are independent, so we can work on all of them at once.
+const MAX_PARALLEL_REGIONS: usize = 32;
+
+/// Registers or updates EC2 Image Builder recipes and pipelines for published AMIs
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ImageBuilderArgs {
+    /// Path to the JSON file containing regional AMI IDs
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// The variant of the AMIs
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture of the AMIs
+    #[structopt(long)]
+    arch: String,
+
+    /// The version of the AMIs; recipe versions must be like "1.13.0"
+    #[structopt(long)]
+    version: String,
+
+    /// The name of the image recipe; defaults to "bottlerocket-{variant}-{arch}"
+    #[structopt(long)]
+    recipe_name: Option<String>,
+
+    /// The name of the image pipeline; defaults to the recipe name
+    #[structopt(long)]
+    pipeline_name: Option<String>,
+
+    /// Comma-separated list of regions to update, overriding Infra.toml; given regions must be
+    /// in the --ami-input file
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+}
+
+/// What to register in one region
+struct RegionTarget<'a> {
+    region: Region,
+    client_config: SdkConfig,
+    image: Image,
+    recipe_name: &'a str,
+    pipeline_name: &'a str,
+    version: &'a str,
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, image_builder_args: &ImageBuilderArgs) -> Result<()> {
+    info!(
+        "Using AMI data from path: {}",
+        image_builder_args.ami_input.display()
+    );
+    let file = File::open(&image_builder_args.ami_input).context(error::FileSnafu {
+        path: &image_builder_args.ami_input,
+    })?;
+    let mut ami_input: HashMap<String, Image> =
+        serde_json::from_reader(file).context(error::DeserializeSnafu {
+            path: &image_builder_args.ami_input,
+        })?;
+    trace!("Parsed AMI input: {:?}", ami_input);
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_default();
+    let image_builder = aws
+        .image_builder
+        .clone()
+        .context(error::MissingConfigSnafu {
+            missing: "aws.image_builder",
+        })?;
+    ensure!(
+        !image_builder.components.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.image_builder.components"
+        }
+    );
+    ensure!(
+        image_builder.infrastructure_configuration.is_some(),
+        error::MissingConfigSnafu {
+            missing: "aws.image_builder.infrastructure_configuration"
+        }
+    );
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !image_builder_args.regions.is_empty() {
+        image_builder_args.regions.clone()
+    } else {
+        aws.regions_for("image_builder").clone().into()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = region_from_string(&regions[0]);
+
+    let requested_regions = HashSet::from_iter(regions.iter());
+    let known_regions = HashSet::<&String>::from_iter(ami_input.keys());
+    ensure!(
+        requested_regions.is_subset(&known_regions),
+        error::UnknownRegionsSnafu {
+            regions: requested_regions
+                .difference(&known_regions)
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+        }
+    );
+
+    let version = recipe_version(&image_builder_args.version)?;
+    let recipe_name = image_builder_args.recipe_name.clone().unwrap_or_else(|| {
+        format!(
+            "bottlerocket-{}-{}",
+            image_builder_args.variant, image_builder_args.arch
+        )
+    });
+    let pipeline_name = image_builder_args
+        .pipeline_name
+        .clone()
+        .unwrap_or_else(|| recipe_name.clone());
+
+    let mut targets = Vec::with_capacity(regions.len());
+    for name in regions {
+        let image = ami_input
+            .remove(&name)
+            .with_context(|| error::UnknownRegionsSnafu {
+                regions: vec![name.clone()],
+            })?;
+        let region = region_from_string(&name);
+        let client_config = build_client_config(&region, &base_region, &aws).await;
+        targets.push(RegionTarget {
+            region,
+            client_config,
+            image,
+            recipe_name: &recipe_name,
+            pipeline_name: &pipeline_name,
+            version: &version,
+        });
+    }
+
+    info!(
+        "Pointing image pipeline '{}' at version {} in {} regions",
+        pipeline_name,
+        version,
+        targets.len()
+    );
+    let requests = targets.iter().map(|target| {
+        let request = args.state.tracer.traced(
+            "image_builder",
+            Some(target.region.as_ref()),
+            update_region(target, &aws, &image_builder),
+        );
+        (target.region.to_string(), request)
+    });
+    let results: Vec<Result<()>> =
+        stream::iter(args.state.interrupt.tracked("image pipelines", requests))
+            .buffer_unordered(MAX_PARALLEL_REGIONS)
+            .collect()
+            .await;
+
+    let mut failed = 0;
+    for result in results {
+        if let Err(e) = result {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
+    ensure!(failed == 0, error::FailedRegionsSnafu { count: failed });
+    Ok(())
+}
+
+/// Registers the recipe in the target's region, unless it exists, then creates or updates the
+/// pipeline to use it.
+async fn update_region(
+    target: &RegionTarget<'_>,
+    pubsys_aws_config: &PubsysAwsConfig,
+    image_builder: &AwsImageBuilderConfig,
+) -> Result<()> {
+    let region = target.region.as_ref();
+    let client = ImageBuilderClient::from_pubsys_config(&target.client_config, pubsys_aws_config);
+    let name_filter = |name: &str| Filter::builder().name("name").values(name).build();
+
+    let recipes = client
+        .list_image_recipes()
+        .owner(Ownership::Self_)
+        .filters(name_filter(target.recipe_name))
+        .send()
+        .await
+        .context(error::ListImageRecipesSnafu { region })?;
+    let recipe_arn = match find_recipe_arn(
+        recipes.image_recipe_summary_list().unwrap_or_default(),
+        target.version,
+    ) {
+        Some(arn) => {
+            info!("Using existing image recipe {} in {}", arn, region);
+            arn
+        }
+        None => {
+            let components = image_builder
+                .components
+                .iter()
+                .map(|arn| {
+                    ComponentConfiguration::builder()
+                        .component_arn(regional_arn(arn, region))
+                        .build()
+                })
+                .collect();
+            // The SDK fills in the client token, so retries don't register the recipe twice.
+            let response = client
+                .create_image_recipe()
+                .name(target.recipe_name)
+                .semantic_version(target.version)
+                .description(format!("Built on {}", target.image.name))
+                .parent_image(&target.image.id)
+                .set_components(Some(components))
+                .send()
+                .await
+                .context(error::CreateImageRecipeSnafu { region })?;
+            let arn = response
+                .image_recipe_arn
+                .context(error::MissingResponseFieldSnafu {
+                    action: "CreateImageRecipe",
+                    field: "imageRecipeArn",
+                    region,
+                })?;
+            info!("Created image recipe {} in {}", arn, region);
+            arn
+        }
+    };
+
+    let infrastructure_configuration = image_builder
+        .infrastructure_configuration
+        .as_deref()
+        .map(|arn| regional_arn(arn, region));
+    let schedule = image_builder
+        .schedule
+        .as_ref()
+        .map(|schedule| Schedule::builder().schedule_expression(schedule).build());
+
+    let pipelines = client
+        .list_image_pipelines()
+        .filters(name_filter(target.pipeline_name))
+        .send()
+        .await
+        .context(error::ListImagePipelinesSnafu { region })?;
+    match pipelines
+        .image_pipeline_list()
+        .and_then(|list| list.first())
+        .and_then(|pipeline| pipeline.arn())
+    {
+        Some(pipeline_arn) => {
+            client
+                .update_image_pipeline()
+                .image_pipeline_arn(pipeline_arn)
+                .image_recipe_arn(&recipe_arn)
+                .set_infrastructure_configuration_arn(infrastructure_configuration)
+                .set_schedule(schedule)
+                .send()
+                .await
+                .context(error::UpdateImagePipelineSnafu { region })?;
+            info!("Updated image pipeline {} in {}", pipeline_arn, region);
+        }
+        None => {
+            let response = client
+                .create_image_pipeline()
+                .name(target.pipeline_name)
+                .image_recipe_arn(&recipe_arn)
+                .set_infrastructure_configuration_arn(infrastructure_configuration)
+                .set_schedule(schedule)
+                .status(PipelineStatus::Enabled)
+                .send()
+                .await
+                .context(error::CreateImagePipelineSnafu { region })?;
+            info!(
+                "Created image pipeline {} in {}",
+                response.image_pipeline_arn().unwrap_or_default(),
+                region
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the recipe version for the given Bottlerocket version, dropping any build suffix.
+fn recipe_version(version: &str) -> Result<String> {
+    let version = crate::friendly_version(version).context(error::ParseVersionSnafu { version })?;
+    Ok(format!(
+        "{}.{}.{}",
+        version.major, version.minor, version.patch
+    ))
+}
+
+/// Returns the ARN for the given region from a configured ARN that may contain "{region}".
+fn regional_arn(arn: &str, region: &str) -> String {
+    arn.replace("{region}", region)
+}
+
+/// Returns the ARN of the recipe with the given version from a ListImageRecipes response, if
+/// there is one.  Recipe ARNs end with the recipe's version.
+fn find_recipe_arn(recipes: &[ImageRecipeSummary], version: &str) -> Option<String> {
+    let suffix = format!("/{}", version);
+    recipes
+        .iter()
+        .filter_map(|recipe| recipe.arn())
+        .find(|arn| arn.ends_with(&suffix))
+        .map(str::to_string)
+}
+
+mod error {
+    use aws_sdk_imagebuilder::error::{
+        CreateImagePipelineError, CreateImageRecipeError, ListImagePipelinesError,
+        ListImageRecipesError, UpdateImagePipelineError,
+    };
+    use aws_sdk_imagebuilder::types::SdkError;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to create image pipeline in {}: {}", region, source))]
+        CreateImagePipeline {
+            region: String,
+            source: SdkError<CreateImagePipelineError>,
+        },
+
+        #[snafu(display("Failed to create image recipe in {}: {}", region, source))]
+        CreateImageRecipe {
+            region: String,
+            source: SdkError<CreateImageRecipeError>,
+        },
+
+        #[snafu(display("Failed to deserialize AMI input from '{}': {}", path.display(), source))]
+        Deserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to update image pipelines in {} regions", count))]
+        FailedRegions { count: usize },
+
+        #[snafu(display("Failed to open '{}': {}", path.display(), source))]
+        File {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to list image pipelines in {}: {}", region, source))]
+        ListImagePipelines {
+            region: String,
+            source: SdkError<ListImagePipelinesError>,
+        },
+
+        #[snafu(display("Failed to list image recipes in {}: {}", region, source))]
+        ListImageRecipes {
+            region: String,
+            source: SdkError<ListImageRecipesError>,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("{} response in {} is missing {}", action, region, field))]
+        MissingResponseField {
+            action: String,
+            field: String,
+            region: String,
+        },
+
+        #[snafu(display("Failed to parse version '{}': {}", version, source))]
+        ParseVersion {
+            version: String,
+            source: semver::Error,
+        },
+
+        #[snafu(display("Given region(s) are not in the AMI input: {}", regions.join(", ")))]
+        UnknownRegions { regions: Vec<String> },
+
+        #[snafu(display("Failed to update image pipeline in {}: {}", region, source))]
+        UpdateImagePipeline {
+            region: String,
+            source: SdkError<UpdateImagePipelineError>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::CreateImagePipeline { .. }
+            | Self::CreateImageRecipe { .. }
+            | Self::ListImagePipelines { .. }
+            | Self::ListImageRecipes { .. }
+            | Self::UpdateImagePipeline { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_recipe_arn, recipe_version, regional_arn};
+    use aws_sdk_imagebuilder::model::ImageRecipeSummary;
+
+    #[test]
+    fn versions_and_arns() {
+        assert_eq!(recipe_version("v1.13.0-abcd1234").unwrap(), "1.13.0");
+        assert_eq!(
+            regional_arn(
+                "arn:aws:imagebuilder:{region}:aws:component/update-linux/x.x.x",
+                "us-west-2"
+            ),
+            "arn:aws:imagebuilder:us-west-2:aws:component/update-linux/x.x.x"
+        );
+    }
+
+    #[test]
+    fn finds_recipe_version() {
+        let recipes: Vec<_> = ["1.12.0", "1.13.0"]
+            .iter()
+            .map(|version| {
+                ImageRecipeSummary::builder()
+                    .arn(format!(
+                        "arn:aws:imagebuilder:us-west-2:123456789012:image-recipe/br/{}",
+                        version
+                    ))
+                    .build()
+            })
+            .collect();
+        assert_eq!(
+            find_recipe_arn(&recipes, "1.13.0").unwrap(),
+            "arn:aws:imagebuilder:us-west-2:123456789012:image-recipe/br/1.13.0"
+        );
+        assert_eq!(find_recipe_arn(&recipes, "1.3.0"), None);
+        assert_eq!(find_recipe_arn(&[], "1.13.0"), None);
+    }
+}
@@ -0,0 +1,701 @@
+//! The kms module owns the 'kms' subcommand, which manages the customer managed KMS keys that
+//! encrypt the AMIs pubsys copies to other regions.  In each region, it finds the key with the
+//! configured alias and checks that its key policy lets EBS use it on behalf of the key's own
+//! account and the accounts AMIs are shared with.  With `--create`, it creates missing keys and
+//! aliases, and adds missing statements to key policies, leaving any other statements alone.
+//!
+//! The ARNs of the keys are recorded in `aws.ami_kms.key_arns` in Infra.lock, from which the `ami`
+//! subcommand reads them.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::region_from_string;
+use crate::aws::tags::kms_tags;
+use crate::exit_code::{Classify, Kind};
+use crate::run_state::RunState;
+use crate::Args;
+use aws_sdk_ec2::Region;
+use aws_sdk_kms::model::{KeyState, KeyUsageType};
+use aws_sdk_kms::Client as KmsClient;
+use aws_sdk_sts::Client as StsClient;
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace, warn};
+use pubsys_config::{partition_for_region, AwsConfig as PubsysAwsConfig, InfraConfig};
+use serde_json::{json, Value};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use structopt::{clap, StructOpt};
+
+/// The key alias used if none is given on the command line or in Infra.toml.
+const DEFAULT_ALIAS: &str = "alias/bottlerocket-ami";
+
+/// KMS only has one key policy per key, and this is its name.
+const POLICY_NAME: &str = "default";
+
+/// Regions are independent, so we can work on all of them at once.
+const MAX_PARALLEL_REGIONS: usize = 32;
+
+/// Creates or validates the KMS keys that encrypt AMIs
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct KmsArgs {
+    /// The alias of the key in each region, overriding Infra.toml; defaults to
+    /// "alias/bottlerocket-ami"
+    #[structopt(long)]
+    alias: Option<String>,
+
+    /// Comma-separated list of account IDs that may use the keys to launch shared AMIs,
+    /// overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    share_with: Vec<String>,
+
+    /// Comma-separated list of regions to manage keys in, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Create missing keys and aliases, and add missing key policy statements, rather than only
+    /// reporting them
+    #[structopt(long)]
+    create: bool,
+}
+
+/// The keys `manage_keys` finds or creates
+#[derive(Debug, Clone, Default)]
+pub struct KmsOptions {
+    /// The alias of the key in each region, in place of the one in Infra.toml; defaults to
+    /// "alias/bottlerocket-ami"
+    pub alias: Option<String>,
+    /// Account IDs that may use the keys to launch shared AMIs, in place of the ones in
+    /// Infra.toml if not empty
+    pub share_with: Vec<String>,
+    /// Regions to manage keys in; the regions in Infra.toml are used if this is empty
+    pub regions: Vec<String>,
+    /// Create missing keys and aliases, and add missing key policy statements, rather than only
+    /// reporting them
+    pub create: bool,
+}
+
+impl From<&KmsArgs> for KmsOptions {
+    fn from(args: &KmsArgs) -> Self {
+        Self {
+            alias: args.alias.clone(),
+            share_with: args.share_with.clone(),
+            regions: args.regions.clone(),
+            create: args.create,
+        }
+    }
+}
+
+/// The keys found or created by `manage_keys`
+#[derive(Debug, Clone, Default)]
+pub struct KmsKeys {
+    /// The ARN of the key in each region that succeeded, keyed by region name
+    pub key_arns: BTreeMap<String, String>,
+    /// The number of regions that failed; their errors are logged
+    pub failed: usize,
+}
+
+/// Common entrypoint from main()
+pub async fn run(args: &Args, kms_args: &KmsArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let keys =
+        manage_keys_with_state(&args.state, &infra_config, &KmsOptions::from(kms_args)).await?;
+
+    // Record the keys we have, even if some regions failed, so a rerun only has to fix those.
+    record_key_arns(args, &keys.key_arns)?;
+    ensure!(
+        keys.failed == 0,
+        error::FailedRegionsSnafu { count: keys.failed }
+    );
+    Ok(())
+}
+
+/// Finds, and with `create` creates, the key with the configured alias in each region, and checks
+/// or fixes its key policy.  Failures in single regions are logged and counted rather than
+/// returned, so the keys in other regions can still be used.
+pub async fn manage_keys(infra_config: &InfraConfig, options: &KmsOptions) -> Result<KmsKeys> {
+    manage_keys_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Manages the keys like `manage_keys`, tracing and tracking them in the given run.
+pub(crate) async fn manage_keys_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &KmsOptions,
+) -> Result<KmsKeys> {
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let ami_kms = aws.ami_kms.clone().unwrap_or_default();
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
+    } else {
+        aws.regions_for("kms").clone().into()
+    }
+    .into_iter()
+    .map(|name| region_from_string(&name))
+    .collect::<Vec<Region>>();
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let alias = options
+        .alias
+        .clone()
+        .or(ami_kms.alias)
+        .unwrap_or_else(|| DEFAULT_ALIAS.to_string());
+    ensure!(
+        alias.starts_with("alias/"),
+        error::InvalidAliasSnafu { alias: &alias }
+    );
+    let share_accounts = if !options.share_with.is_empty() {
+        options.share_with.clone()
+    } else {
+        ami_kms.share_accounts
+    };
+
+    let mut requests = Vec::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        let kms_client = KmsClient::from_pubsys_config(&client_config, &aws);
+        let sts_client = StsClient::from_pubsys_config(&client_config, &aws);
+        let request = state.tracer.traced(
+            "kms_key",
+            Some(region.as_ref()),
+            manage_key(
+                region.clone(),
+                kms_client,
+                sts_client,
+                &alias,
+                &share_accounts,
+                options.create,
+                &aws,
+            ),
+        );
+        requests.push((region.to_string(), request));
+    }
+
+    info!(
+        "{} keys with alias {} in {} regions",
+        if options.create {
+            "Creating or validating"
+        } else {
+            "Validating"
+        },
+        alias,
+        requests.len()
+    );
+    let results: Vec<Result<(Region, String)>> =
+        stream::iter(state.interrupt.tracked("KMS keys", requests))
+            .buffer_unordered(MAX_PARALLEL_REGIONS)
+            .collect()
+            .await;
+
+    let mut key_arns = BTreeMap::new();
+    let mut failed = 0;
+    for result in results {
+        match result {
+            Ok((region, arn)) => {
+                key_arns.insert(region.to_string(), arn);
+            }
+            Err(e) => {
+                error!("{}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(KmsKeys { key_arns, failed })
+}
+
+/// Finds or creates the key with the given alias in the region, and checks or fixes its key
+/// policy.  Returns the key's ARN.
+async fn manage_key(
+    region: Region,
+    kms_client: KmsClient,
+    sts_client: StsClient,
+    alias: &str,
+    share_accounts: &[String],
+    create: bool,
+    pubsys_aws_config: &PubsysAwsConfig,
+) -> Result<(Region, String)> {
+    let account_id = sts_client
+        .get_caller_identity()
+        .send()
+        .await
+        .context(error::GetCallerIdentitySnafu {
+            region: region.as_ref(),
+        })?
+        .account
+        .context(error::MissingInResponseSnafu {
+            request_type: "GetCallerIdentity",
+            missing: "account",
+            region: region.as_ref(),
+        })?;
+    let expected_policy = key_policy(region.as_ref(), &account_id, share_accounts);
+
+    let metadata = match kms_client.describe_key().key_id(alias).send().await {
+        Ok(output) => output.key_metadata,
+        Err(e) => {
+            let e = e.into_service_error();
+            if e.code() != Some("NotFoundException") {
+                return Err(e).context(error::DescribeKeySnafu {
+                    alias,
+                    region: region.as_ref(),
+                });
+            }
+            ensure!(
+                create,
+                error::KeyMissingSnafu {
+                    alias,
+                    region: region.as_ref(),
+                }
+            );
+
+            info!("Creating key {} in {}", alias, region);
+            let output = kms_client
+                .create_key()
+                .description("Encrypts Bottlerocket AMIs")
+                .key_usage(KeyUsageType::EncryptDecrypt)
+                .policy(expected_policy.to_string())
+                .set_tags(Some(kms_tags(pubsys_aws_config)).filter(|tags| !tags.is_empty()))
+                .send()
+                .await
+                .context(error::CreateKeySnafu {
+                    region: region.as_ref(),
+                })?;
+            let metadata = output.key_metadata.context(error::MissingInResponseSnafu {
+                request_type: "CreateKey",
+                missing: "key_metadata",
+                region: region.as_ref(),
+            })?;
+            kms_client
+                .create_alias()
+                .alias_name(alias)
+                .set_target_key_id(metadata.key_id.clone())
+                .send()
+                .await
+                .context(error::CreateAliasSnafu {
+                    alias,
+                    region: region.as_ref(),
+                })?;
+            Some(metadata)
+        }
+    };
+    let metadata = metadata.context(error::MissingInResponseSnafu {
+        request_type: "DescribeKey",
+        missing: "key_metadata",
+        region: region.as_ref(),
+    })?;
+    let arn = metadata.arn.context(error::MissingInResponseSnafu {
+        request_type: "DescribeKey",
+        missing: "arn",
+        region: region.as_ref(),
+    })?;
+    ensure!(
+        metadata.key_state == Some(KeyState::Enabled),
+        error::KeyNotEnabledSnafu {
+            arn: &arn,
+            state: metadata
+                .key_state
+                .as_ref()
+                .map(|state| state.as_str())
+                .unwrap_or("unknown"),
+        }
+    );
+
+    let policy = kms_client
+        .get_key_policy()
+        .key_id(&arn)
+        .policy_name(POLICY_NAME)
+        .send()
+        .await
+        .context(error::GetKeyPolicySnafu { arn: &arn })?
+        .policy
+        .unwrap_or_else(|| "{}".to_string());
+    let policy: Value =
+        serde_json::from_str(&policy).context(error::ParsePolicySnafu { arn: &arn })?;
+    let missing = missing_statements(&policy, &expected_policy);
+    if missing.is_empty() {
+        info!("Key {} in {} is ready: {}", alias, region, arn);
+        return Ok((region, arn));
+    }
+    ensure!(
+        create,
+        error::PolicyMismatchSnafu {
+            arn: &arn,
+            statements: missing,
+        }
+    );
+
+    warn!(
+        "Updating key policy statements {} of {}",
+        missing.join(", "),
+        arn
+    );
+    kms_client
+        .put_key_policy()
+        .key_id(&arn)
+        .policy_name(POLICY_NAME)
+        .policy(merge_policy(&policy, &expected_policy).to_string())
+        .send()
+        .await
+        .context(error::PutKeyPolicySnafu { arn: &arn })?;
+    info!("Key {} in {} is ready: {}", alias, region, arn);
+    Ok((region, arn))
+}
+
+/// Returns the key policy statements a key needs so that its own account can administer it, and
+/// EBS can use it on behalf of that account and the accounts AMIs are shared with.  Launching an
+/// instance from a shared, encrypted AMI takes a grant for EBS, which the last statement allows.
+fn key_policy(region: &str, account_id: &str, share_accounts: &[String]) -> Value {
+    let partition = partition_for_region(region);
+    let dns_suffix = if partition == "aws-cn" {
+        "amazonaws.com.cn"
+    } else {
+        "amazonaws.com"
+    };
+    let root = |account: &str| format!("arn:{}:iam::{}:root", partition, account);
+    let users: BTreeSet<String> = std::iter::once(account_id)
+        .chain(share_accounts.iter().map(String::as_str))
+        .map(root)
+        .collect();
+
+    json!({
+        "Version": "2012-10-17",
+        "Statement": [
+            {
+                "Sid": "EnableAccountAdministration",
+                "Effect": "Allow",
+                "Principal": { "AWS": root(account_id) },
+                "Action": "kms:*",
+                "Resource": "*",
+            },
+            {
+                "Sid": "AllowUseThroughEbs",
+                "Effect": "Allow",
+                "Principal": { "AWS": users },
+                "Action": [
+                    "kms:Decrypt",
+                    "kms:DescribeKey",
+                    "kms:Encrypt",
+                    "kms:GenerateDataKey*",
+                    "kms:ReEncrypt*",
+                ],
+                "Resource": "*",
+                "Condition": {
+                    "StringEquals": {
+                        "kms:ViaService": format!("ec2.{}.{}", region, dns_suffix),
+                    },
+                },
+            },
+            {
+                "Sid": "AllowGrantsForEbs",
+                "Effect": "Allow",
+                "Principal": { "AWS": users },
+                "Action": "kms:CreateGrant",
+                "Resource": "*",
+                "Condition": {
+                    "Bool": { "kms:GrantIsForAWSResource": "true" },
+                },
+            },
+        ],
+    })
+}
+
+/// Returns the Sids of the statements in the expected policy that the actual policy lacks, or has
+/// with different contents.
+fn missing_statements(actual: &Value, expected: &Value) -> Vec<String> {
+    statements(expected)
+        .iter()
+        .filter(|expected| {
+            !statements(actual).iter().any(|actual| {
+                actual["Sid"] == expected["Sid"]
+                    && actual["Effect"] == expected["Effect"]
+                    && string_set(&actual["Principal"]["AWS"])
+                        == string_set(&expected["Principal"]["AWS"])
+                    && string_set(&actual["Action"]) == string_set(&expected["Action"])
+                    && actual["Condition"] == expected["Condition"]
+            })
+        })
+        .map(|statement| statement["Sid"].as_str().unwrap_or_default().to_string())
+        .collect()
+}
+
+/// Returns the actual policy with the expected policy's statements in place of any with the same
+/// Sid.  Other statements, like ones added by hand, are kept.
+fn merge_policy(actual: &Value, expected: &Value) -> Value {
+    let expected_statements = statements(expected);
+    let mut merged = actual.clone();
+    if !merged.is_object() {
+        merged = json!({});
+    }
+    merged["Version"] = expected["Version"].clone();
+    merged["Statement"] = statements(actual)
+        .iter()
+        .filter(|statement| {
+            !expected_statements
+                .iter()
+                .any(|expected| expected["Sid"] == statement["Sid"])
+        })
+        .chain(expected_statements.iter())
+        .cloned()
+        .collect();
+    merged
+}
+
+/// Returns the statements of a policy.  A policy may have a single statement rather than a list.
+fn statements(policy: &Value) -> Vec<Value> {
+    match &policy["Statement"] {
+        Value::Array(statements) => statements.clone(),
+        Value::Null => Vec::new(),
+        statement => vec![statement.clone()],
+    }
+}
+
+/// Returns the strings of a policy element that may be a single string or a list of them.  KMS
+/// stores the policy it's given, so a list with one item stays a list, but one written by hand
+/// may use either.
+fn string_set(value: &Value) -> BTreeSet<&str> {
+    match value {
+        Value::String(s) => std::iter::once(s.as_str()).collect(),
+        Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+/// Writes the given key ARNs into `aws.ami_kms.key_arns` in Infra.lock, so the `ami` subcommand
+/// can find them.  Infra.lock is written next to Infra.toml, which we can't do for a fetched
+/// config, so then we only print them.
+fn record_key_arns(args: &Args, key_arns: &BTreeMap<String, String>) -> Result<()> {
+    if key_arns.is_empty() {
+        return Ok(());
+    }
+    if let Some(url) = &args.infra_config_url {
+        warn!(
+            "Can't record key ARNs for the infra config at {}; add them to aws.ami_kms.key_arns \
+             where the config is kept:",
+            url
+        );
+        for (region, arn) in key_arns {
+            warn!("{} = \"{}\"", region, arn);
+        }
+        return Ok(());
+    }
+
+    let toml_path = &args.infra_config_path;
+    let lock_path = InfraConfig::compute_lock_path(toml_path).context(error::ConfigSnafu)?;
+    let mut infra_config = if lock_path.exists() {
+        InfraConfig::from_lock_path(&lock_path)
+    } else {
+        InfraConfig::from_path(toml_path)
+    }
+    .context(error::ConfigSnafu)?;
+    infra_config
+        .aws
+        .get_or_insert_with(Default::default)
+        .ami_kms
+        .get_or_insert_with(Default::default)
+        .key_arns
+        .extend(key_arns.clone());
+
+    let lock_string = infra_config.to_lock_string().context(error::ConfigSnafu)?;
+    fs::write(&lock_path, lock_string).context(error::WriteLockSnafu { path: &lock_path })?;
+    info!(
+        "Recorded {} key ARNs in '{}'",
+        key_arns.len(),
+        lock_path.display()
+    );
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_kms::error::{
+        CreateAliasError, CreateKeyError, DescribeKeyError, GetKeyPolicyError, PutKeyPolicyError,
+    };
+    use aws_sdk_kms::types::SdkError;
+    use aws_sdk_sts::error::GetCallerIdentityError;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to create alias {} in {}: {}", alias, region, source))]
+        CreateAlias {
+            alias: String,
+            region: String,
+            source: SdkError<CreateAliasError>,
+        },
+
+        #[snafu(display("Failed to create key in {}: {}", region, source))]
+        CreateKey {
+            region: String,
+            source: SdkError<CreateKeyError>,
+        },
+
+        #[snafu(display("Failed to describe key {} in {}: {}", alias, region, source))]
+        DescribeKey {
+            alias: String,
+            region: String,
+            source: DescribeKeyError,
+        },
+
+        #[snafu(display("Failed to manage keys in {} regions", count))]
+        FailedRegions { count: usize },
+
+        #[snafu(display("Failed to get caller identity in {}: {}", region, source))]
+        GetCallerIdentity {
+            region: String,
+            source: SdkError<GetCallerIdentityError>,
+        },
+
+        #[snafu(display("Failed to get key policy of {}: {}", arn, source))]
+        GetKeyPolicy {
+            arn: String,
+            source: SdkError<GetKeyPolicyError>,
+        },
+
+        #[snafu(display("Key alias '{}' must start with 'alias/'", alias))]
+        InvalidAlias { alias: String },
+
+        #[snafu(display("No key has alias {} in {}; use --create to create it", alias, region))]
+        KeyMissing { alias: String, region: String },
+
+        #[snafu(display("Key {} is {}, not enabled", arn, state))]
+        KeyNotEnabled { arn: String, state: String },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Response to {} in {} was missing {}", request_type, region, missing))]
+        MissingInResponse {
+            request_type: String,
+            missing: String,
+            region: String,
+        },
+
+        #[snafu(display("Failed to parse key policy of {}: {}", arn, source))]
+        ParsePolicy {
+            arn: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display(
+            "Key policy of {} is missing or has different statements {}; use --create to update it",
+            arn,
+            statements.join(", ")
+        ))]
+        PolicyMismatch {
+            arn: String,
+            statements: Vec<String>,
+        },
+
+        #[snafu(display("Failed to put key policy of {}: {}", arn, source))]
+        PutKeyPolicy {
+            arn: String,
+            source: SdkError<PutKeyPolicyError>,
+        },
+
+        #[snafu(display("Failed to write lock file '{}': {}", path.display(), source))]
+        WriteLock {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::InvalidAlias { .. } | Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::CreateAlias { .. }
+            | Self::CreateKey { .. }
+            | Self::DescribeKey { .. }
+            | Self::GetCallerIdentity { .. }
+            | Self::GetKeyPolicy { .. }
+            | Self::PutKeyPolicy { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key_policy, merge_policy, missing_statements};
+    use serde_json::json;
+
+    #[test]
+    fn policy_statements() {
+        let expected = key_policy("cn-north-1", "111122223333", &["444455556666".to_string()]);
+        assert_eq!(
+            expected["Statement"][1]["Principal"]["AWS"],
+            json!([
+                "arn:aws-cn:iam::111122223333:root",
+                "arn:aws-cn:iam::444455556666:root"
+            ])
+        );
+        assert_eq!(
+            expected["Statement"][1]["Condition"]["StringEquals"]["kms:ViaService"],
+            "ec2.cn-north-1.amazonaws.com.cn"
+        );
+        assert!(missing_statements(&expected, &expected).is_empty());
+
+        // A policy with only the account's administration statement, which is KMS's default.
+        let actual = json!({
+            "Version": "2012-10-17",
+            "Statement": {
+                "Sid": "EnableAccountAdministration",
+                "Effect": "Allow",
+                "Principal": { "AWS": ["arn:aws-cn:iam::111122223333:root"] },
+                "Action": ["kms:*"],
+                "Resource": "*",
+            },
+        });
+        assert_eq!(
+            missing_statements(&actual, &expected),
+            vec!["AllowUseThroughEbs", "AllowGrantsForEbs"]
+        );
+    }
+
+    #[test]
+    fn merges_policy() {
+        let expected = key_policy("us-west-2", "111122223333", &[]);
+        let actual = json!({
+            "Version": "2012-10-17",
+            "Statement": [
+                { "Sid": "AllowUseThroughEbs", "Effect": "Deny" },
+                { "Sid": "AddedByHand", "Effect": "Allow" },
+            ],
+        });
+        let merged = merge_policy(&actual, &expected);
+        assert!(missing_statements(&merged, &expected).is_empty());
+        let sids: Vec<_> = merged["Statement"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|statement| statement["Sid"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            sids,
+            vec![
+                "AddedByHand",
+                "EnableAccountAdministration",
+                "AllowUseThroughEbs",
+                "AllowGrantsForEbs"
+            ]
+        );
+    }
+}
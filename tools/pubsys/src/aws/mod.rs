@@ -4,15 +4,30 @@ use aws_sdk_ec2::Region;
 #[macro_use]
 pub(crate) mod client;
 
-pub(crate) mod ami;
-pub(crate) mod promote_ssm;
-pub(crate) mod publish_ami;
-pub(crate) mod ssm;
-pub(crate) mod validate_ami;
-pub(crate) mod validate_ssm;
+pub mod ami;
+pub(crate) mod check_permissions;
+pub(crate) mod diff;
+pub(crate) mod identity;
+pub(crate) mod image_builder;
+pub mod kms;
+pub(crate) mod page_size;
+pub mod promote_ami;
+pub mod promote_ssm;
+pub(crate) mod proxy;
+pub mod publish_ami;
+pub(crate) mod query;
+pub(crate) mod rate_limit;
+pub(crate) mod rollback_ssm;
+pub(crate) mod secrets;
+pub mod ssm;
+pub(crate) mod tags;
+pub mod transfer_ami;
+pub mod validate_ami;
+pub mod validate_snapshots;
+pub mod validate_ssm;
 
 /// Builds a Region from the given region name.
-fn region_from_string(name: &str) -> Region {
+pub(crate) fn region_from_string(name: &str) -> Region {
     Region::new(name.to_owned())
 }
 
@@ -34,7 +49,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Failed to parse arch '{}': {}", input, msg))]
         ParseArch { input: String, msg: String },
     }
@@ -0,0 +1,20 @@
+//! The page_size module finds the page sizes for the paginated requests pubsys sends, like
+//! DescribeImages and GetParametersByPath.
+//!
+//! Page sizes are set in the `aws.page_size` table of Infra.toml.  Requests without a page size
+//! there use the service's default.
+
+use pubsys_config::{AwsConfig, AwsPageSizeConfig};
+use std::num::NonZeroU32;
+
+/// Returns the page size chosen by `choose` from the page sizes in the given config, if one is
+/// set, in the form the SDK's paginators take it.
+pub(crate) fn get(
+    aws: &AwsConfig,
+    choose: fn(&AwsPageSizeConfig) -> Option<NonZeroU32>,
+) -> Option<i32> {
+    aws.page_size
+        .as_ref()
+        .and_then(choose)
+        .and_then(|size| i32::try_from(size.get()).ok())
+}
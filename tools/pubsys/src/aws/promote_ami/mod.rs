@@ -0,0 +1,443 @@
+//! The promote_ami module owns the 'promote-ami' subcommand, which moves a release channel, like
+//! "stable", to a new version's AMIs by tagging them with the channel and removing the tag from the
+//! AMIs that had it before.  Consumers that find AMIs by tag can then follow a channel without
+//! reading SSM parameters.
+//!
+//! Channels are scoped to a variant and architecture: promoted AMIs are also tagged with their
+//! variant, and only AMIs of the same variant and architecture lose the channel tag.  In each
+//! region, the new AMIs are tagged before the old ones are untagged, so there's never a moment
+//! when no AMI has the channel tag.
+
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::run_state::RunState;
+use crate::Args;
+use aws_sdk_ec2::model::{ArchitectureValues, Filter, Tag};
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
+use pubsys_config::InfraConfig;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::iter::FromIterator;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// The tag recording the variant of a promoted AMI, which scopes its channel
+const VARIANT_TAG: &str = "pubsys:variant";
+
+/// Regions are independent, so we can work on all of them at once.
+const MAX_PARALLEL_REGIONS: usize = 32;
+
+/// Moves a release channel tag to the AMIs of a new version
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct PromoteAmiArgs {
+    /// Path to the JSON file containing regional AMI IDs of the version to promote
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// The variant of the AMIs
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture of the AMIs
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// The channel to move to these AMIs, like "stable"
+    #[structopt(long)]
+    channel: String,
+
+    /// The key of the channel tag
+    #[structopt(long, default_value = "channel")]
+    channel_tag_key: String,
+
+    /// Comma-separated list of regions to promote in, overriding Infra.toml; given regions must be
+    /// in the --ami-input file
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+}
+
+/// The AMIs `promote` moves a channel to
+#[derive(Debug, Clone)]
+pub struct PromoteAmiOptions {
+    /// Path to the JSON file containing regional AMI IDs of the version to promote
+    pub ami_input: PathBuf,
+    /// The variant of the AMIs
+    pub variant: String,
+    /// The architecture of the AMIs
+    pub arch: ArchitectureValues,
+    /// The channel to move to these AMIs, like "stable"
+    pub channel: String,
+    /// The key of the channel tag, usually "channel"
+    pub channel_tag_key: String,
+    /// Regions to promote in, which must be in the `ami_input` file; the regions in Infra.toml
+    /// are used if this is empty
+    pub regions: Vec<String>,
+}
+
+impl From<&PromoteAmiArgs> for PromoteAmiOptions {
+    fn from(args: &PromoteAmiArgs) -> Self {
+        Self {
+            ami_input: args.ami_input.clone(),
+            variant: args.variant.clone(),
+            arch: args.arch.clone(),
+            channel: args.channel.clone(),
+            channel_tag_key: args.channel_tag_key.clone(),
+            regions: args.regions.clone(),
+        }
+    }
+}
+
+/// Common entrypoint from main()
+pub async fn run(args: &Args, promote_args: &PromoteAmiArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    promote_with_state(
+        &args.state,
+        &infra_config,
+        &PromoteAmiOptions::from(promote_args),
+    )
+    .await
+}
+
+/// Tags the AMIs in the input file with the channel in each region, and removes the tag from the
+/// AMIs of the same variant and architecture that had it before.
+pub async fn promote(infra_config: &InfraConfig, options: &PromoteAmiOptions) -> Result<()> {
+    promote_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Promotes the AMIs like `promote`, tracing and tracking the promotions in the given run.
+pub(crate) async fn promote_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &PromoteAmiOptions,
+) -> Result<()> {
+    info!("Using AMI data from path: {}", options.ami_input.display());
+    let file = File::open(&options.ami_input).context(error::FileSnafu {
+        op: "open",
+        path: &options.ami_input,
+    })?;
+    let mut ami_input: HashMap<String, Image> =
+        serde_json::from_reader(file).context(error::DeserializeSnafu {
+            path: &options.ami_input,
+        })?;
+    trace!("Parsed AMI input: {:?}", ami_input);
+    ensure!(
+        !ami_input.is_empty(),
+        error::InputSnafu {
+            path: &options.ami_input
+        }
+    );
+
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
+    } else {
+        aws.regions_for("promote_ami").clone().into()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = region_from_string(&regions[0]);
+
+    let requested_regions = HashSet::from_iter(regions.iter());
+    let known_regions = HashSet::<&String>::from_iter(ami_input.keys());
+    ensure!(
+        requested_regions.is_subset(&known_regions),
+        error::UnknownRegionsSnafu {
+            regions: requested_regions
+                .difference(&known_regions)
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+        }
+    );
+
+    let mut amis = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    for name in regions {
+        let image = ami_input
+            .remove(&name)
+            .with_context(|| error::UnknownRegionsSnafu {
+                regions: vec![name.clone()],
+            })?;
+        let region = region_from_string(&name);
+        let client_config = build_client_config(&region, &base_region, &aws).await;
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, &aws),
+        );
+        amis.insert(region, image);
+    }
+
+    info!(
+        "Promoting {} {} AMIs to channel '{}'",
+        options.variant,
+        options.arch.as_str(),
+        options.channel
+    );
+    let requests = amis.iter().map(|(region, image)| {
+        let request = state.tracer.traced(
+            "promote_ami",
+            Some(region.as_ref()),
+            promote_in_region(
+                &ec2_clients[region],
+                &rate_limits,
+                region,
+                &image.id,
+                options,
+            ),
+        );
+        (region.to_string(), request)
+    });
+    let results: Vec<Result<()>> =
+        stream::iter(state.interrupt.tracked("AMI promotions", requests))
+            .buffer_unordered(MAX_PARALLEL_REGIONS)
+            .collect()
+            .await;
+
+    let mut failed = 0;
+    for result in results {
+        if let Err(e) = result {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
+    ensure!(failed == 0, error::FailedRegionsSnafu { count: failed });
+    Ok(())
+}
+
+/// Tags the given AMI with the channel, then removes the channel tag from any other AMI of the
+/// same variant and architecture in the region.
+async fn promote_in_region(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &Region,
+    image_id: &str,
+    options: &PromoteAmiOptions,
+) -> Result<()> {
+    let describe_response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .describe_images()
+                .owners("self")
+                .filters(tag_filter(&options.channel_tag_key, &options.channel))
+                .filters(tag_filter(VARIANT_TAG, &options.variant))
+                .filters(
+                    Filter::builder()
+                        .name("architecture")
+                        .values(options.arch.as_str())
+                        .build(),
+                )
+                .send(),
+        )
+        .await
+        .context(error::DescribeImagesSnafu {
+            region: region.as_ref(),
+        })?;
+    let previous_ids: Vec<String> = describe_response
+        .images()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|image| image.image_id())
+        .filter(|id| *id != image_id)
+        .map(str::to_string)
+        .collect();
+
+    rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .create_tags()
+                .resources(image_id)
+                .set_tags(Some(channel_tags(
+                    &options.channel_tag_key,
+                    &options.channel,
+                    &options.variant,
+                )))
+                .send(),
+        )
+        .await
+        .context(error::CreateTagsSnafu {
+            image_id,
+            region: region.as_ref(),
+        })?;
+    info!(
+        "Tagged {} in {} with {}={}",
+        image_id, region, options.channel_tag_key, options.channel
+    );
+
+    if previous_ids.is_empty() {
+        return Ok(());
+    }
+    // Giving the tag's value means EC2 only removes the tag if it still has that value.
+    rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .delete_tags()
+                .set_resources(Some(previous_ids.clone()))
+                .tags(
+                    Tag::builder()
+                        .key(&options.channel_tag_key)
+                        .value(&options.channel)
+                        .build(),
+                )
+                .send(),
+        )
+        .await
+        .context(error::DeleteTagsSnafu {
+            image_ids: previous_ids.join(", "),
+            region: region.as_ref(),
+        })?;
+    info!(
+        "Removed {}={} from {} in {}",
+        options.channel_tag_key,
+        options.channel,
+        previous_ids.join(", "),
+        region
+    );
+    Ok(())
+}
+
+/// Returns a DescribeImages filter for images with the given tag.
+fn tag_filter(key: &str, value: &str) -> Filter {
+    Filter::builder()
+        .name(format!("tag:{}", key))
+        .values(value)
+        .build()
+}
+
+/// Returns the tags that put an AMI of the given variant in the given channel.
+fn channel_tags(channel_tag_key: &str, channel: &str, variant: &str) -> Vec<Tag> {
+    vec![
+        Tag::builder().key(channel_tag_key).value(channel).build(),
+        Tag::builder().key(VARIANT_TAG).value(variant).build(),
+    ]
+}
+
+mod error {
+    use aws_sdk_ec2::error::{CreateTagsError, DeleteTagsError, DescribeImagesError};
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to tag {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        CreateTags {
+            image_id: String,
+            region: String,
+            source: SdkError<CreateTagsError>,
+        },
+
+        #[snafu(display(
+            "Failed to remove channel tag from {} in {}: {}",
+            image_ids,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeleteTags {
+            image_ids: String,
+            region: String,
+            source: SdkError<DeleteTagsError>,
+        },
+
+        #[snafu(display(
+            "Failed to describe images in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeImages {
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("Failed to deserialize input from '{}': {}", path.display(), source))]
+        Deserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to promote AMIs in {} regions", count))]
+        FailedRegions { count: usize },
+
+        #[snafu(display("Failed to {} '{}': {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: io::Error,
+        },
+
+        #[snafu(display("Input '{}' is empty", path.display()))]
+        Input { path: PathBuf },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display(
+            "Given region(s) in Infra.toml / regions argument that are not in --ami-input file: {}",
+            regions.join(", ")
+        ))]
+        UnknownRegions { regions: Vec<String> },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::CreateTags { .. } | Self::DeleteTags { .. } | Self::DescribeImages { .. } => {
+                Some(Kind::AwsApi)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::channel_tags;
+
+    #[test]
+    fn channel_and_variant_tags() {
+        let tags: Vec<(&str, &str)> = channel_tags("channel", "stable", "aws-k8s-1.24")
+            .iter()
+            .map(|tag| (tag.key().unwrap(), tag.value().unwrap()))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![("channel", "stable"), ("pubsys:variant", "aws-k8s-1.24")]
+        );
+    }
+}
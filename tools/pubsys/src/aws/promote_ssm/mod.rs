@@ -1,13 +1,19 @@
 //! The promote_ssm module owns the 'promote-ssm' subcommand and controls the process of copying
 //! SSM parameters from one version to another
 
-use crate::aws::client::build_client_config;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::RateLimits;
 use crate::aws::ssm::template::RenderedParametersMap;
-use crate::aws::ssm::{key_difference, ssm, template, BuildContext, SsmKey};
+use crate::aws::ssm::{key_difference, plan_parameters, ssm, template, BuildContext, SsmKey};
+use crate::aws::tags::ssm_tags;
 use crate::aws::validate_ssm::parse_parameters;
 use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::run_state::RunState;
+use crate::test_trigger;
 use crate::Args;
 use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ec2::Client as Ec2Client;
 use aws_sdk_ssm::{Client as SsmClient, Region};
 use log::{info, trace};
 use pubsys_config::InfraConfig;
@@ -19,7 +25,7 @@ use structopt::{clap, StructOpt};
 /// Copies sets of SSM parameters
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct PromoteArgs {
+pub struct PromoteArgs {
     /// The architecture of the machine image
     #[structopt(long, parse(try_from_str = parse_arch))]
     arch: ArchitectureValues,
@@ -48,30 +54,92 @@ pub(crate) struct PromoteArgs {
     /// and where the newly promoted parameters will be written
     #[structopt(long)]
     ssm_parameter_output: Option<PathBuf>,
+
+    /// Only promote if the source version's AMIs passed the tests started after they were
+    /// published, as configured in the `tests` section of Infra.toml with `wait = true`
+    #[structopt(long)]
+    require_tests: bool,
+}
+
+/// The parameters `promote` copies, from one version to another
+#[derive(Debug, Clone)]
+pub struct PromoteSsmOptions {
+    /// The architecture of the machine image
+    pub arch: ArchitectureValues,
+    /// The variant name for the current build
+    pub variant: String,
+    /// Version number (or string) to copy from
+    pub source: String,
+    /// Version number (or string) to copy to
+    pub target: String,
+    /// Regions to promote in; the regions in Infra.toml are used if this is empty
+    pub regions: Vec<String>,
+    /// File holding the parameter templates
+    pub template_path: PathBuf,
+    /// If set, contains the path to the file holding the original SSM parameters and where the
+    /// newly promoted parameters will be written
+    pub ssm_parameter_output: Option<PathBuf>,
+    /// Only promote if the source version's AMIs passed the tests started after they were
+    /// published, as configured in the `tests` section of Infra.toml with `wait = true`
+    pub require_tests: bool,
+}
+
+impl From<&PromoteArgs> for PromoteSsmOptions {
+    fn from(args: &PromoteArgs) -> Self {
+        Self {
+            arch: args.arch.clone(),
+            variant: args.variant.clone(),
+            source: args.source.clone(),
+            target: args.target.clone(),
+            regions: args.regions.clone(),
+            template_path: args.template_path.clone(),
+            ssm_parameter_output: args.ssm_parameter_output.clone(),
+            require_tests: args.require_tests,
+        }
+    }
 }
 
 /// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
+pub async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    promote_with_state(
+        &args.state,
+        &infra_config,
+        &PromoteSsmOptions::from(promote_args),
+    )
+    .await
+}
+
+/// Copies the source version's parameters to the target version in each region.
+pub async fn promote(infra_config: &InfraConfig, options: &PromoteSsmOptions) -> Result<()> {
+    promote_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Promotes the parameters like `promote`, planning, journaling, and tracing in the given run.
+pub(crate) async fn promote_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &PromoteSsmOptions,
+) -> Result<()> {
     info!(
         "Promoting SSM parameters from {} to {}",
-        promote_args.source, promote_args.target
+        options.source, options.target
     );
 
     // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
-
     trace!("Parsed infra config: {:#?}", infra_config);
-    let aws = infra_config.aws.unwrap_or_default();
-    let ssm_prefix = aws.ssm_prefix.as_deref().unwrap_or("");
-
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !promote_args.regions.is_empty() {
-        promote_args.regions.clone()
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let ssm_prefix = aws.ssm_prefix_for(&options.variant, options.arch.as_ref());
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
     } else {
-        aws.regions.clone().into()
+        aws.regions_for("promote_ssm").clone().into()
     }
     .into_iter()
     .map(|name| region_from_string(&name))
@@ -86,41 +154,44 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     let base_region = &regions[0];
 
     let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
     for region in &regions {
         let client_config = build_client_config(region, base_region, &aws).await;
-        let ssm_client = SsmClient::new(&client_config);
+        let ssm_client = SsmClient::from_pubsys_config(&client_config, &aws);
         ssm_clients.insert(region.clone(), ssm_client);
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
+        ec2_clients.insert(region.clone(), ec2_client);
     }
 
     // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Non-image-specific context for building and rendering templates
     let source_build_context = BuildContext {
-        variant: &promote_args.variant,
-        arch: promote_args.arch.as_str(),
-        image_version: &promote_args.source,
+        variant: &options.variant,
+        arch: options.arch.as_str(),
+        image_version: &options.source,
     };
 
     let target_build_context = BuildContext {
-        variant: &promote_args.variant,
-        arch: promote_args.arch.as_str(),
-        image_version: &promote_args.target,
+        variant: &options.variant,
+        arch: options.arch.as_str(),
+        image_version: &options.target,
     };
 
     info!(
         "Parsing SSM parameter templates from {}",
-        promote_args.template_path.display()
+        options.template_path.display()
     );
     // Doesn't matter which build context we use to find template files because version isn't used
     // in their naming
     let template_parameters =
-        template::get_parameters(&promote_args.template_path, &source_build_context)
+        template::get_parameters(&options.template_path, &source_build_context)
             .context(error::FindTemplatesSnafu)?;
 
     if template_parameters.parameters.is_empty() {
         info!(
             "No parameters for this arch/variant in {}",
-            promote_args.template_path.display()
+            options.template_path.display()
         );
         return Ok(());
     }
@@ -157,7 +228,7 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     // SSM get/compare   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     info!("Getting current SSM parameters for source and target names");
-    let current_source_parameters = ssm::get_parameters(&source_keys, &ssm_clients)
+    let current_source_parameters = ssm::get_parameters(&source_keys, &ssm_clients, &rate_limits)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!(
@@ -167,11 +238,29 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     ensure!(
         !current_source_parameters.is_empty(),
         error::EmptySourceSnafu {
-            version: &promote_args.source
+            version: &options.source
         }
     );
 
-    let current_target_parameters = ssm::get_parameters(&target_keys, &ssm_clients)
+    // The source parameters that hold AMI IDs tell us which AMIs we'd be promoting.
+    if options.require_tests {
+        let mut images: Vec<(Region, String)> = current_source_parameters
+            .iter()
+            .filter(|(_, value)| value.starts_with("ami-"))
+            .map(|(key, value)| (key.region.clone(), value.clone()))
+            .collect();
+        images.sort_by(|a, b| (a.0.as_ref(), &a.1).cmp(&(b.0.as_ref(), &b.1)));
+        images.dedup();
+        info!(
+            "Checking that the {} source AMIs passed their tests",
+            images.len()
+        );
+        test_trigger::check_passed(&images, &ec2_clients, &rate_limits)
+            .await
+            .context(error::TestsSnafu)?;
+    }
+
+    let current_target_parameters = ssm::get_parameters(&target_keys, &ssm_clients, &rate_limits)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!(
@@ -207,10 +296,16 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
         return Ok(());
     }
 
+    // A plan only records the changes, so there's nothing to add to the output file yet.
+    if state.plan.planning() {
+        plan_parameters(&state.plan, &set_parameters, &current_target_parameters);
+        return Ok(());
+    }
+
     // If an output file path was given, read the existing parameters in `ssm_parameter_output` and
     // write the newly promoted parameters to `ssm_parameter_output` along with the original
     // parameters
-    if let Some(ssm_parameter_output) = &promote_args.ssm_parameter_output {
+    if let Some(ssm_parameter_output) = &options.ssm_parameter_output {
         append_rendered_parameters(ssm_parameter_output, &set_parameters, source_target_map)
             .await?;
     }
@@ -218,12 +313,25 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     // SSM set   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     info!("Setting updated SSM parameters.");
-    ssm::set_parameters(&set_parameters, &ssm_clients)
+    state
+        .tracer
+        .traced(
+            "set_parameters",
+            None,
+            ssm::set_parameters(&set_parameters, &ssm_clients, &rate_limits, state),
+        )
         .await
         .context(error::SetSsmSnafu)?;
 
+    if !aws.tags.is_empty() {
+        info!("Applying default tags to SSM parameters.");
+        ssm::tag_parameters(&set_parameters, &ssm_clients, &rate_limits, &ssm_tags(&aws))
+            .await
+            .context(error::TagSsmSnafu)?;
+    }
+
     info!("Validating whether live parameters in SSM reflect changes.");
-    ssm::validate_parameters(&set_parameters, &ssm_clients)
+    ssm::validate_parameters(&set_parameters, &ssm_clients, &rate_limits)
         .await
         .context(error::ValidateSsmSnafu)?;
 
@@ -317,7 +425,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error reading config: {}", source))]
         Config {
             source: pubsys_config::Error,
@@ -353,6 +461,16 @@ mod error {
             source: ssm::Error,
         },
 
+        #[snafu(display("Failed to tag SSM parameters: {}", source))]
+        TagSsm {
+            source: ssm::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Tests {
+            source: crate::test_trigger::Error,
+        },
+
         ValidateSsm {
             source: ssm::Error,
         },
@@ -379,11 +497,20 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 
 use super::ssm::write_rendered_parameters;
 type Result<T> = std::result::Result<T, error::Error>;
 
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
@@ -0,0 +1,297 @@
+//! The proxy module builds the HTTP connector that every AWS SDK client, and the credentials
+//! providers behind them, use when requests have to go through an HTTP proxy.
+//!
+//! The proxy is `aws.proxy` from Infra.toml if given, otherwise the one in the HTTPS_PROXY
+//! environment variable.  Hosts listed in NO_PROXY are reached directly.  Requests are tunneled
+//! through the proxy with CONNECT, and TLS is negotiated with the AWS endpoint inside the tunnel,
+//! the same way the SDK's own connector does it, so the proxy never sees the requests.
+
+use aws_config::provider_config::ProviderConfig;
+use aws_smithy_async::rt::sleep::AsyncSleep;
+use aws_smithy_client::erase::DynConnector;
+use aws_smithy_client::http_connector::{ConnectorSettings, HttpConnector};
+use aws_smithy_client::hyper_ext::Adapter;
+use hyper::client::HttpConnector as TcpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, warn};
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{env, error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// The most we read of a proxy's response to CONNECT before giving up on it
+const MAX_CONNECT_RESPONSE_LEN: usize = 8192;
+
+/// Where to send AWS requests through a proxy, and which hosts to reach directly
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProxySettings {
+    proxy: Url,
+    /// Host names, and domains they're in, that are reached directly
+    no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Returns the proxy settings from the pubsys config and the environment, or None if no proxy
+    /// is configured.
+    fn from_config(pubsys_aws_config: &PubsysAwsConfig) -> Option<Self> {
+        Self::new(
+            pubsys_aws_config.proxy.clone(),
+            env_var(&["HTTPS_PROXY", "https_proxy"]),
+            env_var(&["NO_PROXY", "no_proxy"]),
+        )
+    }
+
+    /// Returns the proxy settings from the given values, preferring the configured proxy to the
+    /// one from the environment.
+    fn new(
+        configured: Option<Url>,
+        https_proxy: Option<String>,
+        no_proxy: Option<String>,
+    ) -> Option<Self> {
+        let proxy = match configured {
+            Some(proxy) => proxy,
+            None => {
+                let https_proxy = https_proxy?;
+                // Proxies are often given without a scheme, like "proxy.example.com:3128".
+                let with_scheme = if https_proxy.contains("://") {
+                    https_proxy
+                } else {
+                    format!("http://{}", https_proxy)
+                };
+                match Url::parse(&with_scheme) {
+                    Ok(proxy) => proxy,
+                    Err(e) => {
+                        warn!(
+                            "Not sending AWS requests through invalid proxy '{}': {}",
+                            with_scheme, e
+                        );
+                        return None;
+                    }
+                }
+            }
+        };
+        let no_proxy = no_proxy
+            .unwrap_or_default()
+            .split(',')
+            .map(|entry| {
+                entry
+                    .trim()
+                    .trim_start_matches("*.")
+                    .trim_start_matches('.')
+            })
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        Some(Self { proxy, no_proxy })
+    }
+
+    /// Returns whether requests to the given host go through the proxy.
+    fn proxies(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        !self
+            .no_proxy
+            .iter()
+            .any(|entry| entry == "*" || host == *entry || host.ends_with(&format!(".{}", entry)))
+    }
+}
+
+/// Returns the value of the first of the given environment variables that's set and not empty.
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Connects to hosts over TCP, through a CONNECT tunnel when the proxy settings call for it
+#[derive(Debug, Clone)]
+struct ProxyTunnel {
+    settings: Arc<ProxySettings>,
+    tcp: TcpConnector,
+}
+
+impl Service<Uri> for ProxyTunnel {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.tcp.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, destination: Uri) -> Self::Future {
+        let mut tcp = self.tcp.clone();
+        let settings = self.settings.clone();
+        Box::pin(async move {
+            let host = destination.host().unwrap_or_default().to_string();
+            if !settings.proxies(&host) {
+                return Ok(tcp.call(destination).await?);
+            }
+            if settings.proxy.scheme() != "http" {
+                return Err(format!(
+                    "Proxy '{}' isn't supported for AWS requests; only http:// proxies are",
+                    settings.proxy
+                )
+                .into());
+            }
+            let port = destination
+                .port_u16()
+                .unwrap_or(match destination.scheme_str() {
+                    Some("http") => 80,
+                    _ => 443,
+                });
+            debug!("Connecting to {}:{} through {}", host, port, settings.proxy);
+            let proxy_uri: Uri = settings.proxy.as_str().parse()?;
+            let mut stream = tcp.call(proxy_uri).await?;
+            tunnel(&mut stream, &host, port, &settings.proxy).await?;
+            Ok(stream)
+        })
+    }
+}
+
+/// Asks the proxy on the other end of the stream to open a tunnel to the given host and port.
+async fn tunnel(stream: &mut TcpStream, host: &str, port: u16, proxy: &Url) -> io::Result<()> {
+    let mut request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+        host = host,
+        port = port
+    );
+    if !proxy.username().is_empty() {
+        let credentials = format!("{}:{}", proxy.username(), proxy.password().unwrap_or(""));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // The proxy sends nothing after its response until we start TLS, so we can read up to the end
+    // of its headers without reading into the tunnel.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("proxy {} sent an overlong response to CONNECT", proxy),
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "proxy {} refused to connect to {}:{}: {}",
+                proxy, host, port, status_line
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns a TLS connector over the given tunnel, with the trust roots and protocols the SDK's
+/// default connector uses.
+fn https_connector(tunnel: ProxyTunnel) -> HttpsConnector<ProxyTunnel> {
+    HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(tunnel)
+}
+
+/// Returns a tunnel for the proxy settings from the pubsys config and environment, or None if no
+/// proxy is configured.
+fn proxy_tunnel(pubsys_aws_config: &PubsysAwsConfig) -> Option<ProxyTunnel> {
+    let settings = ProxySettings::from_config(pubsys_aws_config)?;
+    let mut tcp = TcpConnector::new();
+    // The TLS connector hands us https:// URIs to connect to.
+    tcp.enforce_http(false);
+    Some(ProxyTunnel {
+        settings: Arc::new(settings),
+        tcp,
+    })
+}
+
+/// Returns the connector for AWS SDK configs to use, or None if no proxy is configured and the
+/// SDK's default connector should be used.  Connect and read timeouts from the SDK config still
+/// apply.
+pub(crate) fn http_connector(pubsys_aws_config: &PubsysAwsConfig) -> Option<HttpConnector> {
+    let https = https_connector(proxy_tunnel(pubsys_aws_config)?);
+    let make_connector = move |settings: &ConnectorSettings, sleep: Option<Arc<dyn AsyncSleep>>| {
+        let mut builder = Adapter::builder().connector_settings(settings.clone());
+        if let Some(sleep) = sleep {
+            builder = builder.sleep_impl(sleep);
+        }
+        Some(DynConnector::new(builder.build(https.clone())))
+    };
+    Some(HttpConnector::ConnectorFn(Arc::new(make_connector)))
+}
+
+/// Returns the config for credentials providers, like the default chain and AssumeRole, which
+/// uses the proxy if one is configured.
+pub(crate) fn provider_config(pubsys_aws_config: &PubsysAwsConfig) -> ProviderConfig {
+    let provider_config = ProviderConfig::default();
+    match proxy_tunnel(pubsys_aws_config) {
+        Some(tunnel) => provider_config.with_tcp_connector(https_connector(tunnel)),
+        None => provider_config,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProxySettings;
+    use url::Url;
+
+    #[test]
+    fn configured_proxy_preferred() {
+        let configured = Url::parse("http://configured:3128").unwrap();
+        let settings = ProxySettings::new(
+            Some(configured.clone()),
+            Some("http://env:3128".to_string()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.proxy, configured);
+        assert!(ProxySettings::new(None, None, Some("example.com".to_string())).is_none());
+    }
+
+    #[test]
+    fn env_proxy_without_scheme() {
+        let settings =
+            ProxySettings::new(None, Some("proxy.example.com:3128".to_string()), None).unwrap();
+        assert_eq!(settings.proxy.as_str(), "http://proxy.example.com:3128/");
+    }
+
+    #[test]
+    fn no_proxy_hosts() {
+        let settings = ProxySettings::new(
+            None,
+            Some("http://proxy:3128".to_string()),
+            Some("localhost, .internal.example.com,*.amazonaws.com.cn".to_string()),
+        )
+        .unwrap();
+        assert!(settings.proxies("ec2.us-west-2.amazonaws.com"));
+        assert!(!settings.proxies("localhost"));
+        assert!(!settings.proxies("s3.internal.example.com"));
+        assert!(!settings.proxies("ec2.cn-north-1.amazonaws.com.cn"));
+
+        let everything =
+            ProxySettings::new(None, Some("proxy:3128".to_string()), Some("*".to_string()))
+                .unwrap();
+        assert!(!everything.proxies("ec2.us-west-2.amazonaws.com"));
+    }
+}
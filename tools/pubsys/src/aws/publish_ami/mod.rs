@@ -1,12 +1,22 @@
 //! The publish_ami module owns the 'publish-ami' subcommand and controls the process of granting
 //! and revoking access to EC2 AMIs.
 
+pub(crate) mod verify;
+
+use crate::aws::ami::block_public_access;
 use crate::aws::ami::launch_permissions::{get_launch_permissions, LaunchPermissionDef};
 use crate::aws::ami::wait::{self, wait_for_ami};
 use crate::aws::ami::Image;
-use crate::aws::client::build_client_config;
+use crate::aws::check_permissions::{self, Operation};
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
 use crate::aws::region_from_string;
+use crate::exit_code::{Classify, Kind};
+use crate::notify::BuildInfo;
+use crate::plan::{Grantees, Mutation, Recorder};
+use crate::run_state::RunState;
 use crate::Args;
+use aws_config::SdkConfig;
 use aws_sdk_ec2::error::{ModifyImageAttributeError, ModifySnapshotAttributeError};
 use aws_sdk_ec2::model::{
     ImageAttributeName, OperationType, PermissionGroup, SnapshotAttributeName,
@@ -14,31 +24,62 @@ use aws_sdk_ec2::model::{
 use aws_sdk_ec2::output::{ModifyImageAttributeOutput, ModifySnapshotAttributeOutput};
 use aws_sdk_ec2::types::SdkError;
 use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_smithy_types::retry::ProvideErrorKind;
 use futures::future::{join, ready};
 use futures::stream::{self, StreamExt};
-use log::{debug, error, info, trace};
-use pubsys_config::InfraConfig;
+use governor::clock::DefaultClock;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use log::{debug, error, info, trace, warn};
+use nonzero_ext::nonzero;
+use pubsys_config::{AwsConfig as PubsysAwsConfig, InfraConfig};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::future::Future;
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::{clap, StructOpt};
+use verify::{verify_permissions, SnapshotChanges};
 
-#[derive(Debug, StructOpt)]
-pub(crate) struct ModifyOptions {
+// EC2 throttles mutating actions like ModifyImageAttribute with a token bucket per region that
+// refills at 5 tokens per second.
+// See https://docs.aws.amazon.com/AWSEC2/latest/APIReference/throttling.html#throttling-rate-based
+const MODIFY_ATTRIBUTE_RATE_LIMIT: Quota = Quota::per_second(nonzero!(5u32));
+
+// Requests that are throttled or fail transiently are retried with exponential backoff.
+const MAX_MODIFY_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+// Throttling is per region, so we can safely work on all regions at once.
+const MAX_PARALLEL_REGIONS: usize = 32;
+const MAX_PARALLEL_SNAPSHOTS: usize = 4;
+
+/// A rate limiter for permission changes, keyed by region because EC2 throttles each region
+/// separately.  One limiter should be shared by all requests made during an operation.
+pub(crate) type RegionRateLimiter =
+    RateLimiter<Region, DefaultKeyedStateStore<Region>, DefaultClock>;
+
+/// Creates a rate limiter suitable for modifying image and snapshot attributes.
+pub(crate) fn modify_rate_limiter() -> RegionRateLimiter {
+    RateLimiter::keyed(MODIFY_ATTRIBUTE_RATE_LIMIT)
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct ModifyOptions {
     /// User IDs to give/remove access
     #[structopt(long, use_delimiter = true, group = "who")]
-    pub(crate) user_ids: Vec<String>,
+    pub user_ids: Vec<String>,
     /// Group names to give/remove access
     #[structopt(long, use_delimiter = true, group = "who")]
-    pub(crate) group_names: Vec<String>,
+    pub group_names: Vec<String>,
     /// Organization arns to give/remove access
     #[structopt(long, use_delimiter = true, group = "who")]
-    pub(crate) organization_arns: Vec<String>,
+    pub organization_arns: Vec<String>,
     /// Organizational unit arns to give/remove access
     #[structopt(long, use_delimiter = true, group = "who")]
-    pub(crate) organizational_unit_arns: Vec<String>,
+    pub organizational_unit_arns: Vec<String>,
 }
 
 /// Grants or revokes permissions to Bottlerocket AMIs
@@ -46,7 +87,7 @@ pub(crate) struct ModifyOptions {
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
 #[structopt(group = clap::ArgGroup::with_name("mode").required(true).multiple(false))]
 #[structopt(group = clap::ArgGroup::with_name("who").required(true).multiple(true))]
-pub(crate) struct PublishArgs {
+pub struct PublishArgs {
     /// Path to the JSON file containing regional AMI IDs to modify
     #[structopt(long)]
     ami_input: PathBuf,
@@ -63,31 +104,144 @@ pub(crate) struct PublishArgs {
     #[structopt(long, group = "mode")]
     revoke: bool,
 
+    /// If granting public access and Image Block Public Access is enabled in any region, disable
+    /// it there first rather than failing
+    #[structopt(long)]
+    disable_block_public_access: bool,
+
+    /// Check that the configured credentials have the permissions needed before starting
+    #[structopt(long)]
+    preflight: bool,
+
     #[structopt(flatten)]
     modify_opts: ModifyOptions,
+
+    /// Only change AMI launch permissions, leaving snapshot permissions as they are
+    #[structopt(long, conflicts_with = "snapshot-accounts-path")]
+    skip_snapshot_permissions: bool,
+
+    /// Path to a JSON list of account IDs whose snapshot permissions should change, instead of
+    /// the users and groups given for the AMIs
+    #[structopt(long)]
+    snapshot_accounts_path: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    build_info: BuildInfo,
+}
+
+/// Whether a publish operation grants or revokes access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishMode {
+    Grant,
+    Revoke,
+}
+
+/// The AMIs `publish` changes permissions on, and how
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    /// Path to the JSON file containing regional AMI IDs to modify; it's rewritten with the
+    /// updated AMIs
+    pub ami_input: PathBuf,
+    /// Regions to publish in, which must be in the `ami_input` file; the regions in Infra.toml
+    /// are used if this is empty
+    pub regions: Vec<String>,
+    /// Whether to grant or revoke access
+    pub mode: PublishMode,
+    /// If granting public access and Image Block Public Access is enabled in any region, disable
+    /// it there first rather than failing
+    pub disable_block_public_access: bool,
+    /// Check that the configured credentials have the permissions needed before starting
+    pub preflight: bool,
+    /// The users and groups to give or remove access
+    pub modify_opts: ModifyOptions,
+    /// Only change AMI launch permissions, leaving snapshot permissions as they are
+    pub skip_snapshot_permissions: bool,
+    /// Path to a JSON list of account IDs whose snapshot permissions should change, instead of
+    /// the users and groups given for the AMIs
+    pub snapshot_accounts_path: Option<PathBuf>,
+}
+
+impl From<&PublishArgs> for PublishOptions {
+    fn from(args: &PublishArgs) -> Self {
+        let mode = if args.grant {
+            PublishMode::Grant
+        } else if args.revoke {
+            PublishMode::Revoke
+        } else {
+            unreachable!("developer error: --grant and --revoke not required/exclusive");
+        };
+        Self {
+            ami_input: args.ami_input.clone(),
+            regions: args.regions.clone(),
+            mode,
+            disable_block_public_access: args.disable_block_public_access,
+            preflight: args.preflight,
+            modify_opts: args.modify_opts.clone(),
+            skip_snapshot_permissions: args.skip_snapshot_permissions,
+            snapshot_accounts_path: args.snapshot_accounts_path.clone(),
+        }
+    }
 }
 
 /// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
-    let (operation, description) = if publish_args.grant {
-        (OperationType::Add, "granting access")
-    } else if publish_args.revoke {
-        (OperationType::Remove, "revoking access")
-    } else {
-        unreachable!("developer error: --grant and --revoke not required/exclusive");
+pub async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
+    run_with_options(
+        args,
+        &PublishOptions::from(publish_args),
+        &publish_args.build_info,
+    )
+    .await
+}
+
+/// Publishes the AMIs, then records them and the build info for the run's notification, as the
+/// 'publish-ami' subcommand does.
+pub(crate) async fn run_with_options(
+    args: &Args,
+    options: &PublishOptions,
+    build_info: &BuildInfo,
+) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let result = match args.infra_config(true).context(error::ConfigSnafu) {
+        Ok(infra_config) => publish_with_state(&args.state, &infra_config, options).await,
+        Err(e) => Err(e),
     };
+    // Nothing changed if we only planned, so there's nothing to announce.
+    if !args.state.plan.planning() {
+        args.state
+            .published
+            .record(build_info, result.as_ref().ok());
+    }
+    result.map(drop)
+}
 
-    info!(
-        "Using AMI data from path: {}",
-        publish_args.ami_input.display()
-    );
-    let file = File::open(&publish_args.ami_input).context(error::FileSnafu {
+/// Grants or revokes access to the AMIs in the input file, and their snapshots, in each region.
+/// Returns the updated AMI in each region, keyed by region name.
+pub async fn publish(
+    infra_config: &InfraConfig,
+    options: &PublishOptions,
+) -> Result<HashMap<String, Image>> {
+    publish_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Publishes the AMIs like `publish`, planning and tracing in the given run.
+pub(crate) async fn publish_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &PublishOptions,
+) -> Result<HashMap<String, Image>> {
+    let (operation, description) = match options.mode {
+        PublishMode::Grant => (OperationType::Add, "granting access"),
+        PublishMode::Revoke => (OperationType::Remove, "revoking access"),
+    };
+
+    info!("Using AMI data from path: {}", options.ami_input.display());
+    let file = File::open(&options.ami_input).context(error::FileSnafu {
         op: "open",
-        path: &publish_args.ami_input,
+        path: &options.ami_input,
     })?;
     let mut ami_input: HashMap<String, Image> =
         serde_json::from_reader(file).context(error::DeserializeSnafu {
-            path: &publish_args.ami_input,
+            path: &options.ami_input,
         })?;
     trace!("Parsed AMI input: {:?}", ami_input);
 
@@ -96,22 +250,21 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     ensure!(
         !ami_input.is_empty(),
         error::InputSnafu {
-            path: &publish_args.ami_input
+            path: &options.ami_input
         }
     );
 
-    // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
-    let aws = infra_config.aws.unwrap_or_default();
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !publish_args.regions.is_empty() {
-        publish_args.regions.clone()
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
     } else {
-        aws.regions.clone().into()
+        aws.regions_for("publish_ami").clone().into()
     };
     ensure!(
         !regions.is_empty(),
@@ -135,6 +288,16 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
         }
     );
 
+    if options.preflight {
+        let preflight_regions: Vec<Region> = regions
+            .iter()
+            .map(|name| region_from_string(name))
+            .collect();
+        check_permissions::preflight(Operation::PublishAmi, &aws, &preflight_regions)
+            .await
+            .context(error::PreflightSnafu)?;
+    }
+
     // Parse region names
     let mut amis = HashMap::with_capacity(regions.len());
     for name in regions {
@@ -150,11 +313,32 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
 
     // We make a map storing our regional clients because they're used in a future and need to
     // live until the future is resolved.
+    let mut client_configs = HashMap::with_capacity(amis.len());
     let mut ec2_clients = HashMap::with_capacity(amis.len());
     for region in amis.keys() {
         let client_config = build_client_config(region, &base_region, &aws).await;
-        let ec2_client = Ec2Client::new(&client_config);
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
         ec2_clients.insert(region.clone(), ec2_client);
+        client_configs.insert(region.clone(), client_config);
+    }
+
+    // Image Block Public Access prevents AMIs from being made public, so check for it before we
+    // change any permissions rather than failing partway through.
+    if operation == OperationType::Add
+        && options
+            .modify_opts
+            .group_names
+            .iter()
+            .any(|group| group == PermissionGroup::All.as_str())
+    {
+        check_block_public_access(
+            state,
+            &client_configs,
+            &aws,
+            &rate_limits,
+            options.disable_block_public_access,
+        )
+        .await?;
     }
 
     // If AMIs aren't in "available" state, we can get a DescribeImages response that includes
@@ -169,7 +353,19 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     }
     let mut wait_requests = Vec::with_capacity(amis.len());
     for (region, image) in &amis {
-        let wait_future = wait_for_ami(&image.id, region, &base_region, "available", 1, &aws);
+        let wait_future = state.tracer.traced(
+            "wait_for_ami",
+            Some(region.as_ref()),
+            wait_for_ami(
+                &image.id,
+                region,
+                &base_region,
+                "available",
+                1,
+                &aws,
+                &rate_limits,
+            ),
+        );
         // Store the region and ID so we can include it in errors
         let info_future = ready((region.clone(), image.id.clone()));
         wait_requests.push(join(info_future, wait_future));
@@ -187,42 +383,193 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
         })?;
     }
 
-    let snapshots = get_regional_snapshots(&amis, &ec2_clients).await?;
-    trace!("Found snapshots: {:?}", snapshots);
+    let rate_limiter = modify_rate_limiter();
+    // Keep track of the snapshot changes we made so we can verify them.
+    let snapshot_changes = if options.skip_snapshot_permissions {
+        info!("Skipping snapshot permissions as requested");
+        None
+    } else {
+        // Snapshot permissions may intentionally differ from AMI permissions, for example when
+        // sharing AMIs with accounts that shouldn't be able to create volumes from them.
+        let snapshot_opts = match &options.snapshot_accounts_path {
+            Some(path) => Some(snapshot_modify_options(path)?),
+            None => None,
+        };
 
-    info!(
-        "Updating all snapshot permissions before changing any AMI permissions - {}",
-        description
-    );
-    modify_regional_snapshots(
-        &publish_args.modify_opts,
-        &operation,
-        &snapshots,
-        &ec2_clients,
-    )
-    .await?;
+        let snapshots = get_regional_snapshots(&amis, &ec2_clients, &rate_limits).await?;
+        trace!("Found snapshots: {:?}", snapshots);
+
+        info!(
+            "Updating all snapshot permissions before changing any AMI permissions - {}",
+            description
+        );
+        modify_regional_snapshots(
+            state,
+            snapshot_opts.as_ref().unwrap_or(&options.modify_opts),
+            &operation,
+            &snapshots,
+            &ec2_clients,
+            &rate_limiter,
+            &rate_limits,
+        )
+        .await?;
+        Some((snapshot_opts, snapshots))
+    };
 
     info!("Updating AMI permissions - {}", description);
     modify_regional_images(
-        &publish_args.modify_opts,
+        state,
+        &options.modify_opts,
         &operation,
         &mut amis,
         &ec2_clients,
+        &rate_limiter,
+        &rate_limits,
     )
     .await?;
 
-    write_amis(
-        &publish_args.ami_input,
-        &amis
-            .into_iter()
-            .map(|(region, image)| (region.to_string(), image))
-            .collect::<HashMap<String, Image>>(),
-    )?;
+    let ami_output = amis
+        .iter()
+        .map(|(region, image)| (region.to_string(), image.clone()))
+        .collect::<HashMap<String, Image>>();
+    // The permissions haven't changed yet, so the input is still accurate and there's nothing to
+    // verify.
+    if state.plan.planning() {
+        return Ok(ami_output);
+    }
+    write_amis(&options.ami_input, &ami_output)?;
+
+    // Describe everything again to make sure the changes took effect in every region.
+    info!("Verifying permissions in all regions");
+    let snapshot_changes =
+        snapshot_changes
+            .as_ref()
+            .map(|(snapshot_opts, snapshots)| SnapshotChanges {
+                modify_opts: snapshot_opts.as_ref().unwrap_or(&options.modify_opts),
+                snapshots,
+            });
+    verify_permissions(
+        &options.modify_opts,
+        snapshot_changes,
+        &operation,
+        &amis,
+        &ec2_clients,
+        &rate_limits,
+    )
+    .await
+    .context(error::VerifySnafu {
+        amis_affected: amis.len() as u16,
+    })?;
+
+    Ok(ami_output)
+}
+
+/// Reads the list of account IDs in the given file and returns options that modify snapshot
+/// permissions for only those accounts.
+fn snapshot_modify_options(path: &Path) -> Result<ModifyOptions> {
+    let file = File::open(path).context(error::FileSnafu { op: "open", path })?;
+    let user_ids: Vec<String> =
+        serde_json::from_reader(file).context(error::DeserializeSnafu { path })?;
+    ensure!(
+        !user_ids.is_empty(),
+        error::NoSnapshotAccountsSnafu { path }
+    );
+    trace!("Parsed snapshot accounts: {:?}", user_ids);
+
+    Ok(ModifyOptions {
+        user_ids,
+        group_names: Vec::new(),
+        organization_arns: Vec::new(),
+        organizational_unit_arns: Vec::new(),
+    })
+}
+
+/// Checks whether Image Block Public Access is enabled in any of the given regions.  If
+/// `disable` is true, the setting is disabled in those regions; otherwise, fails with a list of
+/// all blocked regions.
+async fn check_block_public_access(
+    state: &RunState,
+    client_configs: &HashMap<Region, SdkConfig>,
+    pubsys_aws_config: &PubsysAwsConfig,
+    rate_limits: &RateLimits,
+    disable: bool,
+) -> Result<()> {
+    info!("Checking Image Block Public Access state before granting public access");
+    let mut requests = Vec::with_capacity(client_configs.len());
+    for (region, client_config) in client_configs {
+        let state_future =
+            block_public_access::is_blocked(client_config, pubsys_aws_config, rate_limits, region);
+        // Store the region so we can include it in errors
+        let info_future = ready(region.clone());
+        requests.push(join(info_future, state_future));
+    }
+
+    // Send requests in parallel and wait for responses, collecting results into a list.
+    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let responses: Vec<(
+        Region,
+        std::result::Result<bool, block_public_access::Error>,
+    )> = request_stream.collect().await;
+
+    let mut blocked_regions = Vec::new();
+    for (region, response) in responses {
+        let blocked = response.context(error::BlockPublicAccessStateSnafu {
+            region: region.as_ref(),
+        })?;
+        if blocked {
+            blocked_regions.push(region);
+        }
+    }
+    if blocked_regions.is_empty() {
+        return Ok(());
+    }
+
+    let mut region_names: Vec<String> = blocked_regions.iter().map(|r| r.to_string()).collect();
+    region_names.sort();
+    ensure!(
+        disable,
+        error::BlockPublicAccessSnafu {
+            regions: region_names
+        }
+    );
+
+    if state.plan.planning() {
+        for region in region_names {
+            state
+                .plan
+                .add(Mutation::DisableBlockPublicAccess { region });
+        }
+        return Ok(());
+    }
+
+    info!(
+        "Disabling Image Block Public Access in: {}",
+        region_names.join(", ")
+    );
+    let mut requests = Vec::with_capacity(blocked_regions.len());
+    for region in &blocked_regions {
+        let disable_future = block_public_access::disable(
+            &client_configs[region],
+            pubsys_aws_config,
+            rate_limits,
+            region,
+        );
+        requests.push(join(ready(region.clone()), disable_future));
+    }
+    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let responses: Vec<(Region, std::result::Result<(), block_public_access::Error>)> =
+        request_stream.collect().await;
+
+    for (region, response) in responses {
+        response.context(error::DisableBlockPublicAccessSnafu {
+            region: region.as_ref(),
+        })?;
+    }
 
     Ok(())
 }
 
-pub(crate) fn write_amis(path: &PathBuf, amis: &HashMap<String, Image>) -> Result<()> {
+pub(crate) fn write_amis(path: &Path, amis: &HashMap<String, Image>) -> Result<()> {
     let file = File::create(path).context(error::FileSnafu {
         op: "write AMIs to file",
         path,
@@ -238,11 +585,17 @@ pub(crate) async fn get_snapshots(
     image_id: &str,
     region: &Region,
     ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
 ) -> Result<Vec<String>> {
-    let describe_response = ec2_client
-        .describe_images()
-        .set_image_ids(Some(vec![image_id.to_string()]))
-        .send()
+    let describe_response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .describe_images()
+                .set_image_ids(Some(vec![image_id.to_string()]))
+                .send(),
+        )
         .await
         .context(error::DescribeImagesSnafu {
             region: region.as_ref(),
@@ -308,13 +661,14 @@ pub(crate) async fn get_snapshots(
 async fn get_regional_snapshots(
     amis: &HashMap<Region, Image>,
     clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
 ) -> Result<HashMap<Region, Vec<String>>> {
     // Build requests for image information.
     let mut snapshots_requests = Vec::with_capacity(amis.len());
     for (region, image) in amis {
         let ec2_client = &clients[region];
 
-        let snapshots_future = get_snapshots(&image.id, region, ec2_client);
+        let snapshots_future = get_snapshots(&image.id, region, ec2_client, rate_limits);
 
         // Store the region so we can include it in errors
         let info_future = ready(region.clone());
@@ -338,33 +692,56 @@ async fn get_regional_snapshots(
 /// Modify createVolumePermission for the given users/groups on the given snapshots.  The
 /// `operation` should be "add" or "remove" to allow/deny permission.
 pub(crate) async fn modify_snapshots(
+    plan: &Recorder,
     modify_opts: &ModifyOptions,
     operation: &OperationType,
     snapshot_ids: &[String],
     ec2_client: &Ec2Client,
     region: &Region,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
 ) -> Result<()> {
+    if plan.planning() {
+        for snapshot_id in snapshot_ids {
+            let current =
+                verify::get_volume_permissions(ec2_client, rate_limits, region, snapshot_id)
+                    .await
+                    .context(error::VolumePermissionsSnafu)?;
+            plan.add(Mutation::ModifySnapshotPermissions {
+                region: region.to_string(),
+                snapshot_id: snapshot_id.clone(),
+                operation: operation.as_str().to_string(),
+                grantees: Grantees::from(modify_opts),
+                current,
+            });
+        }
+        return Ok(());
+    }
+
     let mut requests = Vec::new();
     for snapshot_id in snapshot_ids {
-        let response_future = ec2_client
-            .modify_snapshot_attribute()
-            .set_attribute(Some(SnapshotAttributeName::CreateVolumePermission))
-            .set_user_ids(
-                (!modify_opts.user_ids.is_empty()).then_some(modify_opts.user_ids.clone()),
-            )
-            .set_group_names(
-                (!modify_opts.group_names.is_empty()).then_some(modify_opts.group_names.clone()),
-            )
-            .set_operation_type(Some(operation.clone()))
-            .set_snapshot_id(Some(snapshot_id.clone()))
-            .send();
+        let response_future = send_with_retry(region, rate_limiter, rate_limits, move || {
+            ec2_client
+                .modify_snapshot_attribute()
+                .set_attribute(Some(SnapshotAttributeName::CreateVolumePermission))
+                .set_user_ids(
+                    (!modify_opts.user_ids.is_empty()).then_some(modify_opts.user_ids.clone()),
+                )
+                .set_group_names(
+                    (!modify_opts.group_names.is_empty())
+                        .then_some(modify_opts.group_names.clone()),
+                )
+                .set_operation_type(Some(operation.clone()))
+                .set_snapshot_id(Some(snapshot_id.clone()))
+                .send()
+        });
         // Store the snapshot_id so we can include it in any errors
         let info_future = ready(snapshot_id.to_string());
         requests.push(join(info_future, response_future));
     }
 
     // Send requests in parallel and wait for responses, collecting results into a list.
-    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let request_stream = stream::iter(requests).buffer_unordered(MAX_PARALLEL_SNAPSHOTS);
     let responses: Vec<(
         String,
         std::result::Result<ModifySnapshotAttributeOutput, SdkError<ModifySnapshotAttributeError>>,
@@ -383,25 +760,48 @@ pub(crate) async fn modify_snapshots(
 /// Modify createVolumePermission for the given users/groups, across all of the snapshots in the
 /// given regional mapping.  The `operation` should be "add" or "remove" to allow/deny permission.
 pub(crate) async fn modify_regional_snapshots(
+    state: &RunState,
     modify_opts: &ModifyOptions,
     operation: &OperationType,
     snapshots: &HashMap<Region, Vec<String>>,
     clients: &HashMap<Region, Ec2Client>,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
 ) -> Result<()> {
     // Build requests to modify snapshot attributes.
     let mut requests = Vec::new();
     for (region, snapshot_ids) in snapshots {
         let ec2_client = &clients[region];
-        let modify_snapshot_future =
-            modify_snapshots(modify_opts, operation, snapshot_ids, ec2_client, region);
+        let modify_snapshot_future = state.tracer.traced(
+            "modify_snapshots",
+            Some(region.as_ref()),
+            modify_snapshots(
+                &state.plan,
+                modify_opts,
+                operation,
+                snapshot_ids,
+                ec2_client,
+                region,
+                rate_limiter,
+                rate_limits,
+            ),
+        );
 
         // Store the region and snapshot ID so we can include it in errors
         let info_future = ready((region.clone(), snapshot_ids.clone()));
-        requests.push(join(info_future, modify_snapshot_future));
+        requests.push((
+            region.to_string(),
+            join(info_future, modify_snapshot_future),
+        ));
     }
 
     // Send requests in parallel and wait for responses, collecting results into a list.
-    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let request_stream = stream::iter(
+        state
+            .interrupt
+            .tracked("snapshot permission changes", requests),
+    )
+    .buffer_unordered(MAX_PARALLEL_REGIONS);
 
     #[allow(clippy::type_complexity)]
     let responses: Vec<((Region, Vec<String>), Result<()>)> = request_stream.collect().await;
@@ -451,52 +851,122 @@ pub(crate) async fn modify_image(
     operation: &OperationType,
     image_id: &str,
     ec2_client: &Ec2Client,
+    region: &Region,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
 ) -> std::result::Result<ModifyImageAttributeOutput, SdkError<ModifyImageAttributeError>> {
-    ec2_client
-        .modify_image_attribute()
-        .set_attribute(Some(
-            ImageAttributeName::LaunchPermission.as_ref().to_string(),
-        ))
-        .set_user_ids((!modify_opts.user_ids.is_empty()).then_some(modify_opts.user_ids.clone()))
-        .set_user_groups(
-            (!modify_opts.group_names.is_empty()).then_some(modify_opts.group_names.clone()),
-        )
-        .set_organization_arns(
-            (!modify_opts.organization_arns.is_empty())
-                .then_some(modify_opts.organization_arns.clone()),
-        )
-        .set_organizational_unit_arns(
-            (!modify_opts.organizational_unit_arns.is_empty())
-                .then_some(modify_opts.organizational_unit_arns.clone()),
-        )
-        .set_operation_type(Some(operation.clone()))
-        .set_image_id(Some(image_id.to_string()))
-        .send()
+    send_with_retry(region, rate_limiter, rate_limits, || {
+        ec2_client
+            .modify_image_attribute()
+            .set_attribute(Some(
+                ImageAttributeName::LaunchPermission.as_ref().to_string(),
+            ))
+            .set_user_ids(
+                (!modify_opts.user_ids.is_empty()).then_some(modify_opts.user_ids.clone()),
+            )
+            .set_user_groups(
+                (!modify_opts.group_names.is_empty()).then_some(modify_opts.group_names.clone()),
+            )
+            .set_organization_arns(
+                (!modify_opts.organization_arns.is_empty())
+                    .then_some(modify_opts.organization_arns.clone()),
+            )
+            .set_organizational_unit_arns(
+                (!modify_opts.organizational_unit_arns.is_empty())
+                    .then_some(modify_opts.organizational_unit_arns.clone()),
+            )
+            .set_operation_type(Some(operation.clone()))
+            .set_image_id(Some(image_id.to_string()))
+            .send()
+    })
+    .await
+}
+
+/// Records a change to launchPermission for the given users/groups on the given image in the plan,
+/// along with the image's current launch permissions, rather than making it.
+pub(crate) async fn plan_image_permissions(
+    plan: &Recorder,
+    modify_opts: &ModifyOptions,
+    operation: &OperationType,
+    image_id: &str,
+    ec2_client: &Ec2Client,
+    region: &Region,
+    rate_limits: &RateLimits,
+) -> Result<()> {
+    let current = get_launch_permissions(ec2_client, rate_limits, region.as_ref(), image_id)
         .await
+        .context(error::DescribeImageAttributeSnafu {
+            image_id,
+            region: region.as_ref(),
+        })?;
+    plan.add(Mutation::ModifyImagePermissions {
+        region: region.to_string(),
+        image_id: image_id.to_string(),
+        operation: operation.as_str().to_string(),
+        grantees: Grantees::from(modify_opts),
+        current,
+    });
+    Ok(())
 }
 
 /// Modify launchPermission for the given users/groups, across all of the images in the given
 /// regional mapping.  The `operation` should be "add" or "remove" to allow/deny permission.
 pub(crate) async fn modify_regional_images(
+    state: &RunState,
     modify_opts: &ModifyOptions,
     operation: &OperationType,
     images: &mut HashMap<Region, Image>,
     clients: &HashMap<Region, Ec2Client>,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
 ) -> Result<()> {
+    if state.plan.planning() {
+        for (region, image) in images.iter() {
+            plan_image_permissions(
+                &state.plan,
+                modify_opts,
+                operation,
+                &image.id,
+                &clients[region],
+                region,
+                rate_limits,
+            )
+            .await?;
+        }
+        return Ok(());
+    }
+
     let mut requests = Vec::new();
     for (region, image) in &mut *images {
         let image_id = &image.id;
         let ec2_client = &clients[region];
 
-        let modify_image_future = modify_image(modify_opts, operation, image_id, ec2_client);
+        let modify_image_future = state.tracer.traced(
+            "modify_image",
+            Some(region.as_ref()),
+            modify_image(
+                modify_opts,
+                operation,
+                image_id,
+                ec2_client,
+                region,
+                rate_limiter,
+                rate_limits,
+            ),
+        );
 
         // Store the region and image ID so we can include it in errors
         let info_future = ready((region.as_ref().to_string(), image_id.clone()));
-        requests.push(join(info_future, modify_image_future));
+        requests.push((region.to_string(), join(info_future, modify_image_future)));
     }
 
     // Send requests in parallel and wait for responses, collecting results into a list.
-    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let request_stream = stream::iter(
+        state
+            .interrupt
+            .tracked("image permission changes", requests),
+    )
+    .buffer_unordered(MAX_PARALLEL_REGIONS);
     #[allow(clippy::type_complexity)]
     let responses: Vec<(
         (String, String),
@@ -520,6 +990,7 @@ pub(crate) async fn modify_regional_images(
                 )?;
                 let launch_permissions: Vec<LaunchPermissionDef> = get_launch_permissions(
                     &clients[&Region::new(region.clone())],
+                    rate_limits,
                     region.as_ref(),
                     &image_id,
                 )
@@ -560,8 +1031,59 @@ pub(crate) async fn modify_regional_images(
     Ok(())
 }
 
+/// Sends the request built by `send` in the given region, waiting for the region's rate limiter
+/// and the operation's EC2 rate limit before each attempt, and retrying with exponential backoff if the
+/// request was throttled or failed transiently.
+async fn send_with_retry<T, E, F, Fut>(
+    region: &Region,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
+    send: F,
+) -> std::result::Result<T, SdkError<E>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::result::Result<T, SdkError<E>>>,
+    E: ProvideErrorKind,
+{
+    let mut attempt = 1;
+    loop {
+        rate_limiter.until_key_ready(region).await;
+        match rate_limits.rate_limited(EC2, region.as_ref(), send()).await {
+            Err(e) if attempt < MAX_MODIFY_ATTEMPTS && is_retryable(&e) => {
+                let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                warn!(
+                    "Request in {} failed on attempt {} of {}, retrying in {:?}",
+                    region, attempt, MAX_MODIFY_ATTEMPTS, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns whether the given error is worth retrying: a throttle, a transient service error, or a
+/// failure to get a response at all.
+fn is_retryable<E: ProvideErrorKind>(error: &SdkError<E>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(context) => {
+            let err = context.err();
+            err.retryable_error_kind().is_some()
+                || matches!(
+                    err.code(),
+                    Some("RequestLimitExceeded" | "InternalError" | "Unavailable")
+                )
+        }
+        _ => false,
+    }
+}
+
 mod error {
-    use crate::aws::ami;
+    use crate::aws::{ami, check_permissions};
     use aws_sdk_ec2::error::{
         DescribeImagesError, ModifyImageAttributeError, ModifySnapshotAttributeError,
     };
@@ -572,7 +1094,23 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
+        #[snafu(display(
+            "Image Block Public Access is enabled in {}; disable it or pass --disable-block-public-access",
+            regions.join(", ")
+        ))]
+        BlockPublicAccess { regions: Vec<String> },
+
+        #[snafu(display(
+            "Failed to get Image Block Public Access state in {}: {}",
+            region,
+            source
+        ))]
+        BlockPublicAccessState {
+            region: String,
+            source: ami::block_public_access::Error,
+        },
+
         #[snafu(display("Error reading config: {}", source))]
         Config { source: pubsys_config::Error },
 
@@ -600,6 +1138,16 @@ mod error {
             source: serde_json::Error,
         },
 
+        #[snafu(display(
+            "Failed to disable Image Block Public Access in {}: {}",
+            region,
+            source
+        ))]
+        DisableBlockPublicAccess {
+            region: String,
+            source: ami::block_public_access::Error,
+        },
+
         #[snafu(display("Failed to {} '{}': {}", op, path.display(), source))]
         File {
             op: String,
@@ -670,6 +1218,12 @@ mod error {
         #[snafu(display("DescribeImages in {} with unique filters returned multiple results: {}", region, images.join(", ")))]
         MultipleImages { region: String, images: Vec<String> },
 
+        #[snafu(display("No account IDs found in '{}'", path.display()))]
+        NoSnapshotAccounts { path: PathBuf },
+
+        #[snafu(display("Permission check failed: {}", source))]
+        Preflight { source: check_permissions::Error },
+
         #[snafu(display("Failed to serialize output to '{}': {}", path.display(), source))]
         Serialize {
             path: PathBuf,
@@ -682,6 +1236,15 @@ mod error {
         ))]
         UnknownRegions { regions: Vec<String> },
 
+        #[snafu(display("Failed to verify permissions: {}", source))]
+        Verify {
+            amis_affected: u16,
+            source: super::verify::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        VolumePermissions { source: super::verify::Error },
+
         #[snafu(display("AMI '{}' in {} did not become available: {}", id, region, source))]
         WaitAmi {
             id: String,
@@ -697,10 +1260,13 @@ mod error {
                 // We list all of these variants so that future editors of the code will have to
                 // look at this and decide whether or not their new error variant might have
                 // modified any AMI permissions.
-                Error::Config { .. }
+                Error::BlockPublicAccess { .. }
+                | Error::BlockPublicAccessState { .. }
+                | Error::Config { .. }
                 | Error::DescribeImageAttribute { .. }
                 | Error::DescribeImages { .. }
                 | Error::Deserialize { .. }
+                | Error::DisableBlockPublicAccess { .. }
                 | Error::File { .. }
                 | Error::Input { .. }
                 | Error::MissingConfig { .. }
@@ -711,6 +1277,8 @@ mod error {
                 | Error::ModifyImageAttributes { .. }
                 | Error::ModifySnapshotAttributes { .. }
                 | Error::MultipleImages { .. }
+                | Error::NoSnapshotAccounts { .. }
+                | Error::Preflight { .. }
                 | Error::Serialize { .. }
                 | Error::UnknownRegions { .. }
                 | Error::WaitAmi { .. } => 0u16,
@@ -721,9 +1289,25 @@ mod error {
                     error_count: _,
                     success_count,
                 } => *success_count,
+
+                // Verification happens after all permissions were updated.
+                Error::Verify { amis_affected, .. } => *amis_affected,
             }
         }
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DescribeImages { .. }
+            | Self::ModifyImageAttribute { .. }
+            | Self::ModifyImageAttributes { .. }
+            | Self::ModifySnapshotAttributes { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
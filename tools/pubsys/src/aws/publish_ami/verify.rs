@@ -0,0 +1,441 @@
+//! The verify module owns checking that the permission changes made by publish-ami took effect in
+//! every region; it's like a validate-ami that only looks at the permissions we just changed.
+
+use super::{ModifyOptions, MAX_PARALLEL_REGIONS};
+use crate::aws::ami::launch_permissions::{get_launch_permissions, LaunchPermissionDef};
+use crate::aws::ami::Image;
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
+use aws_sdk_ec2::model::{OperationType, SnapshotAttributeName};
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use futures::stream::{self, StreamExt};
+use log::{info, warn};
+use snafu::{ensure, ResultExt};
+use std::collections::HashMap;
+use std::time::Duration;
+use tabled::{Table, Tabled};
+
+// Permission changes can take a moment to show up in describe calls, so we check a region again
+// before deciding it didn't converge.
+const MAX_VERIFY_ATTEMPTS: u32 = 5;
+const VERIFY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The snapshot permission changes to verify, if snapshot permissions were changed.
+pub(crate) struct SnapshotChanges<'a> {
+    pub(crate) modify_opts: &'a ModifyOptions,
+    pub(crate) snapshots: &'a HashMap<Region, Vec<String>>,
+}
+
+/// The outcome of verifying the permissions in one region.
+#[derive(Debug)]
+struct RegionVerification {
+    region: Region,
+    image_id: String,
+    /// Requested image permissions that weren't applied
+    image_mismatches: Vec<LaunchPermissionDef>,
+    /// Requested snapshot permissions that weren't applied, by snapshot ID, if snapshots were
+    /// checked
+    snapshot_mismatches: Option<Vec<(String, Vec<LaunchPermissionDef>)>>,
+}
+
+impl RegionVerification {
+    fn converged(&self) -> bool {
+        self.image_mismatches.is_empty()
+            && self
+                .snapshot_mismatches
+                .as_ref()
+                .map(|mismatches| mismatches.is_empty())
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Tabled)]
+struct VerificationRow {
+    region: String,
+    image: String,
+    image_permissions: String,
+    snapshot_permissions: String,
+}
+
+/// Re-describes the permissions of the given images and snapshots, and checks that the requested
+/// change was applied everywhere.  Prints a table of the results and fails if any region didn't
+/// converge.
+pub(crate) async fn verify_permissions(
+    modify_opts: &ModifyOptions,
+    snapshot_changes: Option<SnapshotChanges<'_>>,
+    operation: &OperationType,
+    images: &HashMap<Region, Image>,
+    clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+) -> Result<()> {
+    let image_requested = requested_permissions(modify_opts, true);
+    // Organizations can't be given snapshot permissions, so we don't change or check them.
+    let snapshot_requested = snapshot_changes
+        .as_ref()
+        .map(|changes| requested_permissions(changes.modify_opts, false));
+
+    let mut pending: Vec<Region> = images.keys().cloned().collect();
+    let mut verifications = Vec::with_capacity(pending.len());
+    for attempt in 1..=MAX_VERIFY_ATTEMPTS {
+        let mut requests = Vec::with_capacity(pending.len());
+        for region in &pending {
+            let snapshots = match (&snapshot_changes, &snapshot_requested) {
+                (Some(changes), Some(requested)) => Some((
+                    changes
+                        .snapshots
+                        .get(region)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                    requested.as_slice(),
+                )),
+                _ => None,
+            };
+            requests.push(verify_region(
+                region.clone(),
+                &images[region].id,
+                &clients[region],
+                rate_limits,
+                operation,
+                &image_requested,
+                snapshots,
+            ));
+        }
+
+        let request_stream = stream::iter(requests).buffer_unordered(MAX_PARALLEL_REGIONS);
+        let responses: Vec<Result<RegionVerification>> = request_stream.collect().await;
+
+        let mut retry = Vec::new();
+        for response in responses {
+            let verification = response?;
+            if verification.converged() || attempt == MAX_VERIFY_ATTEMPTS {
+                verifications.push(verification);
+            } else {
+                retry.push(verification.region);
+            }
+        }
+        if retry.is_empty() {
+            break;
+        }
+        warn!(
+            "Permissions haven't converged yet in {}, checking again in {:?}",
+            retry
+                .iter()
+                .map(|region| region.as_ref())
+                .collect::<Vec<_>>()
+                .join(", "),
+            VERIFY_RETRY_DELAY
+        );
+        tokio::time::sleep(VERIFY_RETRY_DELAY).await;
+        pending = retry;
+    }
+
+    verifications.sort_by(|a, b| a.region.as_ref().cmp(b.region.as_ref()));
+    let rows = verifications.iter().map(|verification| VerificationRow {
+        region: verification.region.to_string(),
+        image: verification.image_id.clone(),
+        image_permissions: describe_mismatches(&verification.image_mismatches, operation),
+        snapshot_permissions: match &verification.snapshot_mismatches {
+            None => "not changed".to_string(),
+            Some(mismatches) if mismatches.is_empty() => "ok".to_string(),
+            Some(mismatches) => mismatches
+                .iter()
+                .map(|(snapshot_id, mismatches)| {
+                    format!(
+                        "{} {}",
+                        snapshot_id,
+                        describe_mismatches(mismatches, operation)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        },
+    });
+    println!("{}", Table::new(rows));
+
+    let unconverged: Vec<String> = verifications
+        .iter()
+        .filter(|verification| !verification.converged())
+        .map(|verification| verification.region.to_string())
+        .collect();
+    ensure!(
+        unconverged.is_empty(),
+        error::NotConvergedSnafu {
+            regions: unconverged
+        }
+    );
+
+    info!("Verified permissions in all regions");
+    Ok(())
+}
+
+/// Describes the current permissions of an image and its snapshots in one region, and compares
+/// them against the requested change.
+async fn verify_region(
+    region: Region,
+    image_id: &str,
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    operation: &OperationType,
+    image_requested: &[LaunchPermissionDef],
+    snapshots: Option<(&[String], &[LaunchPermissionDef])>,
+) -> Result<RegionVerification> {
+    let launch_permissions =
+        get_launch_permissions(ec2_client, rate_limits, region.as_ref(), image_id)
+            .await
+            .context(error::DescribeImageAttributeSnafu {
+                image_id,
+                region: region.as_ref(),
+            })?;
+    let image_mismatches = mismatches(image_requested, &launch_permissions, operation);
+
+    let snapshot_mismatches = match snapshots {
+        None => None,
+        Some((snapshot_ids, snapshot_requested)) => {
+            let mut snapshot_mismatches = Vec::new();
+            for snapshot_id in snapshot_ids {
+                let volume_permissions =
+                    get_volume_permissions(ec2_client, rate_limits, &region, snapshot_id).await?;
+                let mismatches = mismatches(snapshot_requested, &volume_permissions, operation);
+                if !mismatches.is_empty() {
+                    snapshot_mismatches.push((snapshot_id.clone(), mismatches));
+                }
+            }
+            Some(snapshot_mismatches)
+        }
+    };
+
+    Ok(RegionVerification {
+        region,
+        image_id: image_id.to_string(),
+        image_mismatches,
+        snapshot_mismatches,
+    })
+}
+
+/// Returns the createVolumePermission entries of the given snapshot.  These are expressed as
+/// launch permissions, which are a superset, so they can be compared the same way.
+pub(crate) async fn get_volume_permissions(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &Region,
+    snapshot_id: &str,
+) -> Result<Vec<LaunchPermissionDef>> {
+    let response = rate_limits
+        .rate_limited(
+            EC2,
+            region.as_ref(),
+            ec2_client
+                .describe_snapshot_attribute()
+                .attribute(SnapshotAttributeName::CreateVolumePermission)
+                .snapshot_id(snapshot_id)
+                .send(),
+        )
+        .await
+        .context(error::DescribeSnapshotAttributeSnafu {
+            snapshot_id,
+            region: region.as_ref(),
+        })?;
+
+    Ok(response
+        .create_volume_permissions()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(
+            |permission| match (permission.group(), permission.user_id()) {
+                (Some(group), _) => Some(LaunchPermissionDef::Group(group.as_str().to_string())),
+                (None, Some(user_id)) => Some(LaunchPermissionDef::UserId(user_id.to_string())),
+                (None, None) => None,
+            },
+        )
+        .collect())
+}
+
+/// Returns the permissions described by the given options.
+fn requested_permissions(
+    modify_opts: &ModifyOptions,
+    include_organizations: bool,
+) -> Vec<LaunchPermissionDef> {
+    let mut requested = Vec::new();
+    requested.extend(
+        modify_opts
+            .user_ids
+            .iter()
+            .cloned()
+            .map(LaunchPermissionDef::UserId),
+    );
+    requested.extend(
+        modify_opts
+            .group_names
+            .iter()
+            .cloned()
+            .map(LaunchPermissionDef::Group),
+    );
+    if include_organizations {
+        requested.extend(
+            modify_opts
+                .organization_arns
+                .iter()
+                .cloned()
+                .map(LaunchPermissionDef::OrganizationArn),
+        );
+        requested.extend(
+            modify_opts
+                .organizational_unit_arns
+                .iter()
+                .cloned()
+                .map(LaunchPermissionDef::OrganizationalUnitArn),
+        );
+    }
+    requested
+}
+
+/// Returns the requested permissions that don't match the actual permissions: those that are
+/// missing if we were adding them, or still present if we were removing them.
+fn mismatches(
+    requested: &[LaunchPermissionDef],
+    actual: &[LaunchPermissionDef],
+    operation: &OperationType,
+) -> Vec<LaunchPermissionDef> {
+    let should_exist = *operation == OperationType::Add;
+    requested
+        .iter()
+        .filter(|permission| actual.contains(permission) != should_exist)
+        .cloned()
+        .collect()
+}
+
+/// Returns a short description of the given mismatches for the verification table.
+fn describe_mismatches(mismatches: &[LaunchPermissionDef], operation: &OperationType) -> String {
+    if mismatches.is_empty() {
+        return "ok".to_string();
+    }
+    let problem = if *operation == OperationType::Add {
+        "missing"
+    } else {
+        "still present"
+    };
+    let permissions = mismatches
+        .iter()
+        .map(|permission| match permission {
+            LaunchPermissionDef::Group(group) => format!("group {}", group),
+            LaunchPermissionDef::UserId(user_id) => format!("user {}", user_id),
+            LaunchPermissionDef::OrganizationArn(arn) => format!("organization {}", arn),
+            LaunchPermissionDef::OrganizationalUnitArn(arn) => {
+                format!("organizational unit {}", arn)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{}: {}", problem, permissions)
+}
+
+mod error {
+    use aws_sdk_ec2::error::DescribeSnapshotAttributeError;
+    use aws_sdk_ec2::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Failed to describe permissions of image {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        DescribeImageAttribute {
+            image_id: String,
+            region: String,
+            source: crate::aws::ami::launch_permissions::Error,
+        },
+
+        #[snafu(display(
+            "Failed to describe permissions of snapshot {} in {}: {}",
+            snapshot_id,
+            region,
+            source
+        ))]
+        DescribeSnapshotAttribute {
+            snapshot_id: String,
+            region: String,
+            #[snafu(source(from(SdkError<DescribeSnapshotAttributeError>, Box::new)))]
+            source: Box<SdkError<DescribeSnapshotAttributeError>>,
+        },
+
+        #[snafu(display(
+            "Permissions did not converge in {}, see table above",
+            regions.join(", ")
+        ))]
+        NotConverged { regions: Vec<String> },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeSnapshotAttribute { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_mismatches, mismatches, requested_permissions};
+    use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+    use crate::aws::publish_ami::ModifyOptions;
+    use aws_sdk_ec2::model::OperationType;
+
+    fn modify_opts() -> ModifyOptions {
+        ModifyOptions {
+            user_ids: vec!["111122223333".to_string()],
+            group_names: vec!["all".to_string()],
+            organization_arns: vec!["arn:aws:organizations::111122223333:organization/o-1".into()],
+            organizational_unit_arns: vec![],
+        }
+    }
+
+    #[test]
+    fn snapshots_exclude_organizations() {
+        let image_requested = requested_permissions(&modify_opts(), true);
+        let snapshot_requested = requested_permissions(&modify_opts(), false);
+        assert_eq!(image_requested.len(), 3);
+        assert_eq!(
+            snapshot_requested,
+            vec![
+                LaunchPermissionDef::UserId("111122223333".to_string()),
+                LaunchPermissionDef::Group("all".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_mismatches() {
+        let requested = requested_permissions(&modify_opts(), false);
+        let actual = vec![LaunchPermissionDef::Group("all".to_string())];
+        let found = mismatches(&requested, &actual, &OperationType::Add);
+        assert_eq!(
+            found,
+            vec![LaunchPermissionDef::UserId("111122223333".to_string())]
+        );
+        assert_eq!(
+            describe_mismatches(&found, &OperationType::Add),
+            "missing: user 111122223333"
+        );
+    }
+
+    #[test]
+    fn remove_mismatches() {
+        let requested = requested_permissions(&modify_opts(), false);
+        let actual = vec![LaunchPermissionDef::Group("all".to_string())];
+        let found = mismatches(&requested, &actual, &OperationType::Remove);
+        assert_eq!(found, vec![LaunchPermissionDef::Group("all".to_string())]);
+        assert_eq!(
+            describe_mismatches(&found, &OperationType::Remove),
+            "still present: group all"
+        );
+        assert_eq!(describe_mismatches(&[], &OperationType::Remove), "ok");
+    }
+}
@@ -0,0 +1,593 @@
+//! The query module sends signed requests to the EC2 Query API directly.  We use this for the
+//! Image Block Public Access calls, which the version of the EC2 SDK we use predates; every other
+//! service is called through its SDK client.
+
+use crate::aws::rate_limit::RateLimits;
+use crate::exit_code::{Classify, Kind};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sdk_ec2::Region;
+use aws_sigv4::http_request::{sign, SignableRequest, SigningSettings};
+use aws_sigv4::SigningParams;
+use aws_smithy_types::retry::RetryConfig;
+use lazy_static::lazy_static;
+use log::{debug, trace, warn};
+use pubsys_config::{partition_for_region, AwsConfig as PubsysAwsConfig};
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use url::{form_urlencoded, Url};
+
+/// The longest we wait between attempts of a request, like the SDK's standard retry
+const MAX_BACKOFF: Duration = Duration::from_secs(20);
+
+/// Error codes that mean a request was throttled and should be retried
+const THROTTLING_CODES: &[&str] = &[
+    "PriorRequestNotComplete",
+    "RequestLimitExceeded",
+    "RequestThrottled",
+    "RequestThrottledException",
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "TooManyRequestsException",
+];
+
+lazy_static! {
+    /// HTTP clients for signed requests, by the proxy they send requests through
+    static ref CLIENTS: Mutex<HashMap<Option<Url>, reqwest::Client>> = Mutex::new(HashMap::new());
+}
+
+/// Describes where to send a Query API request and how to sign it.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    pub(crate) url: String,
+    pub(crate) service: &'static str,
+    pub(crate) signing_region: String,
+    /// The HTTP(S) proxy to send the request through, if not the one from the environment
+    pub(crate) proxy: Option<Url>,
+}
+
+impl Endpoint {
+    /// Returns the regional EC2 endpoint for the given region.
+    pub(crate) fn ec2(region: &Region) -> Self {
+        Self {
+            url: format!("https://ec2.{}.{}/", region, domain(region)),
+            service: "ec2",
+            signing_region: region.to_string(),
+            proxy: None,
+        }
+    }
+
+    /// Returns this endpoint with its URL replaced by the one given for its service in the pubsys
+    /// config, if any, or else by its FIPS or dual-stack endpoint if the pubsys config asks for
+    /// them, like the SDK clients, and using the proxy from the pubsys config, if any.  The signing
+    /// region is kept.
+    pub(crate) fn with_pubsys_config(mut self, pubsys_aws_config: &PubsysAwsConfig) -> Self {
+        let fips = pubsys_aws_config.use_fips_endpoints;
+        let dualstack = pubsys_aws_config.use_dualstack_endpoints;
+        if let Some(url) = pubsys_aws_config.service_endpoint_url(self.service) {
+            self.url = url.to_string();
+        } else if fips || dualstack {
+            let region = Region::new(self.signing_region.clone());
+            let host = if fips {
+                format!("{}-fips", self.service)
+            } else {
+                self.service.to_string()
+            };
+            let domain = if dualstack {
+                dualstack_domain(&region)
+            } else {
+                Some(domain(&region))
+            };
+            match domain {
+                Some(domain) => self.url = format!("https://{}.{}.{}/", host, region, domain),
+                None => warn!(
+                    "{} has no dual-stack endpoint in {}; using {}",
+                    self.service, self.signing_region, self.url
+                ),
+            }
+        }
+        self.proxy = pubsys_aws_config.proxy.clone();
+        self
+    }
+}
+
+/// Returns the DNS suffix for regional endpoints in the partition containing the given region.
+fn domain(region: &Region) -> &'static str {
+    match partition_for_region(region.as_ref()) {
+        "aws-cn" => "amazonaws.com.cn",
+        "aws-iso" => "c2s.ic.gov",
+        "aws-iso-b" => "sc2s.sgov.gov",
+        _ => "amazonaws.com",
+    }
+}
+
+/// Returns the DNS suffix for regional dual-stack endpoints in the partition containing the given
+/// region, if the partition has them.
+fn dualstack_domain(region: &Region) -> Option<&'static str> {
+    match partition_for_region(region.as_ref()) {
+        "aws" | "aws-us-gov" => Some("api.aws"),
+        "aws-cn" => Some("api.amazonwebservices.com.cn"),
+        _ => None,
+    }
+}
+
+/// Sends a Query API request for the given action and parameters to the given endpoint, signed
+/// with the credentials from the given client config, and returns the response body.
+pub(crate) async fn send(
+    client_config: &SdkConfig,
+    rate_limits: &RateLimits,
+    endpoint: &Endpoint,
+    action: &str,
+    version: &str,
+    params: &[(String, String)],
+) -> Result<String> {
+    let mut body = form_urlencoded::Serializer::new(String::new());
+    body.append_pair("Action", action);
+    body.append_pair("Version", version);
+    for (name, value) in params {
+        body.append_pair(name, value);
+    }
+    let body = body.finish();
+    trace!("Sending {} to {}: {}", action, endpoint.url, body);
+
+    let request = http::Request::builder()
+        .method("POST")
+        .uri(&endpoint.url)
+        .header(
+            "content-type",
+            "application/x-www-form-urlencoded; charset=utf-8",
+        )
+        .body(body)
+        .context(error::BuildRequestSnafu { action })?;
+    let (status, response_body) =
+        send_signed(client_config, rate_limits, endpoint, action, request).await?;
+
+    if !status.is_success() {
+        let code = find_element(&response_body, "Code").unwrap_or_default();
+        let message = find_element(&response_body, "Message").unwrap_or(response_body);
+        return error::ResponseSnafu {
+            action,
+            endpoint: &endpoint.url,
+            status: status.as_u16(),
+            code,
+            message,
+        }
+        .fail();
+    }
+
+    Ok(response_body)
+}
+
+/// Signs the given request with the credentials from the given client config, sends it, and
+/// returns the response status and body.  Requests are rate limited, retried, and timed out like
+/// the SDK clients' requests, using the retry and timeout config from the client config.
+async fn send_signed(
+    client_config: &SdkConfig,
+    rate_limits: &RateLimits,
+    endpoint: &Endpoint,
+    action: &str,
+    mut request: http::Request<String>,
+) -> Result<(reqwest::StatusCode, String)> {
+    // Name ourselves like the SDK does, so these requests can be attributed in CloudTrail too.
+    let user_agent = match client_config.app_name() {
+        Some(app_name) => format!("pubsys app/{}", app_name),
+        None => "pubsys".to_string(),
+    };
+    if let Ok(user_agent) = http::HeaderValue::from_str(&user_agent) {
+        request
+            .headers_mut()
+            .insert(http::header::USER_AGENT, user_agent);
+    }
+    let (parts, body) = request.into_parts();
+
+    let retry = client_config
+        .retry_config()
+        .cloned()
+        .unwrap_or_else(RetryConfig::standard);
+    let timeout = client_config.timeout_config();
+    let client = shared_client(
+        &endpoint.proxy,
+        timeout.and_then(|timeout| timeout.connect_timeout()),
+    )?;
+    let read_timeout = timeout.and_then(|timeout| timeout.read_timeout());
+
+    let attempts = async {
+        let mut attempt = 1;
+        loop {
+            let result = rate_limits
+                .rate_limited(
+                    endpoint.service,
+                    &endpoint.signing_region,
+                    send_once(
+                        client_config,
+                        &client,
+                        endpoint,
+                        action,
+                        &parts,
+                        &body,
+                        read_timeout,
+                    ),
+                )
+                .await;
+            let retryable = match &result {
+                Ok((status, response_body)) => is_retryable(*status, response_body),
+                Err(error::Error::SendRequest { source, .. }) => {
+                    source.is_timeout() || source.is_connect()
+                }
+                Err(_) => false,
+            };
+            if !retryable || attempt >= retry.max_attempts() {
+                return result;
+            }
+            let delay = backoff(retry.initial_backoff(), attempt);
+            debug!(
+                "Retrying {} to {} in {}ms",
+                action,
+                endpoint.url,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    };
+    match timeout.and_then(|timeout| timeout.operation_timeout()) {
+        Some(operation_timeout) => tokio::time::timeout(operation_timeout, attempts)
+            .await
+            .ok()
+            .context(error::TimeoutSnafu {
+                action,
+                endpoint: &endpoint.url,
+                timeout_ms: operation_timeout.as_millis(),
+            })?,
+        None => attempts.await,
+    }
+}
+
+/// Signs and sends one attempt of the given request.
+async fn send_once(
+    client_config: &SdkConfig,
+    client: &reqwest::Client,
+    endpoint: &Endpoint,
+    action: &str,
+    parts: &http::request::Parts,
+    body: &str,
+    read_timeout: Option<Duration>,
+) -> Result<(reqwest::StatusCode, String)> {
+    let mut request = http::Request::new(body.to_string());
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.headers_mut() = parts.headers.clone();
+
+    let credentials = client_config
+        .credentials_provider()
+        .context(error::MissingCredentialsSnafu {
+            endpoint: &endpoint.url,
+        })?
+        .provide_credentials()
+        .await
+        .context(error::CredentialsSnafu {
+            endpoint: &endpoint.url,
+        })?;
+
+    // Each attempt is signed separately, since signatures are only valid for a few minutes.
+    let mut signing_params = SigningParams::builder()
+        .access_key(credentials.access_key_id())
+        .secret_key(credentials.secret_access_key())
+        .region(&endpoint.signing_region)
+        .service_name(endpoint.service)
+        .time(SystemTime::now())
+        .settings(SigningSettings::default());
+    signing_params.set_security_token(credentials.session_token());
+    let signing_params = signing_params
+        .build()
+        .map_err(|e| error::Error::SignRequest {
+            action: action.to_string(),
+            msg: e.to_string(),
+        })?;
+    let (signing_instructions, _signature) = sign(SignableRequest::from(&request), &signing_params)
+        .map_err(|e| error::Error::SignRequest {
+            action: action.to_string(),
+            msg: e.to_string(),
+        })?
+        .into_parts();
+    signing_instructions.apply_to_request(&mut request);
+
+    let (parts, body) = request.into_parts();
+    let mut request_builder = client
+        .request(parts.method, parts.uri.to_string())
+        .headers(parts.headers)
+        .body(body);
+    if let Some(read_timeout) = read_timeout {
+        request_builder = request_builder.timeout(read_timeout);
+    }
+    let response = request_builder
+        .send()
+        .await
+        .context(error::SendRequestSnafu {
+            action,
+            endpoint: &endpoint.url,
+        })?;
+    let status = response.status();
+    let response_body = response.text().await.context(error::SendRequestSnafu {
+        action,
+        endpoint: &endpoint.url,
+    })?;
+    Ok((status, response_body))
+}
+
+/// Returns the HTTP client for requests through the given proxy, building it the first time it's
+/// needed, so the run shares one client rather than loading TLS roots for every request.
+fn shared_client(
+    proxy: &Option<Url>,
+    connect_timeout: Option<Duration>,
+) -> Result<reqwest::Client> {
+    let mut clients = CLIENTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(client) = clients.get(proxy) {
+        return Ok(client.clone());
+    }
+    // Pooled connections belong to the async runtime that opened them, and pubsys can use more
+    // than one runtime in a run, so connections aren't kept for later requests.
+    let mut client_builder = reqwest::Client::builder().pool_max_idle_per_host(0);
+    if let Some(connect_timeout) = connect_timeout {
+        client_builder = client_builder.connect_timeout(connect_timeout);
+    }
+    // reqwest uses the proxy from HTTPS_PROXY and friends unless we give it one.
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy.as_str()).context(error::ProxySnafu {
+            proxy: proxy.as_str(),
+        })?;
+        client_builder = client_builder.proxy(proxy);
+    }
+    let client = client_builder.build().context(error::ClientSnafu)?;
+    clients.insert(proxy.clone(), client.clone());
+    Ok(client)
+}
+
+/// Returns whether a response with the given status and body means the request should be retried:
+/// server errors, and throttling, which some services report with a 400 status.
+fn is_retryable(status: reqwest::StatusCode, body: &str) -> bool {
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return true;
+    }
+    if !status.is_client_error() {
+        return false;
+    }
+    find_element(body, "Code")
+        .map(|code| THROTTLING_CODES.contains(&code.as_str()))
+        .unwrap_or(false)
+}
+
+/// Returns how long to wait before retrying after the given attempt: the initial backoff, doubled
+/// for each attempt since the first, up to the SDK's maximum.
+fn backoff(initial_backoff: Duration, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    initial_backoff
+        .checked_mul(factor)
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+/// Returns the text of the first XML element with the given name in the response body, if any.
+/// The responses we handle are small and simple, so this avoids pulling in a full XML parser.
+pub(crate) fn find_element(body: &str, element: &str) -> Option<String> {
+    find_elements(body, element).into_iter().next()
+}
+
+/// Returns the text of each XML element with the given name in the response body, in order.
+fn find_elements(body: &str, element: &str) -> Vec<String> {
+    let open = format!("<{}>", element);
+    let close = format!("</{}>", element);
+    let mut found = Vec::new();
+    let mut rest = body;
+    while let Some(open_index) = rest.find(&open) {
+        let start = open_index + open.len();
+        let end = match rest[start..].find(&close) {
+            Some(len) => start + len,
+            None => break,
+        };
+        found.push(rest[start..end].trim().to_string());
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+mod error {
+    use aws_credential_types::provider::error::CredentialsError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to build {} request: {}", action, source))]
+        BuildRequest { action: String, source: http::Error },
+
+        #[snafu(display("Failed to build HTTP client: {}", source))]
+        Client { source: reqwest::Error },
+
+        #[snafu(display("Failed to load credentials for {}: {}", endpoint, source))]
+        Credentials {
+            endpoint: String,
+            source: CredentialsError,
+        },
+
+        #[snafu(display("No credentials provider configured for {}", endpoint))]
+        MissingCredentials { endpoint: String },
+
+        #[snafu(display("Invalid proxy '{}': {}", proxy, source))]
+        Proxy {
+            proxy: String,
+            source: reqwest::Error,
+        },
+
+        #[snafu(display(
+            "{} to {} failed with status {}: {} {}",
+            action,
+            endpoint,
+            status,
+            code,
+            message
+        ))]
+        Response {
+            action: String,
+            endpoint: String,
+            status: u16,
+            code: String,
+            message: String,
+        },
+
+        #[snafu(display("Failed to send {} request to {}: {}", action, endpoint, source))]
+        SendRequest {
+            action: String,
+            endpoint: String,
+            source: reqwest::Error,
+        },
+
+        #[snafu(display("Failed to sign {} request: {}", action, msg))]
+        SignRequest { action: String, msg: String },
+
+        #[snafu(display("{} to {} timed out after {}ms", action, endpoint, timeout_ms))]
+        Timeout {
+            action: String,
+            endpoint: String,
+            timeout_ms: u128,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::Response { .. } | Self::SendRequest { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{backoff, find_element, find_elements, is_retryable, Endpoint, MAX_BACKOFF};
+    use aws_sdk_ec2::Region;
+    use pubsys_config::AwsConfig as PubsysAwsConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn find_state_element() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<GetImageBlockPublicAccessStateResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+    <requestId>ebd7f4e9-7a2c-4a7b-9f3d-3e6c0f1b2a11</requestId>
+    <imageBlockPublicAccessState>block-new-sharing</imageBlockPublicAccessState>
+</GetImageBlockPublicAccessStateResponse>"#;
+        assert_eq!(
+            find_element(body, "imageBlockPublicAccessState"),
+            Some("block-new-sharing".to_string())
+        );
+    }
+
+    #[test]
+    fn find_missing_element() {
+        let body = "<Response><Errors><Error><Code>UnauthorizedOperation</Code></Error></Errors></Response>";
+        assert_eq!(find_element(body, "imageBlockPublicAccessState"), None);
+        assert_eq!(
+            find_element(body, "Code"),
+            Some("UnauthorizedOperation".to_string())
+        );
+    }
+
+    #[test]
+    fn find_repeated_elements() {
+        let body =
+            "<r><member><n>a</n></member><member><n>b</n></member><member><n>c</n></member></r>";
+        assert_eq!(find_elements(body, "n"), vec!["a", "b", "c"]);
+        assert_eq!(find_elements(body, "member").len(), 3);
+        assert!(find_elements(body, "missing").is_empty());
+    }
+
+    #[test]
+    fn partition_endpoints() {
+        let ec2 = Endpoint::ec2(&Region::new("cn-northwest-1"));
+        assert_eq!(ec2.url, "https://ec2.cn-northwest-1.amazonaws.com.cn/");
+        assert_eq!(ec2.signing_region, "cn-northwest-1");
+
+        let ec2 = Endpoint::ec2(&Region::new("us-iso-east-1"));
+        assert_eq!(ec2.url, "https://ec2.us-iso-east-1.c2s.ic.gov/");
+        let ec2 = Endpoint::ec2(&Region::new("us-isob-east-1"));
+        assert_eq!(ec2.url, "https://ec2.us-isob-east-1.sc2s.sgov.gov/");
+        assert_eq!(ec2.signing_region, "us-isob-east-1");
+    }
+
+    #[test]
+    fn fips_endpoints() {
+        let fips = PubsysAwsConfig {
+            use_fips_endpoints: true,
+            ..Default::default()
+        };
+        let ec2 = Endpoint::ec2(&Region::new("us-west-2")).with_pubsys_config(&fips);
+        assert_eq!(ec2.url, "https://ec2-fips.us-west-2.amazonaws.com/");
+        assert_eq!(ec2.signing_region, "us-west-2");
+        let ec2 = Endpoint::ec2(&Region::new("us-gov-west-1"))
+            .with_pubsys_config(&PubsysAwsConfig::default());
+        assert_eq!(ec2.url, "https://ec2.us-gov-west-1.amazonaws.com/");
+        // Endpoint URLs from the config still take precedence.
+        let overridden = PubsysAwsConfig {
+            endpoint_url: Some("http://localhost:4566".parse().unwrap()),
+            ..fips
+        };
+        let ec2 = Endpoint::ec2(&Region::new("us-east-1")).with_pubsys_config(&overridden);
+        assert_eq!(ec2.url, "http://localhost:4566/");
+    }
+
+    #[test]
+    fn dualstack_endpoints() {
+        let dualstack = PubsysAwsConfig {
+            use_dualstack_endpoints: true,
+            ..Default::default()
+        };
+        let ec2 = Endpoint::ec2(&Region::new("eu-west-1")).with_pubsys_config(&dualstack);
+        assert_eq!(ec2.url, "https://ec2.eu-west-1.api.aws/");
+        let ec2 = Endpoint::ec2(&Region::new("cn-north-1")).with_pubsys_config(&dualstack);
+        assert_eq!(
+            ec2.url,
+            "https://ec2.cn-north-1.api.amazonwebservices.com.cn/"
+        );
+        let fips_dualstack = PubsysAwsConfig {
+            use_fips_endpoints: true,
+            ..dualstack.clone()
+        };
+        let ec2 = Endpoint::ec2(&Region::new("us-east-1")).with_pubsys_config(&fips_dualstack);
+        assert_eq!(ec2.url, "https://ec2-fips.us-east-1.api.aws/");
+        // Partitions without dual-stack endpoints keep the IPv4 endpoint.
+        let ec2 = Endpoint::ec2(&Region::new("us-iso-east-1")).with_pubsys_config(&dualstack);
+        assert_eq!(ec2.url, "https://ec2.us-iso-east-1.c2s.ic.gov/");
+    }
+
+    #[test]
+    fn retryable_responses() {
+        use reqwest::StatusCode;
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE, ""));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS, ""));
+        assert!(is_retryable(
+            StatusCode::BAD_REQUEST,
+            "<Response><Errors><Error><Code>RequestLimitExceeded</Code></Error></Errors></Response>"
+        ));
+        assert!(!is_retryable(
+            StatusCode::FORBIDDEN,
+            "<Response><Errors><Error><Code>UnauthorizedOperation</Code></Error></Errors></Response>"
+        ));
+        assert!(!is_retryable(StatusCode::OK, ""));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let initial = Duration::from_millis(500);
+        assert_eq!(backoff(initial, 1), initial);
+        assert_eq!(backoff(initial, 3), Duration::from_secs(2));
+        assert_eq!(backoff(initial, 40), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,134 @@
+//! The rate_limit module holds the token buckets shared by the requests an operation sends to a
+//! service, so that a release across many regions stays under the account's API limits rather
+//! than starving other automation running in the same account.
+//!
+//! Limits are set per service in the `aws.rate_limits` table of Infra.toml, and apply to each
+//! region separately, like EC2's own throttling.  Services without a limit aren't throttled here.
+//!
+//! Separately, `aws.max_concurrency` (or `--max-concurrency`) bounds the number of requests in
+//! flight at once, across every service and region, for operators who'd rather have one knob than
+//! a rate per service.
+//!
+//! Limits are built from the config an operation uses and passed along with its clients, so every
+//! request the operation sends shares the same buckets.
+
+use governor::clock::DefaultClock;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use pubsys_config::AwsConfig;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Service names used as keys in `aws.rate_limits`
+pub(crate) const EC2: &str = "ec2";
+pub(crate) const SSM: &str = "ssm";
+
+/// A rate limiter for one service, keyed by region name.
+type ServiceRateLimiter = RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>;
+
+/// The rate limiter for each service with a configured limit, and the permits for requests in
+/// flight, if their number is limited.  Clones share the same buckets and permits.
+#[derive(Clone, Default)]
+pub(crate) struct RateLimits {
+    limiters: HashMap<String, Arc<ServiceRateLimiter>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl RateLimits {
+    /// Creates rate limiters for the services in `aws.rate_limits`, with the given requests per
+    /// second in each region, and limits the requests in flight to `aws.max_concurrency`, if set.
+    pub(crate) fn new(aws: &AwsConfig) -> Self {
+        let limiters = aws
+            .rate_limits
+            .iter()
+            .map(|(service, per_second)| {
+                (
+                    service.clone(),
+                    Arc::new(RateLimiter::keyed(Quota::per_second(*per_second))),
+                )
+            })
+            .collect();
+        // Semaphore permits don't belong to an async runtime, so one limit covers every runtime
+        // the operation uses.
+        let concurrency = aws
+            .max_concurrency
+            .map(|max| Arc::new(Semaphore::new(max.get())));
+        Self {
+            limiters,
+            concurrency,
+        }
+    }
+
+    /// Runs the given request once fewer than the maximum number of requests are in flight, for
+    /// services without a rate limit, like S3.
+    pub(crate) fn limited<F: Future>(&self, request: F) -> impl Future<Output = F::Output> {
+        let concurrency = self.concurrency.clone();
+        async move {
+            let _permit = permit(concurrency).await;
+            request.await
+        }
+    }
+
+    /// Waits until a request may be sent to the given service in the given region, and until
+    /// fewer than the maximum number of requests are in flight, then runs the given request.
+    /// Requests can be built up front and run later, like in a stream, so this copies the region
+    /// and limits rather than borrowing them.
+    pub(crate) fn rate_limited<F: Future>(
+        &self,
+        service: &str,
+        region: &str,
+        request: F,
+    ) -> impl Future<Output = F::Output> {
+        let limiter = self.limiters.get(service).cloned();
+        let concurrency = self.concurrency.clone();
+        let region = region.to_string();
+        async move {
+            if let Some(limiter) = limiter {
+                limiter.until_key_ready(&region).await;
+            }
+            let _permit = permit(concurrency).await;
+            request.await
+        }
+    }
+}
+
+/// Waits until fewer than the maximum number of requests are in flight, if there is one, and
+/// returns a permit that counts as in flight until it's dropped.
+async fn permit(concurrency: Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    concurrency?.acquire_owned().await.ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimits;
+    use pubsys_config::AwsConfig;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn per_region_buckets() {
+        let mut aws = AwsConfig::default();
+        aws.rate_limits
+            .insert("test-service".to_string(), NonZeroU32::new(2).unwrap());
+        let limits = RateLimits::new(&aws);
+
+        let buckets = &limits.limiters["test-service"];
+        let region = "us-west-2".to_string();
+        assert!(buckets.check_key(&region).is_ok());
+        assert!(buckets.check_key(&region).is_ok());
+        assert!(buckets.check_key(&region).is_err());
+        // Other regions have their own buckets.
+        assert!(buckets.check_key(&"us-east-1".to_string()).is_ok());
+
+        // Clones share the buckets.
+        let cloned = limits.clone();
+        assert!(cloned.limiters["test-service"].check_key(&region).is_err());
+
+        // Limits built from another config have their own.
+        let other = RateLimits::new(&aws);
+        assert!(other.limiters["test-service"].check_key(&region).is_ok());
+
+        assert!(!limits.limiters.contains_key("unlimited-service"));
+    }
+}
@@ -0,0 +1,302 @@
+//! The rollback_ssm module owns the 'rollback-ssm' subcommand, which undoes a promotion: it sets
+//! the SSM parameters of a version, like 'latest', back to the values they had before.
+//!
+//! By default the earlier values come from SSM's own parameter history: each parameter goes back
+//! to its most recent value that differs from the current one.  Rolling back twice therefore
+//! undoes the rollback.  Alternatively, `--restore-from` takes the values from a parameter file,
+//! like the one written by `promote-ssm --ssm-parameter-output` before the bad promotion.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::RateLimits;
+use crate::aws::ssm::{ssm, template, BuildContext, SsmKey, SsmParameters};
+use crate::aws::tags::ssm_tags;
+use crate::aws::validate_ssm::parse_parameters;
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ssm::{Client as SsmClient, Region};
+use futures::future::try_join_all;
+use log::{info, trace, warn};
+use snafu::{ensure, ResultExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// Restores the previous values of a version's SSM parameters
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct RollbackSsmArgs {
+    /// The architecture of the machine image
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// The variant name for the current build
+    #[structopt(long)]
+    variant: String,
+
+    /// Version number (or string, like 'latest') whose parameters to roll back
+    #[structopt(long)]
+    target: String,
+
+    /// Comma-separated list of regions to roll back in, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// File holding the parameter templates
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Take the values to restore from this parameter file, rather than from parameter history
+    #[structopt(long, parse(from_os_str))]
+    restore_from: Option<PathBuf>,
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, rollback_args: &RollbackSsmArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let ssm_prefix = aws.ssm_prefix_for(&rollback_args.variant, rollback_args.arch.as_ref());
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !rollback_args.regions.is_empty() {
+        rollback_args.regions.clone()
+    } else {
+        aws.regions_for("rollback_ssm").clone().into()
+    }
+    .into_iter()
+    .map(|name| region_from_string(&name))
+    .collect::<Vec<Region>>();
+
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        let ssm_client = SsmClient::from_pubsys_config(&client_config, &aws);
+        ssm_clients.insert(region.clone(), ssm_client);
+    }
+
+    let build_context = BuildContext {
+        variant: &rollback_args.variant,
+        arch: rollback_args.arch.as_str(),
+        image_version: &rollback_args.target,
+    };
+    info!(
+        "Parsing SSM parameter templates from {}",
+        rollback_args.template_path.display()
+    );
+    let template_parameters =
+        template::get_parameters(&rollback_args.template_path, &build_context)
+            .context(error::FindTemplatesSnafu)?;
+    if template_parameters.parameters.is_empty() {
+        info!(
+            "No parameters for this arch/variant in {}",
+            rollback_args.template_path.display()
+        );
+        return Ok(());
+    }
+    let parameter_names =
+        template::render_parameter_names(&template_parameters, ssm_prefix, &build_context)
+            .context(error::RenderTemplatesSnafu)?;
+    let keys: Vec<SsmKey> = regions
+        .iter()
+        .flat_map(|region| {
+            parameter_names
+                .values()
+                .map(move |name| SsmKey::new(region.clone(), name.clone()))
+        })
+        .collect();
+
+    let restore_parameters = match &rollback_args.restore_from {
+        Some(restore_from) => {
+            info!("Reading values to restore from {}", restore_from.display());
+            let recorded: SsmParameters = parse_parameters(restore_from)
+                .await
+                .context(error::ParseRestoreFileSnafu { path: restore_from })?
+                .into_values()
+                .flatten()
+                .collect();
+            let current = ssm::get_parameters(&keys, &ssm_clients, &rate_limits)
+                .await
+                .context(error::FetchSsmSnafu)?;
+            keys.into_iter()
+                .filter_map(|key| {
+                    let value = recorded.get(&key);
+                    if value.is_none() {
+                        warn!(
+                            "{} in {} isn't in {}, leaving it alone",
+                            key.name,
+                            key.region,
+                            restore_from.display()
+                        );
+                    }
+                    value
+                        .filter(|value| current.get(&key) != Some(*value))
+                        .cloned()
+                        .map(|value| (key, value))
+                })
+                .collect()
+        }
+        None => {
+            info!("Getting SSM parameter history");
+            let histories = try_join_all(keys.iter().map(|key| {
+                ssm::get_parameter_history(
+                    &key.region,
+                    &ssm_clients[&key.region],
+                    &rate_limits,
+                    &key.name,
+                )
+            }))
+            .await
+            .context(error::FetchSsmSnafu)?;
+            let mut restore_parameters = HashMap::new();
+            for (key, history) in keys.into_iter().zip(histories) {
+                match previous_value(&history) {
+                    Some((version, value)) => {
+                        info!(
+                            "{} in {} goes back to version {}: {}",
+                            key.name, key.region, version, value
+                        );
+                        restore_parameters.insert(key, value.clone());
+                    }
+                    None => warn!(
+                        "{} in {} has no earlier value, leaving it alone",
+                        key.name, key.region
+                    ),
+                }
+            }
+            restore_parameters
+        }
+    };
+
+    if restore_parameters.is_empty() {
+        info!("No changes necessary.");
+        return Ok(());
+    }
+    if args.dry_run {
+        for (key, value) in &restore_parameters {
+            info!("Would set {} in {} to {}", key.name, key.region, value);
+        }
+        return Ok(());
+    }
+
+    info!("Restoring {} SSM parameters.", restore_parameters.len());
+    args.state
+        .tracer
+        .traced(
+            "set_parameters",
+            None,
+            ssm::set_parameters(&restore_parameters, &ssm_clients, &rate_limits, &args.state),
+        )
+        .await
+        .context(error::SetSsmSnafu)?;
+
+    if !aws.tags.is_empty() {
+        info!("Applying default tags to SSM parameters.");
+        ssm::tag_parameters(
+            &restore_parameters,
+            &ssm_clients,
+            &rate_limits,
+            &ssm_tags(&aws),
+        )
+        .await
+        .context(error::TagSsmSnafu)?;
+    }
+
+    info!("Validating whether live parameters in SSM reflect changes.");
+    ssm::validate_parameters(&restore_parameters, &ssm_clients, &rate_limits)
+        .await
+        .context(error::ValidateSsmSnafu)?;
+
+    info!("All parameters match requested values.");
+    Ok(())
+}
+
+/// Given a parameter's history from oldest to newest, returns the most recent version whose value
+/// differs from the current one, if any.
+fn previous_value(history: &[(i64, String)]) -> Option<&(i64, String)> {
+    let (_, current) = history.last()?;
+    history.iter().rev().find(|(_, value)| value != current)
+}
+
+mod error {
+    use crate::aws::{ssm::ssm, ssm::template, validate_ssm};
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to fetch parameters from SSM: {}", source))]
+        FetchSsm { source: ssm::Error },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates { source: template::Error },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to read parameters to restore from {}: {}", path.display(), source))]
+        ParseRestoreFile {
+            path: PathBuf,
+            source: validate_ssm::Error,
+        },
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates { source: template::Error },
+
+        #[snafu(display("Failed to set SSM parameters: {}", source))]
+        SetSsm { source: ssm::Error },
+
+        #[snafu(display("Failed to tag SSM parameters: {}", source))]
+        TagSsm { source: ssm::Error },
+
+        #[snafu(display("Failed to validate SSM parameters: {}", source))]
+        ValidateSsm { source: ssm::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::previous_value;
+
+    #[test]
+    fn previous_values() {
+        let history = vec![
+            (1, "ami-1".to_string()),
+            (2, "ami-2".to_string()),
+            (3, "ami-3".to_string()),
+            (4, "ami-3".to_string()),
+        ];
+        assert_eq!(previous_value(&history), Some(&(2, "ami-2".to_string())));
+
+        let history = vec![(1, "ami-1".to_string()), (2, "ami-1".to_string())];
+        assert_eq!(previous_value(&history), None);
+        assert_eq!(previous_value(&[]), None);
+    }
+}
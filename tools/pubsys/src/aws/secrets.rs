@@ -0,0 +1,145 @@
+//! The secrets module fetches secrets, like repo signing keys, that are stored in AWS Secrets
+//! Manager or as SSM SecureString parameters.  They're fetched with the credentials from the `aws`
+//! section of Infra.toml, so the secrets can live in the publishing account rather than on the
+//! machine running pubsys.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::region_from_string;
+use crate::exit_code::{Classify, Kind};
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use aws_sdk_ssm::Client as SsmClient;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{OptionExt, ResultExt};
+
+/// Where a secret is stored, as given by its ARN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SecretService {
+    SecretsManager,
+    Ssm,
+}
+
+/// Parses the ARN of a Secrets Manager secret or an SSM parameter, returning the service that
+/// stores it and its region.
+pub(crate) fn parse_secret_arn(arn: &str) -> Result<(SecretService, &str)> {
+    let fields: Vec<&str> = arn.splitn(6, ':').collect();
+    let (service, region, resource) = match fields.as_slice() {
+        ["arn", _, service, region, _, resource] if !region.is_empty() => {
+            (*service, *region, *resource)
+        }
+        _ => return error::SecretArnSnafu { arn }.fail(),
+    };
+    match service {
+        "secretsmanager" if resource.starts_with("secret:") => {
+            Ok((SecretService::SecretsManager, region))
+        }
+        "ssm" if resource.starts_with("parameter/") => Ok((SecretService::Ssm, region)),
+        _ => error::SecretArnSnafu { arn }.fail(),
+    }
+}
+
+/// Fetches the text of the secret with the given ARN, which can be a Secrets Manager secret or an
+/// SSM parameter; SecureString parameters are decrypted.
+pub(crate) async fn get_secret(arn: &str, pubsys_aws_config: &PubsysAwsConfig) -> Result<String> {
+    let (service, region) = parse_secret_arn(arn)?;
+    let region = region_from_string(region);
+    let client_config = build_client_config(&region, &region, pubsys_aws_config).await;
+
+    match service {
+        SecretService::SecretsManager => {
+            let response =
+                SecretsManagerClient::from_pubsys_config(&client_config, pubsys_aws_config)
+                    .get_secret_value()
+                    .secret_id(arn)
+                    .send()
+                    .await
+                    .context(error::SecretsManagerSnafu { arn })?;
+            response
+                .secret_string
+                .context(error::MissingSecretStringSnafu { arn })
+        }
+        SecretService::Ssm => {
+            // GetParameter takes the ARN in place of the name.
+            let response = SsmClient::from_pubsys_config(&client_config, pubsys_aws_config)
+                .get_parameter()
+                .name(arn)
+                .with_decryption(true)
+                .send()
+                .await
+                .context(error::SsmSnafu { arn })?;
+            response
+                .parameter
+                .and_then(|parameter| parameter.value)
+                .context(error::MissingParameterValueSnafu { arn })
+        }
+    }
+}
+
+mod error {
+    use aws_sdk_secretsmanager::error::GetSecretValueError;
+    use aws_sdk_ssm::error::GetParameterError;
+    use aws_sdk_ssm::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("SSM parameter '{}' has no value", arn))]
+        MissingParameterValue { arn: String },
+
+        #[snafu(display(
+            "Secret '{}' has no SecretString; only secrets stored as text are supported",
+            arn
+        ))]
+        MissingSecretString { arn: String },
+
+        #[snafu(display(
+            "'{}' is not the ARN of a Secrets Manager secret or an SSM parameter",
+            arn
+        ))]
+        SecretArn { arn: String },
+
+        #[snafu(display("Failed to get secret '{}' from Secrets Manager: {}", arn, source))]
+        SecretsManager {
+            arn: String,
+            source: SdkError<GetSecretValueError>,
+        },
+
+        #[snafu(display("Failed to get SSM parameter '{}': {}", arn, source))]
+        Ssm {
+            arn: String,
+            source: SdkError<GetParameterError>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::SecretsManager { .. } | Self::Ssm { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_secret_arn, SecretService};
+
+    #[test]
+    fn secret_arns() {
+        assert_eq!(
+            parse_secret_arn("arn:aws:secretsmanager:us-west-2:111122223333:secret:key-AbCdEf")
+                .unwrap(),
+            (SecretService::SecretsManager, "us-west-2")
+        );
+        assert_eq!(
+            parse_secret_arn("arn:aws-cn:ssm:cn-north-1:111122223333:parameter/repo/key").unwrap(),
+            (SecretService::Ssm, "cn-north-1")
+        );
+        assert!(parse_secret_arn("arn:aws:s3:::bucket/key").is_err());
+        assert!(parse_secret_arn("arn:aws:ssm:us-west-2:111122223333:document/key").is_err());
+        assert!(parse_secret_arn("/repo/key").is_err());
+    }
+}
@@ -6,11 +6,17 @@ pub(crate) mod ssm;
 pub(crate) mod template;
 
 use self::template::RenderedParameter;
+use crate::aws::check_permissions::{self, Operation};
 use crate::aws::ssm::template::RenderedParametersMap;
+use crate::aws::tags::ssm_tags;
 use crate::aws::{
-    ami::public::ami_is_public, ami::Image, client::build_client_config, parse_arch,
-    region_from_string,
+    ami::public::ami_is_public, ami::Image, client::build_client_config, client::ServiceClient,
+    parse_arch, rate_limit::RateLimits, region_from_string,
 };
+use crate::exit_code::{Classify, Kind};
+use crate::plan::{Mutation, Recorder};
+use crate::run_state::RunState;
+use crate::test_trigger::{self, TestRequest};
 use crate::Args;
 use aws_config::SdkConfig;
 use aws_sdk_ec2::{model::ArchitectureValues, Client as Ec2Client};
@@ -33,7 +39,7 @@ use structopt::{clap, StructOpt};
 /// Sets SSM parameters based on current build information
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct SsmArgs {
+pub struct SsmArgs {
     // This is JSON output from `pubsys ami` like `{"us-west-2": "ami-123"}`
     /// Path to the JSON file containing regional AMI IDs to modify
     #[structopt(long, parse(from_os_str))]
@@ -70,6 +76,53 @@ pub(crate) struct SsmArgs {
     /// If set, writes the generated SSM parameters to this path
     #[structopt(long)]
     ssm_parameter_output: Option<PathBuf>,
+
+    /// Check that the configured credentials have the permissions needed before starting
+    #[structopt(long)]
+    preflight: bool,
+}
+
+/// The parameters `publish` sets, and the AMIs they're for
+#[derive(Debug, Clone)]
+pub struct SsmOptions {
+    /// Path to the JSON file containing regional AMI IDs, like the output of `ami::build`
+    pub ami_input: PathBuf,
+    /// The architecture of the machine image
+    pub arch: ArchitectureValues,
+    /// The variant name for the current build
+    pub variant: String,
+    /// The version of the current build
+    pub version: String,
+    /// Regions where parameters are published; the regions in Infra.toml are used if this is
+    /// empty
+    pub regions: Vec<String>,
+    /// File holding the parameter templates
+    pub template_path: PathBuf,
+    /// Allows overwrite of existing parameters
+    pub allow_clobber: bool,
+    /// Allows publishing non-public images to the `/aws/` namespace
+    pub allow_private_images: bool,
+    /// If set, writes the generated SSM parameters to this path
+    pub ssm_parameter_output: Option<PathBuf>,
+    /// Check that the configured credentials have the permissions needed before starting
+    pub preflight: bool,
+}
+
+impl From<&SsmArgs> for SsmOptions {
+    fn from(args: &SsmArgs) -> Self {
+        Self {
+            ami_input: args.ami_input.clone(),
+            arch: args.arch.clone(),
+            variant: args.variant.clone(),
+            version: args.version.clone(),
+            regions: args.regions.clone(),
+            template_path: args.template_path.clone(),
+            allow_clobber: args.allow_clobber,
+            allow_private_images: args.allow_private_images,
+            ssm_parameter_output: args.ssm_parameter_output.clone(),
+            preflight: args.preflight,
+        }
+    }
 }
 
 /// Wrapper struct over parameter update and AWS clients needed to execute on it.
@@ -80,21 +133,64 @@ struct SsmParamUpdateOp {
 }
 
 /// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
-    // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+pub async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
+    run_with_options(args, &SsmOptions::from(ssm_args)).await
+}
 
+/// Sets the parameters, then starts any tests configured to run once they point to the new AMIs,
+/// as the 'ssm' subcommand does.
+pub(crate) async fn run_with_options(args: &Args, options: &SsmOptions) -> Result<()> {
     // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
-    trace!("Parsed infra config: {:#?}", infra_config);
-    let aws = infra_config.aws.unwrap_or_default();
-    let ssm_prefix = aws.ssm_prefix.as_deref().unwrap_or("");
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let amis = publish_with_state(&args.state, &infra_config, options).await?;
+    // Nothing was published if we only planned.
+    if args.state.plan.planning() {
+        return Ok(());
+    }
+
+    // Start any tests configured to run once the parameters point to the new AMIs.
+    let request = TestRequest {
+        subcommand: "ssm",
+        variant: Some(&options.variant),
+        version: Some(&options.version),
+        arch: options.arch.as_ref(),
+        amis: amis
+            .iter()
+            .map(|(region, image)| (region.to_string(), image.id.clone()))
+            .collect(),
+    };
+    test_trigger::trigger(args, &request)
+        .await
+        .context(error::TestsSnafu)
+}
+
+/// Sets the parameters and returns the AMIs they're for, keyed by region.
+pub async fn publish(
+    infra_config: &InfraConfig,
+    options: &SsmOptions,
+) -> Result<HashMap<Region, Image>> {
+    publish_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Sets the parameters like `publish`, planning, journaling, and tracing in the given run.
+pub(crate) async fn publish_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &SsmOptions,
+) -> Result<HashMap<Region, Image>> {
+    // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !ssm_args.regions.is_empty() {
-        ssm_args.regions.clone()
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let ssm_prefix = aws.ssm_prefix_for(&options.variant, options.arch.as_ref());
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
     } else {
-        aws.regions.clone().into()
+        aws.regions_for("ssm").clone().into()
     };
     ensure!(
         !regions.is_empty(),
@@ -104,30 +200,40 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     );
     let base_region = region_from_string(&regions[0]);
 
-    let amis = parse_ami_input(&regions, ssm_args)?;
+    if options.preflight {
+        let preflight_regions: Vec<Region> = regions
+            .iter()
+            .map(|name| region_from_string(name))
+            .collect();
+        check_permissions::preflight(Operation::Ssm, &aws, &preflight_regions)
+            .await
+            .context(error::PreflightSnafu)?;
+    }
+
+    let amis = parse_ami_input(&regions, options)?;
 
     // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Non-image-specific context for building and rendering templates
     let build_context = BuildContext {
-        variant: &ssm_args.variant,
-        arch: ssm_args.arch.as_ref(),
-        image_version: &ssm_args.version,
+        variant: &options.variant,
+        arch: options.arch.as_ref(),
+        image_version: &options.version,
     };
 
     info!(
         "Parsing SSM parameter templates from {}",
-        ssm_args.template_path.display()
+        options.template_path.display()
     );
-    let template_parameters = template::get_parameters(&ssm_args.template_path, &build_context)
+    let template_parameters = template::get_parameters(&options.template_path, &build_context)
         .context(error::FindTemplatesSnafu)?;
 
     if template_parameters.parameters.is_empty() {
         info!(
             "No parameters for this arch/variant in {}",
-            ssm_args.template_path.display()
+            options.template_path.display()
         );
-        return Ok(());
+        return Ok(amis);
     }
 
     let new_parameters =
@@ -136,7 +242,7 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     trace!("Generated templated parameters: {:#?}", new_parameters);
 
     // If the path to an output file was given, write the rendered parameters to this file
-    if let Some(ssm_parameter_output) = &ssm_args.ssm_parameter_output {
+    if let Some(ssm_parameter_output) = &options.ssm_parameter_output {
         write_rendered_parameters(
             ssm_parameter_output,
             &RenderedParametersMap::from(&new_parameters).rendered_parameters,
@@ -161,12 +267,12 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
             }
         };
 
-        let ssm_client = SsmClient::new(&client_config);
+        let ssm_client = SsmClient::from_pubsys_config(&client_config, &aws);
         if ssm_clients.get(region).is_none() {
             ssm_clients.insert(region.clone(), ssm_client);
         }
 
-        let ec2_client = Ec2Client::new(&client_config);
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
         param_update_ops.push(SsmParamUpdateOp {
             parameter: parameter.clone(),
             ec2_client,
@@ -174,10 +280,10 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     }
 
     // Unless overridden, only allow public images to be published to public parameters.
-    if !ssm_args.allow_private_images {
+    if !options.allow_private_images {
         info!("Ensuring that only public images are published to public parameters.");
         ensure!(
-            check_public_namespace_amis_are_public(param_update_ops.iter()).await?,
+            check_public_namespace_amis_are_public(param_update_ops.iter(), &rate_limits).await?,
             error::NoPrivateImagesSnafu
         );
     }
@@ -187,7 +293,7 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     info!("Getting current SSM parameters");
     let new_parameter_names: Vec<&SsmKey> =
         new_parameters.iter().map(|param| &param.ssm_key).collect();
-    let current_parameters = ssm::get_parameters(&new_parameter_names, &ssm_clients)
+    let current_parameters = ssm::get_parameters(&new_parameter_names, &ssm_clients, &rate_limits)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!("Current SSM parameters: {:#?}", current_parameters);
@@ -199,12 +305,12 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     );
     if parameters_to_set.is_empty() {
         info!("No changes necessary.");
-        return Ok(());
+        return Ok(amis);
     }
 
     // Unless the user wants to allow it, make sure we're not going to overwrite any existing
     // keys.
-    if !ssm_args.allow_clobber {
+    if !options.allow_clobber {
         let current_keys: HashSet<&SsmKey> = current_parameters.keys().collect();
         let new_keys: HashSet<&SsmKey> = parameters_to_set.keys().collect();
         ensure!(current_keys.is_disjoint(&new_keys), error::NoClobberSnafu);
@@ -212,18 +318,41 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
 
     // SSM set   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
+    if state.plan.planning() {
+        plan_parameters(&state.plan, &parameters_to_set, &current_parameters);
+        return Ok(amis);
+    }
+
     info!("Setting updated SSM parameters.");
-    ssm::set_parameters(&parameters_to_set, &ssm_clients)
+    state
+        .tracer
+        .traced(
+            "set_parameters",
+            None,
+            ssm::set_parameters(&parameters_to_set, &ssm_clients, &rate_limits, state),
+        )
         .await
         .context(error::SetSsmSnafu)?;
 
+    if !aws.tags.is_empty() {
+        info!("Applying default tags to SSM parameters.");
+        ssm::tag_parameters(
+            &parameters_to_set,
+            &ssm_clients,
+            &rate_limits,
+            &ssm_tags(&aws),
+        )
+        .await
+        .context(error::TagSsmSnafu)?;
+    }
+
     info!("Validating whether live parameters in SSM reflect changes.");
-    ssm::validate_parameters(&parameters_to_set, &ssm_clients)
+    ssm::validate_parameters(&parameters_to_set, &ssm_clients, &rate_limits)
         .await
         .context(error::ValidateSsmSnafu)?;
 
     info!("All parameters match requested values.");
-    Ok(())
+    Ok(amis)
 }
 
 /// Write rendered parameters to the file at `ssm_parameters_output`
@@ -259,6 +388,7 @@ const MAX_CONCURRENT_AMI_CHECKS: usize = 8;
 /// Given a set of SSM parameter updates, ensures all parameters in the public namespace refer to public AMIs.
 async fn check_public_namespace_amis_are_public(
     parameter_updates: impl Iterator<Item = &SsmParamUpdateOp>,
+    rate_limits: &RateLimits,
 ) -> Result<bool> {
     let public_namespace_updates = parameter_updates
         .filter(|update| update.parameter.ssm_key.is_in_public_namespace())
@@ -268,7 +398,7 @@ async fn check_public_namespace_amis_are_public(
     let check_ami_public = |update: SsmParamUpdateOp| async move {
         let region = &update.parameter.ssm_key.region;
         let ami_id = &update.parameter.ami.id;
-        let is_public = ami_is_public(&update.ec2_client, region.as_ref(), ami_id)
+        let is_public = ami_is_public(&update.ec2_client, rate_limits, region.as_ref(), ami_id)
             .await
             .context(error::CheckAmiPublicSnafu {
                 ami_id: ami_id.to_string(),
@@ -305,13 +435,13 @@ async fn check_public_namespace_amis_are_public(
 
 /// The key to a unique SSM parameter
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
-pub(crate) struct SsmKey {
-    pub(crate) region: Region,
-    pub(crate) name: String,
+pub struct SsmKey {
+    pub region: Region,
+    pub name: String,
 }
 
 impl SsmKey {
-    pub(crate) fn new(region: Region, name: String) -> Self {
+    pub fn new(region: Region, name: String) -> Self {
         Self { region, name }
     }
 
@@ -338,15 +468,15 @@ pub(crate) struct BuildContext<'a> {
 pub(crate) type SsmParameters = HashMap<SsmKey, String>;
 
 /// Parse the AMI input file
-fn parse_ami_input(regions: &[String], ssm_args: &SsmArgs) -> Result<HashMap<Region, Image>> {
-    info!("Using AMI data from path: {}", ssm_args.ami_input.display());
-    let file = File::open(&ssm_args.ami_input).context(error::FileSnafu {
+fn parse_ami_input(regions: &[String], options: &SsmOptions) -> Result<HashMap<Region, Image>> {
+    info!("Using AMI data from path: {}", options.ami_input.display());
+    let file = File::open(&options.ami_input).context(error::FileSnafu {
         op: "open",
-        path: &ssm_args.ami_input,
+        path: &options.ami_input,
     })?;
     let mut ami_input: HashMap<String, Image> =
         serde_json::from_reader(file).context(error::DeserializeSnafu {
-            path: &ssm_args.ami_input,
+            path: &options.ami_input,
         })?;
     trace!("Parsed AMI input: {:#?}", ami_input);
 
@@ -355,7 +485,7 @@ fn parse_ami_input(regions: &[String], ssm_args: &SsmArgs) -> Result<HashMap<Reg
     ensure!(
         !ami_input.is_empty(),
         error::InputSnafu {
-            path: &ssm_args.ami_input
+            path: &options.ami_input
         }
     );
 
@@ -389,6 +519,25 @@ fn parse_ami_input(regions: &[String], ssm_args: &SsmArgs) -> Result<HashMap<Reg
     Ok(amis)
 }
 
+/// Records setting the given parameters in the plan, rather than setting them, along with their
+/// current values, so `pubsys apply` can tell if they've changed since.
+pub(crate) fn plan_parameters(
+    plan: &Recorder,
+    parameters_to_set: &SsmParameters,
+    current: &SsmParameters,
+) {
+    let mut keys: Vec<&SsmKey> = parameters_to_set.keys().collect();
+    keys.sort_by(|a, b| (a.region.as_ref(), &a.name).cmp(&(b.region.as_ref(), &b.name)));
+    for key in keys {
+        plan.add(Mutation::PutParameter {
+            region: key.region.to_string(),
+            name: key.name.clone(),
+            value: parameters_to_set[key].clone(),
+            current: current.get(key).cloned(),
+        });
+    }
+}
+
 /// Shows the user the difference between two sets of parameters.  We look for parameters in
 /// `wanted` that are either missing or changed in `current`.  We print these differences for the
 /// user, then return the `wanted` values.
@@ -434,6 +583,7 @@ pub(crate) fn key_difference(wanted: &SsmParameters, current: &SsmParameters) ->
 }
 
 mod error {
+    use crate::aws::check_permissions;
     use crate::aws::ssm::{ssm, template};
     use snafu::Snafu;
     use std::io;
@@ -441,7 +591,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error reading config: {}", source))]
         Config {
             source: pubsys_config::Error,
@@ -503,6 +653,11 @@ mod error {
         #[snafu(display("Cowardly refusing to publish private image to public namespace without ALLOW_PRIVATE_IMAGES"))]
         NoPrivateImages,
 
+        #[snafu(display("Permission check failed: {}", source))]
+        Preflight {
+            source: check_permissions::Error,
+        },
+
         #[snafu(display("Failed to render templates: {}", source))]
         RenderTemplates {
             source: template::Error,
@@ -513,6 +668,16 @@ mod error {
             source: ssm::Error,
         },
 
+        #[snafu(display("Failed to tag SSM parameters: {}", source))]
+        TagSsm {
+            source: ssm::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Tests {
+            source: crate::test_trigger::Error,
+        },
+
         #[snafu(display(
             "Given region(s) in Infra.toml / regions argument that are not in --ami-input file: {}",
             regions.join(", ")
@@ -537,5 +702,14 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
@@ -1,13 +1,17 @@
 //! The ssm module owns the getting and setting of parameters in SSM.
 
 use super::{SsmKey, SsmParameters};
+use crate::aws::rate_limit::{RateLimits, SSM};
+use crate::exit_code::{Classify, Kind};
+use crate::run_state::RunState;
 use aws_sdk_ssm::error::{GetParametersError, PutParameterError};
-use aws_sdk_ssm::model::ParameterType;
+use aws_sdk_ssm::model::{ParameterType, ResourceTypeForTagging, Tag};
 use aws_sdk_ssm::output::{GetParametersOutput, PutParameterOutput};
 use aws_sdk_ssm::types::SdkError;
 use aws_sdk_ssm::{Client as SsmClient, Region};
-use futures::future::{join, ready};
+use futures::future::{join, ready, try_join_all};
 use futures::stream::{self, FuturesUnordered, StreamExt};
+use indicatif::ProgressBar;
 use log::{debug, error, info, trace, warn};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
@@ -20,6 +24,7 @@ use std::time::Duration;
 pub(crate) async fn get_parameters<K>(
     requested: &[K],
     clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
 ) -> Result<SsmParameters>
 where
     K: AsRef<SsmKey>,
@@ -45,6 +50,7 @@ where
                 .get_parameters()
                 .set_names((!names_chunk.is_empty()).then_some(names_chunk.to_vec().clone()))
                 .send();
+            let get_future = rate_limits.rate_limited(SSM, region.as_ref(), get_future);
 
             // Store the region so we can include it in errors and the output map
             let info_future = ready((region.clone(), len));
@@ -135,10 +141,14 @@ where
     Ok(parameters)
 }
 
-/// Fetches all SSM parameters under a given prefix using the given clients
+/// Fetches all SSM parameters under a given prefix using the given clients, counting each region
+/// on the given progress bar as it finishes
 pub(crate) async fn get_parameters_by_prefix<'a>(
     clients: &'a HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
     ssm_prefix: &str,
+    progress_bar: &ProgressBar,
 ) -> HashMap<&'a Region, Result<SsmParameters>> {
     // Build requests for parameters; we have to request with a regional client so we split them by
     // region
@@ -146,7 +156,13 @@ pub(crate) async fn get_parameters_by_prefix<'a>(
     for region in clients.keys() {
         trace!("Requesting parameters in {}", region);
         let ssm_client: &SsmClient = &clients[region];
-        let get_future = get_parameters_by_prefix_in_region(region, ssm_client, ssm_prefix);
+        let get_future = get_parameters_by_prefix_in_region(
+            region,
+            ssm_client,
+            rate_limits,
+            page_size,
+            ssm_prefix,
+        );
 
         requests.push(join(ready(region), get_future));
     }
@@ -155,29 +171,84 @@ pub(crate) async fn get_parameters_by_prefix<'a>(
     requests
         .into_iter()
         .collect::<FuturesUnordered<_>>()
+        .inspect(|_| progress_bar.inc(1))
         .collect()
         .await
 }
 
-/// Fetches all SSM parameters under a given prefix in a single region
+/// Fetches all SSM parameters under any of the given prefixes using the given clients.  A region's
+/// result is an error if fetching under any of the prefixes failed there.
+pub(crate) async fn get_parameters_by_prefixes<'a, I, S>(
+    clients: &'a HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
+    ssm_prefixes: I,
+    progress_bar: &ProgressBar,
+) -> HashMap<&'a Region, Result<SsmParameters>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut all_parameters: HashMap<&Region, Result<SsmParameters>> = HashMap::new();
+    for ssm_prefix in ssm_prefixes {
+        let prefix_parameters = get_parameters_by_prefix(
+            clients,
+            rate_limits,
+            page_size,
+            ssm_prefix.as_ref(),
+            progress_bar,
+        )
+        .await;
+        for (region, result) in prefix_parameters {
+            let region_parameters = all_parameters
+                .entry(region)
+                .or_insert_with(|| Ok(HashMap::new()));
+            match result {
+                Ok(more) => {
+                    if let Ok(parameters) = region_parameters {
+                        parameters.extend(more);
+                    }
+                }
+                // Keep the first error for the region.
+                Err(e) => {
+                    if region_parameters.is_ok() {
+                        *region_parameters = Err(e);
+                    }
+                }
+            }
+        }
+    }
+    all_parameters
+}
+
+/// Fetches all SSM parameters under a given prefix in a single region, in pages of the given size,
+/// if any
 pub(crate) async fn get_parameters_by_prefix_in_region(
     region: &Region,
     client: &SsmClient,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
     ssm_prefix: &str,
 ) -> Result<SsmParameters> {
     info!("Retrieving SSM parameters in {}", region.to_string());
     let mut parameters = HashMap::new();
 
     // Send the request
-    let mut get_future = client
+    let mut paginator = client
         .get_parameters_by_path()
         .path(ssm_prefix)
         .recursive(true)
-        .into_paginator()
-        .send();
+        .into_paginator();
+    if let Some(page_size) = page_size {
+        paginator = paginator.page_size(page_size);
+    }
+    let mut get_future = paginator.send();
 
-    // Iterate over the retrieved parameters
-    while let Some(page) = get_future.next().await {
+    // Iterate over the retrieved parameters; each page is a request, so each waits its turn.
+    while let Some(page) = rate_limits
+        .rate_limited(SSM, region.as_ref(), get_future.next())
+        .await
+    {
         let retrieved_parameters = page
             .context(error::GetParametersByPathSnafu {
                 path: ssm_prefix,
@@ -217,10 +288,60 @@ pub(crate) async fn get_parameters_by_prefix_in_region(
     Ok(parameters)
 }
 
+/// Fetches the recorded versions of an SSM parameter in a single region, as (version, value) pairs
+/// from oldest to newest.  A parameter that doesn't exist has no versions.
+pub(crate) async fn get_parameter_history(
+    region: &Region,
+    client: &SsmClient,
+    rate_limits: &RateLimits,
+    name: &str,
+) -> Result<Vec<(i64, String)>> {
+    let mut versions = Vec::new();
+    let mut get_future = client
+        .get_parameter_history()
+        .name(name)
+        .into_paginator()
+        .send();
+
+    // Each page is a request, so each waits its turn.
+    while let Some(page) = rate_limits
+        .rate_limited(SSM, region.as_ref(), get_future.next())
+        .await
+    {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.code() == Some("ParameterNotFound") {
+                    return Ok(Vec::new());
+                }
+                return Err(e).context(error::GetParameterHistorySnafu {
+                    name,
+                    region: region.as_ref(),
+                });
+            }
+        };
+        for parameter in page.parameters().unwrap_or_default() {
+            let value = parameter.value().context(error::MissingFieldSnafu {
+                region: region.as_ref(),
+                field: format!("value for version {} of {}", parameter.version(), name),
+            })?;
+            versions.push((parameter.version(), value.to_string()));
+        }
+    }
+    versions.sort_by_key(|(version, _)| *version);
+    Ok(versions)
+}
+
+/// The kind of work `set_parameters` tracks and journals
+const SET_PARAMETERS: &str = "SSM parameters set";
+
 /// Sets the values of the given SSM keys using the given clients
 pub(crate) async fn set_parameters(
     parameters_to_set: &SsmParameters,
     ssm_clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+    state: &RunState,
 ) -> Result<()> {
     // Start with a small delay between requests, and increase if we get throttled.
     let mut request_interval = Duration::from_millis(100);
@@ -241,9 +362,20 @@ pub(crate) async fn set_parameters(
         failures: u8,
     }
 
-    // Create the initial request contexts
+    // Create the initial request contexts, skipping parameters that a journal from an earlier run
+    // records as set to the same value
     let mut contexts = Vec::new();
     for (SsmKey { region, name }, value) in parameters_to_set {
+        let journaled = state
+            .journal
+            .detail(SET_PARAMETERS, &format!("{} in {}", name, region));
+        if journaled == Some(Some(value.clone())) {
+            debug!(
+                "Skipping {} in {}, which the journal records as set",
+                name, region
+            );
+            continue;
+        }
         contexts.push(RequestContext {
             region,
             name,
@@ -288,12 +420,14 @@ pub(crate) async fn set_parameters(
                 .set_overwrite(Some(true))
                 .set_type(Some(ParameterType::String))
                 .send();
+            let put_future = rate_limits.rate_limited(SSM, context.region.as_ref(), put_future);
 
             let regional_list = regional_requests
                 .entry(context.region)
                 .or_insert_with(Vec::new);
             // Store the context so we can retry as needed
-            regional_list.push(join(ready(context), put_future));
+            let name = format!("{} in {}", context.name, context.region);
+            regional_list.push((name, join(ready(context), put_future)));
         }
 
         // Create a throttled stream per region; throttling applies per region.  (Request futures
@@ -302,7 +436,7 @@ pub(crate) async fn set_parameters(
         let mut throttled_streams = Vec::new();
         for (_region, request_list) in regional_requests {
             throttled_streams.push(Box::pin(tokio_stream::StreamExt::throttle(
-                stream::iter(request_list),
+                stream::iter(state.interrupt.tracked(SET_PARAMETERS, request_list)),
                 request_interval,
             )));
         }
@@ -316,6 +450,12 @@ pub(crate) async fn set_parameters(
 
         // For each error response, check if we should retry or bail.
         for (context, response) in responses {
+            if response.is_ok() {
+                let name = format!("{} in {}", context.name, context.region);
+                state
+                    .journal
+                    .record(SET_PARAMETERS, &name, Some(context.value));
+            }
             if let Err(e) = response {
                 // Throttling errors are not currently surfaced in AWS SDK Rust, doing a string match is best we can do
                 let error_type = e
@@ -370,14 +510,63 @@ pub(crate) async fn set_parameters(
     Ok(())
 }
 
+/// Applies the given tags to the given parameters.  PutParameter can't tag a parameter when it
+/// overwrites one, so we tag them in separate requests; each region's requests run in turn, and
+/// regions run in parallel.
+pub(crate) async fn tag_parameters(
+    parameters: &SsmParameters,
+    ssm_clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+    tags: &[Tag],
+) -> Result<()> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    let mut regional_names: HashMap<&Region, Vec<&str>> = HashMap::new();
+    for SsmKey { region, name } in parameters.keys() {
+        regional_names.entry(region).or_default().push(name);
+    }
+
+    let regional_requests = regional_names
+        .into_iter()
+        .map(|(region, names)| async move {
+            let ssm_client = &ssm_clients[region];
+            for name in names {
+                rate_limits
+                    .rate_limited(
+                        SSM,
+                        region.as_ref(),
+                        ssm_client
+                            .add_tags_to_resource()
+                            .resource_type(ResourceTypeForTagging::Parameter)
+                            .resource_id(name)
+                            .set_tags(Some(tags.to_vec()))
+                            .send(),
+                    )
+                    .await
+                    .context(error::AddTagsSnafu {
+                        name,
+                        region: region.as_ref(),
+                    })?;
+            }
+            Ok::<(), error::Error>(())
+        });
+    try_join_all(regional_requests).await?;
+
+    Ok(())
+}
+
 /// Fetch the given parameters, and ensure the live values match the given values
 pub(crate) async fn validate_parameters(
     expected_parameters: &SsmParameters,
     ssm_clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
 ) -> Result<()> {
     // Fetch the given parameter names
     let expected_parameter_names: Vec<&SsmKey> = expected_parameters.keys().collect();
-    let updated_parameters = get_parameters(&expected_parameter_names, ssm_clients).await?;
+    let updated_parameters =
+        get_parameters(&expected_parameter_names, ssm_clients, rate_limits).await?;
 
     // Walk through and check each value
     let mut success = true;
@@ -407,7 +596,10 @@ pub(crate) async fn validate_parameters(
 }
 
 pub(crate) mod error {
-    use aws_sdk_ssm::error::{GetParametersByPathError, GetParametersError};
+    use aws_sdk_ssm::error::{
+        AddTagsToResourceError, GetParameterHistoryError, GetParametersByPathError,
+        GetParametersError,
+    };
     use aws_sdk_ssm::types::SdkError;
     use snafu::Snafu;
     use std::error::Error as _;
@@ -417,6 +609,25 @@ pub(crate) mod error {
     #[snafu(visibility(pub(super)))]
     #[allow(clippy::large_enum_variant)]
     pub enum Error {
+        #[snafu(display("Failed to tag SSM parameter {} in {}: {}", name, region, source))]
+        AddTags {
+            name: String,
+            region: String,
+            source: SdkError<AddTagsToResourceError>,
+        },
+
+        #[snafu(display(
+            "Failed to fetch history of SSM parameter {} in {}: {}",
+            name,
+            region,
+            source
+        ))]
+        GetParameterHistory {
+            name: String,
+            region: String,
+            source: GetParameterHistoryError,
+        },
+
         #[snafu(display("Failed to fetch SSM parameters in {}: {}", region, source.source().map(|x| x.to_string()).unwrap_or("unknown".to_string())))]
         GetParameters {
             region: String,
@@ -465,5 +676,19 @@ pub(crate) mod error {
         ValidateParameters,
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 pub(crate) type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::AddTags { .. }
+            | Self::GetParameterHistory { .. }
+            | Self::GetParameters { .. }
+            | Self::GetParametersByPath { .. }
+            | Self::SetParameters { .. }
+            | Self::Throttled { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -236,7 +236,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error building template from '{}': {}", template, source))]
         AddTemplate {
             template: String,
@@ -266,7 +266,7 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
 
 #[cfg(test)]
@@ -287,6 +287,7 @@ mod test {
                     name: "test1-image-name".to_string(),
                     public: Some(true),
                     launch_permissions: Some(vec![]),
+                    lineage: None,
                 },
                 ssm_key: SsmKey {
                     region: Region::new("us-west-2"),
@@ -300,6 +301,7 @@ mod test {
                     name: "test2-image-name".to_string(),
                     public: Some(true),
                     launch_permissions: Some(vec![]),
+                    lineage: None,
                 },
                 ssm_key: SsmKey {
                     region: Region::new("us-west-2"),
@@ -313,6 +315,7 @@ mod test {
                     name: "test3-image-name".to_string(),
                     public: Some(true),
                     launch_permissions: Some(vec![]),
+                    lineage: None,
                 },
                 ssm_key: SsmKey {
                     region: Region::new("us-east-1"),
@@ -0,0 +1,169 @@
+//! The tags module provides the default tags from the `aws.tags` table of Infra.toml in the form
+//! each AWS service takes them, so that every resource pubsys creates can carry them.
+
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
+use aws_sdk_ec2::Client as Ec2Client;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+
+/// Returns the default tags, sorted by key so requests are consistent.
+fn sorted_tags(pubsys_aws_config: &PubsysAwsConfig) -> BTreeMap<&str, &str> {
+    pubsys_aws_config
+        .tags
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// Returns the default tags as EC2 tags.
+pub(crate) fn ec2_tags(pubsys_aws_config: &PubsysAwsConfig) -> Vec<aws_sdk_ec2::model::Tag> {
+    sorted_tags(pubsys_aws_config)
+        .into_iter()
+        .map(|(key, value)| {
+            aws_sdk_ec2::model::Tag::builder()
+                .key(key)
+                .value(value)
+                .build()
+        })
+        .collect()
+}
+
+/// Returns the default tags as SSM tags.
+pub(crate) fn ssm_tags(pubsys_aws_config: &PubsysAwsConfig) -> Vec<aws_sdk_ssm::model::Tag> {
+    sorted_tags(pubsys_aws_config)
+        .into_iter()
+        .map(|(key, value)| {
+            aws_sdk_ssm::model::Tag::builder()
+                .key(key)
+                .value(value)
+                .build()
+        })
+        .collect()
+}
+
+/// Returns the default tags as KMS tags.
+pub(crate) fn kms_tags(pubsys_aws_config: &PubsysAwsConfig) -> Vec<aws_sdk_kms::model::Tag> {
+    sorted_tags(pubsys_aws_config)
+        .into_iter()
+        .map(|(key, value)| {
+            aws_sdk_kms::model::Tag::builder()
+                .tag_key(key)
+                .tag_value(value)
+                .build()
+        })
+        .collect()
+}
+
+/// Returns the default tags in the URL-encoded form S3 takes for object tagging, or None if there
+/// aren't any.
+pub(crate) fn s3_tagging(pubsys_aws_config: &PubsysAwsConfig) -> Option<String> {
+    if pubsys_aws_config.tags.is_empty() {
+        return None;
+    }
+    let pairs: Vec<String> = sorted_tags(pubsys_aws_config)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+        .collect();
+    Some(pairs.join("&"))
+}
+
+/// Percent-encodes everything but unreserved characters, as S3 expects in its tagging header.
+/// (Form encoding isn't suitable because it encodes spaces as '+'.)
+fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Applies the default tags to the given EC2 resources, like AMIs and their snapshots.  Does
+/// nothing if there are no default tags.
+pub(crate) async fn tag_ec2_resources(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &str,
+    resource_ids: &[String],
+    pubsys_aws_config: &PubsysAwsConfig,
+) -> Result<()> {
+    let tags = ec2_tags(pubsys_aws_config);
+    if tags.is_empty() || resource_ids.is_empty() {
+        return Ok(());
+    }
+    rate_limits
+        .rate_limited(
+            EC2,
+            region,
+            ec2_client
+                .create_tags()
+                .set_resources(Some(resource_ids.to_vec()))
+                .set_tags(Some(tags))
+                .send(),
+        )
+        .await
+        .context(error::CreateTagsSnafu {
+            resources: resource_ids.join(", "),
+            region,
+        })?;
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::CreateTagsError;
+    use aws_sdk_ec2::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Failed to apply default tags to {} in {}: {}",
+            resources,
+            region,
+            source
+        ))]
+        CreateTags {
+            resources: String,
+            region: String,
+            #[snafu(source(from(SdkError<CreateTagsError>, Box::new)))]
+            source: Box<SdkError<CreateTagsError>>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::CreateTags { .. } => Some(Kind::AwsApi),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::s3_tagging;
+    use pubsys_config::AwsConfig as PubsysAwsConfig;
+
+    #[test]
+    fn s3_tagging_is_url_encoded() {
+        let mut aws = PubsysAwsConfig::default();
+        assert_eq!(s3_tagging(&aws), None);
+
+        aws.tags
+            .insert("team".to_string(), "os platform".to_string());
+        aws.tags
+            .insert("cost-center".to_string(), "a&b=c".to_string());
+        assert_eq!(
+            s3_tagging(&aws).unwrap(),
+            "cost-center=a%26b%3Dc&team=os%20platform"
+        );
+    }
+}
@@ -0,0 +1,773 @@
+//! The transfer_ami module owns the 'transfer-ami' subcommand and controls the process of copying
+//! AMIs from the account in which they were built into the account that should own the published
+//! AMIs.
+//!
+//! The build account shares each AMI and its snapshots with the publishing account, then the
+//! publishing account copies the AMI within the same region, which makes it the owner of the copy.
+
+use crate::aws::ami::lineage::tag_image;
+use crate::aws::ami::register::get_ami_id;
+use crate::aws::ami::wait::{self, wait_for_ami};
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::publish_ami::{
+    get_snapshots, modify_image, modify_rate_limiter, modify_snapshots, write_amis, ModifyOptions,
+    RegionRateLimiter,
+};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::region_from_string;
+use crate::aws::tags::tag_ec2_resources;
+use crate::exit_code::{Classify, Kind};
+use crate::plan::Recorder;
+use crate::progress::progress_bar;
+use crate::run_state::RunState;
+use crate::Args;
+use aws_sdk_ec2::error::CopyImageError;
+use aws_sdk_ec2::model::OperationType;
+use aws_sdk_ec2::output::CopyImageOutput;
+use aws_sdk_ec2::types::SdkError;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_sts::Client as StsClient;
+use futures::future::{join, ready};
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace, warn};
+use pubsys_config::{AwsConfig as PubsysAwsConfig, InfraConfig};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::iter::FromIterator;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// Copies AMIs into the publishing account so that it owns the published AMIs
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct TransferArgs {
+    /// Path to the JSON file containing regional AMI IDs in the build account
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// Comma-separated list of regions to copy in, overriding Infra.toml; given regions must be in
+    /// the --ami-input file
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Path where the regional AMI IDs in the publishing account are saved in JSON; this can be
+    /// the same as --ami-input
+    #[structopt(long, parse(from_os_str))]
+    ami_output: PathBuf,
+
+    /// Leave the source AMIs shared with the publishing account after copying them
+    #[structopt(long)]
+    keep_source_access: bool,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// The state of the transfer in a single region.
+#[derive(Debug)]
+struct Transfer {
+    region: Region,
+    source: Image,
+    /// The description of the source AMI, which we give to the copy
+    description: Option<String>,
+    /// The copy in the publishing account, once it's known
+    target_id: Option<String>,
+    /// Whether we shared the source AMI, and so should revoke access when done
+    shared: bool,
+}
+
+/// The AMIs `transfer` copies into the publishing account, and where it saves the copies
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// Path to the JSON file containing regional AMI IDs in the build account
+    pub ami_input: PathBuf,
+    /// Regions to copy in, which must be in the `ami_input` file; the regions in Infra.toml are
+    /// used if this is empty
+    pub regions: Vec<String>,
+    /// Path where the regional AMI IDs in the publishing account are saved in JSON; this can be
+    /// the same as `ami_input`
+    pub ami_output: PathBuf,
+    /// Leave the source AMIs shared with the publishing account after copying them
+    pub keep_source_access: bool,
+    /// Don't display progress bars
+    pub no_progress: bool,
+}
+
+impl From<&TransferArgs> for TransferOptions {
+    fn from(args: &TransferArgs) -> Self {
+        Self {
+            ami_input: args.ami_input.clone(),
+            regions: args.regions.clone(),
+            ami_output: args.ami_output.clone(),
+            keep_source_access: args.keep_source_access,
+            no_progress: args.no_progress,
+        }
+    }
+}
+
+/// Common entrypoint from main()
+pub async fn run(args: &Args, transfer_args: &TransferArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    transfer_with_state(
+        &args.state,
+        &infra_config,
+        &TransferOptions::from(transfer_args),
+    )
+    .await
+    .map(drop)
+}
+
+/// Copies the AMIs in the input file into the publishing account in each region, and saves the
+/// copies to the output file, even if some of them failed.  Returns the copy in each region,
+/// keyed by region name.
+pub async fn transfer(
+    infra_config: &InfraConfig,
+    options: &TransferOptions,
+) -> Result<HashMap<String, Image>> {
+    transfer_with_state(&RunState::default(), infra_config, options).await
+}
+
+/// Transfers the AMIs like `transfer`, tracing and tracking the copies in the given run.
+pub(crate) async fn transfer_with_state(
+    state: &RunState,
+    infra_config: &InfraConfig,
+    options: &TransferOptions,
+) -> Result<HashMap<String, Image>> {
+    info!("Using AMI data from path: {}", options.ami_input.display());
+    let file = File::open(&options.ami_input).context(error::FileSnafu {
+        op: "open",
+        path: &options.ami_input,
+    })?;
+    let mut ami_input: HashMap<String, Image> =
+        serde_json::from_reader(file).context(error::DeserializeSnafu {
+            path: &options.ami_input,
+        })?;
+    trace!("Parsed AMI input: {:?}", ami_input);
+    ensure!(
+        !ami_input.is_empty(),
+        error::InputSnafu {
+            path: &options.ami_input
+        }
+    );
+
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let publishing_aws = aws.publishing_config().context(error::MissingConfigSnafu {
+        missing: "aws.publishing",
+    })?;
+    // Requests with the publishing account's credentials share the limits of the build account's.
+    let rate_limits = RateLimits::new(&aws);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions = if !options.regions.is_empty() {
+        options.regions.clone()
+    } else {
+        aws.regions_for("transfer_ami").clone().into()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = region_from_string(&regions[0]);
+
+    let requested_regions = HashSet::from_iter(regions.iter());
+    let known_regions = HashSet::<&String>::from_iter(ami_input.keys());
+    ensure!(
+        requested_regions.is_subset(&known_regions),
+        error::UnknownRegionsSnafu {
+            regions: requested_regions
+                .difference(&known_regions)
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+        }
+    );
+
+    let mut transfers = Vec::with_capacity(regions.len());
+    for name in regions {
+        let source = ami_input
+            .remove(&name)
+            .with_context(|| error::UnknownRegionsSnafu {
+                regions: vec![name.clone()],
+            })?;
+        transfers.push(Transfer {
+            region: region_from_string(&name),
+            source,
+            description: None,
+            target_id: None,
+            shared: false,
+        });
+    }
+
+    // We make maps storing our regional clients because they're used in a future and need to
+    // live until the future is resolved.
+    let mut source_clients = HashMap::with_capacity(transfers.len());
+    let mut target_clients = HashMap::with_capacity(transfers.len());
+    for transfer in &transfers {
+        let region = &transfer.region;
+        let source_config = build_client_config(region, &base_region, &aws).await;
+        source_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&source_config, &aws),
+        );
+        let target_config = build_client_config(region, &base_region, &publishing_aws).await;
+        target_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&target_config, &publishing_aws),
+        );
+    }
+
+    let publishing_account_id = get_account_id(&base_region, &publishing_aws).await?;
+    info!(
+        "Copying AMIs into publishing account {}",
+        publishing_account_id
+    );
+
+    find_existing_copies(
+        &mut transfers,
+        &source_clients,
+        &target_clients,
+        &rate_limits,
+    )
+    .await?;
+
+    // Share the source AMIs and their snapshots with the publishing account so it can copy them.
+    let share_opts = ModifyOptions {
+        user_ids: vec![publishing_account_id],
+        group_names: Vec::new(),
+        organization_arns: Vec::new(),
+        organizational_unit_arns: Vec::new(),
+    };
+    let rate_limiter = modify_rate_limiter();
+    let mut share_result = Ok(());
+    for transfer in transfers.iter_mut().filter(|t| t.target_id.is_none()) {
+        if let Err(e) = modify_source_access(
+            &state.plan,
+            &share_opts,
+            &OperationType::Add,
+            transfer,
+            &source_clients,
+            &rate_limiter,
+            &rate_limits,
+        )
+        .await
+        {
+            share_result = Err(e);
+            break;
+        }
+        transfer.shared = true;
+    }
+
+    let copy_result = match share_result {
+        Ok(()) => {
+            copy_amis(
+                state,
+                &mut transfers,
+                &target_clients,
+                &rate_limits,
+                &base_region,
+                &publishing_aws,
+                options.no_progress,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+
+    // Whether or not copies succeeded, stop sharing the source AMIs unless asked not to.
+    if !options.keep_source_access {
+        for transfer in transfers.iter().filter(|t| t.shared) {
+            if let Err(e) = modify_source_access(
+                &state.plan,
+                &share_opts,
+                &OperationType::Remove,
+                transfer,
+                &source_clients,
+                &rate_limiter,
+                &rate_limits,
+            )
+            .await
+            {
+                warn!(
+                    "Failed to stop sharing {} in {} with the publishing account: {}",
+                    transfer.source.id, transfer.region, e
+                );
+            }
+        }
+    }
+
+    // Save the AMIs we have in the publishing account, even if some failed, so they can be used.
+    let mut amis = HashMap::with_capacity(transfers.len());
+    for transfer in &transfers {
+        if let Some(target_id) = &transfer.target_id {
+            amis.insert(
+                transfer.region.to_string(),
+                Image {
+                    id: target_id.clone(),
+                    name: transfer.source.name.clone(),
+                    public: Some(false),
+                    launch_permissions: Some(vec![]),
+                    // The copy holds the same artifact as its source, so keep its lineage.
+                    lineage: transfer.source.lineage.clone(),
+                },
+            );
+        }
+    }
+    if !amis.is_empty() {
+        write_amis(&options.ami_output, &amis).context(error::WriteAmisSnafu {
+            path: &options.ami_output,
+        })?;
+    }
+
+    copy_result.map(|()| amis)
+}
+
+/// Returns the account ID of the credentials in the given config.
+async fn get_account_id(region: &Region, pubsys_aws_config: &PubsysAwsConfig) -> Result<String> {
+    let client_config = build_client_config(region, region, pubsys_aws_config).await;
+    let response = StsClient::from_pubsys_config(&client_config, pubsys_aws_config)
+        .get_caller_identity()
+        .send()
+        .await
+        .context(error::GetCallerIdentitySnafu {
+            region: region.as_ref(),
+        })?;
+    response.account.context(error::MissingInResponseSnafu {
+        request_type: "GetCallerIdentity",
+        missing: "account",
+    })
+}
+
+/// Checks whether the publishing account already has a copy of each source AMI, in which case
+/// we don't need to copy it again.
+async fn find_existing_copies(
+    transfers: &mut [Transfer],
+    source_clients: &HashMap<Region, Ec2Client>,
+    target_clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+) -> Result<()> {
+    info!("Checking whether AMIs already exist in the publishing account");
+    for transfer in transfers.iter_mut() {
+        let region = &transfer.region;
+        let describe_response = rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                source_clients[region]
+                    .describe_images()
+                    .image_ids(transfer.source.id.clone())
+                    .send(),
+            )
+            .await
+            .context(error::DescribeImagesSnafu {
+                region: region.as_ref(),
+            })?;
+        let source_image = describe_response
+            .images
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .context(error::MissingImageSnafu {
+                image_id: &transfer.source.id,
+                region: region.as_ref(),
+            })?;
+        transfer.description = source_image.description.clone();
+        let arch = source_image
+            .architecture
+            .context(error::MissingInResponseSnafu {
+                request_type: "DescribeImages",
+                missing: "architecture",
+            })?;
+
+        let existing_id = get_ami_id(
+            &transfer.source.name,
+            &arch,
+            region,
+            &target_clients[region],
+            rate_limits,
+        )
+        .await
+        .context(error::GetAmiIdSnafu {
+            name: &transfer.source.name,
+            region: region.as_ref(),
+        })?;
+        if let Some(existing_id) = existing_id {
+            info!(
+                "Found '{}' already in the publishing account in {}: {}",
+                transfer.source.name, region, existing_id
+            );
+            transfer.target_id = Some(existing_id);
+        }
+    }
+    Ok(())
+}
+
+/// Grants or revokes access to the source AMI and its snapshots for the given accounts.
+async fn modify_source_access(
+    plan: &Recorder,
+    modify_opts: &ModifyOptions,
+    operation: &OperationType,
+    transfer: &Transfer,
+    source_clients: &HashMap<Region, Ec2Client>,
+    rate_limiter: &RegionRateLimiter,
+    rate_limits: &RateLimits,
+) -> Result<()> {
+    let region = &transfer.region;
+    let ec2_client = &source_clients[region];
+    let snapshot_ids = get_snapshots(&transfer.source.id, region, ec2_client, rate_limits)
+        .await
+        .context(error::GetSnapshotsSnafu {
+            image_id: &transfer.source.id,
+            region: region.as_ref(),
+        })?;
+    modify_snapshots(
+        plan,
+        modify_opts,
+        operation,
+        &snapshot_ids,
+        ec2_client,
+        region,
+        rate_limiter,
+        rate_limits,
+    )
+    .await
+    .context(error::ModifySourceSnafu {
+        thing: "snapshots",
+        region: region.as_ref(),
+    })?;
+    modify_image(
+        modify_opts,
+        operation,
+        &transfer.source.id,
+        ec2_client,
+        region,
+        rate_limiter,
+        rate_limits,
+    )
+    .await
+    .context(error::ModifySourceImageSnafu {
+        image_id: &transfer.source.id,
+        region: region.as_ref(),
+    })?;
+    Ok(())
+}
+
+/// Copies each source AMI that doesn't already have a copy into the publishing account, and
+/// waits for the copies to be available.
+async fn copy_amis(
+    state: &RunState,
+    transfers: &mut [Transfer],
+    target_clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+    base_region: &Region,
+    publishing_aws: &PubsysAwsConfig,
+    no_progress: bool,
+) -> Result<()> {
+    let mut copy_requests = Vec::with_capacity(transfers.len());
+    for transfer in transfers.iter().filter(|t| t.target_id.is_none()) {
+        let region = &transfer.region;
+        info!(
+            "Starting copy of {} into the publishing account in {}",
+            transfer.source.id, region
+        );
+        let copy_future = target_clients[region]
+            .copy_image()
+            .set_name(Some(transfer.source.name.clone()))
+            .set_description(transfer.description.clone())
+            .set_source_image_id(Some(transfer.source.id.clone()))
+            .set_source_region(Some(region.as_ref().to_string()))
+            .send();
+        let copy_future = state
+            .tracer
+            .traced("copy_image", Some(region.as_ref()), copy_future);
+        let copy_future = rate_limits.rate_limited(EC2, region.as_ref(), copy_future);
+        // Store the region so we can output it to the user
+        let region_future = ready(region.clone());
+        copy_requests.push((region.to_string(), join(region_future, copy_future)));
+    }
+    if copy_requests.is_empty() {
+        return Ok(());
+    }
+
+    let request_stream =
+        stream::iter(state.interrupt.tracked("AMI transfers", copy_requests)).buffer_unordered(4);
+    let copy_responses: Vec<(
+        Region,
+        std::result::Result<CopyImageOutput, SdkError<CopyImageError>>,
+    )> = request_stream.collect().await;
+
+    // Report on successes and errors; don't fail immediately if we see an error so we can report
+    // all successful IDs.
+    let mut saw_error = false;
+    let mut copied = HashMap::with_capacity(copy_responses.len());
+    for (region, copy_response) in copy_responses {
+        match copy_response {
+            Ok(output) => match output.image_id {
+                Some(image_id) => {
+                    info!(
+                        "Copied AMI into the publishing account in {}: {}",
+                        region, image_id
+                    );
+                    copied.insert(region, image_id);
+                }
+                None => {
+                    saw_error = true;
+                    error!("Copied AMI in {} but didn't receive an AMI ID!", region);
+                }
+            },
+            Err(e) => {
+                saw_error = true;
+                error!(
+                    "Copy in {} failed: {}",
+                    region,
+                    e.into_service_error().code().unwrap_or("unknown")
+                );
+            }
+        }
+    }
+
+    // The copies must be available before the source AMIs stop being shared.
+    let mut wait_requests = Vec::with_capacity(copied.len());
+    for (region, image_id) in &copied {
+        let wait_future = state.tracer.traced(
+            "wait_for_ami",
+            Some(region.as_ref()),
+            wait_for_ami(
+                image_id,
+                region,
+                base_region,
+                "available",
+                1,
+                publishing_aws,
+                rate_limits,
+            ),
+        );
+        let info_future = ready((region.clone(), image_id.clone()));
+        wait_requests.push(join(info_future, wait_future));
+    }
+    let progress_bar = progress_bar(no_progress, wait_requests.len(), "Waiting for copies");
+    let request_stream = stream::iter(wait_requests)
+        .buffer_unordered(4)
+        .inspect(|_| progress_bar.inc(1));
+    let wait_responses: Vec<((Region, String), std::result::Result<(), wait::Error>)> =
+        request_stream.collect().await;
+    for ((region, image_id), wait_response) in wait_responses {
+        if let Err(e) = wait_response {
+            saw_error = true;
+            error!(
+                "AMI '{}' in {} did not become available: {}",
+                image_id, region, e
+            );
+        }
+    }
+
+    for transfer in transfers.iter_mut() {
+        if let Some(image_id) = copied.remove(&transfer.region) {
+            // Tags aren't copied with the AMI, so record its lineage on the copy again.
+            if let Some(lineage) = &transfer.source.lineage {
+                if let Err(e) = tag_image(
+                    &target_clients[&transfer.region],
+                    rate_limits,
+                    transfer.region.as_ref(),
+                    &image_id,
+                    lineage,
+                )
+                .await
+                {
+                    saw_error = true;
+                    error!("{}", e);
+                }
+            }
+            if let Err(e) = tag_copy(
+                &target_clients[&transfer.region],
+                rate_limits,
+                &transfer.region,
+                &image_id,
+                publishing_aws,
+            )
+            .await
+            {
+                saw_error = true;
+                error!("{}", e);
+            }
+            transfer.target_id = Some(image_id);
+        }
+    }
+
+    ensure!(!saw_error, error::AmiCopySnafu);
+    Ok(())
+}
+
+/// Applies the default tags to a copied AMI and its snapshots, which exist once it's available.
+async fn tag_copy(
+    ec2_client: &Ec2Client,
+    rate_limits: &RateLimits,
+    region: &Region,
+    image_id: &str,
+    publishing_aws: &PubsysAwsConfig,
+) -> Result<()> {
+    if publishing_aws.tags.is_empty() {
+        return Ok(());
+    }
+    let snapshot_ids = get_snapshots(image_id, region, ec2_client, rate_limits)
+        .await
+        .context(error::GetSnapshotsSnafu {
+            image_id,
+            region: region.as_ref(),
+        })?;
+    let resource_ids: Vec<String> = std::iter::once(image_id.to_string())
+        .chain(snapshot_ids)
+        .collect();
+    tag_ec2_resources(
+        ec2_client,
+        rate_limits,
+        region.as_ref(),
+        &resource_ids,
+        publishing_aws,
+    )
+    .await
+    .context(error::TagSnafu)
+}
+
+mod error {
+    use crate::aws::{ami, publish_ami};
+    use aws_sdk_ec2::error::{DescribeImagesError, ModifyImageAttributeError};
+    use aws_sdk_ec2::types::SdkError;
+    use aws_sdk_sts::error::GetCallerIdentityError;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Some AMIs failed to copy into the publishing account, see above"))]
+        AmiCopy,
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to describe images in {}: {}", region, source))]
+        DescribeImages {
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("Failed to deserialize input from '{}': {}", path.display(), source))]
+        Deserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to {} '{}': {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: io::Error,
+        },
+
+        #[snafu(display(
+            "Error getting AMI ID for {} in publishing account in {}: {}",
+            name,
+            region,
+            source
+        ))]
+        GetAmiId {
+            name: String,
+            region: String,
+            source: ami::register::Error,
+        },
+
+        #[snafu(display("Error getting publishing account ID in {}: {}", region, source))]
+        GetCallerIdentity {
+            region: String,
+            source: SdkError<GetCallerIdentityError>,
+        },
+
+        #[snafu(display(
+            "Failed to get snapshot IDs associated with {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        GetSnapshots {
+            image_id: String,
+            region: String,
+            source: publish_ami::Error,
+        },
+
+        #[snafu(display("Input '{}' is empty", path.display()))]
+        Input { path: PathBuf },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to find given AMI ID {} in {}", image_id, region))]
+        MissingImage { image_id: String, region: String },
+
+        #[snafu(display("Response to {} was missing {}", request_type, missing))]
+        MissingInResponse {
+            request_type: String,
+            missing: String,
+        },
+
+        #[snafu(display(
+            "Failed to modify access to source {} in {}: {}",
+            thing,
+            region,
+            source
+        ))]
+        ModifySource {
+            thing: String,
+            region: String,
+            source: publish_ami::Error,
+        },
+
+        #[snafu(display(
+            "Failed to modify access to source image {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        ModifySourceImage {
+            image_id: String,
+            region: String,
+            source: SdkError<ModifyImageAttributeError>,
+        },
+
+        #[snafu(display("{}", source))]
+        Tag { source: crate::aws::tags::Error },
+
+        #[snafu(display(
+            "Given region(s) in Infra.toml / regions argument that are not in --ami-input file: {}",
+            regions.join(", ")
+        ))]
+        UnknownRegions { regions: Vec<String> },
+
+        #[snafu(display("Failed to write AMIs to '{}': {}", path.display(), source))]
+        WriteAmis {
+            path: PathBuf,
+            source: publish_ami::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DescribeImages { .. }
+            | Self::GetCallerIdentity { .. }
+            | Self::ModifySourceImage { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
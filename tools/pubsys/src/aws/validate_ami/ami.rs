@@ -1,15 +1,18 @@
 //! The ami module owns the describing of images in EC2.
 
-use aws_sdk_ec2::model::Image;
+use aws_sdk_ec2::model::{Filter, Image};
 use aws_sdk_ec2::{Client as Ec2Client, Region};
 use futures::future::{join, ready};
 use futures::stream::{FuturesUnordered, StreamExt};
+use indicatif::ProgressBar;
 use log::{info, trace};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
 
 use crate::aws::ami::launch_permissions::{get_launch_permissions, LaunchPermissionDef};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
 
 /// Wrapper structure for the `ImageDef` struct, used during deserialization
 #[derive(Deserialize)]
@@ -30,27 +33,27 @@ impl ImageData {
 
 /// Structure of the EC2 image fields that should be validated
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
-pub(crate) struct ImageDef {
+pub struct ImageDef {
     /// The ID of the EC2 image
-    pub(crate) id: String,
+    pub id: String,
 
     /// The name of the EC2 image
-    pub(crate) name: String,
+    pub name: String,
 
     /// Whether or not the EC2 image is public
     #[serde(default)]
-    pub(crate) public: bool,
+    pub public: bool,
 
     /// The launch permissions for the EC2 image.
-    pub(crate) launch_permissions: Option<Vec<LaunchPermissionDef>>,
+    pub launch_permissions: Option<Vec<LaunchPermissionDef>>,
 
     /// Whether or not the EC2 image supports Elastic Network Adapter
     #[serde(default = "default_ena_support")]
-    pub(crate) ena_support: bool,
+    pub ena_support: bool,
 
     /// The level of the EC2 image's Single Root I/O Virtualization support
     #[serde(default = "default_sriov_net_support")]
-    pub(crate) sriov_net_support: String,
+    pub sriov_net_support: String,
 }
 
 fn default_ena_support() -> bool {
@@ -81,7 +84,10 @@ impl From<(Image, Option<Vec<LaunchPermissionDef>>)> for ImageDef {
 /// `ImageDef`.
 pub(crate) async fn describe_images<'a>(
     clients: &'a HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
     expected_images: &HashMap<Region, Vec<ImageDef>>,
+    progress_bar: &ProgressBar,
 ) -> HashMap<&'a Region, Result<HashMap<String, ImageDef>>> {
     // Build requests for images; we have to request with a regional client so we split them by
     // region
@@ -91,6 +97,8 @@ pub(crate) async fn describe_images<'a>(
         let get_future = describe_images_in_region(
             region,
             ec2_client,
+            rate_limits,
+            page_size,
             expected_images
                 .get(region)
                 .map(|i| i.to_owned())
@@ -107,31 +115,45 @@ pub(crate) async fn describe_images<'a>(
     requests
         .into_iter()
         .collect::<FuturesUnordered<_>>()
+        .inspect(|_| progress_bar.inc(1))
         .collect()
         .await
 }
 
-/// Fetches the images whose IDs are keys in `expected_images`
+/// Fetches the images whose IDs are keys in `expected_images`, in pages of the given size, if any
 pub(crate) async fn describe_images_in_region(
     region: &Region,
     client: &Ec2Client,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
     expected_images: HashMap<String, ImageDef>,
 ) -> Result<HashMap<String, ImageDef>> {
     info!("Retrieving images in {}", region.to_string());
     let mut images = HashMap::new();
 
-    // Send the request
-    let mut get_future = client
-        .describe_images()
-        .include_deprecated(true)
-        .set_image_ids(Some(Vec::from_iter(
-            expected_images.keys().map(|k| k.to_owned()),
-        )))
-        .into_paginator()
-        .send();
-
-    // Iterate over the retrieved images
-    while let Some(page) = get_future.next().await {
+    // Send the request.  EC2 doesn't accept a page size along with image IDs, so if we have a page
+    // size, we filter by image ID instead.
+    let image_ids = Vec::from_iter(expected_images.keys().map(|k| k.to_owned()));
+    let request = client.describe_images().include_deprecated(true);
+    let mut get_future = match page_size {
+        Some(page_size) => request
+            .filters(
+                Filter::builder()
+                    .name("image-id")
+                    .set_values(Some(image_ids))
+                    .build(),
+            )
+            .into_paginator()
+            .page_size(page_size),
+        None => request.set_image_ids(Some(image_ids)).into_paginator(),
+    }
+    .send();
+
+    // Iterate over the retrieved images; each page is a request, so each waits its turn.
+    while let Some(page) = rate_limits
+        .rate_limited(EC2, region.as_ref(), get_future.next())
+        .await
+    {
         let retrieved_images = page
             .context(error::DescribeImagesSnafu {
                 region: region.to_string(),
@@ -162,7 +184,7 @@ pub(crate) async fn describe_images_in_region(
             );
             let launch_permissions = if !expected_public {
                 Some(
-                    get_launch_permissions(client, region.as_ref(), &image_id)
+                    get_launch_permissions(client, rate_limits, region.as_ref(), &image_id)
                         .await
                         .context(error::GetLaunchPermissionsSnafu {
                             region: region.as_ref(),
@@ -190,7 +212,7 @@ pub(crate) mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     #[allow(clippy::large_enum_variant)]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display(
             "Failed to describe images in {}: {}",
             region,
@@ -222,3 +244,12 @@ pub(crate) mod error {
 }
 
 pub(crate) type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for error::Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
@@ -1,13 +1,16 @@
 //! The validate_ami module owns the 'validate-ami' subcommand and controls the process of validating
 //! EC2 images
 
-pub(crate) mod ami;
-pub(crate) mod results;
+pub mod ami;
+pub mod results;
 
 use self::ami::{ImageData, ImageDef};
 use self::results::{AmiValidationResult, AmiValidationResultStatus, AmiValidationResults};
-use crate::aws::client::build_client_config;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::RateLimits;
 use crate::aws::validate_ami::ami::describe_images;
+use crate::progress::progress_bar;
 use crate::Args;
 use aws_sdk_ec2::{Client as AmiClient, Region};
 use log::{error, info, trace};
@@ -23,7 +26,7 @@ use structopt::{clap, StructOpt};
 /// `sriov-net-support`, and `launch-permissions` fields have the expected values.
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct ValidateAmiArgs {
+pub struct ValidateAmiArgs {
     /// File holding the expected amis
     #[structopt(long, parse(from_os_str))]
     expected_amis_path: PathBuf,
@@ -32,76 +35,108 @@ pub(crate) struct ValidateAmiArgs {
     #[structopt(long, parse(from_os_str))]
     write_results_path: Option<PathBuf>,
 
-    #[structopt(long, requires = "write-results-path")]
+    #[structopt(
+        long,
+        requires = "write-results-path",
+        possible_values = AmiValidationResultStatus::NAMES
+    )]
     /// Optional filter to only write validation results with these statuses to the above path
-    /// The available statuses are: `Correct`, `Incorrect`, `Missing`.
+    /// The available statuses are: `Correct`, `Incorrect`, `Missing`, `Unreachable`.
     write_results_filter: Option<Vec<AmiValidationResultStatus>>,
 
     #[structopt(long)]
     /// If this argument is given, print the validation results summary as a JSON object instead
     /// of a plaintext table
     json: bool,
+
+    #[structopt(long)]
+    /// Don't display progress bars
+    no_progress: bool,
 }
 
-/// Performs EC2 image validation and returns the `AmiValidationResults` object
-pub(crate) async fn validate(
-    args: &Args,
-    validate_ami_args: &ValidateAmiArgs,
-) -> Result<AmiValidationResults> {
-    info!("Parsing Infra.toml file");
+/// What `validate` checks, and where it writes the results
+#[derive(Debug, Clone, Default)]
+pub struct ValidateAmiOptions {
+    /// File holding the expected AMIs, in the format `ami` writes
+    pub expected_amis_path: PathBuf,
+    /// Where to write the validation results as JSON, if anywhere
+    pub write_results_path: Option<PathBuf>,
+    /// Only write results with these statuses; all results are written if this is None
+    pub write_results_filter: Option<Vec<AmiValidationResultStatus>>,
+    /// Don't display progress bars
+    pub no_progress: bool,
+}
 
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
+impl From<&ValidateAmiArgs> for ValidateAmiOptions {
+    fn from(args: &ValidateAmiArgs) -> Self {
+        Self {
+            expected_amis_path: args.expected_amis_path.clone(),
+            write_results_path: args.write_results_path.clone(),
+            write_results_filter: args.write_results_filter.clone(),
+            no_progress: args.no_progress,
+        }
+    }
+}
 
+/// Performs EC2 image validation and returns the `AmiValidationResults` object
+pub async fn validate(
+    infra_config: &InfraConfig,
+    options: &ValidateAmiOptions,
+) -> Result<AmiValidationResults> {
     trace!("Parsed infra config: {:#?}", infra_config);
 
-    let aws = infra_config.aws.unwrap_or_default();
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
 
     // Parse the expected ami file
     info!("Parsing expected ami file");
-    let expected_images = parse_expected_amis(&validate_ami_args.expected_amis_path).await?;
+    let expected_images = parse_expected_amis(&options.expected_amis_path).await?;
 
     info!("Parsed expected ami file");
 
     // Create a `HashMap` of `AmiClient`s, one for each region where validation should happen
     let base_region = &Region::new(
-        aws.regions
+        aws.regions_for("validate_ami")
             .get(0)
-            .ok_or(error::Error::EmptyInfraRegions {
-                path: args.infra_config_path.clone(),
-            })?
+            .ok_or(error::Error::EmptyInfraRegions)?
             .clone(),
     );
     let mut ami_clients = HashMap::with_capacity(expected_images.len());
 
     for region in expected_images.keys() {
         let client_config = build_client_config(region, base_region, &aws).await;
-        let ami_client = AmiClient::new(&client_config);
+        let ami_client = AmiClient::from_pubsys_config(&client_config, &aws);
         ami_clients.insert(region.clone(), ami_client);
     }
 
     // Retrieve the EC2 images using the `AmiClient`s
     info!("Retrieving EC2 images");
-    let images = describe_images(&ami_clients, &expected_images)
-        .await
-        .into_iter()
-        .map(|(region, result)| {
-            (
-                region,
-                result.map_err(|e| {
-                    error!(
-                        "Failed to retrieve images in region {}: {}",
-                        region.to_string(),
-                        e
-                    );
-                    error::Error::UnreachableRegion {
-                        region: region.to_string(),
-                    }
-                }),
-            )
-        })
-        .collect::<HashMap<&Region, Result<_>>>();
+    let progress_bar = progress_bar(options.no_progress, ami_clients.len(), "Retrieving images");
+    let images = describe_images(
+        &ami_clients,
+        &rate_limits,
+        page_size::get(&aws, |page_size| page_size.describe_images),
+        &expected_images,
+        &progress_bar,
+    )
+    .await
+    .into_iter()
+    .map(|(region, result)| {
+        (
+            region,
+            result.map_err(|e| {
+                error!(
+                    "Failed to retrieve images in region {}: {}",
+                    region.to_string(),
+                    e
+                );
+                error::Error::UnreachableRegion {
+                    region: region.to_string(),
+                }
+            }),
+        )
+    })
+    .collect::<HashMap<&Region, Result<_>>>();
 
     // Validate the retrieved EC2 images per region
     info!("Validating EC2 images");
@@ -125,10 +160,10 @@ pub(crate) async fn validate(
     let validation_results = AmiValidationResults::from_result_map(results);
 
     // If a path was given, write the results
-    if let Some(write_results_path) = &validate_ami_args.write_results_path {
+    if let Some(write_results_path) = &options.write_results_path {
         // Filter the results by given status, and if no statuses were given, get all results
         info!("Writing results to file");
-        let results = if let Some(filter) = &validate_ami_args.write_results_filter {
+        let results = if let Some(filter) = &options.write_results_filter {
             validation_results.get_results_for_status(filter)
         } else {
             validation_results.get_all_results()
@@ -217,8 +252,11 @@ pub(crate) async fn parse_expected_amis(
 }
 
 /// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, validate_ami_args: &ValidateAmiArgs) -> Result<()> {
-    let results = validate(args, validate_ami_args).await?;
+pub async fn run(args: &Args, validate_ami_args: &ValidateAmiArgs) -> Result<()> {
+    info!("Parsing Infra.toml file");
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let results = validate(&infra_config, &ValidateAmiOptions::from(validate_ami_args)).await?;
 
     if validate_ami_args.json {
         println!(
@@ -238,12 +276,12 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error reading config: {}", source))]
         Config { source: pubsys_config::Error },
 
-        #[snafu(display("Empty regions array in Infra.toml at path {}", path.display()))]
-        EmptyInfraRegions { path: PathBuf },
+        #[snafu(display("Empty regions array in Infra.toml"))]
+        EmptyInfraRegions,
 
         #[snafu(display("Failed to parse image file: {}", source))]
         ParseExpectedImagesFile { source: serde_json::Error },
@@ -271,7 +309,7 @@ mod error {
     }
 }
 
-pub(crate) use error::Error;
+pub use error::Error;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
@@ -10,8 +10,8 @@ use std::fmt::{self, Display};
 use tabled::{Table, Tabled};
 
 /// Represent the possible status of an EC2 image validation
-#[derive(Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
-pub(crate) enum AmiValidationResultStatus {
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum AmiValidationResultStatus {
     /// The image was found and its monitored fields have the expected values
     Correct,
 
@@ -28,24 +28,30 @@ pub(crate) enum AmiValidationResultStatus {
 derive_display_from_serialize!(AmiValidationResultStatus);
 derive_fromstr_from_deserialize!(AmiValidationResultStatus);
 
+impl AmiValidationResultStatus {
+    /// The names of the statuses, as given to `--write-results-filter`
+    pub(crate) const NAMES: &'static [&'static str] =
+        &["Correct", "Incorrect", "Missing", "Unreachable"];
+}
+
 /// Represents a single EC2 image validation result
 #[derive(Debug, Eq, Hash, PartialEq, Serialize)]
-pub(crate) struct AmiValidationResult {
+pub struct AmiValidationResult {
     /// The ID of the image
-    pub(crate) id: String,
+    pub id: String,
 
     /// `ImageDef` containing expected values for the image
-    pub(crate) expected_image_def: ImageDef,
+    pub expected_image_def: ImageDef,
 
     /// `ImageDef` containing actual values for the image
-    pub(crate) actual_image_def: Option<ImageDef>,
+    pub actual_image_def: Option<ImageDef>,
 
     /// The region the image resides in
     #[serde(serialize_with = "serialize_region")]
-    pub(crate) region: Region,
+    pub region: Region,
 
     /// The validation status of the image
-    pub(crate) status: AmiValidationResultStatus,
+    pub status: AmiValidationResultStatus,
 }
 
 fn serialize_region<S>(region: &Region, serializer: S) -> std::result::Result<S::Ok, S::Error>
@@ -114,8 +120,8 @@ impl From<&HashSet<AmiValidationResult>> for AmiValidationRegionSummary {
 
 /// Represents all EC2 image validation results
 #[derive(Debug)]
-pub(crate) struct AmiValidationResults {
-    pub(crate) results: HashMap<Region, HashSet<AmiValidationResult>>,
+pub struct AmiValidationResults {
+    pub results: HashMap<Region, HashSet<AmiValidationResult>>,
 }
 
 impl Default for AmiValidationResults {
@@ -143,12 +149,12 @@ impl Display for AmiValidationResults {
 }
 
 impl AmiValidationResults {
-    pub(crate) fn from_result_map(results: HashMap<Region, HashSet<AmiValidationResult>>) -> Self {
+    pub fn from_result_map(results: HashMap<Region, HashSet<AmiValidationResult>>) -> Self {
         AmiValidationResults { results }
     }
 
     /// Returns a `HashSet` containing all validation results whose status is present in `requested_status`
-    pub(crate) fn get_results_for_status(
+    pub fn get_results_for_status(
         &self,
         requested_status: &[AmiValidationResultStatus],
     ) -> HashSet<&AmiValidationResult> {
@@ -165,7 +171,7 @@ impl AmiValidationResults {
     }
 
     /// Returns a `HashSet` containing all validation results
-    pub(crate) fn get_all_results(&self) -> HashSet<&AmiValidationResult> {
+    pub fn get_all_results(&self) -> HashSet<&AmiValidationResult> {
         let mut results = HashSet::new();
         for region_results in self.results.values() {
             results.extend(region_results)
@@ -185,7 +191,7 @@ impl AmiValidationResults {
             .collect()
     }
 
-    pub(crate) fn get_json_summary(&self) -> serde_json::Value {
+    pub fn get_json_summary(&self) -> serde_json::Value {
         serde_json::json!(self
             .get_results_summary()
             .into_iter()
@@ -0,0 +1,729 @@
+//! The validate_snapshots module owns the 'validate-snapshots' subcommand and controls the process
+//! of validating the EBS snapshots that back EC2 images
+
+pub mod results;
+
+use self::results::{
+    SnapshotValidationResult, SnapshotValidationResultStatus, SnapshotValidationResults,
+};
+use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::publish_ami::get_snapshots;
+use crate::aws::publish_ami::verify::get_volume_permissions;
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
+use crate::progress::progress_bar;
+use crate::Args;
+use aws_sdk_ec2::model::Snapshot;
+use aws_sdk_ec2::types::SdkError;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use buildsys::manifest;
+use futures::future::{join, ready};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, trace};
+use pubsys_config::InfraConfig;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+
+/// The error code EC2 gives when a requested snapshot doesn't exist
+const SNAPSHOT_NOT_FOUND: &str = "InvalidSnapshot.NotFound";
+
+/// Validates the EBS snapshots backing the EC2 images in the file given by `ami-input`, like the
+/// `amis.json` written by the `ami` subcommand, by calling `describe-snapshots` and ensuring each
+/// snapshot exists and has the expected encryption, size, and `createVolumePermission` sharing.
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct ValidateSnapshotsArgs {
+    /// Path to the JSON file containing regional AMI IDs whose snapshots should be validated
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// Path to the variant manifest; if given, snapshot sizes are checked against its image layout
+    #[structopt(long, parse(from_os_str))]
+    variant_manifest: Option<PathBuf>,
+
+    /// ARN of the KMS key the snapshots should be encrypted with; if not given, the snapshots
+    /// should be unencrypted
+    #[structopt(long)]
+    kms_key_id: Option<String>,
+
+    /// Optional path where the validation results should be written
+    #[structopt(long, parse(from_os_str))]
+    write_results_path: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        requires = "write-results-path",
+        possible_values = SnapshotValidationResultStatus::NAMES
+    )]
+    /// Optional filter to only write validation results with these statuses to the above path
+    /// The available statuses are: `Correct`, `Incorrect`, `Missing`, `Unreachable`.
+    write_results_filter: Option<Vec<SnapshotValidationResultStatus>>,
+
+    #[structopt(long)]
+    /// If this argument is given, print the validation results summary as a JSON object instead
+    /// of a plaintext table
+    json: bool,
+
+    #[structopt(long)]
+    /// Don't display progress bars
+    no_progress: bool,
+}
+
+/// What `validate` checks, and where it writes the results
+#[derive(Debug, Clone, Default)]
+pub struct ValidateSnapshotsOptions {
+    /// JSON file holding the regional AMIs whose snapshots are checked, like `ami` writes
+    pub ami_input: PathBuf,
+    /// Variant manifest to check snapshot sizes against, if they're checked
+    pub variant_manifest: Option<PathBuf>,
+    /// ARN of the KMS key the snapshots should be encrypted with, or None if they should be
+    /// unencrypted
+    pub kms_key_id: Option<String>,
+    /// Where to write the validation results as JSON, if anywhere
+    pub write_results_path: Option<PathBuf>,
+    /// Only write results with these statuses; all results are written if this is None
+    pub write_results_filter: Option<Vec<SnapshotValidationResultStatus>>,
+    /// Don't display progress bars
+    pub no_progress: bool,
+}
+
+impl From<&ValidateSnapshotsArgs> for ValidateSnapshotsOptions {
+    fn from(args: &ValidateSnapshotsArgs) -> Self {
+        Self {
+            ami_input: args.ami_input.clone(),
+            variant_manifest: args.variant_manifest.clone(),
+            kms_key_id: args.kms_key_id.clone(),
+            write_results_path: args.write_results_path.clone(),
+            write_results_filter: args.write_results_filter.clone(),
+            no_progress: args.no_progress,
+        }
+    }
+}
+
+/// Structure of the EBS snapshot fields that should be validated
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct SnapshotDef {
+    /// Whether or not the snapshot is encrypted
+    pub encrypted: bool,
+
+    /// The ARN of the KMS key the snapshot is encrypted with
+    pub kms_key_id: Option<String>,
+
+    /// The size of the snapshot in GiB, if it's checked
+    pub size_gib: Option<i32>,
+
+    /// Whether or not the snapshot is public
+    pub public: bool,
+
+    /// The accounts allowed to create volumes from the snapshot, if it's not expected to be public
+    pub user_ids: Option<Vec<String>>,
+}
+
+impl SnapshotDef {
+    /// Returns a copy without the fields that `expected` doesn't check, so that they aren't
+    /// counted as differences.
+    fn checked_against(&self, expected: &SnapshotDef) -> SnapshotDef {
+        SnapshotDef {
+            size_gib: expected.size_gib.and(self.size_gib),
+            user_ids: expected.user_ids.as_ref().and(self.user_ids.clone()),
+            ..self.clone()
+        }
+    }
+}
+
+impl From<(&Snapshot, Vec<LaunchPermissionDef>)> for SnapshotDef {
+    fn from(args: (&Snapshot, Vec<LaunchPermissionDef>)) -> Self {
+        let (snapshot, permissions) = args;
+        let mut user_ids = Vec::new();
+        let mut public = false;
+        for permission in permissions {
+            match permission {
+                LaunchPermissionDef::Group(group) if group == "all" => public = true,
+                LaunchPermissionDef::UserId(user_id) => user_ids.push(user_id),
+                _ => {}
+            }
+        }
+        user_ids.sort();
+        Self {
+            encrypted: snapshot.encrypted().unwrap_or_default(),
+            kms_key_id: snapshot.kms_key_id().map(str::to_string),
+            size_gib: snapshot.volume_size(),
+            public,
+            user_ids: Some(user_ids),
+        }
+    }
+}
+
+/// What the snapshots of every image are expected to look like, apart from their sharing, which
+/// comes from each image's own permissions
+#[derive(Debug, Default)]
+pub(crate) struct ExpectedSnapshots {
+    /// The ARN of the KMS key the snapshots should be encrypted with, if any
+    pub(crate) kms_key_id: Option<String>,
+
+    /// The sizes in GiB of each image's snapshots, in block device mapping order, if checked
+    pub(crate) sizes_gib: Option<Vec<i32>>,
+}
+
+impl ExpectedSnapshots {
+    /// Returns the expected values for the snapshot at the given position in the image's block
+    /// device mappings.  Organizations can't be given snapshot permissions, so only accounts are
+    /// expected.
+    fn snapshot_def(&self, image: &Image, index: usize) -> SnapshotDef {
+        let public = image.public.unwrap_or_default();
+        let user_ids = if public {
+            None
+        } else {
+            let mut user_ids: Vec<String> = image
+                .launch_permissions
+                .iter()
+                .flatten()
+                .filter_map(|permission| match permission {
+                    LaunchPermissionDef::UserId(user_id) => Some(user_id.clone()),
+                    _ => None,
+                })
+                .collect();
+            user_ids.sort();
+            Some(user_ids)
+        };
+        SnapshotDef {
+            encrypted: self.kms_key_id.is_some(),
+            kms_key_id: self.kms_key_id.clone(),
+            size_gib: self
+                .sizes_gib
+                .as_ref()
+                .map(|sizes| sizes.get(index).copied().unwrap_or_default()),
+            public,
+            user_ids,
+        }
+    }
+}
+
+/// The snapshots found for an image in one region, by ID, in block device mapping order; a
+/// snapshot that doesn't exist has no `SnapshotDef`.  `None` means the image itself wasn't found.
+type RegionSnapshots = Option<Vec<(String, Option<SnapshotDef>)>>;
+
+/// Performs EBS snapshot validation and returns the `SnapshotValidationResults` object
+pub async fn validate(
+    infra_config: &InfraConfig,
+    options: &ValidateSnapshotsOptions,
+) -> Result<SnapshotValidationResults> {
+    trace!("Parsed infra config: {:#?}", infra_config);
+
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+
+    // Parse the AMI file
+    info!("Parsing AMI input file");
+    let images = parse_ami_input(&options.ami_input)?;
+
+    let expected = ExpectedSnapshots {
+        kms_key_id: options.kms_key_id.clone(),
+        sizes_gib: match &options.variant_manifest {
+            Some(path) => Some(snapshot_sizes(path)?),
+            None => None,
+        },
+    };
+
+    // Create a `HashMap` of `Ec2Client`s, one for each region where validation should happen
+    let base_region = &Region::new(
+        aws.regions_for("validate_snapshots")
+            .get(0)
+            .context(error::EmptyInfraRegionsSnafu)?
+            .clone(),
+    );
+    let mut ec2_clients = HashMap::with_capacity(images.len());
+    for region in images.keys() {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, &aws);
+        ec2_clients.insert(region.clone(), ec2_client);
+    }
+
+    // Retrieve the snapshots using the `Ec2Client`s
+    info!("Retrieving EBS snapshots");
+    let progress_bar = progress_bar(
+        options.no_progress,
+        ec2_clients.len(),
+        "Retrieving snapshots",
+    );
+    let snapshots: HashMap<&Region, Result<RegionSnapshots>> = ec2_clients
+        .iter()
+        .map(|(region, ec2_client)| {
+            join(
+                ready(region),
+                describe_snapshots_in_region(region, ec2_client, &rate_limits, &images[region]),
+            )
+        })
+        .collect::<FuturesUnordered<_>>()
+        .inspect(|_| progress_bar.inc(1))
+        .collect()
+        .await;
+
+    // Validate the retrieved snapshots per region
+    info!("Validating EBS snapshots");
+    let results: HashMap<Region, HashSet<SnapshotValidationResult>> = snapshots
+        .into_iter()
+        .map(|(region, region_result)| {
+            if let Err(e) = &region_result {
+                error!("Failed to retrieve snapshots in region {}: {}", region, e);
+            }
+            (
+                region.clone(),
+                validate_snapshots_in_region(&images[region], &expected, &region_result, region),
+            )
+        })
+        .collect();
+
+    let validation_results = SnapshotValidationResults::from_result_map(results);
+
+    // If a path was given, write the results
+    if let Some(write_results_path) = &options.write_results_path {
+        // Filter the results by given status, and if no statuses were given, get all results
+        info!("Writing results to file");
+        let results = if let Some(filter) = &options.write_results_filter {
+            validation_results.get_results_for_status(filter)
+        } else {
+            validation_results.get_all_results()
+        };
+
+        // Write the results as JSON
+        serde_json::to_writer_pretty(
+            &File::create(write_results_path).context(error::WriteValidationResultsSnafu {
+                path: write_results_path,
+            })?,
+            &results,
+        )
+        .context(error::SerializeValidationResultsSnafu)?;
+    }
+
+    Ok(validation_results)
+}
+
+/// Validates the snapshots of an image in a single region, based on the expected snapshot values
+/// and the snapshots retrieved from the region.  If the image or region couldn't be read, a single
+/// result without a snapshot ID stands for the image's snapshots.
+pub(crate) fn validate_snapshots_in_region(
+    image: &Image,
+    expected: &ExpectedSnapshots,
+    actual_snapshots: &Result<RegionSnapshots>,
+    region: &Region,
+) -> HashSet<SnapshotValidationResult> {
+    match actual_snapshots {
+        Ok(Some(actual_snapshots)) => actual_snapshots
+            .iter()
+            .enumerate()
+            .map(|(index, (snapshot_id, actual))| {
+                let expected_def = expected.snapshot_def(image, index);
+                let actual_def = actual
+                    .as_ref()
+                    .map(|actual| actual.checked_against(&expected_def));
+                SnapshotValidationResult::new(
+                    Some(snapshot_id.clone()),
+                    image.id.clone(),
+                    expected_def,
+                    Ok(actual_def),
+                    region.clone(),
+                )
+            })
+            .collect(),
+        Ok(None) => HashSet::from([SnapshotValidationResult::new(
+            None,
+            image.id.clone(),
+            expected.snapshot_def(image, 0),
+            Ok(None),
+            region.clone(),
+        )]),
+        Err(_) => HashSet::from([SnapshotValidationResult::new(
+            None,
+            image.id.clone(),
+            expected.snapshot_def(image, 0),
+            Err(error::Error::UnreachableRegion {
+                region: region.to_string(),
+            }),
+            region.clone(),
+        )]),
+    }
+}
+
+/// Fetches the snapshots backing the given image, along with their permissions.
+async fn describe_snapshots_in_region(
+    region: &Region,
+    client: &Ec2Client,
+    rate_limits: &RateLimits,
+    image: &Image,
+) -> Result<RegionSnapshots> {
+    info!("Retrieving snapshots in {}", region);
+    let snapshot_ids = match get_snapshots(&image.id, region, client, rate_limits).await {
+        Ok(snapshot_ids) => snapshot_ids,
+        Err(crate::aws::publish_ami::Error::MissingImage { .. }) => return Ok(None),
+        Err(e) => {
+            return Err(e).context(error::GetSnapshotsSnafu {
+                image_id: &image.id,
+                region: region.as_ref(),
+            })
+        }
+    };
+
+    let mut snapshots = Vec::with_capacity(snapshot_ids.len());
+    for snapshot_id in snapshot_ids {
+        // An image's snapshots can be deleted out from under it, so a missing one isn't an error.
+        let response = match rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                client
+                    .describe_snapshots()
+                    .snapshot_ids(&snapshot_id)
+                    .send(),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().code() == Some(SNAPSHOT_NOT_FOUND) =>
+            {
+                snapshots.push((snapshot_id, None));
+                continue;
+            }
+            Err(e) => {
+                return Err(e).context(error::DescribeSnapshotsSnafu {
+                    snapshot_id: &snapshot_id,
+                    region: region.as_ref(),
+                })
+            }
+        };
+        let snapshot = match response.snapshots().and_then(|s| s.first()) {
+            Some(snapshot) => snapshot,
+            None => {
+                snapshots.push((snapshot_id, None));
+                continue;
+            }
+        };
+
+        trace!(
+            "Retrieving volume permissions for {} in {}",
+            snapshot_id,
+            region
+        );
+        let permissions = get_volume_permissions(client, rate_limits, region, &snapshot_id)
+            .await
+            .context(error::GetVolumePermissionsSnafu {
+                snapshot_id: &snapshot_id,
+                region: region.as_ref(),
+            })?;
+        let snapshot_def = SnapshotDef::from((snapshot, permissions));
+        snapshots.push((snapshot_id, Some(snapshot_def)));
+    }
+
+    info!("Snapshots in {} have been retrieved", region);
+    Ok(Some(snapshots))
+}
+
+/// Parse the file holding AMI IDs. Return a `HashMap` of `Region` mapped to the `Image` in that
+/// region.
+pub(crate) fn parse_ami_input(ami_input: &Path) -> Result<HashMap<Region, Image>> {
+    let images: HashMap<String, Image> = serde_json::from_reader(
+        &File::open(ami_input).context(error::ReadAmiInputSnafu { path: ami_input })?,
+    )
+    .context(error::ParseAmiInputSnafu { path: ami_input })?;
+
+    Ok(images
+        .into_iter()
+        .map(|(region, image)| (Region::new(region), image))
+        .collect())
+}
+
+/// Returns the sizes in GiB of the snapshots registered for the given variant, in block device
+/// mapping order.
+fn snapshot_sizes(variant_manifest: &Path) -> Result<Vec<i32>> {
+    let manifest =
+        manifest::ManifestInfo::new(variant_manifest).context(error::LoadVariantManifestSnafu {
+            path: variant_manifest,
+        })?;
+    let image_layout = manifest
+        .image_layout()
+        .context(error::MissingImageLayoutSnafu {
+            path: variant_manifest,
+        })?;
+    let (os_image_size, data_image_size) = image_layout.image_sizes_gib();
+    Ok([os_image_size, data_image_size]
+        .into_iter()
+        .filter(|size| *size > 0)
+        .collect())
+}
+
+/// Common entrypoint from main()
+pub async fn run(args: &Args, validate_snapshots_args: &ValidateSnapshotsArgs) -> Result<()> {
+    info!("Parsing Infra.toml file");
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let results = validate(
+        &infra_config,
+        &ValidateSnapshotsOptions::from(validate_snapshots_args),
+    )
+    .await?;
+
+    if validate_snapshots_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&results.get_json_summary())
+                .context(error::SerializeResultsSummarySnafu)?
+        )
+    } else {
+        println!("{}", results);
+    }
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::DescribeSnapshotsError;
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to describe snapshot {} in {}: {}",
+            snapshot_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeSnapshots {
+            snapshot_id: String,
+            region: String,
+            source: SdkError<DescribeSnapshotsError>,
+        },
+
+        #[snafu(display("Empty regions array in Infra.toml"))]
+        EmptyInfraRegions,
+
+        #[snafu(display(
+            "Failed to find snapshots of image {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        GetSnapshots {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(crate::aws::publish_ami::Error, Box::new)))]
+            source: Box<crate::aws::publish_ami::Error>,
+        },
+
+        #[snafu(display(
+            "Failed to retrieve volume permissions for snapshot {} in {}: {}",
+            snapshot_id,
+            region,
+            source
+        ))]
+        GetVolumePermissions {
+            snapshot_id: String,
+            region: String,
+            source: crate::aws::publish_ami::verify::Error,
+        },
+
+        #[snafu(display("Unable to parse variant manifest '{}': {}", path.display(), source))]
+        LoadVariantManifest {
+            path: PathBuf,
+            source: buildsys::manifest::Error,
+        },
+
+        #[snafu(display("Could not find image layout for {}", path.display()))]
+        MissingImageLayout { path: PathBuf },
+
+        #[snafu(display("Failed to parse AMI input '{}': {}", path.display(), source))]
+        ParseAmiInput {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read AMI input '{}': {}", path.display(), source))]
+        ReadAmiInput {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize results summary to JSON: {}", source))]
+        SerializeResultsSummary { source: serde_json::Error },
+
+        #[snafu(display("Failed to serialize validation results to json: {}", source))]
+        SerializeValidationResults { source: serde_json::Error },
+
+        #[snafu(display("Failed to retrieve snapshots from region {}", region))]
+        UnreachableRegion { region: String },
+
+        #[snafu(display("Failed to write validation results to {:?}: {}", path, source))]
+        WriteValidationResults {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+
+pub use error::Error;
+
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::DescribeSnapshots { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::results::SnapshotValidationResultStatus;
+    use super::{validate_snapshots_in_region, ExpectedSnapshots, SnapshotDef};
+    use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+    use crate::aws::ami::Image;
+    use aws_sdk_ec2::Region;
+
+    fn image(public: bool, user_ids: &[&str]) -> Image {
+        Image {
+            id: "ami-1".to_string(),
+            name: "bottlerocket".to_string(),
+            public: Some(public),
+            launch_permissions: Some(
+                user_ids
+                    .iter()
+                    .map(|id| LaunchPermissionDef::UserId(id.to_string()))
+                    .collect(),
+            ),
+            lineage: None,
+        }
+    }
+
+    fn snapshot(size_gib: i32, public: bool, user_ids: &[&str]) -> SnapshotDef {
+        SnapshotDef {
+            encrypted: false,
+            kms_key_id: None,
+            size_gib: Some(size_gib),
+            public,
+            user_ids: Some(user_ids.iter().map(|id| id.to_string()).collect()),
+        }
+    }
+
+    fn statuses(
+        image: &Image,
+        expected: &ExpectedSnapshots,
+        actual: Vec<(&str, Option<SnapshotDef>)>,
+    ) -> Vec<(Option<String>, SnapshotValidationResultStatus)> {
+        let actual = actual
+            .into_iter()
+            .map(|(id, def)| (id.to_string(), def))
+            .collect();
+        let mut statuses: Vec<_> = validate_snapshots_in_region(
+            image,
+            expected,
+            &Ok(Some(actual)),
+            &Region::new("us-west-2"),
+        )
+        .into_iter()
+        .map(|result| (result.id, result.status))
+        .collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+
+    // Sizes and permissions are only compared when they're expected.
+    #[test]
+    fn unchecked_fields_ignored() {
+        let image = image(true, &[]);
+        let actual = vec![
+            ("snap-1", Some(snapshot(2, true, &["111122223333"]))),
+            ("snap-2", Some(snapshot(1, true, &[]))),
+        ];
+        assert_eq!(
+            statuses(&image, &ExpectedSnapshots::default(), actual),
+            vec![
+                (
+                    Some("snap-1".to_string()),
+                    SnapshotValidationResultStatus::Correct
+                ),
+                (
+                    Some("snap-2".to_string()),
+                    SnapshotValidationResultStatus::Correct
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sizes_encryption_and_sharing() {
+        let image = image(false, &["444455556666", "111122223333"]);
+        let expected = ExpectedSnapshots {
+            kms_key_id: None,
+            sizes_gib: Some(vec![2, 1]),
+        };
+        let actual = vec![
+            (
+                "snap-1",
+                Some(snapshot(2, false, &["111122223333", "444455556666"])),
+            ),
+            ("snap-2", Some(snapshot(2, false, &["111122223333"]))),
+            ("snap-3", None),
+        ];
+        assert_eq!(
+            statuses(&image, &expected, actual),
+            vec![
+                (
+                    Some("snap-1".to_string()),
+                    SnapshotValidationResultStatus::Correct
+                ),
+                (
+                    Some("snap-2".to_string()),
+                    SnapshotValidationResultStatus::Incorrect
+                ),
+                (
+                    Some("snap-3".to_string()),
+                    SnapshotValidationResultStatus::Missing
+                ),
+            ]
+        );
+
+        let encrypted = ExpectedSnapshots {
+            kms_key_id: Some("arn:aws:kms:us-west-2:111122223333:key/abc".to_string()),
+            sizes_gib: None,
+        };
+        let actual = vec![(
+            "snap-1",
+            Some(snapshot(2, false, &["111122223333", "444455556666"])),
+        )];
+        assert_eq!(
+            statuses(&image, &encrypted, actual),
+            vec![(
+                Some("snap-1".to_string()),
+                SnapshotValidationResultStatus::Incorrect
+            )]
+        );
+    }
+
+    #[test]
+    fn missing_image() {
+        let results = validate_snapshots_in_region(
+            &image(true, &[]),
+            &ExpectedSnapshots::default(),
+            &Ok(None),
+            &Region::new("us-west-2"),
+        );
+        let result = results.into_iter().next().unwrap();
+        assert_eq!(result.id, None);
+        assert_eq!(result.image_id, "ami-1");
+        assert_eq!(result.status, SnapshotValidationResultStatus::Missing);
+    }
+}
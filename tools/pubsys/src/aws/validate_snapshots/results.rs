@@ -0,0 +1,295 @@
+//! The results module owns the reporting of EBS snapshot validation results.
+
+use super::{Result, SnapshotDef};
+use aws_sdk_ec2::Region;
+use serde::{Deserialize, Serialize};
+use serde_plain::{derive_display_from_serialize, derive_fromstr_from_deserialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use tabled::{Table, Tabled};
+
+/// Represent the possible status of an EBS snapshot validation
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum SnapshotValidationResultStatus {
+    /// The snapshot was found and its monitored fields have the expected values
+    Correct,
+
+    /// The snapshot was found but some of the monitored fields do not have the expected values
+    Incorrect,
+
+    /// The snapshot, or the image it backs, was expected but not found
+    Missing,
+
+    /// The region containing the snapshot was not reachable
+    Unreachable,
+}
+
+derive_display_from_serialize!(SnapshotValidationResultStatus);
+derive_fromstr_from_deserialize!(SnapshotValidationResultStatus);
+
+impl SnapshotValidationResultStatus {
+    /// The names of the statuses, as given to `--write-results-filter`
+    pub(crate) const NAMES: &'static [&'static str] =
+        &["Correct", "Incorrect", "Missing", "Unreachable"];
+}
+
+/// Represents a single EBS snapshot validation result
+#[derive(Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct SnapshotValidationResult {
+    /// The ID of the snapshot, if the image it backs was found
+    pub id: Option<String>,
+
+    /// The ID of the image the snapshot backs
+    pub image_id: String,
+
+    /// `SnapshotDef` containing expected values for the snapshot
+    pub expected_snapshot_def: SnapshotDef,
+
+    /// `SnapshotDef` containing actual values for the snapshot
+    pub actual_snapshot_def: Option<SnapshotDef>,
+
+    /// The region the snapshot resides in
+    #[serde(serialize_with = "serialize_region")]
+    pub region: Region,
+
+    /// The validation status of the snapshot
+    pub status: SnapshotValidationResultStatus,
+}
+
+fn serialize_region<S>(region: &Region, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(region.to_string().as_str())
+}
+
+impl SnapshotValidationResult {
+    pub(crate) fn new(
+        id: Option<String>,
+        image_id: String,
+        expected_snapshot_def: SnapshotDef,
+        actual_snapshot_def: Result<Option<SnapshotDef>>,
+        region: Region,
+    ) -> Self {
+        // Determine the validation status based on equality, presence, and absence of expected and
+        // actual snapshot values
+        let status = match (&expected_snapshot_def, &actual_snapshot_def) {
+            (expected_snapshot_def, Ok(Some(actual_snapshot_def)))
+                if actual_snapshot_def == expected_snapshot_def =>
+            {
+                SnapshotValidationResultStatus::Correct
+            }
+            (_, Ok(Some(_))) => SnapshotValidationResultStatus::Incorrect,
+            (_, Ok(None)) => SnapshotValidationResultStatus::Missing,
+            (_, Err(_)) => SnapshotValidationResultStatus::Unreachable,
+        };
+        SnapshotValidationResult {
+            id,
+            image_id,
+            expected_snapshot_def,
+            actual_snapshot_def: actual_snapshot_def.unwrap_or_default(),
+            region,
+            status,
+        }
+    }
+}
+
+#[derive(Tabled, Serialize)]
+struct SnapshotValidationRegionSummary {
+    correct: u64,
+    incorrect: u64,
+    missing: u64,
+    unreachable: u64,
+}
+
+impl From<&HashSet<SnapshotValidationResult>> for SnapshotValidationRegionSummary {
+    fn from(results: &HashSet<SnapshotValidationResult>) -> Self {
+        let mut region_validation = SnapshotValidationRegionSummary {
+            correct: 0,
+            incorrect: 0,
+            missing: 0,
+            unreachable: 0,
+        };
+        for validation_result in results {
+            match validation_result.status {
+                SnapshotValidationResultStatus::Correct => region_validation.correct += 1,
+                SnapshotValidationResultStatus::Incorrect => region_validation.incorrect += 1,
+                SnapshotValidationResultStatus::Missing => region_validation.missing += 1,
+                SnapshotValidationResultStatus::Unreachable => region_validation.unreachable += 1,
+            }
+        }
+        region_validation
+    }
+}
+
+/// Represents all EBS snapshot validation results
+#[derive(Debug)]
+pub struct SnapshotValidationResults {
+    pub results: HashMap<Region, HashSet<SnapshotValidationResult>>,
+}
+
+impl Default for SnapshotValidationResults {
+    fn default() -> Self {
+        Self::from_result_map(HashMap::new())
+    }
+}
+
+impl Display for SnapshotValidationResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Create a summary for each region, counting the number of snapshots per status
+        let region_validations: HashMap<Region, SnapshotValidationRegionSummary> =
+            self.get_results_summary();
+
+        // Represent the `HashMap` of summaries as a `Table`
+        let table = Table::new(
+            region_validations
+                .iter()
+                .map(|(region, results)| (region.to_string(), results))
+                .collect::<Vec<(String, &SnapshotValidationRegionSummary)>>(),
+        )
+        .to_string();
+        write!(f, "{}", table)
+    }
+}
+
+impl SnapshotValidationResults {
+    pub fn from_result_map(results: HashMap<Region, HashSet<SnapshotValidationResult>>) -> Self {
+        SnapshotValidationResults { results }
+    }
+
+    /// Returns a `HashSet` containing all validation results whose status is present in `requested_status`
+    pub fn get_results_for_status(
+        &self,
+        requested_status: &[SnapshotValidationResultStatus],
+    ) -> HashSet<&SnapshotValidationResult> {
+        let mut results = HashSet::new();
+        for region_results in self.results.values() {
+            results.extend(
+                region_results
+                    .iter()
+                    .filter(|result| requested_status.contains(&result.status))
+                    .collect::<HashSet<&SnapshotValidationResult>>(),
+            )
+        }
+        results
+    }
+
+    /// Returns a `HashSet` containing all validation results
+    pub fn get_all_results(&self) -> HashSet<&SnapshotValidationResult> {
+        let mut results = HashSet::new();
+        for region_results in self.results.values() {
+            results.extend(region_results)
+        }
+        results
+    }
+
+    fn get_results_summary(&self) -> HashMap<Region, SnapshotValidationRegionSummary> {
+        self.results
+            .iter()
+            .map(|(region, region_result)| {
+                (
+                    region.clone(),
+                    SnapshotValidationRegionSummary::from(region_result),
+                )
+            })
+            .collect()
+    }
+
+    pub fn get_json_summary(&self) -> serde_json::Value {
+        serde_json::json!(self
+            .get_results_summary()
+            .into_iter()
+            .map(|(region, results)| (region.to_string(), results))
+            .collect::<HashMap<String, SnapshotValidationRegionSummary>>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        SnapshotValidationResult, SnapshotValidationResultStatus, SnapshotValidationResults,
+    };
+    use crate::aws::validate_snapshots::{error, SnapshotDef};
+    use aws_sdk_ec2::Region;
+    use std::collections::{HashMap, HashSet};
+
+    fn snapshot_def(encrypted: bool) -> SnapshotDef {
+        SnapshotDef {
+            encrypted,
+            kms_key_id: None,
+            size_gib: Some(2),
+            public: true,
+            user_ids: None,
+        }
+    }
+
+    fn result(
+        id: &str,
+        actual: super::Result<Option<SnapshotDef>>,
+        region: &str,
+    ) -> SnapshotValidationResult {
+        SnapshotValidationResult::new(
+            Some(id.to_string()),
+            "ami-1".to_string(),
+            snapshot_def(false),
+            actual,
+            Region::new(region.to_string()),
+        )
+    }
+
+    // Tests that each status is filtered and counted on its own
+    #[test]
+    fn get_results_for_status() {
+        let results = SnapshotValidationResults::from_result_map(HashMap::from([
+            (
+                Region::new("us-west-2"),
+                HashSet::from([
+                    result("snap-1", Ok(Some(snapshot_def(false))), "us-west-2"),
+                    result("snap-2", Ok(Some(snapshot_def(true))), "us-west-2"),
+                    result("snap-3", Ok(None), "us-west-2"),
+                ]),
+            ),
+            (
+                Region::new("us-east-1"),
+                HashSet::from([result(
+                    "snap-4",
+                    Err(error::Error::UnreachableRegion {
+                        region: "us-east-1".to_string(),
+                    }),
+                    "us-east-1",
+                )]),
+            ),
+        ]));
+
+        let ids = |statuses: &[SnapshotValidationResultStatus]| {
+            let mut ids: Vec<String> = results
+                .get_results_for_status(statuses)
+                .into_iter()
+                .filter_map(|result| result.id.clone())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&[SnapshotValidationResultStatus::Correct]), ["snap-1"]);
+        assert_eq!(
+            ids(&[
+                SnapshotValidationResultStatus::Incorrect,
+                SnapshotValidationResultStatus::Missing
+            ]),
+            ["snap-2", "snap-3"]
+        );
+        assert_eq!(
+            ids(&[SnapshotValidationResultStatus::Unreachable]),
+            ["snap-4"]
+        );
+        assert_eq!(results.get_all_results().len(), 4);
+
+        assert_eq!(
+            results.get_json_summary(),
+            serde_json::json!({
+                "us-west-2": {"correct": 1, "incorrect": 1, "missing": 1, "unreachable": 0},
+                "us-east-1": {"correct": 0, "incorrect": 0, "missing": 0, "unreachable": 1},
+            })
+        );
+    }
+}
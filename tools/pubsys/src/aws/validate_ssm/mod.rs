@@ -4,9 +4,13 @@
 pub mod results;
 
 use self::results::{SsmValidationResult, SsmValidationResultStatus, SsmValidationResults};
-use super::ssm::ssm::get_parameters_by_prefix;
+use super::ssm::ssm::get_parameters_by_prefixes;
 use super::ssm::{SsmKey, SsmParameters};
-use crate::aws::client::build_client_config;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::RateLimits;
+use crate::exit_code::{Classify, Kind};
+use crate::progress::progress_bar;
 use crate::Args;
 use aws_sdk_ssm::{Client as SsmClient, Region};
 use log::{error, info, trace};
@@ -35,70 +39,115 @@ pub struct ValidateSsmArgs {
     write_results_path: Option<PathBuf>,
 
     /// Optional filter to only write validation results with these statuses to the above path
-    /// Available statuses are: `Correct`, `Incorrect`, `Missing`, `Unexpected`
-    #[structopt(long, requires = "write-results-path")]
+    /// Available statuses are: `Correct`, `Incorrect`, `Missing`, `Unexpected`, `Unreachable`
+    #[structopt(
+        long,
+        requires = "write-results-path",
+        possible_values = SsmValidationResultStatus::NAMES
+    )]
     write_results_filter: Option<Vec<SsmValidationResultStatus>>,
 
     /// If this flag is added, print the results summary table as JSON instead of a
     /// plaintext table
     #[structopt(long)]
     json: bool,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// What `validate` checks, and where it writes the results
+#[derive(Debug, Clone, Default)]
+pub struct ValidateSsmOptions {
+    /// File holding the expected parameters, in the format `validate-ssm --write-results-path`
+    /// and `promote-ssm` use
+    pub expected_parameters_path: PathBuf,
+    /// Also report parameters in the validation regions that aren't expected
+    pub check_unexpected: bool,
+    /// Where to write the validation results as JSON, if anywhere
+    pub write_results_path: Option<PathBuf>,
+    /// Only write results with these statuses; all results are written if this is None
+    pub write_results_filter: Option<Vec<SsmValidationResultStatus>>,
+    /// Don't display progress bars
+    pub no_progress: bool,
+}
+
+impl From<&ValidateSsmArgs> for ValidateSsmOptions {
+    fn from(args: &ValidateSsmArgs) -> Self {
+        Self {
+            expected_parameters_path: args.expected_parameters_path.clone(),
+            check_unexpected: args.check_unexpected,
+            write_results_path: args.write_results_path.clone(),
+            write_results_filter: args.write_results_filter.clone(),
+            no_progress: args.no_progress,
+        }
+    }
 }
 
 /// Performs SSM parameter validation and returns the `SsmValidationResults` object
 pub async fn validate(
-    args: &Args,
-    validate_ssm_args: &ValidateSsmArgs,
+    infra_config: &InfraConfig,
+    options: &ValidateSsmOptions,
 ) -> Result<SsmValidationResults> {
-    info!("Parsing Infra.toml file");
-
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
-
     let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
 
     trace!("Parsed infra config: {:#?}", infra_config);
 
-    let ssm_prefix = aws.ssm_prefix.as_deref().unwrap_or("");
+    // The expected parameters can be for any variant and architecture, so look under all of the
+    // configured prefixes.
+    let ssm_prefixes = aws.all_ssm_prefixes();
 
     // Parse the file holding expected parameters
     info!("Parsing expected parameters file");
-    let expected_parameters = parse_parameters(&validate_ssm_args.expected_parameters_path).await?;
+    let expected_parameters = parse_parameters(&options.expected_parameters_path).await?;
 
     info!("Parsed expected parameters file");
 
     // Create a HashMap of SsmClients, one for each region where validation should happen
-    let base_region = Region::new(aws.regions[0].clone());
+    let base_region = Region::new(aws.regions_for("validate_ssm")[0].clone());
     let mut ssm_clients = HashMap::with_capacity(expected_parameters.len());
 
     for region in expected_parameters.keys() {
         let client_config = build_client_config(region, &base_region, &aws).await;
-        let ssm_client = SsmClient::new(&client_config);
+        let ssm_client = SsmClient::from_pubsys_config(&client_config, &aws);
         ssm_clients.insert(region.clone(), ssm_client);
     }
 
     // Retrieve the SSM parameters using the SsmClients
     info!("Retrieving SSM parameters");
-    let parameters = get_parameters_by_prefix(&ssm_clients, ssm_prefix)
-        .await
-        .into_iter()
-        .map(|(region, result)| {
-            (
-                region,
-                result.map_err(|e| {
-                    error!(
-                        "Failed to retrieve images in region {}: {}",
-                        region.to_string(),
-                        e
-                    );
-                    error::Error::UnreachableRegion {
-                        region: region.to_string(),
-                    }
-                }),
-            )
-        })
-        .collect::<HashMap<&Region, Result<_>>>();
+    // Each region is checked once per prefix.
+    let progress_bar = progress_bar(
+        options.no_progress,
+        ssm_clients.len() * ssm_prefixes.len(),
+        "Retrieving parameters",
+    );
+    let parameters = get_parameters_by_prefixes(
+        &ssm_clients,
+        &rate_limits,
+        page_size::get(&aws, |page_size| page_size.get_parameters_by_path),
+        ssm_prefixes,
+        &progress_bar,
+    )
+    .await
+    .into_iter()
+    .map(|(region, result)| {
+        (
+            region,
+            result.map_err(|e| {
+                error!(
+                    "Failed to retrieve images in region {}: {}",
+                    region.to_string(),
+                    e
+                );
+                error::Error::UnreachableRegion {
+                    region: region.to_string(),
+                }
+            }),
+        )
+    })
+    .collect::<HashMap<&Region, Result<_>>>();
 
     // Validate the retrieved SSM parameters per region
     info!("Validating SSM parameters");
@@ -110,7 +159,7 @@ pub async fn validate(
                 validate_parameters_in_region(
                     expected_parameters.get(region).unwrap_or(&HashMap::new()),
                     &region_result,
-                    validate_ssm_args.check_unexpected,
+                    options.check_unexpected,
                 ),
             )
         })
@@ -119,10 +168,10 @@ pub async fn validate(
     let validation_results = SsmValidationResults::new(results);
 
     // If a path was given to write the results to, write the results
-    if let Some(write_results_path) = &validate_ssm_args.write_results_path {
+    if let Some(write_results_path) = &options.write_results_path {
         // Filter the results by given status, and if no statuses were given, get all results
         info!("Writing results to file");
-        let results = if let Some(filter) = &validate_ssm_args.write_results_filter {
+        let results = if let Some(filter) = &options.write_results_filter {
             validation_results.get_results_for_status(filter)
         } else {
             validation_results.get_all_results()
@@ -241,8 +290,11 @@ pub(crate) async fn parse_parameters(
 }
 
 /// Common entrypoint from main()
-pub(crate) async fn run(args: &Args, validate_ssm_args: &ValidateSsmArgs) -> Result<()> {
-    let results = validate(args, validate_ssm_args).await?;
+pub async fn run(args: &Args, validate_ssm_args: &ValidateSsmArgs) -> Result<()> {
+    info!("Parsing Infra.toml file");
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let results = validate(&infra_config, &ValidateSsmOptions::from(validate_ssm_args)).await?;
 
     if validate_ssm_args.json {
         println!(
@@ -305,9 +357,18 @@ pub(crate) mod error {
     }
 }
 
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
 
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::aws::{
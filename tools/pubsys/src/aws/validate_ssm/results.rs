@@ -9,7 +9,7 @@ use std::fmt::{self, Display};
 use tabled::{Table, Tabled};
 
 /// Represent the possible status of an SSM validation
-#[derive(Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum SsmValidationResultStatus {
     /// The expected value was equal to the actual value
     Correct,
@@ -30,24 +30,35 @@ pub enum SsmValidationResultStatus {
 derive_display_from_serialize!(SsmValidationResultStatus);
 derive_fromstr_from_deserialize!(SsmValidationResultStatus);
 
+impl SsmValidationResultStatus {
+    /// The names of the statuses, as given to `--write-results-filter`
+    pub(crate) const NAMES: &'static [&'static str] = &[
+        "Correct",
+        "Incorrect",
+        "Missing",
+        "Unexpected",
+        "Unreachable",
+    ];
+}
+
 /// Represents a single SSM validation result
 #[derive(Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct SsmValidationResult {
     /// The name of the parameter
-    pub(crate) name: String,
+    pub name: String,
 
     /// The expected value of the parameter
-    pub(crate) expected_value: Option<String>,
+    pub expected_value: Option<String>,
 
     /// The actual retrieved value of the parameter
-    pub(crate) actual_value: Option<String>,
+    pub actual_value: Option<String>,
 
     /// The region the parameter resides in
     #[serde(serialize_with = "serialize_region")]
-    pub(crate) region: Region,
+    pub region: Region,
 
     /// The validation status of the parameter
-    pub(crate) status: SsmValidationResultStatus,
+    pub status: SsmValidationResultStatus,
 }
 
 fn serialize_region<S>(region: &Region, serializer: S) -> std::result::Result<S::Ok, S::Error>
@@ -119,7 +130,7 @@ impl From<&HashSet<SsmValidationResult>> for SsmValidationRegionSummary {
 /// Represents all SSM validation results
 #[derive(Debug)]
 pub struct SsmValidationResults {
-    pub(crate) results: HashMap<Region, HashSet<SsmValidationResult>>,
+    pub results: HashMap<Region, HashSet<SsmValidationResult>>,
 }
 
 impl Default for SsmValidationResults {
@@ -170,7 +181,7 @@ impl SsmValidationResults {
     }
 
     /// Returns a `HashSet` containing all validation results
-    pub(crate) fn get_all_results(&self) -> HashSet<&SsmValidationResult> {
+    pub fn get_all_results(&self) -> HashSet<&SsmValidationResult> {
         let mut results = HashSet::new();
         for region_results in self.results.values() {
             results.extend(region_results)
@@ -190,7 +201,7 @@ impl SsmValidationResults {
             .collect()
     }
 
-    pub(crate) fn get_json_summary(&self) -> serde_json::Value {
+    pub fn get_json_summary(&self) -> serde_json::Value {
         serde_json::json!(self
             .get_results_summary()
             .into_iter()
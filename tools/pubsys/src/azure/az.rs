@@ -0,0 +1,234 @@
+//! The az module handles the process of building and executing calls to the Azure CLI, `az`, which
+//! uses the credentials from `az login` or the usual `AZURE_*` environment variables.
+use duct::cmd;
+use log::trace;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use snafu::{ensure, ResultExt};
+use std::path::Path;
+
+/// The parts of an image version we record
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImageVersion {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+/// The image definition an image version belongs to, created if it doesn't exist
+pub(crate) struct ImageDefinition<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) publisher: &'a str,
+    pub(crate) offer: &'a str,
+    pub(crate) sku: &'a str,
+    pub(crate) architecture: &'a str,
+}
+
+pub(crate) struct Az {
+    subscription: Option<String>,
+}
+
+impl Az {
+    const AZ: &'static str = "az";
+
+    /// Make a new instance of `Az` that uses the given subscription, or the CLI's default.
+    pub(crate) fn new(subscription: Option<String>) -> Self {
+        Self { subscription }
+    }
+
+    /// Uploads the VHD at the given path to a page blob, which is what images are created from.
+    pub(crate) fn upload_vhd<P>(
+        &self,
+        storage_account: &str,
+        container: &str,
+        blob_name: &str,
+        vhd_path: P,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let vhd_path = vhd_path.as_ref().display().to_string();
+        self.run(&[
+            "storage",
+            "blob",
+            "upload",
+            "--auth-mode",
+            "login",
+            "--account-name",
+            storage_account,
+            "--container-name",
+            container,
+            "--name",
+            blob_name,
+            "--file",
+            &vhd_path,
+            "--type",
+            "page",
+            "--no-progress",
+        ])
+        .map(drop)
+    }
+
+    /// Returns the resource ID of the given storage account, which image versions refer to.
+    pub(crate) fn storage_account_id(
+        &self,
+        resource_group: &str,
+        storage_account: &str,
+    ) -> Result<String> {
+        #[derive(Deserialize)]
+        struct StorageAccount {
+            id: String,
+        }
+        let account: StorageAccount = self.run_json(&[
+            "storage",
+            "account",
+            "show",
+            "--resource-group",
+            resource_group,
+            "--name",
+            storage_account,
+        ])?;
+        Ok(account.id)
+    }
+
+    /// Creates the given image definition in the gallery, unless it already exists.
+    pub(crate) fn ensure_image_definition(
+        &self,
+        resource_group: &str,
+        gallery: &str,
+        definition: &ImageDefinition<'_>,
+    ) -> Result<()> {
+        let common = [
+            "--resource-group",
+            resource_group,
+            "--gallery-name",
+            gallery,
+            "--gallery-image-definition",
+            definition.name,
+        ];
+        let mut show = vec!["sig", "image-definition", "show"];
+        show.extend(common);
+        match self.run(&show) {
+            Ok(_) => return Ok(()),
+            Err(Error::Az { ref output, .. }) if output.contains("ResourceNotFound") => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut create = vec!["sig", "image-definition", "create"];
+        create.extend(common);
+        create.extend([
+            "--publisher",
+            definition.publisher,
+            "--offer",
+            definition.offer,
+            "--sku",
+            definition.sku,
+            "--architecture",
+            definition.architecture,
+            "--os-type",
+            "Linux",
+            "--os-state",
+            "Generalized",
+            "--hyper-v-generation",
+            "V2",
+        ]);
+        self.run(&create).map(drop)
+    }
+
+    /// Creates an image version from the given VHD blob, replicated to the given regions, and
+    /// waits for it to finish.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create_image_version(
+        &self,
+        resource_group: &str,
+        gallery: &str,
+        definition: &str,
+        version: &str,
+        vhd_uri: &str,
+        storage_account_id: &str,
+        regions: &[String],
+    ) -> Result<ImageVersion> {
+        let mut args = vec![
+            "sig",
+            "image-version",
+            "create",
+            "--resource-group",
+            resource_group,
+            "--gallery-name",
+            gallery,
+            "--gallery-image-definition",
+            definition,
+            "--gallery-image-version",
+            version,
+            "--os-vhd-uri",
+            vhd_uri,
+            "--os-vhd-storage-account",
+            storage_account_id,
+            "--location",
+            &regions[0],
+            "--target-regions",
+        ];
+        args.extend(regions.iter().map(String::as_str));
+        self.run_json(&args)
+    }
+
+    /// Runs `az` with the given arguments, parsing its output as JSON.
+    fn run_json<T>(&self, args: &[&str]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let output = self.run(args)?;
+        serde_json::from_str(&output).context(error::ParseOutputSnafu {
+            command: args.join(" "),
+        })
+    }
+
+    /// Runs `az` with the given arguments and returns its output.
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let mut az_args = args.to_vec();
+        az_args.extend(["--output", "json", "--only-show-errors"]);
+        if let Some(subscription) = &self.subscription {
+            az_args.extend(["--subscription", subscription.as_str()]);
+        }
+        trace!("Running {} {}", Self::AZ, az_args.join(" "));
+
+        let output = cmd(Self::AZ, az_args)
+            .stderr_to_stdout()
+            .stdout_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        trace!("{}", stdout);
+        ensure!(
+            output.status.success(),
+            error::AzSnafu {
+                command: args.join(" "),
+                output: stdout,
+            }
+        );
+        Ok(stdout)
+    }
+}
+
+pub(crate) mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("'az {}' failed: {}", command, output))]
+        Az { command: String, output: String },
+
+        #[snafu(display("Failed to start command: {}", source))]
+        CommandStart { source: std::io::Error },
+
+        #[snafu(display("Failed to parse output of 'az {}': {}", command, source))]
+        ParseOutput {
+            command: String,
+            source: serde_json::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
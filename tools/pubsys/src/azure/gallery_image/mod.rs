@@ -0,0 +1,250 @@
+//! The gallery_image module owns the 'gallery-image' subcommand, which creates a version of a
+//! Bottlerocket image in the Azure Compute Gallery from a VHD uploaded by 'upload-vhd', replicated
+//! to the configured regions.  Like `pubsys ami` does with amis.json, it can write the resulting
+//! image to a JSON file for later steps.
+//!
+//! Each variant and architecture gets its own image definition in the gallery, created on first
+//! use; each Bottlerocket version is an image version in it.
+use crate::azure::az::{Az, ImageDefinition};
+use crate::azure::upload_vhd::blob_url;
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use log::{info, trace};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// The offer recorded on image definitions created in the gallery
+const OFFER: &str = "bottlerocket";
+
+/// Creates an Azure Compute Gallery image version from an uploaded VHD
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct GalleryImageArgs {
+    /// The name of the uploaded VHD blob
+    #[structopt(long)]
+    blob_name: String,
+
+    /// The variant of the image
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture of the image
+    #[structopt(long)]
+    arch: String,
+
+    /// The version of the image; image versions in a gallery must be like "1.13.0"
+    #[structopt(long)]
+    version: String,
+
+    /// Comma-separated list of regions to replicate to, overriding Infra.toml; the first is the
+    /// gallery's region
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// If specified, save the created image in JSON format to this file, like amis.json
+    #[structopt(long, parse(from_os_str))]
+    image_output: Option<PathBuf>,
+}
+
+/// What we record about a created image in each region it's replicated to
+#[derive(Debug, Serialize)]
+pub(crate) struct Image {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+/// Returns the architecture name image definitions use for the given architecture.
+fn gallery_arch(arch: &str) -> Result<&'static str> {
+    match arch {
+        "x86_64" | "amd64" => Ok("x64"),
+        "aarch64" | "arm64" => Ok("Arm64"),
+        _ => error::UnknownArchSnafu { arch }.fail(),
+    }
+}
+
+/// Returns the image version for the given Bottlerocket version, dropping any build suffix.
+fn gallery_version(version: &str) -> Result<String> {
+    let version = crate::friendly_version(version).context(error::ParseVersionSnafu { version })?;
+    Ok(format!(
+        "{}.{}.{}",
+        version.major, version.minor, version.patch
+    ))
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, image_args: &GalleryImageArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let azure = infra_config
+        .azure
+        .context(error::MissingConfigSnafu { missing: "azure" })?;
+    let required = |value: &Option<String>, missing: &str| {
+        value.clone().context(error::MissingConfigSnafu { missing })
+    };
+    let resource_group = required(&azure.resource_group, "azure.resource_group")?;
+    let storage_account = required(&azure.storage_account, "azure.storage_account")?;
+    let container = required(&azure.storage_container, "azure.storage_container")?;
+    let gallery = required(&azure.gallery, "azure.gallery")?;
+    let publisher = required(&azure.publisher, "azure.publisher")?;
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    let regions = if !image_args.regions.is_empty() {
+        image_args.regions.clone()
+    } else {
+        azure.regions.clone()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "azure.regions"
+        }
+    );
+
+    let az = Az::new(azure.subscription.clone());
+    let definition_name = format!("bottlerocket-{}-{}", image_args.variant, image_args.arch);
+    let sku = format!("{}-{}", image_args.variant, image_args.arch);
+    let definition = ImageDefinition {
+        name: &definition_name,
+        publisher: &publisher,
+        offer: OFFER,
+        sku: &sku,
+        architecture: gallery_arch(&image_args.arch)?,
+    };
+    info!("Ensuring image definition {} exists", definition_name);
+    az.ensure_image_definition(&resource_group, &gallery, &definition)
+        .context(error::ImageDefinitionSnafu {
+            definition: &definition_name,
+        })?;
+
+    let version = gallery_version(&image_args.version)?;
+    let storage_account_id = az
+        .storage_account_id(&resource_group, &storage_account)
+        .context(error::StorageAccountSnafu {
+            storage_account: &storage_account,
+        })?;
+    info!(
+        "Creating version {} of {}, replicated to {}",
+        version,
+        definition_name,
+        regions.join(", ")
+    );
+    let image_version = az
+        .create_image_version(
+            &resource_group,
+            &gallery,
+            &definition_name,
+            &version,
+            &blob_url(&storage_account, &container, &image_args.blob_name),
+            &storage_account_id,
+            &regions,
+        )
+        .context(error::ImageVersionSnafu {
+            definition: &definition_name,
+            version: &version,
+        })?;
+    info!("Created image version {}", image_version.id);
+
+    if let Some(image_output) = &image_args.image_output {
+        // A gallery image version has one ID across the regions it's replicated to.
+        let images: HashMap<&String, Image> = regions
+            .iter()
+            .map(|region| {
+                (
+                    region,
+                    Image {
+                        id: image_version.id.clone(),
+                        name: format!("{}/{}", definition_name, image_version.name),
+                    },
+                )
+            })
+            .collect();
+        let file = File::create(image_output).context(error::FileSnafu { path: image_output })?;
+        serde_json::to_writer_pretty(file, &images).context(error::SerializeSnafu)?;
+        info!("Wrote image data to {}", image_output.display());
+    }
+
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to create '{}': {}", path.display(), source))]
+        File {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to create image definition {}: {}", definition, source))]
+        ImageDefinition {
+            definition: String,
+            source: crate::azure::az::Error,
+        },
+
+        #[snafu(display("Failed to create version {} of {}: {}", version, definition, source))]
+        ImageVersion {
+            definition: String,
+            version: String,
+            source: crate::azure::az::Error,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to parse version '{}': {}", version, source))]
+        ParseVersion {
+            version: String,
+            source: semver::Error,
+        },
+
+        #[snafu(display("Failed to serialize image data: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to find storage account {}: {}", storage_account, source))]
+        StorageAccount {
+            storage_account: String,
+            source: crate::azure::az::Error,
+        },
+
+        #[snafu(display("Unknown architecture '{}'", arch))]
+        UnknownArch { arch: String },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gallery_arch, gallery_version};
+
+    #[test]
+    fn gallery_names() {
+        assert_eq!(gallery_arch("x86_64").unwrap(), "x64");
+        assert_eq!(gallery_arch("aarch64").unwrap(), "Arm64");
+        assert!(gallery_arch("riscv64").is_err());
+
+        assert_eq!(gallery_version("v1.13.0-abcd1234").unwrap(), "1.13.0");
+        assert!(gallery_version("latest").is_err());
+    }
+}
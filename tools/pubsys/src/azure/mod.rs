@@ -0,0 +1,3 @@
+pub(crate) mod az;
+pub(crate) mod gallery_image;
+pub(crate) mod upload_vhd;
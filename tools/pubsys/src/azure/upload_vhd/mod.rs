@@ -0,0 +1,114 @@
+//! The upload_vhd module owns the 'upload-vhd' subcommand, which uploads a Bottlerocket VHD to the
+//! storage account in Infra.toml, so that the 'gallery-image' subcommand can create an image from
+//! it.
+use crate::azure::az::Az;
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use log::{info, trace};
+use snafu::{OptionExt, ResultExt};
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// Uploads a Bottlerocket VHD to an Azure storage account
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct UploadVhdArgs {
+    /// Path to the VHD image
+    #[structopt(long, parse(from_os_str))]
+    vhd: PathBuf,
+
+    /// The name of the blob to upload to; defaults to the VHD's file name
+    #[structopt(long)]
+    blob_name: Option<String>,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, upload_args: &UploadVhdArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let azure = infra_config
+        .azure
+        .context(error::MissingConfigSnafu { missing: "azure" })?;
+    let storage_account = azure
+        .storage_account
+        .as_deref()
+        .context(error::MissingConfigSnafu {
+            missing: "azure.storage_account",
+        })?;
+    let container = azure
+        .storage_container
+        .as_deref()
+        .context(error::MissingConfigSnafu {
+            missing: "azure.storage_container",
+        })?;
+
+    let blob_name = match &upload_args.blob_name {
+        Some(blob_name) => blob_name.clone(),
+        None => upload_args
+            .vhd
+            .file_name()
+            .context(error::NoFileNameSnafu {
+                path: &upload_args.vhd,
+            })?
+            .to_string_lossy()
+            .to_string(),
+    };
+
+    info!(
+        "Uploading {} to {}/{} as {}",
+        upload_args.vhd.display(),
+        storage_account,
+        container,
+        blob_name
+    );
+    Az::new(azure.subscription.clone())
+        .upload_vhd(storage_account, container, &blob_name, &upload_args.vhd)
+        .context(error::UploadVhdSnafu)?;
+    info!(
+        "Uploaded {}",
+        blob_url(storage_account, container, &blob_name)
+    );
+
+    Ok(())
+}
+
+/// Returns the URL of the given blob.
+pub(crate) fn blob_url(storage_account: &str, container: &str, blob_name: &str) -> String {
+    format!(
+        "https://{}.blob.core.windows.net/{}/{}",
+        storage_account, container, blob_name
+    )
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("'{}' has no file name; give --blob-name", path.display()))]
+        NoFileName { path: PathBuf },
+
+        #[snafu(display("Failed to upload VHD: {}", source))]
+        UploadVhd { source: crate::azure::az::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,743 @@
+//! The check_infra module owns the 'check-infra' subcommand, which looks for mistakes in
+//! Infra.toml (or Infra.lock) that would otherwise only be found partway through a long publishing
+//! run: malformed region names, role ARNs, and bucket names, regions and roles from different
+//! partitions, and referenced files that don't exist.
+
+use crate::aws::secrets::parse_secret_arn;
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use log::{error, info, trace};
+use pubsys_config::{AwsConfig, InfraConfig, NotifyConfig, RepoConfig, SigningKeyConfig};
+use snafu::{ensure, ResultExt};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use structopt::{clap, StructOpt};
+
+/// The services whose endpoint URLs can be set in `aws.endpoint_urls`
+const ENDPOINT_SERVICES: &[&str] = &[
+    "cloudfront",
+    "ebs",
+    "ec2",
+    "iam",
+    "kms",
+    "s3",
+    "secretsmanager",
+    "sns",
+    "ssm",
+    "sts",
+];
+
+/// The subcommands that can have their own list in `aws.command_regions`
+const REGION_COMMANDS: &[&str] = &[
+    "ami",
+    "check_parity",
+    "check_permissions",
+    "diff",
+    "eol",
+    "gc",
+    "image_builder",
+    "inventory",
+    "kms",
+    "promote_ami",
+    "promote_ssm",
+    "publish_ami",
+    "report",
+    "rollback_ssm",
+    "ssm",
+    "transfer_ami",
+    "validate_ami",
+    "validate_snapshots",
+    "validate_ssm",
+];
+
+/// S3 objects can have at most this many tags, fewer than other resources
+const MAX_S3_OBJECT_TAGS: usize = 10;
+
+/// Checks Infra.toml for mistakes before running other subcommands
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct CheckInfraArgs {
+    #[structopt(long)]
+    /// Only check this named repo from Infra.toml, rather than all of them
+    repo: Option<String>,
+}
+
+/// The AWS partitions; resources can't be shared or copied between them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Partition {
+    Aws,
+    AwsCn,
+    AwsUsGov,
+    AwsIso,
+    AwsIsoB,
+}
+
+impl Partition {
+    /// Returns the partition containing the given region.
+    fn of_region(region: &str) -> Self {
+        if region.starts_with("cn-") {
+            Partition::AwsCn
+        } else if region.starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else if region.starts_with("us-isob-") {
+            Partition::AwsIsoB
+        } else if region.starts_with("us-iso-") {
+            Partition::AwsIso
+        } else {
+            Partition::Aws
+        }
+    }
+
+    /// Returns the partition with the given name, as used in ARNs.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "aws" => Some(Partition::Aws),
+            "aws-cn" => Some(Partition::AwsCn),
+            "aws-us-gov" => Some(Partition::AwsUsGov),
+            "aws-iso" => Some(Partition::AwsIso),
+            "aws-iso-b" => Some(Partition::AwsIsoB),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Partition::Aws => "aws",
+            Partition::AwsCn => "aws-cn",
+            Partition::AwsUsGov => "aws-us-gov",
+            Partition::AwsIso => "aws-iso",
+            Partition::AwsIsoB => "aws-iso-b",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A mistake in the infra config, and the config value it was found in
+#[derive(Debug)]
+struct Problem {
+    location: String,
+    message: String,
+}
+
+/// Collects the problems found while checking the infra config.
+#[derive(Debug, Default)]
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn add<S1, S2>(&mut self, location: S1, message: S2)
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.problems.push(Problem {
+            location: location.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Checks a region name, and that it's in the expected partition, if one is given.
+    fn region(&mut self, location: &str, region: &str, expected: Option<(Partition, &str)>) {
+        if let Some(message) = region_problem(region) {
+            self.add(location, message);
+            return;
+        }
+        if let Some((partition, source)) = expected {
+            let actual = Partition::of_region(region);
+            if actual != partition {
+                self.add(
+                    location,
+                    format!(
+                        "'{}' is in the {} partition, but {} is in the {} partition; resources \
+                        can't be copied between partitions, so use a separate Infra.toml for each",
+                        region, actual, source, partition
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks a role ARN, and that it's in the expected partition, if one is given.
+    fn role_arn(&mut self, location: &str, arn: &str, expected: Option<(Partition, &str)>) {
+        let partition = match parse_role_arn(arn) {
+            Ok(partition) => partition,
+            Err(message) => {
+                self.add(location, message);
+                return;
+            }
+        };
+        if let Some((expected, source)) = expected {
+            if partition != expected {
+                self.add(
+                    location,
+                    format!(
+                        "'{}' is in the {} partition, but {} is in the {} partition",
+                        arn, partition, source, expected
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that a file referenced by the config exists.
+    fn file(&mut self, location: &str, path: &Path) {
+        if !path.exists() {
+            self.add(
+                location,
+                format!(
+                    "'{}' does not exist; relative paths are relative to the directory pubsys \
+                    runs in",
+                    path.display()
+                ),
+            );
+        }
+    }
+
+    /// Checks the `aws` section of the config.
+    fn aws(&mut self, aws: &AwsConfig) {
+        // AMIs are registered in the first region and copied to the rest, so they all have to be
+        // in its partition.
+        let base = aws.regions.front().map(|region| region.as_str());
+        let base_partition = base.map(Partition::of_region);
+        let base_source = base.map(|region| format!("the first of aws.regions, '{}',", region));
+        let expected = base_partition.zip(base_source.as_deref());
+
+        for (i, region) in aws.regions.iter().enumerate() {
+            self.region(&format!("aws.regions[{}]", i), region, expected);
+        }
+        if let Some(role) = &aws.role {
+            self.role_arn("aws.role", role, expected);
+        }
+        for (region, region_config) in &aws.region {
+            let location = format!("aws.region.{}", region);
+            self.region(&location, region, None);
+            if let Some(role) = &region_config.role {
+                let region_source = format!("region '{}'", region);
+                self.role_arn(
+                    &format!("{}.role", location),
+                    role,
+                    Some((Partition::of_region(region), &region_source)),
+                );
+            }
+        }
+        if let Some(publishing) = &aws.publishing {
+            if let Some(role) = &publishing.role {
+                self.role_arn("aws.publishing.role", role, expected);
+            }
+            for (region, region_config) in &publishing.region {
+                let location = format!("aws.publishing.region.{}", region);
+                self.region(&location, region, None);
+                if let Some(role) = &region_config.role {
+                    let region_source = format!("region '{}'", region);
+                    self.role_arn(
+                        &format!("{}.role", location),
+                        role,
+                        Some((Partition::of_region(region), &region_source)),
+                    );
+                }
+            }
+        }
+        if let Some(page_size) = &aws.page_size {
+            let page_sizes = [
+                ("describe_images", page_size.describe_images, 5, 1000),
+                (
+                    "get_parameters_by_path",
+                    page_size.get_parameters_by_path,
+                    1,
+                    10,
+                ),
+                ("list_objects", page_size.list_objects, 1, 1000),
+            ];
+            for (name, size, min, max) in page_sizes {
+                if let Some(message) = size.and_then(|size| page_size_problem(size.get(), min, max))
+                {
+                    self.add(format!("aws.page_size.{}", name), message);
+                }
+            }
+        }
+        if aws.use_fips_endpoints {
+            for (i, region) in aws.regions.iter().enumerate() {
+                if Partition::of_region(region) == Partition::AwsCn {
+                    self.add(
+                        format!("aws.regions[{}]", i),
+                        format!(
+                            "'{}' has no FIPS endpoints, but aws.use_fips_endpoints is set",
+                            region
+                        ),
+                    );
+                }
+            }
+        }
+        for (name, partition_config) in &aws.partition {
+            let location = format!("aws.partition.{}", name);
+            let partition = match Partition::from_name(name) {
+                Some(partition) => partition,
+                None => {
+                    self.add(location, format!("'{}' is not an AWS partition", name));
+                    continue;
+                }
+            };
+            if let Some(role) = &partition_config.role {
+                self.role_arn(
+                    &format!("{}.role", location),
+                    role,
+                    Some((partition, &location)),
+                );
+            }
+        }
+        for (name, s3_config) in aws.s3.iter().flatten() {
+            let location = format!("aws.s3.{}", name);
+            if let Some(region) = &s3_config.region {
+                self.region(&format!("{}.region", location), region, None);
+            }
+            if let Some(bucket_name) = &s3_config.bucket_name {
+                if let Some(message) = bucket_name_problem(bucket_name) {
+                    self.add(format!("{}.bucket_name", location), message);
+                }
+            }
+        }
+        for (command, regions) in &aws.command_regions {
+            let location = format!("aws.command_regions.{}", command);
+            if !REGION_COMMANDS.contains(&command.as_str()) {
+                self.add(
+                    location.clone(),
+                    format!(
+                        "'{}' is not a subcommand that uses regions; use one of {}",
+                        command,
+                        REGION_COMMANDS.join(", ")
+                    ),
+                );
+            }
+            // Validation can span partitions, so only the region names are checked.
+            for (i, region) in regions.iter().enumerate() {
+                self.region(&format!("{}[{}]", location, i), region, None);
+            }
+        }
+        for key in aws.tags.keys() {
+            if key.to_lowercase().starts_with("aws:") {
+                self.add(
+                    format!("aws.tags.{}", key),
+                    "tag keys starting with 'aws:' are reserved for AWS",
+                );
+            }
+        }
+        if aws.tags.len() > MAX_S3_OBJECT_TAGS {
+            self.add(
+                "aws.tags",
+                format!(
+                    "S3 objects can have at most {} tags, so repo uploads would fail",
+                    MAX_S3_OBJECT_TAGS
+                ),
+            );
+        }
+        for service in aws.endpoint_urls.keys() {
+            if !ENDPOINT_SERVICES.contains(&service.as_str()) {
+                self.add(
+                    format!("aws.endpoint_urls.{}", service),
+                    format!(
+                        "'{}' is not a service pubsys calls; use one of {}",
+                        service,
+                        ENDPOINT_SERVICES.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks the destinations in the `notify` section of the config.
+    fn notify(&mut self, notify: &NotifyConfig) {
+        for (i, sns) in notify.sns.iter().enumerate() {
+            if let Some(message) = topic_arn_problem(&sns.topic_arn) {
+                self.add(format!("notify.sns[{}].topic_arn", i), message);
+            }
+        }
+    }
+
+    /// Checks the named repo from the `repo` section of the config.
+    fn repo(&mut self, name: &str, repo_config: &RepoConfig, aws: Option<&AwsConfig>) {
+        let location = format!("repo.{}", name);
+        if let Some(root_role_url) = &repo_config.root_role_url {
+            if root_role_url.scheme() == "file" {
+                match root_role_url.to_file_path() {
+                    Ok(path) => self.file(&format!("{}.root_role_url", location), &path),
+                    Err(()) => self.add(
+                        format!("{}.root_role_url", location),
+                        format!("'{}' is not a valid file URL", root_role_url),
+                    ),
+                }
+            }
+        }
+        if let Some(signing_keys) = &repo_config.signing_keys {
+            self.signing_key(&format!("{}.signing_keys", location), signing_keys);
+        }
+        for (i, signing_keys) in repo_config
+            .additional_signing_keys
+            .iter()
+            .flatten()
+            .enumerate()
+        {
+            self.signing_key(
+                &format!("{}.additional_signing_keys[{}]", location, i),
+                signing_keys,
+            );
+        }
+        if let Some(root_keys) = &repo_config.root_keys {
+            self.signing_key(&format!("{}.root_keys", location), root_keys);
+        }
+        for (role, delegation) in repo_config.delegations.iter().flatten() {
+            for (i, key_path) in delegation.keys.iter().enumerate() {
+                self.file(
+                    &format!("{}.delegations.{}.keys[{}]", location, role, i),
+                    key_path,
+                );
+            }
+        }
+        if let Some(file_hosting_config_name) = &repo_config.file_hosting_config_name {
+            let has_s3_config = aws
+                .and_then(|aws| aws.s3.as_ref())
+                .map_or(false, |s3| s3.contains_key(file_hosting_config_name));
+            if !has_s3_config {
+                self.add(
+                    format!("{}.file_hosting_config_name", location),
+                    format!(
+                        "there is no [aws.s3.{}] section describing the repo's bucket",
+                        file_hosting_config_name
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks a signing key config, making sure any files it refers to exist.
+    fn signing_key(&mut self, location: &str, signing_key_config: &SigningKeyConfig) {
+        match signing_key_config {
+            SigningKeyConfig::file { path } => self.file(&format!("{}.path", location), path),
+            SigningKeyConfig::kms { key_id, config } => {
+                let has_key = key_id.is_some()
+                    || config
+                        .as_ref()
+                        .and_then(|config| config.single_available_key())
+                        .is_some();
+                if !has_key {
+                    self.add(
+                        location,
+                        "no KMS key_id is given, and available_keys doesn't list exactly one key",
+                    );
+                }
+                for region in config
+                    .iter()
+                    .flat_map(|config| config.available_keys.values())
+                {
+                    self.region(&format!("{}.available_keys", location), region, None);
+                }
+            }
+            SigningKeyConfig::ssm { .. } => {}
+            SigningKeyConfig::secret { arn } => {
+                if let Err(e) = parse_secret_arn(arn) {
+                    self.add(format!("{}.arn", location), e.to_string());
+                }
+            }
+            SigningKeyConfig::pkcs11 {
+                module_path,
+                public_key_path,
+                ..
+            } => {
+                self.file(&format!("{}.module_path", location), module_path);
+                self.file(&format!("{}.public_key_path", location), public_key_path);
+            }
+        }
+    }
+}
+
+/// Returns a description of what's wrong with the given page size, if anything, given the smallest
+/// and largest page sizes the service accepts.
+fn page_size_problem(size: u32, min: u32, max: u32) -> Option<String> {
+    if size < min || size > max {
+        Some(format!(
+            "page size {} is outside the service's limits of {} to {}",
+            size, min, max
+        ))
+    } else {
+        None
+    }
+}
+
+/// Returns a description of what's wrong with the given region name, if anything.  Region names
+/// look like `us-west-2` or `us-gov-east-1`.
+fn region_problem(region: &str) -> Option<String> {
+    let parts: Vec<&str> = region.split('-').collect();
+    let valid = parts.len() >= 3
+        && parts[0].len() == 2
+        && parts[..parts.len() - 1]
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase()))
+        && parts[parts.len() - 1].chars().all(|c| c.is_ascii_digit())
+        && !parts[parts.len() - 1].is_empty();
+    if valid {
+        None
+    } else {
+        Some(format!(
+            "'{}' is not a region name; region names look like 'us-west-2'",
+            region
+        ))
+    }
+}
+
+/// Parses an IAM role ARN like `arn:aws:iam::111122223333:role/name`, returning its partition, or
+/// a description of what's wrong with it.
+fn parse_role_arn(arn: &str) -> std::result::Result<Partition, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not an IAM role ARN; role ARNs look like \
+            'arn:aws:iam::111122223333:role/name'",
+            arn
+        )
+    };
+    let fields: Vec<&str> = arn.splitn(6, ':').collect();
+    if fields.len() != 6 || fields[0] != "arn" || fields[2] != "iam" || !fields[3].is_empty() {
+        return Err(invalid());
+    }
+    let partition = Partition::from_name(fields[1]).ok_or_else(|| {
+        format!(
+            "'{}' is not a known partition in role ARN '{}'",
+            fields[1], arn
+        )
+    })?;
+    if !is_account_id(fields[4]) {
+        return Err(format!(
+            "'{}' is not an account ID in role ARN '{}'; account IDs are 12 digits",
+            fields[4], arn
+        ));
+    }
+    match fields[5].strip_prefix("role/") {
+        Some(name) if !name.is_empty() && !name.ends_with('/') => Ok(partition),
+        _ => Err(invalid()),
+    }
+}
+
+/// Returns a description of what's wrong with the given SNS topic ARN, if anything.
+fn topic_arn_problem(arn: &str) -> Option<String> {
+    let fields: Vec<&str> = arn.splitn(6, ':').collect();
+    let valid = fields.len() == 6
+        && fields[0] == "arn"
+        && Partition::from_name(fields[1]).is_some()
+        && fields[2] == "sns"
+        && region_problem(fields[3]).is_none()
+        && is_account_id(fields[4])
+        && !fields[5].is_empty();
+    if valid {
+        None
+    } else {
+        Some(format!(
+            "'{}' is not an SNS topic ARN; topic ARNs look like \
+            'arn:aws:sns:us-west-2:111122223333:name'",
+            arn
+        ))
+    }
+}
+
+fn is_account_id(account_id: &str) -> bool {
+    account_id.len() == 12 && account_id.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns a description of what's wrong with the given S3 bucket name, if anything, following
+/// the S3 naming rules.
+fn bucket_name_problem(name: &str) -> Option<String> {
+    let message = if name.len() < 3 || name.len() > 63 {
+        "bucket names must be between 3 and 63 characters long"
+    } else if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        "bucket names may only contain lowercase letters, numbers, dots, and hyphens"
+    } else if !name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !name.ends_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        "bucket names must begin and end with a letter or number"
+    } else if name.contains("..") {
+        "bucket names may not contain two adjacent dots"
+    } else if name.parse::<Ipv4Addr>().is_ok() {
+        "bucket names may not be formatted as an IP address"
+    } else {
+        return None;
+    };
+    Some(format!(
+        "'{}' is not a valid bucket name: {}",
+        name, message
+    ))
+}
+
+/// Checks the infra config, returning any problems found.  If a repo name is given, only that
+/// repo is checked.
+fn check_config(infra_config: &InfraConfig, repo: Option<&str>) -> Vec<Problem> {
+    let mut checker = Checker::default();
+    if let Some(aws) = &infra_config.aws {
+        checker.aws(aws);
+    }
+    if let Some(notify) = &infra_config.notify {
+        checker.notify(notify);
+    }
+    let mut repos: Vec<_> = infra_config.repo.iter().flatten().collect();
+    repos.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, repo_config) in repos {
+        if repo.map_or(true, |repo| repo == name) {
+            checker.repo(name, repo_config, infra_config.aws.as_ref());
+        }
+    }
+    checker.problems
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, check_args: &CheckInfraArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+
+    if let Some(repo) = &check_args.repo {
+        let has_repo = infra_config
+            .repo
+            .as_ref()
+            .map_or(false, |repos| repos.contains_key(repo));
+        ensure!(has_repo, error::MissingRepoSnafu { repo });
+    }
+
+    let problems = check_config(&infra_config, check_args.repo.as_deref());
+    if problems.is_empty() {
+        info!("No problems found in infra config");
+        return Ok(());
+    }
+    for problem in &problems {
+        error!("{}: {}", problem.location, problem.message);
+    }
+    error::InvalidSnafu {
+        count: problems.len(),
+    }
+    .fail()
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Found {} problems in infra config, see above", count))]
+        Invalid { count: usize },
+
+        #[snafu(display("Infra.toml has no repo named '{}'", repo))]
+        MissingRepo { repo: String },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingRepo { .. } => Some(Kind::Config),
+            Self::Invalid { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        bucket_name_problem, page_size_problem, parse_role_arn, region_problem, topic_arn_problem,
+        Partition,
+    };
+    use pubsys_config::{partition_for_region, AwsConfig};
+
+    #[test]
+    fn regions() {
+        assert!(region_problem("us-west-2").is_none());
+        assert!(region_problem("us-gov-east-1").is_none());
+        assert!(region_problem("cn-northwest-1").is_none());
+        assert!(region_problem("us-west").is_some());
+        assert!(region_problem("US-WEST-2").is_some());
+        assert!(region_problem("uswest-2").is_some());
+        assert_eq!(Partition::of_region("us-gov-west-1"), Partition::AwsUsGov);
+        assert_eq!(Partition::of_region("cn-north-1"), Partition::AwsCn);
+        assert_eq!(Partition::of_region("eu-west-1"), Partition::Aws);
+    }
+
+    #[test]
+    fn mixed_partitions() {
+        let mut aws: AwsConfig = toml::from_str(
+            r#"
+            regions = ["us-west-2", "us-east-1"]
+            [command_regions]
+            validate_ami = ["us-west-2", "cn-north-1", "us-gov-west-1"]
+            "#,
+        )
+        .unwrap();
+        let message = aws.check_partitions().unwrap_err().to_string();
+        assert!(message.contains("cn-north-1, us-gov-west-1"), "{}", message);
+
+        aws.partition = toml::from_str(
+            r#"
+            aws-cn = { profile = "china" }
+            aws-us-gov = { profile = "govcloud" }
+            "#,
+        )
+        .unwrap();
+        assert!(aws.check_partitions().is_ok());
+        for region in ["us-west-2", "cn-north-1", "us-gov-west-1", "us-isob-east-1"] {
+            assert_eq!(
+                partition_for_region(region),
+                Partition::of_region(region).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn page_sizes() {
+        assert!(page_size_problem(5, 5, 1000).is_none());
+        assert!(page_size_problem(10, 1, 10).is_none());
+        assert!(page_size_problem(4, 5, 1000).is_some());
+        assert!(page_size_problem(50, 1, 10).is_some());
+    }
+
+    #[test]
+    fn role_arns() {
+        assert_eq!(
+            parse_role_arn("arn:aws:iam::111122223333:role/publisher"),
+            Ok(Partition::Aws)
+        );
+        assert_eq!(
+            parse_role_arn("arn:aws-cn:iam::111122223333:role/path/publisher"),
+            Ok(Partition::AwsCn)
+        );
+        assert!(parse_role_arn("arn:aws:iam::11112222333:role/publisher").is_err());
+        assert!(parse_role_arn("arn:aws:iam::111122223333:user/publisher").is_err());
+        assert!(parse_role_arn("arn:aws:sts::111122223333:role/publisher").is_err());
+        assert!(parse_role_arn("arn:aws-mars:iam::111122223333:role/publisher").is_err());
+        assert!(parse_role_arn("publisher").is_err());
+    }
+
+    #[test]
+    fn topic_arns() {
+        assert!(topic_arn_problem("arn:aws:sns:us-west-2:111122223333:publishing").is_none());
+        assert!(topic_arn_problem("arn:aws:sns::111122223333:publishing").is_some());
+        assert!(topic_arn_problem("arn:aws:sqs:us-west-2:111122223333:publishing").is_some());
+    }
+
+    #[test]
+    fn bucket_names() {
+        assert!(bucket_name_problem("my-repo-bucket").is_none());
+        assert!(bucket_name_problem("repo.example.com").is_none());
+        assert!(bucket_name_problem("ab").is_some());
+        assert!(bucket_name_problem("My_Bucket").is_some());
+        assert!(bucket_name_problem("-bucket").is_some());
+        assert!(bucket_name_problem("my..bucket").is_some());
+        assert!(bucket_name_problem("192.168.1.1").is_some());
+    }
+}
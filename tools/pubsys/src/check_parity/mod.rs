@@ -0,0 +1,437 @@
+//! The check_parity module owns the 'check-parity' subcommand, which checks that each variant is
+//! published the same way for x86_64 and aarch64: every SSM parameter of a version in a region has
+//! a counterpart for the other architecture, and the AMIs the parameters refer to still exist.
+//!
+//! Parameters are found the same way the 'gc' subcommand finds them, and matched across
+//! architectures by version, region, and the template they were rendered from.  An AMI parameter
+//! whose AMI is gone counts as missing.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::region_from_string;
+use crate::aws::ssm::{template, BuildContext};
+use crate::exit_code::{Classify, Kind};
+use crate::gc::{self, Parameter, VERSION_MARKER};
+use crate::Args;
+use aws_sdk_ec2::model::{ArchitectureValues, Filter};
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use log::{error, info, trace};
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+
+/// EC2 accepts this many values in a describe-images filter
+const MAX_FILTER_VALUES: usize = 200;
+
+/// Checks that variants are published the same way for each architecture
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct CheckParityArgs {
+    /// Comma-separated list of variants to check
+    #[structopt(long = "variant", required = true, use_delimiter = true)]
+    variants: Vec<String>,
+
+    /// File holding the parameter templates the variants were published with
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Only check this version, rather than every published version and pointer
+    #[structopt(long)]
+    version: Option<String>,
+
+    /// Comma-separated list of regions to check, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Print the gaps as JSON instead of a table
+    #[structopt(long)]
+    json: bool,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// Where a published parameter sits, independent of architecture
+type Slot = (String, String, String);
+
+/// Something published for one architecture but not the other
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Tabled)]
+struct Gap {
+    variant: String,
+    version: String,
+    region: String,
+    template: String,
+    present_for: String,
+    missing_for: String,
+}
+
+/// Returns the slots where the given parameters are published, leaving out AMI parameters whose
+/// AMIs are gone.
+fn published_slots(
+    versions: &BTreeMap<String, Vec<Parameter>>,
+    only_version: Option<&str>,
+    existing_amis: &HashSet<(String, String)>,
+) -> BTreeSet<Slot> {
+    versions
+        .iter()
+        .filter(|(version, _)| only_version.map_or(true, |only| only == version.as_str()))
+        .flat_map(|(version, parameters)| {
+            parameters.iter().filter_map(move |parameter| {
+                let region = parameter.key.region.to_string();
+                if parameter.value.starts_with("ami-")
+                    && !existing_amis.contains(&(region.clone(), parameter.value.clone()))
+                {
+                    error!(
+                        "{} in {} refers to {}, which doesn't exist",
+                        parameter.key.name, region, parameter.value
+                    );
+                    return None;
+                }
+                Some((version.clone(), region, parameter.template.clone()))
+            })
+        })
+        .collect()
+}
+
+/// Lists the slots published for one architecture but not the other.
+fn gaps(variant: &str, slots: &[(&str, BTreeSet<Slot>); 2]) -> Vec<Gap> {
+    let [(first_arch, first), (second_arch, second)] = slots;
+    let one_way = |present: &BTreeSet<Slot>,
+                   missing: &BTreeSet<Slot>,
+                   present_for: &str,
+                   missing_for: &str| {
+        present
+            .difference(missing)
+            .map(|(version, region, template)| Gap {
+                variant: variant.to_string(),
+                version: version.clone(),
+                region: region.clone(),
+                template: template.clone(),
+                present_for: present_for.to_string(),
+                missing_for: missing_for.to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut gaps = one_way(first, second, *first_arch, *second_arch);
+    gaps.extend(one_way(second, first, *second_arch, *first_arch));
+    gaps.sort();
+    gaps
+}
+
+/// Returns which of the given AMIs, by region and ID, exist.
+async fn existing_amis(
+    amis: &BTreeSet<(String, String)>,
+    ec2_clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+) -> Result<HashSet<(String, String)>> {
+    let mut regional_ids: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (region, image_id) in amis {
+        regional_ids
+            .entry(region)
+            .or_default()
+            .push(image_id.clone());
+    }
+
+    let mut existing = HashSet::new();
+    for (region, image_ids) in regional_ids {
+        let region = region_from_string(region);
+        // Asking for missing IDs directly fails the whole request, so we filter by ID instead.
+        for batch in image_ids.chunks(MAX_FILTER_VALUES) {
+            let response = rate_limits
+                .rate_limited(
+                    EC2,
+                    region.as_ref(),
+                    ec2_clients[&region]
+                        .describe_images()
+                        .include_deprecated(true)
+                        .filters(
+                            Filter::builder()
+                                .name("image-id")
+                                .set_values(Some(batch.to_vec()))
+                                .build(),
+                        )
+                        .send(),
+                )
+                .await
+                .context(error::DescribeImagesSnafu {
+                    region: region.as_ref(),
+                })?;
+            existing.extend(
+                response
+                    .images()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|image| image.image_id())
+                    .map(|image_id| (region.to_string(), image_id.to_string())),
+            );
+        }
+    }
+    Ok(existing)
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, parity_args: &CheckParityArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions: Vec<Region> = if !parity_args.regions.is_empty() {
+        parity_args.regions.clone()
+    } else {
+        aws.regions_for("check_parity").clone().into()
+    }
+    .iter()
+    .map(|name| region_from_string(name))
+    .collect();
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, &aws),
+        );
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, &aws),
+        );
+    }
+
+    // The architectures whose publications should match
+    let arches = [ArchitectureValues::X8664, ArchitectureValues::Arm64];
+    let mut all_gaps = Vec::new();
+    for variant in &parity_args.variants {
+        let mut published = Vec::with_capacity(arches.len());
+        for arch in &arches {
+            let build_context = BuildContext {
+                variant,
+                arch: arch.as_ref(),
+                image_version: VERSION_MARKER,
+            };
+            let template_parameters =
+                template::get_parameters(&parity_args.template_path, &build_context)
+                    .context(error::FindTemplatesSnafu)?;
+            let ssm_prefix = aws.ssm_prefix_for(variant, arch.as_ref());
+            let patterns = gc::name_patterns(&template_parameters, ssm_prefix, &build_context)
+                .context(error::FindVersionsSnafu)?;
+            ensure!(!patterns.is_empty(), error::NoVersionedTemplatesSnafu);
+
+            info!(
+                "Finding the published versions of {} for {}",
+                variant,
+                arch.as_ref()
+            );
+            let versions = gc::find_versions(
+                &ssm_clients,
+                &rate_limits,
+                page_size::get(&aws, |page_size| page_size.get_parameters_by_path),
+                &patterns,
+                parity_args.no_progress,
+            )
+            .await
+            .context(error::FindVersionsSnafu)?;
+            published.push((arch.as_ref(), versions));
+        }
+
+        let amis: BTreeSet<(String, String)> = published
+            .iter()
+            .flat_map(|(_, versions)| versions.values().flatten())
+            .filter(|parameter| parameter.value.starts_with("ami-"))
+            .map(|parameter| (parameter.key.region.to_string(), parameter.value.clone()))
+            .collect();
+        info!("Checking that {} AMIs of {} exist", amis.len(), variant);
+        let existing = existing_amis(&amis, &ec2_clients, &rate_limits).await?;
+
+        let slots = [0, 1].map(|index| {
+            let (arch, versions) = &published[index];
+            (
+                *arch,
+                published_slots(versions, parity_args.version.as_deref(), &existing),
+            )
+        });
+        all_gaps.extend(gaps(variant, &slots));
+    }
+
+    if parity_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&all_gaps).context(error::SerializeSnafu)?
+        );
+    } else if !all_gaps.is_empty() {
+        println!("{}", Table::new(all_gaps.iter().cloned()));
+    }
+    ensure!(
+        all_gaps.is_empty(),
+        error::GapsSnafu {
+            count: all_gaps.len()
+        }
+    );
+    info!(
+        "{} published the same for each architecture",
+        parity_args.variants.join(", ")
+    );
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::DescribeImagesError;
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to describe AMIs in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeImages {
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("Failed to find published versions: {}", source))]
+        FindVersions { source: crate::gc::Error },
+
+        #[snafu(display("Found {} artifacts published for only one architecture", count))]
+        Gaps { count: usize },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display(
+            "No parameter template includes the version, so versions can't be told apart"
+        ))]
+        NoVersionedTemplates,
+
+        #[snafu(display("Failed to serialize gaps: {}", source))]
+        Serialize { source: serde_json::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            Self::Gaps { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gaps, published_slots};
+    use crate::aws::ssm::SsmKey;
+    use crate::gc::Parameter;
+    use aws_sdk_ssm::Region;
+    use std::collections::{BTreeMap, HashSet};
+
+    fn published(arch: &str, entries: &[(&str, &str, &str)]) -> BTreeMap<String, Vec<Parameter>> {
+        let mut versions: BTreeMap<String, Vec<Parameter>> = BTreeMap::new();
+        for (version, region, value) in entries {
+            versions
+                .entry(version.to_string())
+                .or_default()
+                .push(Parameter {
+                    key: SsmKey::new(
+                        Region::new(region.to_string()),
+                        format!("/bottlerocket/aws-dev/{}/{}/image_id", arch, version),
+                    ),
+                    template: "{variant}/{arch}/{image_version}/image_id".to_string(),
+                    value: value.to_string(),
+                });
+        }
+        versions
+    }
+
+    #[test]
+    fn finds_gaps() {
+        let x86 = published(
+            "x86_64",
+            &[
+                ("1.13.0", "us-west-2", "ami-x1"),
+                ("1.13.0", "us-east-1", "ami-x2"),
+                ("1.14.0", "us-west-2", "ami-x3"),
+            ],
+        );
+        let arm = published(
+            "arm64",
+            &[
+                ("1.13.0", "us-west-2", "ami-a1"),
+                ("1.13.0", "us-east-1", "ami-a2"),
+            ],
+        );
+        // The aarch64 AMI in us-east-1 was deregistered.
+        let existing: HashSet<(String, String)> = [
+            ("us-west-2", "ami-x1"),
+            ("us-east-1", "ami-x2"),
+            ("us-west-2", "ami-x3"),
+            ("us-west-2", "ami-a1"),
+        ]
+        .iter()
+        .map(|(region, id)| (region.to_string(), id.to_string()))
+        .collect();
+
+        let slots = [
+            ("x86_64", published_slots(&x86, None, &existing)),
+            ("arm64", published_slots(&arm, None, &existing)),
+        ];
+        let found: Vec<(String, String, String)> = gaps("aws-dev", &slots)
+            .into_iter()
+            .map(|gap| (gap.version, gap.region, gap.missing_for))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "1.13.0".to_string(),
+                    "us-east-1".to_string(),
+                    "arm64".to_string()
+                ),
+                (
+                    "1.14.0".to_string(),
+                    "us-west-2".to_string(),
+                    "arm64".to_string()
+                ),
+            ]
+        );
+
+        let slots = [
+            ("x86_64", published_slots(&x86, Some("1.13.0"), &existing)),
+            ("arm64", published_slots(&arm, Some("1.13.0"), &existing)),
+        ];
+        assert_eq!(gaps("aws-dev", &slots).len(), 1);
+    }
+}
@@ -0,0 +1,44 @@
+//! The completions module owns the 'completions' subcommand, which prints a script that completes
+//! pubsys subcommands, arguments, and argument values in the given shell.
+
+use crate::Args;
+use std::io::{self, Write};
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+/// The name completions are generated for; it must match the name of the installed binary.
+const BIN_NAME: &str = "pubsys";
+
+/// Prints a completion script for pubsys
+#[derive(Debug, StructOpt)]
+pub(crate) struct CompletionsArgs {
+    /// The shell to generate completions for: bash, zsh, fish, powershell, or elvish
+    #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+    shell: Shell,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(completions_args: &CompletionsArgs) {
+    write_completions(completions_args.shell, &mut io::stdout());
+}
+
+/// Writes the completion script for the given shell.
+fn write_completions<W: Write>(shell: Shell, writer: &mut W) {
+    Args::clap().gen_completions_to(BIN_NAME, shell, writer);
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_completions;
+    use structopt::clap::Shell;
+
+    #[test]
+    fn completes_subcommands_and_values() {
+        let mut script = Vec::new();
+        write_completions(Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("validate-ami"));
+        assert!(script.contains("--write-results-filter"));
+        assert!(script.contains("Correct Incorrect Missing Unreachable"));
+    }
+}
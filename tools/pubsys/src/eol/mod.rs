@@ -0,0 +1,636 @@
+//! The eol module owns the 'eol' subcommand, which retires one release of a variant and arch
+//! everywhere it's published: its AMIs are deprecated in each region, pointers like "latest" that
+//! still refer to it are deleted or, with `--retarget-to`, pointed at another release, and, if a
+//! repo is given, its targets are flagged so the next `gc-repo` or `gc` removes them.
+//!
+//! Releases and pointers are found the same way the 'gc' subcommand finds them.  The release's own
+//! versioned parameters are kept, so that 'gc' can still find its AMIs and remove them later.
+//!
+//! With `--dry-run`, the report is only shown.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::ssm::{ssm, template, BuildContext, SsmKey, SsmParameters};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::gc::{self, Parameter, VERSION_MARKER};
+use crate::repo::gc_repo::flag_targets;
+use crate::repo::s3::{self, RepoBucket};
+use crate::Args;
+use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, trace};
+use parse_datetime::parse_datetime;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+
+/// Deprecates a release's AMIs, retires the SSM parameters pointing at it, and flags its targets
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct EolArgs {
+    /// The variant of the release
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture of the release
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// The version of the release, as it appears in its parameter names
+    #[structopt(long)]
+    version: String,
+
+    /// File holding the parameter templates the release was published with
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Point parameters that refer to the release, like "latest", at this release instead of
+    /// deleting them
+    #[structopt(long)]
+    retarget_to: Option<String>,
+
+    /// When the AMIs become deprecated, like "in 7 days" or an RFC 3339 time; a minute from now
+    /// by default
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    deprecate_at: Option<DateTime<Utc>>,
+
+    /// Comma-separated list of regions to retire the release from, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Also flag the release's targets in this named repo from Infra.toml for removal
+    #[structopt(long)]
+    repo: Option<String>,
+
+    /// Write the report to this path as JSON
+    #[structopt(long, parse(from_os_str))]
+    report_path: Option<PathBuf>,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// An AMI to deprecate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct DeprecatedAmi {
+    region: String,
+    image_id: String,
+}
+
+/// A pointer parameter that refers to the release, and its new value if it's retargeted rather
+/// than deleted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct RetiredParameter {
+    region: String,
+    name: String,
+    value: String,
+    new_value: Option<String>,
+}
+
+/// Everything a run would change
+#[derive(Debug, Serialize)]
+struct Report {
+    version: String,
+    deprecate_at: String,
+    amis: Vec<DeprecatedAmi>,
+    parameters: Vec<RetiredParameter>,
+    repo_targets: Vec<String>,
+}
+
+#[derive(Tabled)]
+struct ReportRow {
+    kind: &'static str,
+    region: String,
+    id: String,
+    action: String,
+}
+
+impl Report {
+    fn rows(&self) -> Vec<ReportRow> {
+        let amis = self.amis.iter().map(|ami| ReportRow {
+            kind: "AMI",
+            region: ami.region.clone(),
+            id: ami.image_id.clone(),
+            action: format!("deprecate at {}", self.deprecate_at),
+        });
+        let parameters = self.parameters.iter().map(|parameter| ReportRow {
+            kind: "SSM parameter",
+            region: parameter.region.clone(),
+            id: parameter.name.clone(),
+            action: match &parameter.new_value {
+                Some(new_value) => format!("set to '{}'", new_value),
+                None => "delete".to_string(),
+            },
+        });
+        let targets = self.repo_targets.iter().map(|path| ReportRow {
+            kind: "repo target",
+            region: String::new(),
+            id: path.clone(),
+            action: "flag for removal".to_string(),
+        });
+        amis.chain(parameters).chain(targets).collect()
+    }
+}
+
+/// Returns the pointers' parameters that refer to the given release: those with the same value
+/// from the same template in the same region as one of the release's parameters.
+fn pointers_to<'a>(
+    versions: &'a BTreeMap<String, Vec<Parameter>>,
+    version: &str,
+) -> Vec<(&'a String, &'a Parameter)> {
+    let release = &versions[version];
+    versions
+        .iter()
+        .filter(|(pointer, _)| gc::release_version(pointer).is_none())
+        .flat_map(|(pointer, parameters)| parameters.iter().map(move |p| (pointer, p)))
+        .filter(|(_, pointer_parameter)| {
+            release.iter().any(|parameter| {
+                pointer_parameter.template == parameter.template
+                    && pointer_parameter.key.region == parameter.key.region
+                    && pointer_parameter.value == parameter.value
+            })
+        })
+        .collect()
+}
+
+/// Returns whether the repo target at the given path belongs to the given release, going by the
+/// image naming convention, like `targets/<sha256>.bottlerocket-aws-dev-x86_64-1.13.0-abcd.img.lz4`.
+fn is_release_target(path: &str, variant: &str, arch: &str, version: &str) -> bool {
+    let version = version.trim_start_matches('v');
+    let needle = format!("-{}-{}-{}", variant, arch, version);
+    let filename = match path.strip_prefix("targets/") {
+        Some(target) => target.rsplit('/').next().unwrap_or(target),
+        None => return false,
+    };
+    filename.match_indices(&needle).any(|(index, _)| {
+        matches!(
+            filename[index + needle.len()..].chars().next(),
+            Some('-') | Some('.')
+        )
+    })
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, eol_args: &EolArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let ssm_prefix = aws.ssm_prefix_for(&eol_args.variant, eol_args.arch.as_ref());
+    let version = &eol_args.version;
+    ensure!(
+        gc::release_version(version).is_some(),
+        error::NotReleaseSnafu { version }
+    );
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions: Vec<Region> = if !eol_args.regions.is_empty() {
+        eol_args.regions.clone()
+    } else {
+        aws.regions_for("eol").clone().into()
+    }
+    .iter()
+    .map(|name| region_from_string(name))
+    .collect();
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, &aws),
+        );
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, &aws),
+        );
+    }
+
+    // Find the release and its pointers
+
+    let build_context = BuildContext {
+        variant: &eol_args.variant,
+        arch: eol_args.arch.as_str(),
+        image_version: VERSION_MARKER,
+    };
+    info!(
+        "Parsing SSM parameter templates from {}",
+        eol_args.template_path.display()
+    );
+    let template_parameters = template::get_parameters(&eol_args.template_path, &build_context)
+        .context(error::FindTemplatesSnafu)?;
+    let patterns = gc::name_patterns(&template_parameters, ssm_prefix, &build_context)
+        .context(error::FindReleasesSnafu)?;
+    ensure!(!patterns.is_empty(), error::NoVersionedTemplatesSnafu);
+
+    info!("Finding the published releases of {}", eol_args.variant);
+    let versions = gc::find_versions(
+        &ssm_clients,
+        &rate_limits,
+        page_size::get(&aws, |page_size| page_size.get_parameters_by_path),
+        &patterns,
+        eol_args.no_progress,
+    )
+    .await
+    .context(error::FindReleasesSnafu)?;
+    ensure!(
+        versions.contains_key(version),
+        error::UnpublishedSnafu { version }
+    );
+    if let Some(retarget_to) = &eol_args.retarget_to {
+        ensure!(
+            retarget_to != version
+                && gc::release_version(retarget_to).is_some()
+                && versions.contains_key(retarget_to),
+            error::BadRetargetSnafu {
+                retarget_to,
+                version
+            }
+        );
+    }
+
+    // Report
+
+    let pointers = pointers_to(&versions, version);
+    let mut parameters = Vec::with_capacity(pointers.len());
+    for (_, parameter) in &pointers {
+        let new_value = match &eol_args.retarget_to {
+            Some(retarget_to) => Some(
+                versions[retarget_to]
+                    .iter()
+                    .find(|target| {
+                        target.template == parameter.template
+                            && target.key.region == parameter.key.region
+                    })
+                    .map(|target| target.value.clone())
+                    .context(error::MissingRetargetSnafu {
+                        name: &parameter.key.name,
+                        region: parameter.key.region.as_ref(),
+                        retarget_to,
+                    })?,
+            ),
+            None => None,
+        };
+        parameters.push(RetiredParameter {
+            region: parameter.key.region.to_string(),
+            name: parameter.key.name.clone(),
+            value: parameter.value.clone(),
+            new_value,
+        });
+    }
+    parameters.sort_by(|a, b| (&a.region, &a.name).cmp(&(&b.region, &b.name)));
+
+    // Only deprecate AMIs that no other release, or pointer we're leaving alone, refers to.
+    let untouched: BTreeMap<String, Vec<Parameter>> = versions
+        .iter()
+        .map(|(name, version_parameters)| {
+            let kept = version_parameters
+                .iter()
+                .filter(|parameter| {
+                    !pointers
+                        .iter()
+                        .any(|(_, pointer_parameter)| *pointer_parameter == *parameter)
+                })
+                .cloned()
+                .collect();
+            (name.clone(), kept)
+        })
+        .collect();
+    let amis: Vec<DeprecatedAmi> = gc::amis_to_remove(&untouched, &[version.clone()])
+        .into_keys()
+        .map(|(region, image_id)| DeprecatedAmi { region, image_id })
+        .collect();
+
+    let mut repo_targets = Vec::new();
+    let mut repo_client = None;
+    if let Some(repo) = &eol_args.repo {
+        let bucket = RepoBucket::from_config(&infra_config, repo).context(error::S3Snafu)?;
+        let client = bucket.client(&aws, &rate_limits).await;
+        info!("Listing the targets of repo '{}' in {}", repo, bucket.name);
+        repo_targets = s3::list_objects(&client, &bucket, "targets/")
+            .await
+            .context(error::S3Snafu)?
+            .into_iter()
+            .map(|object| object.path)
+            .filter(|path| {
+                is_release_target(path, &eol_args.variant, eol_args.arch.as_str(), version)
+            })
+            .collect();
+        repo_client = Some((client, bucket));
+    }
+
+    // EC2 refuses deprecation times in the past, so "now" has to be a little in the future.
+    let deprecate_at = eol_args
+        .deprecate_at
+        .unwrap_or_else(|| Utc::now() + Duration::minutes(1));
+    let report = Report {
+        version: version.clone(),
+        deprecate_at: deprecate_at.to_rfc3339(),
+        amis,
+        parameters,
+        repo_targets,
+    };
+    println!("{}", Table::new(report.rows()));
+    info!(
+        "Retiring {}: deprecating {} AMIs, {} {} SSM parameters, and flagging {} repo targets",
+        version,
+        report.amis.len(),
+        if eol_args.retarget_to.is_some() {
+            "retargeting"
+        } else {
+            "deleting"
+        },
+        report.parameters.len(),
+        report.repo_targets.len()
+    );
+    if let Some(report_path) = &eol_args.report_path {
+        serde_json::to_writer_pretty(
+            File::create(report_path).context(error::WriteReportSnafu { path: report_path })?,
+            &report,
+        )
+        .context(error::SerializeReportSnafu)?;
+    }
+
+    if args.dry_run {
+        info!("Not changing anything, since this is a dry run");
+        return Ok(());
+    }
+
+    // Retire   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    // Pointers go first, so nothing leads to the release once its AMIs are deprecated.
+    if eol_args.retarget_to.is_some() {
+        let new_values: SsmParameters = report
+            .parameters
+            .iter()
+            .filter_map(|parameter| {
+                Some((
+                    SsmKey::new(
+                        region_from_string(&parameter.region),
+                        parameter.name.clone(),
+                    ),
+                    parameter.new_value.clone()?,
+                ))
+            })
+            .collect();
+        ssm::set_parameters(&new_values, &ssm_clients, &rate_limits, &args.state)
+            .await
+            .context(error::SetParametersSnafu)?;
+        info!("Retargeted {} SSM parameters", new_values.len());
+    } else {
+        let keys: Vec<SsmKey> = report
+            .parameters
+            .iter()
+            .map(|parameter| {
+                SsmKey::new(
+                    region_from_string(&parameter.region),
+                    parameter.name.clone(),
+                )
+            })
+            .collect();
+        let failed =
+            gc::delete_parameters(&args.state.interrupt, &keys, &ssm_clients, &rate_limits).await;
+        ensure!(failed == 0, error::FailedParametersSnafu { count: failed });
+        info!("Deleted {} SSM parameters", keys.len());
+    }
+
+    let deprecate_at = aws_smithy_types::DateTime::from_secs(deprecate_at.timestamp());
+    for ami in &report.amis {
+        let region = region_from_string(&ami.region);
+        rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                ec2_clients[&region]
+                    .enable_image_deprecation()
+                    .image_id(&ami.image_id)
+                    .deprecate_at(deprecate_at)
+                    .send(),
+            )
+            .await
+            .context(error::DeprecateImageSnafu {
+                image_id: &ami.image_id,
+                region: region.as_ref(),
+            })?;
+        info!("Deprecated {} in {}", ami.image_id, region);
+    }
+
+    if let Some((client, bucket)) = repo_client {
+        if !report.repo_targets.is_empty() {
+            flag_targets(&client, &bucket, &report.repo_targets, version)
+                .await
+                .context(error::GcRepoSnafu)?;
+            info!(
+                "Flagged {} repo targets for the next GC",
+                report.repo_targets.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::EnableImageDeprecationError;
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Can't retarget {}'s parameters to '{}'; it has to be another published release",
+            version,
+            retarget_to
+        ))]
+        BadRetarget {
+            retarget_to: String,
+            version: String,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to deprecate {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeprecateImage {
+            image_id: String,
+            region: String,
+            source: SdkError<EnableImageDeprecationError>,
+        },
+
+        #[snafu(display(
+            "Failed to delete {} batches of SSM parameters; see the errors above",
+            count
+        ))]
+        FailedParameters { count: usize },
+
+        #[snafu(display("Failed to find releases: {}", source))]
+        FindReleases { source: crate::gc::Error },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        GcRepo { source: crate::repo::gc_repo::Error },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display(
+            "Can't retarget {} in {}: {} has no parameter from the same template there",
+            name,
+            region,
+            retarget_to
+        ))]
+        MissingRetarget {
+            name: String,
+            region: String,
+            retarget_to: String,
+        },
+
+        #[snafu(display(
+            "'{}' is a pointer, not a release; give the release's version",
+            version
+        ))]
+        NotRelease { version: String },
+
+        #[snafu(display(
+            "No parameter template includes the version, so releases can't be told apart"
+        ))]
+        NoVersionedTemplates,
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display("Failed to serialize report: {}", source))]
+        SerializeReport { source: serde_json::Error },
+
+        #[snafu(display("Failed to set SSM parameters: {}", source))]
+        SetParameters { source: crate::aws::ssm::ssm::Error },
+
+        #[snafu(display("Found no published parameters of release {}", version))]
+        Unpublished { version: String },
+
+        #[snafu(display("Failed to write report to '{}': {}", path.display(), source))]
+        WriteReport {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DeprecateImage { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_release_target, pointers_to};
+    use crate::aws::ssm::SsmKey;
+    use crate::gc::Parameter;
+    use aws_sdk_ssm::Region;
+    use std::collections::BTreeMap;
+
+    fn parameter(region: &str, version: &str, value: &str) -> Parameter {
+        Parameter {
+            key: SsmKey::new(
+                Region::new(region.to_string()),
+                format!("/bottlerocket/aws-dev/x86_64/{}/image_id", version),
+            ),
+            template: "{variant}/{arch}/{image_version}/image_id".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn finds_pointers() {
+        let versions: BTreeMap<String, Vec<Parameter>> = [
+            (
+                "1.13.0",
+                vec![
+                    parameter("us-west-2", "1.13.0", "ami-old-west"),
+                    parameter("us-east-1", "1.13.0", "ami-old-east"),
+                ],
+            ),
+            (
+                "1.14.0",
+                vec![
+                    parameter("us-west-2", "1.14.0", "ami-new-west"),
+                    parameter("us-east-1", "1.14.0", "ami-new-east"),
+                ],
+            ),
+            (
+                "latest",
+                vec![
+                    // Only us-west-2 still points at the old release.
+                    parameter("us-west-2", "latest", "ami-old-west"),
+                    parameter("us-east-1", "latest", "ami-new-east"),
+                ],
+            ),
+        ]
+        .into_iter()
+        .map(|(version, parameters)| (version.to_string(), parameters))
+        .collect();
+
+        let pointers = pointers_to(&versions, "1.13.0");
+        assert_eq!(pointers.len(), 1);
+        assert_eq!(pointers[0].0, "latest");
+        assert_eq!(pointers[0].1.key.region.as_ref(), "us-west-2");
+        assert!(pointers_to(&versions, "1.14.0")
+            .iter()
+            .all(|(_, parameter)| parameter.key.region.as_ref() == "us-east-1"));
+    }
+
+    #[test]
+    fn release_targets() {
+        let matches = |path| is_release_target(path, "aws-dev", "x86_64", "v1.13.0");
+        assert!(matches(
+            "targets/ab12.bottlerocket-aws-dev-x86_64-1.13.0-abcd.img.lz4"
+        ));
+        assert!(matches("targets/bottlerocket-aws-dev-x86_64-1.13.0.ext4"));
+        assert!(!matches(
+            "targets/ab12.bottlerocket-aws-dev-x86_64-1.13.01-abcd.img.lz4"
+        ));
+        assert!(!matches(
+            "targets/ab12.bottlerocket-aws-dev-aarch64-1.13.0-abcd.img.lz4"
+        ));
+        assert!(!matches(
+            "aws-dev/x86_64/bottlerocket-aws-dev-x86_64-1.13.0.json"
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+//! The estimate module owns the 'estimate' subcommand, which estimates what publishing a build
+//! would cost: EBS snapshot storage for its AMI in each region, S3 storage for its repo, and the
+//! cross-region transfer of copying the AMI from the base region to the others.  It doesn't call
+//! AWS, so it can be used to compare plans, like adding regions, before committing to them.
+//!
+//! Snapshots only store the blocks of an image that hold data, so each image is read to count
+//! them, the same way they're skipped when the image is uploaded.  Prices come from the pricing
+//! table bundled with pubsys, or from `--pricing-path`.
+
+use crate::exit_code::{Classify, Kind};
+use crate::repo::s3::RepoBucket;
+use crate::Args;
+use log::{info, trace, warn};
+use serde::Deserialize;
+use snafu::{ensure, ResultExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+
+/// The pricing table used unless `--pricing-path` is given
+const BUNDLED_PRICING: &str = include_str!("pricing.toml");
+
+/// Snapshot blocks are this big, so an image is stored in chunks of this size
+const SNAPSHOT_BLOCK_SIZE: usize = 512 * 1024;
+
+const GIB: f64 = (1024 * 1024 * 1024) as f64;
+
+/// Estimates the storage and transfer costs of publishing a build
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct EstimateArgs {
+    /// Path to the image containing the os volume
+    #[structopt(short = "o", long, parse(from_os_str))]
+    os_image: PathBuf,
+
+    /// Path to the image containing the data volume
+    #[structopt(short = "d", long, parse(from_os_str))]
+    data_image: Option<PathBuf>,
+
+    /// Regions the AMI would be published to, the first being the base for copying; overrides
+    /// the regions Infra.toml gives the ami subcommand
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Path to the built repo, whose files would be uploaded
+    #[structopt(long, parse(from_os_str))]
+    repo_path: Option<PathBuf>,
+
+    /// Use the region of this named repo's bucket from Infra.toml for the repo's storage, rather
+    /// than the base region
+    #[structopt(long)]
+    repo: Option<String>,
+
+    /// Use the prices in this TOML file rather than the bundled pricing table
+    #[structopt(long, parse(from_os_str))]
+    pricing_path: Option<PathBuf>,
+}
+
+/// Prices in USD
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Prices {
+    /// EBS snapshot storage, per GB-month
+    snapshot_gb_month: f64,
+    /// S3 storage, per GB-month
+    s3_gb_month: f64,
+    /// Transfer out of the region to another region, per GB
+    transfer_gb: f64,
+}
+
+/// Prices by region, with defaults for regions that aren't listed
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PricingTable {
+    default: Prices,
+    #[serde(default)]
+    regions: BTreeMap<String, Prices>,
+}
+
+impl PricingTable {
+    fn prices(&self, region: &str) -> Prices {
+        self.regions.get(region).copied().unwrap_or_else(|| {
+            warn!("No prices listed for {}, using the default prices", region);
+            self.default
+        })
+    }
+}
+
+/// The estimated costs in one region, in USD
+#[derive(Debug, Default, PartialEq)]
+struct RegionEstimate {
+    region: String,
+    snapshot_gib: f64,
+    snapshot_monthly: f64,
+    repo_monthly: f64,
+    /// The one-time cost of copying the AMI into the region
+    transfer: f64,
+}
+
+#[derive(Tabled)]
+struct EstimateRow {
+    region: String,
+    snapshot_gib: String,
+    snapshots_per_month: String,
+    repo_per_month: String,
+    copy_transfer: String,
+}
+
+impl From<&RegionEstimate> for EstimateRow {
+    fn from(estimate: &RegionEstimate) -> Self {
+        Self {
+            region: estimate.region.clone(),
+            snapshot_gib: format!("{:.2}", estimate.snapshot_gib),
+            snapshots_per_month: format!("${:.2}", estimate.snapshot_monthly),
+            repo_per_month: format!("${:.2}", estimate.repo_monthly),
+            copy_transfer: format!("${:.2}", estimate.transfer),
+        }
+    }
+}
+
+/// Returns how many bytes of the file are in blocks that hold data, and would be stored in a
+/// snapshot of it.
+fn stored_bytes(path: &Path) -> Result<u64> {
+    let mut file = File::open(path).context(error::ReadImageSnafu { path })?;
+    let mut block = vec![0; SNAPSHOT_BLOCK_SIZE];
+    let mut stored = 0;
+    loop {
+        // A short read only happens at the end of the file.
+        let mut filled = 0;
+        while filled < block.len() {
+            match file
+                .read(&mut block[filled..])
+                .context(error::ReadImageSnafu { path })?
+            {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        if block[..filled].iter().any(|byte| *byte != 0) {
+            stored += filled as u64;
+        }
+    }
+    Ok(stored)
+}
+
+/// Returns the total size of the files under the given directory.
+fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path).context(error::ReadRepoSnafu { path })? {
+        let entry = entry.context(error::ReadRepoSnafu { path })?;
+        let metadata = entry
+            .metadata()
+            .context(error::ReadRepoSnafu { path: entry.path() })?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Estimates the costs in each region of storing the given snapshot bytes, copied from the first
+/// region to the others, and the given repo bytes in the repo region.
+fn estimate(
+    pricing: &PricingTable,
+    regions: &[String],
+    snapshot_bytes: u64,
+    repo_bytes: u64,
+    repo_region: &str,
+) -> Vec<RegionEstimate> {
+    let snapshot_gib = snapshot_bytes as f64 / GIB;
+    let repo_gib = repo_bytes as f64 / GIB;
+    let base_prices = pricing.prices(&regions[0]);
+    let mut estimates: Vec<RegionEstimate> = regions
+        .iter()
+        .enumerate()
+        .map(|(index, region)| RegionEstimate {
+            region: region.clone(),
+            snapshot_gib,
+            snapshot_monthly: snapshot_gib * pricing.prices(region).snapshot_gb_month,
+            repo_monthly: 0.0,
+            // Copies are sent out of the base region.
+            transfer: if index == 0 {
+                0.0
+            } else {
+                snapshot_gib * base_prices.transfer_gb
+            },
+        })
+        .collect();
+    if repo_bytes > 0 {
+        let repo_monthly = repo_gib * pricing.prices(repo_region).s3_gb_month;
+        match estimates
+            .iter_mut()
+            .find(|estimate| estimate.region == repo_region)
+        {
+            Some(estimate) => estimate.repo_monthly = repo_monthly,
+            None => estimates.push(RegionEstimate {
+                region: repo_region.to_string(),
+                repo_monthly,
+                ..Default::default()
+            }),
+        }
+    }
+    estimates
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, estimate_args: &EstimateArgs) -> Result<()> {
+    let pricing_toml = match &estimate_args.pricing_path {
+        Some(path) => fs::read_to_string(path).context(error::ReadPricingSnafu { path })?,
+        None => BUNDLED_PRICING.to_string(),
+    };
+    let pricing: PricingTable = toml::from_str(&pricing_toml).context(error::ParsePricingSnafu)?;
+
+    // Only read the config if we need its regions or repo.
+    let infra_config = if estimate_args.regions.is_empty() || estimate_args.repo.is_some() {
+        let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+        trace!("Parsed infra config: {:#?}", infra_config);
+        Some(infra_config)
+    } else {
+        None
+    };
+    let regions: Vec<String> = if !estimate_args.regions.is_empty() {
+        estimate_args.regions.clone()
+    } else {
+        infra_config
+            .as_ref()
+            .and_then(|infra_config| infra_config.aws.as_ref())
+            .map(|aws| aws.regions_for("ami").iter().cloned().collect())
+            .unwrap_or_default()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let repo_region = match (&estimate_args.repo, &infra_config) {
+        (Some(repo), Some(infra_config)) => RepoBucket::from_config(infra_config, repo)
+            .context(error::S3Snafu)?
+            .region
+            .to_string(),
+        _ => regions[0].clone(),
+    };
+
+    let mut snapshot_bytes = 0;
+    for image in std::iter::once(&estimate_args.os_image).chain(&estimate_args.data_image) {
+        info!("Reading {} to find its stored size", image.display());
+        snapshot_bytes += stored_bytes(image)?;
+    }
+    let repo_bytes = match &estimate_args.repo_path {
+        Some(path) => directory_size(path)?,
+        None => 0,
+    };
+
+    let estimates = estimate(&pricing, &regions, snapshot_bytes, repo_bytes, &repo_region);
+    println!("{}", Table::new(estimates.iter().map(EstimateRow::from)));
+    let monthly: f64 = estimates
+        .iter()
+        .map(|estimate| estimate.snapshot_monthly + estimate.repo_monthly)
+        .sum();
+    let transfer: f64 = estimates.iter().map(|estimate| estimate.transfer).sum();
+    info!(
+        "Publishing to {} regions would cost about ${:.2} per month in storage, plus ${:.2} to copy the AMI",
+        regions.len(),
+        monthly,
+        transfer
+    );
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to parse pricing table: {}", source))]
+        ParsePricing { source: toml::de::Error },
+
+        #[snafu(display("Failed to read image '{}': {}", path.display(), source))]
+        ReadImage {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to read pricing table '{}': {}", path.display(), source))]
+        ReadPricing {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to read repo '{}': {}", path.display(), source))]
+        ReadRepo {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{estimate, stored_bytes, PricingTable, BUNDLED_PRICING, GIB, SNAPSHOT_BLOCK_SIZE};
+    use std::io::Write;
+
+    #[test]
+    fn bundled_pricing() {
+        let pricing: PricingTable = toml::from_str(BUNDLED_PRICING).unwrap();
+        assert!(pricing.regions.contains_key("us-west-2"));
+        assert_eq!(pricing.prices("nowhere-1"), pricing.default);
+    }
+
+    #[test]
+    fn stored() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut data = vec![0; SNAPSHOT_BLOCK_SIZE * 3 + 10];
+        // Data in the second block and in the partial last block
+        data[SNAPSHOT_BLOCK_SIZE + 1] = 1;
+        data[SNAPSHOT_BLOCK_SIZE * 3 + 9] = 1;
+        file.write_all(&data).unwrap();
+        assert_eq!(
+            stored_bytes(file.path()).unwrap(),
+            SNAPSHOT_BLOCK_SIZE as u64 + 10
+        );
+    }
+
+    #[test]
+    fn estimates() {
+        let pricing: PricingTable = toml::from_str(
+            r#"
+            [default]
+            snapshot_gb_month = 0.05
+            s3_gb_month = 0.02
+            transfer_gb = 0.02
+
+            [regions.sa-east-1]
+            snapshot_gb_month = 0.1
+            s3_gb_month = 0.04
+            transfer_gb = 0.1
+            "#,
+        )
+        .unwrap();
+        let regions = vec!["us-west-2".to_string(), "sa-east-1".to_string()];
+        let estimates = estimate(&pricing, &regions, 2 * GIB as u64, GIB as u64, "us-east-1");
+        assert_eq!(estimates.len(), 3);
+        assert_eq!(estimates[0].snapshot_monthly, 0.1);
+        assert_eq!(estimates[0].transfer, 0.0);
+        assert_eq!(estimates[1].snapshot_monthly, 0.2);
+        // Copies are charged at the base region's transfer price.
+        assert_eq!(estimates[1].transfer, 0.04);
+        assert_eq!(estimates[2].region, "us-east-1");
+        assert_eq!(estimates[2].repo_monthly, 0.02);
+        assert_eq!(estimates[2].snapshot_gib, 0.0);
+    }
+}
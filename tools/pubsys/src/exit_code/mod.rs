@@ -0,0 +1,172 @@
+//! The exit_code module decides which exit code pubsys uses for an error, so that automation
+//! running pubsys can tell classes of failure apart without parsing error messages:
+//!
+//! * 1: any failure not covered below
+//! * 2: a check or validation ran and found problems, like failed repo targets, expiring
+//!   metadata, or denied permissions
+//! * 3: an AWS API request failed, like one that was throttled, rejected, or couldn't be sent
+//! * 4: the config is missing, unreadable, or lacks a setting the subcommand needs
+//! * 130: the user interrupted pubsys with Ctrl-C, like a shell reports for SIGINT
+//!
+//! An error is classified by the errors that caused it, so a validation that couldn't finish
+//! because an AWS request failed gets the AWS code.  If causes fall in more than one class, config
+//! errors win over AWS errors, which win over validation failures.  An interrupt wins over all of
+//! them, since errors after an interrupt may only be a result of stopping early.
+//!
+//! Each module's error says which of its variants fall in a class by implementing `Classify`.
+
+use crate::aws::ami::{launch_permissions, lineage, public, register, wait};
+use crate::aws::{
+    ami, check_permissions, diff, identity, image_builder, kms, promote_ami, promote_ssm,
+    publish_ami, query, rollback_ssm, secrets, ssm, tags, transfer_ami, validate_ami,
+    validate_snapshots, validate_ssm,
+};
+use crate::azure::{gallery_image, upload_vhd};
+use crate::repo::{check_expirations, cloudfront, repo_manifest, s3, validate_repo};
+use crate::{
+    check_infra, check_parity, eol, estimate, gc, gcp, inventory, journal, plan, release,
+    release_manifest, remote_config, report, test_trigger,
+};
+use std::error::Error;
+use std::iter;
+
+pub const FAILURE: i32 = 1;
+pub const VALIDATION: i32 = 2;
+pub const AWS_API: i32 = 3;
+pub const CONFIG: i32 = 4;
+pub const INTERRUPTED: i32 = 130;
+
+/// A class of failure with its own exit code, in order of precedence, lowest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Kind {
+    /// A check or validation ran and found problems
+    Validation,
+    /// An AWS API request failed
+    AwsApi,
+    /// The config is missing, unreadable, or lacks a setting
+    Config,
+    /// The user interrupted pubsys
+    Interrupted,
+}
+
+impl Kind {
+    fn exit_code(self) -> i32 {
+        match self {
+            Kind::Validation => VALIDATION,
+            Kind::AwsApi => AWS_API,
+            Kind::Config => CONFIG,
+            Kind::Interrupted => INTERRUPTED,
+        }
+    }
+}
+
+/// Implemented by the errors of modules that can fail in a way with its own exit code.
+pub(crate) trait Classify {
+    /// Returns the class of this failure, if it has one of its own.  Errors that wrap another
+    /// module's error return `None`, because the wrapped error is classified on its own.
+    fn kind(&self) -> Option<Kind>;
+}
+
+/// Returns the exit code for the given error, based on it and the chain of errors that caused it.
+pub(crate) fn for_error(error: &(dyn Error + 'static)) -> i32 {
+    iter::successors(Some(error), |e| e.source())
+        .filter_map(kind)
+        .max()
+        .map_or(FAILURE, Kind::exit_code)
+}
+
+/// Returns the class of the given error, not counting its causes.
+fn kind(error: &(dyn Error + 'static)) -> Option<Kind> {
+    if error.is::<pubsys_config::Error>() {
+        return Some(Kind::Config);
+    }
+    // Each error classifies itself; this only finds which one it is.
+    macro_rules! classify {
+        ($($error:ty),* $(,)?) => {
+            $(
+                if let Some(error) = error.downcast_ref::<$error>() {
+                    return error.kind();
+                }
+            )*
+        };
+    }
+    classify!(
+        crate::Error,
+        ami::Error,
+        check_expirations::Error,
+        check_infra::Error,
+        check_parity::Error,
+        check_permissions::Error,
+        check_permissions::simulate::Error,
+        cloudfront::Error,
+        diff::Error,
+        eol::Error,
+        estimate::Error,
+        gallery_image::Error,
+        gc::Error,
+        gcp::image::Error,
+        identity::Error,
+        image_builder::Error,
+        inventory::Error,
+        journal::Error,
+        kms::Error,
+        launch_permissions::Error,
+        lineage::Error,
+        plan::apply::Error,
+        promote_ami::Error,
+        promote_ssm::Error,
+        public::Error,
+        publish_ami::Error,
+        publish_ami::verify::Error,
+        query::Error,
+        register::Error,
+        release::Error,
+        release_manifest::Error,
+        remote_config::Error,
+        repo_manifest::Error,
+        report::Error,
+        rollback_ssm::Error,
+        s3::Error,
+        secrets::Error,
+        ssm::Error,
+        ssm::ssm::Error,
+        tags::Error,
+        test_trigger::Error,
+        transfer_ami::Error,
+        upload_vhd::Error,
+        validate_ami::ami::error::Error,
+        validate_repo::Error,
+        validate_snapshots::Error,
+        validate_ssm::Error,
+        wait::Error,
+    );
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{for_error, CONFIG, FAILURE, INTERRUPTED, VALIDATION};
+    use crate::aws::check_permissions;
+    use crate::repo::check_expirations;
+    use std::fmt;
+
+    #[test]
+    fn classifies_causes() {
+        let denied = check_permissions::Error::Denied { count: 2 };
+        assert_eq!(for_error(&denied), VALIDATION);
+
+        let missing = check_permissions::Error::MissingConfig {
+            missing: "aws.regions".to_string(),
+        };
+        assert_eq!(for_error(&missing), CONFIG);
+
+        let expiring = check_expirations::Error::RepoExpirations {
+            metadata_url: "https://example.com/metadata/".parse().unwrap(),
+        };
+        assert_eq!(for_error(&expiring), VALIDATION);
+
+        assert_eq!(for_error(&crate::Error::Interrupted), INTERRUPTED);
+
+        assert_eq!(for_error(&fmt::Error), FAILURE);
+    }
+}
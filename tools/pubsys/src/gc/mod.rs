@@ -0,0 +1,941 @@
+//! The gc module owns the 'gc' subcommand, which removes the old releases of a variant and arch
+//! from everywhere they're published in one pass: their SSM parameters, their AMIs and the
+//! snapshots behind them, and, if a repo is given, the repo targets no kept metadata refers to.
+//!
+//! Releases are found through their versioned SSM parameters, whose names come from the same
+//! templates the 'ssm' subcommand uses.  Any other rendered "version", like "latest", is a pointer,
+//! and it refers to each release that has a parameter with the same value from the same template
+//! in the same region.  A release is kept if it's one of the latest `--keep-latest` versions, if
+//! it's given with `--keep-version`, or if a pointer refers to it; an AMI is only deleted if no
+//! kept release or pointer refers to it.
+//!
+//! Without `--delete`, the plan is only shown.  With it, parameters are deleted first, so nothing
+//! can find a release through SSM once its AMIs are going, then the AMIs and their snapshots, and
+//! then the repo targets.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::publish_ami::get_snapshots;
+use crate::aws::rate_limit::{RateLimits, EC2, SSM};
+use crate::aws::ssm::{ssm, template, BuildContext, SsmKey};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::interrupt::Interrupt;
+use crate::progress::progress_bar;
+use crate::repo::gc_repo::find_unreferenced;
+use crate::repo::s3::{self, RepoBucket};
+use crate::Args;
+use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use chrono::Duration;
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace, warn};
+use parse_datetime::parse_offset;
+use semver::Version;
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+
+/// Stands in for the version when rendering parameter names, so we can find where it goes
+pub(crate) const VERSION_MARKER: &str = "pubsys-gc-version";
+
+/// SSM deletes at most this many parameters per request
+const MAX_DELETE_PARAMETERS: usize = 10;
+
+/// Regions are independent, so we can work on all of them at once.
+const MAX_PARALLEL_REGIONS: usize = 32;
+
+/// Removes old releases' SSM parameters, AMIs, snapshots, and repo targets
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct GcArgs {
+    /// The variant whose releases should be removed
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture whose releases should be removed
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// File holding the parameter templates the releases were published with
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Keep this many of the latest releases; for the repo, keep the targets of this many of the
+    /// latest versions of each targets role
+    #[structopt(long, default_value = "3")]
+    keep_latest: NonZeroUsize,
+
+    /// Also keep this release; can be given more than once
+    #[structopt(long = "keep-version", number_of_values = 1)]
+    keep_versions: Vec<String>,
+
+    /// Comma-separated list of regions to clean up, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Also remove the targets of this named repo from Infra.toml that no kept metadata refers to
+    #[structopt(long)]
+    repo: Option<String>,
+
+    /// Keep unreferenced repo targets written more recently than this, like "7 days", since a
+    /// repo being published may not refer to them yet
+    #[structopt(long, default_value = "3 days", parse(try_from_str = parse_offset))]
+    min_age: Duration,
+
+    /// Write the plan to this path as JSON
+    #[structopt(long, parse(from_os_str))]
+    plan_path: Option<PathBuf>,
+
+    /// Delete what the plan lists; otherwise, it's only shown
+    #[structopt(long)]
+    delete: bool,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// The parts of a template's rendered parameter names before and after the version
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct NamePattern {
+    template: String,
+    prefix: String,
+    suffix: String,
+}
+
+impl NamePattern {
+    /// Returns the version in the given parameter name, if the name came from this template.
+    pub(crate) fn version<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let version = name
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)?;
+        (!version.is_empty() && !version.contains('/')).then_some(version)
+    }
+}
+
+/// A published parameter of a release or pointer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Parameter {
+    pub(crate) key: SsmKey,
+    pub(crate) template: String,
+    pub(crate) value: String,
+}
+
+/// An AMI to remove, and the releases that referred to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PlannedAmi {
+    region: String,
+    image_id: String,
+    releases: BTreeSet<String>,
+    snapshot_ids: Vec<String>,
+}
+
+/// An SSM parameter to remove
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PlannedParameter {
+    region: String,
+    name: String,
+    release: String,
+}
+
+/// A repo target to remove
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct PlannedTarget {
+    path: String,
+    size: i64,
+}
+
+/// Everything a run would remove, and the releases it keeps, with the reason for each
+#[derive(Debug, Default, Serialize)]
+struct Plan {
+    kept_releases: BTreeMap<String, String>,
+    removed_releases: Vec<String>,
+    parameters: Vec<PlannedParameter>,
+    amis: Vec<PlannedAmi>,
+    repo_targets: Vec<PlannedTarget>,
+}
+
+#[derive(Tabled)]
+struct PlanRow {
+    kind: &'static str,
+    region: String,
+    id: String,
+    release: String,
+}
+
+impl Plan {
+    fn rows(&self) -> Vec<PlanRow> {
+        let parameters = self.parameters.iter().map(|parameter| PlanRow {
+            kind: "SSM parameter",
+            region: parameter.region.clone(),
+            id: parameter.name.clone(),
+            release: parameter.release.clone(),
+        });
+        let amis = self.amis.iter().map(|ami| PlanRow {
+            kind: "AMI",
+            region: ami.region.clone(),
+            id: ami.image_id.clone(),
+            release: join(&ami.releases),
+        });
+        let snapshots = self.amis.iter().flat_map(|ami| {
+            ami.snapshot_ids.iter().map(move |snapshot_id| PlanRow {
+                kind: "snapshot",
+                region: ami.region.clone(),
+                id: snapshot_id.clone(),
+                release: join(&ami.releases),
+            })
+        });
+        let targets = self.repo_targets.iter().map(|target| PlanRow {
+            kind: "repo target",
+            region: String::new(),
+            id: format!("{} ({} bytes)", target.path, target.size),
+            release: String::new(),
+        });
+        parameters
+            .chain(amis)
+            .chain(snapshots)
+            .chain(targets)
+            .collect()
+    }
+}
+
+fn join(releases: &BTreeSet<String>) -> String {
+    releases
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the version of a release, or None for a pointer like "latest".
+pub(crate) fn release_version(version: &str) -> Option<Version> {
+    Version::parse(version.trim_start_matches('v')).ok()
+}
+
+/// Renders the name templates with a marker for the version, and returns the patterns of the
+/// names that have a version.
+pub(crate) fn name_patterns(
+    template_parameters: &template::TemplateParameters,
+    ssm_prefix: &str,
+    build_context: &BuildContext<'_>,
+) -> Result<Vec<NamePattern>> {
+    let rendered = template::render_parameter_names(template_parameters, ssm_prefix, build_context)
+        .context(error::RenderTemplatesSnafu)?;
+    let mut patterns: Vec<NamePattern> = rendered
+        .into_iter()
+        .filter_map(|(template, name)| {
+            let (prefix, suffix) = name.split_once(VERSION_MARKER)?;
+            Some(NamePattern {
+                template,
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+            })
+        })
+        .collect();
+    patterns.sort_by(|a, b| a.template.cmp(&b.template));
+    Ok(patterns)
+}
+
+/// Groups the given parameters by the version in their names, skipping those that didn't come
+/// from a template with a version.
+fn group_by_version(
+    patterns: &[NamePattern],
+    parameters: impl IntoIterator<Item = (SsmKey, String)>,
+) -> BTreeMap<String, Vec<Parameter>> {
+    let mut versions: BTreeMap<String, Vec<Parameter>> = BTreeMap::new();
+    for (key, value) in parameters {
+        let found = patterns
+            .iter()
+            .find_map(|pattern| Some((pattern, pattern.version(&key.name)?.to_string())));
+        if let Some((pattern, version)) = found {
+            versions.entry(version).or_default().push(Parameter {
+                template: pattern.template.clone(),
+                key,
+                value,
+            });
+        }
+    }
+    versions
+}
+
+/// Decides which releases to keep, and why, and which to remove.  Pointers aren't releases, so
+/// they're in neither list.
+fn choose_releases(
+    versions: &BTreeMap<String, Vec<Parameter>>,
+    keep_latest: NonZeroUsize,
+    keep_versions: &[String],
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut releases: Vec<(Version, &String)> = versions
+        .keys()
+        .filter_map(|version| Some((release_version(version)?, version)))
+        .collect();
+    releases.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut kept = BTreeMap::new();
+    for (_, version) in releases.iter().take(keep_latest.get()) {
+        kept.insert(
+            version.to_string(),
+            format!("one of the latest {}", keep_latest),
+        );
+    }
+    for version in keep_versions {
+        if versions.contains_key(version) {
+            kept.entry(version.clone())
+                .or_insert_with(|| "requested".to_string());
+        }
+    }
+    for (pointer, pointer_parameters) in versions
+        .iter()
+        .filter(|(version, _)| release_version(version).is_none())
+    {
+        for (_, version) in &releases {
+            let referred = versions[*version].iter().any(|parameter| {
+                pointer_parameters.iter().any(|pointer_parameter| {
+                    pointer_parameter.template == parameter.template
+                        && pointer_parameter.key.region == parameter.key.region
+                        && pointer_parameter.value == parameter.value
+                })
+            });
+            if referred {
+                kept.entry(version.to_string())
+                    .or_insert_with(|| format!("'{}' refers to it", pointer));
+            }
+        }
+    }
+
+    let removed = releases
+        .iter()
+        .rev()
+        .map(|(_, version)| version.to_string())
+        .filter(|version| !kept.contains_key(version))
+        .collect();
+    (kept, removed)
+}
+
+/// Returns the AMIs the removed releases' parameters refer to, by region and ID, leaving out any
+/// that a kept release or pointer also refers to.
+pub(crate) fn amis_to_remove(
+    versions: &BTreeMap<String, Vec<Parameter>>,
+    removed: &[String],
+) -> BTreeMap<(String, String), BTreeSet<String>> {
+    let is_ami = |value: &str| value.starts_with("ami-");
+    let still_used: HashSet<(&Region, &str)> = versions
+        .iter()
+        .filter(|(version, _)| !removed.contains(version))
+        .flat_map(|(_, parameters)| parameters)
+        .filter(|parameter| is_ami(&parameter.value))
+        .map(|parameter| (&parameter.key.region, parameter.value.as_str()))
+        .collect();
+
+    let mut amis: BTreeMap<(String, String), BTreeSet<String>> = BTreeMap::new();
+    for version in removed {
+        for parameter in &versions[version] {
+            if is_ami(&parameter.value)
+                && !still_used.contains(&(&parameter.key.region, parameter.value.as_str()))
+            {
+                amis.entry((parameter.key.region.to_string(), parameter.value.clone()))
+                    .or_default()
+                    .insert(version.clone());
+            }
+        }
+    }
+    amis
+}
+
+/// Lists the published parameters of every release and pointer of the variant and arch, grouped
+/// by the version in their names.
+pub(crate) async fn find_versions(
+    ssm_clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+    page_size: Option<i32>,
+    patterns: &[NamePattern],
+    no_progress: bool,
+) -> Result<BTreeMap<String, Vec<Parameter>>> {
+    // Every release of the variant and arch is under the path leading up to the version.
+    let mut paths = BTreeSet::new();
+    for pattern in patterns {
+        let path = pattern
+            .prefix
+            .rsplit_once('/')
+            .map(|(path, _)| path)
+            .filter(|path| path.starts_with('/'))
+            .ok_or_else(|| error::Error::NoParameterPath {
+                template: pattern.template.clone(),
+            })?;
+        paths.insert(path.to_string());
+    }
+
+    let progress_bar = progress_bar(
+        no_progress,
+        ssm_clients.len() * paths.len(),
+        "Finding releases",
+    );
+    let mut published = Vec::new();
+    for (region, result) in
+        ssm::get_parameters_by_prefixes(ssm_clients, rate_limits, page_size, &paths, &progress_bar)
+            .await
+    {
+        // We can't tell what's still in use in a region we can't read, so we stop.
+        published.extend(result.context(error::FetchSsmSnafu {
+            region: region.as_ref(),
+        })?);
+    }
+    Ok(group_by_version(patterns, published))
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, gc_args: &GcArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let ssm_prefix = aws.ssm_prefix_for(&gc_args.variant, gc_args.arch.as_ref());
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions: Vec<Region> = if !gc_args.regions.is_empty() {
+        gc_args.regions.clone()
+    } else {
+        aws.regions_for("gc").clone().into()
+    }
+    .iter()
+    .map(|name| region_from_string(name))
+    .collect();
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, &aws).await;
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, &aws),
+        );
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, &aws),
+        );
+    }
+
+    // Find the releases   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    let build_context = BuildContext {
+        variant: &gc_args.variant,
+        arch: gc_args.arch.as_str(),
+        image_version: VERSION_MARKER,
+    };
+    info!(
+        "Parsing SSM parameter templates from {}",
+        gc_args.template_path.display()
+    );
+    let template_parameters = template::get_parameters(&gc_args.template_path, &build_context)
+        .context(error::FindTemplatesSnafu)?;
+    let patterns = name_patterns(&template_parameters, ssm_prefix, &build_context)?;
+    ensure!(!patterns.is_empty(), error::NoVersionedTemplatesSnafu);
+
+    info!("Finding the published releases of {}", gc_args.variant);
+    let versions = find_versions(
+        &ssm_clients,
+        &rate_limits,
+        page_size::get(&aws, |page_size| page_size.get_parameters_by_path),
+        &patterns,
+        gc_args.no_progress,
+    )
+    .await?;
+    ensure!(!versions.is_empty(), error::NoReleasesSnafu);
+
+    // Plan   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    let (kept_releases, removed_releases) =
+        choose_releases(&versions, gc_args.keep_latest, &gc_args.keep_versions);
+    for (version, reason) in &kept_releases {
+        info!("Keeping release {}: {}", version, reason);
+    }
+
+    let mut parameters: Vec<PlannedParameter> = removed_releases
+        .iter()
+        .flat_map(|version| {
+            versions[version]
+                .iter()
+                .map(move |parameter| PlannedParameter {
+                    region: parameter.key.region.to_string(),
+                    name: parameter.key.name.clone(),
+                    release: version.clone(),
+                })
+        })
+        .collect();
+    parameters.sort_by(|a, b| (&a.region, &a.name).cmp(&(&b.region, &b.name)));
+
+    let mut amis = Vec::new();
+    for ((region, image_id), releases) in amis_to_remove(&versions, &removed_releases) {
+        let ec2_region = region_from_string(&region);
+        let snapshot_ids = match get_snapshots(
+            &image_id,
+            &ec2_region,
+            &ec2_clients[&ec2_region],
+            &rate_limits,
+        )
+        .await
+        {
+            Ok(snapshot_ids) => snapshot_ids,
+            Err(crate::aws::publish_ami::Error::MissingImage { .. }) => {
+                info!("{} in {} was already deregistered", image_id, region);
+                continue;
+            }
+            Err(e) => {
+                return Err(e).context(error::GetSnapshotsSnafu {
+                    image_id,
+                    region: &region,
+                })
+            }
+        };
+        amis.push(PlannedAmi {
+            region,
+            image_id,
+            releases,
+            snapshot_ids,
+        });
+    }
+
+    let mut repo_targets = Vec::new();
+    let mut repo_client = None;
+    if let Some(repo) = &gc_args.repo {
+        let bucket = RepoBucket::from_config(&infra_config, repo).context(error::S3Snafu)?;
+        let client = bucket.client(&aws, &rate_limits).await;
+        info!("Listing the files of repo '{}' in {}", repo, bucket.name);
+        let (unreferenced, _) =
+            find_unreferenced(&client, &bucket, gc_args.keep_latest, gc_args.min_age)
+                .await
+                .context(error::GcRepoSnafu)?;
+        repo_targets = unreferenced
+            .into_iter()
+            .map(|object| PlannedTarget {
+                path: object.path,
+                size: object.size,
+            })
+            .collect();
+        repo_client = Some((client, bucket));
+    }
+
+    let plan = Plan {
+        kept_releases,
+        removed_releases,
+        parameters,
+        amis,
+        repo_targets,
+    };
+    println!("{}", Table::new(plan.rows()));
+    info!(
+        "Removing {} releases: {} SSM parameters, {} AMIs, and {} repo targets",
+        plan.removed_releases.len(),
+        plan.parameters.len(),
+        plan.amis.len(),
+        plan.repo_targets.len()
+    );
+    if let Some(plan_path) = &gc_args.plan_path {
+        serde_json::to_writer_pretty(
+            File::create(plan_path).context(error::WritePlanSnafu { path: plan_path })?,
+            &plan,
+        )
+        .context(error::SerializePlanSnafu)?;
+    }
+
+    if !gc_args.delete {
+        info!("Not deleting anything; pass --delete to carry out the plan");
+        return Ok(());
+    }
+
+    // Delete   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    let keys: Vec<SsmKey> = plan
+        .parameters
+        .iter()
+        .map(|parameter| {
+            SsmKey::new(
+                region_from_string(&parameter.region),
+                parameter.name.clone(),
+            )
+        })
+        .collect();
+    let mut failed =
+        delete_parameters(&args.state.interrupt, &keys, &ssm_clients, &rate_limits).await;
+    if failed > 0 {
+        // The AMIs could still be found through the parameters we failed to delete.
+        return error::FailedDeletionsSnafu { count: failed }.fail();
+    }
+    failed += delete_amis(
+        &args.state.interrupt,
+        &plan.amis,
+        &ec2_clients,
+        &rate_limits,
+    )
+    .await;
+    if let Some((client, bucket)) = repo_client {
+        if !plan.repo_targets.is_empty() && !args.state.interrupt.requested() {
+            let paths: Vec<String> = plan
+                .repo_targets
+                .iter()
+                .map(|target| target.path.clone())
+                .collect();
+            s3::delete_objects(&client, &bucket, &paths)
+                .await
+                .context(error::S3Snafu)?;
+            info!("Deleted {} repo targets", paths.len());
+        }
+    }
+    ensure!(failed == 0, error::FailedDeletionsSnafu { count: failed });
+    Ok(())
+}
+
+/// Deletes the given parameters, in batches per region, and returns how many batches failed.
+pub(crate) async fn delete_parameters(
+    interrupt: &Interrupt,
+    parameters: &[SsmKey],
+    clients: &HashMap<Region, SsmClient>,
+    rate_limits: &RateLimits,
+) -> usize {
+    let mut regional_names: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for parameter in parameters {
+        regional_names
+            .entry(parameter.region.as_ref())
+            .or_default()
+            .push(parameter.name.clone());
+    }
+
+    let mut requests = Vec::new();
+    for (region, names) in &regional_names {
+        let region = region_from_string(region);
+        for (index, batch) in names.chunks(MAX_DELETE_PARAMETERS).enumerate() {
+            let name = format!("batch {} in {}", index + 1, region);
+            let client = &clients[&region];
+            let region = region.clone();
+            let request = async move {
+                rate_limits
+                    .rate_limited(
+                        SSM,
+                        region.as_ref(),
+                        client
+                            .delete_parameters()
+                            .set_names(Some(batch.to_vec()))
+                            .send(),
+                    )
+                    .await
+                    .context(error::DeleteParametersSnafu {
+                        region: region.as_ref(),
+                    })
+            };
+            requests.push((name, request));
+        }
+    }
+
+    let results: Vec<Result<_>> =
+        stream::iter(interrupt.tracked("SSM parameter deletions", requests))
+            .buffer_unordered(MAX_PARALLEL_REGIONS)
+            .collect()
+            .await;
+    count_failures(results)
+}
+
+/// Deregisters the given AMIs, then deletes their snapshots, and returns how many AMIs failed.
+async fn delete_amis(
+    interrupt: &Interrupt,
+    amis: &[PlannedAmi],
+    clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+) -> usize {
+    let requests = amis.iter().map(|ami| {
+        let region = region_from_string(&ami.region);
+        let client = &clients[&region];
+        let request = async move {
+            rate_limits
+                .rate_limited(
+                    EC2,
+                    region.as_ref(),
+                    client.deregister_image().image_id(&ami.image_id).send(),
+                )
+                .await
+                .context(error::DeregisterImageSnafu {
+                    image_id: &ami.image_id,
+                    region: region.as_ref(),
+                })?;
+            // An image's snapshots can't be deleted until it's deregistered.
+            for snapshot_id in &ami.snapshot_ids {
+                rate_limits
+                    .rate_limited(
+                        EC2,
+                        region.as_ref(),
+                        client.delete_snapshot().snapshot_id(snapshot_id).send(),
+                    )
+                    .await
+                    .context(error::DeleteSnapshotSnafu {
+                        snapshot_id,
+                        region: region.as_ref(),
+                    })?;
+            }
+            info!("Deleted {} in {}", ami.image_id, region);
+            Ok(())
+        };
+        (format!("{} in {}", ami.image_id, ami.region), request)
+    });
+
+    let results: Vec<Result<()>> = stream::iter(interrupt.tracked("AMI deletions", requests))
+        .buffer_unordered(MAX_PARALLEL_REGIONS)
+        .collect()
+        .await;
+    count_failures(results)
+}
+
+fn count_failures<T>(results: Vec<Result<T>>) -> usize {
+    let mut failed = 0;
+    for result in results {
+        if let Err(e) = result {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        warn!("{} deletions failed", failed);
+    }
+    failed
+}
+
+mod error {
+    use aws_sdk_ec2::error::{DeleteSnapshotError, DeregisterImageError};
+    use aws_sdk_ssm::error::DeleteParametersError;
+    use aws_sdk_ssm::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to delete SSM parameters in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeleteParameters {
+            region: String,
+            source: SdkError<DeleteParametersError>,
+        },
+
+        #[snafu(display(
+            "Failed to delete snapshot {} in {}: {}",
+            snapshot_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeleteSnapshot {
+            snapshot_id: String,
+            region: String,
+            source: SdkError<DeleteSnapshotError>,
+        },
+
+        #[snafu(display(
+            "Failed to deregister {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeregisterImage {
+            image_id: String,
+            region: String,
+            source: SdkError<DeregisterImageError>,
+        },
+
+        #[snafu(display("Failed to delete {} items; see the errors above", count))]
+        FailedDeletions { count: usize },
+
+        #[snafu(display("Failed to fetch SSM parameters in {}: {}", region, source))]
+        FetchSsm {
+            region: String,
+            source: crate::aws::ssm::ssm::Error,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        GcRepo { source: crate::repo::gc_repo::Error },
+
+        #[snafu(display("Failed to find snapshots of {} in {}: {}", image_id, region, source))]
+        GetSnapshots {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(crate::aws::publish_ami::Error, Box::new)))]
+            source: Box<crate::aws::publish_ami::Error>,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display(
+            "Parameter names from template '{}' aren't under a path like '/prefix/...', so they can't be listed",
+            template
+        ))]
+        NoParameterPath { template: String },
+
+        #[snafu(display("Found no published releases, refusing to delete anything"))]
+        NoReleases,
+
+        #[snafu(display(
+            "No parameter template includes the version, so releases can't be told apart"
+        ))]
+        NoVersionedTemplates,
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display("Failed to serialize plan: {}", source))]
+        SerializePlan { source: serde_json::Error },
+
+        #[snafu(display("Failed to write plan to '{}': {}", path.display(), source))]
+        WritePlan {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::DeleteParameters { .. }
+            | Self::DeleteSnapshot { .. }
+            | Self::DeregisterImage { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{amis_to_remove, choose_releases, group_by_version, NamePattern, Parameter};
+    use crate::aws::ssm::SsmKey;
+    use aws_sdk_ssm::Region;
+    use std::collections::BTreeMap;
+    use std::num::NonZeroUsize;
+
+    fn patterns() -> Vec<NamePattern> {
+        ["image_id", "image_version"]
+            .iter()
+            .map(|name| NamePattern {
+                template: format!("{{variant}}/{{arch}}/{{image_version}}/{}", name),
+                prefix: "/bottlerocket/aws-dev/x86_64/".to_string(),
+                suffix: format!("/{}", name),
+            })
+            .collect()
+    }
+
+    /// Returns the published parameters of the given versions, with their AMI and image version
+    /// in us-west-2.
+    fn published(releases: &[(&str, &str, &str)]) -> BTreeMap<String, Vec<Parameter>> {
+        let parameters = releases.iter().flat_map(|(version, ami, image_version)| {
+            [("image_id", ami), ("image_version", image_version)]
+                .into_iter()
+                .map(move |(name, value)| {
+                    (
+                        SsmKey::new(
+                            Region::new("us-west-2"),
+                            format!("/bottlerocket/aws-dev/x86_64/{}/{}", version, name),
+                        ),
+                        value.to_string(),
+                    )
+                })
+        });
+        group_by_version(&patterns(), parameters)
+    }
+
+    #[test]
+    fn versions_in_names() {
+        let pattern = &patterns()[0];
+        assert_eq!(
+            pattern.version("/bottlerocket/aws-dev/x86_64/1.13.0/image_id"),
+            Some("1.13.0")
+        );
+        assert_eq!(
+            pattern.version("/bottlerocket/aws-dev/x86_64/latest/image_id"),
+            Some("latest")
+        );
+        assert_eq!(
+            pattern.version("/bottlerocket/aws-dev/x86_64/a/b/image_id"),
+            None
+        );
+        assert_eq!(
+            pattern.version("/bottlerocket/aws-dev/x86_64/1.13.0/image_version"),
+            None
+        );
+    }
+
+    #[test]
+    fn keeps_latest_requested_and_pointed_to() {
+        let versions = published(&[
+            ("1.9.0", "ami-9", "1.9.0"),
+            ("1.10.0", "ami-10", "1.10.0"),
+            ("1.11.0", "ami-11", "1.11.0"),
+            ("1.12.0", "ami-12", "1.12.0"),
+            ("1.13.0", "ami-13", "1.13.0"),
+            ("latest", "ami-10", "1.10.0"),
+        ]);
+        let (kept, removed) = choose_releases(
+            &versions,
+            NonZeroUsize::new(2).unwrap(),
+            &["1.11.0".to_string()],
+        );
+        assert_eq!(
+            kept.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("1.10.0".to_string(), "'latest' refers to it".to_string()),
+                ("1.11.0".to_string(), "requested".to_string()),
+                ("1.12.0".to_string(), "one of the latest 2".to_string()),
+                ("1.13.0".to_string(), "one of the latest 2".to_string()),
+            ]
+        );
+        assert_eq!(removed, vec!["1.9.0".to_string()]);
+    }
+
+    #[test]
+    fn shared_amis_kept() {
+        // A rebuilt release that reused the AMI of an older one
+        let versions = published(&[
+            ("1.9.0", "ami-9", "1.9.0"),
+            ("1.10.0", "ami-10", "1.10.0"),
+            ("1.10.1", "ami-10", "1.10.1"),
+        ]);
+        let (_, removed) = choose_releases(&versions, NonZeroUsize::new(1).unwrap(), &[]);
+        assert_eq!(removed, vec!["1.9.0".to_string(), "1.10.0".to_string()]);
+        let amis = amis_to_remove(&versions, &removed);
+        assert_eq!(
+            amis.keys().cloned().collect::<Vec<_>>(),
+            vec![("us-west-2".to_string(), "ami-9".to_string())]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! The gcloud module handles the process of building and executing calls to the Google Cloud CLI,
+//! `gcloud`, which uses the credentials from `gcloud auth login` or the usual application default
+//! credentials.
+use duct::cmd;
+use log::trace;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt};
+use std::path::Path;
+
+/// The role that lets a member create instances from an image
+const IMAGE_USER_ROLE: &str = "roles/compute.imageUser";
+
+/// The parts of a Compute Engine image we record
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Image {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) self_link: String,
+    #[serde(default)]
+    pub(crate) family: Option<String>,
+}
+
+/// What to create an image from, and how
+pub(crate) struct ImageSpec<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) description: Option<&'a str>,
+    pub(crate) family: Option<&'a str>,
+    pub(crate) source_uri: &'a str,
+    pub(crate) architecture: &'a str,
+    pub(crate) guest_os_features: &'a [String],
+    pub(crate) storage_location: Option<&'a str>,
+}
+
+pub(crate) struct Gcloud {
+    project: Option<String>,
+}
+
+impl Gcloud {
+    const GCLOUD: &'static str = "gcloud";
+
+    /// Make a new instance of `Gcloud` that uses the given project, or the CLI's default.
+    pub(crate) fn new(project: Option<String>) -> Self {
+        Self { project }
+    }
+
+    /// Uploads the file at the given path to the given `gs://` URI.
+    pub(crate) fn upload<P>(&self, path: P, uri: &str) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().display().to_string();
+        self.run(&["storage", "cp", &path, uri]).map(drop)
+    }
+
+    /// Returns the image with the given name, if it exists.
+    pub(crate) fn describe_image(&self, name: &str) -> Result<Option<Image>> {
+        match self.run_json(&["compute", "images", "describe", name]) {
+            Ok(image) => Ok(Some(image)),
+            Err(Error::Gcloud { ref output, .. }) if output.contains("was not found") => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates an image from the given spec and waits for it to be ready.
+    pub(crate) fn create_image(&self, spec: &ImageSpec<'_>) -> Result<Image> {
+        let guest_os_features = spec.guest_os_features.join(",");
+        let mut args = vec![
+            "compute",
+            "images",
+            "create",
+            spec.name,
+            "--source-uri",
+            spec.source_uri,
+            "--architecture",
+            spec.architecture,
+        ];
+        if !guest_os_features.is_empty() {
+            args.extend(["--guest-os-features", &guest_os_features]);
+        }
+        if let Some(description) = spec.description {
+            args.extend(["--description", description]);
+        }
+        if let Some(family) = spec.family {
+            args.extend(["--family", family]);
+        }
+        if let Some(storage_location) = spec.storage_location {
+            args.extend(["--storage-location", storage_location]);
+        }
+
+        // `create` returns a list of the images it created, which is just ours.
+        let mut images: Vec<Image> = self.run_json(&args)?;
+        ensure!(
+            images.len() == 1,
+            error::UnexpectedOutputSnafu {
+                command: args.join(" "),
+                count: images.len(),
+            }
+        );
+        Ok(images.remove(0))
+    }
+
+    /// Lets the given IAM member, like "allAuthenticatedUsers" or "projectViewer:my-project",
+    /// create instances from the image.
+    pub(crate) fn grant_image_user(&self, image: &str, member: &str) -> Result<()> {
+        self.run(&[
+            "compute",
+            "images",
+            "add-iam-policy-binding",
+            image,
+            "--member",
+            member,
+            "--role",
+            IMAGE_USER_ROLE,
+        ])
+        .map(drop)
+    }
+
+    /// Runs `gcloud` with the given arguments, parsing its output as JSON.
+    fn run_json<T>(&self, args: &[&str]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let output = self.run(args)?;
+        serde_json::from_str(&output).context(error::ParseOutputSnafu {
+            command: args.join(" "),
+        })
+    }
+
+    /// Runs `gcloud` with the given arguments and returns its output.
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let mut gcloud_args = args.to_vec();
+        gcloud_args.extend(["--format", "json", "--quiet"]);
+        if let Some(project) = &self.project {
+            gcloud_args.extend(["--project", project.as_str()]);
+        }
+        trace!("Running {} {}", Self::GCLOUD, gcloud_args.join(" "));
+
+        // Progress and status messages go to stderr, so only capture it in case of failure.
+        let output = cmd(Self::GCLOUD, gcloud_args)
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        trace!("{}", stdout);
+        ensure!(
+            output.status.success(),
+            error::GcloudSnafu {
+                command: args.join(" "),
+                output: String::from_utf8_lossy(&output.stderr).to_string(),
+            }
+        );
+        Ok(stdout)
+    }
+}
+
+pub(crate) mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to start command: {}", source))]
+        CommandStart { source: std::io::Error },
+
+        #[snafu(display("'gcloud {}' failed: {}", command, output))]
+        Gcloud { command: String, output: String },
+
+        #[snafu(display("Failed to parse output of 'gcloud {}': {}", command, source))]
+        ParseOutput {
+            command: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Expected 'gcloud {}' to return 1 image, got {}", command, count))]
+        UnexpectedOutput { command: String, count: usize },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
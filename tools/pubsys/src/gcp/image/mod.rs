@@ -0,0 +1,268 @@
+//! The image module owns the 'gcp-image' subcommand, which publishes a Bottlerocket image to
+//! Compute Engine the way the 'ami' and 'publish-ami' subcommands do for EC2: it uploads the image
+//! tarball to the Cloud Storage bucket in Infra.toml, creates an image from it, and grants access
+//! to it, either to everyone or to specific projects.  Like `pubsys ami` does with amis.json, it can
+//! write the result to a JSON file for later steps.
+//!
+//! Running it again for an image that already exists skips the upload and creation, and only
+//! makes sure the requested access is granted.
+use crate::exit_code::{Classify, Kind};
+use crate::gcp::gcloud::{Gcloud, Image, ImageSpec};
+use crate::Args;
+use log::{info, trace};
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt};
+use std::fs::File;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// The IAM member that means any signed-in Google account
+const PUBLIC_MEMBER: &str = "allAuthenticatedUsers";
+
+/// Creates a Compute Engine image from a Bottlerocket image tarball
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct GcpImageArgs {
+    /// Path to the image tarball, a .tar.gz holding disk.raw
+    #[structopt(long, parse(from_os_str))]
+    image: PathBuf,
+
+    /// The architecture of the image
+    #[structopt(long)]
+    arch: String,
+
+    /// The desired image name; GCP allows lowercase letters, digits, and hyphens
+    #[structopt(long)]
+    name: String,
+
+    /// The desired image description
+    #[structopt(long)]
+    description: Option<String>,
+
+    /// The image family to add the image to, in place of gcp.family in Infra.toml
+    #[structopt(long)]
+    family: Option<String>,
+
+    /// Comma-separated guest OS features to enable on the image
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "UEFI_COMPATIBLE,VIRTIO_SCSI_MULTIQUEUE,GVNIC"
+    )]
+    guest_os_features: Vec<String>,
+
+    /// Make the image usable by anyone, rather than only the configured projects
+    #[structopt(long)]
+    make_public: bool,
+
+    /// Comma-separated list of projects to share the image with, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    share_with_projects: Vec<String>,
+
+    /// If specified, save the published image in JSON format to this file, like amis.json
+    #[structopt(long, parse(from_os_str))]
+    image_output: Option<PathBuf>,
+}
+
+/// What we record about a published image
+#[derive(Debug, Serialize)]
+pub(crate) struct PublishedImage {
+    pub(crate) project: Option<String>,
+    #[serde(flatten)]
+    pub(crate) image: Image,
+    pub(crate) public: bool,
+    pub(crate) shared_with_projects: Vec<String>,
+}
+
+/// Returns the architecture name Compute Engine uses for the given architecture.
+fn gcp_arch(arch: &str) -> Result<&'static str> {
+    match arch {
+        "x86_64" | "amd64" => Ok("X86_64"),
+        "aarch64" | "arm64" => Ok("ARM64"),
+        _ => error::UnknownArchSnafu { arch }.fail(),
+    }
+}
+
+/// Returns the IAM members that should be able to use the image.  Projects are granted access
+/// through their viewers, which includes the service accounts that create instances in them.
+fn iam_members(make_public: bool, projects: &[String]) -> Vec<String> {
+    let mut members: Vec<String> = projects
+        .iter()
+        .map(|project| format!("projectViewer:{}", project))
+        .collect();
+    if make_public {
+        members.push(PUBLIC_MEMBER.to_string());
+    }
+    members
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, image_args: &GcpImageArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let gcp = infra_config
+        .gcp
+        .context(error::MissingConfigSnafu { missing: "gcp" })?;
+    let bucket = gcp.bucket.as_deref().context(error::MissingConfigSnafu {
+        missing: "gcp.bucket",
+    })?;
+    let architecture = gcp_arch(&image_args.arch)?;
+
+    // If the user gave an override list of projects, use that, otherwise use what's in the config
+    let projects = if !image_args.share_with_projects.is_empty() {
+        image_args.share_with_projects.clone()
+    } else {
+        gcp.share_with_projects.clone()
+    };
+
+    let gcloud = Gcloud::new(gcp.project.clone());
+    let image =
+        match gcloud
+            .describe_image(&image_args.name)
+            .context(error::DescribeImageSnafu {
+                name: &image_args.name,
+            })? {
+            Some(image) => {
+                info!(
+                    "Found image {} ({}), skipping upload and creation",
+                    image.name, image.id
+                );
+                image
+            }
+            None => {
+                let source_uri = format!("gs://{}/{}.tar.gz", bucket, image_args.name);
+                info!("Uploading {} to {}", image_args.image.display(), source_uri);
+                gcloud
+                    .upload(&image_args.image, &source_uri)
+                    .context(error::UploadSnafu { uri: &source_uri })?;
+
+                info!("Creating image {}", image_args.name);
+                let spec = ImageSpec {
+                    name: &image_args.name,
+                    description: image_args.description.as_deref(),
+                    family: image_args.family.as_deref().or(gcp.family.as_deref()),
+                    source_uri: &source_uri,
+                    architecture,
+                    guest_os_features: &image_args.guest_os_features,
+                    storage_location: gcp.storage_location.as_deref(),
+                };
+                let image = gcloud
+                    .create_image(&spec)
+                    .context(error::CreateImageSnafu {
+                        name: &image_args.name,
+                    })?;
+                info!("Created image {} ({})", image.name, image.id);
+                image
+            }
+        };
+
+    for member in iam_members(image_args.make_public, &projects) {
+        info!("Granting {} use of {}", member, image.name);
+        gcloud
+            .grant_image_user(&image.name, &member)
+            .context(error::GrantAccessSnafu {
+                name: &image.name,
+                member: &member,
+            })?;
+    }
+
+    if let Some(image_output) = &image_args.image_output {
+        let published = PublishedImage {
+            project: gcp.project.clone(),
+            image,
+            public: image_args.make_public,
+            shared_with_projects: projects,
+        };
+        let file = File::create(image_output).context(error::FileSnafu { path: image_output })?;
+        serde_json::to_writer_pretty(file, &published).context(error::SerializeSnafu)?;
+        info!("Wrote image data to {}", image_output.display());
+    }
+
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to create image {}: {}", name, source))]
+        CreateImage {
+            name: String,
+            source: crate::gcp::gcloud::Error,
+        },
+
+        #[snafu(display("Failed to look for image {}: {}", name, source))]
+        DescribeImage {
+            name: String,
+            source: crate::gcp::gcloud::Error,
+        },
+
+        #[snafu(display("Failed to create '{}': {}", path.display(), source))]
+        File {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to grant {} use of image {}: {}", member, name, source))]
+        GrantAccess {
+            name: String,
+            member: String,
+            source: crate::gcp::gcloud::Error,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to serialize image data: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Unknown architecture '{}'", arch))]
+        UnknownArch { arch: String },
+
+        #[snafu(display("Failed to upload image to {}: {}", uri, source))]
+        Upload {
+            uri: String,
+            source: crate::gcp::gcloud::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gcp_arch, iam_members};
+
+    #[test]
+    fn architectures() {
+        assert_eq!(gcp_arch("x86_64").unwrap(), "X86_64");
+        assert_eq!(gcp_arch("arm64").unwrap(), "ARM64");
+        assert!(gcp_arch("riscv64").is_err());
+    }
+
+    #[test]
+    fn members() {
+        let projects = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            iam_members(false, &projects),
+            vec!["projectViewer:a", "projectViewer:b"]
+        );
+        assert_eq!(iam_members(true, &[]), vec!["allAuthenticatedUsers"]);
+    }
+}
@@ -0,0 +1,2 @@
+pub(crate) mod gcloud;
+pub(crate) mod image;
@@ -0,0 +1,195 @@
+//! The interrupt module lets a subcommand stop cleanly on Ctrl-C.  After the first interrupt,
+//! requests that haven't started yet aren't sent, requests already in flight are allowed to
+//! finish, and the subcommand reports which of its work was done and which wasn't, so a release
+//! isn't left half-written with no record of where it stopped.  A second interrupt exits
+//! immediately.
+//!
+//! Work is tracked by wrapping the requests a subcommand would fan out, like the AMI copies to
+//! each region or the SSM parameters to set, with `Interrupt::tracked`.  Each run has its own
+//! `Interrupt`, which only a Ctrl-C listener installed for that run sets.
+
+use crate::exit_code;
+use log::warn;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::runtime;
+
+/// Whether a run has been interrupted, and the work it's tracked so far, by kind, in the order
+/// each kind was first tracked.  Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Interrupt {
+    interrupted: Arc<AtomicBool>,
+    work: Arc<Mutex<Vec<(&'static str, Work)>>>,
+}
+
+/// Starts listening for Ctrl-C in the background, so that the run with the given interrupt state
+/// can stop cleanly.
+pub(crate) fn install(interrupt: &Interrupt) -> std::io::Result<()> {
+    // Subcommands make and drop their own async runtimes, so the listener gets one of its own.
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let interrupted = Arc::clone(&interrupt.interrupted);
+    thread::spawn(move || {
+        rt.block_on(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            interrupted.store(true, Ordering::SeqCst);
+            warn!(
+                "Interrupted; waiting for requests in flight to finish.  Press Ctrl-C again to exit immediately."
+            );
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("Interrupted again; exiting without waiting");
+                process::exit(exit_code::INTERRUPTED);
+            }
+        })
+    });
+    Ok(())
+}
+
+impl Interrupt {
+    /// Returns whether the user has interrupted the run.
+    pub(crate) fn requested(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Returns the given named requests, such that each is recorded as done when it finishes, and
+    /// none are returned after an interrupt.  The requests should be run lazily, like with
+    /// `buffer_unordered`, so that only the ones in flight at the interrupt are run.  `kind`
+    /// describes the work, like "AMI copies", and is shared by every call for the same kind of
+    /// work.
+    pub(crate) fn tracked<I, F>(
+        &self,
+        kind: &'static str,
+        requests: I,
+    ) -> impl Iterator<Item = impl Future<Output = F::Output>>
+    where
+        I: IntoIterator<Item = (String, F)>,
+        F: Future,
+    {
+        let requests: Vec<(String, F)> = requests.into_iter().collect();
+        self.with_work(kind, |work| {
+            for (name, _) in &requests {
+                work.start(name);
+            }
+        });
+        let (checked, finished) = (self.clone(), self.clone());
+        requests
+            .into_iter()
+            .take_while(move |_| !checked.requested())
+            .map(move |(name, request)| {
+                let interrupt = finished.clone();
+                async move {
+                    let output = request.await;
+                    interrupt.with_work(kind, |work| work.finish(&name));
+                    output
+                }
+            })
+    }
+
+    /// Logs how much of each kind of tracked work was done, and lists what wasn't.
+    pub(crate) fn report(&self) {
+        if let Ok(work) = self.work.lock() {
+            for (kind, work) in work.iter() {
+                warn!("{}", work.summary(kind));
+            }
+        }
+    }
+
+    /// Runs the given function on the tracked work of the given kind.
+    fn with_work(&self, kind: &'static str, f: impl FnOnce(&mut Work)) {
+        if let Ok(mut all_work) = self.work.lock() {
+            match all_work.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, work)) => f(work),
+                None => {
+                    let mut work = Work::default();
+                    f(&mut work);
+                    all_work.push((kind, work));
+                }
+            }
+        }
+    }
+}
+
+/// The names of the requests of one kind that are done, and of those that aren't
+#[derive(Debug, Default)]
+struct Work {
+    done: BTreeSet<String>,
+    pending: BTreeSet<String>,
+}
+
+impl Work {
+    /// Records a request as pending; a request that's retried is pending again.
+    fn start(&mut self, name: &str) {
+        self.done.remove(name);
+        self.pending.insert(name.to_string());
+    }
+
+    fn finish(&mut self, name: &str) {
+        self.pending.remove(name);
+        self.done.insert(name.to_string());
+    }
+
+    fn summary(&self, kind: &str) -> String {
+        let mut summary = format!(
+            "{}: {} done, {} not done",
+            kind,
+            self.done.len(),
+            self.pending.len()
+        );
+        if !self.pending.is_empty() {
+            let pending: Vec<&str> = self.pending.iter().map(String::as_str).collect();
+            summary.push_str(&format!(" ({})", pending.join(", ")));
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Interrupt, Work};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn summary() {
+        let mut work = Work::default();
+        for region in ["us-west-2", "us-east-1", "eu-west-1"] {
+            work.start(region);
+        }
+        work.finish("us-west-2");
+        assert_eq!(
+            work.summary("AMI copies"),
+            "AMI copies: 1 done, 2 not done (eu-west-1, us-east-1)"
+        );
+
+        // A retried request isn't done until it finishes again.
+        work.start("us-west-2");
+        work.finish("us-east-1");
+        work.finish("eu-west-1");
+        assert_eq!(
+            work.summary("AMI copies"),
+            "AMI copies: 2 done, 1 not done (us-west-2)"
+        );
+
+        work.finish("us-west-2");
+        assert_eq!(work.summary("AMI copies"), "AMI copies: 3 done, 0 not done");
+    }
+
+    #[test]
+    fn runs_are_separate() {
+        let (first, second) = (Interrupt::default(), Interrupt::default());
+        first.interrupted.store(true, Ordering::SeqCst);
+        assert!(first.clone().requested());
+        assert!(!second.requested());
+
+        // Only the interrupted run stops sending requests.
+        let requests = || vec![("us-west-2".to_string(), async {})];
+        assert_eq!(first.tracked("AMI copies", requests()).count(), 0);
+        assert_eq!(second.tracked("AMI copies", requests()).count(), 1);
+    }
+}
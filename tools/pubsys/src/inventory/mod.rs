@@ -0,0 +1,601 @@
+//! The inventory module owns the 'inventory' subcommand, which lists everything published for a
+//! variant and arch, for one version or for all of them, in one file:
+//!
+//! * every SSM parameter under the variant and arch's SSM prefix, in each region, with the version
+//!   it belongs to, found through the parameter templates as the 'gc' subcommand does
+//! * every AMI those parameters refer to, with its name and whether it's public
+//! * if a repo is given, the versions of the repo's metadata roles and the updates in its
+//!   manifest.json for the variant and arch
+//!
+//! The inventory is written as JSON, or as CSV with one row per item.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::RateLimits;
+use crate::aws::ssm::{ssm, template, BuildContext, SsmKey};
+use crate::aws::validate_ami::ami::{describe_images_in_region, ImageDef};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::gc::{name_patterns, NamePattern, VERSION_MARKER};
+use crate::progress::progress_bar;
+use crate::repo::diff_repo::{load_repo, role_versions};
+use crate::repo::repo_urls;
+use crate::{friendly_version, Args};
+use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use log::{info, trace};
+use pubsys_config::{AwsConfig, InfraConfig};
+use semver::Version;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+
+/// Lists the published AMIs, SSM parameters, and repo metadata of a variant and arch
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct InventoryArgs {
+    /// The variant to list
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture to list
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// File holding the parameter templates the versions were published with
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Only list this version, as given to the 'ssm' subcommand; otherwise, all are listed
+    #[structopt(long)]
+    version: Option<String>,
+
+    /// Comma-separated list of regions to list, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// Also list the metadata of this named repo from Infra.toml
+    #[structopt(long, requires = "root-role-path")]
+    repo: Option<String>,
+
+    /// Path to root.json for the repo
+    #[structopt(long, parse(from_os_str))]
+    root_role_path: Option<PathBuf>,
+
+    /// How to write the inventory: "json" or "csv"
+    #[structopt(long, default_value = "json", possible_values = &["json", "csv"])]
+    format: String,
+
+    /// Write the inventory to this path, rather than to stdout
+    #[structopt(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// A published SSM parameter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct InventoryParameter {
+    region: String,
+    name: String,
+    value: String,
+    /// The version in the parameter's name, like "1.13.0" or "latest"; None if the name didn't
+    /// come from a template with a version
+    version: Option<String>,
+}
+
+/// A published AMI
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct InventoryAmi {
+    region: String,
+    image_id: String,
+    /// The versions whose parameters refer to the AMI
+    versions: BTreeSet<String>,
+    /// The AMI's name; None if it wasn't found in EC2
+    name: Option<String>,
+    public: Option<bool>,
+}
+
+/// A repo's metadata
+#[derive(Debug, Serialize)]
+struct InventoryRepo {
+    name: String,
+    metadata_url: String,
+    /// The version of each metadata role, including delegated roles
+    roles: BTreeMap<String, u64>,
+    /// The versions of the variant and arch's updates in manifest.json
+    updates: Vec<String>,
+}
+
+/// Everything published for the variant and arch
+#[derive(Debug, Serialize)]
+struct Inventory {
+    variant: String,
+    arch: String,
+    version: Option<String>,
+    parameters: Vec<InventoryParameter>,
+    amis: Vec<InventoryAmi>,
+    repo: Option<InventoryRepo>,
+}
+
+impl Inventory {
+    /// Writes the inventory as CSV, one row per item.
+    fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "kind,region,version,id,value,public")?;
+        let mut row = |fields: [&str; 6]| {
+            let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(writer, "{}", fields.join(","))
+        };
+        for parameter in &self.parameters {
+            row([
+                "ssm-parameter",
+                &parameter.region,
+                parameter.version.as_deref().unwrap_or_default(),
+                &parameter.name,
+                &parameter.value,
+                "",
+            ])?;
+        }
+        for ami in &self.amis {
+            let versions: Vec<&str> = ami.versions.iter().map(String::as_str).collect();
+            let public = ami
+                .public
+                .map(|public| public.to_string())
+                .unwrap_or_default();
+            row([
+                "ami",
+                &ami.region,
+                &versions.join(" "),
+                &ami.image_id,
+                ami.name.as_deref().unwrap_or_default(),
+                &public,
+            ])?;
+        }
+        if let Some(repo) = &self.repo {
+            for (role, version) in &repo.roles {
+                row([
+                    "repo-role",
+                    "",
+                    "",
+                    &format!("{} {}", repo.name, role),
+                    &version.to_string(),
+                    "",
+                ])?;
+            }
+            for update in &repo.updates {
+                row(["repo-update", "", update, &repo.name, "", ""])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Returns the published parameters, each with the version in its name, leaving out those not in
+/// the requested version, if one was given.
+fn inventory_parameters(
+    patterns: &[NamePattern],
+    published: impl IntoIterator<Item = (SsmKey, String)>,
+    version: Option<&str>,
+) -> Vec<InventoryParameter> {
+    let mut parameters: Vec<InventoryParameter> = published
+        .into_iter()
+        .map(|(key, value)| InventoryParameter {
+            version: patterns
+                .iter()
+                .find_map(|pattern| pattern.version(&key.name))
+                .map(str::to_string),
+            region: key.region.to_string(),
+            name: key.name,
+            value,
+        })
+        .filter(|parameter| version.is_none() || parameter.version.as_deref() == version)
+        .collect();
+    parameters.sort_by(|a, b| (&a.region, &a.name).cmp(&(&b.region, &b.name)));
+    parameters
+}
+
+/// Returns the AMIs the parameters refer to, by region and ID, with the versions referring to each.
+fn referenced_amis(
+    parameters: &[InventoryParameter],
+) -> BTreeMap<(String, String), BTreeSet<String>> {
+    let mut amis: BTreeMap<(String, String), BTreeSet<String>> = BTreeMap::new();
+    for parameter in parameters
+        .iter()
+        .filter(|parameter| parameter.value.starts_with("ami-"))
+    {
+        let versions = amis
+            .entry((parameter.region.clone(), parameter.value.clone()))
+            .or_default();
+        if let Some(version) = &parameter.version {
+            versions.insert(version.clone());
+        }
+    }
+    amis
+}
+
+/// Lists the SSM parameters and AMIs of the variant and arch.
+async fn list_aws(
+    inventory_args: &InventoryArgs,
+    aws: &AwsConfig,
+) -> Result<(Vec<InventoryParameter>, Vec<InventoryAmi>)> {
+    let ssm_prefix = aws.ssm_prefix_for(&inventory_args.variant, inventory_args.arch.as_ref());
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config
+    // for this subcommand.
+    let regions: Vec<Region> = if !inventory_args.regions.is_empty() {
+        inventory_args.regions.clone()
+    } else {
+        aws.regions_for("inventory").clone().into()
+    }
+    .iter()
+    .map(|name| region_from_string(name))
+    .collect();
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let rate_limits = RateLimits::new(aws);
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, aws).await;
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, aws),
+        );
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, aws),
+        );
+    }
+
+    let build_context = BuildContext {
+        variant: &inventory_args.variant,
+        arch: inventory_args.arch.as_str(),
+        image_version: VERSION_MARKER,
+    };
+    info!(
+        "Parsing SSM parameter templates from {}",
+        inventory_args.template_path.display()
+    );
+    let template_parameters =
+        template::get_parameters(&inventory_args.template_path, &build_context)
+            .context(error::FindTemplatesSnafu)?;
+    let patterns =
+        name_patterns(&template_parameters, ssm_prefix, &build_context).context(error::GcSnafu)?;
+
+    info!("Listing SSM parameters under {}", ssm_prefix);
+    let progress_bar = progress_bar(
+        inventory_args.no_progress,
+        ssm_clients.len(),
+        "Listing parameters",
+    );
+    let mut published = Vec::new();
+    for (region, result) in ssm::get_parameters_by_prefix(
+        &ssm_clients,
+        &rate_limits,
+        page_size::get(aws, |page_size| page_size.get_parameters_by_path),
+        ssm_prefix,
+        &progress_bar,
+    )
+    .await
+    {
+        // A region we can't read would leave a hole in the inventory, so we stop.
+        published.extend(result.context(error::FetchSsmSnafu {
+            region: region.as_ref(),
+        })?);
+    }
+    let parameters = inventory_parameters(&patterns, published, inventory_args.version.as_deref());
+
+    let mut regional_amis: BTreeMap<String, Vec<(String, BTreeSet<String>)>> = BTreeMap::new();
+    for ((region, image_id), versions) in referenced_amis(&parameters) {
+        regional_amis
+            .entry(region)
+            .or_default()
+            .push((image_id, versions));
+    }
+    let mut amis = Vec::new();
+    for (region, region_amis) in regional_amis {
+        let ec2_region = region_from_string(&region);
+        // Treating the images as public means we don't fetch launch permissions we don't list.
+        let expected = region_amis
+            .iter()
+            .map(|(image_id, _)| {
+                (
+                    image_id.clone(),
+                    ImageDef {
+                        id: image_id.clone(),
+                        name: String::new(),
+                        public: true,
+                        launch_permissions: None,
+                        ena_support: true,
+                        sriov_net_support: String::new(),
+                    },
+                )
+            })
+            .collect();
+        let found = describe_images_in_region(
+            &ec2_region,
+            &ec2_clients[&ec2_region],
+            &rate_limits,
+            page_size::get(aws, |page_size| page_size.describe_images),
+            expected,
+        )
+        .await
+        .context(error::DescribeImagesSnafu { region: &region })?;
+        for (image_id, versions) in region_amis {
+            let image = found.get(&image_id);
+            amis.push(InventoryAmi {
+                region: region.clone(),
+                image_id,
+                versions,
+                name: image.map(|image| image.name.clone()),
+                public: image.map(|image| image.public),
+            });
+        }
+    }
+
+    Ok((parameters, amis))
+}
+
+/// Lists the repo's metadata versions and the variant and arch's updates.
+fn list_repo(
+    inventory_args: &InventoryArgs,
+    infra_config: &InfraConfig,
+    repo: &str,
+    root_role_path: &Path,
+) -> Result<InventoryRepo> {
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .and_then(|repo_section| repo_section.get(repo))
+        .context(error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", repo),
+        })?;
+    let (metadata_url, targets_url) = repo_urls(
+        repo_config,
+        &inventory_args.variant,
+        inventory_args.arch.as_ref(),
+    )
+    .context(error::RepoSnafu)?
+    .context(error::MissingConfigSnafu {
+        missing: format!("metadata_base_url and targets_url for repo {}", repo),
+    })?;
+
+    info!("Loading TUF repo from {}", metadata_url);
+    let (loaded, manifest) =
+        load_repo(root_role_path, &metadata_url, &targets_url).context(error::LoadRepoSnafu)?;
+
+    // Updates are listed by their version without the build, like "1.13.0".
+    let version = inventory_args
+        .version
+        .as_deref()
+        .and_then(|version| friendly_version(version).ok())
+        .map(|version| Version::new(version.major, version.minor, version.patch));
+    let updates = manifest
+        .updates
+        .iter()
+        .filter(|update| {
+            update.variant == inventory_args.variant
+                && update.arch == inventory_args.arch.as_str()
+                && (inventory_args.version.is_none() || Some(&update.version) == version.as_ref())
+        })
+        .map(|update| update.version.to_string())
+        .collect();
+
+    Ok(InventoryRepo {
+        name: repo.to_string(),
+        metadata_url: metadata_url.to_string(),
+        roles: role_versions(&loaded)
+            .into_iter()
+            .map(|(role, version)| (role, version.get()))
+            .collect(),
+        updates,
+    })
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, inventory_args: &InventoryArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    // The repo is loaded with blocking requests, which can't run inside the async runtime.
+    let repo = match (&inventory_args.repo, &inventory_args.root_role_path) {
+        (Some(repo), Some(root_role_path)) => Some(list_repo(
+            inventory_args,
+            &infra_config,
+            repo,
+            root_role_path,
+        )?),
+        _ => None,
+    };
+
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    let (parameters, amis) = rt.block_on(list_aws(inventory_args, &aws))?;
+    info!(
+        "Found {} SSM parameters and {} AMIs",
+        parameters.len(),
+        amis.len()
+    );
+
+    let inventory = Inventory {
+        variant: inventory_args.variant.clone(),
+        arch: inventory_args.arch.as_str().to_string(),
+        version: inventory_args.version.clone(),
+        parameters,
+        amis,
+        repo,
+    };
+
+    let mut writer: Box<dyn Write> = match &inventory_args.output {
+        Some(output) => Box::new(File::create(output).context(error::WriteSnafu { path: output })?),
+        None => Box::new(io::stdout()),
+    };
+    let path = inventory_args
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("stdout"));
+    if inventory_args.format == "csv" {
+        inventory
+            .write_csv(&mut writer)
+            .context(error::WriteSnafu { path })?;
+    } else {
+        serde_json::to_writer_pretty(&mut writer, &inventory).context(error::SerializeSnafu)?;
+        writeln!(writer).context(error::WriteSnafu { path })?;
+    }
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to describe AMIs in {}: {}", region, source))]
+        DescribeImages {
+            region: String,
+            source: crate::aws::validate_ami::ami::Error,
+        },
+
+        #[snafu(display("Failed to list SSM parameters in {}: {}", region, source))]
+        FetchSsm {
+            region: String,
+            source: crate::aws::ssm::ssm::Error,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Gc {
+            #[snafu(source(from(crate::gc::Error, Box::new)))]
+            source: Box<crate::gc::Error>,
+        },
+
+        #[snafu(display("Failed to load repo: {}", source))]
+        LoadRepo {
+            #[snafu(source(from(crate::repo::diff_repo::Error, Box::new)))]
+            source: Box<crate::repo::diff_repo::Error>,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Failed to serialize inventory: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to write inventory to '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_field, inventory_parameters, referenced_amis};
+    use crate::aws::ssm::{template::TemplateParameters, BuildContext, SsmKey};
+    use crate::gc::{name_patterns, VERSION_MARKER};
+    use aws_sdk_ssm::Region;
+
+    fn key(name: &str) -> SsmKey {
+        SsmKey::new(Region::new("us-west-2"), name.to_string())
+    }
+
+    #[test]
+    fn lists_parameters_by_version() {
+        let template_parameters: TemplateParameters = toml::from_str(
+            r#"
+            [[parameter]]
+            name = "{image_version}/image_id"
+            value = "{image_id}"
+            "#,
+        )
+        .unwrap();
+        let build_context = BuildContext {
+            variant: "aws-k8s-1.24",
+            arch: "x86_64",
+            image_version: VERSION_MARKER,
+        };
+        let patterns = name_patterns(&template_parameters, "/a/b", &build_context).unwrap();
+        let published = vec![
+            (key("/a/b/1.13.0/image_id"), "ami-1".to_string()),
+            (key("/a/b/latest/image_id"), "ami-1".to_string()),
+            (key("/a/b/1.12.0/image_id"), "ami-0".to_string()),
+            (key("/a/b/other"), "x".to_string()),
+        ];
+
+        let all = inventory_parameters(&patterns, published.clone(), None);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3].name, "/a/b/other");
+        assert_eq!(all[3].version, None);
+        let amis = referenced_amis(&all);
+        assert_eq!(amis.len(), 2);
+        assert_eq!(
+            amis[&("us-west-2".to_string(), "ami-1".to_string())],
+            ["1.13.0".to_string(), "latest".to_string()].into()
+        );
+
+        let one = inventory_parameters(&patterns, published, Some("1.13.0"));
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].value, "ami-1");
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("ami-1"), "ami-1");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
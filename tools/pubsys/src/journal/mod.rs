@@ -0,0 +1,457 @@
+//! The journal module lets a long-running subcommand pick up where an earlier run stopped, rather
+//! than starting over.  With `--resume <journal>`, each unit of work that finishes, like an AMI
+//! copy to a region, an SSM parameter set in a region, or a repo file uploaded, is appended to the
+//! journal, and a later run with the same journal skips the units it records.
+//!
+//! The journal is a local file or an `s3://` URL.  The first line records the subcommand and a
+//! digest of its arguments, so a journal can't be resumed by a run that would do different work;
+//! each later line is one finished unit, recorded under the same kind and name that
+//! `Interrupt::tracked` reports it by, sometimes with a detail, like the ID of the copied AMI.
+//!
+//! Lines are appended as the work finishes, so a local journal survives a crash.  A journal in S3
+//! is kept in a local copy and uploaded when the subcommand stops, whether or not it succeeded.
+
+use crate::exit_code::{Classify, Kind};
+use crate::remote_config::default_s3_client;
+use crate::Args;
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use log::{info, warn};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// The first line of a journal, identifying the run it belongs to
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+struct Header {
+    subcommand: String,
+    /// The SHA-256 digest of the subcommand's arguments and environment
+    args_sha256: String,
+}
+
+/// A finished unit of work
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+struct Entry {
+    /// The kind of work, like "AMI copies"
+    kind: String,
+    /// The unit of work, like the region an AMI was copied to
+    name: String,
+    /// Anything a resumed run needs to know about the unit, like the ID of the copied AMI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+/// The finished units of work, and what was recorded about them, by kind and name
+type Done = HashMap<(String, String), Option<String>>;
+
+/// The journal a run records its finished work in, if its subcommand was given one.  Clones record
+/// into the same journal.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Journal {
+    open: Arc<Mutex<Option<OpenJournal>>>,
+}
+
+#[derive(Debug)]
+struct OpenJournal {
+    /// The local journal, or the local copy of one in S3, which entries are appended to
+    file: File,
+    done: Done,
+    /// If the journal is in S3, its URL, and the directory holding the local copy
+    remote: Option<(Url, TempDir)>,
+    profile: Option<String>,
+}
+
+/// If the arguments name a journal, loads the work it records as done and starts recording.  A
+/// journal that doesn't exist yet is created.
+pub(crate) fn open(args: &Args) -> Result<Journal> {
+    let location = match &args.resume {
+        Some(location) => location,
+        None => return Ok(Journal::default()),
+    };
+    let header = Header {
+        subcommand: args.operation.as_deref().unwrap_or("pubsys").to_string(),
+        args_sha256: hex::encode(digest(
+            &SHA256,
+            format!("{:?} {:?}", args.env, args.subcommand).as_bytes(),
+        )),
+    };
+
+    let (path, remote) = match location.to_str().filter(|l| l.starts_with("s3://")) {
+        Some(url) => {
+            let url = Url::parse(url).context(error::UrlSnafu { url })?;
+            let dir = TempDir::new().context(error::TempDirSnafu)?;
+            let path = dir.path().join("journal.jsonl");
+            let rt = Runtime::new().context(error::RuntimeSnafu)?;
+            if let Some(data) = rt.block_on(fetch(&url, args.profile.as_deref()))? {
+                fs::write(&path, data).context(error::FileSnafu {
+                    op: "write",
+                    path: &path,
+                })?;
+            }
+            (path, Some((url, dir)))
+        }
+        None => (location.clone(), None),
+    };
+
+    let existing = match fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(error::FileSnafu { op: "read", path }),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context(error::FileSnafu {
+            op: "open",
+            path: &path,
+        })?;
+    let done = match existing {
+        Some(contents) => {
+            let done = parse(&contents, &header, location)?;
+            info!(
+                "Resuming from journal {}; {} units of work already done",
+                location.display(),
+                done.len()
+            );
+            // A crash may have left a partial last line, which the next entry mustn't extend.
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                writeln!(file).context(error::FileSnafu {
+                    op: "write",
+                    path: &path,
+                })?;
+            }
+            done
+        }
+        None => {
+            info!(
+                "Recording finished work in new journal {}",
+                location.display()
+            );
+            append(&mut file, &header).context(error::FileSnafu {
+                op: "write",
+                path: &path,
+            })?;
+            Done::new()
+        }
+    };
+
+    Ok(Journal {
+        open: Arc::new(Mutex::new(Some(OpenJournal {
+            file,
+            done,
+            remote,
+            profile: args.profile.clone(),
+        }))),
+    })
+}
+
+impl Journal {
+    /// If the journal records the given unit of work as done, returns what was recorded about it.
+    pub(crate) fn detail(&self, kind: &str, name: &str) -> Option<Option<String>> {
+        let journal = self.open.lock().ok()?;
+        journal
+            .as_ref()?
+            .done
+            .get(&(kind.to_string(), name.to_string()))
+            .cloned()
+    }
+
+    /// Records the given unit of work as done, with anything a resumed run needs to know about
+    /// it.  Failing to record it only means a resumed run will do it again, so we just warn.
+    pub(crate) fn record(&self, kind: &str, name: &str, detail: Option<&str>) {
+        let mut journal = match self.open.lock() {
+            Ok(journal) => journal,
+            Err(_) => return,
+        };
+        if let Some(journal) = journal.as_mut() {
+            let entry = Entry {
+                kind: kind.to_string(),
+                name: name.to_string(),
+                detail: detail.map(str::to_string),
+            };
+            if let Err(e) = append(&mut journal.file, &entry) {
+                warn!("Failed to record {} '{}' in journal: {}", kind, name, e);
+            }
+            journal.done.insert((entry.kind, entry.name), entry.detail);
+        }
+    }
+
+    /// Stops recording, uploading the journal if it's in S3.
+    pub(crate) fn close(&self) -> Result<()> {
+        let journal = match self.open.lock().ok().and_then(|mut journal| journal.take()) {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        if let Some((url, dir)) = &journal.remote {
+            let path = dir.path().join("journal.jsonl");
+            let data = fs::read(&path).context(error::FileSnafu { op: "read", path })?;
+            let rt = Runtime::new().context(error::RuntimeSnafu)?;
+            rt.block_on(store(url, journal.profile.as_deref(), data))?;
+            info!(
+                "Uploaded journal of {} finished units of work to {}",
+                journal.done.len(),
+                url
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Appends one line of JSON to the journal.
+fn append<T: Serialize>(file: &mut File, line: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(line)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.flush()
+}
+
+/// Parses a journal, checking that it belongs to the run with the given header.  A last line that
+/// doesn't parse was cut short by a crash, so that unit of work is just done again.
+fn parse(contents: &str, header: &Header, location: &Path) -> Result<Done> {
+    let mut lines = contents.lines().enumerate().peekable();
+    let found: Header = lines
+        .next()
+        .and_then(|(_, line)| serde_json::from_str(line).ok())
+        .context(error::HeaderSnafu { location })?;
+    ensure!(
+        &found == header,
+        error::MismatchSnafu {
+            location,
+            subcommand: found.subcommand,
+        }
+    );
+
+    let mut done = Done::new();
+    while let Some((index, line)) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(_) if lines.peek().is_none() => {
+                warn!(
+                    "Ignoring incomplete last line of journal {}",
+                    location.display()
+                );
+                break;
+            }
+            Err(e) => {
+                return Err(e).context(error::EntrySnafu {
+                    location,
+                    line: index + 1,
+                })
+            }
+        };
+        done.insert((entry.kind, entry.name), entry.detail);
+    }
+    Ok(done)
+}
+
+/// Splits an `s3://bucket/key` URL.
+fn bucket_and_key(url: &Url) -> Result<(&str, &str)> {
+    let bucket = url
+        .host_str()
+        .context(error::S3UrlSnafu { url: url.as_str() })?;
+    let key = url.path().trim_start_matches('/');
+    ensure!(!key.is_empty(), error::S3UrlSnafu { url: url.as_str() });
+    Ok((bucket, key))
+}
+
+/// Fetches a journal from S3, if it exists.
+async fn fetch(url: &Url, profile: Option<&str>) -> Result<Option<Vec<u8>>> {
+    let (bucket, key) = bucket_and_key(url)?;
+    let s3_client = default_s3_client(profile).await;
+    let output = match s3_client.get_object().bucket(bucket).key(key).send().await {
+        Ok(output) => output,
+        Err(SdkError::ServiceError(service_error))
+            if service_error.err().code() == Some("NoSuchKey") =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context(error::GetObjectSnafu { url: url.as_str() }),
+    };
+    let mut data = Vec::new();
+    output
+        .body
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .context(error::ReadObjectSnafu { url: url.as_str() })?;
+    Ok(Some(data))
+}
+
+/// Uploads a journal to S3.
+async fn store(url: &Url, profile: Option<&str>, data: Vec<u8>) -> Result<()> {
+    let (bucket, key) = bucket_and_key(url)?;
+    let s3_client = default_s3_client(profile).await;
+    s3_client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type("application/x-ndjson")
+        .body(ByteStream::from(data))
+        .send()
+        .await
+        .context(error::PutObjectSnafu { url: url.as_str() })?;
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_s3::error::{GetObjectError, PutObjectError};
+    use aws_sdk_s3::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Invalid entry on line {} of journal {}: {}",
+            line,
+            location.display(),
+            source
+        ))]
+        Entry {
+            location: PathBuf,
+            line: usize,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to {} journal '{}': {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: io::Error,
+        },
+
+        #[snafu(display(
+            "Failed to fetch journal from {}: {}",
+            url,
+            DisplayErrorContext(source)
+        ))]
+        GetObject {
+            url: String,
+            source: SdkError<GetObjectError>,
+        },
+
+        #[snafu(display("Journal {} doesn't start with a pubsys journal header", location.display()))]
+        Header { location: PathBuf },
+
+        #[snafu(display(
+            "Journal {} is from a '{}' run with different arguments; remove it to start over",
+            location.display(),
+            subcommand
+        ))]
+        Mismatch {
+            location: PathBuf,
+            subcommand: String,
+        },
+
+        #[snafu(display("Failed to upload journal to {}: {}", url, DisplayErrorContext(source)))]
+        PutObject {
+            url: String,
+            source: SdkError<PutObjectError>,
+        },
+
+        #[snafu(display("Failed to read journal from {}: {}", url, source))]
+        ReadObject { url: String, source: io::Error },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: io::Error },
+
+        #[snafu(display("Journal URL '{}' must look like s3://bucket/key", url))]
+        S3Url { url: String },
+
+        #[snafu(display("Failed to create directory for journal: {}", source))]
+        TempDir { source: io::Error },
+
+        #[snafu(display("Invalid journal URL '{}': {}", url, source))]
+        Url {
+            url: String,
+            source: url::ParseError,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::GetObject { .. } | Self::PutObject { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Header};
+    use std::path::Path;
+
+    fn header(args_sha256: &str) -> Header {
+        Header {
+            subcommand: "ssm".to_string(),
+            args_sha256: args_sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn resumes_recorded_work() {
+        let contents = concat!(
+            r#"{"subcommand":"ssm","args_sha256":"abcd"}"#,
+            "\n",
+            r#"{"kind":"SSM parameters set","name":"/a in us-west-2","detail":"ami-1"}"#,
+            "\n",
+            r#"{"kind":"SSM parameters set","name":"/b in us-west-2"}"#,
+            "\n",
+            r#"{"kind":"SSM parameters set","na"#,
+        );
+        let done = parse(contents, &header("abcd"), Path::new("journal.jsonl")).unwrap();
+        assert_eq!(done.len(), 2);
+        assert_eq!(
+            done[&(
+                "SSM parameters set".to_string(),
+                "/a in us-west-2".to_string()
+            )],
+            Some("ami-1".to_string())
+        );
+        assert_eq!(
+            done[&(
+                "SSM parameters set".to_string(),
+                "/b in us-west-2".to_string()
+            )],
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_runs() {
+        let path = Path::new("journal.jsonl");
+        let contents = concat!(r#"{"subcommand":"ssm","args_sha256":"abcd"}"#, "\n");
+        assert!(parse(contents, &header("ef01"), path).is_err());
+        assert!(parse("not a journal\n", &header("abcd"), path).is_err());
+
+        // Only the last line may be cut short.
+        let contents = concat!(
+            r#"{"subcommand":"ssm","args_sha256":"abcd"}"#,
+            "\n",
+            r#"{"kind":"SSM par"#,
+            "\n",
+            r#"{"kind":"SSM parameters set","name":"/b in us-west-2"}"#,
+            "\n",
+        );
+        assert!(parse(contents, &header("abcd"), path).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! The json_log module provides the logger used with `--log-format json`, which writes each log
+//! message as a line of JSON so that pubsys output can be shipped to a log service and queried.
+
+use crate::log_levels::LogLevels;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How log messages are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'; use 'text' or 'json'", s)),
+        }
+    }
+}
+
+/// A log message, as written in JSON
+#[derive(Debug, Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    module: &'a str,
+    /// The subcommand being run
+    operation: Option<&'a str>,
+    message: String,
+}
+
+/// Writes log messages as JSON lines; errors go to stderr and everything else to stdout, like the
+/// text loggers.
+pub(crate) struct JsonLogger {
+    levels: LogLevels,
+    operation: Option<String>,
+}
+
+impl JsonLogger {
+    /// Installs a JSON logger for messages at the given levels or above, recording the given
+    /// subcommand as the operation of each message.
+    pub(crate) fn init(
+        levels: LogLevels,
+        operation: Option<&str>,
+    ) -> std::result::Result<(), log::SetLoggerError> {
+        log::set_max_level(levels.max_level());
+        log::set_boxed_logger(Box::new(Self {
+            levels,
+            operation: operation.map(str::to_string),
+        }))
+    }
+
+    /// Returns the JSON line for the given record, without a trailing newline.
+    fn format(&self, record: &Record<'_>) -> String {
+        let json_record = JsonRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            level: record.level().to_string(),
+            module: record.module_path().unwrap_or_else(|| record.target()),
+            operation: self.operation.as_deref(),
+            message: record.args().to_string(),
+        };
+        // Serializing strings can't fail.
+        serde_json::to_string(&json_record).unwrap_or_default()
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.levels.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        // There's nowhere to report a failure to write a log message.
+        let _ = if record.level() == Level::Error {
+            writeln!(io::stderr().lock(), "{}", line)
+        } else {
+            writeln!(io::stdout().lock(), "{}", line)
+        };
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JsonLogger;
+    use log::{Level, Log, Record};
+
+    #[test]
+    fn formats_json_lines() {
+        let logger = JsonLogger {
+            levels: "INFO".parse().unwrap(),
+            operation: Some("ami".to_string()),
+        };
+        let line = logger.format(
+            &Record::builder()
+                .level(Level::Info)
+                .target("pubsys::aws::ami")
+                .module_path(Some("pubsys::aws::ami"))
+                .args(format_args!("Registered AMI in {}", "us-west-2"))
+                .build(),
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["module"], "pubsys::aws::ami");
+        assert_eq!(value["operation"], "ami");
+        assert_eq!(value["message"], "Registered AMI in us-west-2");
+        assert!(value["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        // SDK messages are only shown at warning level by default.
+        let sdk_info = Record::builder()
+            .level(Level::Info)
+            .target("aws_config::profile")
+            .build();
+        assert!(!logger.enabled(sdk_info.metadata()));
+    }
+}
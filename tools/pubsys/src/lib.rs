@@ -0,0 +1,908 @@
+/*!
+`pubsys` simplifies the process of publishing Bottlerocket updates.
+
+Currently implemented:
+* building repos, whether starting from an existing repo or from scratch
+* adding SBOMs and SLSA provenance to repos as targets, listed with the images they describe
+* uploading built repos to S3, resuming interrupted uploads
+* validating repos by loading them and retrieving their targets
+* verifying that published repos still match the signed manifest recorded when they were published
+* checking for repository metadata expirations within specified number of days
+* refreshing and re-signing repos' non-root metadata files
+* comparing two versions of a repo to list changed targets and metadata
+* comparing AMI data or expected SSM parameters files with each other, or with what's live in AWS
+* finishing repos whose metadata was signed offline, by attaching the detached signatures
+* deleting repo targets from S3 that no kept metadata refers to
+* reporting the size and composition of repos, and their growth over time
+* mirroring a repo's files from its S3 bucket into another repo's bucket
+* registering and copying EC2 AMIs
+* copying EC2 AMIs from the build account into a separate publishing account
+* Marking EC2 AMIs public (or private again)
+* validating the EBS snapshots behind AMIs, like their encryption, size, and sharing
+* creating and validating the per-region KMS keys that encrypt AMI copies, and their key policies
+* setting SSM parameters based on built AMIs
+* starting tests of newly published AMIs, and waiting for them before promoting SSM parameters
+* promoting SSM parameters from versioned entries to named (e.g. 'latest')
+* rolling SSM parameters back to their values before a bad promotion
+* moving release channel tags, like 'channel=stable', to a new version's AMIs
+* pointing EC2 Image Builder recipes and pipelines at a new version's AMIs
+* running a whole release (AMIs, SSM parameters, and repo) from one spec, resuming where it stopped
+* removing old releases' SSM parameters, AMIs, snapshots, and repo targets in one pass
+* retiring a release: deprecating its AMIs, retiring pointers to it, and flagging its repo targets
+* estimating the snapshot storage, repo storage, and copy transfer costs of publishing a build
+* validating SSM parameters by comparing the returned parameters in a region to a given list of parameters
+* listing every AMI, SSM parameter, and repo metadata version published for a variant, as JSON or CSV
+* reporting whether a version is published consistently to its AMIs, SSM parameters, and repo
+* recording a release's AMIs, SSM parameters, and repo metadata in a signed manifest, and verifying
+  the release still matches it
+* checking that the configured credentials have the permissions needed by the above, listing any missing actions
+* checking Infra.toml for mistakes, like malformed ARNs and missing files, before a long run
+* checking that variants' AMIs and SSM parameters are published the same for x86_64 and aarch64
+* serving AMI, SSM parameter, and repo expiration checks over HTTP, for dashboards and monitors
+* showing the resolved infra config, and regenerating Infra.lock after Infra.toml changes
+* uploading VHDs to Azure and publishing them as Azure Compute Gallery image versions
+* uploading image tarballs to GCP and publishing them as Compute Engine images, public or shared
+* generating shell completions for its subcommands and arguments
+* notifying SNS topics, Slack, and webhooks when subcommands start, succeed, or fail
+* stopping cleanly on Ctrl-C, with a summary of the work done and not done
+* journaling the work long-running subcommands finish, locally or in S3, so re-runs can resume
+* planning AMI and SSM changes to a file for review, and applying a reviewed plan unless live state changed
+* dry runs of the subcommands that change things, logging the changes they would make
+
+To be implemented:
+* high-level document describing pubsys usage with examples
+
+Configuration comes from:
+* command-line parameters, to specify basic options and paths to the below files
+* Infra.toml, for repo and AMI configuration, from a local path or an S3 or HTTPS URL
+* Release.toml, for migrations
+* Policy files for repo metadata expiration and update wave timing
+
+The `pubsys` binary is a thin wrapper around this library, which tools like release orchestrators
+can also call directly.  The AMI, SSM, validation, and repo operations each take a
+`pubsys_config::InfraConfig` and an options struct with public fields, like
+`aws::validate_ami::validate` with `ValidateAmiOptions`, and return their results as types, like
+`AmiValidationResults`, rather than printing them.  The subcommands' arguments are only converted
+into these options; `run` with `Args` still runs a whole command line.
+*/
+
+pub mod aws;
+mod azure;
+mod check_infra;
+mod check_parity;
+mod completions;
+mod eol;
+mod estimate;
+pub mod exit_code;
+mod gc;
+mod gcp;
+mod interrupt;
+mod inventory;
+mod journal;
+mod json_log;
+mod lock;
+mod log_levels;
+mod notify;
+mod plan;
+mod progress;
+mod release;
+mod release_manifest;
+mod remote_config;
+pub mod repo;
+mod report;
+mod run_state;
+mod serve;
+mod telemetry;
+mod test_trigger;
+mod vmware;
+
+use exit_code::{Classify, Kind};
+use json_log::{JsonLogger, LogFormat};
+use log::error;
+use log_levels::LogLevels;
+use pubsys_config::{AwsRetryMode, InfraConfig};
+use semver::Version;
+use snafu::ResultExt;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// Installs the logger chosen by the log arguments.  This is for the `pubsys` binary; programs
+/// using the library will usually want to install their own.
+pub fn init_logger(args: &Args) -> Result<()> {
+    let log_levels = if args.quiet {
+        args.log_level.clone().quiet()
+    } else {
+        args.log_level.clone()
+    };
+    if args.log_format == LogFormat::Json {
+        JsonLogger::init(log_levels, args.operation.as_deref()).context(error::LoggerSnafu)
+    } else {
+        log_levels.init_text().context(error::LoggerSnafu)
+    }
+}
+
+/// Lets the subcommand run with the given arguments stop cleanly on Ctrl-C, finishing the requests
+/// in flight and reporting which of its work was done, rather than dying mid-write.  This is for
+/// the `pubsys` binary; programs using the library can call it if they don't handle interrupts
+/// themselves.
+pub fn handle_interrupts(args: &Args) -> Result<()> {
+    interrupt::install(&args.state.interrupt).context(error::RuntimeSnafu)
+}
+
+/// Runs the subcommand given in the arguments.
+pub fn run(mut args: Args) -> Result<()> {
+    // A config given as a URL is downloaded up front; the directory holding it is removed on drop.
+    let _remote_config_dir =
+        remote_config::localize(&mut args).context(error::RemoteConfigSnafu)?;
+
+    // One runtime serves the run's async subcommands and its notifications.
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+
+    if args.verify_identity || args.expected_account.is_some() {
+        rt.block_on(aws::identity::verify(&args))
+            .context(error::IdentitySnafu)?;
+    }
+
+    args.state.journal = journal::open(&args).context(error::JournalSnafu)?;
+    args.state.plan = plan::start(&args).context(error::PlanSnafu)?;
+
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let operation = args.operation.as_deref().unwrap_or("pubsys");
+        args.state.tracer =
+            telemetry::init(endpoint.clone(), operation).context(error::TelemetrySnafu)?;
+    }
+
+    // Completions only print a script, so nobody needs to hear about them.
+    let mut notifier = match args.subcommand {
+        SubCommand::Completions(_) => None,
+        _ => Some(notify::Notifier::new(&args, rt.handle().clone())),
+    };
+    if let Some(notifier) = notifier.as_mut() {
+        notifier.start();
+    }
+
+    let result = match args.subcommand {
+        SubCommand::Repo(ref repo_args) => repo::run(&args, repo_args).context(error::RepoSnafu),
+        SubCommand::UploadRepo(ref upload_args) => {
+            repo::upload_repo::run(&args, upload_args).context(error::UploadRepoSnafu)
+        }
+        SubCommand::ValidateRepo(ref validate_repo_args) => {
+            repo::validate_repo::run(&args, validate_repo_args).context(error::ValidateRepoSnafu)
+        }
+        SubCommand::VerifyRepoManifest(ref verify_args) => {
+            repo::repo_manifest::run(&args, verify_args).context(error::VerifyRepoManifestSnafu)
+        }
+        SubCommand::CheckRepoExpirations(ref check_expirations_args) => {
+            repo::check_expirations::run(&args, check_expirations_args)
+                .context(error::CheckExpirationsSnafu)
+        }
+        SubCommand::RefreshRepo(ref refresh_repo_args) => {
+            repo::refresh_repo::run(&args, refresh_repo_args).context(error::RefreshRepoSnafu)
+        }
+        SubCommand::DiffRepo(ref diff_args) => {
+            repo::diff_repo::run(&args, diff_args).context(error::DiffRepoSnafu)
+        }
+        SubCommand::RepoStats(ref stats_args) => {
+            repo::repo_stats::run(&args, stats_args).context(error::RepoStatsSnafu)
+        }
+        SubCommand::GcRepo(ref gc_args) => {
+            repo::gc_repo::run(&args, gc_args).context(error::GcRepoSnafu)
+        }
+        SubCommand::SyncRepo(ref sync_args) => {
+            repo::sync_repo::run(&args, sync_args).context(error::SyncRepoSnafu)
+        }
+        SubCommand::AttachRepoSignatures(ref attach_args) => {
+            repo::offline_signing::run(&args, attach_args).context(error::AttachRepoSignaturesSnafu)
+        }
+        SubCommand::Ami(ref ami_args) => rt.block_on(async {
+            aws::ami::run(&args, ami_args)
+                .await
+                .map(drop)
+                .context(error::AmiSnafu)
+        }),
+        SubCommand::TransferAmi(ref transfer_args) => rt.block_on(async {
+            aws::transfer_ami::run(&args, transfer_args)
+                .await
+                .context(error::TransferAmiSnafu)
+        }),
+        SubCommand::PublishAmi(ref publish_args) => rt.block_on(async {
+            aws::publish_ami::run(&args, publish_args)
+                .await
+                .context(error::PublishAmiSnafu)
+        }),
+        SubCommand::Ssm(ref ssm_args) => rt.block_on(async {
+            aws::ssm::run(&args, ssm_args)
+                .await
+                .context(error::SsmSnafu)
+        }),
+        SubCommand::PromoteSsm(ref promote_args) => rt.block_on(async {
+            aws::promote_ssm::run(&args, promote_args)
+                .await
+                .context(error::PromoteSsmSnafu)
+        }),
+        SubCommand::RollbackSsm(ref rollback_args) => rt.block_on(async {
+            aws::rollback_ssm::run(&args, rollback_args)
+                .await
+                .context(error::RollbackSsmSnafu)
+        }),
+        SubCommand::PromoteAmi(ref promote_args) => rt.block_on(async {
+            aws::promote_ami::run(&args, promote_args)
+                .await
+                .context(error::PromoteAmiSnafu)
+        }),
+        SubCommand::Apply(ref apply_args) => rt.block_on(async {
+            plan::apply::run(&args, apply_args)
+                .await
+                .context(error::ApplySnafu)
+        }),
+        SubCommand::Kms(ref kms_args) => rt.block_on(async {
+            aws::kms::run(&args, kms_args)
+                .await
+                .context(error::KmsSnafu)
+        }),
+        SubCommand::ImageBuilder(ref image_builder_args) => rt.block_on(async {
+            aws::image_builder::run(&args, image_builder_args)
+                .await
+                .context(error::ImageBuilderSnafu)
+        }),
+        SubCommand::Diff(ref diff_args) => rt.block_on(async {
+            aws::diff::run(&args, diff_args)
+                .await
+                .context(error::DiffSnafu)
+        }),
+        SubCommand::ValidateSsm(ref validate_ssm_args) => rt.block_on(async {
+            aws::validate_ssm::run(&args, validate_ssm_args)
+                .await
+                .context(error::ValidateSsmSnafu)
+        }),
+        SubCommand::ValidateAmi(ref validate_ami_args) => rt.block_on(async {
+            aws::validate_ami::run(&args, validate_ami_args)
+                .await
+                .context(error::ValidateAmiSnafu)
+        }),
+        SubCommand::ValidateSnapshots(ref validate_snapshots_args) => rt.block_on(async {
+            aws::validate_snapshots::run(&args, validate_snapshots_args)
+                .await
+                .context(error::ValidateSnapshotsSnafu)
+        }),
+        SubCommand::Gc(ref gc_args) => {
+            rt.block_on(async { gc::run(&args, gc_args).await.context(error::GcSnafu) })
+        }
+        SubCommand::Eol(ref eol_args) => {
+            rt.block_on(async { eol::run(&args, eol_args).await.context(error::EolSnafu) })
+        }
+        SubCommand::Release(ref release_args) => {
+            release::run(&args, release_args).context(error::ReleaseSnafu)
+        }
+        SubCommand::Inventory(ref inventory_args) => {
+            inventory::run(&args, inventory_args).context(error::InventorySnafu)
+        }
+        SubCommand::Report(ref report_args) => {
+            report::run(&args, report_args).context(error::ReportSnafu)
+        }
+        SubCommand::Estimate(ref estimate_args) => {
+            estimate::run(&args, estimate_args).context(error::EstimateSnafu)
+        }
+        SubCommand::Manifest(ref manifest_args) => {
+            release_manifest::run(&args, manifest_args).context(error::ManifestSnafu)
+        }
+        SubCommand::VerifyManifest(ref verify_args) => {
+            release_manifest::verify(&args, verify_args).context(error::VerifyManifestSnafu)
+        }
+        SubCommand::CheckPermissions(ref check_args) => rt.block_on(async {
+            aws::check_permissions::run(&args, check_args)
+                .await
+                .context(error::CheckPermissionsSnafu)
+        }),
+        SubCommand::CheckInfra(ref check_args) => {
+            check_infra::run(&args, check_args).context(error::CheckInfraSnafu)
+        }
+        SubCommand::CheckParity(ref parity_args) => rt.block_on(async {
+            check_parity::run(&args, parity_args)
+                .await
+                .context(error::CheckParitySnafu)
+        }),
+        SubCommand::Serve(ref serve_args) => rt.block_on(async {
+            serve::run(&args, serve_args)
+                .await
+                .context(error::ServeSnafu)
+        }),
+        SubCommand::Lock(ref lock_args) => lock::run(&args, lock_args).context(error::LockSnafu),
+        SubCommand::UploadOva(ref upload_args) => {
+            vmware::upload_ova::run(&args, upload_args).context(error::UploadOvaSnafu)
+        }
+        SubCommand::UploadVhd(ref upload_args) => {
+            azure::upload_vhd::run(&args, upload_args).context(error::UploadVhdSnafu)
+        }
+        SubCommand::GalleryImage(ref image_args) => {
+            azure::gallery_image::run(&args, image_args).context(error::GalleryImageSnafu)
+        }
+        SubCommand::GcpImage(ref image_args) => {
+            gcp::image::run(&args, image_args).context(error::GcpImageSnafu)
+        }
+        SubCommand::Completions(ref completions_args) => {
+            completions::run(completions_args);
+            Ok(())
+        }
+    };
+    // A plan is only worth reviewing if the subcommand got through all of its planning.
+    let state = &args.state;
+    let result = result.and_then(|()| state.plan.finish().context(error::PlanSnafu));
+    // A subcommand that stopped early may still have succeeded at what it did, but it didn't
+    // finish the job.  Whatever failed along the way, being interrupted is what the caller needs
+    // to hear about.
+    let result = if state.interrupt.requested() {
+        state.interrupt.report();
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+        error::InterruptedSnafu.fail()
+    } else {
+        result
+    };
+    // A journal in S3 is uploaded whether or not the subcommand succeeded, so a re-run can resume
+    // from it; failing to upload it only fails a subcommand that otherwise succeeded.
+    let result = match (result, state.journal.close().context(error::JournalSnafu)) {
+        (Ok(()), closed) => closed,
+        (Err(e), Err(close_error)) => {
+            error!("{}", close_error);
+            Err(e)
+        }
+        (Err(e), Ok(())) => Err(e),
+    };
+
+    if let Some(notifier) = &notifier {
+        notifier.finish(&result);
+    }
+    state.tracer.export(result.is_ok());
+    result
+}
+
+/// Automates publishing of Bottlerocket updates
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct Args {
+    #[structopt(global = true, long, default_value = "INFO")]
+    /// How much detail to log; from least to most: ERROR, WARN, INFO, DEBUG, TRACE.  Modules can
+    /// be given their own level after the default, like "INFO,pubsys::aws::ssm=TRACE"
+    log_level: LogLevels,
+
+    #[structopt(global = true, long)]
+    /// Only log errors, except from modules given their own level with --log-level
+    quiet: bool,
+
+    #[structopt(global = true, long, default_value = "text", possible_values = &["text", "json"])]
+    /// How to write log messages: "text", or "json" for one JSON object per line
+    log_format: LogFormat,
+
+    #[structopt(long, parse(from_os_str), default_value = "Infra.toml")]
+    /// Path to Infra.toml, or an equivalent .yaml or .json file, or an s3:// or https:// URL of one;
+    /// defaults to Infra.toml in the current directory (NOTE: must be specified before subcommand)
+    infra_config_path: PathBuf,
+
+    #[structopt(skip)]
+    /// The URL the infra config was fetched from, if it was given as one; infra_config_path is
+    /// then the downloaded copy
+    infra_config_url: Option<Url>,
+
+    #[structopt(global = true, long)]
+    /// Use this named environment from the `env` section of Infra.toml, like "prod"
+    env: Option<String>,
+
+    #[structopt(global = true, long)]
+    /// Use this named AWS profile rather than `aws.profile` from Infra.toml; profiles using IAM
+    /// Identity Center (SSO) or `credential_process` are supported
+    profile: Option<String>,
+
+    #[structopt(global = true, long)]
+    /// Attempt each AWS request at most this many times, rather than `aws.retry.max_attempts`
+    retry_max_attempts: Option<NonZeroU32>,
+
+    #[structopt(global = true, long, possible_values = &["standard", "adaptive"])]
+    /// How AWS clients retry, "standard" or "adaptive", rather than `aws.retry.mode`
+    retry_mode: Option<AwsRetryMode>,
+
+    #[structopt(global = true, long)]
+    /// Milliseconds to wait before the first retry of an AWS request, rather than
+    /// `aws.retry.initial_backoff_ms`
+    retry_initial_backoff_ms: Option<u64>,
+
+    #[structopt(global = true, long)]
+    /// Milliseconds to wait to connect to an AWS service, rather than
+    /// `aws.timeout.connect_timeout_ms`
+    connect_timeout_ms: Option<NonZeroU64>,
+
+    #[structopt(global = true, long)]
+    /// Milliseconds to wait for each read of an AWS response, rather than
+    /// `aws.timeout.read_timeout_ms`
+    read_timeout_ms: Option<NonZeroU64>,
+
+    #[structopt(global = true, long)]
+    /// Milliseconds to wait for a whole AWS operation, including retries, rather than
+    /// `aws.timeout.operation_timeout_ms`
+    operation_timeout_ms: Option<NonZeroU64>,
+
+    #[structopt(global = true, long)]
+    /// Add this name to the user agent of AWS requests, like one including a pipeline run ID,
+    /// rather than `aws.app_name`
+    aws_app_name: Option<String>,
+
+    #[structopt(global = true, long)]
+    /// Send at most this many AWS requests at once, across all services and regions, trading speed
+    /// for less throttling, rather than `aws.max_concurrency`
+    max_concurrency: Option<NonZeroUsize>,
+
+    #[structopt(global = true, long)]
+    /// Before running the subcommand, log the AWS account and principal that it will act as
+    verify_identity: bool,
+
+    #[structopt(global = true, long)]
+    /// Before running the subcommand, fail unless its AWS credentials are for this account ID;
+    /// implies --verify-identity
+    expected_account: Option<String>,
+
+    #[structopt(global = true, long)]
+    /// Send a trace of the subcommand and its AWS operations to the OpenTelemetry collector at
+    /// this URL, using OTLP/HTTP, like "http://localhost:4318"
+    otlp_endpoint: Option<Url>,
+
+    #[structopt(global = true, long, parse(from_os_str))]
+    /// Record the work the subcommand finishes in this journal, a local path or an s3:// URL, and
+    /// skip work it already records from an earlier run with the same arguments; it's created if
+    /// it doesn't exist
+    resume: Option<PathBuf>,
+
+    #[structopt(global = true, long, parse(from_os_str))]
+    /// Rather than making changes, write the changes the subcommand would make to this file, for
+    /// `pubsys apply`; supported by ami, publish-ami, ssm, and promote-ssm
+    plan: Option<PathBuf>,
+
+    #[structopt(global = true, long, conflicts_with = "plan")]
+    /// Read everything and work out the changes the subcommand would make, but only log them;
+    /// supported by ami, publish-ami, ssm, promote-ssm, rollback-ssm, repo, gc-repo, eol, and lock
+    /// regenerate
+    dry_run: bool,
+
+    #[structopt(subcommand)]
+    subcommand: SubCommand,
+
+    #[structopt(skip)]
+    /// The name of the subcommand, like "publish-ami", if the arguments came from the command line
+    operation: Option<String>,
+
+    #[structopt(skip)]
+    /// The plan, journal, interrupt, and trace state of the run with these arguments
+    state: run_state::RunState,
+}
+
+impl Args {
+    /// Parses the arguments passed to the program, exiting with usage information if they're
+    /// invalid.
+    pub fn from_command_line() -> Self {
+        let matches = Self::clap().get_matches();
+        let mut args = Self::from_clap(&matches);
+        args.operation = matches.subcommand_name().map(str::to_string);
+        args
+    }
+
+    /// Loads the infra config, from Infra.lock if it exists, otherwise from Infra.toml (or a
+    /// default, if `default` is true), with the chosen environment, --profile, --aws-app-name,
+    /// --max-concurrency, and retry and timeout arguments applied.
+    pub(crate) fn infra_config(
+        &self,
+        default: bool,
+    ) -> std::result::Result<InfraConfig, pubsys_config::Error> {
+        let mut infra_config = InfraConfig::from_path_or_lock_in_env(
+            &self.infra_config_path,
+            default,
+            self.env.as_deref(),
+        )?;
+        if let Some(profile) = &self.profile {
+            infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .profile = Some(profile.clone());
+        }
+        if let Some(app_name) = &self.aws_app_name {
+            infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .app_name = Some(app_name.clone());
+        }
+        if let Some(max_concurrency) = self.max_concurrency {
+            infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .max_concurrency = Some(max_concurrency);
+        }
+        if self.retry_max_attempts.is_some()
+            || self.retry_mode.is_some()
+            || self.retry_initial_backoff_ms.is_some()
+        {
+            let retry = infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .retry
+                .get_or_insert_with(Default::default);
+            retry.max_attempts = self.retry_max_attempts.or(retry.max_attempts);
+            retry.mode = self.retry_mode.or(retry.mode);
+            retry.initial_backoff_ms = self.retry_initial_backoff_ms.or(retry.initial_backoff_ms);
+        }
+        if self.connect_timeout_ms.is_some()
+            || self.read_timeout_ms.is_some()
+            || self.operation_timeout_ms.is_some()
+        {
+            let timeout = infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .timeout
+                .get_or_insert_with(Default::default);
+            timeout.connect_timeout_ms = self.connect_timeout_ms.or(timeout.connect_timeout_ms);
+            timeout.read_timeout_ms = self.read_timeout_ms.or(timeout.read_timeout_ms);
+            timeout.operation_timeout_ms =
+                self.operation_timeout_ms.or(timeout.operation_timeout_ms);
+        }
+        Ok(infra_config)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum SubCommand {
+    Repo(repo::RepoArgs),
+    UploadRepo(repo::upload_repo::UploadRepoArgs),
+    ValidateRepo(repo::validate_repo::ValidateRepoArgs),
+    VerifyRepoManifest(repo::repo_manifest::VerifyRepoManifestArgs),
+    CheckRepoExpirations(repo::check_expirations::CheckExpirationsArgs),
+    RefreshRepo(repo::refresh_repo::RefreshRepoArgs),
+    AttachRepoSignatures(repo::offline_signing::AttachSignaturesArgs),
+    DiffRepo(repo::diff_repo::DiffRepoArgs),
+    GcRepo(repo::gc_repo::GcRepoArgs),
+    RepoStats(repo::repo_stats::RepoStatsArgs),
+    SyncRepo(repo::sync_repo::SyncRepoArgs),
+
+    Ami(aws::ami::AmiArgs),
+    TransferAmi(aws::transfer_ami::TransferArgs),
+    PublishAmi(aws::publish_ami::PublishArgs),
+    ValidateAmi(aws::validate_ami::ValidateAmiArgs),
+    ValidateSnapshots(aws::validate_snapshots::ValidateSnapshotsArgs),
+    Kms(aws::kms::KmsArgs),
+    Apply(plan::apply::ApplyArgs),
+
+    Ssm(aws::ssm::SsmArgs),
+    PromoteSsm(aws::promote_ssm::PromoteArgs),
+    RollbackSsm(aws::rollback_ssm::RollbackSsmArgs),
+    PromoteAmi(aws::promote_ami::PromoteAmiArgs),
+    ImageBuilder(aws::image_builder::ImageBuilderArgs),
+    ValidateSsm(aws::validate_ssm::ValidateSsmArgs),
+    Diff(aws::diff::DiffArgs),
+
+    Release(release::ReleaseArgs),
+    Gc(gc::GcArgs),
+    Eol(eol::EolArgs),
+    Inventory(inventory::InventoryArgs),
+    Report(report::ReportArgs),
+    Estimate(estimate::EstimateArgs),
+    Manifest(release_manifest::ManifestArgs),
+    VerifyManifest(release_manifest::VerifyManifestArgs),
+
+    #[structopt(visible_alias = "check-iam")]
+    CheckPermissions(aws::check_permissions::CheckPermissionsArgs),
+    CheckInfra(check_infra::CheckInfraArgs),
+    CheckParity(check_parity::CheckParityArgs),
+    Serve(serve::ServeArgs),
+    Lock(lock::LockArgs),
+
+    UploadOva(vmware::upload_ova::UploadArgs),
+
+    UploadVhd(azure::upload_vhd::UploadVhdArgs),
+    GalleryImage(azure::gallery_image::GalleryImageArgs),
+
+    GcpImage(gcp::image::GcpImageArgs),
+
+    Completions(completions::CompletionsArgs),
+}
+
+impl SubCommand {
+    /// Returns how the subcommand handles `--plan` and `--dry-run`.  Every subcommand is listed, so
+    /// a new one has to decide.
+    fn plan_support(&self) -> plan::Support {
+        match self {
+            SubCommand::Ami(_)
+            | SubCommand::PublishAmi(_)
+            | SubCommand::Ssm(_)
+            | SubCommand::PromoteSsm(_) => plan::Support::Plans,
+
+            SubCommand::Repo(_)
+            | SubCommand::GcRepo(_)
+            | SubCommand::RollbackSsm(_)
+            | SubCommand::Eol(_)
+            | SubCommand::Lock(_) => plan::Support::DryRunsItself,
+
+            SubCommand::UploadRepo(_)
+            | SubCommand::ValidateRepo(_)
+            | SubCommand::VerifyRepoManifest(_)
+            | SubCommand::CheckRepoExpirations(_)
+            | SubCommand::RefreshRepo(_)
+            | SubCommand::AttachRepoSignatures(_)
+            | SubCommand::DiffRepo(_)
+            | SubCommand::RepoStats(_)
+            | SubCommand::SyncRepo(_)
+            | SubCommand::TransferAmi(_)
+            | SubCommand::ValidateAmi(_)
+            | SubCommand::ValidateSnapshots(_)
+            | SubCommand::Kms(_)
+            | SubCommand::Apply(_)
+            | SubCommand::PromoteAmi(_)
+            | SubCommand::ImageBuilder(_)
+            | SubCommand::ValidateSsm(_)
+            | SubCommand::Diff(_)
+            | SubCommand::Release(_)
+            | SubCommand::Gc(_)
+            | SubCommand::Inventory(_)
+            | SubCommand::Report(_)
+            | SubCommand::Estimate(_)
+            | SubCommand::Manifest(_)
+            | SubCommand::VerifyManifest(_)
+            | SubCommand::CheckPermissions(_)
+            | SubCommand::CheckInfra(_)
+            | SubCommand::CheckParity(_)
+            | SubCommand::Serve(_)
+            | SubCommand::UploadOva(_)
+            | SubCommand::UploadVhd(_)
+            | SubCommand::GalleryImage(_)
+            | SubCommand::GcpImage(_)
+            | SubCommand::Completions(_) => plan::Support::Unsupported,
+        }
+    }
+}
+
+/// Parses a SemVer, stripping a leading 'v' if present
+pub(crate) fn friendly_version(
+    mut version_str: &str,
+) -> std::result::Result<Version, semver::Error> {
+    if version_str.starts_with('v') {
+        version_str = &version_str[1..];
+    };
+
+    Version::parse(version_str)
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to build AMI: {}", source))]
+        Ami { source: crate::aws::ami::Error },
+
+        #[snafu(display("Failed to apply plan: {}", source))]
+        Apply { source: crate::plan::apply::Error },
+
+        #[snafu(display("Failed to attach signatures to repository metadata: {}", source))]
+        AttachRepoSignatures {
+            source: crate::repo::offline_signing::Error,
+        },
+
+        #[snafu(display("Failed to check infra config: {}", source))]
+        CheckInfra { source: crate::check_infra::Error },
+
+        #[snafu(display("Failed to check architecture parity: {}", source))]
+        CheckParity { source: crate::check_parity::Error },
+
+        #[snafu(display("Failed to check permissions: {}", source))]
+        CheckPermissions {
+            source: crate::aws::check_permissions::Error,
+        },
+
+        #[snafu(display("Failed to compare AMIs or parameters: {}", source))]
+        Diff { source: crate::aws::diff::Error },
+
+        #[snafu(display("Failed to compare repositories: {}", source))]
+        DiffRepo {
+            source: crate::repo::diff_repo::Error,
+        },
+
+        #[snafu(display("Failed to retire release: {}", source))]
+        Eol { source: crate::eol::Error },
+
+        #[snafu(display("Failed to estimate costs: {}", source))]
+        Estimate { source: crate::estimate::Error },
+
+        #[snafu(display("Failed to publish Azure gallery image: {}", source))]
+        GalleryImage {
+            source: crate::azure::gallery_image::Error,
+        },
+
+        #[snafu(display("Failed to remove old releases: {}", source))]
+        Gc { source: crate::gc::Error },
+
+        #[snafu(display("Failed to publish GCP image: {}", source))]
+        GcpImage { source: crate::gcp::image::Error },
+
+        #[snafu(display("Failed to clean up repository targets: {}", source))]
+        GcRepo { source: crate::repo::gc_repo::Error },
+
+        #[snafu(display("Failed to verify AWS identity: {}", source))]
+        Identity { source: crate::aws::identity::Error },
+
+        #[snafu(display("Failed to update EC2 Image Builder: {}", source))]
+        ImageBuilder {
+            source: crate::aws::image_builder::Error,
+        },
+
+        #[snafu(display("Failed to list inventory: {}", source))]
+        Inventory { source: crate::inventory::Error },
+
+        #[snafu(display("Interrupted before all work was done"))]
+        Interrupted,
+
+        #[snafu(display("Failed to use journal: {}", source))]
+        Journal { source: crate::journal::Error },
+
+        #[snafu(display("Failed to manage KMS keys: {}", source))]
+        Kms { source: crate::aws::kms::Error },
+
+        #[snafu(display("Failed to show or regenerate Infra.lock: {}", source))]
+        Lock { source: crate::lock::Error },
+
+        #[snafu(display("Logger setup error: {}", source))]
+        Logger { source: log::SetLoggerError },
+
+        #[snafu(display("Failed to write release manifest: {}", source))]
+        Manifest {
+            source: crate::release_manifest::Error,
+        },
+
+        #[snafu(display("Failed to plan changes: {}", source))]
+        Plan { source: crate::plan::Error },
+
+        #[snafu(display(
+            "Error during publish-ami command: {}: {}",
+            publish_ami_message(source),
+            source
+        ))]
+        PublishAmi {
+            source: crate::aws::publish_ami::Error,
+        },
+
+        #[snafu(display("Failed to promote AMIs: {}", source))]
+        PromoteAmi {
+            source: crate::aws::promote_ami::Error,
+        },
+
+        #[snafu(display("Failed to promote SSM: {}", source))]
+        PromoteSsm {
+            source: crate::aws::promote_ssm::Error,
+        },
+
+        #[snafu(display("Failed to run release: {}", source))]
+        Release { source: crate::release::Error },
+
+        #[snafu(display("Failed to fetch infra config: {}", source))]
+        RemoteConfig { source: crate::remote_config::Error },
+
+        #[snafu(display("Failed to build repo: {}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display("Failed to report repository stats: {}", source))]
+        RepoStats {
+            source: crate::repo::repo_stats::Error,
+        },
+
+        #[snafu(display("Failed to validate repository: {}", source))]
+        ValidateRepo {
+            source: crate::repo::validate_repo::Error,
+        },
+
+        #[snafu(display("Check expirations error: {}", source))]
+        CheckExpirations {
+            source: crate::repo::check_expirations::Error,
+        },
+
+        #[snafu(display("Failed to report on release: {}", source))]
+        Report { source: crate::report::Error },
+
+        #[snafu(display("Failed to refresh repository metadata: {}", source))]
+        RefreshRepo {
+            source: crate::repo::refresh_repo::Error,
+        },
+
+        #[snafu(display("Failed to roll back SSM: {}", source))]
+        RollbackSsm {
+            source: crate::aws::rollback_ssm::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Failed to serve checks: {}", source))]
+        Serve { source: crate::serve::Error },
+
+        #[snafu(display("Failed to update SSM: {}", source))]
+        Ssm { source: crate::aws::ssm::Error },
+
+        #[snafu(display("Failed to sync repository: {}", source))]
+        SyncRepo {
+            source: crate::repo::sync_repo::Error,
+        },
+
+        #[snafu(display("Failed to start trace: {}", source))]
+        Telemetry { source: crate::telemetry::Error },
+
+        #[snafu(display("Failed to transfer AMIs to publishing account: {}", source))]
+        TransferAmi {
+            source: crate::aws::transfer_ami::Error,
+        },
+
+        #[snafu(display("Failed to upload OVA: {}", source))]
+        UploadOva {
+            source: crate::vmware::upload_ova::Error,
+        },
+
+        #[snafu(display("Failed to upload repository: {}", source))]
+        UploadRepo {
+            source: crate::repo::upload_repo::Error,
+        },
+
+        #[snafu(display("Failed to upload VHD: {}", source))]
+        UploadVhd {
+            source: crate::azure::upload_vhd::Error,
+        },
+
+        #[snafu(display("Failed to validate SSM parameters: {}", source))]
+        ValidateSsm {
+            source: crate::aws::validate_ssm::Error,
+        },
+
+        #[snafu(display("Failed to validate EC2 images: {}", source))]
+        ValidateAmi {
+            source: crate::aws::validate_ami::Error,
+        },
+
+        #[snafu(display("Failed to validate EBS snapshots: {}", source))]
+        ValidateSnapshots {
+            source: crate::aws::validate_snapshots::Error,
+        },
+
+        #[snafu(display("Failed to verify release manifest: {}", source))]
+        VerifyManifest {
+            source: crate::release_manifest::Error,
+        },
+
+        #[snafu(display("Failed to verify repository manifest: {}", source))]
+        VerifyRepoManifest {
+            source: crate::repo::repo_manifest::Error,
+        },
+    }
+
+    fn publish_ami_message(error: &crate::aws::publish_ami::Error) -> String {
+        match error.amis_affected() {
+            0 => String::from("No AMI permissions were updated"),
+            1 => String::from("Permissions for 1 AMI were updated, the rest failed"),
+            n => format!("Permissions for {} AMIs were updated, the rest failed", n),
+        }
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::Interrupted => Some(Kind::Interrupted),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Returns the code pubsys exits with for this error; see the `exit_code` module for what each
+    /// code means.
+    pub fn exit_code(&self) -> i32 {
+        exit_code::for_error(self)
+    }
+}
@@ -0,0 +1,286 @@
+//! The lock module owns the 'lock' subcommand, which shows the infra config that other
+//! subcommands will use, and regenerates Infra.lock from Infra.toml after config changes.
+
+use crate::Args;
+use log::info;
+use pubsys_config::{InfraConfig, RepoConfig, SigningKeyConfig};
+use snafu::ResultExt;
+use std::fs;
+use structopt::{clap, StructOpt};
+
+/// Shows or regenerates Infra.lock
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) enum LockArgs {
+    /// Prints the infra config that other subcommands will use, after applying Infra.lock, the
+    /// chosen environment, environment variable overrides, and --profile
+    Show,
+    /// Rewrites Infra.lock from the current Infra.toml, keeping the values infrasys generated
+    Regenerate,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, lock_args: &LockArgs) -> Result<()> {
+    match lock_args {
+        LockArgs::Show => show(args),
+        LockArgs::Regenerate => regenerate(args),
+    }
+}
+
+fn show(args: &Args) -> Result<()> {
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    print!(
+        "{}",
+        infra_config.to_lock_string().context(error::ConfigSnafu)?
+    );
+    Ok(())
+}
+
+fn regenerate(args: &Args) -> Result<()> {
+    // Infra.lock is written next to Infra.toml, which we can't do for a fetched config.
+    if let Some(url) = &args.infra_config_url {
+        return error::RemoteConfigSnafu { url: url.as_str() }.fail();
+    }
+
+    let toml_path = &args.infra_config_path;
+    let mut infra_config = InfraConfig::from_path(toml_path).context(error::ConfigSnafu)?;
+
+    let lock_path = InfraConfig::compute_lock_path(toml_path).context(error::ConfigSnafu)?;
+    if lock_path.exists() {
+        let old_lock = InfraConfig::from_lock_path(&lock_path).context(error::ConfigSnafu)?;
+        for carried in carry_over(&old_lock, &mut infra_config) {
+            info!("Keeping {} from existing Infra.lock", carried);
+        }
+    } else {
+        info!(
+            "No existing lock file at '{}'; generating from '{}' alone",
+            lock_path.display(),
+            toml_path.display()
+        );
+    }
+
+    let lock_string = infra_config.to_lock_string().context(error::ConfigSnafu)?;
+    // A dry run prints the new Infra.lock rather than writing it.
+    if args.dry_run {
+        print!("{}", lock_string);
+    } else {
+        fs::write(&lock_path, lock_string).context(error::WriteLockSnafu { path: &lock_path })?;
+        info!("Wrote '{}'", lock_path.display());
+    }
+    Ok(())
+}
+
+/// Copies the values that infrasys and `pubsys kms` generated, like bucket names and KMS key IDs,
+/// from an old lock into a config read from Infra.toml, wherever the config leaves them unset.
+/// Returns a description of each value that was copied.
+fn carry_over(old_lock: &InfraConfig, config: &mut InfraConfig) -> Vec<String> {
+    let mut carried = Vec::new();
+
+    if let (Some(old_repos), Some(repos)) = (&old_lock.repo, &mut config.repo) {
+        for (name, repo) in repos.iter_mut() {
+            if let Some(old_repo) = old_repos.get(name) {
+                carry_over_repo(name, old_repo, repo, &mut carried);
+            }
+        }
+    }
+
+    let old_buckets = old_lock.aws.as_ref().and_then(|aws| aws.s3.as_ref());
+    let buckets = config.aws.as_mut().and_then(|aws| aws.s3.as_mut());
+    if let (Some(old_buckets), Some(buckets)) = (old_buckets, buckets) {
+        for (name, bucket) in buckets.iter_mut() {
+            let old_bucket = match old_buckets.get(name) {
+                Some(old_bucket) => old_bucket,
+                None => continue,
+            };
+            if bucket.stack_arn.is_none() && old_bucket.stack_arn.is_some() {
+                bucket.stack_arn = old_bucket.stack_arn.clone();
+                carried.push(format!("aws.s3.{}.stack_arn", name));
+            }
+            if bucket.bucket_name.is_none() && old_bucket.bucket_name.is_some() {
+                bucket.bucket_name = old_bucket.bucket_name.clone();
+                carried.push(format!("aws.s3.{}.bucket_name", name));
+            }
+        }
+    }
+
+    // `pubsys kms` records the ARNs of the keys it creates only in the lock.
+    let old_key_arns = old_lock
+        .aws
+        .as_ref()
+        .and_then(|aws| aws.ami_kms.as_ref())
+        .map(|ami_kms| &ami_kms.key_arns)
+        .filter(|key_arns| !key_arns.is_empty());
+    if let (Some(old_key_arns), Some(aws)) = (old_key_arns, config.aws.as_mut()) {
+        let ami_kms = aws.ami_kms.get_or_insert_with(Default::default);
+        if ami_kms.key_arns.is_empty() {
+            ami_kms.key_arns = old_key_arns.clone();
+            carried.push("aws.ami_kms.key_arns".to_string());
+        }
+    }
+
+    carried
+}
+
+fn carry_over_repo(
+    name: &str,
+    old_repo: &RepoConfig,
+    repo: &mut RepoConfig,
+    carried: &mut Vec<String>,
+) {
+    macro_rules! carry {
+        ($field:ident) => {
+            if repo.$field.is_none() && old_repo.$field.is_some() {
+                repo.$field = old_repo.$field.clone();
+                carried.push(format!("repo.{}.{}", name, stringify!($field)));
+            }
+        };
+    }
+    carry!(metadata_base_url);
+    carry!(targets_url);
+    carry!(root_role_url);
+    carry!(root_role_sha512);
+
+    if carry_over_kms(&old_repo.signing_keys, &mut repo.signing_keys) {
+        carried.push(format!("repo.{}.signing_keys", name));
+    }
+    if carry_over_kms(&old_repo.root_keys, &mut repo.root_keys) {
+        carried.push(format!("repo.{}.root_keys", name));
+    }
+}
+
+/// Copies the keys infrasys created from an old KMS key config into a new one, if the new one
+/// doesn't list any of its own.  Returns true if anything was copied.
+fn carry_over_kms(old_key: &Option<SigningKeyConfig>, key: &mut Option<SigningKeyConfig>) -> bool {
+    let (old_config, config) = match (old_key, key) {
+        (
+            Some(SigningKeyConfig::kms {
+                config: Some(old_config),
+                ..
+            }),
+            Some(SigningKeyConfig::kms { config, .. }),
+        ) => (old_config, config),
+        _ => return false,
+    };
+    let config = config.get_or_insert_with(|| old_config.clone());
+
+    let mut copied = false;
+    if config.available_keys.is_empty() && !old_config.available_keys.is_empty() {
+        config.available_keys = old_config.available_keys.clone();
+        copied = true;
+    }
+    if config.key_stack_arns.is_empty() && !old_config.key_stack_arns.is_empty() {
+        config.key_stack_arns = old_config.key_stack_arns.clone();
+        copied = true;
+    }
+    copied
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Can't regenerate Infra.lock for the infra config at {}; regenerate it where the \
+             config is kept",
+            url
+        ))]
+        RemoteConfig { url: String },
+
+        #[snafu(display("Failed to write lock file '{}': {}", path.display(), source))]
+        WriteLock { path: PathBuf, source: io::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::carry_over;
+    use pubsys_config::InfraConfig;
+
+    #[test]
+    fn carries_over_generated_values() {
+        let old_lock: InfraConfig = toml::from_str(
+            r#"
+            [repo.default]
+            metadata_base_url = "https://old.example.com/metadata/"
+            targets_url = "https://old.example.com/targets/"
+            signing_keys = { kms = { available_keys = { "abc" = "us-west-2" }, regions = ["us-west-2"] } }
+
+            [aws.s3.bucket]
+            s3_prefix = "/repo"
+            stack_arn = "arn:aws:cloudformation:us-west-2:111122223333:stack/repo/1"
+            bucket_name = "generated-bucket"
+            "#,
+        )
+        .unwrap();
+        let mut config: InfraConfig = toml::from_str(
+            r#"
+            [repo.default]
+            targets_url = "https://new.example.com/targets/"
+            signing_keys = { kms = { regions = ["us-west-2"] } }
+
+            [aws.s3.bucket]
+            s3_prefix = "/repo"
+            "#,
+        )
+        .unwrap();
+
+        let mut carried = carry_over(&old_lock, &mut config);
+        carried.sort();
+        assert_eq!(
+            carried,
+            vec![
+                "aws.s3.bucket.bucket_name",
+                "aws.s3.bucket.stack_arn",
+                "repo.default.metadata_base_url",
+                "repo.default.signing_keys",
+            ]
+        );
+
+        let repo = &config.repo.as_ref().unwrap()["default"];
+        // Values set in Infra.toml win over the old lock.
+        assert_eq!(
+            repo.targets_url.as_ref().unwrap().as_str(),
+            "https://new.example.com/targets/"
+        );
+        let bucket = &config.aws.as_ref().unwrap().s3.as_ref().unwrap()["bucket"];
+        assert_eq!(bucket.bucket_name.as_deref(), Some("generated-bucket"));
+    }
+
+    #[test]
+    fn carries_over_ami_key_arns() {
+        let old_lock: InfraConfig = toml::from_str(
+            r#"
+            [aws.ami_kms.key_arns]
+            us-west-2 = "arn:aws:kms:us-west-2:111122223333:key/abc"
+            "#,
+        )
+        .unwrap();
+        let mut config: InfraConfig = toml::from_str(
+            r#"
+            [aws.ami_kms]
+            alias = "alias/bottlerocket-ami"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            carry_over(&old_lock, &mut config),
+            vec!["aws.ami_kms.key_arns"]
+        );
+        let ami_kms = config.aws.unwrap().ami_kms.unwrap();
+        assert_eq!(ami_kms.alias.as_deref(), Some("alias/bottlerocket-ami"));
+        assert_eq!(
+            ami_kms.key_arns["us-west-2"],
+            "arn:aws:kms:us-west-2:111122223333:key/abc"
+        );
+    }
+}
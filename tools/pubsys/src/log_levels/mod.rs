@@ -0,0 +1,173 @@
+//! The log_levels module parses `--log-level`, which sets the default level of log messages and,
+//! optionally, levels for individual modules, so that one subsystem can be traced without tracing
+//! every module of the AWS SDK.
+
+use log::{LevelFilter, SetLoggerError};
+use simplelog::{CombinedLogger, ConfigBuilder, SharedLogger, SimpleLogger};
+use std::str::FromStr;
+
+/// Log targets from the AWS SDK that are only worth showing at warning level or above, unless the
+/// user asks for more detail than the default.
+const QUIET_TARGETS: &[&str] = &[
+    "aws_config",
+    "aws_credential_types",
+    "aws_smithy",
+    "tracing::span",
+];
+
+/// The level of log messages to show by default, and for any modules given their own level, like
+/// `info,pubsys::aws::ssm=trace`.  A module's level also applies to the modules under it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LogLevels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for LogLevels {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            LevelFilter::from_str(level.trim()).map_err(|_| {
+                format!(
+                    "unknown log level '{}'; use ERROR, WARN, INFO, DEBUG, or TRACE",
+                    level.trim()
+                )
+            })
+        };
+
+        let mut levels = LogLevels {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(format!("missing module name in '{}'", directive));
+                    }
+                    let level = parse_level(level)?;
+                    // If a module is given twice, the last level wins.
+                    levels.modules.retain(|(existing, _)| existing != module);
+                    levels.modules.push((module.to_string(), level));
+                }
+                None => levels.default = parse_level(directive)?,
+            }
+        }
+        Ok(levels)
+    }
+}
+
+impl LogLevels {
+    /// Only shows errors by default; modules given their own level keep it.
+    pub(crate) fn quiet(mut self) -> Self {
+        self.default = LevelFilter::Error;
+        self
+    }
+
+    /// Returns the modules that aren't logged at the default level, with their levels.  This
+    /// includes the noisy AWS SDK modules, unless the user gave them a level.
+    fn rules(&self) -> Vec<(&str, LevelFilter)> {
+        let mut rules: Vec<(&str, LevelFilter)> = self
+            .modules
+            .iter()
+            .map(|(module, level)| (module.as_str(), *level))
+            .collect();
+        if self.default == LevelFilter::Info {
+            for target in QUIET_TARGETS.iter().copied() {
+                if !rules.iter().any(|(module, _)| target.starts_with(module)) {
+                    rules.push((target, LevelFilter::Warn));
+                }
+            }
+        }
+        rules
+    }
+
+    /// Returns the level of messages to show from the given log target, which is a module path
+    /// unless the message said otherwise.  The most specific module given a level wins.
+    pub(crate) fn level_for(&self, target: &str) -> LevelFilter {
+        self.rules()
+            .into_iter()
+            .filter(|(module, _)| target.starts_with(module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    /// Returns the most detailed level of any module, which is the most the logger has to check.
+    pub(crate) fn max_level(&self) -> LevelFilter {
+        self.rules()
+            .into_iter()
+            .map(|(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+
+    /// Installs text loggers for these levels.  SimpleLogger sends errors to stderr and anything
+    /// less to stdout; to use different levels for different modules and retain that mixed
+    /// logging, we spin up a logger for each module with its own level, plus one for the rest.
+    pub(crate) fn init_text(&self) -> std::result::Result<(), SetLoggerError> {
+        let rules = self.rules();
+        let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::with_capacity(rules.len() + 1);
+        let mut default_config = ConfigBuilder::new();
+        for (module, level) in &rules {
+            default_config.add_filter_ignore(module.to_string());
+
+            let mut config = ConfigBuilder::new();
+            config.add_filter_allow(module.to_string());
+            // Modules under this one that have their own level get their own logger.
+            for (other, _) in &rules {
+                if other.len() > module.len() && other.starts_with(module) {
+                    config.add_filter_ignore(other.to_string());
+                }
+            }
+            loggers.push(SimpleLogger::new(*level, config.build()));
+        }
+        loggers.push(SimpleLogger::new(self.default, default_config.build()));
+        CombinedLogger::init(loggers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogLevels;
+    use log::LevelFilter;
+
+    #[test]
+    fn module_levels() {
+        let levels: LogLevels = "warn, pubsys::aws=debug, pubsys::aws::ssm=TRACE"
+            .parse()
+            .unwrap();
+        assert_eq!(levels.level_for("pubsys::repo"), LevelFilter::Warn);
+        assert_eq!(levels.level_for("pubsys::aws::ami"), LevelFilter::Debug);
+        assert_eq!(
+            levels.level_for("pubsys::aws::ssm::ssm"),
+            LevelFilter::Trace
+        );
+        assert_eq!(levels.max_level(), LevelFilter::Trace);
+
+        // Only errors are shown by default when quiet, but module levels still apply.
+        let quiet = levels.quiet();
+        assert_eq!(quiet.level_for("pubsys::repo"), LevelFilter::Error);
+        assert_eq!(quiet.level_for("pubsys::aws::ami"), LevelFilter::Debug);
+
+        assert!("pubsys=loud".parse::<LogLevels>().is_err());
+        assert!("=debug".parse::<LogLevels>().is_err());
+    }
+
+    #[test]
+    fn quiet_sdk_by_default() {
+        let levels: LogLevels = "INFO".parse().unwrap();
+        assert_eq!(levels.level_for("pubsys::aws::ami"), LevelFilter::Info);
+        assert_eq!(levels.level_for("aws_config::profile"), LevelFilter::Warn);
+
+        // The SDK can be given a level of its own.
+        let levels: LogLevels = "aws_config=debug".parse().unwrap();
+        assert_eq!(levels.level_for("aws_config::profile"), LevelFilter::Debug);
+        assert_eq!(levels.level_for("aws_smithy_http"), LevelFilter::Warn);
+
+        // If the user asks for more detail, they get it from the SDK too.
+        let levels: LogLevels = "DEBUG".parse().unwrap();
+        assert_eq!(levels.level_for("aws_smithy_http"), LevelFilter::Debug);
+    }
+}
@@ -0,0 +1,492 @@
+//! The notify module tells people and bots how a subcommand is going, by sending a notification
+//! when it starts, succeeds, or fails to each destination in the `notify` section of Infra.toml:
+//! SNS topics, Slack incoming webhooks, and generic HTTPS webhooks.
+//!
+//! Each kind of destination is a `Sink`; SNS and the generic webhook get the notification as
+//! JSON, while Slack gets a line of text.  Notifications are best effort, like telemetry, so
+//! failing to send one is logged but doesn't fail the subcommand.
+//!
+//! Subcommands that publish AMIs, like `ami` and `publish-ami`, record the build and the AMIs in
+//! the run's `Published`, so the notification sent when they finish lists them.
+
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::region_from_string;
+use crate::exit_code;
+use crate::Args;
+use aws_sdk_sns::Client as SnsClient;
+use chrono::Utc;
+use log::{debug, warn};
+use pubsys_config::{AwsConfig as PubsysAwsConfig, NotifyConfig, NotifyEvent};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use serde_json::json;
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use structopt::StructOpt;
+use tokio::runtime::Handle;
+use url::Url;
+
+/// SNS subjects are limited to 100 characters.
+const MAX_SNS_SUBJECT: usize = 100;
+
+/// Describes the build being published, for notifications
+#[derive(Debug, Default, StructOpt)]
+pub(crate) struct BuildInfo {
+    /// The variant of the build, included in notifications
+    #[structopt(long)]
+    pub(crate) variant: Option<String>,
+
+    /// The version of the build, included in notifications
+    #[structopt(long)]
+    pub(crate) version: Option<String>,
+}
+
+/// What a run published, for the notification sent when it finishes.  Clones record into the
+/// same place.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Published {
+    recorded: Arc<Mutex<Details>>,
+}
+
+impl Published {
+    /// Records the build being published, and the AMIs published for it if there are any, so
+    /// they're included in the notification.  AMIs recorded earlier in the run are replaced by
+    /// ones in the same region.
+    pub(crate) fn record(&self, build_info: &BuildInfo, amis: Option<&HashMap<String, Image>>) {
+        if let Ok(mut details) = self.recorded.lock() {
+            if build_info.variant.is_some() {
+                details.variant = build_info.variant.clone();
+            }
+            if build_info.version.is_some() {
+                details.version = build_info.version.clone();
+            }
+            for (region, image) in amis.into_iter().flatten() {
+                details.amis.insert(region.clone(), image.id.clone());
+            }
+        }
+    }
+
+    fn details(&self) -> Details {
+        self.recorded
+            .lock()
+            .map(|details| details.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The build and AMIs a run published
+#[derive(Clone, Debug, Default, Serialize)]
+struct Details {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// Mapping of region to AMI ID
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    amis: BTreeMap<String, String>,
+}
+
+/// A notification about a subcommand, as sent to SNS topics and webhooks
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    event: NotifyEvent,
+    subcommand: &'a str,
+    /// The named environment from Infra.toml, if one was chosen
+    environment: Option<&'a str>,
+    /// When the notification was sent, in RFC 3339 format
+    timestamp: String,
+    /// How long the subcommand ran, once it's finished
+    duration_seconds: Option<u64>,
+    /// The error that stopped the subcommand, if it failed
+    error: Option<String>,
+    /// The exit code pubsys will exit with, if the subcommand failed
+    exit_code: Option<i32>,
+    /// The build and AMIs the subcommand published, if it publishes any
+    #[serde(flatten)]
+    published: Details,
+}
+
+impl Notification<'_> {
+    /// Returns a one-line summary of the notification, for people.
+    fn summary(&self) -> String {
+        let environment = self
+            .environment
+            .map(|environment| format!(" in {}", environment))
+            .unwrap_or_default();
+        let duration = self
+            .duration_seconds
+            .map(|seconds| format!(" after {}m {}s", seconds / 60, seconds % 60))
+            .unwrap_or_default();
+        let outcome = match self.event {
+            NotifyEvent::Start => "started",
+            NotifyEvent::Success => "succeeded",
+            NotifyEvent::Failure => "failed",
+        };
+        let build = match (&self.published.variant, &self.published.version) {
+            (Some(variant), Some(version)) => format!(" for {} {}", variant, version),
+            (Some(name), None) | (None, Some(name)) => format!(" for {}", name),
+            (None, None) => String::new(),
+        };
+        let mut summary = format!(
+            "pubsys {}{} {}{}{}",
+            self.subcommand, environment, outcome, build, duration
+        );
+        if let Some(error) = &self.error {
+            summary.push_str(": ");
+            summary.push_str(error);
+        }
+        summary
+    }
+}
+
+/// A destination for notifications
+trait Sink {
+    /// Describes the destination, for log messages.  This shouldn't include secrets, like the
+    /// path of a Slack webhook URL.
+    fn describe(&self) -> String;
+
+    /// Sends the notification to the destination.
+    fn send(&self, notification: &Notification<'_>) -> Result<()>;
+}
+
+/// An SNS topic, which gets the notification as JSON with the summary as its subject
+struct SnsSink {
+    topic_arn: String,
+    aws: PubsysAwsConfig,
+    /// The run's runtime, which sends the requests
+    runtime: Handle,
+}
+
+impl SnsSink {
+    async fn publish(&self, subject: &str, message: &str) -> Result<()> {
+        // Topic ARNs look like arn:aws:sns:us-west-2:111122223333:name, and we have to talk to
+        // SNS in the topic's region.
+        let region = self
+            .topic_arn
+            .split(':')
+            .nth(3)
+            .filter(|region| !region.is_empty())
+            .map(region_from_string)
+            .context(error::TopicArnSnafu {
+                topic_arn: &self.topic_arn,
+            })?;
+        let client_config = build_client_config(&region, &region, &self.aws).await;
+
+        SnsClient::from_pubsys_config(&client_config, &self.aws)
+            .publish()
+            .topic_arn(&self.topic_arn)
+            .subject(subject)
+            .message(message)
+            .send()
+            .await
+            .context(error::SnsSnafu {
+                topic_arn: &self.topic_arn,
+            })?;
+        Ok(())
+    }
+}
+
+impl Sink for SnsSink {
+    fn describe(&self) -> String {
+        self.topic_arn.clone()
+    }
+
+    fn send(&self, notification: &Notification<'_>) -> Result<()> {
+        let message = serde_json::to_string(notification).context(error::SerializeSnafu)?;
+        let mut subject = notification.summary();
+        if subject.len() > MAX_SNS_SUBJECT {
+            let mut end = MAX_SNS_SUBJECT;
+            while !subject.is_char_boundary(end) {
+                end -= 1;
+            }
+            subject.truncate(end);
+        }
+        self.runtime.block_on(self.publish(&subject, &message))
+    }
+}
+
+/// A Slack incoming webhook, which gets the summary as the message text
+struct SlackSink {
+    webhook_url: Url,
+}
+
+impl Sink for SlackSink {
+    fn describe(&self) -> String {
+        format!("Slack webhook at {}", host(&self.webhook_url))
+    }
+
+    fn send(&self, notification: &Notification<'_>) -> Result<()> {
+        let body = json!({ "text": notification.summary() });
+        post(&self.webhook_url, &[], body.to_string())
+    }
+}
+
+/// A generic HTTPS endpoint, which gets the notification as JSON
+struct WebhookSink {
+    url: Url,
+    headers: Vec<(String, String)>,
+}
+
+impl Sink for WebhookSink {
+    fn describe(&self) -> String {
+        format!("webhook at {}", host(&self.url))
+    }
+
+    fn send(&self, notification: &Notification<'_>) -> Result<()> {
+        let body = serde_json::to_string(notification).context(error::SerializeSnafu)?;
+        post(&self.url, &self.headers, body)
+    }
+}
+
+/// Returns the host of a URL, which is all of it that's safe to log.
+fn host(url: &Url) -> &str {
+    url.host_str().unwrap_or_default()
+}
+
+/// POSTs a JSON body to the URL with the given extra headers.
+fn post(url: &Url, headers: &[(String, String)], body: String) -> Result<()> {
+    let mut request = reqwest::blocking::Client::new()
+        .post(url.as_str())
+        .header(CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request
+        .body(body)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .context(error::PostSnafu { host: host(url) })?;
+    Ok(())
+}
+
+/// Sends notifications about one run of a subcommand
+pub(crate) struct Notifier {
+    subcommand: String,
+    environment: Option<String>,
+    config: NotifyConfig,
+    sinks: Vec<Box<dyn Sink>>,
+    published: Published,
+    started: Instant,
+}
+
+impl Notifier {
+    /// Builds a notifier from the `notify` section of the infra config, which sends to SNS with
+    /// the given runtime.  If the config can't be loaded, the subcommand will say why, so we just
+    /// don't send notifications.
+    pub(crate) fn new(args: &Args, runtime: Handle) -> Self {
+        let subcommand = args.operation.as_deref().unwrap_or("pubsys").to_string();
+        let (config, aws) = match args.infra_config(true) {
+            Ok(infra_config) => (
+                infra_config.notify.unwrap_or_default(),
+                infra_config.aws.unwrap_or_default(),
+            ),
+            Err(e) => {
+                debug!(
+                    "Not sending notifications, since the config didn't load: {}",
+                    e
+                );
+                Default::default()
+            }
+        };
+
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        for sns in &config.sns {
+            sinks.push(Box::new(SnsSink {
+                topic_arn: sns.topic_arn.clone(),
+                aws: aws.clone(),
+                runtime: runtime.clone(),
+            }));
+        }
+        for slack in &config.slack {
+            sinks.push(Box::new(SlackSink {
+                webhook_url: slack.webhook_url.clone(),
+            }));
+        }
+        for webhook in &config.webhook {
+            let mut headers: Vec<_> = webhook.headers.clone().into_iter().collect();
+            headers.sort();
+            sinks.push(Box::new(WebhookSink {
+                url: webhook.url.clone(),
+                headers,
+            }));
+        }
+
+        Self {
+            subcommand,
+            environment: args.env.clone(),
+            config,
+            sinks,
+            published: args.state.published.clone(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Sends the notification that the subcommand is starting.
+    pub(crate) fn start(&mut self) {
+        self.started = Instant::now();
+        self.notify(NotifyEvent::Start, None);
+    }
+
+    /// Sends the notification that the subcommand succeeded or failed.
+    pub(crate) fn finish<T, E>(&self, result: &std::result::Result<T, E>)
+    where
+        E: StdError + 'static,
+    {
+        match result {
+            Ok(_) => self.notify(NotifyEvent::Success, None),
+            Err(e) => {
+                let error: &(dyn StdError + 'static) = e;
+                self.notify(NotifyEvent::Failure, Some(error))
+            }
+        }
+    }
+
+    fn notify(&self, event: NotifyEvent, error: Option<&(dyn StdError + 'static)>) {
+        if self.sinks.is_empty() || !wants(&self.config, &self.subcommand, event) {
+            return;
+        }
+        let notification = Notification {
+            event,
+            subcommand: &self.subcommand,
+            environment: self.environment.as_deref(),
+            timestamp: Utc::now().to_rfc3339(),
+            duration_seconds: match event {
+                NotifyEvent::Start => None,
+                _ => Some(self.started.elapsed().as_secs()),
+            },
+            error: error.map(|e| e.to_string()),
+            exit_code: error.map(exit_code::for_error),
+            published: self.published.details(),
+        };
+        for sink in &self.sinks {
+            debug!("Sending {:?} notification to {}", event, sink.describe());
+            if let Err(e) = sink.send(&notification) {
+                warn!("Failed to send notification to {}: {}", sink.describe(), e);
+            }
+        }
+    }
+}
+
+/// Returns whether the config asks for notifications about the event for the subcommand.
+fn wants(config: &NotifyConfig, subcommand: &str, event: NotifyEvent) -> bool {
+    (config.subcommands.is_empty() || config.subcommands.iter().any(|s| s == subcommand))
+        && (config.events.is_empty() || config.events.contains(&event))
+}
+
+mod error {
+    use aws_sdk_sns::error::PublishError;
+    use aws_sdk_sns::types::SdkError;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to send notification to {}: {}", host, source))]
+        Post {
+            host: String,
+            source: reqwest::Error,
+        },
+
+        #[snafu(display("Failed to serialize notification: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to publish notification to {}: {}", topic_arn, source))]
+        Sns {
+            topic_arn: String,
+            source: SdkError<PublishError>,
+        },
+
+        #[snafu(display("Invalid SNS topic ARN '{}'", topic_arn))]
+        TopicArn { topic_arn: String },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{wants, BuildInfo, Details, Notification, Published};
+    use crate::aws::ami::Image;
+    use pubsys_config::{NotifyConfig, NotifyEvent};
+    use std::collections::HashMap;
+
+    #[test]
+    fn summaries() {
+        let mut notification = Notification {
+            event: NotifyEvent::Start,
+            subcommand: "promote-ssm",
+            environment: Some("prod"),
+            timestamp: "2023-01-01T00:00:00+00:00".to_string(),
+            duration_seconds: None,
+            error: None,
+            exit_code: None,
+            published: Details::default(),
+        };
+        assert_eq!(notification.summary(), "pubsys promote-ssm in prod started");
+
+        notification.event = NotifyEvent::Failure;
+        notification.environment = None;
+        notification.duration_seconds = Some(125);
+        notification.error = Some("Infra.toml is missing aws.regions".to_string());
+        assert_eq!(
+            notification.summary(),
+            "pubsys promote-ssm failed after 2m 5s: Infra.toml is missing aws.regions"
+        );
+
+        notification.event = NotifyEvent::Success;
+        notification.subcommand = "publish-ami";
+        notification.error = None;
+        notification.published.variant = Some("aws-k8s-1.24".to_string());
+        notification.published.version = Some("1.13.0".to_string());
+        assert_eq!(
+            notification.summary(),
+            "pubsys publish-ami succeeded for aws-k8s-1.24 1.13.0 after 2m 5s"
+        );
+    }
+
+    #[test]
+    fn published_amis() {
+        let image = |id: &str| Image {
+            id: id.to_string(),
+            name: "bottlerocket".to_string(),
+            public: Some(true),
+            launch_permissions: None,
+            lineage: None,
+        };
+        let build_info = BuildInfo {
+            variant: Some("aws-k8s-1.24".to_string()),
+            version: Some("1.13.0".to_string()),
+        };
+        let published = Published::default();
+        published.record(&build_info, None);
+        assert!(published.details().amis.is_empty());
+
+        // A later subcommand in the same run, like publish-ami in a release, updates the list.
+        let mut amis = HashMap::new();
+        amis.insert("us-west-2".to_string(), image("ami-123"));
+        published.clone().record(&BuildInfo::default(), Some(&amis));
+        amis.insert("us-east-1".to_string(), image("ami-456"));
+        published.record(&BuildInfo::default(), Some(&amis));
+
+        let details = published.details();
+        assert_eq!(details.variant.as_deref(), Some("aws-k8s-1.24"));
+        assert_eq!(details.amis["us-west-2"], "ami-123");
+        assert_eq!(details.amis["us-east-1"], "ami-456");
+    }
+
+    #[test]
+    fn filters() {
+        let mut config = NotifyConfig::default();
+        assert!(wants(&config, "ami", NotifyEvent::Start));
+
+        config.subcommands = vec!["ami".to_string(), "promote-ssm".to_string()];
+        config.events = vec![NotifyEvent::Failure];
+        assert!(wants(&config, "ami", NotifyEvent::Failure));
+        assert!(!wants(&config, "ami", NotifyEvent::Start));
+        assert!(!wants(&config, "validate-ssm", NotifyEvent::Failure));
+    }
+}
@@ -0,0 +1,607 @@
+//! The apply module owns the 'apply' subcommand, which makes the changes in a plan written by a
+//! subcommand run with `--plan`.  Every assumption the plan recorded about live state is checked
+//! before any change is made, so a plan that's gone stale fails without changing anything.
+//!
+//! Changes are made one at a time, in the plan's order, except that consecutive parameter changes
+//! are made together, and are then tagged and validated like the `ssm` subcommand does.
+
+use super::{Mutation, Plan};
+use crate::aws::ami::block_public_access;
+use crate::aws::ami::launch_permissions::{get_launch_permissions, LaunchPermissionDef};
+use crate::aws::ami::lineage::tag_image;
+use crate::aws::ami::register::get_ami_id;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::publish_ami::verify::get_volume_permissions;
+use crate::aws::publish_ami::{
+    get_snapshots, modify_image, modify_rate_limiter, modify_snapshots, ModifyOptions,
+};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::ssm::{ssm, SsmKey, SsmParameters};
+use crate::aws::tags::{ssm_tags, tag_ec2_resources};
+use crate::exit_code::{Classify, Kind};
+use crate::run_state::RunState;
+use crate::Args;
+use aws_config::SdkConfig;
+use aws_sdk_ec2::model::{ArchitectureValues, OperationType};
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use log::{error, info};
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+/// Makes the changes in a plan written by a subcommand run with --plan
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ApplyArgs {
+    /// Path to the plan
+    #[structopt(parse(from_os_str))]
+    plan_path: PathBuf,
+}
+
+/// The clients for every region a plan touches, and the rate limits their requests share
+struct Clients {
+    configs: HashMap<Region, SdkConfig>,
+    ec2: HashMap<Region, Ec2Client>,
+    ssm: HashMap<Region, SsmClient>,
+    rate_limits: RateLimits,
+}
+
+impl Clients {
+    async fn new(mutations: &[Mutation], aws: &PubsysAwsConfig) -> Self {
+        let mut regions = HashSet::new();
+        for mutation in mutations {
+            match mutation {
+                Mutation::CopyImage {
+                    region,
+                    source_region,
+                    ..
+                } => {
+                    regions.insert(region);
+                    regions.insert(source_region);
+                }
+                Mutation::RegisterImage { region, .. }
+                | Mutation::DisableBlockPublicAccess { region }
+                | Mutation::ModifyImagePermissions { region, .. }
+                | Mutation::ModifySnapshotPermissions { region, .. }
+                | Mutation::PutParameter { region, .. } => {
+                    regions.insert(region);
+                }
+            }
+        }
+
+        let mut clients = Self {
+            configs: HashMap::with_capacity(regions.len()),
+            ec2: HashMap::with_capacity(regions.len()),
+            ssm: HashMap::with_capacity(regions.len()),
+            rate_limits: RateLimits::new(aws),
+        };
+        for name in regions {
+            let region = Region::new(name.clone());
+            let client_config = build_client_config(&region, &region, aws).await;
+            clients.ec2.insert(
+                region.clone(),
+                Ec2Client::from_pubsys_config(&client_config, aws),
+            );
+            clients.ssm.insert(
+                region.clone(),
+                SsmClient::from_pubsys_config(&client_config, aws),
+            );
+            clients.configs.insert(region, client_config);
+        }
+        clients
+    }
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, apply_args: &ApplyArgs) -> Result<()> {
+    let path = &apply_args.plan_path;
+    let plan_str = fs::read_to_string(path).context(error::ReadSnafu { path })?;
+    let plan: Plan = serde_json::from_str(&plan_str).context(error::ParseSnafu { path })?;
+    // The environment decides the credentials and roles, so it has to be the one planned with.
+    ensure!(
+        plan.environment == args.env,
+        error::EnvironmentSnafu {
+            planned: plan.environment.as_deref().unwrap_or("none"),
+            given: args.env.as_deref().unwrap_or("none"),
+        }
+    );
+    // Only dry runs record registrations, so a plan that has one wasn't written by pubsys.
+    if let Some(Mutation::RegisterImage { name, region, .. }) = plan
+        .mutations
+        .iter()
+        .find(|mutation| matches!(mutation, Mutation::RegisterImage { .. }))
+    {
+        return error::RegisterSnafu { name, region }.fail();
+    }
+    if plan.mutations.is_empty() {
+        info!("The plan has no changes to make");
+        return Ok(());
+    }
+    info!(
+        "Applying {} changes planned by {} at {}",
+        plan.mutations.len(),
+        plan.subcommand,
+        plan.created
+    );
+
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
+    let aws = infra_config.aws.unwrap_or_default();
+    let clients = Clients::new(&plan.mutations, &aws).await;
+
+    info!("Checking that live state still matches the plan");
+    let drift = check_drift(&plan.mutations, &clients).await?;
+    if !drift.is_empty() {
+        for difference in &drift {
+            error!("{}", difference);
+        }
+        return error::DriftSnafu { count: drift.len() }.fail();
+    }
+
+    let rate_limiter = modify_rate_limiter();
+    let mut parameters = SsmParameters::new();
+    for mutation in plan.mutations {
+        if args.state.interrupt.requested() {
+            break;
+        }
+        if let Mutation::PutParameter {
+            region,
+            name,
+            value,
+            ..
+        } = mutation
+        {
+            parameters.insert(SsmKey::new(Region::new(region), name), value);
+            continue;
+        }
+        set_parameters(&args.state, &mut parameters, &clients, &aws).await?;
+
+        info!("Applying: {}", mutation.describe());
+        match mutation {
+            Mutation::CopyImage {
+                region,
+                source_region,
+                source_image_id,
+                name,
+                description,
+                kms_key_id,
+                lineage,
+                ..
+            } => {
+                let region = Region::new(region);
+                let ec2_client = &clients.ec2[&region];
+                let output = clients
+                    .rate_limits
+                    .rate_limited(
+                        EC2,
+                        region.as_ref(),
+                        ec2_client
+                            .copy_image()
+                            .set_description(Some(description))
+                            .set_name(Some(name))
+                            .set_source_image_id(Some(source_image_id))
+                            .set_source_region(Some(source_region))
+                            .set_encrypted(kms_key_id.as_ref().map(|_| true))
+                            .set_kms_key_id(kms_key_id)
+                            .send(),
+                    )
+                    .await
+                    .context(error::CopyImageSnafu {
+                        region: region.as_ref(),
+                    })?;
+                let image_id = output.image_id.context(error::MissingImageIdSnafu {
+                    region: region.as_ref(),
+                })?;
+                info!("Copy in {} is {}", region, image_id);
+                tag_image(
+                    ec2_client,
+                    &clients.rate_limits,
+                    region.as_ref(),
+                    &image_id,
+                    &lineage,
+                )
+                .await
+                .context(error::TagLineageSnafu)?;
+                tag_ec2_resources(
+                    ec2_client,
+                    &clients.rate_limits,
+                    region.as_ref(),
+                    &[image_id],
+                    &aws,
+                )
+                .await
+                .context(error::TagSnafu)?;
+            }
+            Mutation::DisableBlockPublicAccess { region } => {
+                let region = Region::new(region);
+                block_public_access::disable(
+                    &clients.configs[&region],
+                    &aws,
+                    &clients.rate_limits,
+                    &region,
+                )
+                .await
+                .context(error::DisableBlockPublicAccessSnafu {
+                    region: region.as_ref(),
+                })?;
+            }
+            Mutation::ModifyImagePermissions {
+                region,
+                image_id,
+                operation,
+                grantees,
+                ..
+            } => {
+                let region = Region::new(region);
+                modify_image(
+                    &ModifyOptions::from(&grantees),
+                    &OperationType::from(operation.as_str()),
+                    &image_id,
+                    &clients.ec2[&region],
+                    &region,
+                    &rate_limiter,
+                    &clients.rate_limits,
+                )
+                .await
+                .context(error::ModifyImageSnafu {
+                    image_id: &image_id,
+                    region: region.as_ref(),
+                })?;
+            }
+            Mutation::ModifySnapshotPermissions {
+                region,
+                snapshot_id,
+                operation,
+                grantees,
+                ..
+            } => {
+                let region = Region::new(region);
+                modify_snapshots(
+                    &args.state.plan,
+                    &ModifyOptions::from(&grantees),
+                    &OperationType::from(operation.as_str()),
+                    &[snapshot_id],
+                    &clients.ec2[&region],
+                    &region,
+                    &rate_limiter,
+                    &clients.rate_limits,
+                )
+                .await
+                .context(error::ModifySnapshotSnafu)?;
+            }
+            Mutation::PutParameter { .. } => unreachable!("parameters are set in batches above"),
+            Mutation::RegisterImage { .. } => unreachable!("registrations are rejected above"),
+        }
+    }
+    if !args.state.interrupt.requested() {
+        set_parameters(&args.state, &mut parameters, &clients, &aws).await?;
+    }
+
+    info!("Applied plan from {}", path.display());
+    Ok(())
+}
+
+/// Returns a description of each way live state differs from what the plan assumed.
+async fn check_drift(mutations: &[Mutation], clients: &Clients) -> Result<Vec<String>> {
+    let mut drift = Vec::new();
+    let mut parameters = Vec::new();
+    for mutation in mutations {
+        match mutation {
+            Mutation::CopyImage {
+                region,
+                source_region,
+                source_image_id,
+                name,
+                arch,
+                lineage,
+                ..
+            } => {
+                let region = Region::new(region.clone());
+                let arch = ArchitectureValues::from(arch.as_str());
+                let existing = get_ami_id(
+                    name.as_str(),
+                    &arch,
+                    &region,
+                    &clients.ec2[&region],
+                    &clients.rate_limits,
+                )
+                .await
+                .context(error::GetAmiIdSnafu {
+                    region: region.as_ref(),
+                })?;
+                if let Some(id) = existing {
+                    drift.push(format!(
+                        "An AMI named '{}' now exists in {}: {}",
+                        name, region, id
+                    ));
+                }
+
+                let source_region = Region::new(source_region.clone());
+                let snapshot_ids = get_snapshots(
+                    source_image_id,
+                    &source_region,
+                    &clients.ec2[&source_region],
+                    &clients.rate_limits,
+                )
+                .await
+                .context(error::GetSnapshotsSnafu {
+                    image_id: source_image_id,
+                    region: source_region.as_ref(),
+                })?;
+                if snapshot_ids != lineage.source_snapshot_ids {
+                    drift.push(format!(
+                        "Source AMI {} in {} now has snapshots [{}] rather than [{}]",
+                        source_image_id,
+                        source_region,
+                        snapshot_ids.join(", "),
+                        lineage.source_snapshot_ids.join(", ")
+                    ));
+                }
+            }
+            Mutation::RegisterImage { .. } => unreachable!("registrations are rejected in run"),
+            // Disabling Image Block Public Access doesn't depend on anything.
+            Mutation::DisableBlockPublicAccess { .. } => {}
+            Mutation::ModifyImagePermissions {
+                region,
+                image_id,
+                current,
+                ..
+            } => {
+                let live = get_launch_permissions(
+                    &clients.ec2[&Region::new(region.clone())],
+                    &clients.rate_limits,
+                    region,
+                    image_id,
+                )
+                .await
+                .context(error::GetLaunchPermissionsSnafu {
+                    image_id,
+                    region: region.as_str(),
+                })?;
+                if !same_permissions(&live, current) {
+                    drift.push(format!(
+                        "Launch permissions of {} in {} changed since the plan was made",
+                        image_id, region
+                    ));
+                }
+            }
+            Mutation::ModifySnapshotPermissions {
+                region,
+                snapshot_id,
+                current,
+                ..
+            } => {
+                let region = Region::new(region.clone());
+                let live = get_volume_permissions(
+                    &clients.ec2[&region],
+                    &clients.rate_limits,
+                    &region,
+                    snapshot_id,
+                )
+                .await
+                .context(error::GetVolumePermissionsSnafu)?;
+                if !same_permissions(&live, current) {
+                    drift.push(format!(
+                        "Create-volume permissions of {} in {} changed since the plan was made",
+                        snapshot_id, region
+                    ));
+                }
+            }
+            Mutation::PutParameter {
+                region,
+                name,
+                current,
+                ..
+            } => {
+                parameters.push((
+                    SsmKey::new(Region::new(region.clone()), name.clone()),
+                    current,
+                ));
+            }
+        }
+    }
+
+    if !parameters.is_empty() {
+        let keys: Vec<&SsmKey> = parameters.iter().map(|(key, _)| key).collect();
+        let live = ssm::get_parameters(&keys, &clients.ssm, &clients.rate_limits)
+            .await
+            .context(error::GetParametersSnafu)?;
+        for (key, current) in &parameters {
+            let live_value = live.get(key);
+            if live_value != current.as_ref() {
+                drift.push(format!(
+                    "{} in {} is {} rather than {}",
+                    key.name,
+                    key.region,
+                    describe_value(live_value),
+                    describe_value(current.as_ref())
+                ));
+            }
+        }
+    }
+    Ok(drift)
+}
+
+/// Sets, tags, and validates the given parameters, and clears them.
+async fn set_parameters(
+    state: &RunState,
+    parameters: &mut SsmParameters,
+    clients: &Clients,
+    aws: &PubsysAwsConfig,
+) -> Result<()> {
+    if parameters.is_empty() {
+        return Ok(());
+    }
+    info!("Applying: set {} SSM parameters", parameters.len());
+    ssm::set_parameters(parameters, &clients.ssm, &clients.rate_limits, state)
+        .await
+        .context(error::SetParametersSnafu)?;
+    if !aws.tags.is_empty() {
+        ssm::tag_parameters(
+            parameters,
+            &clients.ssm,
+            &clients.rate_limits,
+            &ssm_tags(aws),
+        )
+        .await
+        .context(error::SetParametersSnafu)?;
+    }
+    ssm::validate_parameters(parameters, &clients.ssm, &clients.rate_limits)
+        .await
+        .context(error::SetParametersSnafu)?;
+    parameters.clear();
+    Ok(())
+}
+
+/// Returns whether two lists of permissions have the same entries, in any order.
+fn same_permissions(a: &[LaunchPermissionDef], b: &[LaunchPermissionDef]) -> bool {
+    a.iter().collect::<HashSet<_>>() == b.iter().collect::<HashSet<_>>()
+}
+
+fn describe_value(value: Option<&String>) -> String {
+    match value {
+        Some(value) => format!("'{}'", value),
+        None => "unset".to_string(),
+    }
+}
+
+mod error {
+    use crate::aws::ami::{block_public_access, launch_permissions, lineage, register};
+    use crate::aws::publish_ami::{self, verify};
+    use crate::aws::ssm::ssm;
+    use aws_sdk_ec2::error::{CopyImageError, ModifyImageAttributeError};
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to copy AMI to {}: {}", region, DisplayErrorContext(source)))]
+        CopyImage {
+            region: String,
+            source: SdkError<CopyImageError>,
+        },
+
+        #[snafu(display(
+            "Failed to disable Image Block Public Access in {}: {}",
+            region,
+            source
+        ))]
+        DisableBlockPublicAccess {
+            region: String,
+            source: block_public_access::Error,
+        },
+
+        #[snafu(display(
+            "Live state changed in {} ways since the plan was made; make a new plan",
+            count
+        ))]
+        Drift { count: usize },
+
+        #[snafu(display(
+            "The plan was made with environment '{}', but apply was given '{}'",
+            planned,
+            given
+        ))]
+        Environment { planned: String, given: String },
+
+        #[snafu(display("Failed to look for AMI in {}: {}", region, source))]
+        GetAmiId {
+            region: String,
+            source: register::Error,
+        },
+
+        #[snafu(display(
+            "Failed to get launch permissions of {} in {}: {}",
+            image_id,
+            region,
+            source
+        ))]
+        GetLaunchPermissions {
+            image_id: String,
+            region: String,
+            source: launch_permissions::Error,
+        },
+
+        #[snafu(display("Failed to get current SSM parameters: {}", source))]
+        GetParameters { source: ssm::Error },
+
+        #[snafu(display("Failed to get snapshots of {} in {}: {}", image_id, region, source))]
+        GetSnapshots {
+            image_id: String,
+            region: String,
+            source: publish_ami::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        GetVolumePermissions { source: verify::Error },
+
+        #[snafu(display("Copy to {} didn't return an AMI ID", region))]
+        MissingImageId { region: String },
+
+        #[snafu(display(
+            "Failed to modify permissions of {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        ModifyImage {
+            image_id: String,
+            region: String,
+            source: SdkError<ModifyImageAttributeError>,
+        },
+
+        #[snafu(display("{}", source))]
+        ModifySnapshot { source: publish_ami::Error },
+
+        #[snafu(display("Failed to parse plan '{}': {}", path.display(), source))]
+        Parse {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read plan '{}': {}", path.display(), source))]
+        Read {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display(
+            "The plan registers '{}' in {}, which only a dry run can record; run `pubsys ami` \
+             without --plan to register it",
+            name,
+            region
+        ))]
+        Register { name: String, region: String },
+
+        #[snafu(display("Failed to set SSM parameters: {}", source))]
+        SetParameters { source: ssm::Error },
+
+        #[snafu(display("{}", source))]
+        Tag { source: crate::aws::tags::Error },
+
+        #[snafu(display("{}", source))]
+        TagLineage { source: lineage::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::Environment { .. } | Self::Register { .. } => Some(Kind::Config),
+            Self::CopyImage { .. } | Self::ModifyImage { .. } => Some(Kind::AwsApi),
+            Self::Drift { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! The plan module lets mutating subcommands run in two phases, so that a change can be reviewed
+//! before it's made.  With `--plan <file>`, the `ami`, `publish-ami`, `ssm`, and `promote-ssm`
+//! subcommands read everything they normally would, but rather than copying AMIs, changing
+//! permissions, or setting parameters, they record each change as a `Mutation` and write the
+//! list to the file.  `pubsys apply <file>` then makes exactly those changes; see `apply`.
+//!
+//! Each mutation also records the live state it was planned against, like the current value of a
+//! parameter, so that `apply` can refuse to run a plan that no longer matches the world.
+//!
+//! With `--dry-run`, the same subcommands plan without writing a file, logging each change they
+//! would make instead.  The other subcommands that take `--dry-run`, like `repo` and `gc-repo`,
+//! handle it themselves.
+
+pub(crate) mod apply;
+
+use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+use crate::aws::ami::lineage::Lineage;
+use crate::aws::publish_ami::ModifyOptions;
+use crate::Args;
+use chrono::Utc;
+use log::info;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The changes a subcommand would make
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct Plan {
+    /// The subcommand that made the plan, like "publish-ami"
+    pub(crate) subcommand: String,
+    /// The named environment from Infra.toml the plan was made with, if any; `apply` has to use
+    /// the same one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) environment: Option<String>,
+    /// When the plan was made, in RFC 3339 format
+    pub(crate) created: String,
+    /// The changes, in the order they have to be made
+    pub(crate) mutations: Vec<Mutation>,
+}
+
+/// One change to live state, and what the state was when it was planned
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub(crate) enum Mutation {
+    /// Register an AMI from the built images.  Only dry runs record this; registering uploads the
+    /// snapshots, which a plan can't put off until it's applied.
+    RegisterImage {
+        region: String,
+        name: String,
+        arch: String,
+    },
+
+    /// Copy an AMI into another region; planned while no AMI of the same name was there
+    CopyImage {
+        region: String,
+        source_region: String,
+        source_image_id: String,
+        name: String,
+        description: String,
+        arch: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kms_key_id: Option<String>,
+        /// Tagged on the copy; also records the snapshots the source AMI had when planned
+        lineage: Lineage,
+    },
+
+    /// Disable Image Block Public Access in a region, so AMIs can be made public
+    DisableBlockPublicAccess { region: String },
+
+    /// Add or remove launch permissions on an AMI
+    ModifyImagePermissions {
+        region: String,
+        image_id: String,
+        /// "add" or "remove"
+        operation: String,
+        #[serde(flatten)]
+        grantees: Grantees,
+        /// The AMI's launch permissions when the change was planned
+        current: Vec<LaunchPermissionDef>,
+    },
+
+    /// Add or remove create-volume permissions on a snapshot
+    ModifySnapshotPermissions {
+        region: String,
+        snapshot_id: String,
+        /// "add" or "remove"
+        operation: String,
+        #[serde(flatten)]
+        grantees: Grantees,
+        /// The snapshot's create-volume permissions when the change was planned
+        current: Vec<LaunchPermissionDef>,
+    },
+
+    /// Set an SSM parameter
+    PutParameter {
+        region: String,
+        name: String,
+        value: String,
+        /// The parameter's value when the change was planned, or none if it didn't exist
+        current: Option<String>,
+    },
+}
+
+impl Mutation {
+    /// Returns a one-line description of the change, for people reviewing the plan.
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Mutation::RegisterImage { region, name, arch } => {
+                format!("register {} ({}) in {}", name, arch, region)
+            }
+            Mutation::CopyImage {
+                region,
+                source_region,
+                source_image_id,
+                name,
+                ..
+            } => format!(
+                "copy {} ({}) from {} to {}",
+                source_image_id, name, source_region, region
+            ),
+            Mutation::DisableBlockPublicAccess { region } => {
+                format!("disable Image Block Public Access in {}", region)
+            }
+            Mutation::ModifyImagePermissions {
+                region,
+                image_id,
+                operation,
+                grantees,
+                ..
+            } => format!(
+                "{} launch permissions on {} in {} for {}",
+                operation,
+                image_id,
+                region,
+                grantees.describe()
+            ),
+            Mutation::ModifySnapshotPermissions {
+                region,
+                snapshot_id,
+                operation,
+                grantees,
+                ..
+            } => format!(
+                "{} create-volume permissions on {} in {} for {}",
+                operation,
+                snapshot_id,
+                region,
+                grantees.describe()
+            ),
+            Mutation::PutParameter {
+                region,
+                name,
+                value,
+                current,
+            } => match current {
+                Some(current) => format!(
+                    "set {} in {} to '{}' (was '{}')",
+                    name, region, value, current
+                ),
+                None => format!("create {} in {} as '{}'", name, region, value),
+            },
+        }
+    }
+}
+
+/// Who a permission change is for; the serializable form of `ModifyOptions`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct Grantees {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) user_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) group_names: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) organization_arns: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) organizational_unit_arns: Vec<String>,
+}
+
+impl Grantees {
+    fn describe(&self) -> String {
+        self.user_ids
+            .iter()
+            .chain(&self.group_names)
+            .chain(&self.organization_arns)
+            .chain(&self.organizational_unit_arns)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl From<&ModifyOptions> for Grantees {
+    fn from(modify_opts: &ModifyOptions) -> Self {
+        Self {
+            user_ids: modify_opts.user_ids.clone(),
+            group_names: modify_opts.group_names.clone(),
+            organization_arns: modify_opts.organization_arns.clone(),
+            organizational_unit_arns: modify_opts.organizational_unit_arns.clone(),
+        }
+    }
+}
+
+impl From<&Grantees> for ModifyOptions {
+    fn from(grantees: &Grantees) -> Self {
+        Self {
+            user_ids: grantees.user_ids.clone(),
+            group_names: grantees.group_names.clone(),
+            organization_arns: grantees.organization_arns.clone(),
+            organizational_unit_arns: grantees.organizational_unit_arns.clone(),
+        }
+    }
+}
+
+/// The plan a run is recording, if its subcommand was given `--plan` or `--dry-run`, and the file
+/// to write it to, if it's not a dry run.  Clones record into the same plan.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Recorder {
+    recording: Arc<Mutex<Option<(Option<PathBuf>, Plan)>>>,
+}
+
+/// How a subcommand handles `--plan` and `--dry-run`
+pub(crate) enum Support {
+    /// Records its changes with a `Recorder`, so it can plan and do dry runs
+    Plans,
+    /// Can't plan, but shows what it would change on its own when given `--dry-run`
+    DryRunsItself,
+    /// Can't plan or do dry runs
+    Unsupported,
+}
+
+/// If the arguments ask for a plan or a dry run, starts recording one, failing if the subcommand
+/// can't plan.
+pub(crate) fn start(args: &Args) -> Result<Recorder> {
+    let subcommand = args.operation.as_deref().unwrap_or("pubsys").to_string();
+    let support = args.subcommand.plan_support();
+    let plans = matches!(support, Support::Plans);
+    let path = match &args.plan {
+        Some(path) => {
+            ensure!(plans, error::UnsupportedSnafu { subcommand });
+            info!(
+                "Planning changes rather than making them; the plan will be written to {}",
+                path.display()
+            );
+            Some(path.clone())
+        }
+        None if args.dry_run => {
+            if matches!(support, Support::DryRunsItself) {
+                return Ok(Recorder::default());
+            }
+            ensure!(plans, error::UnsupportedDryRunSnafu { subcommand });
+            info!("Dry run; logging the changes that would be made rather than making them");
+            None
+        }
+        None => return Ok(Recorder::default()),
+    };
+    let plan = Plan {
+        subcommand,
+        environment: args.env.clone(),
+        created: Utc::now().to_rfc3339(),
+        mutations: Vec::new(),
+    };
+    Ok(Recorder {
+        recording: Arc::new(Mutex::new(Some((path, plan)))),
+    })
+}
+
+impl Recorder {
+    /// Returns whether the subcommand should record its changes rather than make them.
+    pub(crate) fn planning(&self) -> bool {
+        self.recording
+            .lock()
+            .map(|recording| recording.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Returns whether the subcommand is only logging its changes, with no plan to write.
+    pub(crate) fn dry_run(&self) -> bool {
+        self.recording
+            .lock()
+            .map(|recording| matches!(*recording, Some((None, _))))
+            .unwrap_or(false)
+    }
+
+    /// Records a change in the plan.
+    pub(crate) fn add(&self, mutation: Mutation) {
+        if let Ok(mut recording) = self.recording.lock() {
+            if let Some((path, plan)) = recording.as_mut() {
+                if path.is_some() {
+                    info!("Planned: {}", mutation.describe());
+                } else {
+                    info!("Would {}", mutation.describe());
+                }
+                plan.mutations.push(mutation);
+            }
+        }
+    }
+
+    /// Writes the recorded plan, if there is one, and stops recording.
+    pub(crate) fn finish(&self) -> Result<()> {
+        let taken = self
+            .recording
+            .lock()
+            .ok()
+            .and_then(|mut recording| recording.take());
+        let (path, plan) = match taken {
+            Some((Some(path), plan)) => (path, plan),
+            Some((None, plan)) => {
+                info!(
+                    "Dry run; made none of the {} changes listed above",
+                    plan.mutations.len()
+                );
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let json = serde_json::to_string_pretty(&plan).context(error::SerializeSnafu)?;
+        fs::write(&path, json).context(error::WriteSnafu { path: &path })?;
+        info!(
+            "Wrote plan of {} changes to {}; run `pubsys apply {}` to make them",
+            plan.mutations.len(),
+            path.display(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to serialize plan: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display(
+            "The {} subcommand can't plan; --plan is supported by ami, publish-ami, ssm, and promote-ssm",
+            subcommand
+        ))]
+        Unsupported { subcommand: String },
+
+        #[snafu(display(
+            "The {} subcommand can't do a dry run; --dry-run is supported by ami, publish-ami, ssm, promote-ssm, rollback-ssm, repo, gc-repo, eol, and lock regenerate",
+            subcommand
+        ))]
+        UnsupportedDryRun { subcommand: String },
+
+        #[snafu(display("Failed to write plan to '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{Grantees, Mutation, Plan};
+    use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+
+    #[test]
+    fn plan_round_trip() {
+        let plan = Plan {
+            subcommand: "publish-ami".to_string(),
+            environment: Some("prod".to_string()),
+            created: "2023-01-01T00:00:00+00:00".to_string(),
+            mutations: vec![
+                Mutation::ModifyImagePermissions {
+                    region: "us-west-2".to_string(),
+                    image_id: "ami-123".to_string(),
+                    operation: "add".to_string(),
+                    grantees: Grantees {
+                        group_names: vec!["all".to_string()],
+                        ..Default::default()
+                    },
+                    current: vec![LaunchPermissionDef::UserId("012345678901".to_string())],
+                },
+                Mutation::PutParameter {
+                    region: "us-west-2".to_string(),
+                    name: "/bottlerocket/latest/image_id".to_string(),
+                    value: "ami-123".to_string(),
+                    current: None,
+                },
+            ],
+        };
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains(r#""action":"modify-image-permissions""#));
+        assert!(json.contains(r#""group_names":["all"]"#));
+        assert!(!json.contains("user_ids"));
+        assert_eq!(serde_json::from_str::<Plan>(&json).unwrap(), plan);
+    }
+
+    #[test]
+    fn descriptions() {
+        let put = Mutation::PutParameter {
+            region: "us-east-1".to_string(),
+            name: "/a".to_string(),
+            value: "new".to_string(),
+            current: Some("old".to_string()),
+        };
+        assert_eq!(put.describe(), "set /a in us-east-1 to 'new' (was 'old')");
+
+        let grant = Mutation::ModifySnapshotPermissions {
+            region: "us-east-1".to_string(),
+            snapshot_id: "snap-1".to_string(),
+            operation: "remove".to_string(),
+            grantees: Grantees {
+                user_ids: vec!["012345678901".to_string()],
+                group_names: vec!["all".to_string()],
+                ..Default::default()
+            },
+            current: Vec::new(),
+        };
+        assert_eq!(
+            grant.describe(),
+            "remove create-volume permissions on snap-1 in us-east-1 for 012345678901, all"
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! The progress module draws progress bars for operations that work through many regions or
+//! files, so interactive users can see how far along they are and how long is left.
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// Creates a progress bar counting `len` steps of work, labeled with `verb`.  If the user doesn't
+/// want progress bars, the returned bar is hidden, so callers can update it either way.  Bars are
+/// drawn to stderr, and only if it's a terminal.
+pub(crate) fn progress_bar(no_progress: bool, len: usize, verb: &str) -> ProgressBar {
+    if no_progress {
+        return ProgressBar::hidden();
+    }
+    let progress_bar = ProgressBar::new(len as u64);
+    // The template is the same as the one used for snapshot uploads, which is known to parse, so
+    // there's no need to fail the operation if it somehow doesn't; the default style is fine.
+    if let Ok(style) = ProgressStyle::with_template(
+        &["  ", verb, "  [{bar:50.white/black}] {pos}/{len} ({eta})"].concat(),
+    ) {
+        progress_bar.set_style(style.progress_chars("=> "));
+    }
+    progress_bar
+}
@@ -0,0 +1,733 @@
+//! The release module owns the 'release' subcommand, which runs the whole publication sequence
+//! for a release from one spec file: registering and copying AMIs, publishing them, setting SSM
+//! parameters, and building the repo.  Each stage is a table in the spec holding the arguments of
+//! the subcommand it runs, by their long option names:
+//!
+//! ```toml
+//! [ami]
+//! os-image = "build/images/x86_64-aws-k8s-1.24/latest/bottlerocket-aws-k8s-1.24-x86_64.img.lz4"
+//! variant-manifest = "variants/aws-k8s-1.24/Cargo.toml"
+//! arch = "x86_64"
+//! ami-output = "build/amis.json"
+//!
+//! [publish-ami]
+//! ami-input = "build/amis.json"
+//! grant = true
+//! group-names = ["all"]
+//!
+//! [ssm]
+//! ami-input = "build/amis.json"
+//! # ...
+//!
+//! [repo]
+//! repo = "default"
+//! # ...
+//! ```
+//!
+//! Stages always run in that order, and stages not in the spec are skipped.  Flags are given as
+//! `true`, and lists as arrays or comma-separated strings.  Each stage's arguments are converted
+//! into the options of the library function it runs, and all of them are checked before the first
+//! stage runs.
+//!
+//! After each stage finishes, it's recorded in a checkpoint file next to the spec, so if a stage
+//! fails or the release is interrupted, running the same command again resumes with the stage that
+//! didn't finish.  The checkpoint records the spec's digest, and is only used with the spec it was
+//! written for.
+
+use crate::aws::ami::AmiOptions;
+use crate::aws::parse_arch;
+use crate::aws::publish_ami::{ModifyOptions, PublishMode, PublishOptions};
+use crate::aws::ssm::SsmOptions;
+use crate::exit_code::{Classify, Kind};
+use crate::notify::BuildInfo;
+use crate::repo::{ExpirationOverrides, RepoOptions};
+use crate::{aws, friendly_version, repo, Args};
+use log::info;
+use parse_datetime::{parse_datetime, parse_offset};
+use pubsys_config::RepoExpirationPolicy;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+
+/// Runs the publication stages of a release from a spec file, resuming where a previous run stopped
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ReleaseArgs {
+    /// Path to the release spec, a TOML file with a table of arguments for each stage to run: ami,
+    /// publish-ami, ssm, and repo
+    #[structopt(long, parse(from_os_str))]
+    spec: PathBuf,
+
+    /// Path to the checkpoint file; defaults to the spec path with ".checkpoint.json" added
+    #[structopt(long, parse(from_os_str))]
+    checkpoint_path: Option<PathBuf>,
+
+    /// Ignore any checkpoint and run every stage from the start
+    #[structopt(long)]
+    restart: bool,
+}
+
+/// The arguments of one stage, by long option name
+type StageArgs = BTreeMap<String, toml::Value>;
+
+/// The stages of a release, in the order they run
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ReleaseSpec {
+    ami: Option<StageArgs>,
+    publish_ami: Option<StageArgs>,
+    ssm: Option<StageArgs>,
+    repo: Option<StageArgs>,
+}
+
+/// A stage with its options, ready to run
+#[derive(Debug)]
+enum Stage {
+    Ami {
+        options: AmiOptions,
+        ami_output: Option<PathBuf>,
+    },
+    PublishAmi {
+        options: PublishOptions,
+        build_info: BuildInfo,
+    },
+    Ssm(SsmOptions),
+    Repo(RepoOptions),
+}
+
+impl Stage {
+    /// The stage's name, as in the spec and checkpoint
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Ami { .. } => "ami",
+            Stage::PublishAmi { .. } => "publish-ami",
+            Stage::Ssm(_) => "ssm",
+            Stage::Repo(_) => "repo",
+        }
+    }
+}
+
+/// What's recorded about a release's progress
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+struct Checkpoint {
+    /// The SHA-256 digest of the spec the stages were run with
+    spec_sha256: String,
+    /// The names of the stages that finished
+    completed: Vec<String>,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, release_args: &ReleaseArgs) -> Result<()> {
+    let spec_str = fs::read_to_string(&release_args.spec).context(error::FileSnafu {
+        op: "read",
+        path: &release_args.spec,
+    })?;
+    let spec: ReleaseSpec = toml::from_str(&spec_str).context(error::ParseSpecSnafu {
+        path: &release_args.spec,
+    })?;
+    let stages = parse_stages(spec, args.dry_run)?;
+    ensure!(
+        !stages.is_empty(),
+        error::NoStagesSnafu {
+            path: &release_args.spec
+        }
+    );
+
+    let spec_sha256 = hex::encode(digest(&SHA256, spec_str.as_bytes()));
+    let checkpoint_path = match &release_args.checkpoint_path {
+        Some(path) => path.clone(),
+        None => {
+            let mut path = release_args.spec.clone().into_os_string();
+            path.push(".checkpoint.json");
+            PathBuf::from(path)
+        }
+    };
+    let mut checkpoint = if release_args.restart {
+        Checkpoint::default()
+    } else {
+        read_checkpoint(&checkpoint_path)?.unwrap_or_default()
+    };
+    if checkpoint.completed.is_empty() {
+        checkpoint.spec_sha256 = spec_sha256;
+    } else {
+        ensure!(
+            checkpoint.spec_sha256 == spec_sha256,
+            error::SpecChangedSnafu {
+                spec: &release_args.spec,
+                checkpoint: &checkpoint_path,
+            }
+        );
+    }
+
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    for stage in &stages {
+        let name = stage.name();
+        if checkpoint.completed.iter().any(|done| done == name) {
+            info!(
+                "Skipping stage '{}', which finished in a previous run",
+                name
+            );
+            continue;
+        }
+        if args.state.interrupt.requested() {
+            // The top level reports the interruption; the checkpoint has what finished.
+            return Ok(());
+        }
+
+        info!("Running stage '{}'", name);
+        match stage {
+            Stage::Ami {
+                options,
+                ami_output,
+            } => rt
+                .block_on(aws::ami::run_with_options(
+                    args,
+                    options,
+                    ami_output.as_deref(),
+                ))
+                .map(drop)
+                .context(error::AmiSnafu)?,
+            Stage::PublishAmi {
+                options,
+                build_info,
+            } => rt
+                .block_on(aws::publish_ami::run_with_options(
+                    args, options, build_info,
+                ))
+                .context(error::PublishAmiSnafu)?,
+            Stage::Ssm(options) => rt
+                .block_on(aws::ssm::run_with_options(args, options))
+                .context(error::SsmSnafu)?,
+            // Building the repo uses blocking requests, so it runs outside the runtime.
+            Stage::Repo(options) => {
+                repo::run_with_options(args, options).context(error::RepoSnafu)?
+            }
+        }
+
+        checkpoint.completed.push(name.to_string());
+        write_checkpoint(&checkpoint_path, &checkpoint)?;
+        info!("Finished stage '{}'", name);
+    }
+
+    info!("Release from {} is complete", release_args.spec.display());
+    Ok(())
+}
+
+/// Converts the arguments of each stage in the spec into its options, returning the stages in the
+/// order they run.
+fn parse_stages(spec: ReleaseSpec, dry_run: bool) -> Result<Vec<Stage>> {
+    let mut stages = Vec::new();
+    if let Some(stage_args) = spec.ami {
+        stages.push(ami_stage(StageTable::new("ami", stage_args))?);
+    }
+    if let Some(stage_args) = spec.publish_ami {
+        stages.push(publish_ami_stage(StageTable::new(
+            "publish-ami",
+            stage_args,
+        ))?);
+    }
+    if let Some(stage_args) = spec.ssm {
+        stages.push(ssm_stage(StageTable::new("ssm", stage_args))?);
+    }
+    if let Some(stage_args) = spec.repo {
+        stages.push(repo_stage(StageTable::new("repo", stage_args), dry_run)?);
+    }
+    Ok(stages)
+}
+
+/// Returns the 'ami' stage, with the arguments of the 'ami' subcommand.
+fn ami_stage(mut table: StageTable) -> Result<Stage> {
+    let options = AmiOptions {
+        os_image: table.required("os-image", PathBuf::from_str)?,
+        data_image: table.optional("data-image", PathBuf::from_str)?,
+        variant_manifest: table.required("variant-manifest", PathBuf::from_str)?,
+        arch: table.required("arch", parse_arch)?,
+        name: table.optional("name", String::from_str)?,
+        description: table.optional("description", String::from_str)?,
+        default_name: table.optional("default-name", String::from_str)?,
+        variant: table.optional("variant", String::from_str)?,
+        version: table.optional("version", String::from_str)?,
+        regions: table.list("regions", String::from_str)?,
+        preflight: table.flag("preflight")?,
+        no_progress: table.flag("no-progress")?,
+    };
+    let ami_output = table.optional("ami-output", PathBuf::from_str)?;
+    table.finish()?;
+    Ok(Stage::Ami {
+        options,
+        ami_output,
+    })
+}
+
+/// Returns the 'publish-ami' stage, with the arguments of the 'publish-ami' subcommand.
+fn publish_ami_stage(mut table: StageTable) -> Result<Stage> {
+    let mode = match (table.flag("grant")?, table.flag("revoke")?) {
+        (true, false) => PublishMode::Grant,
+        (false, true) => PublishMode::Revoke,
+        _ => return table.invalid("exactly one of 'grant' and 'revoke' must be true"),
+    };
+    let modify_opts = ModifyOptions {
+        user_ids: table.list("user-ids", String::from_str)?,
+        group_names: table.list("group-names", String::from_str)?,
+        organization_arns: table.list("organization-arns", String::from_str)?,
+        organizational_unit_arns: table.list("organizational-unit-arns", String::from_str)?,
+    };
+    if modify_opts.user_ids.is_empty()
+        && modify_opts.group_names.is_empty()
+        && modify_opts.organization_arns.is_empty()
+        && modify_opts.organizational_unit_arns.is_empty()
+    {
+        return table.invalid(
+            "one of 'user-ids', 'group-names', 'organization-arns', or \
+             'organizational-unit-arns' is required",
+        );
+    }
+    let options = PublishOptions {
+        ami_input: table.required("ami-input", PathBuf::from_str)?,
+        regions: table.list("regions", String::from_str)?,
+        mode,
+        disable_block_public_access: table.flag("disable-block-public-access")?,
+        preflight: table.flag("preflight")?,
+        modify_opts,
+        skip_snapshot_permissions: table.flag("skip-snapshot-permissions")?,
+        snapshot_accounts_path: table.optional("snapshot-accounts-path", PathBuf::from_str)?,
+    };
+    if options.skip_snapshot_permissions && options.snapshot_accounts_path.is_some() {
+        return table
+            .invalid("'skip-snapshot-permissions' can't be used with 'snapshot-accounts-path'");
+    }
+    let build_info = BuildInfo {
+        variant: table.optional("variant", String::from_str)?,
+        version: table.optional("version", String::from_str)?,
+    };
+    table.finish()?;
+    Ok(Stage::PublishAmi {
+        options,
+        build_info,
+    })
+}
+
+/// Returns the 'ssm' stage, with the arguments of the 'ssm' subcommand.
+fn ssm_stage(mut table: StageTable) -> Result<Stage> {
+    let options = SsmOptions {
+        ami_input: table.required("ami-input", PathBuf::from_str)?,
+        arch: table.required("arch", parse_arch)?,
+        variant: table.required("variant", String::from_str)?,
+        version: table.required("version", String::from_str)?,
+        regions: table.list("regions", String::from_str)?,
+        template_path: table.required("template-path", PathBuf::from_str)?,
+        allow_clobber: table.flag("allow-clobber")?,
+        allow_private_images: table.flag("allow-private-images")?,
+        ssm_parameter_output: table.optional("ssm-parameter-output", PathBuf::from_str)?,
+        preflight: table.flag("preflight")?,
+    };
+    table.finish()?;
+    Ok(Stage::Ssm(options))
+}
+
+/// Returns the 'repo' stage, with the arguments of the 'repo' subcommand.  The expiration policy
+/// is read now, so a missing or invalid policy is found before any stage runs.
+fn repo_stage(mut table: StageTable, dry_run: bool) -> Result<Stage> {
+    let policy_path: PathBuf = table.required("repo-expiration-policy-path", PathBuf::from_str)?;
+    let overrides = ExpirationOverrides {
+        targets_expiry: table.optional("targets-expiry", parse_offset)?,
+        snapshot_expiry: table.optional("snapshot-expiry", parse_offset)?,
+        timestamp_expiry: table.optional("timestamp-expiry", parse_offset)?,
+    };
+    let options = RepoOptions {
+        repo: table.required("repo", String::from_str)?,
+        arch: table.required("arch", String::from_str)?,
+        version: table.required("version", friendly_version)?,
+        variant: table.required("variant", String::from_str)?,
+        boot_image: table.required("boot-image", PathBuf::from_str)?,
+        root_image: table.required("root-image", PathBuf::from_str)?,
+        hash_image: table.required("hash-image", PathBuf::from_str)?,
+        link_targets: table.list("link-target", PathBuf::from_str)?,
+        copy_targets: table.list("copy-target", PathBuf::from_str)?,
+        sboms: table.list("sbom", PathBuf::from_str)?,
+        provenance: table.list("provenance", PathBuf::from_str)?,
+        expiration_policy: overrides.apply(
+            RepoExpirationPolicy::from_path(&policy_path)
+                .context(error::ExpirationPolicySnafu { path: &policy_path })?,
+        ),
+        release_config_path: table.required("release-config-path", PathBuf::from_str)?,
+        wave_policy_path: table.required("wave-policy-path", PathBuf::from_str)?,
+        root_role_path: table.required("root-role-path", PathBuf::from_str)?,
+        default_key_path: table.required("default-key-path", PathBuf::from_str)?,
+        release_start_time: table.optional("release-start-time", parse_datetime)?,
+        outdir: table.required("outdir", PathBuf::from_str)?,
+        preflight: table.flag("preflight")?,
+        emit_unsigned: table.optional("emit-unsigned", PathBuf::from_str)?,
+        dry_run,
+    };
+    table.finish()?;
+    Ok(Stage::Repo(options))
+}
+
+/// The arguments of one stage, taken out by name as they're converted into the stage's options,
+/// so any left over are unknown
+struct StageTable {
+    stage: &'static str,
+    args: StageArgs,
+}
+
+impl StageTable {
+    fn new(stage: &'static str, args: StageArgs) -> Self {
+        // Names can also be given with underscores, like the fields they set.
+        let args = args
+            .into_iter()
+            .map(|(name, value)| (name.replace('_', "-"), value))
+            .collect();
+        Self { stage, args }
+    }
+
+    /// Removes the named argument and returns its value as it would be given on the command line,
+    /// if it's given.
+    fn text(&mut self, name: &str) -> Result<Option<String>> {
+        match self.args.remove(name) {
+            None => Ok(None),
+            Some(value) => text_value(self.stage, name, &value).map(Some),
+        }
+    }
+
+    /// Removes the named argument and returns its parsed value, if it's given.
+    fn optional<T, E, F>(&mut self, name: &str, parse: F) -> Result<Option<T>>
+    where
+        E: Display,
+        F: Fn(&str) -> std::result::Result<T, E>,
+    {
+        match self.text(name)? {
+            None => Ok(None),
+            Some(value) => self.parse(name, &value, &parse).map(Some),
+        }
+    }
+
+    /// Removes the named argument and returns its parsed value, failing if it isn't given.
+    fn required<T, E, F>(&mut self, name: &str, parse: F) -> Result<T>
+    where
+        E: Display,
+        F: Fn(&str) -> std::result::Result<T, E>,
+    {
+        let stage = self.stage;
+        self.optional(name, parse)?
+            .context(error::MissingArgSnafu { stage, name })
+    }
+
+    /// Removes the named list argument, given as an array or a comma-separated string, and returns
+    /// its parsed values; the list is empty if it isn't given.
+    fn list<T, E, F>(&mut self, name: &str, parse: F) -> Result<Vec<T>>
+    where
+        E: Display,
+        F: Fn(&str) -> std::result::Result<T, E>,
+    {
+        let values = match self.args.remove(name) {
+            None => Vec::new(),
+            Some(toml::Value::Array(values)) => values
+                .iter()
+                .map(|value| text_value(self.stage, name, value))
+                .collect::<Result<Vec<_>>>()?,
+            Some(value) => text_value(self.stage, name, &value)?
+                .split(',')
+                .map(str::to_string)
+                .collect(),
+        };
+        values
+            .iter()
+            .map(|value| self.parse(name, value, &parse))
+            .collect()
+    }
+
+    /// Removes the named flag and returns whether it's set.
+    fn flag(&mut self, name: &str) -> Result<bool> {
+        match self.args.remove(name) {
+            None => Ok(false),
+            Some(toml::Value::Boolean(set)) => Ok(set),
+            Some(value) => error::InvalidValueSnafu {
+                stage: self.stage,
+                name,
+                value: value.to_string(),
+                msg: "flags must be true or false",
+            }
+            .fail(),
+        }
+    }
+
+    fn parse<T, E, F>(&self, name: &str, value: &str, parse: F) -> Result<T>
+    where
+        E: Display,
+        F: Fn(&str) -> std::result::Result<T, E>,
+    {
+        parse(value).map_err(|e| {
+            error::InvalidValueSnafu {
+                stage: self.stage,
+                name,
+                value,
+                msg: e.to_string(),
+            }
+            .build()
+        })
+    }
+
+    /// Fails with the given explanation of why the stage's arguments don't go together.
+    fn invalid<T>(&self, msg: &str) -> Result<T> {
+        error::StageArgsSnafu {
+            stage: self.stage,
+            msg,
+        }
+        .fail()
+    }
+
+    /// Fails if any arguments weren't taken, since the stage doesn't know them.
+    fn finish(self) -> Result<()> {
+        ensure!(
+            self.args.is_empty(),
+            error::UnknownArgsSnafu {
+                stage: self.stage,
+                names: self.args.into_keys().collect::<Vec<_>>(),
+            }
+        );
+        Ok(())
+    }
+}
+
+/// Returns the given single value as it would be given on the command line.
+fn text_value(stage: &str, name: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        _ => error::UnsupportedValueSnafu { stage, name }.fail(),
+    }
+}
+
+/// Reads the checkpoint at the given path, if there is one.
+fn read_checkpoint(path: &Path) -> Result<Option<Checkpoint>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let checkpoint_str = fs::read_to_string(path).context(error::FileSnafu { op: "read", path })?;
+    let checkpoint: Checkpoint =
+        serde_json::from_str(&checkpoint_str).context(error::ParseCheckpointSnafu { path })?;
+    info!(
+        "Resuming from checkpoint {}; finished stages: {}",
+        path.display(),
+        checkpoint.completed.join(", ")
+    );
+    Ok(Some(checkpoint))
+}
+
+/// Writes the checkpoint to the given path, replacing the previous one only once the new one is
+/// complete.
+fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let checkpoint_str =
+        serde_json::to_string_pretty(checkpoint).context(error::SerializeCheckpointSnafu)?;
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    fs::write(&temp_path, checkpoint_str).context(error::FileSnafu {
+        op: "write",
+        path: &temp_path,
+    })?;
+    fs::rename(&temp_path, path).context(error::FileSnafu {
+        op: "replace",
+        path,
+    })
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Stage 'ami' failed: {}", source))]
+        Ami { source: crate::aws::ami::Error },
+
+        #[snafu(display(
+            "Failed to read repo expiration policy '{}': {}",
+            path.display(),
+            source
+        ))]
+        ExpirationPolicy {
+            path: PathBuf,
+            source: pubsys_config::Error,
+        },
+
+        #[snafu(display("Failed to {} '{}': {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Release spec '{}' has no stages", path.display()))]
+        NoStages { path: PathBuf },
+
+        #[snafu(display(
+            "Invalid value '{}' for argument '{}' of stage '{}': {}",
+            value,
+            name,
+            stage,
+            msg
+        ))]
+        InvalidValue {
+            stage: String,
+            name: String,
+            value: String,
+            msg: String,
+        },
+
+        #[snafu(display("Stage '{}' needs argument '{}'", stage, name))]
+        MissingArg { stage: String, name: String },
+
+        #[snafu(display("Invalid checkpoint '{}': {}", path.display(), source))]
+        ParseCheckpoint {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Invalid release spec '{}': {}", path.display(), source))]
+        ParseSpec {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Stage 'publish-ami' failed: {}", source))]
+        PublishAmi {
+            source: crate::aws::publish_ami::Error,
+        },
+
+        #[snafu(display("Stage 'repo' failed: {}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Failed to serialize checkpoint: {}", source))]
+        SerializeCheckpoint { source: serde_json::Error },
+
+        #[snafu(display(
+            "Release spec '{}' changed since checkpoint '{}' was written; use --restart to run \
+            every stage again",
+            spec.display(),
+            checkpoint.display()
+        ))]
+        SpecChanged { spec: PathBuf, checkpoint: PathBuf },
+
+        #[snafu(display("Stage 'ssm' failed: {}", source))]
+        Ssm { source: crate::aws::ssm::Error },
+
+        #[snafu(display("Invalid arguments for stage '{}': {}", stage, msg))]
+        StageArgs { stage: String, msg: String },
+
+        #[snafu(display("Unknown arguments for stage '{}': {}", stage, names.join(", ")))]
+        UnknownArgs { stage: String, names: Vec<String> },
+
+        #[snafu(display(
+            "Argument '{}' of stage '{}' must be a string, number, boolean, or list",
+            name,
+            stage
+        ))]
+        UnsupportedValue { stage: String, name: String },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::NoStages { .. }
+            | Self::ParseSpec { .. }
+            | Self::StageArgs { .. }
+            | Self::UnsupportedValue { .. } => Some(Kind::Config),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_stages, ReleaseSpec, Stage, StageArgs, StageTable};
+    use aws_sdk_ec2::model::ArchitectureValues;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    #[test]
+    fn stage_arguments() {
+        let stage_args: StageArgs = toml::from_str(
+            r#"
+            ami-input = "amis.json"
+            grant = true
+            revoke = false
+            regions = ["us-west-2", "us-east-1"]
+            user_ids = "111111111111,222222222222"
+            count = 3
+            "#,
+        )
+        .unwrap();
+        let mut table = StageTable::new("publish-ami", stage_args);
+        assert_eq!(
+            table.required("ami-input", PathBuf::from_str).unwrap(),
+            PathBuf::from("amis.json")
+        );
+        assert!(table.flag("grant").unwrap());
+        assert!(!table.flag("revoke").unwrap());
+        assert!(!table.flag("preflight").unwrap());
+        assert_eq!(
+            table.list("regions", String::from_str).unwrap(),
+            vec!["us-west-2", "us-east-1"]
+        );
+        assert_eq!(
+            table.list("user-ids", String::from_str).unwrap(),
+            vec!["111111111111", "222222222222"]
+        );
+        assert!(table.required("missing", String::from_str).is_err());
+        assert!(table.finish().is_err());
+
+        let stage_args: StageArgs = toml::from_str("nested = { a = 1 }").unwrap();
+        let mut table = StageTable::new("ami", stage_args);
+        assert!(table.optional("nested", String::from_str).is_err());
+    }
+
+    #[test]
+    fn stage_order() {
+        let spec: ReleaseSpec = toml::from_str(
+            r#"
+            [publish-ami]
+            ami-input = "amis.json"
+            grant = true
+            group-names = ["all"]
+
+            [ami]
+            os-image = "os.img"
+            variant-manifest = "Cargo.toml"
+            arch = "x86_64"
+            "#,
+        )
+        .unwrap();
+        let stages = parse_stages(spec, false).unwrap();
+        let names: Vec<&str> = stages.iter().map(Stage::name).collect();
+        assert_eq!(names, vec!["ami", "publish-ami"]);
+        match &stages[0] {
+            Stage::Ami { options, .. } => assert_eq!(options.arch, ArchitectureValues::X8664),
+            stage => panic!("unexpected stage {:?}", stage),
+        }
+
+        let spec: ReleaseSpec = toml::from_str("[ssm]\nno-such-option = true").unwrap();
+        assert!(parse_stages(spec, false).is_err());
+
+        let spec: ReleaseSpec =
+            toml::from_str("[publish-ami]\nami-input = \"amis.json\"\ngroup-names = [\"all\"]")
+                .unwrap();
+        assert!(parse_stages(spec, false).is_err());
+    }
+}
@@ -0,0 +1,665 @@
+//! The release_manifest module owns the 'manifest' and 'verify-manifest' subcommands, which record
+//! what a release published and later confirm it's still published that way.
+//!
+//! A release manifest lists, for one variant, architecture, and version, the AMI in each region,
+//! the values of the SSM parameters pointing to them, and the sha256 of each versioned metadata
+//! file in the repo.  It's signed like a repo manifest, with the repo's signing keys, so anyone
+//! holding the repo's root.json can check it; see `repo_manifest`.  'manifest' only records what's
+//! actually live, so it fails if the AMIs or parameters aren't all published yet.
+//!
+//! Metadata files are recorded by their versioned names, like "3.targets.json", which stay in the
+//! repo when it's refreshed, so a manifest can still be verified after later releases.
+
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::aws::ssm::{ssm, template, BuildContext, SsmKey};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::repo::repo_manifest::{self, ManifestSignature};
+use crate::repo::{get_signing_key_source, repo_urls};
+use crate::Args;
+use aws_sdk_ec2::model::Filter;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use chrono::{DateTime, Utc};
+use log::{error, info, trace};
+use pubsys_config::AwsConfig;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+use tough::key_source::{KeySource, LocalKeySource};
+use tough::schema::{Root, Signed};
+use tough::{DefaultTransport, Repository, Transport, TransportErrorKind};
+use url::Url;
+
+/// Records a release's AMIs, SSM parameters, and repo metadata in a signed manifest
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ManifestArgs {
+    /// Path to the JSON file of the release's AMIs, written by the ami subcommand
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// The architecture of the release
+    #[structopt(long)]
+    arch: String,
+
+    /// The variant of the release
+    #[structopt(long)]
+    variant: String,
+
+    /// The version of the release
+    #[structopt(long)]
+    version: String,
+
+    /// File holding the parameter templates the release was published with; if not given, no
+    /// parameters are recorded
+    #[structopt(long, parse(from_os_str))]
+    template_path: Option<PathBuf>,
+
+    /// Use this named repo infrastructure from Infra.toml, for the repo and its signing keys
+    #[structopt(long)]
+    repo: String,
+
+    /// Path to root.json for the repo
+    #[structopt(long, parse(from_os_str))]
+    root_role_path: PathBuf,
+
+    /// If we generated a local key, we'll find it here; used if Infra.toml has no key defined
+    #[structopt(long, parse(from_os_str))]
+    default_key_path: PathBuf,
+
+    /// Where to write the manifest
+    #[structopt(long, parse(from_os_str))]
+    output: PathBuf,
+}
+
+/// Confirms that a release is still published the way its signed manifest records
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct VerifyManifestArgs {
+    /// Path to the release manifest
+    #[structopt(long, parse(from_os_str))]
+    manifest_path: PathBuf,
+
+    /// Path to root.json for the release's repo; the manifest has to be signed by its targets keys
+    #[structopt(long, parse(from_os_str))]
+    root_role_path: PathBuf,
+}
+
+/// An AMI published for the release
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AmiRecord {
+    id: String,
+    name: String,
+}
+
+/// What was published for a release, as recorded or as found live
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Published {
+    /// The AMI in each region
+    amis: BTreeMap<String, AmiRecord>,
+    /// The value of each SSM parameter, by region and name
+    parameters: BTreeMap<String, BTreeMap<String, String>>,
+    /// The sha256 of each versioned metadata file in the repo, by file name
+    metadata: BTreeMap<String, String>,
+}
+
+/// The published state of a release
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ReleaseState {
+    variant: String,
+    arch: String,
+    version: String,
+    recorded_at: DateTime<Utc>,
+    metadata_url: Url,
+    #[serde(flatten)]
+    published: Published,
+}
+
+/// The release manifest as it's written to disk
+#[derive(Debug, Serialize, Deserialize)]
+struct ReleaseManifest {
+    signed: ReleaseState,
+    signatures: Vec<ManifestSignature>,
+}
+
+/// Describes each way the live state differs from the recorded state.  Only what's recorded is
+/// compared, since the live state is looked up from it.
+fn compare(recorded: &Published, live: &Published) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (region, ami) in &recorded.amis {
+        match live.amis.get(region) {
+            Some(live_ami) if live_ami == ami => {}
+            Some(live_ami) => problems.push(format!(
+                "AMI {} in {} is named '{}', but the manifest recorded '{}'",
+                ami.id, region, live_ami.name, ami.name
+            )),
+            None => problems.push(format!("AMI {} in {} is missing", ami.id, region)),
+        }
+    }
+    for (region, parameters) in &recorded.parameters {
+        for (name, value) in parameters {
+            match live.parameters.get(region).and_then(|live| live.get(name)) {
+                Some(live_value) if live_value == value => {}
+                Some(live_value) => problems.push(format!(
+                    "Parameter {} in {} is '{}', but the manifest recorded '{}'",
+                    name, region, live_value, value
+                )),
+                None => problems.push(format!("Parameter {} in {} is missing", name, region)),
+            }
+        }
+    }
+    for (file, sha256) in &recorded.metadata {
+        match live.metadata.get(file) {
+            Some(live_sha256) if live_sha256 == sha256 => {}
+            Some(live_sha256) => problems.push(format!(
+                "Metadata file {} has sha256 {}, but the manifest recorded {}",
+                file, live_sha256, sha256
+            )),
+            None => problems.push(format!("Metadata file {} is missing", file)),
+        }
+    }
+    problems
+}
+
+/// Returns the names of the repo's current metadata files that stay in the repo after it's
+/// refreshed: root, and, with consistent snapshots, snapshot and targets.
+fn metadata_files(repo: &Repository) -> Vec<String> {
+    let root = &repo.root().signed;
+    let mut files = vec![format!("{}.root.json", root.version)];
+    if root.consistent_snapshot {
+        files.push(format!("{}.snapshot.json", repo.snapshot().signed.version));
+        files.push(format!("{}.targets.json", repo.targets().signed.version));
+    }
+    files
+}
+
+/// Fetches the given metadata files from the repo and returns their sha256, by file name.  Files
+/// that don't exist are left out.
+fn metadata_hashes(metadata_url: &Url, files: &[String]) -> Result<BTreeMap<String, String>> {
+    let transport = DefaultTransport::new();
+    let mut hashes = BTreeMap::new();
+    for file in files {
+        let url_str = format!("{}/{}", metadata_url.as_str().trim_end_matches('/'), file);
+        let url = Url::parse(&url_str).context(error::ParseUrlSnafu { input: &url_str })?;
+        let mut reader = match transport.fetch(url.clone()) {
+            Ok(reader) => reader,
+            Err(e) if matches!(e.kind(), TransportErrorKind::FileNotFound) => continue,
+            Err(e) => return Err(e).context(error::FetchMetadataSnafu { url }),
+        };
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .context(error::ReadMetadataSnafu { url })?;
+        hashes.insert(file.clone(), hex::encode(digest(&SHA256, &data)));
+    }
+    Ok(hashes)
+}
+
+/// Finds the live state of the AMIs and parameters recorded in the given state.
+async fn find_published(aws: &AwsConfig, recorded: &Published) -> Result<Published> {
+    // Clients in every region assume roles starting from the first one.
+    let regions: Vec<Region> = recorded
+        .amis
+        .keys()
+        .chain(recorded.parameters.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|name| region_from_string(name))
+        .collect();
+    let base_region = regions.first().context(error::EmptySnafu)?;
+    let rate_limits = RateLimits::new(aws);
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let client_config = build_client_config(region, base_region, aws).await;
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, aws),
+        );
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, aws),
+        );
+    }
+
+    let mut live = Published::default();
+    info!("Checking {} AMIs", recorded.amis.len());
+    for (region_name, ami) in &recorded.amis {
+        let region = region_from_string(region_name);
+        // Asking for a missing ID directly fails the request, so we filter by ID instead.
+        let response = rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                ec2_clients[&region]
+                    .describe_images()
+                    .include_deprecated(true)
+                    .filters(
+                        Filter::builder()
+                            .name("image-id")
+                            .values(ami.id.clone())
+                            .build(),
+                    )
+                    .send(),
+            )
+            .await
+            .context(error::DescribeImagesSnafu {
+                region: region_name,
+            })?;
+        if let Some(image) = response.images().unwrap_or_default().first() {
+            live.amis.insert(
+                region_name.clone(),
+                AmiRecord {
+                    id: image.image_id().unwrap_or_default().to_string(),
+                    name: image.name().unwrap_or_default().to_string(),
+                },
+            );
+        }
+    }
+
+    let keys: Vec<SsmKey> = recorded
+        .parameters
+        .iter()
+        .flat_map(|(region_name, parameters)| {
+            parameters
+                .keys()
+                .map(move |name| SsmKey::new(region_from_string(region_name), name.clone()))
+        })
+        .collect();
+    info!("Checking {} parameters", keys.len());
+    for (key, value) in ssm::get_parameters(&keys, &ssm_clients, &rate_limits)
+        .await
+        .context(error::FetchSsmSnafu)?
+    {
+        live.parameters
+            .entry(key.region.to_string())
+            .or_default()
+            .insert(key.name, value);
+    }
+    Ok(live)
+}
+
+/// Returns the parameters the templates render for the release's AMIs, by region and name.
+fn expected_parameters(
+    aws: &AwsConfig,
+    manifest_args: &ManifestArgs,
+    amis: &HashMap<String, Image>,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let mut expected: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let template_path = match &manifest_args.template_path {
+        Some(template_path) => template_path,
+        None => return Ok(expected),
+    };
+    // Parameters are named for the architecture the way EC2 names it.
+    let arch = parse_arch(&manifest_args.arch)
+        .ok()
+        .context(error::ParseArchSnafu {
+            input: &manifest_args.arch,
+        })?;
+    let build_context = BuildContext {
+        variant: &manifest_args.variant,
+        arch: arch.as_ref(),
+        image_version: &manifest_args.version,
+    };
+    let template_parameters = template::get_parameters(template_path, &build_context)
+        .context(error::FindTemplatesSnafu)?;
+    let amis: HashMap<Region, Image> = amis
+        .iter()
+        .map(|(region, image)| (region_from_string(region), image.clone()))
+        .collect();
+    let ssm_prefix = aws.ssm_prefix_for(&manifest_args.variant, arch.as_ref());
+    for parameter in
+        template::render_parameters(template_parameters, &amis, ssm_prefix, &build_context)
+            .context(error::RenderTemplatesSnafu)?
+    {
+        expected
+            .entry(parameter.ssm_key.region.to_string())
+            .or_default()
+            .insert(parameter.ssm_key.name, parameter.value);
+    }
+    Ok(expected)
+}
+
+/// Reads root.json, whose targets keys sign release manifests.
+fn read_root(root_role_path: &Path) -> Result<Signed<Root>> {
+    let file = File::open(root_role_path).context(error::FileSnafu {
+        path: root_role_path,
+    })?;
+    serde_json::from_reader(file).context(error::ParseSnafu {
+        path: root_role_path,
+    })
+}
+
+/// Common entrypoint from main() for the 'manifest' subcommand
+pub(crate) fn run(args: &Args, manifest_args: &ManifestArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&manifest_args.repo)
+        .with_context(|| error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", manifest_args.repo),
+        })?;
+
+    info!(
+        "Using AMI data from path: {}",
+        manifest_args.ami_input.display()
+    );
+    let amis: HashMap<String, Image> = serde_json::from_reader(
+        File::open(&manifest_args.ami_input).context(error::FileSnafu {
+            path: &manifest_args.ami_input,
+        })?,
+    )
+    .context(error::ParseSnafu {
+        path: &manifest_args.ami_input,
+    })?;
+    let expected = Published {
+        amis: amis
+            .iter()
+            .map(|(region, image)| {
+                (
+                    region.clone(),
+                    AmiRecord {
+                        id: image.id.clone(),
+                        name: image.name.clone(),
+                    },
+                )
+            })
+            .collect(),
+        parameters: expected_parameters(&aws, manifest_args, &amis)?,
+        metadata: BTreeMap::new(),
+    };
+
+    // Only record what's live, so the manifest can't vouch for something that wasn't published.
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    let mut published = rt.block_on(find_published(&aws, &expected))?;
+    let problems = compare(&expected, &published);
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    ensure!(
+        problems.is_empty(),
+        error::UnpublishedSnafu {
+            problems: problems.len()
+        }
+    );
+
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &manifest_args.variant, &manifest_args.arch)
+            .context(error::RepoSnafu)?
+            .context(error::MissingRepoUrlsSnafu {
+                repo: &manifest_args.repo,
+            })?;
+    let repo = repo_manifest::load_repo(&manifest_args.root_role_path, &metadata_url, targets_url)
+        .context(error::RepoManifestSnafu)?;
+    info!("Loaded TUF repo: {}", metadata_url);
+    let files = metadata_files(&repo);
+    published.metadata = metadata_hashes(&metadata_url, &files)?;
+    ensure!(
+        published.metadata.len() == files.len(),
+        error::MissingMetadataSnafu { metadata_url }
+    );
+
+    // Check if we have a signing key defined in Infra.toml; if not, we'll fall back to the
+    // generated local key.  Any additional signing keys sign alongside it.
+    let mut key_sources: Vec<Box<dyn KeySource>> = Vec::new();
+    if repo_config.signing_keys.is_none() {
+        ensure!(
+            manifest_args.default_key_path.exists(),
+            error::MissingConfigSnafu {
+                missing: "signing_keys in repo config, and we found no local key",
+            }
+        );
+        key_sources.push(Box::new(LocalKeySource {
+            path: manifest_args.default_key_path.clone(),
+        }));
+    }
+    for signing_key_config in repo_config.all_signing_keys() {
+        key_sources
+            .push(get_signing_key_source(signing_key_config, &aws).context(error::RepoSnafu)?);
+    }
+
+    let state = ReleaseState {
+        variant: manifest_args.variant.clone(),
+        arch: manifest_args.arch.clone(),
+        version: manifest_args.version.clone(),
+        recorded_at: Utc::now(),
+        metadata_url,
+        published,
+    };
+    let signatures = repo_manifest::sign(&repo.root().signed, &state, &key_sources)
+        .context(error::RepoManifestSnafu)?;
+    let manifest = ReleaseManifest {
+        signed: state,
+        signatures,
+    };
+
+    let path = &manifest_args.output;
+    info!("Writing release manifest to: {}", path.display());
+    let mut data = serde_json::to_vec_pretty(&manifest).context(error::SerializeSnafu)?;
+    data.push(b'\n');
+    fs::write(path, data).context(error::WriteSnafu { path })
+}
+
+/// Common entrypoint from main() for the 'verify-manifest' subcommand
+pub(crate) fn verify(args: &Args, verify_args: &VerifyManifestArgs) -> Result<()> {
+    // Consumers of a release may not have an Infra.toml; the defaults use their own credentials.
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    let manifest: ReleaseManifest = serde_json::from_reader(
+        File::open(&verify_args.manifest_path).context(error::FileSnafu {
+            path: &verify_args.manifest_path,
+        })?,
+    )
+    .context(error::ParseSnafu {
+        path: &verify_args.manifest_path,
+    })?;
+    let root = read_root(&verify_args.root_role_path)?;
+    repo_manifest::verify_signatures(&root.signed, &manifest.signed, &manifest.signatures)
+        .context(error::RepoManifestSnafu)?;
+    let recorded = &manifest.signed;
+    info!(
+        "Verifying {} {} {}, recorded at {}",
+        recorded.variant,
+        recorded.arch,
+        recorded.version,
+        recorded.recorded_at.to_rfc3339()
+    );
+
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    let mut live = rt.block_on(find_published(&aws, &recorded.published))?;
+    let files: Vec<String> = recorded.published.metadata.keys().cloned().collect();
+    live.metadata = metadata_hashes(&recorded.metadata_url, &files)?;
+
+    let problems = compare(&recorded.published, &live);
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    ensure!(
+        problems.is_empty(),
+        error::MismatchSnafu {
+            problems: problems.len()
+        }
+    );
+    info!("Release matches its manifest");
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::DescribeImagesError;
+    use aws_sdk_ec2::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+    use url::Url;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to describe AMIs in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeImages {
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("The manifest has no AMIs or parameters"))]
+        Empty,
+
+        #[snafu(display("Failed to fetch metadata from '{}': {}", url, source))]
+        FetchMetadata {
+            url: Url,
+            source: tough::TransportError,
+        },
+
+        #[snafu(display("Failed to fetch parameters from SSM: {}", source))]
+        FetchSsm { source: crate::aws::ssm::ssm::Error },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        File {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("Release differs from its manifest in {} ways; see above", problems))]
+        Mismatch { problems: usize },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Repo at '{}' is missing current metadata files", metadata_url))]
+        MissingMetadata { metadata_url: Url },
+
+        #[snafu(display("Repo '{}' has no metadata and targets URLs", repo))]
+        MissingRepoUrls { repo: String },
+
+        #[snafu(display("Invalid JSON in '{}': {}", path.display(), source))]
+        Parse {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Unknown architecture '{}'", input))]
+        ParseArch { input: String },
+
+        #[snafu(display("Failed to parse URL '{}': {}", input, source))]
+        ParseUrl {
+            input: String,
+            source: url::ParseError,
+        },
+
+        #[snafu(display("Failed to read metadata from '{}': {}", url, source))]
+        ReadMetadata { url: Url, source: std::io::Error },
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display("{}", source))]
+        RepoManifest {
+            source: crate::repo::repo_manifest::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Failed to serialize release manifest: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Release isn't fully published; {} problems, see above", problems))]
+        Unpublished { problems: usize },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } | Self::MissingRepoUrls { .. } => Some(Kind::Config),
+            Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            Self::Mismatch { .. } | Self::Unpublished { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, AmiRecord, Published};
+    use std::collections::BTreeMap;
+
+    fn published(image_id: &str, parameter: &str, sha256: &str) -> Published {
+        Published {
+            amis: BTreeMap::from([(
+                "us-west-2".to_string(),
+                AmiRecord {
+                    id: image_id.to_string(),
+                    name: "bottlerocket-aws-dev-x86_64-v1.0.0".to_string(),
+                },
+            )]),
+            parameters: BTreeMap::from([(
+                "us-west-2".to_string(),
+                BTreeMap::from([(
+                    "/bottlerocket/aws-dev/x86_64/1.0.0/image_id".to_string(),
+                    parameter.to_string(),
+                )]),
+            )]),
+            metadata: BTreeMap::from([("2.targets.json".to_string(), sha256.to_string())]),
+        }
+    }
+
+    #[test]
+    fn matching_release() {
+        let recorded = published("ami-1", "ami-1", "ab");
+        assert!(compare(&recorded, &published("ami-1", "ami-1", "ab")).is_empty());
+    }
+
+    #[test]
+    fn changed_release() {
+        let recorded = published("ami-1", "ami-1", "ab");
+        let mut live = published("ami-1", "ami-2", "cd");
+        live.amis.clear();
+        let problems = compare(&recorded, &live);
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "AMI ami-1 in us-west-2 is missing");
+    }
+}
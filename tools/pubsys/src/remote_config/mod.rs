@@ -0,0 +1,188 @@
+//! The remote_config module fetches an infra config given as an `s3://` or `https://` URL rather
+//! than a local path, so CI jobs can share one centrally managed Infra.toml.  The file is
+//! downloaded once, before any subcommand runs, into a temporary directory, and the subcommands
+//! read it like any other local config.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::exit_code::{Classify, Kind};
+use crate::Args;
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::{Client as S3Client, Region};
+use log::info;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// The region we use to fetch from S3 if none is configured in the environment or profile
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// The file name we use for a downloaded config if its URL doesn't end in one
+const DEFAULT_FILE_NAME: &str = "Infra.toml";
+
+/// If the infra config path in the given args is an `s3://` or `https://` URL, downloads the
+/// config into a temporary directory and points the args at the local copy.  The returned
+/// directory must be kept for as long as the config is used.
+pub(crate) fn localize(args: &mut Args) -> Result<Option<TempDir>> {
+    let location = match args.infra_config_path.to_str() {
+        Some(location) if is_remote(location) => location.to_string(),
+        _ => return Ok(None),
+    };
+    let url = Url::parse(&location).context(error::UrlSnafu { url: &location })?;
+
+    info!("Fetching infra config from {}", url);
+    let data = match url.scheme() {
+        "s3" => {
+            let rt = Runtime::new().context(error::RuntimeSnafu)?;
+            rt.block_on(fetch_s3(&url, args.profile.as_deref()))?
+        }
+        _ => fetch_https(&url)?,
+    };
+
+    // Keep the file name, since its extension decides how the config is parsed.
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(DEFAULT_FILE_NAME);
+    let dir = TempDir::new().context(error::TempDirSnafu)?;
+    let path = dir.path().join(file_name);
+    fs::write(&path, data).context(error::WriteSnafu { path: &path })?;
+
+    args.infra_config_path = path;
+    args.infra_config_url = Some(url);
+    Ok(Some(dir))
+}
+
+/// Returns whether the given infra config location is a URL we fetch, rather than a local path.
+fn is_remote(location: &str) -> bool {
+    location.starts_with("s3://") || location.starts_with("https://")
+}
+
+/// Fetches an object given as `s3://bucket/key`, using the default credentials, or the named
+/// profile if one is given.  We don't have the infra config yet, so it can't tell us which.
+async fn fetch_s3(url: &Url, profile: Option<&str>) -> Result<Vec<u8>> {
+    let bucket = url
+        .host_str()
+        .context(error::S3UrlSnafu { url: url.as_str() })?;
+    let key = url.path().trim_start_matches('/');
+    ensure!(!key.is_empty(), error::S3UrlSnafu { url: url.as_str() });
+
+    let s3_client = default_s3_client(profile).await;
+    let output = s3_client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context(error::GetObjectSnafu { url: url.as_str() })?;
+    let mut data = Vec::new();
+    output
+        .body
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .context(error::ReadObjectSnafu { url: url.as_str() })?;
+    Ok(data)
+}
+
+/// Builds an S3 client in the default region, using the default credentials, or the named profile
+/// if one is given.
+pub(crate) async fn default_s3_client(profile: Option<&str>) -> S3Client {
+    let region = RegionProviderChain::default_provider()
+        .region()
+        .await
+        .unwrap_or_else(|| Region::new(DEFAULT_S3_REGION));
+    let aws = PubsysAwsConfig {
+        profile: profile.map(str::to_string),
+        ..Default::default()
+    };
+    let client_config = build_client_config(&region, &region, &aws).await;
+    S3Client::from_pubsys_config(&client_config, &aws)
+}
+
+/// Fetches a file over HTTPS.
+fn fetch_https(url: &Url) -> Result<Vec<u8>> {
+    let response = reqwest::blocking::get(url.clone())
+        .and_then(|response| response.error_for_status())
+        .context(error::HttpsSnafu { url: url.as_str() })?;
+    let data = response
+        .bytes()
+        .context(error::HttpsSnafu { url: url.as_str() })?;
+    Ok(data.to_vec())
+}
+
+mod error {
+    use aws_sdk_s3::error::GetObjectError;
+    use aws_sdk_s3::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Failed to fetch infra config from {}: {}",
+            url,
+            DisplayErrorContext(source)
+        ))]
+        GetObject {
+            url: String,
+            source: SdkError<GetObjectError>,
+        },
+
+        #[snafu(display("Failed to fetch infra config from {}: {}", url, source))]
+        Https { url: String, source: reqwest::Error },
+
+        #[snafu(display("Failed to read infra config from {}: {}", url, source))]
+        ReadObject { url: String, source: io::Error },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: io::Error },
+
+        #[snafu(display("Infra config URL '{}' must look like s3://bucket/key", url))]
+        S3Url { url: String },
+
+        #[snafu(display("Failed to create temporary directory: {}", source))]
+        TempDir { source: io::Error },
+
+        #[snafu(display("Invalid infra config URL '{}': {}", url, source))]
+        Url {
+            url: String,
+            source: url::ParseError,
+        },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::GetObject { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_remote;
+
+    #[test]
+    fn remote_locations() {
+        assert!(is_remote("s3://my-bucket/infra/Infra.toml"));
+        assert!(is_remote("https://example.com/Infra.yaml"));
+        assert!(!is_remote("Infra.toml"));
+        assert!(!is_remote("/home/user/s3://Infra.toml"));
+        assert!(!is_remote("http://example.com/Infra.toml"));
+    }
+}
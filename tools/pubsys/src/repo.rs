@@ -1,20 +1,38 @@
 //! The repo module owns the 'repo' subcommand and controls the process of building a repository.
 
-pub(crate) mod check_expirations;
-pub(crate) mod refresh_repo;
-pub(crate) mod validate_repo;
-
+mod attestations;
+pub mod check_expirations;
+pub(crate) mod cloudfront;
+pub(crate) mod diff_repo;
+pub(crate) mod gc_repo;
+pub(crate) mod offline_signing;
+mod pkcs11;
+pub mod refresh_repo;
+pub(crate) mod repo_manifest;
+pub(crate) mod repo_stats;
+pub(crate) mod s3;
+mod secret_key;
+pub(crate) mod sync_repo;
+pub(crate) mod upload_repo;
+pub mod validate_repo;
+
+use crate::aws::{check_permissions, proxy};
+use crate::repo::pkcs11::Pkcs11KeySource;
+use crate::repo::secret_key::SecretKeySource;
 use crate::{friendly_version, Args};
 use aws_sdk_kms::{Client as KmsClient, Region};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
-use parse_datetime::parse_datetime;
+use nonzero_ext::nonzero;
+use parse_datetime::{parse_datetime, parse_offset};
 use pubsys_config::{
-    InfraConfig, KMSKeyConfig, RepoConfig, RepoExpirationPolicy, SigningKeyConfig,
+    AwsConfig as PubsysAwsConfig, DelegationConfig, InfraConfig, KMSKeyConfig, RepoConfig,
+    RepoExpirationPolicy, SignatureThresholds, SigningKeyConfig,
 };
 use semver::Version;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::{self, File};
 use std::num::NonZeroU64;
@@ -23,10 +41,12 @@ use structopt::{clap, StructOpt};
 use tempfile::NamedTempFile;
 use tokio::runtime::Runtime;
 use tough::{
-    editor::signed::PathExists,
+    editor::signed::{PathExists, SignedRepository},
     editor::RepositoryEditor,
     key_source::{KeySource, LocalKeySource},
-    schema::Target,
+    schema::decoded::{Decoded, Hex},
+    schema::key::Key,
+    schema::{PathPattern, PathSet, RoleType, Root, Signed, Target},
     RepositoryLoader, TransportErrorKind,
 };
 use tough_kms::{KmsKeySource, KmsSigningAlgorithm};
@@ -41,7 +61,7 @@ lazy_static! {
 /// Builds Bottlerocket repos using latest build artifacts
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct RepoArgs {
+pub struct RepoArgs {
     // Metadata about the update
     #[structopt(long)]
     /// Use this named repo infrastructure from Infra.toml
@@ -74,6 +94,14 @@ pub(crate) struct RepoArgs {
     #[structopt(long = "copy-target", parse(from_os_str))]
     /// Optional paths to add as targets and copy into repo
     copy_targets: Vec<PathBuf>,
+    #[structopt(long = "sbom", parse(from_os_str), use_delimiter = true)]
+    /// Optional paths to SBOMs of the images, in SPDX or CycloneDX format, to add as targets
+    sboms: Vec<PathBuf>,
+    #[structopt(long, parse(from_os_str), use_delimiter = true)]
+    /// Optional paths to in-toto provenance attestations, like SLSA provenance, for the images to
+    /// add as targets; the SBOMs and provenance are listed with the images in a release
+    /// attestations target
+    provenance: Vec<PathBuf>,
 
     // Policies that pubsys interprets to set repo parameters
     #[structopt(long, parse(from_os_str))]
@@ -97,14 +125,166 @@ pub(crate) struct RepoArgs {
     #[structopt(long, parse(try_from_str = parse_datetime))]
     /// When the waves and expiration timer will start; RFC3339 date or "in X hours/days/weeks"
     release_start_time: Option<DateTime<Utc>>,
+    #[structopt(flatten)]
+    expiration_overrides: ExpirationOverrides,
 
     #[structopt(long, parse(from_os_str))]
     /// Where to store the created repo
     outdir: PathBuf,
+
+    #[structopt(long)]
+    /// Check that the configured credentials can use the signing key before starting
+    preflight: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Instead of signing the repo, write the unsigned metadata and a signing request to this
+    /// directory, to be signed elsewhere and finished with attach-repo-signatures
+    emit_unsigned: Option<PathBuf>,
+}
+
+impl RepoArgs {
+    /// Returns the options for `build`, reading the expiration policy and applying any overrides.
+    fn options(&self, dry_run: bool) -> Result<RepoOptions> {
+        ensure!(
+            !dry_run || self.emit_unsigned.is_none(),
+            error::DryRunUnsignedSnafu
+        );
+        info!(
+            "Using repo expiration policy from path: {}",
+            self.repo_expiration_policy_path.display()
+        );
+        let expiration_policy = self.expiration_overrides.apply(
+            RepoExpirationPolicy::from_path(&self.repo_expiration_policy_path)
+                .context(error::ConfigSnafu)?,
+        );
+        Ok(RepoOptions {
+            repo: self.repo.clone(),
+            arch: self.arch.clone(),
+            version: self.version.clone(),
+            variant: self.variant.clone(),
+            boot_image: self.boot_image.clone(),
+            root_image: self.root_image.clone(),
+            hash_image: self.hash_image.clone(),
+            link_targets: self.link_targets.clone(),
+            copy_targets: self.copy_targets.clone(),
+            sboms: self.sboms.clone(),
+            provenance: self.provenance.clone(),
+            expiration_policy,
+            release_config_path: self.release_config_path.clone(),
+            wave_policy_path: self.wave_policy_path.clone(),
+            root_role_path: self.root_role_path.clone(),
+            default_key_path: self.default_key_path.clone(),
+            release_start_time: self.release_start_time,
+            outdir: self.outdir.clone(),
+            preflight: self.preflight,
+            emit_unsigned: self.emit_unsigned.clone(),
+            dry_run,
+        })
+    }
+}
+
+/// The update `build` adds to a repo, and where it writes the repo
+#[derive(Debug, Clone)]
+pub struct RepoOptions {
+    /// The repo from the infra config to add the update to; a default config is used if it isn't
+    /// there
+    pub repo: String,
+    pub arch: String,
+    pub version: Version,
+    pub variant: String,
+
+    /// Path to the image containing the boot partition
+    pub boot_image: PathBuf,
+    /// Path to the image containing the root partition
+    pub root_image: PathBuf,
+    /// Path to the image containing the verity hashes
+    pub hash_image: PathBuf,
+
+    /// Paths to add as targets and symlink into the repo
+    pub link_targets: Vec<PathBuf>,
+    /// Paths to add as targets and copy into the repo
+    pub copy_targets: Vec<PathBuf>,
+    /// Paths to SBOMs of the images, to add as targets
+    pub sboms: Vec<PathBuf>,
+    /// Paths to provenance attestations of the images, to add as targets
+    pub provenance: Vec<PathBuf>,
+
+    /// When the repo metadata expires, counted from the release start time
+    pub expiration_policy: RepoExpirationPolicy,
+    /// Path to Release.toml
+    pub release_config_path: PathBuf,
+    /// Path to the file that defines when the update becomes available
+    pub wave_policy_path: PathBuf,
+    /// Path to root.json for the repo
+    pub root_role_path: PathBuf,
+    /// The local signing key, used if the repo config has no signing key
+    pub default_key_path: PathBuf,
+    /// When the waves and expiration timer start; defaults to now
+    pub release_start_time: Option<DateTime<Utc>>,
+
+    /// Where to write the repo
+    pub outdir: PathBuf,
+    /// Check that the configured credentials can use the signing key before starting
+    pub preflight: bool,
+    /// Instead of signing the repo, write the unsigned metadata and a signing request to this
+    /// directory
+    pub emit_unsigned: Option<PathBuf>,
+    /// Build and sign the repo, but only print the files that would be written
+    pub dry_run: bool,
+}
+
+/// Expirations that replace the ones in the expiration policy file for a single run, like for a
+/// hotfix repo that should expire sooner than usual
+#[derive(Debug, StructOpt)]
+pub(crate) struct ExpirationOverrides {
+    #[structopt(long, parse(try_from_str = parse_offset))]
+    /// How long after the start time targets.json expires, like "in 7 days"; overrides the
+    /// expiration policy
+    pub(crate) targets_expiry: Option<Duration>,
+    #[structopt(long, parse(try_from_str = parse_offset))]
+    /// How long after the start time snapshot.json expires; overrides the expiration policy
+    pub(crate) snapshot_expiry: Option<Duration>,
+    #[structopt(long, parse(try_from_str = parse_offset))]
+    /// How long after the start time timestamp.json expires; overrides the expiration policy
+    pub(crate) timestamp_expiry: Option<Duration>,
+}
+
+impl ExpirationOverrides {
+    /// Returns the given policy with any overridden expirations replaced.
+    pub(crate) fn apply(&self, mut policy: RepoExpirationPolicy) -> RepoExpirationPolicy {
+        let overrides = [
+            (
+                "targets",
+                self.targets_expiry,
+                &mut policy.targets_expiration,
+            ),
+            (
+                "snapshot",
+                self.snapshot_expiry,
+                &mut policy.snapshot_expiration,
+            ),
+            (
+                "timestamp",
+                self.timestamp_expiry,
+                &mut policy.timestamp_expiration,
+            ),
+        ];
+        for (role, maybe_expiry, expiration) in overrides {
+            if let Some(expiry) = maybe_expiry {
+                info!(
+                    "Overriding {} expiration from the policy file: {} hours",
+                    role,
+                    expiry.num_hours()
+                );
+                *expiration = expiry;
+            }
+        }
+        policy
+    }
 }
 
 /// Adds update, migrations, and waves to the Manifest
-fn update_manifest(repo_args: &RepoArgs, manifest: &mut Manifest) -> Result<()> {
+fn update_manifest(options: &RepoOptions, manifest: &mut Manifest) -> Result<()> {
     // Add update   =^..^=   =^..^=   =^..^=   =^..^=
 
     let filename = |path: &PathBuf| -> Result<String> {
@@ -117,21 +297,21 @@ fn update_manifest(repo_args: &RepoArgs, manifest: &mut Manifest) -> Result<()>
     };
 
     let images = Images {
-        boot: filename(&repo_args.boot_image)?,
-        root: filename(&repo_args.root_image)?,
-        hash: filename(&repo_args.hash_image)?,
+        boot: filename(&options.boot_image)?,
+        root: filename(&options.root_image)?,
+        hash: filename(&options.hash_image)?,
     };
 
     info!(
         "Adding update to manifest for version: {}, arch: {}, variant: {}",
-        repo_args.version, repo_args.arch, repo_args.variant
+        options.version, options.arch, options.variant
     );
     manifest
         .add_update(
-            repo_args.version.clone(),
+            options.version.clone(),
             None,
-            repo_args.arch.clone(),
-            repo_args.variant.clone(),
+            options.arch.clone(),
+            options.variant.clone(),
             images,
         )
         .context(error::AddUpdateSnafu)?;
@@ -140,11 +320,11 @@ fn update_manifest(repo_args: &RepoArgs, manifest: &mut Manifest) -> Result<()>
 
     info!(
         "Using release config from path: {}",
-        repo_args.release_config_path.display()
+        options.release_config_path.display()
     );
-    let release = Release::from_path(&repo_args.release_config_path).context(
+    let release = Release::from_path(&options.release_config_path).context(
         error::UpdateMetadataReadSnafu {
-            path: &repo_args.release_config_path,
+            path: &options.release_config_path,
         },
     )?;
     trace!(
@@ -160,30 +340,30 @@ fn update_manifest(repo_args: &RepoArgs, manifest: &mut Manifest) -> Result<()>
 
     // Add update waves   =^..^=   =^..^=   =^..^=   =^..^=
 
-    let wave_start_time = repo_args.release_start_time.unwrap_or(*DEFAULT_START_TIME);
+    let wave_start_time = options.release_start_time.unwrap_or(*DEFAULT_START_TIME);
     info!(
         "Using wave policy from path: {}",
-        repo_args.wave_policy_path.display()
+        options.wave_policy_path.display()
     );
     info!(
         "Offsets from that file will be added to the release start time of: {}",
         wave_start_time
     );
-    let waves = UpdateWaves::from_path(&repo_args.wave_policy_path).context(
+    let waves = UpdateWaves::from_path(&options.wave_policy_path).context(
         error::UpdateMetadataReadSnafu {
-            path: &repo_args.wave_policy_path,
+            path: &options.wave_policy_path,
         },
     )?;
     manifest
         .set_waves(
-            repo_args.variant.clone(),
-            repo_args.arch.clone(),
-            repo_args.version.clone(),
+            options.variant.clone(),
+            options.arch.clone(),
+            options.version.clone(),
             wave_start_time,
             &waves,
         )
         .context(error::SetWavesSnafu {
-            wave_policy_path: &repo_args.wave_policy_path,
+            wave_policy_path: &options.wave_policy_path,
         })?;
 
     Ok(())
@@ -231,7 +411,7 @@ fn set_versions(editor: &mut RepositoryEditor) -> Result<()> {
 
 /// Adds targets, expirations, and version to the RepositoryEditor
 fn update_editor<'a, P>(
-    repo_args: &'a RepoArgs,
+    options: &'a RepoOptions,
     editor: &mut RepositoryEditor,
     targets: impl Iterator<Item = &'a PathBuf>,
     manifest_path: P,
@@ -260,14 +440,8 @@ where
 
     // Add expirations   =^..^=   =^..^=   =^..^=   =^..^=
 
-    info!(
-        "Using repo expiration policy from path: {}",
-        repo_args.repo_expiration_policy_path.display()
-    );
-    let expiration = RepoExpirationPolicy::from_path(&repo_args.repo_expiration_policy_path)
-        .context(error::ConfigSnafu)?;
-
-    let expiration_start_time = repo_args.release_start_time.unwrap_or(*DEFAULT_START_TIME);
+    let expiration = &options.expiration_policy;
+    let expiration_start_time = options.release_start_time.unwrap_or(*DEFAULT_START_TIME);
     let snapshot_expiration = expiration_start_time + expiration.snapshot_expiration;
     let targets_expiration = expiration_start_time + expiration.targets_expiration;
     let timestamp_expiration = expiration_start_time + expiration.timestamp_expiration;
@@ -300,7 +474,7 @@ where
 
 /// If the infra config has a repo section defined for the given repo, and it has metadata base and
 /// targets URLs defined, returns those URLs, otherwise None.
-fn repo_urls<'a>(
+pub(crate) fn repo_urls<'a>(
     repo_config: &'a RepoConfig,
     variant: &str,
     arch: &str,
@@ -328,13 +502,14 @@ fn repo_urls<'a>(
 }
 
 /// Builds an editor and manifest; will start from an existing repo if one is specified in the
-/// configuration.  Returns Err if we fail to read from the repo.  Returns Ok(None) if we detect
-/// that the repo does not exist.
+/// configuration.  Also returns the names of the roles that targets.json already delegates to.
+/// Returns Err if we fail to read from the repo.  Returns Ok(None) if we detect that the repo does
+/// not exist.
 fn load_editor_and_manifest<'a, P>(
     root_role_path: P,
     metadata_url: &'a Url,
     targets_url: &'a Url,
-) -> Result<Option<(RepositoryEditor, Manifest)>>
+) -> Result<Option<(RepositoryEditor, Manifest, Vec<String>)>>
 where
     P: AsRef<Path>,
 {
@@ -368,11 +543,24 @@ where
             let manifest = serde_json::from_reader(reader).context(error::InvalidJsonSnafu {
                 path: "manifest.json",
             })?;
+            let delegated_roles = repo
+                .targets()
+                .signed
+                .delegations
+                .as_ref()
+                .map(|delegations| {
+                    delegations
+                        .roles
+                        .iter()
+                        .map(|role| role.name.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
 
             let editor = RepositoryEditor::from_repo(root_role_path, repo)
                 .context(error::EditorFromRepoSnafu)?;
 
-            Ok(Some((editor, manifest)))
+            Ok(Some((editor, manifest, delegated_roles)))
         }
         // If we fail to load, but we only failed because the repo doesn't exist yet, then start
         // fresh by signalling that there is no known repo.  Otherwise, fail hard.
@@ -388,6 +576,132 @@ where
     }
 }
 
+/// Adds the delegated targets roles from Infra.toml to targets.json, or refreshes the metadata of
+/// the ones it already delegates to, from the metadata their publishers host.
+fn update_delegations(
+    editor: &mut RepositoryEditor,
+    delegations: &HashMap<String, DelegationConfig>,
+    existing_roles: &[String],
+) -> Result<()> {
+    let mut names: Vec<&String> = delegations.keys().collect();
+    names.sort();
+    for name in names {
+        let delegation = &delegations[name];
+        if existing_roles.contains(name) {
+            info!(
+                "Refreshing delegated role '{}' from {}",
+                name, delegation.metadata_url
+            );
+            editor
+                .update_delegated_targets(name, delegation.metadata_url.as_str())
+                .context(error::DelegationSnafu { role: name })?;
+        } else {
+            info!(
+                "Adding delegated role '{}' from {}",
+                name, delegation.metadata_url
+            );
+            let paths = delegation
+                .paths
+                .iter()
+                .map(|path| PathPattern::new(path))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .context(error::DelegationPathSnafu { role: name })?;
+            editor
+                .add_role(
+                    name,
+                    delegation.metadata_url.as_str(),
+                    PathSet::Paths(paths),
+                    delegation.threshold.unwrap_or(nonzero!(1u64)),
+                    Some(delegation_keys(name, delegation)?),
+                )
+                .context(error::DelegationSnafu { role: name })?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the public keys of a delegated role, keyed by their key IDs.
+fn delegation_keys(
+    role: &str,
+    delegation: &DelegationConfig,
+) -> Result<HashMap<Decoded<Hex>, Key>> {
+    let mut keys = HashMap::new();
+    for path in &delegation.keys {
+        let key: Key =
+            serde_json::from_reader(File::open(path).context(error::FileSnafu { path })?)
+                .context(error::InvalidJsonSnafu { path })?;
+        let key_id = key
+            .key_id()
+            .context(error::DelegationKeyIdSnafu { role, path })?;
+        keys.insert(key_id, key);
+    }
+    ensure!(!keys.is_empty(), error::NoDelegationKeysSnafu { role });
+    Ok(keys)
+}
+
+/// Checks that the given keys will sign each non-root role enough times to meet its threshold:
+/// the one from Infra.toml, or root.json's, whichever is higher.  tough signs a role with every key
+/// that root.json lists for it, so we count the keys that are listed.
+fn check_signature_thresholds(
+    root_role_path: &Path,
+    key_sources: &[Box<dyn KeySource>],
+    thresholds: Option<&SignatureThresholds>,
+) -> Result<()> {
+    let root: Signed<Root> =
+        serde_json::from_reader(File::open(root_role_path).context(error::FileSnafu {
+            path: root_role_path,
+        })?)
+        .context(error::InvalidJsonSnafu {
+            path: root_role_path,
+        })?;
+
+    let mut key_ids = Vec::with_capacity(key_sources.len());
+    for key_source in key_sources {
+        key_ids.push(
+            key_source
+                .as_sign()
+                .context(error::KeySourceSnafu)?
+                .tuf_key()
+                .key_id()
+                .context(error::KeyIdSnafu)?,
+        );
+    }
+
+    let thresholds = [
+        (RoleType::Targets, thresholds.and_then(|t| t.targets)),
+        (RoleType::Snapshot, thresholds.and_then(|t| t.snapshot)),
+        (RoleType::Timestamp, thresholds.and_then(|t| t.timestamp)),
+    ];
+    for (role, configured) in thresholds {
+        let role_keys = root
+            .signed
+            .roles
+            .get(&role)
+            .context(error::MissingRootRoleSnafu { role })?;
+        let required = configured.map_or(role_keys.threshold, |configured| {
+            configured.max(role_keys.threshold)
+        });
+        let signatures = role_keys
+            .keyids
+            .iter()
+            .filter(|key_id| key_ids.contains(key_id))
+            .count() as u64;
+        debug!(
+            "The {} role will have {} of {} required signatures",
+            role, signatures, required
+        );
+        ensure!(
+            signatures >= required.get(),
+            error::SignatureThresholdSnafu {
+                role,
+                signatures,
+                required: required.get(),
+            }
+        );
+    }
+    Ok(())
+}
+
 /// Inspects the `tough` error to see if it is a `Transport` error, and if so, is it `FileNotFound`.
 fn is_file_not_found_error(e: &tough::error::Error) -> bool {
     if let tough::error::Error::Transport { source, .. } = e {
@@ -397,65 +711,168 @@ fn is_file_not_found_error(e: &tough::error::Error) -> bool {
     }
 }
 
-/// Gets the corresponding `KeySource` according to the signing key config from Infra.toml
-fn get_signing_key_source(signing_key_config: &SigningKeyConfig) -> Result<Box<dyn KeySource>> {
+/// Gets the corresponding `KeySource` according to the signing key config from Infra.toml.  Keys
+/// stored as AWS secrets are fetched with the credentials from the given AWS config.
+pub(crate) fn get_signing_key_source(
+    signing_key_config: &SigningKeyConfig,
+    aws: &PubsysAwsConfig,
+) -> Result<Box<dyn KeySource>> {
     match signing_key_config {
         SigningKeyConfig::file { path } => Ok(Box::new(LocalKeySource { path: path.clone() })),
-        SigningKeyConfig::kms { key_id, config, .. } => Ok(Box::new(KmsKeySource {
-            profile: None,
-            key_id: key_id
-                .clone()
-                .context(error::MissingConfigSnafu { missing: "key_id" })?,
-            client: {
-                let key_id_val = key_id
-                    .clone()
-                    .context(error::MissingConfigSnafu { missing: "key_id" })?;
-                match config.as_ref() {
-                    Some(config_val) => get_client(config_val, &key_id_val)?,
+        SigningKeyConfig::kms { key_id, config } => {
+            // The private key never leaves KMS; tough asks KMS for the public key and signatures.
+            let key_id = kms_key_id(key_id.as_ref(), config.as_ref())?;
+            Ok(Box::new(KmsKeySource {
+                profile: None,
+                client: match config.as_ref() {
+                    Some(config_val) => get_client(config_val, &key_id, aws)?,
                     None => None,
-                }
-            },
-            signing_algorithm: KmsSigningAlgorithm::RsassaPssSha256,
-        })),
+                },
+                key_id,
+                signing_algorithm: KmsSigningAlgorithm::RsassaPssSha256,
+            }))
+        }
         SigningKeyConfig::ssm { parameter } => Ok(Box::new(SsmKeySource {
             profile: None,
             parameter_name: parameter.clone(),
             key_id: None,
         })),
+        SigningKeyConfig::secret { arn } => Ok(Box::new(SecretKeySource {
+            arn: arn.clone(),
+            aws: aws.clone(),
+        })),
+        SigningKeyConfig::pkcs11 {
+            module_path,
+            token_label,
+            key_label,
+            public_key_path,
+            pin_env,
+        } => Ok(Box::new(Pkcs11KeySource {
+            module_path: module_path.clone(),
+            token_label: token_label.clone(),
+            key_label: key_label.clone(),
+            public_key_path: public_key_path.clone(),
+            pin_env: pin_env.clone(),
+        })),
     }
 }
 
+/// Returns the KMS key to sign with: the configured `key_id`, or else the only key in
+/// `available_keys`, which is where infrasys records the keys it creates.
+fn kms_key_id(key_id: Option<&String>, config: Option<&KMSKeyConfig>) -> Result<String> {
+    key_id
+        .or_else(|| config.and_then(KMSKeyConfig::single_available_key))
+        .cloned()
+        .context(error::MissingConfigSnafu {
+            missing: "key_id, or a single key in available_keys",
+        })
+}
+
 /// Helper function that generates a KmsClient or None given config containing available keys
-fn get_client(kmskey_config: &KMSKeyConfig, key_id: &str) -> Result<Option<KmsClient>> {
+fn get_client(
+    kmskey_config: &KMSKeyConfig,
+    key_id: &str,
+    aws: &PubsysAwsConfig,
+) -> Result<Option<KmsClient>> {
     if let Some(region) = kmskey_config.available_keys.get(key_id) {
         let rt = Runtime::new().context(error::RuntimeSnafu)?;
-        Ok(Some(rt.block_on(async { async_get_client(region).await })))
+        Ok(Some(
+            rt.block_on(async { async_get_client(region, aws).await }),
+        ))
     } else {
         Ok(None)
     }
 }
 
-/// Helper function that generates a KmsClient given region
-async fn async_get_client(region: &str) -> KmsClient {
-    let client_config = aws_config::from_env()
-        .region(Region::new(region.to_string()))
-        .load()
-        .await;
-    KmsClient::new(&client_config)
+/// Helper function that generates a KmsClient given region, connecting through the proxy from the
+/// given AWS config, if any
+async fn async_get_client(region: &str, aws: &PubsysAwsConfig) -> KmsClient {
+    let mut loader = aws_config::from_env().region(Region::new(region.to_string()));
+    if let Some(connector) = proxy::http_connector(aws) {
+        loader = loader.http_connector(connector);
+    }
+    KmsClient::new(&loader.load().await)
+}
+
+/// Prints the files that a real run would write to the output directory for upload, with their
+/// sizes.  The metadata is written to a temporary directory so we can see its final size.
+fn print_dry_run<'a>(
+    options: &RepoOptions,
+    signed_repo: &SignedRepository,
+    manifest_path: &Path,
+    targets: impl Iterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    let mut files = Vec::new();
+    let size = |path: &Path| -> Result<u64> {
+        Ok(fs::metadata(path).context(error::FileSnafu { path })?.len())
+    };
+    files.push(("targets/manifest.json".to_string(), size(manifest_path)?));
+    for target in targets {
+        let name = target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(error::InvalidImagePathSnafu { path: target })?;
+        files.push((format!("targets/{}", name), size(target)?));
+    }
+
+    let metadata_dir = tempfile::tempdir().context(error::TempFileSnafu)?;
+    signed_repo
+        .write(metadata_dir.path())
+        .context(error::RepoWriteSnafu {
+            path: metadata_dir.path(),
+        })?;
+    let mut metadata_files = Vec::new();
+    for entry in fs::read_dir(metadata_dir.path()).context(error::FileSnafu {
+        path: metadata_dir.path(),
+    })? {
+        let path = entry
+            .context(error::FileSnafu {
+                path: metadata_dir.path(),
+            })?
+            .path();
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        metadata_files.push((
+            format!("{}/{}/{}", options.variant, options.arch, filename),
+            size(&path)?,
+        ));
+    }
+    metadata_files.sort();
+    files.extend(metadata_files);
+
+    info!(
+        "Dry run; not writing to {}.  These files would be written:",
+        options.outdir.display()
+    );
+    for (path, size) in &files {
+        println!("{}\t{} bytes", path, size);
+    }
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    println!("{} files, {} bytes in total", files.len(), total);
+    Ok(())
 }
 
 /// Common entrypoint from main()
-pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
-    let metadata_out_dir = repo_args
-        .outdir
-        .join(&repo_args.variant)
-        .join(&repo_args.arch);
-    let targets_out_dir = repo_args.outdir.join("targets");
+pub fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
+    run_with_options(args, &repo_args.options(args.dry_run)?)
+}
+
+/// Builds the repo with the infra config from the command line, as the 'repo' subcommand does.
+pub(crate) fn run_with_options(args: &Args, options: &RepoOptions) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
+    build(&infra_config, options)
+}
+
+/// Adds the update in the options to the repo, starting from the published repo if there is one,
+/// and writes the signed repo to the output directory.
+pub fn build(infra_config: &InfraConfig, options: &RepoOptions) -> Result<()> {
+    let metadata_out_dir = options.outdir.join(&options.variant).join(&options.arch);
+    let targets_out_dir = options.outdir.join("targets");
 
     // If the given metadata directory exists, throw an error.  We don't want to overwrite a user's
     // existing repository.  (The targets directory is shared, so it's fine if that exists.)
     ensure!(
-        !Path::exists(&metadata_out_dir),
+        options.dry_run || !Path::exists(&metadata_out_dir),
         error::RepoExistsSnafu {
             path: metadata_out_dir
         }
@@ -463,10 +880,8 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
 
     // Build repo   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
 
     // If the user has the requested (or "default") repo defined in their Infra.toml, use it,
     // otherwise use a default config.
@@ -474,49 +889,68 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
     let repo_config = if let Some(repo_config) = infra_config
         .repo
         .as_ref()
-        .and_then(|repo_section| repo_section.get(&repo_args.repo))
+        .and_then(|repo_section| repo_section.get(&options.repo))
         .map(|repo| {
-            info!("Using repo '{}' from Infra.toml", repo_args.repo);
+            info!("Using repo '{}' from Infra.toml", options.repo);
             repo
         }) {
         repo_config
     } else {
         info!(
             "Didn't find repo '{}' in Infra.toml, using default configuration",
-            repo_args.repo
+            options.repo
         );
         &default_repo_config
     };
 
+    if options.preflight && options.emit_unsigned.is_none() {
+        let rt = Runtime::new().context(error::RuntimeSnafu)?;
+        for signing_key_config in repo_config.all_signing_keys() {
+            rt.block_on(check_permissions::preflight_signing_key(
+                signing_key_config,
+                &aws,
+            ))
+            .context(error::PreflightSnafu)?;
+        }
+    }
+
     // Build a repo editor and manifest, from an existing repo if available, otherwise fresh
-    let maybe_urls = repo_urls(repo_config, &repo_args.variant, &repo_args.arch)?;
-    let (mut editor, mut manifest) = if let Some((metadata_url, targets_url)) = maybe_urls.as_ref()
+    let maybe_urls = repo_urls(repo_config, &options.variant, &options.arch)?;
+    let (mut editor, mut manifest, delegated_roles) = if let Some((metadata_url, targets_url)) =
+        maybe_urls.as_ref()
     {
         info!("Found metadata and target URLs, loading existing repository");
-        match load_editor_and_manifest(&repo_args.root_role_path, metadata_url, targets_url)? {
-            Some((editor, manifest)) => (editor, manifest),
+        match load_editor_and_manifest(&options.root_role_path, metadata_url, targets_url)? {
+            Some(loaded) => loaded,
             None => {
                 warn!(
                     "Did not find repo at '{}', starting a new one",
                     metadata_url
                 );
                 (
-                    RepositoryEditor::new(&repo_args.root_role_path)
+                    RepositoryEditor::new(&options.root_role_path)
                         .context(error::NewEditorSnafu)?,
                     Manifest::default(),
+                    Vec::new(),
                 )
             }
         }
     } else {
         info!("Did not find metadata and target URLs in infra config, creating a new repository");
         (
-            RepositoryEditor::new(&repo_args.root_role_path).context(error::NewEditorSnafu)?,
+            RepositoryEditor::new(&options.root_role_path).context(error::NewEditorSnafu)?,
             Manifest::default(),
+            Vec::new(),
         )
     };
 
+    // Add or refresh any delegated targets roles
+    if let Some(delegations) = repo_config.delegations.as_ref() {
+        update_delegations(&mut editor, delegations, &delegated_roles)?;
+    }
+
     // Add update information to manifest
-    update_manifest(repo_args, &mut manifest)?;
+    update_manifest(options, &mut manifest)?;
     // Write manifest to tempfile so it can be copied in as target later
     let manifest_path = NamedTempFile::new()
         .context(error::TempFileSnafu)?
@@ -525,38 +959,72 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         path: &manifest_path,
     })?;
 
+    // Write a release attestations target that ties the images to any SBOMs and provenance, which
+    // are copied in along with it
+    let attestations_dir = tempfile::tempdir().context(error::TempFileSnafu)?;
+    let attestation_targets =
+        attestations::write_release_attestations(options, attestations_dir.path())
+            .context(error::AttestationsSnafu)?;
+
     // Add manifest and targets to editor
-    let copy_targets = &repo_args.copy_targets;
-    let link_targets = repo_args.link_targets.iter().chain(vec![
-        &repo_args.boot_image,
-        &repo_args.root_image,
-        &repo_args.hash_image,
+    let copy_targets: Vec<&PathBuf> = options
+        .copy_targets
+        .iter()
+        .chain(&attestation_targets)
+        .collect();
+    let link_targets = options.link_targets.iter().chain(vec![
+        &options.boot_image,
+        &options.root_image,
+        &options.hash_image,
     ]);
-    let all_targets = copy_targets.iter().chain(link_targets.clone());
+    let all_targets = copy_targets.iter().copied().chain(link_targets.clone());
 
-    update_editor(repo_args, &mut editor, all_targets, &manifest_path)?;
+    update_editor(options, &mut editor, all_targets, &manifest_path)?;
 
     // Sign repo   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // Check if we have a signing key defined in Infra.toml; if not, we'll fall back to the
-    // generated local key.
-    let signing_key_config = repo_config.signing_keys.as_ref();
-
-    let key_source = if let Some(signing_key_config) = signing_key_config {
-        get_signing_key_source(signing_key_config)?
+    // If the repo is going to be signed elsewhere, we only need the public keys from root.json.
+    // Otherwise, check if we have a signing key defined in Infra.toml; if not, we'll fall back to
+    // the generated local key.  Any additional signing keys sign alongside it.
+    let key_sources = if options.emit_unsigned.is_some() {
+        offline_signing::placeholder_keys(&options.root_role_path)
+            .context(error::OfflineSigningSnafu)?
     } else {
-        ensure!(
-            repo_args.default_key_path.exists(),
-            error::MissingConfigSnafu {
-                missing: "signing_keys in repo config, and we found no local key",
-            }
-        );
-        Box::new(LocalKeySource {
-            path: repo_args.default_key_path.clone(),
-        })
+        let mut key_sources: Vec<Box<dyn KeySource>> = Vec::new();
+        if repo_config.signing_keys.is_none() {
+            ensure!(
+                options.default_key_path.exists(),
+                error::MissingConfigSnafu {
+                    missing: "signing_keys in repo config, and we found no local key",
+                }
+            );
+            key_sources.push(Box::new(LocalKeySource {
+                path: options.default_key_path.clone(),
+            }));
+        }
+        for signing_key_config in repo_config.all_signing_keys() {
+            key_sources.push(get_signing_key_source(signing_key_config, &aws)?);
+        }
+        check_signature_thresholds(
+            &options.root_role_path,
+            &key_sources,
+            repo_config.signature_thresholds.as_ref(),
+        )?;
+        key_sources
     };
 
-    let signed_repo = editor.sign(&[key_source]).context(error::RepoSignSnafu)?;
+    let signed_repo = editor.sign(&key_sources).context(error::RepoSignSnafu)?;
+
+    // A dry run builds and signs the repo, but only lists the files that would be written for
+    // upload, with their sizes.
+    if options.dry_run {
+        return print_dry_run(
+            options,
+            &signed_repo,
+            &manifest_path,
+            copy_targets.iter().copied().chain(link_targets),
+        );
+    }
 
     // Write repo   =^..^=   =^..^=   =^..^=   =^..^=
 
@@ -613,6 +1081,17 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
             })?;
     }
 
+    if let Some(request_dir) = options.emit_unsigned.as_ref() {
+        return offline_signing::write_signing_request(
+            &signed_repo,
+            &options.root_role_path,
+            &options.variant,
+            &options.arch,
+            request_dir,
+        )
+        .context(error::OfflineSigningSnafu);
+    }
+
     info!("Writing repo metadata to: {}", metadata_out_dir.display());
     fs::create_dir_all(&metadata_out_dir).context(error::CreateDirSnafu {
         path: &metadata_out_dir,
@@ -620,8 +1099,16 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
     signed_repo
         .write(&metadata_out_dir)
         .context(error::RepoWriteSnafu {
-            path: &repo_args.outdir,
+            path: &options.outdir,
         })?;
+    repo_manifest::write_repo_manifest(
+        &options.root_role_path,
+        &metadata_out_dir,
+        &options.variant,
+        &options.arch,
+        &key_sources,
+    )
+    .context(error::RepoManifestSnafu)?;
 
     Ok(())
 }
@@ -635,7 +1122,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Failed to add new update to manifest: {}", source))]
         AddUpdate {
             source: update_metadata::error::Error,
@@ -648,6 +1135,11 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("Failed to add SBOMs and provenance: {}", source))]
+        Attestations {
+            source: crate::repo::attestations::Error,
+        },
+
         #[snafu(display("Failed to build target metadata from path '{}': {}", path.display(), source))]
         BuildTarget {
             path: PathBuf,
@@ -669,6 +1161,36 @@ mod error {
         #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
         CreateDir { path: PathBuf, source: io::Error },
 
+        #[snafu(display("Failed to update delegated role '{}': {}", role, source))]
+        Delegation {
+            role: String,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(display(
+            "Failed to get ID of key '{}' for delegated role '{}': {}",
+            path.display(),
+            role,
+            source
+        ))]
+        DelegationKeyId {
+            role: String,
+            path: PathBuf,
+            source: tough::schema::Error,
+        },
+
+        #[snafu(display("Invalid path pattern for delegated role '{}': {}", role, source))]
+        DelegationPath {
+            role: String,
+            source: tough::schema::Error,
+        },
+
+        #[snafu(display(
+            "--dry-run can't be used with --emit-unsigned, which only writes the unsigned repo"
+        ))]
+        DryRunUnsigned,
+
         #[snafu(display("Failed to create repo editor from given repo: {}", source))]
         EditorFromRepo {
             #[snafu(source(from(tough::error::Error, Box::new)))]
@@ -701,9 +1223,20 @@ mod error {
             source: update_metadata::error::Error,
         },
 
+        #[snafu(display("Failed to get key ID: {}", source))]
+        KeyId { source: tough::schema::Error },
+
+        #[snafu(display("Failed to get key from key source: {}", source))]
+        KeySource {
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
         #[snafu(display("Infra.toml is missing {}", missing))]
         MissingConfig { missing: String },
 
+        #[snafu(display("root.json has no keys for the {} role", role))]
+        MissingRootRole { role: tough::schema::RoleType },
+
         #[snafu(display("Repo URLs not specified for repo '{}'", repo))]
         MissingRepoUrls { repo: String },
 
@@ -713,18 +1246,31 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("No keys given for delegated role '{}'", role))]
+        NoDelegationKeys { role: String },
+
         #[snafu(display("Repo does not have a manifest.json: {}", metadata_url))]
         NoManifest { metadata_url: Url },
 
         #[snafu(display("Non-UTF8 path '{}' not supported", path.display()))]
         NonUtf8Path { path: PathBuf },
 
+        #[snafu(display("Failed to prepare repo for offline signing: {}", source))]
+        OfflineSigning {
+            source: crate::repo::offline_signing::Error,
+        },
+
         #[snafu(display("Invalid URL '{}': {}", input, source))]
         ParseUrl {
             input: String,
             source: url::ParseError,
         },
 
+        #[snafu(display("Permission check failed: {}", source))]
+        Preflight {
+            source: crate::aws::check_permissions::Error,
+        },
+
         #[snafu(display("Failed to read target '{}' from repo: {}", target, source))]
         ReadTarget {
             target: String,
@@ -759,6 +1305,11 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("Failed to write repo manifest: {}", source))]
+        RepoManifest {
+            source: crate::repo::repo_manifest::Error,
+        },
+
         #[snafu(display("Requested repository does not exist: '{}'", url))]
         RepoNotFound { url: Url },
 
@@ -789,6 +1340,19 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display(
+            "The {} role would only have {} of the {} signatures it needs; configure more \
+             signing keys that root.json trusts for it",
+            role,
+            signatures,
+            required
+        ))]
+        SignatureThreshold {
+            role: tough::schema::RoleType,
+            signatures: u64,
+            required: u64,
+        },
+
         #[snafu(display("Failed to set waves from '{}': {}", wave_policy_path.display(), source))]
         SetWaves {
             wave_policy_path: PathBuf,
@@ -805,5 +1369,5 @@ mod error {
         },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,320 @@
+//! The attestations module adds supply-chain metadata for an update to the repo: SBOMs and
+//! SLSA-style provenance are added as targets, and a release attestations target lists them with
+//! the update's images and their sha256s.  Since they're all targets, consumers fetch and verify
+//! them through the same root.json as the images, for example with tuftool.
+//!
+//! Provenance has to be an in-toto statement, or a DSSE envelope holding one; SBOMs have to be
+//! SPDX or CycloneDX documents.
+
+use crate::repo::s3::sha256_file;
+use crate::repo::RepoOptions;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The prefix of in-toto statement types, which is followed by the statement version
+const IN_TOTO_STATEMENT_PREFIX: &str = "https://in-toto.io/Statement/";
+
+/// The payload type of a DSSE envelope holding an in-toto statement
+const IN_TOTO_PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+/// The name and sha256 of a target
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TargetDigest {
+    pub(crate) target: String,
+    /// Hex-encoded
+    pub(crate) sha256: String,
+}
+
+/// An SBOM or provenance target, and the format it's in
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Attestation {
+    #[serde(flatten)]
+    pub(crate) digest: TargetDigest,
+    /// For SBOMs, "spdx-json", "spdx-tag-value", or "cyclonedx-json"; for provenance, the
+    /// predicate type of the statement, like "https://slsa.dev/provenance/v0.2", or "dsse" for an
+    /// envelope
+    pub(crate) format: String,
+}
+
+/// The release attestations target, which ties an update's images to its supply-chain metadata
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ReleaseAttestations {
+    pub(crate) variant: String,
+    pub(crate) arch: String,
+    pub(crate) version: String,
+    /// The update's images, keyed by their role in the update: "boot", "root", or "hash"
+    pub(crate) images: BTreeMap<String, TargetDigest>,
+    pub(crate) sboms: Vec<Attestation>,
+    pub(crate) provenance: Vec<Attestation>,
+}
+
+/// Returns the name of the release attestations target for an update.  Targets from every
+/// variant and arch share a directory, so the name includes them.
+pub(crate) fn target_name(variant: &str, arch: &str, version: &str) -> String {
+    format!("release-attestations-{}-{}-{}.json", variant, arch, version)
+}
+
+/// If any SBOMs or provenance were given, writes the release attestations for the update into the
+/// given directory, under its target name, and returns the paths of every file to add as a
+/// target: the SBOMs, the provenance, and the release attestations.
+pub(super) fn write_release_attestations(
+    options: &RepoOptions,
+    dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    if options.sboms.is_empty() && options.provenance.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut images = BTreeMap::new();
+    for (role, path) in [
+        ("boot", &options.boot_image),
+        ("root", &options.root_image),
+        ("hash", &options.hash_image),
+    ] {
+        images.insert(role.to_string(), target_digest(path)?);
+    }
+
+    let mut sboms = Vec::with_capacity(options.sboms.len());
+    for path in &options.sboms {
+        let contents = fs::read(path).context(error::ReadSnafu { path })?;
+        let format = sbom_format(&contents).context(error::UnknownSbomFormatSnafu { path })?;
+        sboms.push(Attestation {
+            digest: target_digest(path)?,
+            format: format.to_string(),
+        });
+    }
+
+    let image_digests: Vec<&str> = images.values().map(|image| image.sha256.as_str()).collect();
+    let mut provenance = Vec::with_capacity(options.provenance.len());
+    for path in &options.provenance {
+        let contents = fs::read(path).context(error::ReadSnafu { path })?;
+        let statement: Value =
+            serde_json::from_slice(&contents).context(error::ParseProvenanceSnafu { path })?;
+        let format = provenance_format(&statement, &image_digests, path)?;
+        provenance.push(Attestation {
+            digest: target_digest(path)?,
+            format,
+        });
+    }
+
+    let version = options.version.to_string();
+    let attestations = ReleaseAttestations {
+        variant: options.variant.clone(),
+        arch: options.arch.clone(),
+        version: version.clone(),
+        images,
+        sboms,
+        provenance,
+    };
+    let path = dir.join(target_name(&options.variant, &options.arch, &version));
+    let json = serde_json::to_string_pretty(&attestations).context(error::SerializeSnafu)?;
+    fs::write(&path, json).context(error::WriteSnafu { path: &path })?;
+    info!(
+        "Adding {} SBOMs and {} provenance attestations, listed in {}",
+        attestations.sboms.len(),
+        attestations.provenance.len(),
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let mut targets: Vec<PathBuf> = options
+        .sboms
+        .iter()
+        .chain(&options.provenance)
+        .cloned()
+        .collect();
+    targets.push(path);
+    Ok(targets)
+}
+
+/// Returns the target name and sha256 of a file that's added as a target.
+fn target_digest(path: &Path) -> Result<TargetDigest> {
+    let target = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context(error::InvalidPathSnafu { path })?
+        .to_string();
+    let sha256 = hex::encode(sha256_file(path).context(error::HashSnafu { path })?);
+    Ok(TargetDigest { target, sha256 })
+}
+
+/// Returns the format of an SBOM, if it's one we know.
+fn sbom_format(contents: &[u8]) -> Option<&'static str> {
+    if let Ok(document) = serde_json::from_slice::<Value>(contents) {
+        if document.get("spdxVersion").is_some() {
+            return Some("spdx-json");
+        }
+        if document["bomFormat"] == "CycloneDX" {
+            return Some("cyclonedx-json");
+        }
+        return None;
+    }
+    let text = String::from_utf8_lossy(contents);
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| line.starts_with("SPDXVersion:"))
+        .map(|_| "spdx-tag-value")
+}
+
+/// Checks that provenance is an in-toto statement, or a DSSE envelope holding one, and returns
+/// its format.  Provenance whose subjects don't include any of the update's images is allowed,
+/// since it may describe other targets, but is probably a mistake, so we warn about it.
+fn provenance_format(statement: &Value, image_digests: &[&str], path: &Path) -> Result<String> {
+    // The statement in an envelope is base64-encoded and signed; consumers check the signature
+    // themselves, so we only check that it's the right kind of envelope.
+    if let Some(payload_type) = statement.get("payloadType") {
+        ensure!(
+            payload_type == IN_TOTO_PAYLOAD_TYPE,
+            error::InvalidProvenanceSnafu {
+                path,
+                reason: format!("envelope payload type isn't {}", IN_TOTO_PAYLOAD_TYPE),
+            }
+        );
+        return Ok("dsse".to_string());
+    }
+
+    ensure!(
+        statement["_type"]
+            .as_str()
+            .map(|statement_type| statement_type.starts_with(IN_TOTO_STATEMENT_PREFIX))
+            .unwrap_or(false),
+        error::InvalidProvenanceSnafu {
+            path,
+            reason: "it isn't an in-toto statement",
+        }
+    );
+    let predicate_type =
+        statement["predicateType"]
+            .as_str()
+            .context(error::InvalidProvenanceSnafu {
+                path,
+                reason: "it has no predicateType",
+            })?;
+
+    let subject_digests: Vec<&str> = statement["subject"]
+        .as_array()
+        .map(|subjects| {
+            subjects
+                .iter()
+                .filter_map(|subject| subject["digest"]["sha256"].as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !subject_digests
+        .iter()
+        .any(|digest| image_digests.contains(digest))
+    {
+        warn!(
+            "Provenance in {} doesn't name any of the update's images as a subject",
+            path.display()
+        );
+    }
+    Ok(predicate_type.to_string())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to hash '{}': {}", path.display(), source))]
+        Hash {
+            path: PathBuf,
+            source: crate::repo::s3::Error,
+        },
+
+        #[snafu(display("Invalid target path '{}'", path.display()))]
+        InvalidPath { path: PathBuf },
+
+        #[snafu(display("Invalid provenance in '{}': {}", path.display(), reason))]
+        InvalidProvenance { path: PathBuf, reason: String },
+
+        #[snafu(display("Failed to parse provenance in '{}': {}", path.display(), source))]
+        ParseProvenance {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        Read {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize release attestations: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("'{}' isn't an SPDX or CycloneDX SBOM", path.display()))]
+        UnknownSbomFormat { path: PathBuf },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{provenance_format, sbom_format};
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn sbom_formats() {
+        assert_eq!(
+            sbom_format(br#"{"spdxVersion": "SPDX-2.3"}"#),
+            Some("spdx-json")
+        );
+        assert_eq!(
+            sbom_format(br#"{"bomFormat": "CycloneDX", "specVersion": "1.4"}"#),
+            Some("cyclonedx-json")
+        );
+        assert_eq!(
+            sbom_format(b"# generated\nSPDXVersion: SPDX-2.3\nDataLicense: CC0-1.0\n"),
+            Some("spdx-tag-value")
+        );
+        assert_eq!(sbom_format(br#"{"name": "not an sbom"}"#), None);
+        assert_eq!(sbom_format(b"not an sbom"), None);
+    }
+
+    #[test]
+    fn provenance_formats() {
+        let path = Path::new("provenance.json");
+        let statement = json!({
+            "_type": "https://in-toto.io/Statement/v0.1",
+            "predicateType": "https://slsa.dev/provenance/v0.2",
+            "subject": [{ "name": "root.ext4.lz4", "digest": { "sha256": "abcd" } }],
+            "predicate": {},
+        });
+        assert_eq!(
+            provenance_format(&statement, &["abcd"], path).unwrap(),
+            "https://slsa.dev/provenance/v0.2"
+        );
+
+        let envelope = json!({
+            "payloadType": "application/vnd.in-toto+json",
+            "payload": "e30=",
+            "signatures": [],
+        });
+        assert_eq!(provenance_format(&envelope, &[], path).unwrap(), "dsse");
+
+        assert!(provenance_format(&json!({ "predicateType": "x" }), &[], path).is_err());
+        assert!(provenance_format(
+            &json!({ "_type": "https://in-toto.io/Statement/v1" }),
+            &[],
+            path
+        )
+        .is_err());
+    }
+}
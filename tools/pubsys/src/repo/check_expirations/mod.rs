@@ -1,87 +1,263 @@
 //! The check_expirations module owns the 'check-repo-expirations' subcommand and provide methods for
 //! checking the metadata expirations of a given TUF repository.
 
-use crate::repo::{error as repo_error, repo_urls};
+use crate::exit_code::{Classify, Kind};
+use crate::repo::{error as repo_error, is_file_not_found_error, repo_urls};
 use crate::Args;
 use chrono::{DateTime, Utc};
 use log::{error, info, trace, warn};
 use parse_datetime::parse_datetime;
 use pubsys_config::InfraConfig;
-use snafu::{OptionExt, ResultExt};
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::PathBuf;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_plain::derive_display_from_serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
 use tough::{ExpirationEnforcement, Repository, RepositoryLoader};
 use url::Url;
 
+/// When checking all repos, we check this many at once.
+const MAX_CONCURRENT_CHECKS: usize = 16;
+
 /// Checks for metadata expirations for a set of TUF repositories
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct CheckExpirationsArgs {
-    #[structopt(long)]
+pub struct CheckExpirationsArgs {
+    #[structopt(long, required_unless = "all")]
     /// Use this named repo infrastructure from Infra.toml
-    repo: String,
+    repo: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, required_unless = "all")]
     /// The architecture of the repo being checked for expirations
-    arch: String,
-    #[structopt(long)]
+    arch: Option<String>,
+    #[structopt(long, required_unless = "all")]
     /// The variant of the repo being checked for expirations
-    variant: String,
+    variant: Option<String>,
 
-    #[structopt(long, parse(from_os_str))]
+    #[structopt(long, parse(from_os_str), required_unless = "all")]
     /// Path to root.json for this repo
-    root_role_path: PathBuf,
+    root_role_path: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["repo", "arch", "variant", "root-role-path"],
+        requires_all = &["variants-dir", "roles-dir"]
+    )]
+    /// Check every repo in Infra.toml, for every variant and architecture, instead of one repo
+    all: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// With --all, the directory of variants to check; each subdirectory with a Cargo.toml is a
+    /// variant
+    variants_dir: Option<PathBuf>,
+
+    #[structopt(long, use_delimiter = true, default_value = "x86_64,aarch64")]
+    /// With --all, the architectures to check
+    arches: Vec<String>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// With --all, the directory holding the root.json of each repo, named <repo>.root.json
+    roles_dir: Option<PathBuf>,
 
     #[structopt(long, parse(try_from_str = parse_datetime))]
     /// Finds metadata files expiring between now and a specified time; RFC3339 date or "in X hours/days/weeks"
     expiration_limit: DateTime<Utc>,
+
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    /// Only fail if metadata expires between now and this time, rather than the expiration limit;
+    /// RFC3339 date or "in X hours/days/weeks"
+    fail_within: Option<DateTime<Utc>>,
+
+    #[structopt(long)]
+    /// If this argument is given, print the expirations as a JSON object instead of logging them
+    json: bool,
+}
+
+/// The repos whose metadata expirations are checked
+#[derive(Debug, Clone)]
+pub enum ReposToCheck {
+    /// One repo from the infra config, for one variant and architecture
+    One {
+        repo: String,
+        variant: String,
+        arch: String,
+        /// Path to root.json for the repo
+        root_role_path: PathBuf,
+    },
+    /// Every repo in the infra config, for every variant and architecture
+    All {
+        /// Directory of variants; each subdirectory with a Cargo.toml is a variant
+        variants_dir: PathBuf,
+        arches: Vec<String>,
+        /// Directory holding the root.json of each repo, named <repo>.root.json
+        roles_dir: PathBuf,
+    },
+}
+
+/// What `check` and `json_summary` check
+#[derive(Debug, Clone)]
+pub struct CheckExpirationsOptions {
+    pub repos: ReposToCheck,
+    /// Metadata expiring before this time is reported as expiring
+    pub expiration_limit: DateTime<Utc>,
+    /// Metadata expiring before this time is marked failing; the expiration limit is used if this
+    /// is None
+    pub fail_within: Option<DateTime<Utc>>,
+}
+
+impl CheckExpirationsOptions {
+    /// Returns when metadata has to expire for the check to fail.  Without a separate failure
+    /// window, anything expiring within the limit is a failure.
+    fn fail_date(&self) -> DateTime<Utc> {
+        self.fail_within.unwrap_or(self.expiration_limit)
+    }
+}
+
+impl From<&CheckExpirationsArgs> for CheckExpirationsOptions {
+    fn from(args: &CheckExpirationsArgs) -> Self {
+        let repos = if args.all {
+            // structopt requires these with --all.
+            match (&args.variants_dir, &args.roles_dir) {
+                (Some(variants_dir), Some(roles_dir)) => ReposToCheck::All {
+                    variants_dir: variants_dir.clone(),
+                    arches: args.arches.clone(),
+                    roles_dir: roles_dir.clone(),
+                },
+                _ => unreachable!("developer error: --all requires --variants-dir and --roles-dir"),
+            }
+        } else {
+            // structopt requires these without --all.
+            match (&args.repo, &args.variant, &args.arch, &args.root_role_path) {
+                (Some(repo), Some(variant), Some(arch), Some(root_role_path)) => ReposToCheck::One {
+                    repo: repo.clone(),
+                    variant: variant.clone(),
+                    arch: arch.clone(),
+                    root_role_path: root_role_path.clone(),
+                },
+                _ => unreachable!(
+                    "developer error: --repo, --variant, --arch, and --root-role-path are required without --all"
+                ),
+            }
+        };
+        Self {
+            repos,
+            expiration_limit: args.expiration_limit,
+            fail_within: args.fail_within,
+        }
+    }
+}
+
+/// The expiration status of a metadata role, ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpirationStatus {
+    /// The role doesn't expire before the expiration limit
+    Ok,
+    /// The role expires before the expiration limit
+    Expiring,
+    /// The role has already expired
+    Expired,
+}
+
+derive_display_from_serialize!(ExpirationStatus);
+
+/// The expiration of a single metadata role
+#[derive(Debug, Serialize)]
+pub struct RoleExpiration {
+    pub role: tough::schema::RoleType,
+    /// RFC3339 expiration date
+    pub expires: String,
+    #[serde(skip)]
+    pub expires_at: DateTime<Utc>,
+    pub status: ExpirationStatus,
+    /// Whether the role expires soon enough that the check should fail
+    pub failing: bool,
+}
+
+/// The expirations of all metadata roles in a repo
+#[derive(Debug, Serialize)]
+pub struct RepoExpirations {
+    pub metadata_url: Url,
+    pub roles: Vec<RoleExpiration>,
 }
 
-/// Checks for upcoming role expirations, gathering them in a map of role to expiration datetime.
+impl RepoExpirations {
+    /// Returns whether any role expires soon enough that the check should fail
+    pub fn failing(&self) -> bool {
+        self.roles.iter().any(|role| role.failing)
+    }
+}
+
+/// Checks for upcoming role expirations, returning the expiration status of each role.
 fn find_upcoming_metadata_expiration(
     repo: &Repository,
+    now: DateTime<Utc>,
     end_date: DateTime<Utc>,
-) -> HashMap<tough::schema::RoleType, DateTime<Utc>> {
-    let mut expirations = HashMap::new();
+    fail_date: DateTime<Utc>,
+) -> Vec<RoleExpiration> {
     info!(
         "Looking for metadata expirations happening from now to {}",
         end_date
     );
-    if repo.root().signed.expires <= end_date {
-        expirations.insert(tough::schema::RoleType::Root, repo.root().signed.expires);
-    }
-    if repo.snapshot().signed.expires <= end_date {
-        expirations.insert(
+    [
+        (tough::schema::RoleType::Root, repo.root().signed.expires),
+        (
             tough::schema::RoleType::Snapshot,
             repo.snapshot().signed.expires,
-        );
-    }
-    if repo.targets().signed.expires <= end_date {
-        expirations.insert(
+        ),
+        (
             tough::schema::RoleType::Targets,
             repo.targets().signed.expires,
-        );
-    }
-    if repo.timestamp().signed.expires <= end_date {
-        expirations.insert(
+        ),
+        (
             tough::schema::RoleType::Timestamp,
             repo.timestamp().signed.expires,
-        );
-    }
+        ),
+    ]
+    .into_iter()
+    .map(|(role, expires)| role_expiration(role, expires, now, end_date, fail_date))
+    .collect()
+}
 
-    expirations
+/// Determines the expiration status of a role that expires at the given time.
+fn role_expiration(
+    role: tough::schema::RoleType,
+    expires: DateTime<Utc>,
+    now: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    fail_date: DateTime<Utc>,
+) -> RoleExpiration {
+    let status = if expires < now {
+        ExpirationStatus::Expired
+    } else if expires <= end_date {
+        ExpirationStatus::Expiring
+    } else {
+        ExpirationStatus::Ok
+    };
+    RoleExpiration {
+        role,
+        expires: expires.to_rfc3339(),
+        expires_at: expires,
+        status,
+        failing: expires <= fail_date,
+    }
 }
 
-fn check_expirations(
-    root_role_path: &PathBuf,
+/// Checks the metadata expirations of the repo at the given URLs.  Returns None if the repo
+/// doesn't exist.
+pub(crate) fn check_expirations(
+    root_role_path: &Path,
     metadata_url: &Url,
     targets_url: &Url,
     expiration_limit: DateTime<Utc>,
-) -> Result<()> {
+    fail_within: DateTime<Utc>,
+) -> Result<Option<RepoExpirations>> {
     // Load the repository
-    let repo = RepositoryLoader::new(
+    let repo = match RepositoryLoader::new(
         File::open(root_role_path).context(repo_error::FileSnafu {
             path: root_role_path,
         })?,
@@ -91,9 +267,13 @@ fn check_expirations(
     // We're gonna check the expiration ourselves
     .expiration_enforcement(ExpirationEnforcement::Unsafe)
     .load()
-    .context(repo_error::RepoLoadSnafu {
-        metadata_base_url: metadata_url.clone(),
-    })?;
+    {
+        Ok(repo) => repo,
+        Err(e) if is_file_not_found_error(&e) => return Ok(None),
+        Err(e) => Err(e).context(repo_error::RepoLoadSnafu {
+            metadata_base_url: metadata_url.clone(),
+        })?,
+    };
     info!("Loaded TUF repo:\t{}", metadata_url);
 
     info!("Root expiration:\t{}", repo.root().signed.expires);
@@ -101,85 +281,476 @@ fn check_expirations(
     info!("Targets expiration:\t{}", repo.targets().signed.expires);
     info!("Timestamp expiration:\t{}", repo.timestamp().signed.expires);
     // Check for upcoming metadata expirations if a timeframe is specified
-    let upcoming_expirations = find_upcoming_metadata_expiration(&repo, expiration_limit);
-    if !upcoming_expirations.is_empty() {
-        let now = Utc::now();
-        for (role, expiration_date) in upcoming_expirations {
-            if expiration_date < now {
-                error!(
-                    "Repo '{}': '{}' expired on {}",
-                    metadata_url, role, expiration_date
-                )
-            } else {
-                warn!(
-                    "Repo '{}': '{}' expiring in {} at {}",
+    let now = Utc::now();
+    let roles = find_upcoming_metadata_expiration(&repo, now, expiration_limit, fail_within);
+    for role in &roles {
+        match role.status {
+            ExpirationStatus::Expired => error!(
+                "Repo '{}': '{}' expired on {}",
+                metadata_url, role.role, role.expires
+            ),
+            ExpirationStatus::Expiring => warn!(
+                "Repo '{}': '{}' expiring at {}",
+                metadata_url, role.role, role.expires
+            ),
+            ExpirationStatus::Ok => {}
+        }
+    }
+
+    Ok(Some(RepoExpirations {
+        metadata_url: metadata_url.clone(),
+        roles,
+    }))
+}
+
+/// The expirations of one repo, variant, and architecture
+#[derive(Debug, Serialize)]
+pub struct RepoCheck {
+    pub repo: String,
+    pub variant: String,
+    pub arch: String,
+    #[serde(flatten)]
+    pub expirations: RepoExpirations,
+}
+
+#[derive(Tabled)]
+struct RepoCheckRow {
+    repo: String,
+    variant: String,
+    arch: String,
+    status: ExpirationStatus,
+    next_expiring_role: String,
+    expires: String,
+}
+
+impl From<&RepoCheck> for RepoCheckRow {
+    fn from(check: &RepoCheck) -> Self {
+        let roles = &check.expirations.roles;
+        let status = roles
+            .iter()
+            .map(|role| role.status)
+            .max()
+            .unwrap_or(ExpirationStatus::Ok);
+        let next = roles.iter().min_by_key(|role| role.expires_at);
+        Self {
+            repo: check.repo.clone(),
+            variant: check.variant.clone(),
+            arch: check.arch.clone(),
+            status,
+            next_expiring_role: next.map(|role| role.role.to_string()).unwrap_or_default(),
+            expires: next.map(|role| role.expires.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+/// A repo, variant, and architecture to check when checking all repos
+struct RepoToCheck {
+    repo: String,
+    variant: String,
+    arch: String,
+    root_role_path: PathBuf,
+    metadata_url: Url,
+    targets_url: Url,
+}
+
+/// Returns the names of the variants in the given directory, meaning the subdirectories that
+/// contain a Cargo.toml.
+pub(crate) fn find_variants(variants_dir: &Path) -> Result<Vec<String>> {
+    let mut variants = Vec::new();
+    for entry in fs::read_dir(variants_dir).context(error::ReadDirSnafu { path: variants_dir })? {
+        let entry = entry.context(error::ReadDirSnafu { path: variants_dir })?;
+        if entry.path().join("Cargo.toml").is_file() {
+            variants.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    variants.sort();
+    Ok(variants)
+}
+
+/// Checks the metadata expirations of every repo in Infra.toml, for every variant and
+/// architecture.  Repos that couldn't be checked are logged, and returned as a count.
+fn find_all_expirations(
+    infra_config: &InfraConfig,
+    variants_dir: &Path,
+    arches: &[String],
+    roles_dir: &Path,
+    expiration_limit: DateTime<Utc>,
+    fail_within: DateTime<Utc>,
+) -> Result<(Vec<RepoCheck>, usize)> {
+    let variants = find_variants(variants_dir)?;
+    let repos: BTreeMap<_, _> = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .iter()
+        .collect();
+
+    let mut to_check = Vec::new();
+    'repos: for (repo, repo_config) in repos {
+        let root_role_path = roles_dir.join(format!("{}.root.json", repo));
+        if !root_role_path.exists() {
+            warn!(
+                "Skipping repo '{}', no root role at {}",
+                repo,
+                root_role_path.display()
+            );
+            continue;
+        }
+        for variant in &variants {
+            for arch in arches {
+                let (metadata_url, targets_url) = match repo_urls(repo_config, variant, arch)? {
+                    Some(urls) => urls,
+                    None => {
+                        warn!("Skipping repo '{}', it has no metadata/targets URLs", repo);
+                        continue 'repos;
+                    }
+                };
+                to_check.push(RepoToCheck {
+                    repo: repo.clone(),
+                    variant: variant.clone(),
+                    arch: arch.clone(),
+                    root_role_path: root_role_path.clone(),
                     metadata_url,
-                    role,
-                    expiration_date - now,
-                    expiration_date
-                )
+                    targets_url: targets_url.clone(),
+                });
+            }
+        }
+    }
+
+    info!("Checking up to {} repos", to_check.len());
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(MAX_CONCURRENT_CHECKS)
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+    let results: Vec<(RepoToCheck, Result<Option<RepoExpirations>>)> = thread_pool.install(|| {
+        to_check
+            .into_par_iter()
+            .map(|repo| {
+                let result = check_expirations(
+                    &repo.root_role_path,
+                    &repo.metadata_url,
+                    &repo.targets_url,
+                    expiration_limit,
+                    fail_within,
+                );
+                (repo, result)
+            })
+            .collect()
+    });
+
+    let mut checks = Vec::new();
+    let mut errors = 0;
+    for (repo, result) in results {
+        match result {
+            Ok(Some(expirations)) => checks.push(RepoCheck {
+                repo: repo.repo,
+                variant: repo.variant,
+                arch: repo.arch,
+                expirations,
+            }),
+            Ok(None) => trace!("No repo at {}", repo.metadata_url),
+            Err(e) => {
+                error!(
+                    "Failed to check repo '{}' for {} {}: {}",
+                    repo.repo, repo.variant, repo.arch, e
+                );
+                errors += 1;
             }
         }
-        return Err(Error::RepoExpirations {
-            metadata_url: metadata_url.clone(),
-        });
     }
+    Ok((checks, errors))
+}
 
+/// Checks the metadata expirations of every repo in Infra.toml, for every variant and
+/// architecture, and prints a combined report.
+fn check_all_repos(
+    infra_config: &InfraConfig,
+    variants_dir: &Path,
+    arches: &[String],
+    roles_dir: &Path,
+    options: &CheckExpirationsOptions,
+    json: bool,
+) -> Result<()> {
+    let (checks, errors) = find_all_expirations(
+        infra_config,
+        variants_dir,
+        arches,
+        roles_dir,
+        options.expiration_limit,
+        options.fail_date(),
+    )?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&checks).context(error::SerializeSnafu)?
+        );
+    } else {
+        println!("{}", Table::new(checks.iter().map(RepoCheckRow::from)));
+    }
+
+    let failing = checks
+        .iter()
+        .filter(|check| check.expirations.failing())
+        .count();
+    ensure!(errors == 0, error::CheckFailuresSnafu { count: errors });
+    ensure!(
+        failing == 0,
+        error::AllRepoExpirationsSnafu { count: failing }
+    );
     Ok(())
 }
 
-/// Common entrypoint from main()
-pub(crate) fn run(args: &Args, check_expirations_args: &CheckExpirationsArgs) -> Result<()> {
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
-    trace!("Parsed infra config: {:?}", infra_config);
+/// Checks the metadata expirations of one repo, for one variant and architecture.
+fn find_repo_expirations(
+    infra_config: &InfraConfig,
+    repo: &str,
+    variant: &str,
+    arch: &str,
+    root_role_path: &Path,
+    expiration_limit: DateTime<Utc>,
+    fail_within: DateTime<Utc>,
+) -> Result<RepoExpirations> {
     let repo_config = infra_config
         .repo
         .as_ref()
         .context(repo_error::MissingConfigSnafu {
             missing: "repo section",
         })?
-        .get(&check_expirations_args.repo)
+        .get(repo)
         .with_context(|| repo_error::MissingConfigSnafu {
-            missing: format!("definition for repo {}", &check_expirations_args.repo),
+            missing: format!("definition for repo {}", repo),
         })?;
 
-    let repo_urls = repo_urls(
-        repo_config,
-        &check_expirations_args.variant,
-        &check_expirations_args.arch,
-    )?
-    .context(repo_error::MissingRepoUrlsSnafu {
-        repo: &check_expirations_args.repo,
-    })?;
+    let repo_urls = repo_urls(repo_config, variant, arch)?
+        .context(repo_error::MissingRepoUrlsSnafu { repo })?;
     check_expirations(
-        &check_expirations_args.root_role_path,
+        root_role_path,
         &repo_urls.0,
         repo_urls.1,
-        check_expirations_args.expiration_limit,
+        expiration_limit,
+        fail_within,
+    )?
+    .context(error::MissingRepoSnafu {
+        metadata_url: repo_urls.0.clone(),
+    })
+}
+
+/// Checks the metadata expirations of the repos in the options, returning the expirations of each
+/// repo found.  Expiring metadata isn't an error here; the `failing` field of each role says
+/// whether the check would fail.  Repos that can't be checked are an error.
+pub fn check(
+    infra_config: &InfraConfig,
+    options: &CheckExpirationsOptions,
+) -> Result<Vec<RepoCheck>> {
+    match &options.repos {
+        ReposToCheck::One {
+            repo,
+            variant,
+            arch,
+            root_role_path,
+        } => {
+            let expirations = find_repo_expirations(
+                infra_config,
+                repo,
+                variant,
+                arch,
+                root_role_path,
+                options.expiration_limit,
+                options.fail_date(),
+            )?;
+            Ok(vec![RepoCheck {
+                repo: repo.clone(),
+                variant: variant.clone(),
+                arch: arch.clone(),
+                expirations,
+            }])
+        }
+        ReposToCheck::All {
+            variants_dir,
+            arches,
+            roles_dir,
+        } => {
+            let (checks, errors) = find_all_expirations(
+                infra_config,
+                variants_dir,
+                arches,
+                roles_dir,
+                options.expiration_limit,
+                options.fail_date(),
+            )?;
+            ensure!(errors == 0, error::CheckFailuresSnafu { count: errors });
+            Ok(checks)
+        }
+    }
+}
+
+/// Returns the expirations found by the check as the JSON that `--json` prints, for callers that
+/// report them somewhere other than stdout, like `serve`.  Expiring metadata isn't an error here;
+/// the `failing` field of each role says whether the check would fail.
+pub fn json_summary(
+    infra_config: &InfraConfig,
+    options: &CheckExpirationsOptions,
+) -> Result<serde_json::Value> {
+    let checks = check(infra_config, options)?;
+    match options.repos {
+        // One repo is reported on its own, rather than as a list.
+        ReposToCheck::One { .. } => {
+            serde_json::to_value(&checks[0].expirations).context(error::SerializeSnafu)
+        }
+        ReposToCheck::All { .. } => serde_json::to_value(&checks).context(error::SerializeSnafu),
+    }
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, check_expirations_args: &CheckExpirationsArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let options = CheckExpirationsOptions::from(check_expirations_args);
+    let (repo, variant, arch, root_role_path) = match &options.repos {
+        ReposToCheck::One {
+            repo,
+            variant,
+            arch,
+            root_role_path,
+        } => (repo, variant, arch, root_role_path),
+        ReposToCheck::All {
+            variants_dir,
+            arches,
+            roles_dir,
+        } => {
+            return check_all_repos(
+                &infra_config,
+                variants_dir,
+                arches,
+                roles_dir,
+                &options,
+                check_expirations_args.json,
+            )
+        }
+    };
+    let expirations = find_repo_expirations(
+        &infra_config,
+        repo,
+        variant,
+        arch,
+        root_role_path,
+        options.expiration_limit,
+        options.fail_date(),
     )?;
 
+    if check_expirations_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&expirations).context(error::SerializeSnafu)?
+        );
+    }
+
+    ensure!(
+        !expirations.failing(),
+        error::RepoExpirationsSnafu {
+            metadata_url: expirations.metadata_url.clone(),
+        }
+    );
     Ok(())
 }
 
 mod error {
     use snafu::Snafu;
+    use std::path::PathBuf;
     use url::Url;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(context(false), display("{}", source))]
         Repo {
             #[snafu(source(from(crate::repo::Error, Box::new)))]
             source: Box<crate::repo::Error>,
         },
 
+        #[snafu(display("Found expiring/expired metadata in {} repos", count))]
+        AllRepoExpirations { count: usize },
+
+        #[snafu(display("Failed to check {} repos, see log above", count))]
+        CheckFailures { count: usize },
+
+        #[snafu(display("No repo found at '{}'", metadata_url))]
+        MissingRepo { metadata_url: Url },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Found expiring/expired metadata in '{}'", metadata_url))]
         RepoExpirations { metadata_url: Url },
+
+        #[snafu(display("Failed to serialize expirations to json: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to create thread pool: {}", source))]
+        ThreadPool { source: rayon::ThreadPoolBuildError },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 
 type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::AllRepoExpirations { .. } | Self::RepoExpirations { .. } => {
+                Some(Kind::Validation)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{role_expiration, ExpirationStatus};
+    use chrono::{Duration, Utc};
+    use tough::schema::RoleType;
+
+    #[test]
+    fn expiration_status() {
+        let now = Utc::now();
+        let end_date = now + Duration::days(7);
+        let fail_date = now + Duration::days(3);
+
+        let expired = role_expiration(
+            RoleType::Timestamp,
+            now - Duration::hours(1),
+            now,
+            end_date,
+            fail_date,
+        );
+        assert_eq!(expired.status, ExpirationStatus::Expired);
+        assert!(expired.failing);
+
+        let warning = role_expiration(
+            RoleType::Snapshot,
+            now + Duration::days(5),
+            now,
+            end_date,
+            fail_date,
+        );
+        assert_eq!(warning.status, ExpirationStatus::Expiring);
+        assert!(!warning.failing);
+
+        let ok = role_expiration(
+            RoleType::Root,
+            now + Duration::days(30),
+            now,
+            end_date,
+            fail_date,
+        );
+        assert_eq!(ok.status, ExpirationStatus::Ok);
+        assert!(!ok.failing);
+    }
+}
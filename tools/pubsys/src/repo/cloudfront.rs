@@ -0,0 +1,98 @@
+//! The cloudfront module invalidates files in the CloudFront distributions that serve a repo, so
+//! that edge caches stop serving old copies of metadata we've replaced.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::RateLimits;
+use crate::exit_code::{Classify, Kind};
+use aws_sdk_cloudfront::model::{InvalidationBatch, Paths};
+use aws_sdk_cloudfront::{Client as CloudFrontClient, Region};
+use chrono::Utc;
+use log::info;
+use pubsys_config::AwsConfig;
+use snafu::ResultExt;
+
+/// CloudFront is a global service whose API lives in us-east-1.
+const CLOUDFRONT_REGION: &str = "us-east-1";
+
+/// Invalidates the given paths, like `/aws-dev/x86_64/timestamp.json`, in each distribution.
+pub(crate) async fn invalidate(
+    aws: &AwsConfig,
+    rate_limits: &RateLimits,
+    distribution_ids: &[String],
+    paths: &[String],
+) -> Result<()> {
+    if distribution_ids.is_empty() || paths.is_empty() {
+        return Ok(());
+    }
+    let region = Region::new(CLOUDFRONT_REGION);
+    let client = CloudFrontClient::from_pubsys_config(
+        &build_client_config(&region, &region, aws).await,
+        aws,
+    );
+    // CloudFront uses the caller reference to recognize retries of the same request.
+    let caller_reference = format!("pubsys-{}", Utc::now().timestamp_millis());
+
+    for distribution_id in distribution_ids {
+        let output = rate_limits
+            .limited(
+                client
+                    .create_invalidation()
+                    .distribution_id(distribution_id)
+                    .invalidation_batch(
+                        InvalidationBatch::builder()
+                            .caller_reference(&caller_reference)
+                            .paths(
+                                Paths::builder()
+                                    .quantity(paths.len() as i32)
+                                    .set_items(Some(paths.to_vec()))
+                                    .build(),
+                            )
+                            .build(),
+                    )
+                    .send(),
+            )
+            .await
+            .context(error::CreateInvalidationSnafu { distribution_id })?;
+        info!(
+            "Invalidating {} paths in CloudFront distribution {} ({})",
+            paths.len(),
+            distribution_id,
+            output
+                .invalidation()
+                .and_then(|invalidation| invalidation.id())
+                .unwrap_or("unknown invalidation ID")
+        );
+    }
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_cloudfront::error::CreateInvalidationError;
+    use aws_sdk_cloudfront::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display(
+            "Failed to invalidate CloudFront distribution {}: {}",
+            distribution_id,
+            DisplayErrorContext(source)
+        ))]
+        CreateInvalidation {
+            distribution_id: String,
+            source: SdkError<CreateInvalidationError>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::CreateInvalidation { .. } => Some(Kind::AwsApi),
+        }
+    }
+}
@@ -0,0 +1,523 @@
+//! The diff_repo module owns the 'diff-repo' subcommand, which compares two versions of a TUF
+//! repo, like the published repo and a new one built locally, and reports what changed: targets
+//! added, removed, or changed, metadata version bumps, and updates added to or removed from the
+//! manifest.  This is the changelog of a repo update.
+
+use crate::repo::{error as repo_error, repo_urls};
+use crate::Args;
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tough::{Repository, RepositoryLoader};
+use update_metadata::Manifest;
+use url::Url;
+
+/// Compares two versions of a TUF repo
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct DiffRepoArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml as the old repo
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repos being compared
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repos being compared
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for the repos
+    root_role_path: PathBuf,
+
+    #[structopt(long, requires = "old-targets-url")]
+    /// Compare from the repo metadata at this URL, rather than the one in Infra.toml; useful for
+    /// comparing against an archived copy of the repo
+    old_metadata_url: Option<Url>,
+    #[structopt(long, requires = "old-metadata-url")]
+    /// Targets URL to go with --old-metadata-url
+    old_targets_url: Option<Url>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["new-metadata-url", "new-targets-url"],
+        required_unless = "new-metadata-url"
+    )]
+    /// Compare to the repo built into this directory, i.e. the `--outdir` of the `repo` subcommand
+    local_repo_dir: Option<PathBuf>,
+
+    #[structopt(long, requires = "new-targets-url")]
+    /// Compare to the repo metadata at this URL
+    new_metadata_url: Option<Url>,
+    #[structopt(long, requires = "new-metadata-url")]
+    /// Targets URL to go with --new-metadata-url
+    new_targets_url: Option<Url>,
+
+    #[structopt(long)]
+    /// Print the differences as a JSON object instead of plain text
+    json: bool,
+}
+
+/// The length and sha256 of a target
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TargetSummary {
+    pub(crate) length: u64,
+    /// Hex-encoded
+    pub(crate) sha256: String,
+}
+
+/// A target whose contents differ between the repos
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct TargetChange {
+    pub(crate) old: TargetSummary,
+    pub(crate) new: TargetSummary,
+}
+
+/// The versions of a metadata role in each repo; a delegated role may be in only one of them
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct RoleChange {
+    pub(crate) role: String,
+    pub(crate) old_version: Option<NonZeroU64>,
+    pub(crate) new_version: Option<NonZeroU64>,
+}
+
+/// Everything that differs between two repos
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct RepoDiff {
+    pub(crate) roles: Vec<RoleChange>,
+    pub(crate) added_targets: BTreeMap<String, TargetSummary>,
+    pub(crate) removed_targets: BTreeMap<String, TargetSummary>,
+    pub(crate) changed_targets: BTreeMap<String, TargetChange>,
+    /// Updates in the new manifest, but not the old, like "aws-k8s-1.24 x86_64 1.13.0"
+    pub(crate) added_updates: Vec<String>,
+    /// Updates in the old manifest, but not the new
+    pub(crate) removed_updates: Vec<String>,
+}
+
+impl RepoDiff {
+    fn is_empty(&self) -> bool {
+        self.roles
+            .iter()
+            .all(|role| role.old_version == role.new_version)
+            && self.added_targets.is_empty()
+            && self.removed_targets.is_empty()
+            && self.changed_targets.is_empty()
+            && self.added_updates.is_empty()
+            && self.removed_updates.is_empty()
+    }
+}
+
+/// Returns the version of each metadata role in the repo, including delegated roles.
+pub(crate) fn role_versions(repo: &Repository) -> BTreeMap<String, NonZeroU64> {
+    let mut versions = BTreeMap::new();
+    versions.insert("root".to_string(), repo.root().signed.version);
+    versions.insert("snapshot".to_string(), repo.snapshot().signed.version);
+    versions.insert("targets".to_string(), repo.targets().signed.version);
+    versions.insert("timestamp".to_string(), repo.timestamp().signed.version);
+    let mut delegators = vec![repo.targets()];
+    while let Some(targets) = delegators.pop() {
+        for role in targets
+            .signed
+            .delegations
+            .iter()
+            .flat_map(|delegations| &delegations.roles)
+        {
+            if let Some(role_targets) = role.targets.as_ref() {
+                versions.insert(role.name.clone(), role_targets.signed.version);
+                delegators.push(role_targets);
+            }
+        }
+    }
+    versions
+}
+
+/// Returns the length and sha256 of each target in the repo, including delegated targets.
+pub(crate) fn target_summaries(repo: &Repository) -> BTreeMap<String, TargetSummary> {
+    repo.all_targets()
+        .map(|(name, target)| {
+            (
+                name.raw().to_string(),
+                TargetSummary {
+                    length: target.length,
+                    sha256: hex::encode(&target.hashes.sha256),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Returns a short description of each update in the manifest.
+fn update_names(manifest: &Manifest) -> BTreeSet<String> {
+    manifest
+        .updates
+        .iter()
+        .map(|update| format!("{} {} {}", update.variant, update.arch, update.version))
+        .collect()
+}
+
+/// Pairs up the versions of each role found in either repo, so the report shows every role.
+fn diff_roles(
+    old: &BTreeMap<String, NonZeroU64>,
+    new: &BTreeMap<String, NonZeroU64>,
+) -> Vec<RoleChange> {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    names
+        .into_iter()
+        .map(|name| RoleChange {
+            role: name.clone(),
+            old_version: old.get(name).copied(),
+            new_version: new.get(name).copied(),
+        })
+        .collect()
+}
+
+/// Compares the given targets, metadata versions, and manifest updates.
+fn diff(
+    old_roles: &BTreeMap<String, NonZeroU64>,
+    new_roles: &BTreeMap<String, NonZeroU64>,
+    old_targets: &BTreeMap<String, TargetSummary>,
+    new_targets: &BTreeMap<String, TargetSummary>,
+    old_updates: &BTreeSet<String>,
+    new_updates: &BTreeSet<String>,
+) -> RepoDiff {
+    let mut repo_diff = RepoDiff {
+        roles: diff_roles(old_roles, new_roles),
+        added_updates: new_updates.difference(old_updates).cloned().collect(),
+        removed_updates: old_updates.difference(new_updates).cloned().collect(),
+        ..Default::default()
+    };
+    for (name, old) in old_targets {
+        match new_targets.get(name) {
+            None => {
+                repo_diff.removed_targets.insert(name.clone(), old.clone());
+            }
+            Some(new) if new != old => {
+                repo_diff.changed_targets.insert(
+                    name.clone(),
+                    TargetChange {
+                        old: old.clone(),
+                        new: new.clone(),
+                    },
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, new) in new_targets {
+        if !old_targets.contains_key(name) {
+            repo_diff.added_targets.insert(name.clone(), new.clone());
+        }
+    }
+    repo_diff
+}
+
+/// Loads the repo at the given URLs, and its manifest.
+pub(crate) fn load_repo(
+    root_role_path: &Path,
+    metadata_url: &Url,
+    targets_url: &Url,
+) -> Result<(Repository, Manifest)> {
+    let repo = RepositoryLoader::new(
+        File::open(root_role_path).context(repo_error::FileSnafu {
+            path: root_role_path,
+        })?,
+        metadata_url.clone(),
+        targets_url.clone(),
+    )
+    .load()
+    .context(repo_error::RepoLoadSnafu {
+        metadata_base_url: metadata_url.clone(),
+    })?;
+    info!("Loaded TUF repo: {}", metadata_url);
+
+    let target = "manifest.json";
+    let target = target
+        .try_into()
+        .context(repo_error::ParseTargetNameSnafu { target })?;
+    let manifest = match repo
+        .read_target(&target)
+        .context(repo_error::ReadTargetSnafu {
+            target: target.raw(),
+        })? {
+        Some(reader) => serde_json::from_reader(reader).context(repo_error::InvalidJsonSnafu {
+            path: "manifest.json",
+        })?,
+        None => Manifest::default(),
+    };
+    Ok((repo, manifest))
+}
+
+/// Returns the metadata and targets URLs of a repo written to the given directory by the `repo`
+/// subcommand.
+fn local_repo_urls(local_repo_dir: &Path, variant: &str, arch: &str) -> Result<(Url, Url)> {
+    let dir = local_repo_dir
+        .canonicalize()
+        .context(error::LocalRepoSnafu {
+            path: local_repo_dir,
+        })?;
+    let url = |path: PathBuf| {
+        Url::from_directory_path(&path)
+            .ok()
+            .context(error::LocalRepoUrlSnafu { path })
+    };
+    Ok((
+        url(dir.join(variant).join(arch))?,
+        url(dir.join("targets"))?,
+    ))
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, diff_args: &DiffRepoArgs) -> Result<()> {
+    let (old_metadata_url, old_targets_url) = match (
+        diff_args.old_metadata_url.as_ref(),
+        diff_args.old_targets_url.as_ref(),
+    ) {
+        (Some(metadata_url), Some(targets_url)) => (metadata_url.clone(), targets_url.clone()),
+        _ => {
+            // If a lock file exists, use that, otherwise use Infra.toml
+            let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+            trace!("Parsed infra config: {:?}", infra_config);
+            let repo_config = infra_config
+                .repo
+                .as_ref()
+                .context(repo_error::MissingConfigSnafu {
+                    missing: "repo section",
+                })?
+                .get(&diff_args.repo)
+                .context(repo_error::MissingConfigSnafu {
+                    missing: format!("definition for repo {}", &diff_args.repo),
+                })?;
+            let (metadata_url, targets_url) =
+                repo_urls(repo_config, &diff_args.variant, &diff_args.arch)?.context(
+                    repo_error::MissingRepoUrlsSnafu {
+                        repo: &diff_args.repo,
+                    },
+                )?;
+            (metadata_url, targets_url.clone())
+        }
+    };
+    let (new_metadata_url, new_targets_url) = match (
+        diff_args.local_repo_dir.as_ref(),
+        diff_args.new_metadata_url.as_ref(),
+        diff_args.new_targets_url.as_ref(),
+    ) {
+        (Some(local_repo_dir), _, _) => {
+            local_repo_urls(local_repo_dir, &diff_args.variant, &diff_args.arch)?
+        }
+        (None, Some(metadata_url), Some(targets_url)) => {
+            (metadata_url.clone(), targets_url.clone())
+        }
+        _ => return error::MissingNewRepoSnafu.fail(),
+    };
+
+    let (old_repo, old_manifest) = load_repo(
+        &diff_args.root_role_path,
+        &old_metadata_url,
+        &old_targets_url,
+    )?;
+    let (new_repo, new_manifest) = load_repo(
+        &diff_args.root_role_path,
+        &new_metadata_url,
+        &new_targets_url,
+    )?;
+    let repo_diff = diff(
+        &role_versions(&old_repo),
+        &role_versions(&new_repo),
+        &target_summaries(&old_repo),
+        &target_summaries(&new_repo),
+        &update_names(&old_manifest),
+        &update_names(&new_manifest),
+    );
+
+    if diff_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&repo_diff).context(error::SerializeDiffSnafu)?
+        );
+    } else {
+        print!("{}", repo_diff);
+    }
+    Ok(())
+}
+
+fn version_string(version: Option<NonZeroU64>) -> String {
+    version
+        .map(|version| version.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl Display for RepoDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "The repos are the same");
+        }
+        writeln!(f, "Metadata versions:")?;
+        for role in &self.roles {
+            let marker = if role.old_version == role.new_version {
+                ' '
+            } else {
+                '*'
+            };
+            writeln!(
+                f,
+                "{} {}: {} -> {}",
+                marker,
+                role.role,
+                version_string(role.old_version),
+                version_string(role.new_version)
+            )?;
+        }
+        if !self.added_updates.is_empty() || !self.removed_updates.is_empty() {
+            writeln!(f, "Updates:")?;
+            for update in &self.added_updates {
+                writeln!(f, "+ {}", update)?;
+            }
+            for update in &self.removed_updates {
+                writeln!(f, "- {}", update)?;
+            }
+        }
+        if !self.added_targets.is_empty()
+            || !self.removed_targets.is_empty()
+            || !self.changed_targets.is_empty()
+        {
+            writeln!(f, "Targets:")?;
+            for (name, target) in &self.added_targets {
+                writeln!(f, "+ {} ({} bytes)", name, target.length)?;
+            }
+            for (name, target) in &self.removed_targets {
+                writeln!(f, "- {} ({} bytes)", name, target.length)?;
+            }
+            for (name, change) in &self.changed_targets {
+                writeln!(
+                    f,
+                    "* {} ({} bytes -> {} bytes)",
+                    name, change.old.length, change.new.length
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to find local repo '{}': {}", path.display(), source))]
+        LocalRepo {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to build a file URL from '{}'", path.display()))]
+        LocalRepoUrl { path: PathBuf },
+
+        #[snafu(display("Either --local-repo-dir or --new-metadata-url is required"))]
+        MissingNewRepo,
+
+        #[snafu(context(false), display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to serialize repo differences to json: {}", source))]
+        SerializeDiff { source: serde_json::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{diff, TargetChange, TargetSummary};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::num::NonZeroU64;
+
+    fn target(length: u64, sha256: &str) -> TargetSummary {
+        TargetSummary {
+            length,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    fn versions(roles: &[(&str, u64)]) -> BTreeMap<String, NonZeroU64> {
+        roles
+            .iter()
+            .map(|(role, version)| (role.to_string(), NonZeroU64::new(*version).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn diffs_repos() {
+        let old_targets: BTreeMap<String, TargetSummary> = vec![
+            ("a.img".to_string(), target(1, "aa")),
+            ("b.img".to_string(), target(2, "bb")),
+            ("c.img".to_string(), target(3, "cc")),
+        ]
+        .into_iter()
+        .collect();
+        let new_targets: BTreeMap<String, TargetSummary> = vec![
+            ("a.img".to_string(), target(1, "aa")),
+            ("b.img".to_string(), target(4, "dd")),
+            ("e.img".to_string(), target(5, "ee")),
+        ]
+        .into_iter()
+        .collect();
+        let old_updates: BTreeSet<String> =
+            vec!["dev x86_64 1.0.0".to_string()].into_iter().collect();
+        let new_updates: BTreeSet<String> = vec![
+            "dev x86_64 1.0.0".to_string(),
+            "dev x86_64 1.1.0".to_string(),
+        ]
+        .into_iter()
+        .collect();
+
+        let repo_diff = diff(
+            &versions(&[("root", 1), ("targets", 5), ("my-kit", 2)]),
+            &versions(&[("root", 1), ("targets", 6)]),
+            &old_targets,
+            &new_targets,
+            &old_updates,
+            &new_updates,
+        );
+
+        assert_eq!(
+            repo_diff.added_targets.keys().collect::<Vec<_>>(),
+            ["e.img"]
+        );
+        assert_eq!(
+            repo_diff.removed_targets.keys().collect::<Vec<_>>(),
+            ["c.img"]
+        );
+        assert_eq!(
+            repo_diff.changed_targets.get("b.img"),
+            Some(&TargetChange {
+                old: target(2, "bb"),
+                new: target(4, "dd"),
+            })
+        );
+        assert_eq!(repo_diff.changed_targets.len(), 1);
+        assert_eq!(repo_diff.added_updates, ["dev x86_64 1.1.0"]);
+        assert!(repo_diff.removed_updates.is_empty());
+
+        let kit = repo_diff
+            .roles
+            .iter()
+            .find(|role| role.role == "my-kit")
+            .unwrap();
+        assert_eq!(kit.new_version, None);
+        assert!(!repo_diff.is_empty());
+    }
+}
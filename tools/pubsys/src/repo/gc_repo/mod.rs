@@ -0,0 +1,393 @@
+//! The gc_repo module owns the 'gc-repo' subcommand, which finds the targets in a repo's S3 bucket
+//! that no metadata we're keeping refers to, and deletes them.
+//!
+//! The targets directory is shared by every variant and architecture in the bucket, so we look
+//! at the metadata of all of them.  A version of a targets role (including delegated roles) is
+//! kept if it hasn't expired, or if it's one of the latest `--keep-latest` versions.
+//!
+//! Targets flagged by `pubsys eol` are removed even if kept metadata still refers to them.
+//!
+//! Targets written within `--min-age` are never removed, since targets are uploaded before the
+//! metadata that refers to them, and a repo may be partway through being published.
+
+use crate::aws::rate_limit::RateLimits;
+use crate::repo::s3::{self, RepoBucket, RepoClient, RepoObject};
+use crate::Args;
+use aws_sdk_s3::types::SdkError;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info, trace, warn};
+use parse_datetime::parse_offset;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, HashSet};
+use std::num::NonZeroUsize;
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+use tough::schema::{Signed, Targets};
+
+/// Lists the targets of end-of-life versions, mapped to the version, so the next GC removes them
+const EOL_TARGETS: &str = "eol-targets.json";
+
+/// Deletes targets that no kept repo metadata refers to
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct GcRepoArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long, default_value = "1")]
+    /// Keep the targets of this many of the latest versions of each targets role, even if they've
+    /// expired
+    keep_latest: NonZeroUsize,
+
+    #[structopt(long, default_value = "3 days", parse(try_from_str = parse_offset))]
+    /// Keep unreferenced targets written more recently than this, like "7 days", since a repo
+    /// being published may not refer to them yet
+    min_age: Duration,
+
+    #[structopt(long)]
+    /// Delete the unreferenced targets; otherwise, they're only listed
+    delete: bool,
+}
+
+/// A versioned metadata file for a targets role, like `aws-k8s-1.24/x86_64/1234.targets.json`
+#[derive(Debug, PartialEq, Eq)]
+struct TargetsMetadata<'a> {
+    /// The variant and arch directory, like `aws-k8s-1.24/x86_64`
+    dir: &'a str,
+    role: &'a str,
+    version: u64,
+}
+
+/// Parses the path of a targets role's metadata, returning None for other files.
+fn parse_metadata_path(path: &str) -> Option<TargetsMetadata<'_>> {
+    let (dir, filename) = path.rsplit_once('/')?;
+    if dir.starts_with("targets/") || dir.matches('/').count() != 1 {
+        return None;
+    }
+    let (version, role) = filename.strip_suffix(".json")?.split_once('.')?;
+    // Root and snapshot are versioned too, but don't list targets.
+    if role == "root" || role == "snapshot" {
+        return None;
+    }
+    Some(TargetsMetadata {
+        dir,
+        role,
+        version: version.parse().ok()?,
+    })
+}
+
+/// Returns the path at which a repo with consistent snapshots stores the target with the given
+/// name and sha256.
+fn consistent_target_path(name: &str, sha256: &str) -> String {
+    match name.rsplit_once('/') {
+        Some((dir, filename)) => format!("targets/{}/{}.{}", dir, sha256, filename),
+        None => format!("targets/{}.{}", sha256, name),
+    }
+}
+
+/// Returns whether the target was written before the cutoff.  Targets S3 didn't give a time for
+/// are treated as new, so they're kept.
+fn written_before(object: &RepoObject, cutoff: DateTime<Utc>) -> bool {
+    object
+        .last_modified
+        .map_or(false, |last_modified| last_modified < cutoff)
+}
+
+/// Returns the targets in the bucket that aren't in the given set of referenced paths.
+fn unreferenced<'a>(
+    targets: &'a [RepoObject],
+    referenced: &HashSet<String>,
+) -> Vec<&'a RepoObject> {
+    targets
+        .iter()
+        .filter(|object| !referenced.contains(&object.path))
+        .collect()
+}
+
+/// Collects the paths of the targets referred to by the metadata we're keeping.
+async fn referenced_targets(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    metadata: &[RepoObject],
+    keep_latest: NonZeroUsize,
+) -> Result<HashSet<String>> {
+    // Group the versions of each role, newest first.
+    let mut roles: BTreeMap<(&str, &str), Vec<(u64, &str)>> = BTreeMap::new();
+    for object in metadata {
+        if let Some(parsed) = parse_metadata_path(&object.path) {
+            roles
+                .entry((parsed.dir, parsed.role))
+                .or_default()
+                .push((parsed.version, &object.path));
+        }
+    }
+    ensure!(!roles.is_empty(), error::NoMetadataSnafu);
+
+    let now = Utc::now();
+    let mut referenced = HashSet::new();
+    for ((dir, role), mut versions) in roles {
+        versions.sort_unstable_by(|a, b| b.cmp(a));
+        let mut kept = 0;
+        for (index, (version, path)) in versions.into_iter().enumerate() {
+            let data = s3::get_object(client, bucket, path)
+                .await
+                .context(error::S3Snafu)?;
+            let targets: Signed<Targets> =
+                serde_json::from_slice(&data).context(error::ParseMetadataSnafu { path })?;
+            if index >= keep_latest.get() && targets.signed.expires < now {
+                trace!(
+                    "Not keeping expired {} version {} in {}",
+                    role,
+                    version,
+                    dir
+                );
+                continue;
+            }
+            kept += 1;
+            for (name, target) in &targets.signed.targets {
+                let sha256 = hex::encode(&target.hashes.sha256);
+                referenced.insert(consistent_target_path(name.raw(), &sha256));
+                referenced.insert(format!("targets/{}", name.raw()));
+            }
+        }
+        debug!("Keeping {} versions of {} in {}", kept, role, dir);
+    }
+    Ok(referenced)
+}
+
+/// Returns the targets flagged by `pubsys eol`, mapped to the version each was flagged with.
+pub(crate) async fn flagged_targets(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+) -> Result<BTreeMap<String, String>> {
+    let data = match s3::get_object(client, bucket, EOL_TARGETS).await {
+        Ok(data) => data,
+        Err(s3::Error::GetObject {
+            source: SdkError::ServiceError(service_error),
+            ..
+        }) if service_error.err().code() == Some("NoSuchKey") => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).context(error::S3Snafu),
+    };
+    serde_json::from_slice(&data).context(error::ParseMetadataSnafu { path: EOL_TARGETS })
+}
+
+/// Flags the given targets of an end-of-life version, so the next GC removes them.
+pub(crate) async fn flag_targets(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    paths: &[String],
+    version: &str,
+) -> Result<()> {
+    let mut flagged = flagged_targets(client, bucket).await?;
+    for path in paths {
+        flagged.insert(path.clone(), version.to_string());
+    }
+    let data = serde_json::to_vec_pretty(&flagged).context(error::SerializeFlagsSnafu)?;
+    s3::put_object(client, bucket, EOL_TARGETS, data)
+        .await
+        .context(error::S3Snafu)
+}
+
+/// Lists the bucket's targets that no kept metadata refers to, keeping the metadata of the latest
+/// `keep_latest` versions of each targets role, and any target written within `min_age`.  Also
+/// returns how many targets the bucket has.
+pub(crate) async fn find_unreferenced(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    keep_latest: NonZeroUsize,
+    min_age: Duration,
+) -> Result<(Vec<RepoObject>, usize)> {
+    let (targets, metadata): (Vec<RepoObject>, Vec<RepoObject>) =
+        s3::list_objects(client, bucket, "")
+            .await
+            .context(error::S3Snafu)?
+            .into_iter()
+            .partition(|object| object.path.starts_with("targets/"));
+
+    let mut referenced = referenced_targets(client, bucket, &metadata, keep_latest).await?;
+    for (path, version) in flagged_targets(client, bucket).await? {
+        if referenced.remove(&path) {
+            warn!(
+                "Removing {}, since {} is end-of-life, though kept metadata refers to it",
+                path, version
+            );
+        }
+    }
+    let cutoff = Utc::now() - min_age;
+    let unreferenced = unreferenced(&targets, &referenced)
+        .into_iter()
+        .filter(|object| {
+            let old_enough = written_before(object, cutoff);
+            if !old_enough {
+                debug!("Keeping {}, which was written too recently", object.path);
+            }
+            old_enough
+        })
+        .cloned()
+        .collect();
+    Ok((unreferenced, targets.len()))
+}
+
+async fn gc_repo(args: &Args, gc_args: &GcRepoArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let bucket = RepoBucket::from_config(&infra_config, &gc_args.repo).context(error::S3Snafu)?;
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let client = bucket.client(&aws, &RateLimits::new(&aws)).await;
+
+    info!(
+        "Listing the files of repo '{}' in {}",
+        gc_args.repo, bucket.name
+    );
+    let (unreferenced, target_count) =
+        find_unreferenced(&client, &bucket, gc_args.keep_latest, gc_args.min_age).await?;
+    let size: i64 = unreferenced.iter().map(|object| object.size).sum();
+    for object in &unreferenced {
+        info!("Unreferenced: {} ({} bytes)", object.path, object.size);
+    }
+    info!(
+        "{} of {} targets are unreferenced, totaling {} bytes",
+        unreferenced.len(),
+        target_count,
+        size
+    );
+
+    if args.dry_run {
+        info!("Dry run; not deleting anything");
+        return Ok(());
+    }
+    if !gc_args.delete {
+        info!("Not deleting anything; pass --delete to remove the unreferenced targets");
+        return Ok(());
+    }
+    let paths: Vec<String> = unreferenced
+        .into_iter()
+        .map(|object| object.path.clone())
+        .collect();
+    s3::delete_objects(&client, &bucket, &paths)
+        .await
+        .context(error::S3Snafu)?;
+    info!("Deleted {} targets", paths.len());
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, gc_args: &GcRepoArgs) -> Result<()> {
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    rt.block_on(gc_repo(args, gc_args))
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Found no targets metadata in the bucket, refusing to delete anything"))]
+        NoMetadata,
+
+        #[snafu(display("Failed to parse metadata '{}': {}", path, source))]
+        ParseMetadata {
+            path: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display("Failed to serialize flagged targets: {}", source))]
+        SerializeFlags { source: serde_json::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{
+        consistent_target_path, parse_metadata_path, unreferenced, written_before, TargetsMetadata,
+    };
+    use crate::repo::s3::RepoObject;
+    use chrono::{Duration, Utc};
+    use std::collections::HashSet;
+
+    #[test]
+    fn metadata_paths() {
+        assert_eq!(
+            parse_metadata_path("aws-dev/x86_64/1234.targets.json"),
+            Some(TargetsMetadata {
+                dir: "aws-dev/x86_64",
+                role: "targets",
+                version: 1234,
+            })
+        );
+        assert_eq!(
+            parse_metadata_path("aws-dev/x86_64/7.my-kit.json"),
+            Some(TargetsMetadata {
+                dir: "aws-dev/x86_64",
+                role: "my-kit",
+                version: 7,
+            })
+        );
+        assert_eq!(
+            parse_metadata_path("aws-dev/x86_64/1234.snapshot.json"),
+            None
+        );
+        assert_eq!(parse_metadata_path("aws-dev/x86_64/timestamp.json"), None);
+        assert_eq!(parse_metadata_path("targets/abc.1.targets.json"), None);
+        assert_eq!(parse_metadata_path("root.json"), None);
+    }
+
+    #[test]
+    fn target_paths() {
+        assert_eq!(
+            consistent_target_path("a.img", "ab12"),
+            "targets/ab12.a.img"
+        );
+        assert_eq!(
+            consistent_target_path("my-kit/a.rpm", "ab12"),
+            "targets/my-kit/ab12.a.rpm"
+        );
+    }
+
+    #[test]
+    fn finds_unreferenced() {
+        let object = |path: &str| RepoObject {
+            path: path.to_string(),
+            size: 1,
+            last_modified: None,
+        };
+        let targets = vec![object("targets/ab.a.img"), object("targets/cd.b.img")];
+        let referenced: HashSet<String> =
+            vec!["targets/ab.a.img".to_string()].into_iter().collect();
+        assert_eq!(unreferenced(&targets, &referenced), vec![&targets[1]]);
+    }
+
+    #[test]
+    fn keeps_new_targets() {
+        let now = Utc::now();
+        let object = |last_modified| RepoObject {
+            path: "targets/ab.a.img".to_string(),
+            size: 1,
+            last_modified,
+        };
+        let cutoff = now - Duration::days(3);
+        assert!(written_before(
+            &object(Some(now - Duration::days(4))),
+            cutoff
+        ));
+        assert!(!written_before(
+            &object(Some(now - Duration::days(1))),
+            cutoff
+        ));
+        assert!(!written_before(&object(None), cutoff));
+    }
+}
@@ -0,0 +1,588 @@
+//! The offline_signing module owns signing repos on another machine, like an air-gapped one.
+//!
+//! `repo --emit-unsigned` writes a signing request: the canonical JSON of the targets and snapshot
+//! roles, plus any metadata that's already signed.  The roles are signed elsewhere, and the
+//! 'attach-repo-signatures' subcommand verifies the detached signatures against root.json and
+//! writes out the finished metadata.
+//!
+//! Since targets.json changes once its signatures are attached, the snapshot role in the request
+//! refers to it by version only, rather than by hash, so both roles can be signed in one pass.
+//! The timestamp role has to include the hash of the signed snapshot, so it's signed when the
+//! signatures are attached, with the repo's signing key from Infra.toml.  (TUF expects the
+//! timestamp key to be kept online anyway, since timestamp.json is re-signed most often.)
+
+use crate::repo::{error as repo_error, get_signing_key_source};
+use crate::Args;
+use log::{debug, info, trace};
+use olpc_cjson::CanonicalFormatter;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tough::editor::signed::{SignedRepository, SignedRole};
+use tough::key_source::{KeySource, LocalKeySource};
+use tough::schema::decoded::{Decoded, Hex};
+use tough::schema::key::Key;
+use tough::schema::{
+    Hashes, KeyHolder, Role, RoleType, Root, Signature, Signed, Snapshot, Targets, Timestamp,
+};
+use tough::sign::Sign;
+
+const REQUEST_FILE: &str = "signing-request.json";
+const TIMESTAMP_FILE: &str = "timestamp.json";
+const UNSIGNED_DIR: &str = "unsigned";
+const SIGNED_DIR: &str = "signed";
+
+/// Attaches signatures made offline to the metadata in a signing request
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct AttachSignaturesArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml; its signing key signs timestamp.json
+    repo: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The directory written by `repo --emit-unsigned`
+    request_dir: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// JSON file mapping each role name to its list of TUF signatures, like
+    /// {"targets": [{"keyid": "...", "sig": "..."}]}
+    signatures_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo; the signatures are checked against it
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// If we generated a local key, we'll find it here; used if Infra.toml has no key defined
+    default_key_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Where to store the signed repo metadata
+    outdir: PathBuf,
+}
+
+/// Describes what needs to be signed to finish a repo
+#[derive(Debug, Deserialize, Serialize)]
+struct SigningRequest {
+    variant: String,
+    arch: String,
+    roles: Vec<RoleRequest>,
+    /// The unsigned timestamp role, relative to the request directory; it's completed and signed
+    /// once the snapshot role is signed
+    timestamp: PathBuf,
+}
+
+/// Describes one role that needs to be signed
+#[derive(Debug, Deserialize, Serialize)]
+struct RoleRequest {
+    role: RoleType,
+    /// The canonical JSON to sign, relative to the request directory
+    payload: PathBuf,
+    /// Hex-encoded sha256 of the payload
+    sha256: String,
+    /// The keys that may sign the role, of which `threshold` must
+    key_ids: Vec<Decoded<Hex>>,
+    threshold: NonZeroU64,
+    /// The file name the signed role is written to
+    filename: String,
+}
+
+/// A key source that knows the public key from root.json but can't sign; it lets us use the
+/// repo editor to build metadata that will be signed somewhere else.
+#[derive(Debug)]
+struct PlaceholderKeySource {
+    key: Key,
+}
+
+impl KeySource for PlaceholderKeySource {
+    fn as_sign(
+        &self,
+    ) -> std::result::Result<Box<dyn Sign>, Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        Ok(Box::new(PlaceholderSigner {
+            key: self.key.clone(),
+        }))
+    }
+
+    fn write(
+        &self,
+        _value: &str,
+        _key_id_hex: &str,
+    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(())
+    }
+}
+
+struct PlaceholderSigner {
+    key: Key,
+}
+
+impl Sign for PlaceholderSigner {
+    fn tuf_key(&self) -> Key {
+        self.key.clone()
+    }
+
+    // The signature is thrown away when the signing request is written.
+    fn sign(
+        &self,
+        _msg: &[u8],
+        _rng: &dyn SecureRandom,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Returns placeholder key sources for the keys that root.json trusts to sign the targets,
+/// snapshot, and timestamp roles, so the repo editor can build metadata without a private key.
+pub(crate) fn placeholder_keys(root_role_path: &Path) -> Result<Vec<Box<dyn KeySource>>> {
+    let root = read_root(root_role_path)?;
+    let mut key_ids = Vec::new();
+    for role in [RoleType::Targets, RoleType::Snapshot, RoleType::Timestamp] {
+        let role_keys = root
+            .signed
+            .roles
+            .get(&role)
+            .context(error::MissingRoleSnafu { role })?;
+        for key_id in &role_keys.keyids {
+            if !key_ids.contains(key_id) {
+                key_ids.push(key_id.clone());
+            }
+        }
+    }
+
+    let mut keys: Vec<Box<dyn KeySource>> = Vec::with_capacity(key_ids.len());
+    for key_id in key_ids {
+        let key = root
+            .signed
+            .keys
+            .get(&key_id)
+            .context(error::MissingKeySnafu {
+                key_id: hex::encode(&key_id),
+            })?;
+        keys.push(Box::new(PlaceholderKeySource { key: key.clone() }));
+    }
+    Ok(keys)
+}
+
+/// Writes a signing request for the given repo, which must have been signed with the keys from
+/// `placeholder_keys`, to the given directory.
+pub(crate) fn write_signing_request(
+    signed_repo: &SignedRepository,
+    root_role_path: &Path,
+    variant: &str,
+    arch: &str,
+    request_dir: &Path,
+) -> Result<()> {
+    ensure!(
+        !request_dir.exists(),
+        repo_error::RepoExistsSnafu { path: request_dir }
+    );
+    let root = read_root(root_role_path)?;
+
+    // Let tough lay out the metadata files as usual, then sort them into the ones we need signed
+    // and the ones that are already signed, like root.json and delegated roles.
+    let metadata_dir = tempfile::tempdir().context(repo_error::TempFileSnafu)?;
+    signed_repo
+        .write(metadata_dir.path())
+        .context(repo_error::RepoWriteSnafu {
+            path: metadata_dir.path(),
+        })?;
+
+    let unsigned_dir = request_dir.join(UNSIGNED_DIR);
+    let signed_dir = request_dir.join(SIGNED_DIR);
+    for dir in [&unsigned_dir, &signed_dir] {
+        fs::create_dir_all(dir).context(repo_error::CreateDirSnafu { path: dir })?;
+    }
+
+    let mut roles = Vec::new();
+    let mut timestamp = None;
+    for entry in fs::read_dir(metadata_dir.path()).context(error::ReadDirSnafu {
+        path: metadata_dir.path(),
+    })? {
+        let path = entry
+            .context(error::ReadDirSnafu {
+                path: metadata_dir.path(),
+            })?
+            .path();
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(repo_error::NonUtf8PathSnafu { path: &path })?
+            .to_string();
+
+        let (role, payload) = if is_role_file(&filename, "targets") {
+            let targets: Signed<Targets> = read_json(&path)?;
+            (RoleType::Targets, canonical_json(&targets.signed)?)
+        } else if is_role_file(&filename, "snapshot") {
+            let mut snapshot: Signed<Snapshot> = read_json(&path)?;
+            if let Some(meta) = snapshot.signed.meta.get_mut("targets.json") {
+                meta.hashes = None;
+                meta.length = None;
+            }
+            (RoleType::Snapshot, canonical_json(&snapshot.signed)?)
+        } else if filename == TIMESTAMP_FILE {
+            let unsigned: Signed<Timestamp> = read_json(&path)?;
+            timestamp = Some(unsigned.signed);
+            continue;
+        } else {
+            debug!("Including already signed metadata file {}", filename);
+            let signed_path = signed_dir.join(&filename);
+            fs::copy(&path, &signed_path).context(error::CopySnafu {
+                from: &path,
+                to: &signed_path,
+            })?;
+            continue;
+        };
+
+        let role_keys = root
+            .signed
+            .roles
+            .get(&role)
+            .context(error::MissingRoleSnafu { role })?;
+        let payload_path = Path::new(UNSIGNED_DIR).join(format!("{}.json", role));
+        fs::write(request_dir.join(&payload_path), &payload).context(error::WriteSnafu {
+            path: request_dir.join(&payload_path),
+        })?;
+        roles.push(RoleRequest {
+            role,
+            payload: payload_path,
+            sha256: hex::encode(digest(&SHA256, &payload)),
+            key_ids: role_keys.keyids.clone(),
+            threshold: role_keys.threshold,
+            filename,
+        });
+    }
+    ensure!(roles.len() == 2, error::IncompleteRequestSnafu);
+    let timestamp = timestamp.context(error::IncompleteRequestSnafu)?;
+    let timestamp_path = request_dir.join(TIMESTAMP_FILE);
+    fs::write(&timestamp_path, canonical_json(&timestamp)?).context(error::WriteSnafu {
+        path: &timestamp_path,
+    })?;
+
+    let request = SigningRequest {
+        variant: variant.to_string(),
+        arch: arch.to_string(),
+        roles,
+        timestamp: PathBuf::from(TIMESTAMP_FILE),
+    };
+    let request_path = request_dir.join(REQUEST_FILE);
+    serde_json::to_writer_pretty(
+        File::create(&request_path).context(error::WriteSnafu {
+            path: &request_path,
+        })?,
+        &request,
+    )
+    .context(error::SerializeSnafu)?;
+
+    info!(
+        "Wrote signing request to {}; sign the files in {} and run attach-repo-signatures",
+        request_path.display(),
+        unsigned_dir.display()
+    );
+    Ok(())
+}
+
+/// Returns whether the given file name is tough's name for the given top-level role, with or
+/// without a consistent snapshot version prefix.
+fn is_role_file(filename: &str, role: &str) -> bool {
+    let suffix = format!("{}.json", role);
+    match filename.strip_suffix(&suffix) {
+        Some("") => true,
+        Some(prefix) => prefix
+            .strip_suffix('.')
+            .map(|version| !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Serializes the given value the way TUF signatures expect.
+fn canonical_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut data, CanonicalFormatter::new());
+    value
+        .serialize(&mut serializer)
+        .context(error::SerializeSnafu)?;
+    Ok(data)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    serde_json::from_reader(File::open(path).context(repo_error::FileSnafu { path })?)
+        .context(repo_error::InvalidJsonSnafu { path })
+        .map_err(Into::into)
+}
+
+fn read_root(root_role_path: &Path) -> Result<Signed<Root>> {
+    read_json(root_role_path)
+}
+
+/// Attaches the given signatures to a role's payload, and checks that enough of them are valid.
+/// Returns the signed role as it should be written to disk.
+fn attach<T>(root: &Signed<Root>, payload: &[u8], signatures: Vec<Signature>) -> Result<Vec<u8>>
+where
+    T: Role + Serialize + DeserializeOwned,
+{
+    let signed: Signed<T> = Signed {
+        signed: serde_json::from_slice(payload).context(error::ParsePayloadSnafu)?,
+        signatures,
+    };
+    // Re-serializing must give us exactly what was signed, or the signatures can't match.
+    ensure!(
+        canonical_json(&signed.signed)? == payload,
+        error::NonCanonicalPayloadSnafu
+    );
+    verify(root, &signed)?;
+    let mut data = serde_json::to_vec_pretty(&signed).context(error::SerializeSnafu)?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+fn verify<T: Role + Serialize>(root: &Signed<Root>, signed: &Signed<T>) -> Result<()> {
+    root.signed.verify_role(signed).context(error::VerifySnafu {
+        role: T::TYPE.to_string(),
+    })
+}
+
+/// Points the unsigned timestamp role at the signed snapshot, and signs it with the given key.
+fn sign_timestamp(
+    root: &Signed<Root>,
+    mut timestamp: Timestamp,
+    snapshot: &[u8],
+    key_source: Box<dyn KeySource>,
+) -> Result<Vec<u8>> {
+    let meta = timestamp
+        .meta
+        .get_mut("snapshot.json")
+        .context(error::IncompleteRequestSnafu)?;
+    meta.length = snapshot.len() as u64;
+    meta.hashes = Hashes {
+        sha256: digest(&SHA256, snapshot).as_ref().to_vec().into(),
+        _extra: HashMap::new(),
+    };
+
+    let signed_timestamp = SignedRole::new(
+        timestamp,
+        &KeyHolder::Root(root.signed.clone()),
+        &[key_source],
+        &SystemRandom::new(),
+    )
+    .context(error::SignTimestampSnafu)?;
+    // Make sure the configured key is one that root.json trusts to sign timestamp.json.
+    verify(root, signed_timestamp.signed())?;
+    Ok(signed_timestamp.buffer().clone())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, attach_args: &AttachSignaturesArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&attach_args.repo)
+        .context(repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &attach_args.repo),
+        })?;
+
+    // Check if we have a signing key defined in Infra.toml; if not, we'll fall back to the
+    // generated local key.
+    let key_source = if let Some(signing_key_config) = repo_config.signing_keys.as_ref() {
+        get_signing_key_source(
+            signing_key_config,
+            &infra_config.aws.clone().unwrap_or_default(),
+        )?
+    } else {
+        ensure!(
+            attach_args.default_key_path.exists(),
+            repo_error::MissingConfigSnafu {
+                missing: "signing_keys in repo config, and we found no local key",
+            }
+        );
+        Box::new(LocalKeySource {
+            path: attach_args.default_key_path.clone(),
+        })
+    };
+
+    let request: SigningRequest = read_json(&attach_args.request_dir.join(REQUEST_FILE))?;
+    let root = read_root(&attach_args.root_role_path)?;
+    let mut signatures: HashMap<String, Vec<Signature>> = read_json(&attach_args.signatures_path)?;
+
+    let metadata_out_dir = attach_args
+        .outdir
+        .join(&request.variant)
+        .join(&request.arch);
+    ensure!(
+        !metadata_out_dir.exists(),
+        repo_error::RepoExistsSnafu {
+            path: &metadata_out_dir
+        }
+    );
+
+    // Check everything before writing anything, so we don't leave partial metadata behind.
+    let mut signed_roles = Vec::with_capacity(request.roles.len() + 1);
+    let mut signed_snapshot = None;
+    for role_request in &request.roles {
+        let payload_path = attach_args.request_dir.join(&role_request.payload);
+        let payload = fs::read(&payload_path).context(repo_error::FileSnafu {
+            path: &payload_path,
+        })?;
+        ensure!(
+            hex::encode(digest(&SHA256, &payload)) == role_request.sha256,
+            error::PayloadChangedSnafu {
+                path: &payload_path
+            }
+        );
+
+        let role = role_request.role.to_string();
+        let role_signatures = signatures
+            .remove(&role)
+            .context(error::MissingSignaturesSnafu { role: &role })?;
+        info!(
+            "Verifying {} signatures for the {} role",
+            role_signatures.len(),
+            role
+        );
+        let data = match role_request.role {
+            RoleType::Targets => attach::<Targets>(&root, &payload, role_signatures)?,
+            RoleType::Snapshot => {
+                let data = attach::<Snapshot>(&root, &payload, role_signatures)?;
+                signed_snapshot = Some(data.clone());
+                data
+            }
+            _ => return error::UnexpectedRoleSnafu { role }.fail(),
+        };
+        signed_roles.push((metadata_out_dir.join(&role_request.filename), data));
+    }
+
+    info!("Signing the timestamp role with the repo's signing key");
+    let snapshot = signed_snapshot.context(error::IncompleteRequestSnafu)?;
+    let timestamp: Timestamp = read_json(&attach_args.request_dir.join(&request.timestamp))?;
+    signed_roles.push((
+        metadata_out_dir.join(TIMESTAMP_FILE),
+        sign_timestamp(&root, timestamp, &snapshot, key_source)?,
+    ));
+
+    info!("Writing repo metadata to: {}", metadata_out_dir.display());
+    fs::create_dir_all(&metadata_out_dir).context(repo_error::CreateDirSnafu {
+        path: &metadata_out_dir,
+    })?;
+    for (path, data) in &signed_roles {
+        fs::write(path, data).context(error::WriteSnafu { path })?;
+    }
+    let signed_dir = attach_args.request_dir.join(SIGNED_DIR);
+    for entry in fs::read_dir(&signed_dir).context(error::ReadDirSnafu { path: &signed_dir })? {
+        let path = entry
+            .context(error::ReadDirSnafu { path: &signed_dir })?
+            .path();
+        if let Some(filename) = path.file_name() {
+            let out_path = metadata_out_dir.join(filename);
+            fs::copy(&path, &out_path).context(error::CopySnafu {
+                from: &path,
+                to: &out_path,
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+    use tough::schema::RoleType;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to copy '{}' to '{}': {}", from.display(), to.display(), source))]
+        Copy {
+            from: PathBuf,
+            to: PathBuf,
+            source: io::Error,
+        },
+
+        #[snafu(display("Repo metadata is missing the targets, snapshot, or timestamp role"))]
+        IncompleteRequest,
+
+        #[snafu(display("root.json is missing key {}", key_id))]
+        MissingKey { key_id: String },
+
+        #[snafu(display("root.json has no keys for the {} role", role))]
+        MissingRole { role: RoleType },
+
+        #[snafu(display("No signatures given for the {} role", role))]
+        MissingSignatures { role: String },
+
+        #[snafu(display("Payload isn't canonical JSON, so its signatures can't be checked"))]
+        NonCanonicalPayload,
+
+        #[snafu(display("Failed to parse payload: {}", source))]
+        ParsePayload { source: serde_json::Error },
+
+        #[snafu(display("'{}' changed since the signing request was written", path.display()))]
+        PayloadChanged { path: PathBuf },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
+        #[snafu(context(false), display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to serialize metadata: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to sign timestamp.json: {}", source))]
+        SignTimestamp {
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(display("Signing request includes unexpected role {}", role))]
+        UnexpectedRole { role: String },
+
+        #[snafu(display("Signatures for the {} role aren't valid: {}", role, source))]
+        Verify {
+            role: String,
+            source: tough::schema::Error,
+        },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::is_role_file;
+
+    #[test]
+    fn role_file_names() {
+        assert!(is_role_file("targets.json", "targets"));
+        assert!(is_role_file("1234.targets.json", "targets"));
+        assert!(is_role_file("1234.snapshot.json", "snapshot"));
+        assert!(!is_role_file("1234.my-targets.json", "targets"));
+        assert!(!is_role_file("kit.targets.json", "targets"));
+        assert!(!is_role_file(".targets.json", "targets"));
+        assert!(!is_role_file("1.root.json", "targets"));
+    }
+}
@@ -0,0 +1,161 @@
+//! The pkcs11 module provides a tough `KeySource` for RSA keys held in a PKCS#11 token, like an
+//! HSM or a YubiKey.  Signing is done by `pkcs11-tool` from OpenSC, so the private key never
+//! leaves the token, and we don't have to load the token's PKCS#11 module ourselves.
+
+use duct::cmd;
+use log::trace;
+use ring::rand::SecureRandom;
+use serde_json::json;
+use snafu::{OptionExt, ResultExt};
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use tough::key_source::KeySource;
+use tough::schema::key::Key;
+use tough::sign::Sign;
+
+type BoxedError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// A signing key held in a PKCS#11 token
+#[derive(Debug)]
+pub(crate) struct Pkcs11KeySource {
+    /// Path to the PKCS#11 module for the token, like opensc-pkcs11.so or libykcs11.so
+    pub(crate) module_path: PathBuf,
+    /// Label of the token holding the key
+    pub(crate) token_label: String,
+    /// Label of the private key in the token
+    pub(crate) key_label: String,
+    /// Path to the key's public key, in PEM format
+    pub(crate) public_key_path: PathBuf,
+    /// Environment variable holding the user PIN; if not given, pkcs11-tool prompts for it
+    pub(crate) pin_env: Option<String>,
+}
+
+impl KeySource for Pkcs11KeySource {
+    fn as_sign(&self) -> std::result::Result<Box<dyn Sign>, BoxedError> {
+        let public_key = fs::read_to_string(&self.public_key_path).context(error::FileSnafu {
+            path: &self.public_key_path,
+        })?;
+        // We only need the public key to describe the key in root.json, so it's simplest to let
+        // tough parse it the same way it parses root.json.
+        let key: Key = serde_json::from_value(json!({
+            "keytype": "rsa",
+            "keyval": { "public": public_key },
+            "scheme": "rsassa-pss-sha256",
+        }))
+        .context(error::PublicKeySnafu {
+            path: &self.public_key_path,
+        })?;
+        let pin = match &self.pin_env {
+            Some(pin_env) => Some(
+                env::var(pin_env)
+                    .ok()
+                    .context(error::PinSnafu { pin_env })?,
+            ),
+            None => None,
+        };
+
+        Ok(Box::new(Pkcs11Signer {
+            key,
+            module_path: self.module_path.clone(),
+            token_label: self.token_label.clone(),
+            key_label: self.key_label.clone(),
+            pin,
+        }))
+    }
+
+    fn write(&self, _value: &str, _key_id_hex: &str) -> std::result::Result<(), BoxedError> {
+        Err(error::WriteUnsupportedSnafu.build().into())
+    }
+}
+
+struct Pkcs11Signer {
+    key: Key,
+    module_path: PathBuf,
+    token_label: String,
+    key_label: String,
+    pin: Option<String>,
+}
+
+impl Sign for Pkcs11Signer {
+    fn tuf_key(&self) -> Key {
+        self.key.clone()
+    }
+
+    fn sign(
+        &self,
+        msg: &[u8],
+        // The token generates the PSS salt itself.
+        _rng: &dyn SecureRandom,
+    ) -> std::result::Result<Vec<u8>, BoxedError> {
+        let mut args: Vec<OsString> = vec![
+            "--module".into(),
+            self.module_path.clone().into_os_string(),
+            "--token-label".into(),
+            self.token_label.clone().into(),
+            "--label".into(),
+            self.key_label.clone().into(),
+            "--login".into(),
+            "--sign".into(),
+            // TUF's rsassa-pss-sha256 scheme uses MGF1 with SHA-256 and a salt as long as the
+            // digest, which is pkcs11-tool's default salt length.
+            "--mechanism".into(),
+            "SHA256-RSA-PKCS-PSS".into(),
+            "--hash-algorithm".into(),
+            "SHA256".into(),
+            "--mgf".into(),
+            "MGF1-SHA256".into(),
+        ];
+        if let Some(pin) = &self.pin {
+            args.push("--pin".into());
+            args.push(pin.into());
+        }
+        trace!(
+            "Signing {} bytes with key '{}' in token '{}'",
+            msg.len(),
+            self.key_label,
+            self.token_label
+        );
+        let output = cmd("pkcs11-tool", args)
+            .stdin_bytes(msg)
+            .stdout_capture()
+            .run()
+            .context(error::SignSnafu {
+                key_label: &self.key_label,
+            })?;
+        Ok(output.stdout)
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        File { path: PathBuf, source: io::Error },
+
+        #[snafu(display("PKCS#11 PIN variable '{}' is not set", pin_env))]
+        Pin { pin_env: String },
+
+        #[snafu(display("Invalid RSA public key in '{}': {}", path.display(), source))]
+        PublicKey {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to sign with PKCS#11 key '{}': {}", key_label, source))]
+        Sign {
+            key_label: String,
+            source: io::Error,
+        },
+
+        #[snafu(display("Keys can't be written to a PKCS#11 token by pubsys"))]
+        WriteUnsupported,
+    }
+}
@@ -1,21 +1,37 @@
 //! The refresh_repo module owns the 'refresh-repo' subcommand and provide methods for
 //! refreshing and re-signing the metadata files of a given TUF repository.
 
+use crate::repo::check_expirations::find_variants;
 use crate::repo::{
-    error as repo_error, get_signing_key_source, repo_urls, set_expirations, set_versions,
+    error as repo_error, get_signing_key_source, is_file_not_found_error, repo_manifest, repo_urls,
+    set_expirations, set_versions, ExpirationOverrides,
 };
 use crate::Args;
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
-use log::{info, trace};
-use pubsys_config::{InfraConfig, RepoExpirationPolicy};
+use log::{error, info, trace, warn};
+use pubsys_config::{
+    AwsConfig as PubsysAwsConfig, InfraConfig, RepoConfig, RepoExpirationPolicy, SigningKeyConfig,
+};
+use rayon::prelude::*;
+use ring::rand::SystemRandom;
+use serde::Serialize;
+use serde_plain::derive_display_from_serialize;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
+use std::io::Write;
+use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
 use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+use tempfile::NamedTempFile;
+use tough::editor::signed::SignedRole;
 use tough::editor::RepositoryEditor;
 use tough::key_source::{KeySource, LocalKeySource};
+use tough::schema::decoded::{Decoded, Hex};
+use tough::schema::{KeyHolder, Root, Signed};
 use tough::{ExpirationEnforcement, RepositoryLoader};
 use url::Url;
 
@@ -23,24 +39,51 @@ lazy_static! {
     static ref EXPIRATION_START_TIME: DateTime<Utc> = Utc::now();
 }
 
+/// When refreshing all repos, we refresh this many at once.
+const MAX_CONCURRENT_REFRESHES: usize = 8;
+
 /// Refreshes and re-sign TUF repositories' non-root metadata files with new expiration dates
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct RefreshRepoArgs {
-    #[structopt(long)]
+pub struct RefreshRepoArgs {
+    #[structopt(long, required_unless = "all")]
     /// Use this named repo infrastructure from Infra.toml
-    repo: String,
+    repo: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, required_unless = "all")]
     /// The architecture of the repo being refreshed and re-signed
-    arch: String,
-    #[structopt(long)]
+    arch: Option<String>,
+    #[structopt(long, required_unless = "all")]
     /// The variant of the repo being refreshed and re-signed
-    variant: String,
+    variant: Option<String>,
 
-    #[structopt(long, parse(from_os_str))]
+    #[structopt(long, parse(from_os_str), required_unless = "all")]
     /// Path to root.json for this repo
-    root_role_path: PathBuf,
+    root_role_path: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "repo", "arch", "variant", "root-role-path", "new-key-path", "new-kms-key-id"
+        ],
+        requires_all = &["variants-dir", "roles-dir"]
+    )]
+    /// Refresh every repo in Infra.toml, for every variant and architecture, instead of one repo;
+    /// each is written to <outdir>/<repo>/<variant>/<arch>
+    all: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// With --all, the directory of variants to refresh; each subdirectory with a Cargo.toml is a
+    /// variant
+    variants_dir: Option<PathBuf>,
+
+    #[structopt(long, use_delimiter = true, default_value = "x86_64,aarch64")]
+    /// With --all, the architectures to refresh
+    arches: Vec<String>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// With --all, the directory holding the root.json of each repo, named <repo>.root.json
+    roles_dir: Option<PathBuf>,
 
     #[structopt(long, parse(from_os_str))]
     /// If we generated a local key, we'll find it here; used if Infra.toml has no key defined
@@ -49,6 +92,8 @@ pub(crate) struct RefreshRepoArgs {
     #[structopt(long, parse(from_os_str))]
     /// Path to file that defines when repo non-root metadata should expire
     repo_expiration_policy_path: PathBuf,
+    #[structopt(flatten)]
+    expiration_overrides: ExpirationOverrides,
 
     #[structopt(long, parse(from_os_str))]
     /// Where to store the refresh/re-signed repository (just the metadata files)
@@ -58,14 +103,195 @@ pub(crate) struct RefreshRepoArgs {
     /// If this flag is set, repositories will succeed in loading and be refreshed even if they have
     /// expired metadata files.
     unsafe_refresh: bool,
+
+    #[structopt(long, parse(from_os_str), conflicts_with = "new-kms-key-id")]
+    /// Rotate the repo's signing key to the local key at this path; root.json is updated to trust
+    /// the new key in place of the current one, and signed with both
+    new_key_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Rotate the repo's signing key to this KMS key, like --new-key-path
+    new_kms_key_id: Option<String>,
+}
+
+impl RefreshRepoArgs {
+    /// Returns the options for `refresh`, reading the expiration policy and applying any
+    /// overrides.
+    fn options(&self) -> Result<RefreshRepoOptions, Error> {
+        info!(
+            "Using repo expiration policy from path: {}",
+            self.repo_expiration_policy_path.display()
+        );
+        let expiration_policy = self.expiration_overrides.apply(
+            RepoExpirationPolicy::from_path(&self.repo_expiration_policy_path)
+                .context(repo_error::ConfigSnafu)?,
+        );
+        let repos = if self.all {
+            // structopt requires these with --all.
+            match (&self.variants_dir, &self.roles_dir) {
+                (Some(variants_dir), Some(roles_dir)) => ReposToRefresh::All {
+                    variants_dir: variants_dir.clone(),
+                    arches: self.arches.clone(),
+                    roles_dir: roles_dir.clone(),
+                },
+                _ => unreachable!("developer error: --all requires --variants-dir and --roles-dir"),
+            }
+        } else {
+            // structopt requires these without --all.
+            match (&self.repo, &self.variant, &self.arch, &self.root_role_path) {
+                (Some(repo), Some(variant), Some(arch), Some(root_role_path)) => {
+                    let new_signing_key = match (&self.new_key_path, &self.new_kms_key_id) {
+                        (Some(path), _) => Some(SigningKeyConfig::file { path: path.clone() }),
+                        (None, Some(key_id)) => Some(SigningKeyConfig::kms {
+                            key_id: Some(key_id.clone()),
+                            config: None,
+                        }),
+                        (None, None) => None,
+                    };
+                    ReposToRefresh::One {
+                        repo: repo.clone(),
+                        variant: variant.clone(),
+                        arch: arch.clone(),
+                        root_role_path: root_role_path.clone(),
+                        new_signing_key,
+                    }
+                }
+                _ => unreachable!(
+                    "developer error: --repo, --variant, --arch, and --root-role-path are required without --all"
+                ),
+            }
+        };
+        Ok(RefreshRepoOptions {
+            repos,
+            default_key_path: self.default_key_path.clone(),
+            expiration_policy,
+            outdir: self.outdir.clone(),
+            unsafe_refresh: self.unsafe_refresh,
+        })
+    }
+}
+
+/// The repos `refresh` re-signs
+#[derive(Debug, Clone)]
+pub enum ReposToRefresh {
+    /// One repo from the infra config, for one variant and architecture, written to
+    /// <outdir>/<variant>/<arch>
+    One {
+        repo: String,
+        variant: String,
+        arch: String,
+        /// Path to root.json for the repo
+        root_role_path: PathBuf,
+        /// Rotate the repo's signing key to this one; root.json is updated to trust it in place of
+        /// the current key, and signed with both
+        new_signing_key: Option<SigningKeyConfig>,
+    },
+    /// Every repo in the infra config, for every variant and architecture, each written to
+    /// <outdir>/<repo>/<variant>/<arch>
+    All {
+        /// Directory of variants; each subdirectory with a Cargo.toml is a variant
+        variants_dir: PathBuf,
+        arches: Vec<String>,
+        /// Directory holding the root.json of each repo, named <repo>.root.json
+        roles_dir: PathBuf,
+    },
+}
+
+/// Which repos `refresh` re-signs, and when their metadata expires
+#[derive(Debug, Clone)]
+pub struct RefreshRepoOptions {
+    pub repos: ReposToRefresh,
+    /// The local signing key, used for repos whose config has no signing key
+    pub default_key_path: PathBuf,
+    /// When the refreshed metadata expires, counted from now
+    pub expiration_policy: RepoExpirationPolicy,
+    /// Where to write the refreshed metadata
+    pub outdir: PathBuf,
+    /// Refresh repos even if they have expired metadata
+    pub unsafe_refresh: bool,
+}
+
+/// Returns the ID that root.json uses for the given key.
+fn key_id(key_source: &dyn KeySource) -> Result<Decoded<Hex>, Error> {
+    key_source
+        .as_sign()
+        .context(error::KeySourceSnafu)?
+        .tuf_key()
+        .key_id()
+        .context(error::KeyIdSnafu)
+}
+
+/// Builds the next version of the given root role, trusting the new key in place of the old one
+/// for every role.  The new root is signed with the new key and cross-signed with the old key, so
+/// that clients trusting the current root will accept it.
+// SignedRole takes a slice of boxed key sources, so we take the boxes rather than the key sources.
+#[allow(clippy::borrowed_box)]
+fn rotate_root(
+    old_root: &Signed<Root>,
+    old_key: &Box<dyn KeySource>,
+    new_key: &Box<dyn KeySource>,
+) -> Result<SignedRole<Root>, Error> {
+    let old_key_id = key_id(old_key.as_ref())?;
+    let new_tuf_key = new_key.as_sign().context(error::KeySourceSnafu)?.tuf_key();
+    let new_key_id = new_tuf_key.key_id().context(error::KeyIdSnafu)?;
+    ensure!(old_key_id != new_key_id, error::SameKeySnafu);
+
+    let mut root = old_root.signed.clone();
+    root.version = NonZeroU64::new(root.version.get() + 1).context(error::RootVersionSnafu {
+        version: root.version,
+    })?;
+    let mut rotated = false;
+    for role_keys in root.roles.values_mut() {
+        if role_keys.keyids.contains(&old_key_id) {
+            role_keys.keyids.retain(|keyid| *keyid != old_key_id);
+            if !role_keys.keyids.contains(&new_key_id) {
+                role_keys.keyids.push(new_key_id.clone());
+            }
+            rotated = true;
+        }
+    }
+    ensure!(
+        rotated,
+        error::KeyNotInRootSnafu {
+            key_id: old_key_id
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        }
+    );
+    root.keys.remove(&old_key_id);
+    root.keys.insert(new_key_id, new_tuf_key);
+
+    let rng = SystemRandom::new();
+    let signed_root = SignedRole::new(
+        root.clone(),
+        &KeyHolder::Root(root.clone()),
+        std::slice::from_ref(new_key),
+        &rng,
+    )
+    .context(error::SignRootSnafu)?;
+    let cross_signed_root = SignedRole::new(
+        root,
+        &KeyHolder::Root(old_root.signed.clone()),
+        std::slice::from_ref(old_key),
+        &rng,
+    )
+    .context(error::SignRootSnafu)?;
+    signed_root
+        .add_old_signatures(cross_signed_root.signed().signatures.clone())
+        .context(error::SignRootSnafu)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn refresh_repo(
     root_role_path: &PathBuf,
     metadata_out_dir: &PathBuf,
+    variant: &str,
+    arch: &str,
     metadata_url: &Url,
     targets_url: &Url,
     key_source: Box<dyn KeySource>,
+    new_key_source: Option<Box<dyn KeySource>>,
     expiration: &RepoExpirationPolicy,
     unsafe_refresh: bool,
 ) -> Result<(), Error> {
@@ -97,10 +323,34 @@ fn refresh_repo(
     .context(repo_error::RepoLoadSnafu {
         metadata_base_url: metadata_url.clone(),
     })?;
-    let mut repo_editor = RepositoryEditor::from_repo(root_role_path, repo)
-        .context(repo_error::EditorFromRepoSnafu)?;
     info!("Loaded TUF repo: {}", metadata_url);
 
+    // If we're rotating keys, the editor starts from the new root, and everything is signed with
+    // the new key.  The new root is written out with the rest of the metadata.
+    let (mut repo_editor, key_source) = match new_key_source {
+        None => (
+            RepositoryEditor::from_repo(root_role_path, repo)
+                .context(repo_error::EditorFromRepoSnafu)?,
+            key_source,
+        ),
+        Some(new_key_source) => {
+            let new_root = rotate_root(repo.root(), &key_source, &new_key_source)?;
+            info!(
+                "Rotated signing key in root.json, new root version is {}",
+                new_root.signed().signed.version
+            );
+            let mut new_root_file = NamedTempFile::new().context(repo_error::TempFileSnafu)?;
+            new_root_file
+                .write_all(new_root.buffer())
+                .context(error::WriteRootSnafu)?;
+            (
+                RepositoryEditor::from_repo(new_root_file.path(), repo)
+                    .context(repo_error::EditorFromRepoSnafu)?,
+                new_key_source,
+            )
+        }
+    };
+
     // Refresh the expiration dates of all non-root metadata files
     set_expirations(&mut repo_editor, expiration, *EXPIRATION_START_TIME)?;
 
@@ -108,8 +358,9 @@ fn refresh_repo(
     set_versions(&mut repo_editor)?;
 
     // Sign the repository
+    let key_sources = [key_source];
     let signed_repo = repo_editor
-        .sign(&[key_source])
+        .sign(&key_sources)
         .context(repo_error::RepoSignSnafu)?;
 
     // Write out the metadata files for the repository
@@ -122,94 +373,332 @@ fn refresh_repo(
         .context(repo_error::RepoWriteSnafu {
             path: &metadata_out_dir,
         })?;
+    repo_manifest::write_repo_manifest(
+        root_role_path,
+        metadata_out_dir,
+        variant,
+        arch,
+        &key_sources,
+    )
+    .context(error::RepoManifestSnafu)?;
 
     Ok(())
 }
 
-/// Common entrypoint from main()
-pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<(), Error> {
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
-    trace!("Parsed infra config: {:?}", infra_config);
+/// Returns the signing key defined for the repo in Infra.toml; if there isn't one, we fall back to
+/// the generated local key.
+fn signing_key_source(
+    repo_config: &RepoConfig,
+    aws: &PubsysAwsConfig,
+    default_key_path: &Path,
+) -> Result<Box<dyn KeySource>, Error> {
+    if let Some(signing_key_config) = repo_config.signing_keys.as_ref() {
+        return Ok(get_signing_key_source(signing_key_config, aws)?);
+    }
+    ensure!(
+        default_key_path.exists(),
+        repo_error::MissingConfigSnafu {
+            missing: "signing_keys in repo config, and we found no local key",
+        }
+    );
+    Ok(Box::new(LocalKeySource {
+        path: default_key_path.to_path_buf(),
+    }))
+}
 
-    let repo_config = infra_config
+/// Returns whether the error means there's no repo to refresh at the configured URLs.
+fn is_missing_repo(e: &Error) -> bool {
+    match e {
+        Error::Repo { source } => match source.as_ref() {
+            repo_error::Error::RepoLoad { source, .. } => is_file_not_found_error(source),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The outcome of refreshing one repo, variant, and architecture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefreshStatus {
+    Refreshed,
+    /// Nothing is published at the repo's URLs for this variant and architecture
+    Missing,
+    Failed,
+}
+
+derive_display_from_serialize!(RefreshStatus);
+
+/// The outcome of refreshing one repo, for one variant and architecture
+#[derive(Debug, Tabled)]
+pub struct RepoRefresh {
+    pub repo: String,
+    pub variant: String,
+    pub arch: String,
+    pub status: RefreshStatus,
+}
+
+/// A repo, variant, and architecture to refresh when refreshing all repos
+struct RepoToRefresh {
+    repo: String,
+    variant: String,
+    arch: String,
+    root_role_path: PathBuf,
+    metadata_url: Url,
+    targets_url: Url,
+    key_source: Box<dyn KeySource>,
+}
+
+/// Refreshes every repo in Infra.toml, for every variant and architecture, returning the outcome
+/// of each.  Repos that fail to refresh are logged.
+fn refresh_all_repos(
+    infra_config: &InfraConfig,
+    variants_dir: &Path,
+    arches: &[String],
+    roles_dir: &Path,
+    options: &RefreshRepoOptions,
+) -> Result<Vec<RepoRefresh>, Error> {
+    let variants = find_variants(variants_dir).context(error::FindVariantsSnafu)?;
+    let repos: BTreeMap<_, _> = infra_config
         .repo
         .as_ref()
         .context(repo_error::MissingConfigSnafu {
             missing: "repo section",
         })?
-        .get(&refresh_repo_args.repo)
+        .iter()
+        .collect();
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    let mut to_refresh = Vec::new();
+    'repos: for (repo, repo_config) in repos {
+        let root_role_path = roles_dir.join(format!("{}.root.json", repo));
+        if !root_role_path.exists() {
+            warn!(
+                "Skipping repo '{}', no root role at {}",
+                repo,
+                root_role_path.display()
+            );
+            continue;
+        }
+        for variant in &variants {
+            for arch in arches {
+                let (metadata_url, targets_url) = match repo_urls(repo_config, variant, arch)? {
+                    Some(urls) => urls,
+                    None => {
+                        warn!("Skipping repo '{}', it has no metadata/targets URLs", repo);
+                        continue 'repos;
+                    }
+                };
+                to_refresh.push(RepoToRefresh {
+                    repo: repo.clone(),
+                    variant: variant.clone(),
+                    arch: arch.clone(),
+                    root_role_path: root_role_path.clone(),
+                    metadata_url,
+                    targets_url: targets_url.clone(),
+                    key_source: signing_key_source(repo_config, &aws, &options.default_key_path)?,
+                });
+            }
+        }
+    }
+
+    info!("Refreshing up to {} repos", to_refresh.len());
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(MAX_CONCURRENT_REFRESHES)
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+    let rows: Vec<RepoRefresh> = thread_pool.install(|| {
+        to_refresh
+            .into_par_iter()
+            .map(|repo| {
+                let metadata_out_dir = options
+                    .outdir
+                    .join(&repo.repo)
+                    .join(&repo.variant)
+                    .join(&repo.arch);
+                let result = refresh_repo(
+                    &repo.root_role_path,
+                    &metadata_out_dir,
+                    &repo.variant,
+                    &repo.arch,
+                    &repo.metadata_url,
+                    &repo.targets_url,
+                    repo.key_source,
+                    None,
+                    &options.expiration_policy,
+                    options.unsafe_refresh,
+                );
+                let status = match result {
+                    Ok(()) => RefreshStatus::Refreshed,
+                    Err(e) if is_missing_repo(&e) => {
+                        trace!("No repo at {}", repo.metadata_url);
+                        RefreshStatus::Missing
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to refresh repo '{}' for {} {}: {}",
+                            repo.repo, repo.variant, repo.arch, e
+                        );
+                        RefreshStatus::Failed
+                    }
+                };
+                RepoRefresh {
+                    repo: repo.repo,
+                    variant: repo.variant,
+                    arch: repo.arch,
+                    status,
+                }
+            })
+            .collect()
+    });
+    Ok(rows)
+}
+
+/// Refreshes and re-signs the repos in the options, returning the outcome of each.  When refreshing
+/// all repos, repos that fail are reported in the outcomes rather than as an error.
+pub fn refresh(
+    infra_config: &InfraConfig,
+    options: &RefreshRepoOptions,
+) -> Result<Vec<RepoRefresh>, Error> {
+    let (repo, variant, arch, root_role_path, new_signing_key) = match &options.repos {
+        ReposToRefresh::One {
+            repo,
+            variant,
+            arch,
+            root_role_path,
+            new_signing_key,
+        } => (repo, variant, arch, root_role_path, new_signing_key),
+        ReposToRefresh::All {
+            variants_dir,
+            arches,
+            roles_dir,
+        } => return refresh_all_repos(infra_config, variants_dir, arches, roles_dir, options),
+    };
+    let repo_config = infra_config
+        .repo
+        .as_ref()
         .context(repo_error::MissingConfigSnafu {
-            missing: format!("definition for repo {}", &refresh_repo_args.repo),
+            missing: "repo section",
+        })?
+        .get(repo)
+        .with_context(|| repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", repo),
         })?;
 
-    // Check if we have a signing key defined in Infra.toml; if not, we'll fall back to the
-    // generated local key.
-    let signing_key_config = repo_config.signing_keys.as_ref();
-
-    let key_source = if let Some(signing_key_config) = signing_key_config {
-        get_signing_key_source(signing_key_config)?
-    } else {
-        ensure!(
-            refresh_repo_args.default_key_path.exists(),
-            repo_error::MissingConfigSnafu {
-                missing: "signing_keys in repo config, and we found no local key",
-            }
-        );
-        Box::new(LocalKeySource {
-            path: refresh_repo_args.default_key_path.clone(),
-        })
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let key_source = signing_key_source(repo_config, &aws, &options.default_key_path)?;
+    let new_key_source = match new_signing_key {
+        Some(new_signing_key) => Some(get_signing_key_source(new_signing_key, &aws)?),
+        None => None,
     };
 
-    // Get the expiration policy
-    info!(
-        "Using repo expiration policy from path: {}",
-        refresh_repo_args.repo_expiration_policy_path.display()
-    );
-    let expiration =
-        RepoExpirationPolicy::from_path(&refresh_repo_args.repo_expiration_policy_path)
-            .context(repo_error::ConfigSnafu)?;
-
-    let repo_urls = repo_urls(
-        repo_config,
-        &refresh_repo_args.variant,
-        &refresh_repo_args.arch,
-    )?
-    .context(repo_error::MissingRepoUrlsSnafu {
-        repo: &refresh_repo_args.repo,
-    })?;
+    let repo_urls = repo_urls(repo_config, variant, arch)?
+        .context(repo_error::MissingRepoUrlsSnafu { repo })?;
     refresh_repo(
-        &refresh_repo_args.root_role_path,
-        &refresh_repo_args
-            .outdir
-            .join(&refresh_repo_args.variant)
-            .join(&refresh_repo_args.arch),
+        root_role_path,
+        &options.outdir.join(variant).join(arch),
+        variant,
+        arch,
         &repo_urls.0,
         repo_urls.1,
         key_source,
-        &expiration,
-        refresh_repo_args.unsafe_refresh,
+        new_key_source,
+        &options.expiration_policy,
+        options.unsafe_refresh,
     )?;
+    Ok(vec![RepoRefresh {
+        repo: repo.clone(),
+        variant: variant.clone(),
+        arch: arch.clone(),
+        status: RefreshStatus::Refreshed,
+    }])
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<(), Error> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+
+    let options = refresh_repo_args.options()?;
+    let rows = refresh(&infra_config, &options)?;
+    if let ReposToRefresh::One { .. } = options.repos {
+        return Ok(());
+    }
 
+    // When refreshing all repos, print a combined summary.
+    let failed = rows
+        .iter()
+        .filter(|row| row.status == RefreshStatus::Failed)
+        .count();
+    let refreshed = rows
+        .iter()
+        .filter(|row| row.status == RefreshStatus::Refreshed)
+        .count();
+    println!("{}", Table::new(rows));
+    info!("Refreshed {} repos, {} failed", refreshed, failed);
+    ensure!(failed == 0, error::RefreshFailuresSnafu { count: failed });
     Ok(())
 }
 
 mod error {
     use snafu::Snafu;
+    use std::num::NonZeroU64;
     use url::Url;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(context(false), display("{}", source))]
         Repo {
             #[snafu(source(from(crate::repo::Error, Box::new)))]
             source: Box<crate::repo::Error>,
         },
 
+        #[snafu(display("Failed to find variants: {}", source))]
+        FindVariants {
+            source: crate::repo::check_expirations::Error,
+        },
+
+        #[snafu(display("Failed to get key ID: {}", source))]
+        KeyId { source: tough::schema::Error },
+
+        #[snafu(display(
+            "Current signing key {} isn't in root.json, so we can't rotate it",
+            key_id
+        ))]
+        KeyNotInRoot { key_id: String },
+
+        #[snafu(display("Failed to get key from key source: {}", source))]
+        KeySource {
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display("Failed to refresh {} repos; see above", count))]
+        RefreshFailures { count: usize },
+
         #[snafu(display("Failed to refresh & re-sign metadata for: {:#?}", list_of_urls))]
         RepoRefresh { list_of_urls: Vec<Url> },
+
+        #[snafu(display("Failed to write repo manifest: {}", source))]
+        RepoManifest {
+            source: crate::repo::repo_manifest::Error,
+        },
+
+        #[snafu(display("Can't increment root version {}", version))]
+        RootVersion { version: NonZeroU64 },
+
+        #[snafu(display("New signing key is the same as the current one"))]
+        SameKey,
+
+        #[snafu(display("Failed to sign root.json: {}", source))]
+        SignRoot { source: tough::error::Error },
+
+        #[snafu(display("Failed to create thread pool: {}", source))]
+        ThreadPool { source: rayon::ThreadPoolBuildError },
+
+        #[snafu(display("Failed to write new root.json: {}", source))]
+        WriteRoot { source: std::io::Error },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
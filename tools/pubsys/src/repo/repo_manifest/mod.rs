@@ -0,0 +1,529 @@
+//! The repo_manifest module records the state of a repo when it's published, and owns the
+//! 'verify-repo-manifest' subcommand, which later confirms that the live repo still matches it.
+//!
+//! The `repo` and `refresh-repo` subcommands write a repo manifest next to the metadata they
+//! write, and `upload-repo` publishes it with the rest of the metadata.  (This is unrelated to
+//! manifest.json, the update manifest target.)  It lists the version and expiration of each
+//! metadata role and the sha256 of each target, and is signed with the repo's signing keys, so it
+//! can't be replaced along with the metadata it describes.  Any difference between the manifest
+//! and the live repo means the repo was tampered with, partially overwritten, or published
+//! without us.
+//!
+//! The signing and checking of manifests is shared with the release manifests written by the
+//! 'manifest' subcommand; see `release_manifest`.
+
+use crate::exit_code::{Classify, Kind};
+use crate::repo::diff_repo::{role_versions, target_summaries, TargetSummary};
+use crate::repo::validate_repo::download_targets;
+use crate::repo::{error as repo_error, repo_urls};
+use crate::Args;
+use chrono::{DateTime, Utc};
+use log::{error, info, trace};
+use olpc_cjson::CanonicalFormatter;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ED25519,
+    RSA_PSS_2048_8192_SHA256,
+};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tough::key_source::KeySource;
+use tough::schema::decoded::{Decoded, Hex};
+use tough::schema::key::Key;
+use tough::schema::{RoleType, Root};
+use tough::{DefaultTransport, Repository, RepositoryLoader, Transport};
+use url::Url;
+
+/// The name of the repo manifest, in the metadata directory of each variant and arch
+pub(crate) const MANIFEST_FILE: &str = "repo-manifest.json";
+
+/// Confirms that a published repo still matches the repo manifest written when it was published
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct VerifyRepoManifestArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repo being verified
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repo being verified
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Verify against this copy of the repo manifest, rather than the one published with the repo
+    manifest_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Also download each target to check that its contents match the metadata
+    verify_targets: bool,
+}
+
+/// The published state of a repo
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RepoState {
+    variant: String,
+    arch: String,
+    recorded_at: DateTime<Utc>,
+    /// The version of each metadata role, including delegated roles
+    roles: BTreeMap<String, NonZeroU64>,
+    /// The expiration of each top-level metadata role
+    expirations: BTreeMap<String, DateTime<Utc>>,
+    targets: BTreeMap<String, TargetSummary>,
+}
+
+impl RepoState {
+    fn new(repo: &Repository, variant: &str, arch: &str, recorded_at: DateTime<Utc>) -> Self {
+        let mut expirations = BTreeMap::new();
+        expirations.insert("root".to_string(), repo.root().signed.expires);
+        expirations.insert("snapshot".to_string(), repo.snapshot().signed.expires);
+        expirations.insert("targets".to_string(), repo.targets().signed.expires);
+        expirations.insert("timestamp".to_string(), repo.timestamp().signed.expires);
+        Self {
+            variant: variant.to_string(),
+            arch: arch.to_string(),
+            recorded_at,
+            roles: role_versions(repo),
+            expirations,
+            targets: target_summaries(repo),
+        }
+    }
+}
+
+/// A signature over the canonical JSON of a manifest's contents, by a key from root.json
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ManifestSignature {
+    keyid: Decoded<Hex>,
+    sig: Decoded<Hex>,
+}
+
+/// The repo manifest as it's written to disk
+#[derive(Debug, Serialize, Deserialize)]
+struct RepoManifest {
+    signed: RepoState,
+    signatures: Vec<ManifestSignature>,
+}
+
+/// Serializes the given value the way signatures expect.
+fn canonical_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut data, CanonicalFormatter::new());
+    value
+        .serialize(&mut serializer)
+        .context(error::SerializeSnafu)?;
+    Ok(data)
+}
+
+/// Returns whether the given signature of the message was made by the given key.  tough doesn't
+/// expose its own check for data other than TUF roles, so this mirrors it.
+fn verify_signature(key: &Key, msg: &[u8], signature: &[u8]) -> bool {
+    let (algorithm, public_key): (&'static dyn VerificationAlgorithm, &[u8]) = match key {
+        Key::Ecdsa { keyval, .. } => (&ECDSA_P256_SHA256_ASN1, keyval.public.as_ref()),
+        Key::Ed25519 { keyval, .. } => (&ED25519, keyval.public.as_ref()),
+        Key::Rsa { keyval, .. } => (&RSA_PSS_2048_8192_SHA256, keyval.public.as_ref()),
+    };
+    UnparsedPublicKey::new(algorithm, public_key)
+        .verify(msg, signature)
+        .is_ok()
+}
+
+/// Checks that the manifest is signed by enough of the keys that root.json trusts to sign the
+/// targets role, the same as the metadata it describes.
+fn verify_manifest(root: &Root, manifest: &RepoManifest) -> Result<()> {
+    verify_signatures(root, &manifest.signed, &manifest.signatures)
+}
+
+/// Checks that the signatures of the given manifest contents include enough from the keys that
+/// root.json trusts to sign the targets role.
+pub(crate) fn verify_signatures<T: Serialize>(
+    root: &Root,
+    signed: &T,
+    signatures: &[ManifestSignature],
+) -> Result<()> {
+    let role_keys = root
+        .roles
+        .get(&RoleType::Targets)
+        .context(error::MissingTargetsRoleSnafu)?;
+    let payload = canonical_json(signed)?;
+    let mut valid = HashSet::new();
+    for signature in signatures {
+        if !role_keys.keyids.contains(&signature.keyid) {
+            continue;
+        }
+        if let Some(key) = root.keys.get(&signature.keyid) {
+            if verify_signature(key, &payload, &signature.sig) {
+                valid.insert(signature.keyid.clone());
+            }
+        }
+    }
+    ensure!(
+        valid.len() as u64 >= role_keys.threshold.get(),
+        error::UnsignedManifestSnafu {
+            valid: valid.len(),
+            threshold: role_keys.threshold.get(),
+        }
+    );
+    Ok(())
+}
+
+/// Signs the canonical JSON of the given manifest contents with each of the given keys that
+/// root.json trusts for the targets role, checking that there are enough signatures.
+pub(crate) fn sign<T: Serialize>(
+    root: &Root,
+    signed: &T,
+    key_sources: &[Box<dyn KeySource>],
+) -> Result<Vec<ManifestSignature>> {
+    let payload = canonical_json(signed)?;
+    let role_keys = root
+        .roles
+        .get(&RoleType::Targets)
+        .context(error::MissingTargetsRoleSnafu)?;
+    let rng = SystemRandom::new();
+    let mut signatures = Vec::new();
+    for key_source in key_sources {
+        let sign = key_source.as_sign().context(error::KeySourceSnafu)?;
+        let keyid = sign.tuf_key().key_id().context(error::KeyIdSnafu)?;
+        if !role_keys.keyids.contains(&keyid) {
+            continue;
+        }
+        let sig = sign.sign(&payload, &rng).context(error::SignSnafu)?;
+        signatures.push(ManifestSignature {
+            keyid,
+            sig: sig.into(),
+        });
+    }
+    verify_signatures(root, signed, &signatures)?;
+    Ok(signatures)
+}
+
+/// Describes each way the live repo differs from the recorded state.
+fn compare(recorded: &RepoState, live: &RepoState) -> Vec<String> {
+    let mut problems = Vec::new();
+    if (&recorded.variant, &recorded.arch) != (&live.variant, &live.arch) {
+        problems.push(format!(
+            "Manifest is for {} {}, not {} {}",
+            recorded.variant, recorded.arch, live.variant, live.arch
+        ));
+    }
+    for (role, version) in &recorded.roles {
+        match live.roles.get(role) {
+            Some(live_version) if live_version == version => {}
+            Some(live_version) => problems.push(format!(
+                "Role {} is version {}, but the manifest recorded version {}",
+                role, live_version, version
+            )),
+            None => problems.push(format!("Role {} is missing", role)),
+        }
+    }
+    for role in live.roles.keys() {
+        if !recorded.roles.contains_key(role) {
+            problems.push(format!("Role {} isn't in the manifest", role));
+        }
+    }
+    for (role, expires) in &recorded.expirations {
+        if let Some(live_expires) = live.expirations.get(role) {
+            if live_expires != expires {
+                problems.push(format!(
+                    "Role {} expires at {}, but the manifest recorded {}",
+                    role,
+                    live_expires.to_rfc3339(),
+                    expires.to_rfc3339()
+                ));
+            }
+        }
+    }
+    for (name, target) in &recorded.targets {
+        match live.targets.get(name) {
+            Some(live_target) if live_target == target => {}
+            Some(live_target) => problems.push(format!(
+                "Target {} has sha256 {}, but the manifest recorded {}",
+                name, live_target.sha256, target.sha256
+            )),
+            None => problems.push(format!("Target {} is missing", name)),
+        }
+    }
+    for name in live.targets.keys() {
+        if !recorded.targets.contains_key(name) {
+            problems.push(format!("Target {} isn't in the manifest", name));
+        }
+    }
+    problems
+}
+
+/// Records the state of the repo whose metadata was just written to the given directory, signed
+/// with the given keys, in a repo manifest in the same directory.
+pub(crate) fn write_repo_manifest(
+    root_role_path: &Path,
+    metadata_dir: &Path,
+    variant: &str,
+    arch: &str,
+    key_sources: &[Box<dyn KeySource>],
+) -> Result<()> {
+    let metadata_dir = metadata_dir
+        .canonicalize()
+        .context(error::MetadataDirSnafu { path: metadata_dir })?;
+    let metadata_url =
+        Url::from_directory_path(&metadata_dir)
+            .ok()
+            .context(error::MetadataUrlSnafu {
+                path: &metadata_dir,
+            })?;
+    // Loading the repo doesn't read any targets, so the metadata URL can stand in for the targets
+    // URL; the targets may not be local.
+    let repo = load_repo(root_role_path, &metadata_url, &metadata_url)?;
+    let state = RepoState::new(&repo, variant, arch, Utc::now());
+    let signatures = sign(&repo.root().signed, &state, key_sources)?;
+    let manifest = RepoManifest {
+        signed: state,
+        signatures,
+    };
+
+    let path = metadata_dir.join(MANIFEST_FILE);
+    info!("Writing repo manifest to: {}", path.display());
+    let mut data = serde_json::to_vec_pretty(&manifest).context(error::SerializeSnafu)?;
+    data.push(b'\n');
+    fs::write(&path, data).context(error::WriteSnafu { path: &path })
+}
+
+pub(crate) fn load_repo(
+    root_role_path: &Path,
+    metadata_url: &Url,
+    targets_url: &Url,
+) -> Result<Repository> {
+    let repo = RepositoryLoader::new(
+        File::open(root_role_path).context(repo_error::FileSnafu {
+            path: root_role_path,
+        })?,
+        metadata_url.clone(),
+        targets_url.clone(),
+    )
+    .load()
+    .context(repo_error::RepoLoadSnafu {
+        metadata_base_url: metadata_url.clone(),
+    })?;
+    Ok(repo)
+}
+
+/// Reads the repo manifest from the given file, or else from the repo's metadata URL.
+fn read_manifest(path: Option<&PathBuf>, metadata_url: &Url) -> Result<RepoManifest> {
+    if let Some(path) = path {
+        let file = File::open(path).context(repo_error::FileSnafu { path })?;
+        return serde_json::from_reader(file).context(error::ParseManifestSnafu {
+            location: path.display().to_string(),
+        });
+    }
+    let url_str = format!(
+        "{}/{}",
+        metadata_url.as_str().trim_end_matches('/'),
+        MANIFEST_FILE
+    );
+    let url = Url::parse(&url_str).context(repo_error::ParseUrlSnafu { input: &url_str })?;
+    let reader = DefaultTransport::new()
+        .fetch(url.clone())
+        .context(error::FetchManifestSnafu { url: url.clone() })?;
+    serde_json::from_reader(reader).context(error::ParseManifestSnafu {
+        location: url.to_string(),
+    })
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, verify_args: &VerifyRepoManifestArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&verify_args.repo)
+        .context(repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &verify_args.repo),
+        })?;
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &verify_args.variant, &verify_args.arch)?.context(
+            repo_error::MissingRepoUrlsSnafu {
+                repo: &verify_args.repo,
+            },
+        )?;
+
+    let repo = load_repo(&verify_args.root_role_path, &metadata_url, targets_url)?;
+    info!("Loaded TUF repo: {}", metadata_url);
+    let manifest = read_manifest(verify_args.manifest_path.as_ref(), &metadata_url)?;
+    // Check the signatures against the repo's current root, in case its keys were rotated.
+    verify_manifest(&repo.root().signed, &manifest)?;
+
+    let live = RepoState::new(&repo, &verify_args.variant, &verify_args.arch, Utc::now());
+    let mut problems = compare(&manifest.signed, &live);
+    if verify_args.verify_targets {
+        info!("Downloading {} targets to verify them", live.targets.len());
+        for (target, _) in repo.all_targets() {
+            if let Err(e) = download_targets(&repo, target.clone()) {
+                problems.push(e.to_string());
+            }
+        }
+    }
+
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    ensure!(
+        problems.is_empty(),
+        error::MismatchSnafu {
+            problems: problems.len()
+        }
+    );
+    info!(
+        "Repo matches the manifest recorded at {}",
+        manifest.signed.recorded_at.to_rfc3339()
+    );
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+    use url::Url;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(context(false), display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to fetch repo manifest from '{}': {}", url, source))]
+        FetchManifest {
+            url: Url,
+            source: tough::TransportError,
+        },
+
+        #[snafu(display("Failed to get key ID: {}", source))]
+        KeyId { source: tough::schema::Error },
+
+        #[snafu(display("Failed to get key from key source: {}", source))]
+        KeySource {
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display("Failed to find metadata directory '{}': {}", path.display(), source))]
+        MetadataDir {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to make a file URL from '{}'", path.display()))]
+        MetadataUrl { path: PathBuf },
+
+        #[snafu(display("Repo differs from its manifest in {} ways; see above", problems))]
+        Mismatch { problems: usize },
+
+        #[snafu(display("root.json has no keys for the targets role"))]
+        MissingTargetsRole,
+
+        #[snafu(display("Invalid repo manifest at '{}': {}", location, source))]
+        ParseManifest {
+            location: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to serialize manifest: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to sign manifest: {}", source))]
+        Sign {
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display(
+            "Manifest has {} valid signatures from targets keys, but needs {}",
+            valid,
+            threshold
+        ))]
+        UnsignedManifest { valid: usize, threshold: u64 },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::Mismatch { .. } | Self::UnsignedManifest { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, RepoState};
+    use crate::repo::diff_repo::TargetSummary;
+    use chrono::{DateTime, Utc};
+    use std::collections::BTreeMap;
+    use std::num::NonZeroU64;
+
+    fn state(targets_version: u64, sha256: &str) -> RepoState {
+        let mut roles = BTreeMap::new();
+        roles.insert(
+            "targets".to_string(),
+            NonZeroU64::new(targets_version).unwrap(),
+        );
+        let mut targets = BTreeMap::new();
+        targets.insert(
+            "a.img".to_string(),
+            TargetSummary {
+                length: 1,
+                sha256: sha256.to_string(),
+            },
+        );
+        RepoState {
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            recorded_at: "2023-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+            roles,
+            expirations: BTreeMap::new(),
+            targets,
+        }
+    }
+
+    #[test]
+    fn matching_state() {
+        assert!(compare(&state(1, "ab"), &state(1, "ab")).is_empty());
+    }
+
+    #[test]
+    fn changed_state() {
+        let mut live = state(2, "cd");
+        live.targets.insert(
+            "b.img".to_string(),
+            TargetSummary {
+                length: 1,
+                sha256: "ef".to_string(),
+            },
+        );
+        assert_eq!(compare(&state(1, "ab"), &live).len(), 3);
+    }
+}
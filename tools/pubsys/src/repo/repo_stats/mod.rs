@@ -0,0 +1,391 @@
+//! The repo_stats module owns the 'repo-stats' subcommand, which reports how large a repo's S3
+//! bucket is and what it's made of: the size of each kind of target, and how many versions of
+//! targets metadata each variant and architecture has.
+//!
+//! If given a stats file, it also reports how much the repo has grown since the stats recorded
+//! there, and then records the new stats, so running it after each release tracks growth over
+//! time.
+
+use crate::aws::rate_limit::RateLimits;
+use crate::repo::s3::{self, RepoBucket, RepoObject};
+use crate::Args;
+use chrono::{DateTime, Utc};
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+use tokio::runtime::Runtime;
+
+/// Reports the size and composition of a repo
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct RepoStatsArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Report growth since the stats recorded in this file, if it exists, then record the new
+    /// stats there
+    stats_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Print the stats as a JSON object instead of a plaintext table
+    json: bool,
+}
+
+/// The kinds of files in a repo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Category {
+    Images,
+    Migrations,
+    Kits,
+    Manifest,
+    OtherTargets,
+    Metadata,
+}
+
+impl Category {
+    /// Sorts a file of the bucket into a category by its path.
+    fn of(path: &str) -> Self {
+        let target = match path.strip_prefix("targets/") {
+            Some(target) => target,
+            None => return Category::Metadata,
+        };
+        // Targets signed by delegated roles, like kits, live in their own directories.
+        if target.contains('/') {
+            return Category::Kits;
+        }
+        // Strip the sha256 that consistent snapshots put in front of the name.
+        let name = target.split_once('.').map_or(target, |(_, name)| name);
+        if name.starts_with("migrate_") {
+            Category::Migrations
+        } else if name == "manifest.json" {
+            Category::Manifest
+        } else if name.contains(".img") || name.ends_with(".ova") {
+            Category::Images
+        } else {
+            Category::OtherTargets
+        }
+    }
+}
+
+/// The number and total size of files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStats {
+    files: usize,
+    bytes: i64,
+}
+
+impl FileStats {
+    fn add(&mut self, size: i64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// The size and composition of a repo at one point in time
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RepoStats {
+    recorded_at: DateTime<Utc>,
+    total: FileStats,
+    categories: BTreeMap<Category, FileStats>,
+    /// The number of versions of targets.json kept for each variant and architecture, like
+    /// `aws-k8s-1.24/x86_64`
+    targets_versions: BTreeMap<String, usize>,
+}
+
+impl RepoStats {
+    fn new(objects: &[RepoObject], recorded_at: DateTime<Utc>) -> Self {
+        let mut stats = Self {
+            recorded_at,
+            total: FileStats::default(),
+            categories: BTreeMap::new(),
+            targets_versions: BTreeMap::new(),
+        };
+        for object in objects {
+            stats.total.add(object.size);
+            stats
+                .categories
+                .entry(Category::of(&object.path))
+                .or_default()
+                .add(object.size);
+            if let Some((dir, filename)) = object.path.rsplit_once('/') {
+                let is_versioned_targets = filename
+                    .strip_suffix(".targets.json")
+                    .map_or(false, |version| version.parse::<u64>().is_ok());
+                if is_versioned_targets {
+                    *stats.targets_versions.entry(dir.to_string()).or_default() += 1;
+                }
+            }
+        }
+        stats
+    }
+}
+
+/// The change in size since previously recorded stats
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Growth {
+    since: DateTime<Utc>,
+    files: i64,
+    bytes: i64,
+    categories: BTreeMap<Category, i64>,
+}
+
+impl Growth {
+    fn between(previous: &RepoStats, current: &RepoStats) -> Self {
+        let bytes = |stats: &RepoStats, category| {
+            stats
+                .categories
+                .get(category)
+                .map_or(0, |file_stats| file_stats.bytes)
+        };
+        let categories = previous
+            .categories
+            .keys()
+            .chain(current.categories.keys())
+            .map(|category| {
+                (
+                    *category,
+                    bytes(current, category) - bytes(previous, category),
+                )
+            })
+            .collect();
+        Self {
+            since: previous.recorded_at,
+            files: current.total.files as i64 - previous.total.files as i64,
+            bytes: current.total.bytes - previous.total.bytes,
+            categories,
+        }
+    }
+}
+
+#[derive(Tabled)]
+struct CategoryRow {
+    category: String,
+    files: usize,
+    bytes: i64,
+    growth: String,
+}
+
+fn print_table(stats: &RepoStats, growth: Option<&Growth>) {
+    let growth_of = |category: Option<Category>| match (growth, category) {
+        (None, _) => "-".to_string(),
+        (Some(growth), None) => format!("{:+}", growth.bytes),
+        (Some(growth), Some(category)) => {
+            format!("{:+}", growth.categories.get(&category).unwrap_or(&0))
+        }
+    };
+    let mut rows: Vec<CategoryRow> = stats
+        .categories
+        .iter()
+        .map(|(category, file_stats)| CategoryRow {
+            category: serde_plain::to_string(category).unwrap_or_default(),
+            files: file_stats.files,
+            bytes: file_stats.bytes,
+            growth: growth_of(Some(*category)),
+        })
+        .collect();
+    rows.push(CategoryRow {
+        category: "total".to_string(),
+        files: stats.total.files,
+        bytes: stats.total.bytes,
+        growth: growth_of(None),
+    });
+    println!("{}", Table::new(rows));
+    if let Some(growth) = growth {
+        println!("Growth is since {}", growth.since.to_rfc3339());
+    }
+    for (dir, versions) in &stats.targets_versions {
+        println!("{}: {} versions of targets.json", dir, versions);
+    }
+}
+
+fn read_stats(path: &Path) -> Result<Option<RepoStats>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = File::open(path).context(error::StatsFileSnafu { path })?;
+    serde_json::from_reader(file)
+        .map(Some)
+        .context(error::ParseStatsSnafu { path })
+}
+
+fn write_stats(path: &Path, stats: &RepoStats) -> Result<()> {
+    let file = File::create(path).context(error::StatsFileSnafu { path })?;
+    serde_json::to_writer_pretty(file, stats).context(error::WriteStatsSnafu { path })
+}
+
+async fn repo_stats(args: &Args, stats_args: &RepoStatsArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let bucket =
+        RepoBucket::from_config(&infra_config, &stats_args.repo).context(error::S3Snafu)?;
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let client = bucket.client(&aws, &RateLimits::new(&aws)).await;
+
+    info!(
+        "Listing the files of repo '{}' in {}",
+        stats_args.repo, bucket.name
+    );
+    let objects = s3::list_objects(&client, &bucket, "")
+        .await
+        .context(error::S3Snafu)?;
+    let stats = RepoStats::new(&objects, Utc::now());
+
+    let previous = match &stats_args.stats_path {
+        Some(path) => read_stats(path)?,
+        None => None,
+    };
+    let growth = previous
+        .as_ref()
+        .map(|previous| Growth::between(previous, &stats));
+
+    if stats_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "repo": stats_args.repo,
+                "stats": stats,
+                "growth": growth,
+            }))
+            .context(error::SerializeStatsSnafu)?
+        );
+    } else {
+        print_table(&stats, growth.as_ref());
+    }
+
+    if let Some(path) = &stats_args.stats_path {
+        info!("Recording stats in {}", path.display());
+        write_stats(path, &stats)?;
+    }
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, stats_args: &RepoStatsArgs) -> Result<()> {
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    rt.block_on(repo_stats(args, stats_args))
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Invalid stats file '{}': {}", path.display(), source))]
+        ParseStats {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display("Failed to serialize stats to json: {}", source))]
+        SerializeStats { source: serde_json::Error },
+
+        #[snafu(display("Failed to access stats file '{}': {}", path.display(), source))]
+        StatsFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to write stats file '{}': {}", path.display(), source))]
+        WriteStats {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{Category, FileStats, Growth, RepoStats};
+    use crate::repo::s3::RepoObject;
+    use chrono::{DateTime, Utc};
+
+    fn object(path: &str, size: i64) -> RepoObject {
+        RepoObject {
+            path: path.to_string(),
+            size,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(
+            Category::of("targets/ab12.bottlerocket-aws-dev-x86_64-1.0.0-abc.img.lz4"),
+            Category::Images
+        );
+        assert_eq!(
+            Category::of("targets/ab12.migrate_v1.0.1_add-setting.lz4"),
+            Category::Migrations
+        );
+        assert_eq!(Category::of("targets/my-kit/ab12.a.rpm"), Category::Kits);
+        assert_eq!(
+            Category::of("targets/ab12.manifest.json"),
+            Category::Manifest
+        );
+        assert_eq!(
+            Category::of("targets/ab12.notes.txt"),
+            Category::OtherTargets
+        );
+        assert_eq!(
+            Category::of("aws-dev/x86_64/1234.targets.json"),
+            Category::Metadata
+        );
+    }
+
+    #[test]
+    fn stats_and_growth() {
+        let previous = RepoStats::new(
+            &[
+                object("targets/ab.a.img.lz4", 100),
+                object("aws-dev/x86_64/1.targets.json", 10),
+            ],
+            "2023-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+        );
+        let current = RepoStats::new(
+            &[
+                object("targets/ab.a.img.lz4", 100),
+                object("targets/cd.b.img.lz4", 50),
+                object("aws-dev/x86_64/1.targets.json", 10),
+                object("aws-dev/x86_64/2.targets.json", 12),
+                object("aws-dev/x86_64/timestamp.json", 5),
+            ],
+            "2023-02-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+        );
+        assert_eq!(
+            current.total,
+            FileStats {
+                files: 5,
+                bytes: 177
+            }
+        );
+        assert_eq!(current.targets_versions.get("aws-dev/x86_64"), Some(&2));
+
+        let growth = Growth::between(&previous, &current);
+        assert_eq!(growth.files, 3);
+        assert_eq!(growth.bytes, 67);
+        assert_eq!(growth.categories.get(&Category::Images), Some(&50));
+        assert_eq!(growth.categories.get(&Category::Metadata), Some(&17));
+    }
+}
@@ -0,0 +1,845 @@
+//! The s3 module finds a repo's files in the S3 bucket that infrasys created for it, and provides
+//! the S3 calls that subcommands need to work with them.
+//!
+//! Within the bucket's prefix, metadata lives under `<variant>/<arch>/` and targets under
+//! `targets/`; paths in this module are relative to the prefix.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::RateLimits;
+use crate::aws::tags::s3_tagging;
+use crate::exit_code::{Classify, Kind};
+use crate::repo::repo_manifest;
+use aws_sdk_s3::model::{
+    ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload, CompletedPart, Delete,
+    ObjectIdentifier,
+};
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use aws_sdk_s3::{Client as S3Client, Config as S3ClientConfig, Region};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::{self, StreamExt};
+use log::{debug, warn};
+use pubsys_config::{AwsConfig, InfraConfig};
+use ring::digest::{digest, Context, SHA256};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs::File;
+use std::io::{Read, SeekFrom};
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// S3 accepts at most this many keys in a DeleteObjects call.
+const MAX_DELETE_BATCH: usize = 1000;
+
+/// S3 requires every part of a multipart upload but the last to be at least this large.
+pub(crate) const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The user metadata key under which we record the hex-encoded sha256 of each uploaded file.  S3
+/// only records a whole-file sha256 checksum for single-part uploads, so we need our own for
+/// files uploaded in parts.
+const SHA256_METADATA_KEY: &str = "sha256";
+
+/// The location of a repo's files in S3
+#[derive(Debug, Clone)]
+pub(crate) struct RepoBucket {
+    pub(crate) region: Region,
+    pub(crate) name: String,
+    /// The key prefix, without leading or trailing slashes
+    prefix: String,
+    /// The default tags from Infra.toml, in the form S3 takes for object tagging
+    tagging: Option<String>,
+}
+
+impl RepoBucket {
+    /// Finds the bucket of the given repo, using the `aws.s3` entry named by the repo's
+    /// `file_hosting_config_name`.
+    pub(crate) fn from_config(infra_config: &InfraConfig, repo: &str) -> Result<Self> {
+        let repo_config = infra_config
+            .repo
+            .as_ref()
+            .and_then(|repo_section| repo_section.get(repo))
+            .context(error::MissingConfigSnafu {
+                missing: format!("definition for repo {}", repo),
+            })?;
+        let s3_name =
+            repo_config
+                .file_hosting_config_name
+                .as_ref()
+                .context(error::MissingConfigSnafu {
+                    missing: format!("file_hosting_config_name for repo {}", repo),
+                })?;
+        let s3_config = infra_config
+            .aws
+            .as_ref()
+            .and_then(|aws| aws.s3.as_ref())
+            .and_then(|s3| s3.get(s3_name))
+            .context(error::MissingConfigSnafu {
+                missing: format!("aws.s3 config with name {}", s3_name),
+            })?;
+        Ok(Self {
+            region: Region::new(
+                s3_config
+                    .region
+                    .clone()
+                    .context(error::MissingConfigSnafu {
+                        missing: format!("region for '{}' s3 config", s3_name),
+                    })?,
+            ),
+            name: s3_config
+                .bucket_name
+                .clone()
+                .context(error::MissingConfigSnafu {
+                    missing: format!("bucket_name for '{}' s3 config", s3_name),
+                })?,
+            prefix: s3_config.s3_prefix.trim_matches('/').to_string(),
+            tagging: infra_config.aws.as_ref().and_then(s3_tagging),
+        })
+    }
+
+    /// Returns the S3 key of the given path.
+    pub(crate) fn key(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+
+    /// Returns the path of the given S3 key, if it's within the repo's prefix.
+    pub(crate) fn path<'a>(&self, key: &'a str) -> Option<&'a str> {
+        if self.prefix.is_empty() {
+            Some(key)
+        } else {
+            key.strip_prefix(&self.prefix)?.strip_prefix('/')
+        }
+    }
+
+    /// Builds a client for the bucket's region whose requests share the given rate limits.
+    pub(crate) async fn client(&self, aws: &AwsConfig, rate_limits: &RateLimits) -> RepoClient {
+        RepoClient {
+            s3: S3Client::from_pubsys_config(
+                &build_client_config(&self.region, &self.region, aws).await,
+                aws,
+            ),
+            rate_limits: rate_limits.clone(),
+            list_page_size: page_size::get(aws, |page_size| page_size.list_objects),
+        }
+    }
+
+    /// Builds a client for the bucket's region that uses S3 Transfer Acceleration, which must be
+    /// enabled on the bucket, and whose requests share the given rate limits.
+    pub(crate) async fn accelerated_client(
+        &self,
+        aws: &AwsConfig,
+        rate_limits: &RateLimits,
+    ) -> RepoClient {
+        let sdk_config = build_client_config(&self.region, &self.region, aws).await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config).accelerate(true);
+        if let Some(url) = aws.service_endpoint_url(S3Client::SERVICE) {
+            builder = builder.endpoint_url(url.as_str());
+        }
+        RepoClient {
+            s3: S3Client::from_conf(builder.build()),
+            rate_limits: rate_limits.clone(),
+            list_page_size: page_size::get(aws, |page_size| page_size.list_objects),
+        }
+    }
+}
+
+/// A client for a repo's bucket, and the rate limits its requests share with the rest of the
+/// operation
+#[derive(Clone)]
+pub(crate) struct RepoClient {
+    s3: S3Client,
+    rate_limits: RateLimits,
+    /// The most objects in each page of a listing, if not S3's default
+    list_page_size: Option<i32>,
+}
+
+/// How files are split up for upload; files larger than one part are uploaded in parts
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MultipartOptions {
+    /// The size of each part in bytes, at least `MIN_PART_SIZE`
+    pub(crate) part_size: u64,
+    /// The number of parts of a file to upload at once
+    pub(crate) max_concurrent_parts: usize,
+}
+
+impl Default for MultipartOptions {
+    fn default() -> Self {
+        Self {
+            part_size: 64 * 1024 * 1024,
+            max_concurrent_parts: 4,
+        }
+    }
+}
+
+/// A file in a repo's bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RepoObject {
+    /// The path of the file, relative to the repo's prefix
+    pub(crate) path: String,
+    pub(crate) size: i64,
+    /// When the file was last written, if S3 said
+    pub(crate) last_modified: Option<DateTime<Utc>>,
+}
+
+/// Returns the stage in which the file at the given path should be uploaded.  Each stage is
+/// uploaded fully before the next starts, so that a repo never refers to a file it doesn't have:
+/// targets (0), then versioned metadata (1), then timestamp.json and the repo manifest that
+/// describes the finished repo (2).
+pub(crate) fn upload_stage(path: &str) -> u8 {
+    if path.starts_with("targets/") {
+        0
+    } else if path.ends_with("timestamp.json") || path.ends_with(repo_manifest::MANIFEST_FILE) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Computes the sha256 of a local file, to be checked by S3 on upload.
+pub(crate) fn sha256_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path).context(error::HashFileSnafu { path })?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let count = file
+            .read(&mut buffer)
+            .context(error::HashFileSnafu { path })?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+    }
+    Ok(context.finish().as_ref().to_vec())
+}
+
+/// Lists the files in the bucket whose paths start with the given string.
+pub(crate) async fn list_objects(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path_prefix: &str,
+) -> Result<Vec<RepoObject>> {
+    let mut objects = Vec::new();
+    let mut paginator = client
+        .s3
+        .list_objects_v2()
+        .bucket(&bucket.name)
+        .prefix(bucket.key(path_prefix))
+        .into_paginator();
+    if let Some(page_size) = client.list_page_size {
+        paginator = paginator.page_size(page_size);
+    }
+    let mut pages = paginator.send();
+    while let Some(page) = client.rate_limits.limited(pages.next()).await {
+        let page = page.context(error::ListObjectsSnafu {
+            bucket: &bucket.name,
+        })?;
+        for object in page.contents().unwrap_or_default() {
+            if let Some(path) = object.key().and_then(|key| bucket.path(key)) {
+                objects.push(RepoObject {
+                    path: path.to_string(),
+                    size: object.size(),
+                    last_modified: object.last_modified().and_then(|time| {
+                        Utc.timestamp_opt(time.secs(), time.subsec_nanos()).single()
+                    }),
+                });
+            }
+        }
+    }
+    debug!(
+        "Found {} objects under '{}' in {}",
+        objects.len(),
+        path_prefix,
+        bucket.name
+    );
+    Ok(objects)
+}
+
+/// Downloads the file at the given path.
+pub(crate) async fn get_object(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+) -> Result<Vec<u8>> {
+    let key = bucket.key(path);
+    let output = client
+        .rate_limits
+        .limited(client.s3.get_object().bucket(&bucket.name).key(&key).send())
+        .await
+        .context(error::GetObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    let mut data = Vec::new();
+    output
+        .body
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .context(error::ReadObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    Ok(data)
+}
+
+/// Downloads the file at the given path to a local file.
+pub(crate) async fn download_object(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+    local_path: &Path,
+) -> Result<()> {
+    let key = bucket.key(path);
+    let output = client
+        .rate_limits
+        .limited(client.s3.get_object().bucket(&bucket.name).key(&key).send())
+        .await
+        .context(error::GetObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    let mut file = tokio::fs::File::create(local_path)
+        .await
+        .context(error::LocalFileSnafu { path: local_path })?;
+    tokio::io::copy(&mut output.body.into_async_read(), &mut file)
+        .await
+        .context(error::ReadObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    Ok(())
+}
+
+/// Uploads a local file to the given path.  S3 checks the file against the given sha256, or each
+/// part against its own sha256 for files uploaded in parts, so a file that's corrupted on the way
+/// is rejected rather than stored.
+pub(crate) async fn upload_object(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+    local_path: &Path,
+    sha256: &[u8],
+    multipart: &MultipartOptions,
+) -> Result<()> {
+    ensure!(
+        multipart.part_size >= MIN_PART_SIZE,
+        error::PartSizeSnafu {
+            part_size: multipart.part_size
+        }
+    );
+    let size = tokio::fs::metadata(local_path)
+        .await
+        .context(error::LocalFileSnafu { path: local_path })?
+        .len();
+    if size > multipart.part_size {
+        return upload_multipart(client, bucket, path, local_path, sha256, size, multipart).await;
+    }
+
+    let key = bucket.key(path);
+    let body = ByteStream::from_path(local_path)
+        .await
+        .context(error::ByteStreamSnafu { path: local_path })?;
+    client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .put_object()
+                .bucket(&bucket.name)
+                .key(&key)
+                .checksum_sha256(base64::encode(sha256))
+                .metadata(SHA256_METADATA_KEY, hex::encode(sha256))
+                .set_tagging(bucket.tagging.clone())
+                .body(body)
+                .send(),
+        )
+        .await
+        .context(error::PutObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    Ok(())
+}
+
+/// Writes the given data, like a small JSON document, to the given path.
+pub(crate) async fn put_object(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+    data: Vec<u8>,
+) -> Result<()> {
+    let key = bucket.key(path);
+    client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .put_object()
+                .bucket(&bucket.name)
+                .key(&key)
+                .set_tagging(bucket.tagging.clone())
+                .body(ByteStream::from(data))
+                .send(),
+        )
+        .await
+        .context(error::PutObjectSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    Ok(())
+}
+
+/// Uploads a local file in parts, several at once.  If any part fails, the upload is aborted so
+/// S3 doesn't keep the parts that made it.
+async fn upload_multipart(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+    local_path: &Path,
+    sha256: &[u8],
+    size: u64,
+    multipart: &MultipartOptions,
+) -> Result<()> {
+    let key = bucket.key(path);
+    let output = client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .create_multipart_upload()
+                .bucket(&bucket.name)
+                .key(&key)
+                .checksum_algorithm(ChecksumAlgorithm::Sha256)
+                .metadata(SHA256_METADATA_KEY, hex::encode(sha256))
+                .set_tagging(bucket.tagging.clone())
+                .send(),
+        )
+        .await
+        .context(error::CreateMultipartUploadSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    let upload_id = output
+        .upload_id()
+        .context(error::MissingUploadIdSnafu { key: &key })?;
+
+    let part_count = (size + multipart.part_size - 1) / multipart.part_size;
+    debug!("Uploading {} in {} parts", key, part_count);
+    let parts: Result<Vec<CompletedPart>> = stream::iter(0..part_count)
+        .map(|index| {
+            let offset = index * multipart.part_size;
+            let length = multipart.part_size.min(size - offset);
+            upload_part(
+                client, bucket, &key, upload_id, local_path, index, offset, length,
+            )
+        })
+        .buffered(multipart.max_concurrent_parts)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect();
+    let parts = match parts {
+        Ok(parts) => parts,
+        Err(e) => {
+            if let Err(abort_error) = client
+                .rate_limits
+                .limited(
+                    client
+                        .s3
+                        .abort_multipart_upload()
+                        .bucket(&bucket.name)
+                        .key(&key)
+                        .upload_id(upload_id)
+                        .send(),
+                )
+                .await
+            {
+                warn!(
+                    "Failed to abort upload of {}; its parts may remain in {}: {}",
+                    key, bucket.name, abort_error
+                );
+            }
+            return Err(e);
+        }
+    };
+
+    client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .complete_multipart_upload()
+                .bucket(&bucket.name)
+                .key(&key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send(),
+        )
+        .await
+        .context(error::CompleteMultipartUploadSnafu {
+            bucket: &bucket.name,
+            key: &key,
+        })?;
+    Ok(())
+}
+
+/// Uploads one part of a multipart upload, returning what S3 needs to complete the upload.
+#[allow(clippy::too_many_arguments)]
+async fn upload_part(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    key: &str,
+    upload_id: &str,
+    local_path: &Path,
+    index: u64,
+    offset: u64,
+    length: u64,
+) -> Result<CompletedPart> {
+    let mut file = tokio::fs::File::open(local_path)
+        .await
+        .context(error::LocalFileSnafu { path: local_path })?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .context(error::LocalFileSnafu { path: local_path })?;
+    let mut data = vec![0; length as usize];
+    file.read_exact(&mut data)
+        .await
+        .context(error::LocalFileSnafu { path: local_path })?;
+
+    // Part numbers start at 1.
+    let part_number = index as i32 + 1;
+    let part_sha256 = base64::encode(digest(&SHA256, &data));
+    let output = client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .upload_part()
+                .bucket(&bucket.name)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .checksum_sha256(&part_sha256)
+                .body(ByteStream::from(data))
+                .send(),
+        )
+        .await
+        .context(error::UploadPartSnafu {
+            bucket: &bucket.name,
+            key,
+            part_number,
+        })?;
+    Ok(CompletedPart::builder()
+        .part_number(part_number)
+        .set_e_tag(output.e_tag().map(String::from))
+        .checksum_sha256(part_sha256)
+        .build())
+}
+
+/// Returns the sha256 recorded for the file at the given path when it was uploaded, or None if the
+/// file doesn't exist or was uploaded without one.
+pub(crate) async fn object_sha256(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    path: &str,
+) -> Result<Option<Vec<u8>>> {
+    let key = bucket.key(path);
+    let output = match client
+        .rate_limits
+        .limited(
+            client
+                .s3
+                .head_object()
+                .bucket(&bucket.name)
+                .key(&key)
+                .checksum_mode(ChecksumMode::Enabled)
+                .send(),
+        )
+        .await
+    {
+        Ok(output) => output,
+        Err(SdkError::ServiceError(service_error)) if service_error.err().is_not_found() => {
+            return Ok(None)
+        }
+        Err(e) => {
+            return Err(e).context(error::HeadObjectSnafu {
+                bucket: &bucket.name,
+                key: &key,
+            })
+        }
+    };
+    // Prefer the sha256 we record ourselves; S3's checksum for a multipart upload is a checksum of
+    // the parts' checksums, like "abc=-3", which doesn't decode.  A checksum we can't decode can't
+    // match, so we treat it like a missing one.
+    let recorded = output
+        .metadata()
+        .and_then(|metadata| metadata.get(SHA256_METADATA_KEY))
+        .and_then(|sha256| hex::decode(sha256).ok());
+    Ok(recorded.or_else(|| {
+        output
+            .checksum_sha256()
+            .and_then(|checksum| base64::decode(checksum).ok())
+    }))
+}
+
+/// Deletes the files at the given paths, in batches.
+pub(crate) async fn delete_objects(
+    client: &RepoClient,
+    bucket: &RepoBucket,
+    paths: &[String],
+) -> Result<()> {
+    for batch in paths.chunks(MAX_DELETE_BATCH) {
+        let objects = batch
+            .iter()
+            .map(|path| ObjectIdentifier::builder().key(bucket.key(path)).build())
+            .collect();
+        let output = client
+            .rate_limits
+            .limited(
+                client
+                    .s3
+                    .delete_objects()
+                    .bucket(&bucket.name)
+                    .delete(Delete::builder().set_objects(Some(objects)).build())
+                    .send(),
+            )
+            .await
+            .context(error::DeleteObjectsSnafu {
+                bucket: &bucket.name,
+            })?;
+        let failures = output.errors().unwrap_or_default();
+        if let Some(failure) = failures.first() {
+            return error::DeleteFailedSnafu {
+                bucket: &bucket.name,
+                count: failures.len(),
+                key: failure.key().unwrap_or_default(),
+                message: failure.message().unwrap_or_default(),
+            }
+            .fail();
+        }
+        debug!("Deleted {} objects from {}", batch.len(), bucket.name);
+    }
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_s3::error::{
+        CompleteMultipartUploadError, CreateMultipartUploadError, DeleteObjectsError,
+        GetObjectError, HeadObjectError, ListObjectsV2Error, PutObjectError, UploadPartError,
+    };
+    use aws_sdk_s3::types::SdkError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to read '{}' for upload: {}", path.display(), source))]
+        ByteStream {
+            path: PathBuf,
+            source: aws_smithy_http::byte_stream::error::Error,
+        },
+
+        #[snafu(display(
+            "Failed to complete upload of '{}' to {}: {}",
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        CompleteMultipartUpload {
+            bucket: String,
+            key: String,
+            source: SdkError<CompleteMultipartUploadError>,
+        },
+
+        #[snafu(display(
+            "Failed to start upload of '{}' to {}: {}",
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        CreateMultipartUpload {
+            bucket: String,
+            key: String,
+            source: SdkError<CreateMultipartUploadError>,
+        },
+
+        #[snafu(display(
+            "Failed to delete {} objects from {}, including '{}': {}",
+            count,
+            bucket,
+            key,
+            message
+        ))]
+        DeleteFailed {
+            bucket: String,
+            count: usize,
+            key: String,
+            message: String,
+        },
+
+        #[snafu(display(
+            "Failed to delete objects from {}: {}",
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        DeleteObjects {
+            bucket: String,
+            source: SdkError<DeleteObjectsError>,
+        },
+
+        #[snafu(display(
+            "Failed to get '{}' from {}: {}",
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        GetObject {
+            bucket: String,
+            key: String,
+            source: SdkError<GetObjectError>,
+        },
+
+        #[snafu(display("Failed to hash '{}': {}", path.display(), source))]
+        HashFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display(
+            "Failed to check '{}' in {}: {}",
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        HeadObject {
+            bucket: String,
+            key: String,
+            source: SdkError<HeadObjectError>,
+        },
+
+        #[snafu(display(
+            "Failed to list objects in {}: {}",
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        ListObjects {
+            bucket: String,
+            source: SdkError<ListObjectsV2Error>,
+        },
+
+        #[snafu(display("Failed to access '{}': {}", path.display(), source))]
+        LocalFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("S3 returned no upload ID for '{}'", key))]
+        MissingUploadId { key: String },
+
+        #[snafu(display(
+            "Part size {} is smaller than the {} bytes S3 requires",
+            part_size,
+            super::MIN_PART_SIZE
+        ))]
+        PartSize { part_size: u64 },
+
+        #[snafu(display(
+            "Failed to put '{}' in {}: {}",
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        PutObject {
+            bucket: String,
+            key: String,
+            source: SdkError<PutObjectError>,
+        },
+
+        #[snafu(display("Failed to read '{}' from {}: {}", key, bucket, source))]
+        ReadObject {
+            bucket: String,
+            key: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display(
+            "Failed to upload part {} of '{}' to {}: {}",
+            part_number,
+            key,
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        UploadPart {
+            bucket: String,
+            key: String,
+            part_number: i32,
+            source: SdkError<UploadPartError>,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::CompleteMultipartUpload { .. }
+            | Self::CreateMultipartUpload { .. }
+            | Self::DeleteObjects { .. }
+            | Self::GetObject { .. }
+            | Self::HeadObject { .. }
+            | Self::ListObjects { .. }
+            | Self::PutObject { .. }
+            | Self::UploadPart { .. } => Some(Kind::AwsApi),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{upload_stage, RepoBucket};
+    use aws_sdk_s3::Region;
+
+    fn bucket(prefix: &str) -> RepoBucket {
+        RepoBucket {
+            region: Region::new("us-west-2"),
+            name: String::from("bucket"),
+            prefix: prefix.to_string(),
+            tagging: None,
+        }
+    }
+
+    #[test]
+    fn keys_and_paths() {
+        let prefixed = bucket("my/repo");
+        assert_eq!(prefixed.key("targets/a.img"), "my/repo/targets/a.img");
+        assert_eq!(
+            prefixed.path("my/repo/targets/a.img"),
+            Some("targets/a.img")
+        );
+        assert_eq!(prefixed.path("my/repository/a.img"), None);
+
+        let unprefixed = bucket("");
+        assert_eq!(unprefixed.key("targets/a.img"), "targets/a.img");
+        assert_eq!(unprefixed.path("targets/a.img"), Some("targets/a.img"));
+    }
+
+    #[test]
+    fn stages() {
+        assert_eq!(upload_stage("targets/ab12.a.img"), 0);
+        assert_eq!(upload_stage("aws-dev/x86_64/1234.snapshot.json"), 1);
+        assert_eq!(upload_stage("aws-dev/x86_64/timestamp.json"), 2);
+    }
+}
@@ -0,0 +1,61 @@
+//! The secret_key module provides a tough `KeySource` for private keys stored in AWS Secrets
+//! Manager or as SSM SecureString parameters.  The key is fetched with the credentials from the
+//! `aws` section of Infra.toml each time it's used, and is only kept in memory.
+
+use crate::aws::secrets::get_secret;
+use pubsys_config::AwsConfig as PubsysAwsConfig;
+use snafu::ResultExt;
+use std::error::Error as StdError;
+use tokio::runtime::Runtime;
+use tough::key_source::KeySource;
+use tough::sign::{parse_keypair, Sign};
+
+type BoxedError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// A private key, in PEM format, stored as a secret in AWS
+#[derive(Debug)]
+pub(crate) struct SecretKeySource {
+    /// ARN of the Secrets Manager secret or SSM parameter holding the key
+    pub(crate) arn: String,
+    /// The AWS config from Infra.toml, whose credentials are used to fetch the secret
+    pub(crate) aws: PubsysAwsConfig,
+}
+
+impl KeySource for SecretKeySource {
+    fn as_sign(&self) -> std::result::Result<Box<dyn Sign>, BoxedError> {
+        let rt = Runtime::new().context(error::RuntimeSnafu)?;
+        let key = rt
+            .block_on(get_secret(&self.arn, &self.aws))
+            .context(error::GetSecretSnafu)?;
+        let keypair =
+            parse_keypair(key.as_bytes()).context(error::ParseKeySnafu { arn: &self.arn })?;
+        Ok(Box::new(keypair))
+    }
+
+    fn write(&self, _value: &str, _key_id_hex: &str) -> std::result::Result<(), BoxedError> {
+        Err(error::WriteUnsupportedSnafu.build().into())
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("{}", source))]
+        GetSecret { source: crate::aws::secrets::Error },
+
+        #[snafu(display("Secret '{}' is not a valid private key: {}", arn, source))]
+        ParseKey {
+            arn: String,
+            source: tough::error::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Keys can't be written to AWS secrets by pubsys"))]
+        WriteUnsupported,
+    }
+}
@@ -0,0 +1,222 @@
+//! The sync_repo module owns the 'sync-repo' subcommand, which mirrors the files of one repo's S3
+//! bucket into another's, like a disaster recovery copy in another region.
+//!
+//! Files are copied through the local machine, so the buckets can be in different regions or
+//! accounts.  Targets are copied before metadata, and timestamp.json last, so the mirror never
+//! refers to files it doesn't have yet.  Each file's sha256 is computed as it's copied; S3 checks
+//! the upload against it, and for targets, which are named by their sha256, we check that the
+//! source file matches its name.
+
+use crate::aws::rate_limit::RateLimits;
+use crate::repo::s3::{self, MultipartOptions, RepoBucket, RepoClient, RepoObject};
+use crate::Args;
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
+use snafu::{ensure, ResultExt};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use structopt::{clap, StructOpt};
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+
+/// Copies the metadata and targets of one repo to another
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct SyncRepoArgs {
+    #[structopt(long)]
+    /// Copy from this named repo infrastructure from Infra.toml
+    source_repo: String,
+
+    #[structopt(long)]
+    /// Copy to this named repo infrastructure from Infra.toml
+    destination_repo: String,
+
+    #[structopt(long, default_value = "8")]
+    /// The number of files to copy at once
+    max_concurrent_copies: NonZeroUsize,
+}
+
+/// Returns whether the file at the given path never changes once written, because its name
+/// includes its sha256 or version.  Other files, like timestamp.json, are always copied.
+fn is_immutable(path: &str) -> bool {
+    if path.starts_with("targets/") {
+        return true;
+    }
+    let filename = path.rsplit('/').next().unwrap_or(path);
+    match filename.split_once('.') {
+        Some((version, _)) => !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Returns the sha256 that a target is named by, if the path is a target.
+fn target_sha256(path: &str) -> Option<&str> {
+    path.strip_prefix("targets/")?
+        .rsplit('/')
+        .next()?
+        .split_once('.')
+        .map(|(sha256, _)| sha256)
+}
+
+/// Copies a single file from one bucket to the other.
+async fn copy_object(
+    source_client: &RepoClient,
+    source: &RepoBucket,
+    destination_client: &RepoClient,
+    destination: &RepoBucket,
+    path: &str,
+) -> Result<()> {
+    trace!("Copying {}", path);
+    let file = NamedTempFile::new().context(error::TempFileSnafu)?;
+    s3::download_object(source_client, source, path, file.path())
+        .await
+        .context(error::S3Snafu)?;
+    let sha256 = s3::sha256_file(file.path()).context(error::S3Snafu)?;
+    if let Some(expected) = target_sha256(path) {
+        ensure!(
+            hex::encode(&sha256) == expected,
+            error::HashMismatchSnafu { path }
+        );
+    }
+    s3::upload_object(
+        destination_client,
+        destination,
+        path,
+        file.path(),
+        &sha256,
+        &MultipartOptions::default(),
+    )
+    .await
+    .context(error::S3Snafu)
+}
+
+async fn sync_repo(args: &Args, sync_args: &SyncRepoArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    let source =
+        RepoBucket::from_config(&infra_config, &sync_args.source_repo).context(error::S3Snafu)?;
+    let destination = RepoBucket::from_config(&infra_config, &sync_args.destination_repo)
+        .context(error::S3Snafu)?;
+    let rate_limits = RateLimits::new(&aws);
+    let source_client = source.client(&aws, &rate_limits).await;
+    let destination_client = destination.client(&aws, &rate_limits).await;
+
+    info!(
+        "Comparing repo '{}' in {} to repo '{}' in {}",
+        sync_args.source_repo, source.name, sync_args.destination_repo, destination.name
+    );
+    let source_objects = s3::list_objects(&source_client, &source, "")
+        .await
+        .context(error::S3Snafu)?;
+    let existing: HashMap<String, i64> = s3::list_objects(&destination_client, &destination, "")
+        .await
+        .context(error::S3Snafu)?
+        .into_iter()
+        .map(|object| (object.path, object.size))
+        .collect();
+
+    let mut to_copy: Vec<RepoObject> = source_objects
+        .into_iter()
+        .filter(|object| {
+            !(is_immutable(&object.path) && existing.get(&object.path) == Some(&object.size))
+        })
+        .collect();
+    to_copy.sort_by_key(|object| s3::upload_stage(&object.path));
+    let size: i64 = to_copy.iter().map(|object| object.size).sum();
+    info!("Copying {} files, totaling {} bytes", to_copy.len(), size);
+
+    // Copy each stage fully before starting the next, so the destination never refers to a file
+    // it doesn't have.
+    let mut failures = 0;
+    for stage in 0..=2 {
+        let paths = to_copy
+            .iter()
+            .filter(|object| s3::upload_stage(&object.path) == stage)
+            .map(|object| object.path.as_str());
+        let copies = paths.map(|path| {
+            let (source_client, source) = (&source_client, &source);
+            let (destination_client, destination) = (&destination_client, &destination);
+            (path.to_string(), async move {
+                let result =
+                    copy_object(source_client, source, destination_client, destination, path).await;
+                (path, result)
+            })
+        });
+        let results: Vec<(&str, Result<()>)> =
+            stream::iter(args.state.interrupt.tracked("repo file copies", copies))
+                .buffer_unordered(sync_args.max_concurrent_copies.get())
+                .collect()
+                .await;
+        for (path, result) in results {
+            if let Err(e) = result {
+                error!("Failed to copy {}: {}", path, e);
+                failures += 1;
+            }
+        }
+        ensure!(failures == 0, error::CopySnafu { failures });
+    }
+
+    info!(
+        "Repo '{}' now mirrors repo '{}'",
+        sync_args.destination_repo, sync_args.source_repo
+    );
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, sync_args: &SyncRepoArgs) -> Result<()> {
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    rt.block_on(sync_repo(args, sync_args))
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to copy {} files; see above", failures))]
+        Copy { failures: usize },
+
+        #[snafu(display("Target '{}' in the source repo doesn't match its sha256", path))]
+        HashMismatch { path: String },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display("Failed to create temporary file: {}", source))]
+        TempFile { source: std::io::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{is_immutable, target_sha256};
+
+    #[test]
+    fn immutable_paths() {
+        assert!(is_immutable("targets/ab12.a.img"));
+        assert!(is_immutable("aws-dev/x86_64/1234.targets.json"));
+        assert!(is_immutable("aws-dev/x86_64/1.root.json"));
+        assert!(!is_immutable("aws-dev/x86_64/timestamp.json"));
+        assert!(!is_immutable("root.json"));
+    }
+
+    #[test]
+    fn target_hashes() {
+        assert_eq!(target_sha256("targets/ab12.a.img"), Some("ab12"));
+        assert_eq!(target_sha256("targets/my-kit/ab12.a.rpm"), Some("ab12"));
+        assert_eq!(target_sha256("aws-dev/x86_64/1234.targets.json"), None);
+    }
+}
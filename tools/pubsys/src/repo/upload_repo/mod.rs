@@ -0,0 +1,431 @@
+//! The upload_repo module owns the 'upload-repo' subcommand, which uploads a repo built by the
+//! 'repo' subcommand to the repo's S3 bucket.
+//!
+//! Targets are uploaded before metadata, and timestamp.json last, so clients are never pointed to
+//! files they can't download yet.  Uploads can be resumed: a file is skipped if S3 already has it
+//! at the same key with the same sha256, so re-running after an interruption only uploads what's
+//! missing.
+//!
+//! Large files are uploaded in parts, several at once, and optionally through S3 Transfer
+//! Acceleration, which helps when the bucket is far away.
+//!
+//! If the repo is served through CloudFront, the metadata files we uploaded are invalidated in
+//! its distributions once the upload is done.
+//!
+//! Instead of S3, the repo can be copied to a local directory in the same layout, for air-gapped
+//! users who move repos by their own means; it's loadable with a file:// URL.
+
+use crate::aws::rate_limit::RateLimits;
+use crate::journal::Journal;
+use crate::progress::progress_bar;
+use crate::repo::cloudfront;
+use crate::repo::s3::{self, MultipartOptions, RepoBucket, RepoClient};
+use crate::Args;
+use futures::stream::{self, StreamExt};
+use log::{debug, error, info, trace};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// The kind of work tracked and journaled for uploads, which are named by bucket path
+const UPLOADS: &str = "repo file uploads";
+
+/// Uploads a built repo to its S3 bucket, or copies it to a local directory
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct UploadRepoArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repo being uploaded
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repo being uploaded
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The directory the repo was built into, i.e. the `--outdir` of the `repo` subcommand
+    repo_dir: PathBuf,
+
+    #[structopt(long, default_value = "8")]
+    /// The number of files to upload at once
+    max_concurrent_uploads: NonZeroUsize,
+
+    #[structopt(long, default_value = "64")]
+    /// Files larger than this many MiB are uploaded in parts of this size; at least 5
+    part_size_mib: u64,
+
+    #[structopt(long, default_value = "4")]
+    /// The number of parts of each file to upload at once
+    max_concurrent_parts: NonZeroUsize,
+
+    #[structopt(long)]
+    /// Upload through S3 Transfer Acceleration, which must be enabled on the bucket
+    transfer_acceleration: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = parse_destination),
+        conflicts_with = "transfer-acceleration"
+    )]
+    /// Copy the repo to this local directory or file:// URL instead of the repo's S3 bucket
+    destination: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Don't display progress bars
+    no_progress: bool,
+}
+
+/// Parses a local directory, given as a path or a file:// URL.
+fn parse_destination(input: &str) -> std::result::Result<PathBuf, String> {
+    if !input.starts_with("file://") {
+        return Ok(PathBuf::from(input));
+    }
+    Url::parse(input)
+        .map_err(|e| format!("invalid URL '{}': {}", input, e))?
+        .to_file_path()
+        .map_err(|_| format!("'{}' is not a local file URL", input))
+}
+
+/// Where the repo is uploaded
+enum Destination {
+    S3 {
+        client: RepoClient,
+        bucket: RepoBucket,
+        multipart: MultipartOptions,
+    },
+    Local(PathBuf),
+}
+
+impl Destination {
+    fn name(&self) -> String {
+        match self {
+            Destination::S3 { bucket, .. } => bucket.name.clone(),
+            Destination::Local(dir) => dir.display().to_string(),
+        }
+    }
+}
+
+/// A file of the built repo, and its path in the bucket
+#[derive(Debug, PartialEq, Eq)]
+struct RepoFile {
+    local_path: PathBuf,
+    path: String,
+}
+
+/// Lists the files in the given directory and its subdirectories, giving each the bucket path
+/// under `path_prefix` that mirrors its place in the directory.  Symlinks, like the ones the repo
+/// subcommand makes for linked targets, are followed.
+fn list_files(dir: &Path, path_prefix: &str, files: &mut Vec<RepoFile>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(error::ReadDirSnafu { path: dir })? {
+        let local_path = entry.context(error::ReadDirSnafu { path: dir })?.path();
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(error::NonUtf8PathSnafu { path: &local_path })?;
+        let path = format!("{}/{}", path_prefix, name);
+        if local_path.is_dir() {
+            list_files(&local_path, &path, files)?;
+        } else {
+            files.push(RepoFile { local_path, path });
+        }
+    }
+    Ok(())
+}
+
+/// Uploads a single file, unless the destination already has an identical copy.  Returns whether
+/// it was uploaded.
+async fn upload_file(
+    journal: &Journal,
+    destination: &Destination,
+    file: &RepoFile,
+) -> Result<bool> {
+    let sha256 = s3::sha256_file(&file.local_path).context(error::S3Snafu)?;
+    // A journal from an earlier run saves asking the destination whether it has the file.
+    let sha256_hex = hex::encode(&sha256);
+    if journal.detail(UPLOADS, &file.path) == Some(Some(sha256_hex.clone())) {
+        debug!(
+            "{} is uploaded according to the journal, skipping",
+            file.path
+        );
+        return Ok(false);
+    }
+    match destination {
+        Destination::S3 {
+            client,
+            bucket,
+            multipart,
+        } => {
+            let existing = s3::object_sha256(client, bucket, &file.path)
+                .await
+                .context(error::S3Snafu)?;
+            if existing.as_deref() == Some(sha256.as_slice()) {
+                debug!("{} is already uploaded, skipping", file.path);
+                return Ok(false);
+            }
+            trace!("Uploading {}", file.path);
+            s3::upload_object(
+                client,
+                bucket,
+                &file.path,
+                &file.local_path,
+                &sha256,
+                multipart,
+            )
+            .await
+            .context(error::S3Snafu)?;
+        }
+        Destination::Local(dir) => {
+            let path = dir.join(&file.path);
+            if path.is_file()
+                && s3::sha256_file(&path).context(error::S3Snafu)? == sha256.as_slice()
+            {
+                debug!("{} is already copied, skipping", file.path);
+                return Ok(false);
+            }
+            trace!("Copying {}", file.path);
+            copy_file(&file.local_path, &path).await?;
+        }
+    }
+    journal.record(UPLOADS, &file.path, Some(&sha256_hex));
+    Ok(true)
+}
+
+/// Copies a file into place through a temporary file, so an interrupted copy never leaves a
+/// partial file at the destination.  Symlinks are followed.
+async fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let dir = to.parent().unwrap_or_else(|| Path::new("."));
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context(error::CopySnafu { path: to })?;
+    let temp_path = dir.join(format!(
+        ".{}.partial",
+        to.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    tokio::fs::copy(from, &temp_path)
+        .await
+        .context(error::CopySnafu { path: to })?;
+    tokio::fs::rename(&temp_path, to)
+        .await
+        .context(error::CopySnafu { path: to })
+}
+
+async fn upload_repo(args: &Args, upload_args: &UploadRepoArgs) -> Result<()> {
+    let metadata_dir = upload_args
+        .repo_dir
+        .join(&upload_args.variant)
+        .join(&upload_args.arch);
+    ensure!(
+        metadata_dir.is_dir(),
+        error::MissingMetadataSnafu { path: metadata_dir }
+    );
+    let mut files = Vec::new();
+    list_files(&upload_args.repo_dir.join("targets"), "targets", &mut files)?;
+    list_files(
+        &metadata_dir,
+        &format!("{}/{}", upload_args.variant, upload_args.arch),
+        &mut files,
+    )?;
+
+    // A local destination doesn't need anything from Infra.toml.
+    let infra_config = if upload_args.destination.is_none() {
+        // If a lock file exists, use that, otherwise use Infra.toml
+        let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+        trace!("Parsed infra config: {:?}", infra_config);
+        Some(infra_config)
+    } else {
+        None
+    };
+    let aws = infra_config
+        .as_ref()
+        .and_then(|infra_config| infra_config.aws.clone())
+        .unwrap_or_default();
+    let rate_limits = RateLimits::new(&aws);
+    let destination = match (&upload_args.destination, &infra_config) {
+        (Some(dir), _) => Destination::Local(dir.clone()),
+        (None, Some(infra_config)) => {
+            let bucket =
+                RepoBucket::from_config(infra_config, &upload_args.repo).context(error::S3Snafu)?;
+            let client = if upload_args.transfer_acceleration {
+                info!("Using S3 Transfer Acceleration");
+                bucket.accelerated_client(&aws, &rate_limits).await
+            } else {
+                bucket.client(&aws, &rate_limits).await
+            };
+            let multipart = MultipartOptions {
+                part_size: upload_args.part_size_mib * 1024 * 1024,
+                max_concurrent_parts: upload_args.max_concurrent_parts.get(),
+            };
+            Destination::S3 {
+                client,
+                bucket,
+                multipart,
+            }
+        }
+        (None, None) => unreachable!("Infra.toml is loaded when there's no local destination"),
+    };
+
+    info!(
+        "Uploading {} files of repo '{}' to {}",
+        files.len(),
+        upload_args.repo,
+        destination.name()
+    );
+    // Upload each stage fully before starting the next, so the bucket never refers to a file it
+    // doesn't have.
+    let mut uploaded = 0;
+    let mut uploaded_metadata = Vec::new();
+    let progress_bar = progress_bar(upload_args.no_progress, files.len(), "Uploading files");
+    for stage in 0..=2 {
+        let stage_files = files
+            .iter()
+            .filter(|file| s3::upload_stage(&file.path) == stage);
+        let uploads = stage_files.map(|file| {
+            let destination = &destination;
+            (file.path.clone(), async move {
+                let result = upload_file(&args.state.journal, destination, file).await;
+                (file, result)
+            })
+        });
+        let results: Vec<(&RepoFile, Result<bool>)> =
+            stream::iter(args.state.interrupt.tracked(UPLOADS, uploads))
+                .buffer_unordered(upload_args.max_concurrent_uploads.get())
+                .inspect(|_| progress_bar.inc(1))
+                .collect()
+                .await;
+        let mut failures = 0;
+        for (file, result) in results {
+            match result {
+                Ok(true) => {
+                    uploaded += 1;
+                    if stage > 0 {
+                        uploaded_metadata.push(file.path.as_str());
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to upload {}: {}", file.path, e);
+                    failures += 1;
+                }
+            }
+        }
+        // Files that were uploaded are skipped next time, so re-running resumes from here.
+        ensure!(failures == 0, error::UploadSnafu { failures });
+    }
+
+    info!(
+        "Uploaded {} files; {} were already present",
+        uploaded,
+        files.len() - uploaded
+    );
+
+    // Only the bucket can be served through CloudFront.
+    if let Destination::S3 { bucket, .. } = &destination {
+        let distribution_ids = infra_config
+            .as_ref()
+            .and_then(|infra_config| infra_config.repo.as_ref())
+            .and_then(|repo_section| repo_section.get(&upload_args.repo))
+            .and_then(|repo_config| repo_config.cloudfront_distribution_ids.as_deref())
+            .unwrap_or_default();
+        let paths: Vec<String> = uploaded_metadata
+            .iter()
+            .map(|path| format!("/{}", bucket.key(path)))
+            .collect();
+        cloudfront::invalidate(&aws, &rate_limits, distribution_ids, &paths)
+            .await
+            .context(error::CloudFrontSnafu)?;
+    }
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, upload_args: &UploadRepoArgs) -> Result<()> {
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    rt.block_on(upload_repo(args, upload_args))
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("{}", source))]
+        CloudFront {
+            source: crate::repo::cloudfront::Error,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Failed to copy to '{}': {}", path.display(), source))]
+        Copy {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Repo metadata not found at '{}'", path.display()))]
+        MissingMetadata { path: PathBuf },
+
+        #[snafu(display("Path '{}' is not valid UTF-8", path.display()))]
+        NonUtf8Path { path: PathBuf },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("{}", source))]
+        S3 { source: crate::repo::s3::Error },
+
+        #[snafu(display(
+            "Failed to upload {} files; re-run to retry them without re-uploading the rest",
+            failures
+        ))]
+        Upload { failures: usize },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{list_files, RepoFile};
+    use std::fs;
+
+    #[test]
+    fn lists_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("my-kit")).unwrap();
+        fs::write(dir.path().join("ab12.a.img"), "a").unwrap();
+        fs::write(dir.path().join("my-kit").join("cd34.b.rpm"), "b").unwrap();
+
+        let mut files = Vec::new();
+        list_files(dir.path(), "targets", &mut files).unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            files,
+            vec![
+                RepoFile {
+                    local_path: dir.path().join("ab12.a.img"),
+                    path: "targets/ab12.a.img".to_string(),
+                },
+                RepoFile {
+                    local_path: dir.path().join("my-kit").join("cd34.b.rpm"),
+                    path: "targets/my-kit/cd34.b.rpm".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -1,24 +1,32 @@
 //! The validate_repo module owns the 'validate-repo' subcommand and provides methods for validating
 //! a given TUF repository by attempting to load the repository and download its targets.
 
+pub mod results;
+
+use self::results::{RepoValidationResults, TargetCheck};
+use crate::exit_code::{Classify, Kind};
 use crate::repo::{error as repo_error, repo_urls};
 use crate::Args;
-use log::{info, trace};
+use log::{error, info, trace};
 use pubsys_config::InfraConfig;
-use snafu::{OptionExt, ResultExt};
-use std::cmp::min;
-use std::fs::File;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
 use std::io;
-use std::path::PathBuf;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use structopt::{clap, StructOpt};
+use tough::schema::Target;
 use tough::{Repository, RepositoryLoader, TargetName};
 use url::Url;
 
 /// Validates a set of TUF repositories
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
-pub(crate) struct ValidateRepoArgs {
+pub struct ValidateRepoArgs {
     #[structopt(long)]
     /// Use this named repo infrastructure from Infra.toml
     repo: String,
@@ -37,32 +45,118 @@ pub(crate) struct ValidateRepoArgs {
     #[structopt(long)]
     /// Specifies whether to validate all listed targets by attempting to download them
     validate_targets: bool,
+
+    #[structopt(long, default_value = "16")]
+    /// The number of targets to download at once when validating targets
+    max_concurrent_downloads: NonZeroUsize,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Compare targets in the repo against the files of the same name in this directory, such as
+    /// the local build output directory
+    local_targets_dir: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Fail if the repo's root.json is older than the version recorded in this file, then record
+    /// the repo's version there; protects against rollback of root.json across runs
+    root_version_state_path: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Optional path where the full validation results should be written as JSON
+    write_results_path: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// If this argument is given, print the validation results summary as a JSON object instead
+    /// of a plaintext table
+    json: bool,
 }
 
-/// If we are on a machine with a large number of cores, then we limit the number of simultaneous
-/// downloads to this arbitrarily chosen maximum.
-const MAX_DOWNLOAD_THREADS: usize = 16;
+/// Which repo `validate` loads, and how thoroughly it checks it
+#[derive(Debug, Clone)]
+pub struct ValidateRepoOptions {
+    /// The repo from the infra config to validate
+    pub repo: String,
+    pub arch: String,
+    pub variant: String,
+    /// Path to root.json for the repo
+    pub root_role_path: PathBuf,
+    /// Download every listed target and check it against the repo metadata
+    pub validate_targets: bool,
+    /// The number of targets to download at once when validating targets
+    pub max_concurrent_downloads: NonZeroUsize,
+    /// Compare targets against the files of the same name in this directory
+    pub local_targets_dir: Option<PathBuf>,
+    /// Fail if the repo's root.json is older than the version recorded in this file, then record
+    /// the repo's version there
+    pub root_version_state_path: Option<PathBuf>,
+    /// Where to write the full validation results as JSON, if anywhere
+    pub write_results_path: Option<PathBuf>,
+}
+
+impl From<&ValidateRepoArgs> for ValidateRepoOptions {
+    fn from(args: &ValidateRepoArgs) -> Self {
+        Self {
+            repo: args.repo.clone(),
+            arch: args.arch.clone(),
+            variant: args.variant.clone(),
+            root_role_path: args.root_role_path.clone(),
+            validate_targets: args.validate_targets,
+            max_concurrent_downloads: args.max_concurrent_downloads,
+            local_targets_dir: args.local_targets_dir.clone(),
+            root_version_state_path: args.root_version_state_path.clone(),
+            write_results_path: args.write_results_path.clone(),
+        }
+    }
+}
+
+/// The highest root.json version seen in a repo, recorded between runs
+#[derive(Debug, Deserialize, Serialize)]
+struct RootVersionState {
+    root_version: NonZeroU64,
+}
+
+/// Reads the root.json version recorded in the given state file, if it exists.
+fn read_root_version_state(path: &Path) -> Result<Option<NonZeroU64>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = File::open(path).context(error::RootVersionStateSnafu { path })?;
+    let state: RootVersionState =
+        serde_json::from_reader(file).context(error::ParseRootVersionStateSnafu { path })?;
+    Ok(Some(state.root_version))
+}
+
+/// Records the given root.json version in the state file.
+fn write_root_version_state(path: &Path, root_version: NonZeroU64) -> Result<(), Error> {
+    let file = File::create(path).context(error::RootVersionStateSnafu { path })?;
+    serde_json::to_writer_pretty(file, &RootVersionState { root_version })
+        .context(error::SerializeRootVersionStateSnafu { path })
+}
 
 /// Retrieves listed targets and attempts to download them for validation purposes. We use a Rayon
 /// thread pool instead of tokio for async execution because `reqwest::blocking` creates a tokio
 /// runtime (and multiple tokio runtimes are not supported).
-fn retrieve_targets(repo: &Repository) -> Result<(), Error> {
-    let targets = &repo.targets().signed.targets;
+fn retrieve_targets(
+    repo: &Repository,
+    max_concurrent_downloads: NonZeroUsize,
+) -> Result<Vec<(String, TargetCheck)>, Error> {
+    let targets: Vec<TargetName> = repo.all_targets().map(|(name, _)| name.clone()).collect();
+    // Downloads spend most of their time waiting on the network, so we don't limit the number of
+    // threads to the number of cores.
     let thread_pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(min(num_cpus::get(), MAX_DOWNLOAD_THREADS))
+        .num_threads(max_concurrent_downloads.get())
         .build()
         .context(error::ThreadPoolSnafu)?;
 
     // create the channels through which our download results will be passed
     let (tx, rx) = mpsc::channel();
 
-    for target in targets.keys() {
+    for target in targets.iter().cloned() {
         let repo = repo.clone();
         let tx = tx.clone();
-        info!("Downloading target: {}", target.raw());
-        let target = target.clone();
         thread_pool.spawn(move || {
-            tx.send(download_targets(&repo, target))
+            trace!("Downloading target: {}", target.raw());
+            let result = download_targets(&repo, target.clone());
+            tx.send((target, result))
                 // inability to send on this channel is unrecoverable
                 .unwrap();
         });
@@ -70,19 +164,45 @@ fn retrieve_targets(repo: &Repository) -> Result<(), Error> {
     // close all senders
     drop(tx);
 
-    // block and await all downloads
-    let results: Vec<Result<u64, error::Error>> = rx.into_iter().collect();
-
-    // check all results and return the first error we see
-    for result in results {
-        result?;
+    // Report on each download as it finishes, rather than waiting for all of them.
+    let total = targets.len();
+    let mut completed = 0;
+    let mut failed = 0;
+    let mut bytes_verified = 0u64;
+    let mut checks = Vec::with_capacity(total);
+    for (target, result) in rx {
+        completed += 1;
+        let check = match result {
+            Ok(bytes) => {
+                bytes_verified += bytes;
+                info!(
+                    "[{}/{}] Verified target {} ({} bytes)",
+                    completed,
+                    total,
+                    target.raw(),
+                    bytes
+                );
+                TargetCheck::verified()
+            }
+            Err(e) => {
+                failed += 1;
+                error!("[{}/{}] {}", completed, total, e);
+                TargetCheck::failed(e)
+            }
+        };
+        checks.push((target.raw().to_string(), check));
     }
+    info!(
+        "Verified {} of {} targets, {} bytes in total",
+        total - failed,
+        total,
+        bytes_verified
+    );
 
-    // no errors were found, the targets are validated
-    Ok(())
+    Ok(checks)
 }
 
-fn download_targets(repo: &Repository, target: TargetName) -> Result<u64, Error> {
+pub(crate) fn download_targets(repo: &Repository, target: TargetName) -> Result<u64, Error> {
     let mut reader = match repo.read_target(&target) {
         Ok(Some(reader)) => reader,
         Ok(None) => {
@@ -103,12 +223,78 @@ fn download_targets(repo: &Repository, target: TargetName) -> Result<u64, Error>
     })
 }
 
+/// Compares the files in the given directory against the targets of the same name in the repo,
+/// confirming that the repo holds exactly what was built.  Files that aren't targets are ignored.
+fn compare_local_targets(
+    repo: &Repository,
+    local_targets_dir: &Path,
+) -> Result<Vec<(String, TargetCheck)>, Error> {
+    let targets: HashMap<&TargetName, &Target> = repo.all_targets().collect();
+    let entries = fs::read_dir(local_targets_dir).context(error::ReadDirSnafu {
+        path: local_targets_dir,
+    })?;
+
+    let mut checks = Vec::new();
+    for entry in entries {
+        let path = entry
+            .context(error::ReadDirSnafu {
+                path: local_targets_dir,
+            })?
+            .path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let expected = match TargetName::try_from(name)
+            .ok()
+            .and_then(|target| targets.get(&target).copied())
+        {
+            Some(expected) => expected,
+            None => {
+                trace!("'{}' is not a target in the repo, skipping", name);
+                continue;
+            }
+        };
+
+        let local = Target::from_path(&path).context(error::LocalTargetSnafu { path: &path })?;
+        let check = if local.length != expected.length {
+            TargetCheck::failed(format!(
+                "{} bytes in the repo but {} bytes locally",
+                expected.length, local.length
+            ))
+        } else if local.hashes.sha256 != expected.hashes.sha256 {
+            TargetCheck::failed("sha256 in the repo differs from the local file")
+        } else {
+            TargetCheck::verified()
+        };
+        match &check.message {
+            Some(message) => error!("Target {} does not match the local file: {}", name, message),
+            None => info!("Target {} matches the local file", name),
+        }
+        checks.push((name.to_string(), check));
+    }
+
+    ensure!(
+        !checks.is_empty(),
+        error::NoLocalTargetsSnafu {
+            path: local_targets_dir
+        }
+    );
+    Ok(checks)
+}
+
 fn validate_repo(
     root_role_path: &PathBuf,
     metadata_url: Url,
     targets_url: &Url,
     validate_targets: bool,
-) -> Result<(), Error> {
+    max_concurrent_downloads: NonZeroUsize,
+    local_targets_dir: Option<&Path>,
+    min_root_version: Option<NonZeroU64>,
+) -> Result<RepoValidationResults, Error> {
     // Load the repository
     let repo = RepositoryLoader::new(
         File::open(root_role_path).context(repo_error::FileSnafu {
@@ -122,63 +308,218 @@ fn validate_repo(
         metadata_base_url: metadata_url.clone(),
     })?;
     info!("Loaded TUF repo: {}", metadata_url);
+
+    // tough follows the chain of root.json versions from the one we were given, but a repo that
+    // serves an old chain would still load, so we check against the newest version we know of.
+    let root_version = repo.root().signed.version;
+    if let Some(min_root_version) = min_root_version {
+        ensure!(
+            root_version >= min_root_version,
+            error::RootRollbackSnafu {
+                root_version,
+                min_root_version
+            }
+        );
+        info!(
+            "Repo root.json version {} is at least the pinned version {}",
+            root_version, min_root_version
+        );
+    }
+
+    let mut results = RepoValidationResults::new(&repo, &metadata_url);
     if validate_targets {
         // Try retrieving listed targets
-        retrieve_targets(&repo)?;
+        for (name, check) in retrieve_targets(&repo, max_concurrent_downloads)? {
+            if let Some(result) = results.targets.get_mut(&name) {
+                result.download = Some(check);
+            }
+        }
+    }
+    if let Some(local_targets_dir) = local_targets_dir {
+        for (name, check) in compare_local_targets(&repo, local_targets_dir)? {
+            if let Some(result) = results.targets.get_mut(&name) {
+                result.local = Some(check);
+            }
+        }
     }
 
-    Ok(())
+    Ok(results)
 }
 
-/// Common entrypoint from main()
-pub(crate) fn run(args: &Args, validate_repo_args: &ValidateRepoArgs) -> Result<(), Error> {
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
-    trace!("Parsed infra config: {:?}", infra_config);
+/// Loads the repo in the options and checks it, returning the results.  Targets and delegated roles
+/// that fail their checks are reported in the results, not as errors; see `failed_targets` and
+/// `failed_delegations`.
+pub fn validate(
+    infra_config: &InfraConfig,
+    options: &ValidateRepoOptions,
+) -> Result<RepoValidationResults, Error> {
     let repo_config = infra_config
         .repo
         .as_ref()
         .context(repo_error::MissingConfigSnafu {
             missing: "repo section",
         })?
-        .get(&validate_repo_args.repo)
+        .get(&options.repo)
         .context(repo_error::MissingConfigSnafu {
-            missing: format!("definition for repo {}", &validate_repo_args.repo),
+            missing: format!("definition for repo {}", &options.repo),
         })?;
 
-    let repo_urls = repo_urls(
-        repo_config,
-        &validate_repo_args.variant,
-        &validate_repo_args.arch,
-    )?
-    .context(repo_error::MissingRepoUrlsSnafu {
-        repo: &validate_repo_args.repo,
-    })?;
-    validate_repo(
-        &validate_repo_args.root_role_path,
+    let repo_urls = repo_urls(repo_config, &options.variant, &options.arch)?.context(
+        repo_error::MissingRepoUrlsSnafu {
+            repo: &options.repo,
+        },
+    )?;
+
+    // The root.json version must be at least the one pinned in Infra.toml and the one recorded in
+    // the state file, whichever is higher.
+    let recorded_root_version = match &options.root_version_state_path {
+        Some(path) => read_root_version_state(path)?,
+        None => None,
+    };
+    let min_root_version = repo_config.min_root_version.max(recorded_root_version);
+
+    let results = validate_repo(
+        &options.root_role_path,
         repo_urls.0,
         repo_urls.1,
-        validate_repo_args.validate_targets,
-    )
+        options.validate_targets,
+        options.max_concurrent_downloads,
+        options.local_targets_dir.as_deref(),
+        min_root_version,
+    )?;
+
+    if let Some(path) = &options.root_version_state_path {
+        if let Some(root) = results.roles.iter().find(|role| role.role == "root") {
+            if Some(root.version) > recorded_root_version {
+                info!(
+                    "Recording root.json version {} in {}",
+                    root.version,
+                    path.display()
+                );
+                write_root_version_state(path, root.version)?;
+            }
+        }
+    }
+
+    // If a path was given, write the results
+    if let Some(write_results_path) = &options.write_results_path {
+        info!("Writing results to file");
+        serde_json::to_writer_pretty(
+            &File::create(write_results_path).context(error::WriteValidationResultsSnafu {
+                path: write_results_path,
+            })?,
+            &results,
+        )
+        .context(error::SerializeValidationResultsSnafu)?;
+    }
+
+    Ok(results)
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, validate_repo_args: &ValidateRepoArgs) -> Result<(), Error> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let results = validate(
+        &infra_config,
+        &ValidateRepoOptions::from(validate_repo_args),
+    )?;
+
+    if validate_repo_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&results.get_json_summary())
+                .context(error::SerializeResultsSummarySnafu)?
+        )
+    } else {
+        println!("{}", results);
+    }
+
+    let failed_delegations = results.failed_delegations();
+    ensure!(
+        failed_delegations.is_empty(),
+        error::FailedDelegationsSnafu {
+            roles: failed_delegations
+        }
+    );
+    let failed_targets = results.failed_targets();
+    ensure!(
+        failed_targets.is_empty(),
+        error::FailedTargetsSnafu {
+            targets: failed_targets
+        }
+    );
+    Ok(())
 }
 
 mod error {
     use snafu::Snafu;
     use std::io;
+    use std::num::NonZeroU64;
+    use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
+        #[snafu(display("Failed to validate delegated roles: {}", roles.join(", ")))]
+        FailedDelegations { roles: Vec<String> },
+
+        #[snafu(display("Failed to validate targets: {}", targets.join(", ")))]
+        FailedTargets { targets: Vec<String> },
+
         #[snafu(display("Invalid percentage specified: {} is greater than 100", percentage))]
         InvalidPercentage { percentage: u8 },
 
+        #[snafu(display("Failed to read local target '{}': {}", path.display(), source))]
+        LocalTarget {
+            path: PathBuf,
+            source: tough::schema::Error,
+        },
+
+        #[snafu(display("No files in '{}' are targets in the repo", path.display()))]
+        NoLocalTargets { path: PathBuf },
+
+        #[snafu(display("Failed to parse root version state file '{}': {}", path.display(), source))]
+        ParseRootVersionState {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
         #[snafu(context(false), display("{}", source))]
         Repo {
             #[snafu(source(from(crate::repo::Error, Box::new)))]
             source: Box<crate::repo::Error>,
         },
 
+        #[snafu(display(
+            "Repo serves root.json version {}, older than the pinned version {}; its trust metadata may have been rolled back",
+            root_version,
+            min_root_version
+        ))]
+        RootRollback {
+            root_version: NonZeroU64,
+            min_root_version: NonZeroU64,
+        },
+
+        #[snafu(display("Failed to access root version state file '{}': {}", path.display(), source))]
+        RootVersionState { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to write root version state file '{}': {}", path.display(), source))]
+        SerializeRootVersionState {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to serialize validation results to json: {}", source))]
+        SerializeValidationResults { source: serde_json::Error },
+
+        #[snafu(display("Failed to serialize validation results summary to json: {}", source))]
+        SerializeResultsSummary { source: serde_json::Error },
+
         #[snafu(display("Failed to download and write target '{}': {}", target, source))]
         TargetDownload { target: String, source: io::Error },
 
@@ -194,6 +535,24 @@ mod error {
 
         #[snafu(display("Unable to create thread pool: {}", source))]
         ThreadPool { source: rayon::ThreadPoolBuildError },
+
+        #[snafu(display("Failed to write validation results to {}: {}", path.display(), source))]
+        WriteValidationResults {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::FailedDelegations { .. }
+            | Self::FailedTargets { .. }
+            | Self::RootRollback { .. }
+            | Self::TargetMissing { .. } => Some(Kind::Validation),
+            _ => None,
+        }
     }
 }
-pub(crate) use error::Error;
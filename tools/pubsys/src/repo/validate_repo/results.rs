@@ -0,0 +1,301 @@
+//! The results module owns the reporting of TUF repo validation results.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::num::NonZeroU64;
+use tabled::{Table, Tabled};
+use tough::schema::{Signed, Targets};
+use tough::Repository;
+use url::Url;
+
+/// Represents the possible outcomes of checking a target
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub enum TargetCheckStatus {
+    /// The target was checked and matched the repo metadata
+    Verified,
+
+    /// The target was checked and didn't match the repo metadata, or couldn't be checked
+    Failed,
+}
+
+/// Represents the outcome of one check of a target
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct TargetCheck {
+    pub status: TargetCheckStatus,
+
+    /// Why the check failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl TargetCheck {
+    pub(crate) fn verified() -> Self {
+        Self {
+            status: TargetCheckStatus::Verified,
+            message: None,
+        }
+    }
+
+    pub(crate) fn failed<S: ToString>(message: S) -> Self {
+        Self {
+            status: TargetCheckStatus::Failed,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+/// Represents the validation results of a single target
+#[derive(Debug, Serialize)]
+pub struct TargetResult {
+    /// The length of the target according to the repo metadata
+    pub length: u64,
+
+    /// The sha256 of the target according to the repo metadata, hex-encoded
+    pub sha256: String,
+
+    /// The result of downloading the target from the repo, if it was downloaded
+    pub download: Option<TargetCheck>,
+
+    /// The result of comparing the target to a local file, if there was one
+    pub local: Option<TargetCheck>,
+}
+
+impl TargetResult {
+    fn failed(&self) -> bool {
+        [&self.download, &self.local]
+            .iter()
+            .filter_map(|check| check.as_ref())
+            .any(|check| check.status == TargetCheckStatus::Failed)
+    }
+}
+
+/// Represents the validation results of a delegated targets role
+#[derive(Debug, Serialize)]
+pub struct DelegationResult {
+    /// The role that delegates to this one
+    pub delegated_by: String,
+    pub check: TargetCheck,
+}
+
+/// Represents the version and expiration of a metadata role
+#[derive(Debug, Serialize, Tabled)]
+pub struct RoleResult {
+    pub role: String,
+    pub version: NonZeroU64,
+    /// RFC3339 expiration date
+    pub expires: String,
+}
+
+#[derive(Debug, Serialize, Tabled)]
+struct RepoValidationSummary {
+    targets: usize,
+    delegated_roles: usize,
+    failed_delegations: usize,
+    downloaded: usize,
+    compared_locally: usize,
+    failed: usize,
+    bytes_verified: u64,
+}
+
+/// Represents all validation results of a TUF repo
+#[derive(Debug, Serialize)]
+pub struct RepoValidationResults {
+    pub metadata_url: Url,
+    pub roles: Vec<RoleResult>,
+    /// Mapping of target name to its results, including targets signed by delegated roles
+    pub targets: BTreeMap<String, TargetResult>,
+    /// Mapping of delegated role name to its results
+    pub delegations: BTreeMap<String, DelegationResult>,
+}
+
+impl RepoValidationResults {
+    /// Creates results for the given repo, listing its metadata and targets without any checks.
+    pub(crate) fn new(repo: &Repository, metadata_url: &Url) -> Self {
+        let mut roles = vec![
+            RoleResult {
+                role: "root".to_string(),
+                version: repo.root().signed.version,
+                expires: repo.root().signed.expires.to_rfc3339(),
+            },
+            RoleResult {
+                role: "snapshot".to_string(),
+                version: repo.snapshot().signed.version,
+                expires: repo.snapshot().signed.expires.to_rfc3339(),
+            },
+            RoleResult {
+                role: "targets".to_string(),
+                version: repo.targets().signed.version,
+                expires: repo.targets().signed.expires.to_rfc3339(),
+            },
+            RoleResult {
+                role: "timestamp".to_string(),
+                version: repo.timestamp().signed.version,
+                expires: repo.timestamp().signed.expires.to_rfc3339(),
+            },
+        ];
+
+        let mut delegations = BTreeMap::new();
+        check_delegations("targets", repo.targets(), &mut roles, &mut delegations);
+
+        let targets = repo
+            .all_targets()
+            .map(|(name, target)| {
+                (
+                    name.raw().to_string(),
+                    TargetResult {
+                        length: target.length,
+                        sha256: target
+                            .hashes
+                            .sha256
+                            .iter()
+                            .map(|byte| format!("{:02x}", byte))
+                            .collect(),
+                        download: None,
+                        local: None,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            metadata_url: metadata_url.clone(),
+            roles,
+            targets,
+            delegations,
+        }
+    }
+
+    /// Returns the names of all targets that failed a check
+    pub fn failed_targets(&self) -> Vec<String> {
+        self.targets
+            .iter()
+            .filter(|(_, result)| result.failed())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns the names of all delegated roles that failed their check
+    pub fn failed_delegations(&self) -> Vec<String> {
+        self.delegations
+            .iter()
+            .filter(|(_, result)| result.check.status == TargetCheckStatus::Failed)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn get_summary(&self) -> RepoValidationSummary {
+        let downloaded = self.targets.values().filter(|t| t.download.is_some());
+        RepoValidationSummary {
+            targets: self.targets.len(),
+            delegated_roles: self.delegations.len(),
+            failed_delegations: self.failed_delegations().len(),
+            downloaded: downloaded.clone().count(),
+            compared_locally: self.targets.values().filter(|t| t.local.is_some()).count(),
+            failed: self.failed_targets().len(),
+            bytes_verified: downloaded
+                .filter(|t| {
+                    t.download.as_ref().map(|check| check.status)
+                        == Some(TargetCheckStatus::Verified)
+                })
+                .map(|t| t.length)
+                .sum(),
+        }
+    }
+
+    pub fn get_json_summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "metadata_url": self.metadata_url,
+            "roles": self.roles,
+            "summary": self.get_summary(),
+        })
+    }
+}
+
+/// Walks the delegations of the given targets role, recording the version and expiration of each
+/// delegated role and checking its place in the delegation chain.  tough has already verified the
+/// signatures of each role against the keys its delegator lists when it loaded the repo, so here
+/// we make sure each role's metadata was found and that it only signs targets it was delegated.
+fn check_delegations(
+    delegator: &str,
+    targets: &Signed<Targets>,
+    roles: &mut Vec<RoleResult>,
+    results: &mut BTreeMap<String, DelegationResult>,
+) {
+    let delegations = match targets.signed.delegations.as_ref() {
+        Some(delegations) => delegations,
+        None => return,
+    };
+    for role in &delegations.roles {
+        let check = match role.targets.as_ref() {
+            None => TargetCheck::failed("role metadata was not found"),
+            Some(role_targets) => {
+                roles.push(RoleResult {
+                    role: role.name.clone(),
+                    version: role_targets.signed.version,
+                    expires: role_targets.signed.expires.to_rfc3339(),
+                });
+                let undelegated: Vec<&str> = role_targets
+                    .signed
+                    .targets
+                    .keys()
+                    .filter(|name| !delegations.target_is_delegated(name))
+                    .map(|name| name.raw())
+                    .collect();
+                check_delegations(&role.name, role_targets, roles, results);
+                if undelegated.is_empty() {
+                    TargetCheck::verified()
+                } else {
+                    TargetCheck::failed(format!(
+                        "signs targets outside its delegated paths: {}",
+                        undelegated.join(", ")
+                    ))
+                }
+            }
+        };
+        results.insert(
+            role.name.clone(),
+            DelegationResult {
+                delegated_by: delegator.to_string(),
+                check,
+            },
+        );
+    }
+}
+
+impl Display for RepoValidationResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", Table::new(&self.roles))?;
+        write!(f, "{}", Table::new(vec![self.get_summary()]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TargetCheck, TargetResult};
+
+    fn target_result(download: Option<TargetCheck>, local: Option<TargetCheck>) -> TargetResult {
+        TargetResult {
+            length: 1,
+            sha256: "00".to_string(),
+            download,
+            local,
+        }
+    }
+
+    #[test]
+    fn unchecked_target_is_not_failed() {
+        assert!(!target_result(None, None).failed());
+    }
+
+    #[test]
+    fn any_failed_check_fails_target() {
+        assert!(!target_result(Some(TargetCheck::verified()), None).failed());
+        assert!(target_result(
+            Some(TargetCheck::verified()),
+            Some(TargetCheck::failed("sha256 mismatch"))
+        )
+        .failed());
+        assert!(target_result(Some(TargetCheck::failed("missing")), None).failed());
+    }
+}
@@ -0,0 +1,763 @@
+//! The report module owns the 'report' subcommand, which checks everywhere a version is published
+//! and reports the results together, so one run tells whether a release is consistent:
+//!
+//! * its AMIs, from the AMI input file, exist and have the expected public flag and launch
+//!   permissions, like 'validate-ami' checks
+//! * its SSM parameters, rendered from the templates as the 'ssm' subcommand would, have the
+//!   expected values, like 'validate-ssm' checks
+//! * if a repo is given, its update is in the repo's manifest.json, and the targets the update
+//!   refers to are listed, and optionally downloadable, like 'validate-repo' checks
+//!
+//! Each check is reported with its expected and actual values; any check that isn't correct makes
+//! the subcommand fail.
+
+use crate::aws::ami::Image;
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::page_size;
+use crate::aws::rate_limit::RateLimits;
+use crate::aws::ssm::template::{self, RenderedParameter};
+use crate::aws::ssm::{ssm, BuildContext, SsmKey};
+use crate::aws::validate_ami::ami::{describe_images, ImageDef};
+use crate::aws::validate_ami::results::{AmiValidationResult, AmiValidationResultStatus};
+use crate::aws::validate_ami::{self, validate_images_in_region};
+use crate::aws::validate_snapshots::parse_ami_input;
+use crate::aws::validate_ssm::results::{SsmValidationResult, SsmValidationResultStatus};
+use crate::aws::validate_ssm::{self, validate_parameters_in_region};
+use crate::aws::{parse_arch, region_from_string};
+use crate::exit_code::{Classify, Kind};
+use crate::progress::progress_bar;
+use crate::repo::repo_urls;
+use crate::repo::validate_repo::download_targets;
+use crate::{friendly_version, Args};
+use aws_sdk_ec2::model::ArchitectureValues;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use aws_sdk_ssm::Client as SsmClient;
+use futures::future::join_all;
+use log::{error, info, trace};
+use pubsys_config::{AwsConfig, InfraConfig};
+use semver::Version;
+use serde::Serialize;
+use serde_plain::derive_display_from_serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
+use tokio::runtime::Runtime;
+use tough::{Repository, RepositoryLoader, TargetName};
+use update_metadata::Manifest;
+
+/// Checks that a version is published consistently to its AMIs, SSM parameters, and repo
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ReportArgs {
+    /// The version to check, as given to the 'ssm' subcommand
+    #[structopt(long)]
+    version: String,
+
+    /// The variant of the version
+    #[structopt(long)]
+    variant: String,
+
+    /// The architecture of the version
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: ArchitectureValues,
+
+    /// Path to the JSON file containing the version's regional AMI IDs
+    #[structopt(long, parse(from_os_str))]
+    ami_input: PathBuf,
+
+    /// File holding the parameter templates the version was published with
+    #[structopt(long)]
+    template_path: PathBuf,
+
+    /// Also check the version's update in this named repo from Infra.toml
+    #[structopt(long, requires = "root-role-path")]
+    repo: Option<String>,
+
+    /// Path to root.json for the repo
+    #[structopt(long, parse(from_os_str))]
+    root_role_path: Option<PathBuf>,
+
+    /// Check the update's targets by downloading them, rather than only finding them listed
+    #[structopt(long, requires = "repo")]
+    validate_targets: bool,
+
+    /// Optional path where the full report should be written as JSON
+    #[structopt(long, parse(from_os_str))]
+    write_report_path: Option<PathBuf>,
+
+    /// If this argument is given, print the report summary as a JSON object instead of a
+    /// plaintext table
+    #[structopt(long)]
+    json: bool,
+
+    /// Don't display progress bars
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// Where a checked item is published
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) enum Surface {
+    #[serde(rename = "AMI")]
+    Ami,
+    #[serde(rename = "SSM parameter")]
+    Ssm,
+    #[serde(rename = "repo")]
+    Repo,
+}
+
+derive_display_from_serialize!(Surface);
+
+/// The result of a check, with the same meanings as the statuses of the validate subcommands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) enum CheckStatus {
+    Correct,
+    Incorrect,
+    Missing,
+    Unreachable,
+}
+
+derive_display_from_serialize!(CheckStatus);
+
+/// A single checked item
+#[derive(Debug, Serialize)]
+pub(crate) struct Check {
+    surface: Surface,
+    region: Option<String>,
+    item: String,
+    status: CheckStatus,
+    expected: Option<serde_json::Value>,
+    actual: Option<serde_json::Value>,
+}
+
+impl Check {
+    fn repo(item: &str, status: CheckStatus) -> Self {
+        Self {
+            surface: Surface::Repo,
+            region: None,
+            item: item.to_string(),
+            status,
+            expected: None,
+            actual: None,
+        }
+    }
+}
+
+impl From<AmiValidationResult> for Check {
+    fn from(result: AmiValidationResult) -> Self {
+        let status = match result.status {
+            AmiValidationResultStatus::Correct => CheckStatus::Correct,
+            AmiValidationResultStatus::Incorrect => CheckStatus::Incorrect,
+            AmiValidationResultStatus::Missing => CheckStatus::Missing,
+            AmiValidationResultStatus::Unreachable => CheckStatus::Unreachable,
+        };
+        Self {
+            surface: Surface::Ami,
+            region: Some(result.region.to_string()),
+            item: result.id,
+            status,
+            expected: serde_json::to_value(result.expected_image_def).ok(),
+            actual: result
+                .actual_image_def
+                .and_then(|image_def| serde_json::to_value(image_def).ok()),
+        }
+    }
+}
+
+impl From<SsmValidationResult> for Check {
+    fn from(result: SsmValidationResult) -> Self {
+        let status = match result.status {
+            SsmValidationResultStatus::Correct => CheckStatus::Correct,
+            // We only ask for expected parameters, so none are unexpected.
+            SsmValidationResultStatus::Incorrect | SsmValidationResultStatus::Unexpected => {
+                CheckStatus::Incorrect
+            }
+            SsmValidationResultStatus::Missing => CheckStatus::Missing,
+            SsmValidationResultStatus::Unreachable => CheckStatus::Unreachable,
+        };
+        Self {
+            surface: Surface::Ssm,
+            region: Some(result.region.to_string()),
+            item: result.name,
+            status,
+            expected: result.expected_value.map(serde_json::Value::String),
+            actual: result.actual_value.map(serde_json::Value::String),
+        }
+    }
+}
+
+/// The number of checks of each status for one surface
+#[derive(Debug, Default, PartialEq, Eq, Tabled, Serialize)]
+struct SurfaceSummary {
+    surface: String,
+    correct: u64,
+    incorrect: u64,
+    missing: u64,
+    unreachable: u64,
+}
+
+/// Not-correct checks, as shown after the summary
+#[derive(Tabled)]
+struct ProblemRow {
+    surface: Surface,
+    region: String,
+    item: String,
+    status: CheckStatus,
+}
+
+/// All checks of a version
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    version: String,
+    variant: String,
+    arch: String,
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn summary(&self) -> Vec<SurfaceSummary> {
+        let mut summaries: Vec<(Surface, SurfaceSummary)> = Vec::new();
+        for check in &self.checks {
+            let index = match summaries
+                .iter()
+                .position(|(surface, _)| *surface == check.surface)
+            {
+                Some(index) => index,
+                None => {
+                    summaries.push((
+                        check.surface,
+                        SurfaceSummary {
+                            surface: check.surface.to_string(),
+                            ..Default::default()
+                        },
+                    ));
+                    summaries.len() - 1
+                }
+            };
+            let summary = &mut summaries[index].1;
+            match check.status {
+                CheckStatus::Correct => summary.correct += 1,
+                CheckStatus::Incorrect => summary.incorrect += 1,
+                CheckStatus::Missing => summary.missing += 1,
+                CheckStatus::Unreachable => summary.unreachable += 1,
+            }
+        }
+        summaries.sort_by_key(|(surface, _)| *surface);
+        summaries.into_iter().map(|(_, summary)| summary).collect()
+    }
+
+    fn problems(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.status != CheckStatus::Correct)
+    }
+
+    fn get_json_summary(&self) -> serde_json::Value {
+        serde_json::json!(self
+            .summary()
+            .into_iter()
+            .map(|summary| (summary.surface.clone(), summary))
+            .collect::<HashMap<String, SurfaceSummary>>())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", Table::new(self.summary()))?;
+        let problems: Vec<ProblemRow> = self
+            .problems()
+            .map(|check| ProblemRow {
+                surface: check.surface,
+                region: check.region.clone().unwrap_or_default(),
+                item: check.item.clone(),
+                status: check.status,
+            })
+            .collect();
+        if !problems.is_empty() {
+            write!(f, "{}", Table::new(problems))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the version of a release's update in the repo, which doesn't include the build.
+fn update_version(version: &str) -> Result<Version> {
+    let version = friendly_version(version).context(error::ParseVersionSnafu { version })?;
+    Ok(Version::new(version.major, version.minor, version.patch))
+}
+
+/// Returns the fields 'validate-ami' should find for the given published image.
+fn expected_image_def(image: &Image) -> ImageDef {
+    let public = image.public.unwrap_or_default();
+    ImageDef {
+        id: image.id.clone(),
+        name: image.name.clone(),
+        public,
+        // A private image is shared with exactly the listed accounts, if any.
+        launch_permissions: (!public).then(|| image.launch_permissions.clone().unwrap_or_default()),
+        // Like 'validate-ami', we expect every image to support ENA and SR-IOV.
+        ena_support: true,
+        sriov_net_support: "simple".to_string(),
+    }
+}
+
+/// Checks the version's AMIs and SSM parameters in each region of the AMI input.
+async fn check_aws(
+    report_args: &ReportArgs,
+    aws: &AwsConfig,
+    amis: &HashMap<Region, Image>,
+) -> Result<Vec<Check>> {
+    let base_region = region_from_string(aws.regions_for("report").get(0).context(
+        error::MissingConfigSnafu {
+            missing: "aws.regions",
+        },
+    )?);
+    let rate_limits = RateLimits::new(aws);
+    let mut ec2_clients = HashMap::with_capacity(amis.len());
+    let mut ssm_clients = HashMap::with_capacity(amis.len());
+    for region in amis.keys() {
+        let client_config = build_client_config(region, &base_region, aws).await;
+        ec2_clients.insert(
+            region.clone(),
+            Ec2Client::from_pubsys_config(&client_config, aws),
+        );
+        ssm_clients.insert(
+            region.clone(),
+            SsmClient::from_pubsys_config(&client_config, aws),
+        );
+    }
+    let mut checks = Vec::new();
+
+    // AMIs   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    info!("Checking AMIs");
+    let expected_images: HashMap<Region, Vec<ImageDef>> = amis
+        .iter()
+        .map(|(region, image)| (region.clone(), vec![expected_image_def(image)]))
+        .collect();
+    let progress_bar = progress_bar(
+        report_args.no_progress,
+        ec2_clients.len(),
+        "Retrieving images",
+    );
+    for (region, result) in describe_images(
+        &ec2_clients,
+        &rate_limits,
+        page_size::get(aws, |page_size| page_size.describe_images),
+        &expected_images,
+        &progress_bar,
+    )
+    .await
+    {
+        let result = result.map_err(|e| {
+            error!("Failed to retrieve images in region {}: {}", region, e);
+            validate_ami::Error::UnreachableRegion {
+                region: region.to_string(),
+            }
+        });
+        checks.extend(
+            validate_images_in_region(&expected_images[region], &result, region)
+                .into_iter()
+                .map(Check::from),
+        );
+    }
+
+    // SSM parameters   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    info!("Checking SSM parameters");
+    let build_context = BuildContext {
+        variant: &report_args.variant,
+        arch: report_args.arch.as_ref(),
+        image_version: &report_args.version,
+    };
+    let template_parameters = template::get_parameters(&report_args.template_path, &build_context)
+        .context(error::FindTemplatesSnafu)?;
+    if template_parameters.parameters.is_empty() {
+        info!(
+            "No parameters for this arch/variant in {}",
+            report_args.template_path.display()
+        );
+        return Ok(checks);
+    }
+    let ssm_prefix = aws.ssm_prefix_for(&report_args.variant, report_args.arch.as_ref());
+    let rendered =
+        template::render_parameters(template_parameters, amis, ssm_prefix, &build_context)
+            .context(error::RenderTemplatesSnafu)?;
+    let mut expected_parameters: HashMap<Region, HashMap<SsmKey, String>> = HashMap::new();
+    for (key, value) in RenderedParameter::as_ssm_parameters(&rendered) {
+        expected_parameters
+            .entry(key.region.clone())
+            .or_default()
+            .insert(key, value);
+    }
+
+    // Each region is fetched on its own, so one that can't be reached doesn't hide the others.
+    let requests = expected_parameters.iter().map(|(region, expected)| {
+        let keys: Vec<&SsmKey> = expected.keys().collect();
+        let ssm_clients = &ssm_clients;
+        let rate_limits = &rate_limits;
+        async move {
+            let result = ssm::get_parameters(&keys, ssm_clients, rate_limits)
+                .await
+                .map_err(|e| {
+                    error!("Failed to retrieve parameters in region {}: {}", region, e);
+                    validate_ssm::Error::UnreachableRegion {
+                        region: region.to_string(),
+                    }
+                });
+            validate_parameters_in_region(expected, &result, false)
+        }
+    });
+    for results in join_all(requests).await {
+        checks.extend(results.into_iter().map(Check::from));
+    }
+
+    Ok(checks)
+}
+
+/// Checks that the version's update is in the repo's manifest, and that its targets are there.
+fn check_repo(
+    report_args: &ReportArgs,
+    infra_config: &InfraConfig,
+    repo: &str,
+    root_role_path: &Path,
+) -> Result<Vec<Check>> {
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .and_then(|repo_section| repo_section.get(repo))
+        .context(error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", repo),
+        })?;
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &report_args.variant, report_args.arch.as_ref())
+            .context(error::RepoSnafu)?
+            .context(error::MissingConfigSnafu {
+                missing: format!("metadata_base_url and targets_url for repo {}", repo),
+            })?;
+
+    info!("Loading TUF repo from {}", metadata_url);
+    let loaded = RepositoryLoader::new(
+        File::open(root_role_path).context(error::ReadRootRoleSnafu {
+            path: root_role_path,
+        })?,
+        metadata_url.clone(),
+        targets_url.clone(),
+    )
+    .load();
+    let repo = match loaded {
+        Ok(repo) => repo,
+        Err(e) => {
+            error!("Failed to load repo from {}: {}", metadata_url, e);
+            return Ok(vec![Check::repo(
+                metadata_url.as_str(),
+                CheckStatus::Unreachable,
+            )]);
+        }
+    };
+
+    let manifest = match find_target(&repo, "manifest.json") {
+        Some(target) => {
+            let reader = repo
+                .read_target(&target)
+                .context(error::ReadManifestSnafu)?
+                .context(error::MissingManifestSnafu)?;
+            serde_json::from_reader::<_, Manifest>(reader).context(error::ParseManifestSnafu)?
+        }
+        None => return Ok(vec![Check::repo("manifest.json", CheckStatus::Missing)]),
+    };
+
+    let version = update_version(&report_args.version)?;
+    let update_name = format!("update {} in manifest.json", version);
+    let update = manifest.updates.iter().find(|update| {
+        update.variant == report_args.variant
+            && update.arch == report_args.arch.as_str()
+            && update.version == version
+    });
+    let update = match update {
+        Some(update) => update,
+        None => return Ok(vec![Check::repo(&update_name, CheckStatus::Missing)]),
+    };
+
+    let mut checks = vec![Check::repo(&update_name, CheckStatus::Correct)];
+    for name in [
+        &update.images.boot,
+        &update.images.root,
+        &update.images.hash,
+    ] {
+        let status = match find_target(&repo, name) {
+            None => CheckStatus::Missing,
+            Some(_) if !report_args.validate_targets => CheckStatus::Correct,
+            Some(target) => match download_targets(&repo, target) {
+                Ok(_) => CheckStatus::Correct,
+                Err(e) => {
+                    error!("{}", e);
+                    CheckStatus::Incorrect
+                }
+            },
+        };
+        checks.push(Check::repo(name, status));
+    }
+    Ok(checks)
+}
+
+/// Returns the name of the repo's target with the given name, if the repo lists it.
+fn find_target(repo: &Repository, name: &str) -> Option<TargetName> {
+    repo.all_targets()
+        .map(|(target, _)| target)
+        .find(|target| target.raw() == name)
+        .cloned()
+}
+
+/// Checks every surface and returns the report.
+pub(crate) fn report(args: &Args, report_args: &ReportArgs) -> Result<Report> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.clone().unwrap_or_default();
+
+    info!(
+        "Using AMI data from path: {}",
+        report_args.ami_input.display()
+    );
+    let amis = parse_ami_input(&report_args.ami_input).context(error::AmiInputSnafu)?;
+    ensure!(
+        !amis.is_empty(),
+        error::EmptyAmiInputSnafu {
+            path: &report_args.ami_input
+        }
+    );
+
+    // The repo is loaded with blocking requests, which can't run inside the async runtime.
+    let mut checks = match (&report_args.repo, &report_args.root_role_path) {
+        (Some(repo), Some(root_role_path)) => {
+            check_repo(report_args, &infra_config, repo, root_role_path)?
+        }
+        _ => Vec::new(),
+    };
+
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    checks.extend(rt.block_on(check_aws(report_args, &aws, &amis))?);
+
+    Ok(Report {
+        version: report_args.version.clone(),
+        variant: report_args.variant.clone(),
+        arch: report_args.arch.as_str().to_string(),
+        checks,
+    })
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, report_args: &ReportArgs) -> Result<()> {
+    let report = report(args, report_args)?;
+
+    if let Some(write_report_path) = &report_args.write_report_path {
+        info!("Writing report to file");
+        serde_json::to_writer_pretty(
+            &File::create(write_report_path).context(error::WriteReportSnafu {
+                path: write_report_path,
+            })?,
+            &report,
+        )
+        .context(error::SerializeReportSnafu)?;
+    }
+
+    if report_args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report.get_json_summary())
+                .context(error::SerializeReportSnafu)?
+        )
+    } else {
+        println!("{}", report);
+    }
+
+    let count = report.problems().count();
+    ensure!(count == 0, error::InconsistentSnafu { count });
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to read AMI input: {}", source))]
+        AmiInput {
+            #[snafu(source(from(crate::aws::validate_snapshots::Error, Box::new)))]
+            source: Box<crate::aws::validate_snapshots::Error>,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("AMI input '{}' is empty", path.display()))]
+        EmptyAmiInput { path: PathBuf },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{} checks failed; see the report above", count))]
+        Inconsistent { count: usize },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Repo lists manifest.json but doesn't have it"))]
+        MissingManifest,
+
+        #[snafu(display("Failed to parse manifest.json: {}", source))]
+        ParseManifest { source: serde_json::Error },
+
+        #[snafu(display("Failed to parse version '{}': {}", version, source))]
+        ParseVersion {
+            version: String,
+            source: semver::Error,
+        },
+
+        #[snafu(display("Failed to read manifest.json: {}", source))]
+        ReadManifest { source: tough::error::Error },
+
+        #[snafu(display("Failed to read root role '{}': {}", path.display(), source))]
+        ReadRootRole {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates {
+            source: crate::aws::ssm::template::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to create async runtime: {}", source))]
+        Runtime { source: std::io::Error },
+
+        #[snafu(display("Failed to serialize report to JSON: {}", source))]
+        SerializeReport { source: serde_json::Error },
+
+        #[snafu(display("Failed to write report to '{}': {}", path.display(), source))]
+        WriteReport {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::MissingConfig { .. } => Some(Kind::Config),
+            Self::Inconsistent { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        expected_image_def, update_version, Check, CheckStatus, Report, Surface, SurfaceSummary,
+    };
+    use crate::aws::ami::launch_permissions::LaunchPermissionDef;
+    use crate::aws::ami::Image;
+    use semver::Version;
+
+    fn check(surface: Surface, status: CheckStatus) -> Check {
+        Check {
+            surface,
+            region: Some("us-west-2".to_string()),
+            item: "item".to_string(),
+            status,
+            expected: None,
+            actual: None,
+        }
+    }
+
+    #[test]
+    fn summarizes_by_surface() {
+        let report = Report {
+            version: "1.13.0".to_string(),
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            checks: vec![
+                check(Surface::Repo, CheckStatus::Correct),
+                check(Surface::Ssm, CheckStatus::Incorrect),
+                check(Surface::Ami, CheckStatus::Correct),
+                check(Surface::Ssm, CheckStatus::Correct),
+                check(Surface::Ami, CheckStatus::Unreachable),
+            ],
+        };
+        assert_eq!(
+            report.summary(),
+            vec![
+                SurfaceSummary {
+                    surface: "AMI".to_string(),
+                    correct: 1,
+                    unreachable: 1,
+                    ..Default::default()
+                },
+                SurfaceSummary {
+                    surface: "SSM parameter".to_string(),
+                    correct: 1,
+                    incorrect: 1,
+                    ..Default::default()
+                },
+                SurfaceSummary {
+                    surface: "repo".to_string(),
+                    correct: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(report.problems().count(), 2);
+    }
+
+    #[test]
+    fn update_version_drops_build() {
+        assert_eq!(
+            update_version("v1.13.0-abcd1234").unwrap(),
+            Version::new(1, 13, 0)
+        );
+        assert!(update_version("latest").is_err());
+    }
+
+    #[test]
+    fn private_images_expect_launch_permissions() {
+        let image = |public, launch_permissions| Image {
+            id: "ami-1".to_string(),
+            name: "bottlerocket".to_string(),
+            public,
+            launch_permissions,
+            lineage: None,
+        };
+        assert_eq!(
+            expected_image_def(&image(Some(true), None)).launch_permissions,
+            None
+        );
+        assert_eq!(
+            expected_image_def(&image(None, None)).launch_permissions,
+            Some(vec![])
+        );
+        let shared = vec![LaunchPermissionDef::UserId("123456789012".to_string())];
+        assert_eq!(
+            expected_image_def(&image(Some(false), Some(shared.clone()))).launch_permissions,
+            Some(shared)
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! The run_state module holds what pubsys keeps track of for the length of one run: the plan being
+//! recorded, the journal of finished work, the work to report on an interrupt, the trace being
+//! recorded, and what was published, for notifications.  `run` sets it up for the subcommand it runs and passes it down, so separate runs in
+//! one process, like a release orchestrator's, don't share any of it.
+
+use crate::interrupt::Interrupt;
+use crate::journal::Journal;
+use crate::notify::Published;
+use crate::plan::Recorder;
+use crate::telemetry::Tracer;
+
+/// The state of one run.  The default records no plan, journal, or trace, and is never
+/// interrupted, which is what callers of the library's operations get.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RunState {
+    pub(crate) plan: Recorder,
+    pub(crate) journal: Journal,
+    pub(crate) interrupt: Interrupt,
+    pub(crate) tracer: Tracer,
+    pub(crate) published: Published,
+}
@@ -0,0 +1,622 @@
+//! The serve module owns the 'serve' subcommand, which answers HTTP requests with the JSON
+//! summaries of 'validate-ami', 'validate-ssm', and 'check-repo-expirations', so dashboards and
+//! monitors can check on a release without running pubsys themselves.
+//!
+//! Each check's summary is reused for `--cache-seconds` after it finishes, so frequent polling
+//! doesn't run up API calls; after that, the next GET runs the check again.  Only one check per
+//! endpoint runs at a time, and requests that arrive while it's running get its result.  The checks
+//! are given the same arguments each time, set when starting the server; an endpoint whose inputs
+//! weren't given returns 404.  Responses are 200 when the check ran, even if it found problems, since the
+//! summary says so; they're 500 when the check couldn't run.
+//!
+//! The server is deliberately minimal, answering one GET per connection and closing it, and
+//! accepting at most `--max-connections` connections at once; others wait to be accepted.  It's
+//! meant to sit behind something that handles TLS and authentication if it's reachable by others.
+
+use crate::aws::validate_ami::{self, ValidateAmiOptions};
+use crate::aws::validate_ssm::{self, ValidateSsmOptions};
+use crate::repo::check_expirations::{self, CheckExpirationsOptions, ReposToCheck};
+use crate::Args;
+use chrono::{DateTime, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use parse_datetime::parse_datetime;
+use serde_json::json;
+use snafu::ResultExt;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::{clap, StructOpt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{self, timeout, Instant};
+
+/// Requests larger than this, including headers, are cut off
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+/// Clients that don't finish sending their request in this long are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether we've been interrupted while waiting for connections
+const INTERRUPT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serves validation results over HTTP
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ServeArgs {
+    /// Address and port to listen on
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// How long to reuse a check's summary before running it again; 0 runs it for each request
+    /// that isn't waiting on one already running
+    #[structopt(long, default_value = "60")]
+    cache_seconds: u64,
+
+    /// How many connections to handle at once; more wait to be accepted
+    #[structopt(long, default_value = "32")]
+    max_connections: NonZeroUsize,
+
+    /// File holding the expected AMIs, for /validate-ami
+    #[structopt(long, parse(from_os_str))]
+    expected_amis_path: Option<PathBuf>,
+
+    /// File holding the expected parameters, for /validate-ssm
+    #[structopt(long, parse(from_os_str))]
+    expected_parameters_path: Option<PathBuf>,
+
+    /// For /validate-ssm, also report unexpected parameters in the validation regions
+    #[structopt(long)]
+    check_unexpected: bool,
+
+    /// For /check-repo-expirations, use this named repo infrastructure from Infra.toml
+    #[structopt(long)]
+    repo: Option<String>,
+
+    /// For /check-repo-expirations, the architecture of the repo to check
+    #[structopt(long)]
+    arch: Option<String>,
+
+    /// For /check-repo-expirations, the variant of the repo to check
+    #[structopt(long)]
+    variant: Option<String>,
+
+    /// For /check-repo-expirations, path to root.json for the repo
+    #[structopt(long, parse(from_os_str))]
+    root_role_path: Option<PathBuf>,
+
+    /// For /check-repo-expirations, check every repo in Infra.toml instead of one repo
+    #[structopt(long)]
+    all: bool,
+
+    /// With --all, the directory of variants to check
+    #[structopt(long, parse(from_os_str))]
+    variants_dir: Option<PathBuf>,
+
+    /// With --all, the architectures to check
+    #[structopt(long, use_delimiter = true, default_value = "x86_64,aarch64")]
+    arches: Vec<String>,
+
+    /// With --all, the directory holding the root.json of each repo, named <repo>.root.json
+    #[structopt(long, parse(from_os_str))]
+    roles_dir: Option<PathBuf>,
+
+    /// For /check-repo-expirations, report metadata expiring between the request and this time;
+    /// RFC3339 date or "in X hours/days/weeks", which is relative to each request
+    #[structopt(long)]
+    expiration_limit: Option<String>,
+
+    /// For /check-repo-expirations, mark metadata failing if it expires before this time, rather
+    /// than the expiration limit; RFC3339 date or "in X hours/days/weeks"
+    #[structopt(long)]
+    fail_within: Option<String>,
+}
+
+/// What a request asks for
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    Health,
+    ValidateAmi,
+    ValidateSsm,
+    CheckRepoExpirations,
+}
+
+/// A response to send, with a JSON body
+#[derive(Debug, Clone, PartialEq)]
+struct Response {
+    status: u16,
+    reason: &'static str,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Self {
+            status: 200,
+            reason: "OK",
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            reason,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+
+    /// Returns the response as HTTP/1.1 bytes, closing the connection after it.
+    fn to_http(&self) -> Vec<u8> {
+        let body = self.body.to_string();
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.reason,
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+}
+
+/// A check's last successful response, and when the check finished
+#[derive(Debug)]
+struct Cached {
+    response: Response,
+    finished: Instant,
+}
+
+impl Cached {
+    /// Returns whether this response can answer a request made at the given time: either it's
+    /// younger than the TTL, or the check finished after the request was made, because the
+    /// request was waiting on it.
+    fn answers(&self, requested: Instant, now: Instant, ttl: Duration) -> bool {
+        self.finished >= requested || now.saturating_duration_since(self.finished) < ttl
+    }
+}
+
+/// The checks the server runs, each behind a lock so only one of each runs at a time
+#[derive(Debug)]
+struct Checks {
+    validate_ami: Mutex<Option<Cached>>,
+    validate_ssm: Mutex<Option<Cached>>,
+    check_repo_expirations: Mutex<Option<Cached>>,
+    ttl: Duration,
+}
+
+impl Checks {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            validate_ami: Mutex::new(None),
+            validate_ssm: Mutex::new(None),
+            check_repo_expirations: Mutex::new(None),
+            ttl,
+        }
+    }
+
+    /// Returns the last response of the check for the route, or None if the route isn't a check.
+    fn last(&self, route: &Route) -> Option<&Mutex<Option<Cached>>> {
+        match route {
+            Route::Health => None,
+            Route::ValidateAmi => Some(&self.validate_ami),
+            Route::ValidateSsm => Some(&self.validate_ssm),
+            Route::CheckRepoExpirations => Some(&self.check_repo_expirations),
+        }
+    }
+}
+
+/// Returns the route asked for by an HTTP request line, like "GET /health HTTP/1.1", or the error
+/// response to send if it isn't one we serve.  Query strings are ignored.
+fn route(request_line: &str) -> std::result::Result<Route, Response> {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method, target)
+        }
+        _ => return Err(Response::error(400, "Bad Request", "Malformed request")),
+    };
+    if method != "GET" {
+        return Err(Response::error(
+            405,
+            "Method Not Allowed",
+            format!("Only GET is supported, not {}", method),
+        ));
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    match path.trim_end_matches('/') {
+        "/health" => Ok(Route::Health),
+        "/validate-ami" => Ok(Route::ValidateAmi),
+        "/validate-ssm" => Ok(Route::ValidateSsm),
+        "/check-repo-expirations" => Ok(Route::CheckRepoExpirations),
+        _ => Err(Response::error(
+            404,
+            "Not Found",
+            format!("No endpoint at {}", path),
+        )),
+    }
+}
+
+/// Returns the response for an endpoint whose inputs weren't given when starting the server.
+fn not_configured(endpoint: &str, needs: &str) -> Response {
+    Response::error(
+        404,
+        "Not Found",
+        format!(
+            "{} isn't enabled; start the server with {} to enable it",
+            endpoint, needs
+        ),
+    )
+}
+
+/// Returns the options for the 'validate-ami' check, if it's enabled.
+fn validate_ami_options(serve_args: &ServeArgs) -> Option<ValidateAmiOptions> {
+    Some(ValidateAmiOptions {
+        expected_amis_path: serve_args.expected_amis_path.clone()?,
+        no_progress: true,
+        ..Default::default()
+    })
+}
+
+/// Returns the options for the 'validate-ssm' check, if it's enabled.
+fn validate_ssm_options(serve_args: &ServeArgs) -> Option<ValidateSsmOptions> {
+    Some(ValidateSsmOptions {
+        expected_parameters_path: serve_args.expected_parameters_path.clone()?,
+        check_unexpected: serve_args.check_unexpected,
+        no_progress: true,
+        ..Default::default()
+    })
+}
+
+/// Returns the options for the 'check-repo-expirations' check, if it's enabled.  These are built
+/// again for each request, so relative times like "in 7 days" count from the request.
+fn check_expirations_options(serve_args: &ServeArgs) -> Option<Result<CheckExpirationsOptions>> {
+    let expiration_limit = serve_args.expiration_limit.as_ref()?;
+    Some(repos_to_check(serve_args).and_then(|repos| {
+        Ok(CheckExpirationsOptions {
+            repos,
+            expiration_limit: parse_time("--expiration-limit", expiration_limit)?,
+            fail_within: match &serve_args.fail_within {
+                Some(fail_within) => Some(parse_time("--fail-within", fail_within)?),
+                None => None,
+            },
+        })
+    }))
+}
+
+/// Returns the repos the 'check-repo-expirations' check covers, requiring the same arguments as
+/// the subcommand.
+fn repos_to_check(serve_args: &ServeArgs) -> Result<ReposToCheck> {
+    if serve_args.all {
+        match (&serve_args.variants_dir, &serve_args.roles_dir) {
+            (Some(variants_dir), Some(roles_dir)) => Ok(ReposToCheck::All {
+                variants_dir: variants_dir.clone(),
+                arches: serve_args.arches.clone(),
+                roles_dir: roles_dir.clone(),
+            }),
+            _ => error::MissingArgsSnafu {
+                endpoint: "/check-repo-expirations",
+                needs: "--variants-dir and --roles-dir with --all",
+            }
+            .fail(),
+        }
+    } else {
+        match (
+            &serve_args.repo,
+            &serve_args.variant,
+            &serve_args.arch,
+            &serve_args.root_role_path,
+        ) {
+            (Some(repo), Some(variant), Some(arch), Some(root_role_path)) => {
+                Ok(ReposToCheck::One {
+                    repo: repo.clone(),
+                    variant: variant.clone(),
+                    arch: arch.clone(),
+                    root_role_path: root_role_path.clone(),
+                })
+            }
+            _ => error::MissingArgsSnafu {
+                endpoint: "/check-repo-expirations",
+                needs: "--repo, --arch, --variant, and --root-role-path, or --all",
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Parses a time argument the way the subcommands do.
+fn parse_time(arg: &str, input: &str) -> Result<DateTime<Utc>> {
+    parse_datetime(input).context(error::ParseTimeSnafu { arg, input })
+}
+
+/// Returns the response for the route, from the cache if it's fresh, otherwise by running the
+/// check once any run already in progress finishes.
+async fn respond(args: &Args, serve_args: &ServeArgs, checks: &Checks, route: Route) -> Response {
+    let last = match checks.last(&route) {
+        Some(last) => last,
+        None => return run_check(args, serve_args, route).await,
+    };
+    let requested = Instant::now();
+    let mut last = last.lock().await;
+    if let Some(cached) = last.as_ref() {
+        if cached.answers(requested, Instant::now(), checks.ttl) {
+            return cached.response.clone();
+        }
+    }
+    let response = run_check(args, serve_args, route).await;
+    // Failures aren't cached, so the next request tries again.
+    if response.status == 200 {
+        *last = Some(Cached {
+            response: response.clone(),
+            finished: Instant::now(),
+        });
+    }
+    response
+}
+
+/// Runs the check asked for by the route, returning the response to send.
+async fn run_check(args: &Args, serve_args: &ServeArgs, route: Route) -> Response {
+    let result = match route {
+        Route::Health => return Response::ok(json!({ "status": "ok" })),
+        Route::ValidateAmi => match validate_ami_options(serve_args) {
+            None => return not_configured("/validate-ami", "--expected-amis-path"),
+            Some(options) => validate_ami(args, options).await,
+        },
+        Route::ValidateSsm => match validate_ssm_options(serve_args) {
+            None => return not_configured("/validate-ssm", "--expected-parameters-path"),
+            Some(options) => validate_ssm(args, options).await,
+        },
+        Route::CheckRepoExpirations => match check_expirations_options(serve_args) {
+            None => return not_configured("/check-repo-expirations", "--expiration-limit"),
+            Some(options) => check_repo_expirations(args, options).await,
+        },
+    };
+    match result {
+        Ok(summary) => Response::ok(summary),
+        Err(e) => {
+            error!("{}", e);
+            Response::error(500, "Internal Server Error", e)
+        }
+    }
+}
+
+async fn validate_ami(args: &Args, options: ValidateAmiOptions) -> Result<serde_json::Value> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let results = validate_ami::validate(&infra_config, &options)
+        .await
+        .context(error::ValidateAmiSnafu)?;
+    Ok(results.get_json_summary())
+}
+
+async fn validate_ssm(args: &Args, options: ValidateSsmOptions) -> Result<serde_json::Value> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let results = validate_ssm::validate(&infra_config, &options)
+        .await
+        .context(error::ValidateSsmSnafu)?;
+    Ok(results.get_json_summary())
+}
+
+async fn check_repo_expirations(
+    args: &Args,
+    options: Result<CheckExpirationsOptions>,
+) -> Result<serde_json::Value> {
+    let options = options?;
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    // Loading repos makes blocking requests, which can't be made from async code.
+    tokio::task::spawn_blocking(move || check_expirations::json_summary(&infra_config, &options))
+        .await
+        .context(error::JoinSnafu)?
+        .context(error::CheckRepoExpirationsSnafu)
+}
+
+/// Reads a request from the connection and returns its request line.  Headers are read and
+/// ignored, since no endpoint uses them.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_BYTES);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+/// Answers the request on one connection, then closes it.
+async fn handle_connection(
+    args: &Args,
+    serve_args: &ServeArgs,
+    checks: &Checks,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) {
+    let request_line = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request_line)) => request_line,
+        Ok(Err(e)) => {
+            warn!("Failed to read request from {}: {}", peer, e);
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out reading request from {}", peer);
+            return;
+        }
+    };
+    let response = match route(&request_line) {
+        Ok(route) => respond(args, serve_args, checks, route).await,
+        Err(response) => response,
+    };
+    info!(
+        "{} \"{}\" {}",
+        peer,
+        request_line.trim_end(),
+        response.status
+    );
+    if let Err(e) = stream.write_all(&response.to_http()).await {
+        warn!("Failed to send response to {}: {}", peer, e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, serve_args: &ServeArgs) -> Result<()> {
+    // Check the arguments up front so mistakes show at startup rather than on the first request.
+    if let Some(options) = check_expirations_options(serve_args) {
+        options?;
+    }
+
+    let listener = TcpListener::bind(serve_args.listen)
+        .await
+        .context(error::BindSnafu {
+            address: serve_args.listen,
+        })?;
+    info!("Serving on http://{}", serve_args.listen);
+
+    let checks = Checks::new(Duration::from_secs(serve_args.cache_seconds));
+    // Connections are answered concurrently, so a slow check doesn't hold up health checks, up to
+    // the limit; past that, connections wait in the listen backlog until one finishes.
+    let max_connections = serve_args.max_connections.get();
+    let mut connections = FuturesUnordered::new();
+    let mut interrupt_check = time::interval(INTERRUPT_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            accepted = listener.accept(), if connections.len() < max_connections => match accepted {
+                Ok((stream, peer)) => {
+                    connections.push(handle_connection(args, serve_args, &checks, stream, peer));
+                }
+                Err(e) => warn!("Failed to accept connection: {}", e),
+            },
+            Some(()) = connections.next(), if !connections.is_empty() => {}
+            _ = interrupt_check.tick() => {
+                if args.state.interrupt.requested() {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!(
+        "Stopped listening; finishing {} requests in flight",
+        connections.len()
+    );
+    while connections.next().await.is_some() {}
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::net::SocketAddr;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to listen on {}: {}", address, source))]
+        Bind {
+            address: SocketAddr,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to check repo expirations: {}", source))]
+        CheckRepoExpirations {
+            source: crate::repo::check_expirations::Error,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Check stopped before finishing: {}", source))]
+        Join { source: tokio::task::JoinError },
+
+        #[snafu(display("{} needs {}", endpoint, needs))]
+        MissingArgs {
+            endpoint: &'static str,
+            needs: &'static str,
+        },
+
+        #[snafu(display("Invalid time '{}' for {}: {}", input, arg, source))]
+        ParseTime {
+            arg: String,
+            input: String,
+            source: parse_datetime::Error,
+        },
+
+        #[snafu(display("Failed to validate AMIs: {}", source))]
+        ValidateAmi {
+            source: crate::aws::validate_ami::Error,
+        },
+
+        #[snafu(display("Failed to validate SSM parameters: {}", source))]
+        ValidateSsm {
+            source: crate::aws::validate_ssm::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{route, Cached, Response, Route};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn routes_requests() {
+        assert_eq!(route("GET /health HTTP/1.1\r\n"), Ok(Route::Health));
+        assert_eq!(
+            route("GET /validate-ami?region=us-west-2 HTTP/1.1\r\n"),
+            Ok(Route::ValidateAmi)
+        );
+        assert_eq!(route("GET /validate-ssm/ HTTP/1.0"), Ok(Route::ValidateSsm));
+        assert_eq!(
+            route("GET /check-repo-expirations HTTP/1.1"),
+            Ok(Route::CheckRepoExpirations)
+        );
+    }
+
+    #[test]
+    fn rejects_other_requests() {
+        let status = |request_line| route(request_line).unwrap_err().status;
+        assert_eq!(status("GET /metrics HTTP/1.1"), 404);
+        assert_eq!(status("POST /health HTTP/1.1"), 405);
+        assert_eq!(status("GET /health"), 400);
+        assert_eq!(status(""), 400);
+    }
+
+    #[test]
+    fn cached_response_freshness() {
+        let ttl = Duration::from_secs(60);
+        let start = Instant::now();
+        let cached = Cached {
+            response: Response::ok(json!({})),
+            finished: start,
+        };
+        // Within the TTL
+        assert!(cached.answers(start, start + Duration::from_secs(59), ttl));
+        // Past the TTL
+        assert!(!cached.answers(
+            start + Duration::from_secs(61),
+            start + Duration::from_secs(61),
+            ttl
+        ));
+        // Past the TTL, but the request was waiting on this check, even with caching off
+        let waited = Cached {
+            response: Response::ok(json!({})),
+            finished: start + Duration::from_secs(5),
+        };
+        assert!(waited.answers(start, start + Duration::from_secs(5), Duration::ZERO));
+        assert!(!cached.answers(
+            start + Duration::from_secs(1),
+            start + Duration::from_secs(1),
+            Duration::ZERO
+        ));
+    }
+}
@@ -0,0 +1,356 @@
+//! The telemetry module records how long each subcommand and the AWS operations within it take,
+//! and exports the timings as an OpenTelemetry trace when `--otlp-endpoint` is given, so we can see
+//! where a long publish spends its time and which regions are the long poles.
+//!
+//! Spans are kept in memory and sent once, when the subcommand finishes, to the collector's
+//! OTLP/HTTP endpoint using the JSON encoding.  Every span is a child of the subcommand's span.
+
+use log::{debug, warn};
+use reqwest::header::CONTENT_TYPE;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// The path under the collector's URL that accepts traces
+const TRACES_PATH: &str = "v1/traces";
+
+/// OTLP span kinds; see the `SpanKind` enum in the OTLP protobuf definitions.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_CLIENT: u8 = 3;
+
+/// OTLP status codes; see the `StatusCode` enum in the OTLP protobuf definitions.
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// The trace a run is recording, if the user asked for one.  Clones record into the same trace.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Tracer {
+    trace: Arc<Mutex<Option<Trace>>>,
+}
+
+/// The spans recorded so far, and where to send them
+#[derive(Debug)]
+struct Trace {
+    endpoint: Url,
+    trace_id: String,
+    root: SpanData,
+    spans: Vec<SpanData>,
+}
+
+/// Starts recording a trace for the given subcommand, to be sent to the OTLP/HTTP collector at the
+/// given URL by `export`.
+pub(crate) fn init(endpoint: Url, operation: &str) -> Result<Tracer> {
+    let trace_id = random_id::<16>()?;
+    let mut root = SpanData::new(&trace_id, operation, SPAN_KIND_INTERNAL, None)?;
+    root.attribute("pubsys.operation", operation);
+    Ok(Tracer {
+        trace: Arc::new(Mutex::new(Some(Trace {
+            endpoint,
+            trace_id,
+            root,
+            spans: Vec::new(),
+        }))),
+    })
+}
+
+impl Tracer {
+    /// Ends the subcommand's span and sends the trace to the collector, if we're recording one.
+    /// The trace is only for diagnosis, so failing to send it doesn't fail the subcommand.
+    pub(crate) fn export(&self, succeeded: bool) {
+        let trace = match self.trace.lock().ok().and_then(|mut trace| trace.take()) {
+            Some(trace) => trace,
+            None => return,
+        };
+        let url = format!(
+            "{}/{}",
+            trace.endpoint.as_str().trim_end_matches('/'),
+            TRACES_PATH
+        );
+        debug!("Sending {} spans to {}", trace.spans.len() + 1, url);
+        if let Err(e) = send(&url, trace, succeeded) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Records a span named `name` for the time it takes the given future to finish, noting the
+    /// region it ran in, if any.  The span starts when the future is first polled, so time spent
+    /// waiting in a queue of requests isn't counted.  The span's status is an error if the
+    /// future's result is.
+    pub(crate) fn traced<F, T, E>(
+        &self,
+        name: &str,
+        region: Option<&str>,
+        future: F,
+    ) -> impl Future<Output = std::result::Result<T, E>>
+    where
+        F: Future<Output = std::result::Result<T, E>>,
+    {
+        let tracer = self.clone();
+        let name = name.to_string();
+        let region = region.map(str::to_string);
+        async move {
+            let mut span = Span::start(&tracer, &name);
+            if let Some(region) = &region {
+                span.attribute("cloud.region", region);
+            }
+            let result = future.await;
+            span.end(result.is_ok());
+            result
+        }
+    }
+}
+
+/// Sends the given trace to the collector.
+fn send(url: &str, mut trace: Trace, succeeded: bool) -> Result<()> {
+    trace.root.end(succeeded);
+    let mut spans = trace.spans;
+    spans.push(trace.root);
+
+    let request = ExportTraceServiceRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: Resource {
+                attributes: vec![KeyValue::new("service.name", "pubsys")],
+            },
+            scope_spans: vec![ScopeSpans {
+                scope: Scope { name: "pubsys" },
+                spans,
+            }],
+        }],
+    };
+    let body = serde_json::to_vec(&request).context(error::SerializeSnafu)?;
+    reqwest::blocking::Client::new()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .context(error::ExportSnafu { url })?;
+    Ok(())
+}
+
+/// A span that's recorded in the trace when it's ended, if we're recording one.
+struct Span {
+    tracer: Tracer,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    /// Starts a span for an AWS operation; it's a child of the subcommand's span.
+    fn start(tracer: &Tracer, name: &str) -> Self {
+        let trace = tracer.trace.lock();
+        let data = trace.ok().and_then(|trace| {
+            trace.as_ref().and_then(|trace| {
+                SpanData::new(
+                    &trace.trace_id,
+                    name,
+                    SPAN_KIND_CLIENT,
+                    Some(&trace.root.span_id),
+                )
+                .ok()
+            })
+        });
+        Self {
+            tracer: tracer.clone(),
+            data,
+        }
+    }
+
+    fn attribute(&mut self, key: &'static str, value: &str) {
+        if let Some(data) = &mut self.data {
+            data.attribute(key, value);
+        }
+    }
+
+    fn end(mut self, succeeded: bool) {
+        if let Some(mut data) = self.data.take() {
+            data.end(succeeded);
+            if let Ok(mut trace) = self.tracer.trace.lock() {
+                if let Some(trace) = trace.as_mut() {
+                    trace.spans.push(data);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the current time in nanoseconds since the Unix epoch, as OTLP wants it.
+fn now() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Returns a random ID of N bytes, hex-encoded, as OTLP wants for trace and span IDs.
+fn random_id<const N: usize>() -> Result<String> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| error::Error::Random)?;
+    Ok(hex::encode(bytes))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+// These types mirror the OTLP JSON encoding of an ExportTraceServiceRequest, with only the fields
+// we use.
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportTraceServiceRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Debug, Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<SpanData>,
+}
+
+#[derive(Debug, Serialize)]
+struct Scope {
+    name: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+impl SpanData {
+    fn new(trace_id: &str, name: &str, kind: u8, parent_span_id: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            trace_id: trace_id.to_string(),
+            span_id: random_id::<8>()?,
+            parent_span_id: parent_span_id.map(str::to_string),
+            name: name.to_string(),
+            kind,
+            start_time_unix_nano: now(),
+            end_time_unix_nano: String::new(),
+            attributes: Vec::new(),
+            status: Status::default(),
+        })
+    }
+
+    fn attribute(&mut self, key: &'static str, value: &str) {
+        self.attributes.push(KeyValue::new(key, value));
+    }
+
+    fn end(&mut self, succeeded: bool) {
+        self.end_time_unix_nano = now();
+        self.status.code = if succeeded {
+            STATUS_CODE_OK
+        } else {
+            STATUS_CODE_ERROR
+        };
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Status {
+    code: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+impl KeyValue {
+    fn new(key: &'static str, value: &str) -> Self {
+        Self {
+            key,
+            value: AnyValue {
+                string_value: value.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to send trace to {}: {}", url, source))]
+        Export { url: String, source: reqwest::Error },
+
+        #[snafu(display("Failed to generate a random trace ID"))]
+        Random,
+
+        #[snafu(display("Failed to serialize trace: {}", source))]
+        Serialize { source: serde_json::Error },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{KeyValue, SpanData, SPAN_KIND_CLIENT};
+
+    #[test]
+    fn otlp_json_span() {
+        let mut span = SpanData::new(
+            "0af7651916cd43dd8448eb211c80319c",
+            "wait_for_ami",
+            SPAN_KIND_CLIENT,
+            Some("b7ad6b7169203331"),
+        )
+        .unwrap();
+        span.attributes
+            .push(KeyValue::new("cloud.region", "us-west-2"));
+        span.end(false);
+
+        let value = serde_json::to_value(&span).unwrap();
+        assert_eq!(value["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(value["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(value["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(value["kind"], 3);
+        assert_eq!(value["status"]["code"], 2);
+        assert_eq!(value["attributes"][0]["key"], "cloud.region");
+        assert_eq!(value["attributes"][0]["value"]["stringValue"], "us-west-2");
+        let start: u128 = value["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let end: u128 = value["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert!(start <= end);
+    }
+}
@@ -0,0 +1,401 @@
+//! The test_trigger module starts tests of a build once its AMIs or SSM parameters are published,
+//! as configured in the `tests` section of Infra.toml: by running a command, like one that submits
+//! a testsys run, or by POSTing to an HTTPS endpoint, or both.  Each gets the build and the AMI ID
+//! in each region.
+//!
+//! If the config asks to wait, the subcommand doesn't finish until the tests do, and the result is
+//! tagged on the AMIs, so `promote-ssm --require-tests` can refuse to promote untested AMIs.
+
+use crate::aws::client::{build_client_config, ServiceClient};
+use crate::aws::rate_limit::{RateLimits, EC2};
+use crate::exit_code::{Classify, Kind};
+use crate::interrupt::Interrupt;
+use crate::Args;
+use aws_sdk_ec2::model::Tag;
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use log::{error, info, warn};
+use pubsys_config::{AwsConfig as PubsysAwsConfig, TestsConfig};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use url::Url;
+
+/// The tag recording whether an AMI passed the tests started after it was published
+const TESTS_TAG: &str = "pubsys:tests";
+const PASSED: &str = "passed";
+const FAILED: &str = "failed";
+
+/// Tests are only started after this subcommand if the config doesn't say otherwise.
+const DEFAULT_AFTER: &str = "ssm";
+const DEFAULT_TIMEOUT_MINUTES: u64 = 120;
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the tests are asked to test: the build, and its AMI in each region
+#[derive(Debug, Serialize)]
+pub(crate) struct TestRequest<'a> {
+    /// The subcommand that published the build, like "ssm"
+    pub(crate) subcommand: &'a str,
+    pub(crate) variant: Option<&'a str>,
+    pub(crate) version: Option<&'a str>,
+    pub(crate) arch: &'a str,
+    /// AMI IDs, keyed by region name
+    pub(crate) amis: BTreeMap<String, String>,
+}
+
+/// The webhook's response, when we wait for the tests
+#[derive(Debug, Deserialize)]
+struct Started {
+    status_url: Url,
+}
+
+/// The response from the webhook's status URL
+#[derive(Debug, Deserialize)]
+struct Status {
+    status: String,
+}
+
+/// Starts the configured tests, if there are any for the subcommand, and waits for them if the
+/// config asks to.
+pub(crate) async fn trigger(args: &Args, request: &TestRequest<'_>) -> Result<()> {
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    let tests = match infra_config.tests {
+        Some(tests) => tests,
+        None => return Ok(()),
+    };
+    if !runs_after(&tests, request.subcommand) {
+        return Ok(());
+    }
+    if tests.command.is_empty() && tests.webhook.is_none() {
+        warn!("Not starting tests, since the tests config has no command or webhook");
+        return Ok(());
+    }
+    let aws = infra_config.aws.unwrap_or_default();
+
+    let timeout =
+        Duration::from_secs(60 * tests.timeout_minutes.unwrap_or(DEFAULT_TIMEOUT_MINUTES));
+    let deadline = Instant::now() + timeout;
+    let json = serde_json::to_string(request).context(error::SerializeSnafu)?;
+
+    let mut passed = true;
+    if !tests.command.is_empty() {
+        passed &= run_command(&tests, request, &json, deadline).await?;
+    }
+    if let Some(webhook) = &tests.webhook {
+        let client = reqwest::Client::new();
+        let mut post = client
+            .post(webhook.url.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for (name, value) in &webhook.headers {
+            post = post.header(name, value);
+        }
+        let host = webhook.url.host_str().unwrap_or_default();
+        info!("Starting tests at {}", host);
+        let response = post
+            .body(json.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(error::RequestSnafu { host })?;
+        if tests.wait {
+            let started: Started = response
+                .json()
+                .await
+                .context(error::ResponseSnafu { host })?;
+            passed &= poll_status(
+                &args.state.interrupt,
+                &client,
+                &started.status_url,
+                deadline,
+            )
+            .await?;
+        }
+    }
+
+    if !tests.wait {
+        info!("Started tests; not waiting for their results");
+        return Ok(());
+    }
+    tag_results(&aws, &request.amis, passed).await?;
+    ensure!(
+        passed,
+        error::FailedSnafu {
+            version: request.version.unwrap_or("the build")
+        }
+    );
+    info!("Tests passed");
+    Ok(())
+}
+
+/// Returns whether the config starts tests after the given subcommand.
+fn runs_after(tests: &TestsConfig, subcommand: &str) -> bool {
+    if tests.after.is_empty() {
+        subcommand == DEFAULT_AFTER
+    } else {
+        tests.after.iter().any(|after| after == subcommand)
+    }
+}
+
+/// Runs the configured command with the request in its environment.  If waiting, returns whether
+/// the command succeeded; otherwise, the command only has to start the tests, so it failing is an
+/// error.
+async fn run_command(
+    tests: &TestsConfig,
+    request: &TestRequest<'_>,
+    json: &str,
+    deadline: Instant,
+) -> Result<bool> {
+    let program = &tests.command[0];
+    info!("Starting tests with {}", tests.command.join(" "));
+    let mut command = Command::new(program);
+    command
+        .args(&tests.command[1..])
+        .env("BOTTLEROCKET_TEST_REQUEST", json)
+        .env("BOTTLEROCKET_TEST_ARCH", request.arch)
+        .kill_on_drop(true);
+    if let Some(variant) = request.variant {
+        command.env("BOTTLEROCKET_TEST_VARIANT", variant);
+    }
+    if let Some(version) = request.version {
+        command.env("BOTTLEROCKET_TEST_VERSION", version);
+    }
+
+    let status = tokio::time::timeout_at(deadline.into(), command.status())
+        .await
+        .ok()
+        .context(error::TimeoutSnafu)?
+        .context(error::CommandSnafu { program })?;
+    if status.success() {
+        return Ok(true);
+    }
+    ensure!(tests.wait, error::CommandFailedSnafu { program, status });
+    error!("Tests run by {} failed with {}", program, status);
+    Ok(false)
+}
+
+/// Polls the webhook's status URL until the tests pass or fail, and returns whether they passed.
+async fn poll_status(
+    interrupt: &Interrupt,
+    client: &reqwest::Client,
+    status_url: &Url,
+    deadline: Instant,
+) -> Result<bool> {
+    let host = status_url.host_str().unwrap_or_default();
+    info!("Waiting for tests to finish, per {}", host);
+    loop {
+        ensure!(!interrupt.requested(), error::InterruptedSnafu);
+        ensure!(Instant::now() < deadline, error::TimeoutSnafu);
+        let status: Status = client
+            .get(status_url.as_str())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(error::RequestSnafu { host })?
+            .json()
+            .await
+            .context(error::ResponseSnafu { host })?;
+        match status.status.as_str() {
+            PASSED => return Ok(true),
+            FAILED => {
+                error!("Tests at {} failed", host);
+                return Ok(false);
+            }
+            other => info!("Tests are {}; checking again in {:?}", other, POLL_INTERVAL),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Tags each AMI with whether the tests passed.
+async fn tag_results(
+    aws: &PubsysAwsConfig,
+    amis: &BTreeMap<String, String>,
+    passed: bool,
+) -> Result<()> {
+    let result = if passed { PASSED } else { FAILED };
+    let base_region = match amis.keys().next() {
+        Some(region) => Region::new(region.clone()),
+        None => return Ok(()),
+    };
+    let rate_limits = RateLimits::new(aws);
+    for (region, image_id) in amis {
+        let region = Region::new(region.clone());
+        let client_config = build_client_config(&region, &base_region, aws).await;
+        let ec2_client = Ec2Client::from_pubsys_config(&client_config, aws);
+        rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                ec2_client
+                    .create_tags()
+                    .resources(image_id)
+                    .tags(Tag::builder().key(TESTS_TAG).value(result).build())
+                    .send(),
+            )
+            .await
+            .context(error::CreateTagsSnafu {
+                image_id,
+                region: region.as_ref(),
+            })?;
+    }
+    Ok(())
+}
+
+/// Fails unless each of the given AMIs, keyed by region, is tagged as having passed its tests.
+pub(crate) async fn check_passed(
+    images: &[(Region, String)],
+    ec2_clients: &HashMap<Region, Ec2Client>,
+    rate_limits: &RateLimits,
+) -> Result<()> {
+    let mut untested = 0;
+    for (region, image_id) in images {
+        let response = rate_limits
+            .rate_limited(
+                EC2,
+                region.as_ref(),
+                ec2_clients[region]
+                    .describe_images()
+                    .image_ids(image_id)
+                    .send(),
+            )
+            .await
+            .context(error::DescribeImagesSnafu {
+                image_id,
+                region: region.as_ref(),
+            })?;
+        let result = response
+            .images()
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|image| image.tags().unwrap_or_default())
+            .find(|tag| tag.key() == Some(TESTS_TAG))
+            .and_then(|tag| tag.value());
+        if result != Some(PASSED) {
+            untested += 1;
+            error!(
+                "{} in {} hasn't passed its tests; its {} tag is {}",
+                image_id,
+                region,
+                TESTS_TAG,
+                result.unwrap_or("missing")
+            );
+        }
+    }
+    ensure!(untested == 0, error::UntestedSnafu { count: untested });
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::{CreateTagsError, DescribeImagesError};
+    use aws_sdk_ec2::types::SdkError;
+    use snafu::Snafu;
+    use std::process::ExitStatus;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to run '{}': {}", program, source))]
+        Command {
+            program: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("'{}' failed to start tests: {}", program, status))]
+        CommandFailed { program: String, status: ExitStatus },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to tag {} in {} with its test result: {}",
+            image_id,
+            region,
+            source
+        ))]
+        CreateTags {
+            image_id: String,
+            region: String,
+            source: SdkError<CreateTagsError>,
+        },
+
+        #[snafu(display("Failed to describe {} in {}: {}", image_id, region, source))]
+        DescribeImages {
+            image_id: String,
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display("Tests of {} failed", version))]
+        Failed { version: String },
+
+        #[snafu(display("Interrupted while waiting for tests"))]
+        Interrupted,
+
+        #[snafu(display("Failed to reach test endpoint at {}: {}", host, source))]
+        Request {
+            host: String,
+            source: reqwest::Error,
+        },
+
+        #[snafu(display("Failed to parse response from test endpoint at {}: {}", host, source))]
+        Response {
+            host: String,
+            source: reqwest::Error,
+        },
+
+        #[snafu(display("Failed to serialize test request: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Timed out waiting for tests"))]
+        Timeout,
+
+        #[snafu(display("{} AMIs haven't passed their tests", count))]
+        Untested { count: usize },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+impl Classify for Error {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::CreateTags { .. } | Self::DescribeImages { .. } => Some(Kind::AwsApi),
+            Self::Failed { .. } | Self::Untested { .. } => Some(Kind::Validation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{runs_after, TestRequest};
+    use pubsys_config::TestsConfig;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn after() {
+        let mut tests = TestsConfig::default();
+        assert!(runs_after(&tests, "ssm"));
+        assert!(!runs_after(&tests, "ami"));
+
+        tests.after = vec!["ami".to_string()];
+        assert!(runs_after(&tests, "ami"));
+        assert!(!runs_after(&tests, "ssm"));
+    }
+
+    #[test]
+    fn request_json() {
+        let request = TestRequest {
+            subcommand: "ami",
+            variant: Some("aws-dev"),
+            version: None,
+            arch: "x86_64",
+            amis: BTreeMap::from([("us-west-2".to_string(), "ami-123".to_string())]),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"subcommand":"ami","variant":"aws-dev","version":null,"arch":"x86_64","amis":{"us-west-2":"ami-123"}}"#
+        );
+    }
+}
@@ -3,13 +3,32 @@
 use duct::cmd;
 use log::trace;
 use pubsys_config::vmware::{Datacenter, DatacenterCreds};
-use snafu::ResultExt;
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt};
 use std::env;
 use std::path::Path;
 use std::process::Output;
 
+/// A file in a content library item, as `govc library.info -json` shows it
+#[derive(Debug, Deserialize)]
+pub(crate) struct LibraryFile {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) size: Option<u64>,
+    #[serde(default)]
+    pub(crate) checksum_info: Option<ChecksumInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChecksumInfo {
+    #[serde(default)]
+    pub(crate) algorithm: Option<String>,
+    pub(crate) checksum: String,
+}
+
 pub(crate) struct Govc {
     env_config: Vec<String>,
+    content_library: Option<String>,
 }
 
 impl Govc {
@@ -28,17 +47,66 @@ impl Govc {
         env_config.env_arg("GOVC_RESOURCE_POOL", dc.resource_pool);
         env_config.env_arg("GOVC_FOLDER", dc.folder);
 
-        Self { env_config }
+        Self {
+            env_config,
+            content_library: dc.content_library,
+        }
+    }
+
+    /// Returns a description of where `upload_ova` puts the OVA, for messages.
+    pub(crate) fn target(&self) -> String {
+        match &self.content_library {
+            Some(library) => format!("content library '{}'", library),
+            None => "folder".to_string(),
+        }
+    }
+
+    /// Returns the content library the datacenter uploads to, if any.
+    pub(crate) fn content_library(&self) -> Option<&str> {
+        self.content_library.as_deref()
+    }
+
+    /// Returns the files of the given item in the datacenter's content library.
+    pub(crate) fn library_files(&self, name: &str) -> Result<Vec<LibraryFile>> {
+        let library = self
+            .content_library
+            .as_deref()
+            .context(error::NoContentLibrarySnafu)?;
+        let item_files = format!("/{}/{}/*", library, name);
+        let output = self.run(&[Self::GOVC, "library.info", "-json", &item_files])?;
+        let files: Option<Vec<LibraryFile>> =
+            serde_json::from_slice(&output.stdout).context(error::ParseOutputSnafu {
+                command: "library.info",
+            })?;
+        Ok(files.unwrap_or_default())
     }
 
-    /// Run `govc import.ova` using Docker.
+    /// Removes the given item from the datacenter's content library, so it can be uploaded again.
+    pub(crate) fn remove_library_item(&self, name: &str) -> Result<()> {
+        let library = self
+            .content_library
+            .as_deref()
+            .context(error::NoContentLibrarySnafu)?;
+        let item = format!("/{}/{}", library, name);
+        self.run(&[Self::GOVC, "library.rm", &item]).map(drop)
+    }
+
+    /// Runs the given `govc` command, which doesn't need any files, using Docker.
+    fn run(&self, govc_cmd: &[&str]) -> Result<Output> {
+        let env_config: Vec<&str> = self.env_config.iter().map(|s| s.as_ref()).collect();
+        docker_run(&env_config, None, govc_cmd)
+    }
+
+    /// Run `govc import.ova`, or `govc library.import` if the datacenter has a content library,
+    /// using Docker.
     ///
     /// Using the given name, OVA path, and import spec path, this function builds the `govc
-    /// import.ova` command as it will be used in the container.  It also builds the necessary bind
+    /// import.ova` command as it will be used in the container.  Content library imports don't use
+    /// the import spec; the library item is an OVF template that VMs are deployed from later.  It also builds the necessary bind
     /// mount arguments to mount the import spec and OVA into the container.  Finally, it calls
     /// `govc` via `docker run` invocation using these arguments.
     pub(crate) fn upload_ova<S, P1, P2>(
-        self,
+        &self,
         name: S,
         ova_path: P1,
         import_spec_path: P2,
@@ -75,19 +143,31 @@ impl Govc {
             ),
         ];
 
-        // govc import.ova -options=/path/to/spec -name bottlerocket_vm_name /path/to/ova
-        let govc_cmd = &[
-            Self::GOVC,
-            "import.ova",
-            &format!("-options={}", import_spec_container_path),
-            "-name",
-            name,
-            ova_container_path,
-        ];
+        let options = format!("-options={}", import_spec_container_path);
+        let govc_cmd = match &self.content_library {
+            // govc library.import -n bottlerocket_vm_name library_name /path/to/ova
+            Some(library) => vec![
+                Self::GOVC,
+                "library.import",
+                "-n",
+                name,
+                library,
+                ova_container_path,
+            ],
+            // govc import.ova -options=/path/to/spec -name bottlerocket_vm_name /path/to/ova
+            None => vec![
+                Self::GOVC,
+                "import.ova",
+                &options,
+                "-name",
+                name,
+                ova_container_path,
+            ],
+        };
 
         let env_config: Vec<&str> = self.env_config.iter().map(|s| s.as_ref()).collect();
 
-        docker_run(&env_config, Some(mount_config), govc_cmd)
+        docker_run(&env_config, Some(mount_config), &govc_cmd)
     }
 }
 
@@ -159,7 +239,7 @@ pub(crate) mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Failed to start command: {}", source))]
         CommandStart { source: std::io::Error },
 
@@ -171,7 +251,16 @@ pub(crate) mod error {
             var: String,
             source: std::env::VarError,
         },
+
+        #[snafu(display("Datacenter has no content library configured"))]
+        NoContentLibrary,
+
+        #[snafu(display("Failed to parse output of govc {}: {}", command, source))]
+        ParseOutput {
+            command: String,
+            source: serde_json::Error,
+        },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
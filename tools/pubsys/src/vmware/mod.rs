@@ -1,2 +1,3 @@
 pub(crate) mod govc;
+mod ova;
 pub(crate) mod upload_ova;
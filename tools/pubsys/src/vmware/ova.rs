@@ -0,0 +1,293 @@
+//! The ova module reads the files in a local OVA, which is a tar archive of an OVF descriptor and
+//! its disks, and checks them against the files of a content library item uploaded from it.
+use crate::vmware::govc::LibraryFile;
+use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use snafu::{ensure, ResultExt};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Tar archives are made of 512-byte blocks; each file has a header block, then its data padded to
+/// a whole block
+const BLOCK_SIZE: usize = 512;
+
+/// A file in the local OVA, with the checksums content libraries may report for it
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct OvaFile {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) sha1: String,
+    pub(crate) sha256: String,
+}
+
+/// Returns the files in the OVA at the given path.
+pub(crate) fn ova_files<P>(path: P) -> Result<Vec<OvaFile>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let file = File::open(path).context(error::ReadOvaSnafu { path })?;
+    read_tar(BufReader::new(file)).context(error::ReadOvaSnafu { path })
+}
+
+/// Reads a ustar archive, which is what the OVF spec requires OVAs to be, and returns its regular
+/// files.
+fn read_tar<R: Read>(mut reader: R) -> io::Result<Vec<OvaFile>> {
+    let mut files = Vec::new();
+    let mut header = [0u8; BLOCK_SIZE];
+    loop {
+        reader.read_exact(&mut header)?;
+        // The archive ends with zeroed blocks.
+        if header.iter().all(|b| *b == 0) {
+            return Ok(files);
+        }
+
+        let mut name = header_str(&header[0..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = header_str(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        let size = u64::from_str_radix(header_str(&header[124..136]).trim(), 8).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid size for '{}': {}", name, e),
+            )
+        })?;
+        let padded_size = size + (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64;
+
+        // Only regular files have content we care about; skip anything else.
+        let typeflag = header[156];
+        if typeflag != b'0' && typeflag != 0 {
+            io::copy(&mut (&mut reader).take(padded_size), &mut io::sink())?;
+            continue;
+        }
+
+        let mut sha1 = Context::new(&SHA1_FOR_LEGACY_USE_ONLY);
+        let mut sha256 = Context::new(&SHA256);
+        let mut data = (&mut reader).take(size);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let read = data.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            sha1.update(&buf[..read]);
+            sha256.update(&buf[..read]);
+        }
+        if data.limit() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("'{}' is truncated", name),
+            ));
+        }
+        io::copy(&mut (&mut reader).take(padded_size - size), &mut io::sink())?;
+
+        files.push(OvaFile {
+            name,
+            size,
+            sha1: hex::encode(sha1.finish()),
+            sha256: hex::encode(sha256.finish()),
+        });
+    }
+}
+
+/// Returns the NUL-terminated string at the start of a header field.
+fn header_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+/// Checks that the content library item has each of the OVA's descriptor and disk files, with the
+/// same size and checksum.  Returns a description of each difference; an empty list means the
+/// upload matches.
+pub(crate) fn compare(local: &[OvaFile], library: &[LibraryFile]) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    // The manifest and certificate only describe the other files, and libraries needn't keep them.
+    for file in local
+        .iter()
+        .filter(|f| !f.name.ends_with(".mf") && !f.name.ends_with(".cert"))
+    {
+        let library_file = match library.iter().find(|l| l.name == file.name) {
+            Some(library_file) => library_file,
+            None => {
+                mismatches.push(format!("{} is missing", file.name));
+                continue;
+            }
+        };
+
+        if let Some(size) = library_file.size {
+            if size != file.size {
+                mismatches.push(format!(
+                    "{} is {} bytes, expected {}",
+                    file.name, size, file.size
+                ));
+                continue;
+            }
+        }
+
+        let checksum = library_file.checksum_info.as_ref();
+        let expected = match checksum.and_then(|c| c.algorithm.as_deref()) {
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("SHA1") => &file.sha1,
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("SHA256") => &file.sha256,
+            _ => {
+                mismatches.push(format!("{} has no SHA-1 or SHA-256 checksum", file.name));
+                continue;
+            }
+        };
+        // The algorithm was found above, so the checksum info exists.
+        let actual = checksum
+            .map(|c| c.checksum.to_lowercase())
+            .unwrap_or_default();
+        if &actual != expected {
+            mismatches.push(format!(
+                "{} has checksum {}, expected {}",
+                file.name, actual, expected
+            ));
+        }
+    }
+    mismatches
+}
+
+/// Checks the library item against the local OVA, failing with the differences if they don't
+/// match.
+pub(crate) fn verify(local: &[OvaFile], library: &[LibraryFile]) -> Result<()> {
+    let mismatches = compare(local, library);
+    ensure!(
+        mismatches.is_empty(),
+        error::MismatchSnafu {
+            mismatches: mismatches.join("; "),
+        }
+    );
+    Ok(())
+}
+
+pub(crate) mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Uploaded files don't match the OVA: {}", mismatches))]
+        Mismatch { mismatches: String },
+
+        #[snafu(display("Failed to read OVA '{}': {}", path.display(), source))]
+        ReadOva {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{compare, read_tar, OvaFile, BLOCK_SIZE};
+    use crate::vmware::govc::{ChecksumInfo, LibraryFile};
+    use std::io::Cursor;
+
+    /// Appends a regular file to a tar archive being built in memory.
+    fn append(tar: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}\0", data.len());
+        header[124..136].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        tar.extend_from_slice(&header);
+        tar.extend_from_slice(data);
+        tar.resize(
+            tar.len() + (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE,
+            0,
+        );
+    }
+
+    fn library_file(name: &str, size: u64, algorithm: &str, checksum: &str) -> LibraryFile {
+        LibraryFile {
+            name: name.to_string(),
+            size: Some(size),
+            checksum_info: Some(ChecksumInfo {
+                algorithm: Some(algorithm.to_string()),
+                checksum: checksum.to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn reads_files() {
+        let mut tar = Vec::new();
+        append(&mut tar, "vm.ovf", b"abc");
+        append(&mut tar, "vm.mf", &[]);
+        tar.resize(tar.len() + BLOCK_SIZE * 2, 0);
+
+        let files = read_tar(Cursor::new(tar)).unwrap();
+        assert_eq!(
+            files[0],
+            OvaFile {
+                name: "vm.ovf".to_string(),
+                size: 3,
+                sha1: "a9993e364706816aba3e25717850c26c9cd0d89d".to_string(),
+                sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_string(),
+            }
+        );
+        assert_eq!(files[1].name, "vm.mf");
+        assert_eq!(files[1].size, 0);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut tar = Vec::new();
+        append(&mut tar, "vm.ovf", b"abc");
+        tar.truncate(BLOCK_SIZE + 1);
+        assert!(read_tar(Cursor::new(tar)).is_err());
+    }
+
+    #[test]
+    fn compares_files() {
+        let local = vec![
+            OvaFile {
+                name: "vm.ovf".to_string(),
+                size: 3,
+                sha1: "aa".to_string(),
+                sha256: "bb".to_string(),
+            },
+            OvaFile {
+                name: "disk.vmdk".to_string(),
+                size: 5,
+                sha1: "cc".to_string(),
+                sha256: "dd".to_string(),
+            },
+            OvaFile {
+                name: "vm.mf".to_string(),
+                size: 1,
+                sha1: "ee".to_string(),
+                sha256: "ff".to_string(),
+            },
+        ];
+
+        let matching = vec![
+            library_file("vm.ovf", 3, "SHA1", "AA"),
+            library_file("disk.vmdk", 5, "SHA256", "dd"),
+        ];
+        assert!(compare(&local, &matching).is_empty());
+
+        let corrupted = vec![
+            library_file("vm.ovf", 3, "SHA1", "ab"),
+            library_file("disk.vmdk", 4, "SHA256", "dd"),
+        ];
+        assert_eq!(compare(&local, &corrupted).len(), 2);
+
+        let incomplete = vec![library_file("vm.ovf", 3, "MD5", "aa")];
+        assert_eq!(
+            compare(&local, &incomplete),
+            vec![
+                "vm.ovf has no SHA-1 or SHA-256 checksum",
+                "disk.vmdk is missing"
+            ]
+        );
+    }
+}
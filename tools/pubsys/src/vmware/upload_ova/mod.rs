@@ -1,23 +1,40 @@
 //! The upload_ova module owns the 'upload_ova' subcommand and is responsible for collating all of
 //! the config necessary to upload an OVA bundle to VMware datacenters.
+//!
+//! Datacenters are uploaded to in parallel, each retried on its own if its upload fails, and a
+//! table of the results is printed at the end.  One datacenter failing doesn't stop the others.
+//!
+//! Uploads to a content library are verified by comparing the checksums the library reports for
+//! the item's files with the files in the local OVA, so a corrupted upload fails rather than
+//! becoming a release artifact.
 use crate::vmware::govc::Govc;
+use crate::vmware::ova::{self, OvaFile};
 use crate::Args;
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
 use pubsys_config::vmware::{
     Datacenter, DatacenterBuilder, DatacenterCreds, DatacenterCredsBuilder, DatacenterCredsConfig,
     VMWARE_CREDS_PATH,
 };
-use pubsys_config::InfraConfig;
+use rayon::prelude::*;
 use serde::Serialize;
 use snafu::{ensure, OptionExt, ResultExt};
 use std::fs;
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 use structopt::{clap, StructOpt};
+use tabled::{Table, Tabled};
 use tempfile::NamedTempFile;
 use tinytemplate::TinyTemplate;
 
 const SPEC_TEMPLATE_NAME: &str = "spec_template";
 
+/// OVAs are large, so limit how many uploads share the network at once
+const MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// How long to wait before the first retry of a failed upload; later retries wait longer
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(15);
+
 /// Uploads a Bottlerocket OVA to VMware datacenters
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
@@ -41,13 +58,41 @@ pub(crate) struct UploadArgs {
     /// Datacenters to which you want to upload the OVA
     #[structopt(long, use_delimiter = true)]
     datacenters: Vec<String>,
+
+    /// How many times to retry a datacenter's upload if it fails
+    #[structopt(long, default_value = "2")]
+    retries: u32,
+
+    /// Don't compare content library uploads to the local OVA
+    #[structopt(long)]
+    no_verify: bool,
+
+    /// Remove and retry a content library upload that doesn't match the local OVA, rather than
+    /// failing it right away
+    #[structopt(long)]
+    retry_on_mismatch: bool,
+}
+
+/// Everything needed to upload to one datacenter
+struct DatacenterUpload {
+    name: String,
+    govc: Govc,
+    import_spec: NamedTempFile,
+}
+
+/// The outcome of uploading to one datacenter, as shown in the summary table
+#[derive(Tabled)]
+struct UploadRow {
+    datacenter: String,
+    target: String,
+    attempts: u32,
+    result: String,
 }
 
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
     // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::InfraConfigSnafu)?;
+    let infra_config = args.infra_config(true).context(error::InfraConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
     let vmware = infra_config
@@ -100,10 +145,9 @@ pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
             path: &upload_args.spec,
         })?;
 
-    info!(
-        "Uploading to datacenters: {}",
-        &upload_datacenters.join(", ")
-    );
+    // Build every datacenter's config first, so that a config mistake fails before any upload
+    // starts
+    let mut uploads = Vec::new();
     for dc in upload_datacenters {
         debug!("Building config for {}", &dc);
         // If any specific configuration exists for this datacenter, retrieve it from VMware
@@ -136,24 +180,160 @@ pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
         })?;
         trace!("Import spec: {}", &rendered_spec);
 
+        uploads.push(DatacenterUpload {
+            name: dc.clone(),
+            govc: Govc::new(datacenter, creds),
+            import_spec,
+        });
+    }
+
+    // Read the local OVA's checksums once, if any upload will be verified against them
+    let local_files = if !upload_args.no_verify
+        && uploads
+            .iter()
+            .any(|upload| upload.govc.content_library().is_some())
+    {
+        info!("Reading checksums of {}", upload_args.ova.display());
+        Some(ova::ova_files(&upload_args.ova).context(error::ReadOvaSnafu)?)
+    } else {
+        None
+    };
+
+    info!(
+        "Uploading to datacenters: {}",
+        &upload_datacenters.join(", ")
+    );
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(MAX_CONCURRENT_UPLOADS.min(uploads.len()))
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+    let results: Vec<(UploadRow, bool)> = thread_pool.install(|| {
+        uploads
+            .par_iter()
+            .map(|upload| upload_with_retries(upload_args, upload, local_files.as_deref()))
+            .collect()
+    });
+
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|(_, succeeded)| !succeeded)
+        .map(|(row, _)| row.datacenter.clone())
+        .collect();
+    println!("{}", Table::new(results.into_iter().map(|(row, _)| row)));
+    ensure!(
+        failed.is_empty(),
+        error::FailedUploadsSnafu {
+            datacenters: failed.join(", ")
+        }
+    );
+
+    Ok(())
+}
+
+/// Uploads the OVA to one datacenter, retrying with a growing delay if it fails.  Returns the row
+/// for the summary table and whether the upload succeeded.
+fn upload_with_retries(
+    upload_args: &UploadArgs,
+    upload: &DatacenterUpload,
+    local_files: Option<&[OvaFile]>,
+) -> (UploadRow, bool) {
+    let dc = &upload.name;
+    let target = upload.govc.target();
+    let mut attempt = 1;
+    loop {
         if upload_args.mark_as_template {
             info!(
-                "Uploading OVA to datacenter '{}' as template with name: '{}'",
-                &dc, &upload_args.name
+                "Uploading OVA to {} in datacenter '{}' as template with name: '{}'",
+                target, dc, &upload_args.name
             );
         } else {
             info!(
-                "Uploading OVA to datacenter '{}' with name '{}'",
-                &dc, &upload_args.name
+                "Uploading OVA to {} in datacenter '{}' with name '{}'",
+                target, dc, &upload_args.name
             );
         }
 
-        Govc::new(datacenter, creds)
-            .upload_ova(&upload_args.name, &upload_args.ova, import_spec)
-            .context(error::UploadOvaSnafu)?;
+        let result = upload_ova(upload_args, upload, local_files);
+        let row = |result: String| UploadRow {
+            datacenter: dc.clone(),
+            target: target.clone(),
+            attempts: attempt,
+            result,
+        };
+        match result {
+            Ok(verified) => {
+                info!("Uploaded OVA to datacenter '{}'", dc);
+                let result = if verified {
+                    "uploaded, verified"
+                } else {
+                    "uploaded"
+                };
+                return (row(result.to_string()), true);
+            }
+            Err(e @ error::Error::Verify { .. })
+                if attempt <= upload_args.retries && upload_args.retry_on_mismatch =>
+            {
+                // The bad item has to go before it can be uploaded again.
+                warn!(
+                    "Upload to datacenter '{}' doesn't match, removing it: {}",
+                    dc, e
+                );
+                if let Err(e) = upload.govc.remove_library_item(&upload_args.name) {
+                    error!(
+                        "Failed to remove bad upload from datacenter '{}': {}",
+                        dc, e
+                    );
+                    return (row(format!("failed: {}", e)), false);
+                }
+                attempt += 1;
+            }
+            Err(e @ error::Error::Verify { .. }) => {
+                error!("Upload to datacenter '{}' doesn't match: {}", dc, e);
+                return (row(format!("failed: {}", e)), false);
+            }
+            Err(e) if attempt <= upload_args.retries => {
+                let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                warn!(
+                    "Upload to datacenter '{}' failed, retrying in {:?}: {}",
+                    dc, delay, e
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Upload to datacenter '{}' failed: {}", dc, e);
+                return (row(format!("failed: {}", e)), false);
+            }
+        }
     }
+}
 
-    Ok(())
+/// Uploads the OVA to one datacenter, once, and verifies it if it went to a content library and
+/// local checksums are given.  Returns whether the upload was verified.
+fn upload_ova(
+    upload_args: &UploadArgs,
+    upload: &DatacenterUpload,
+    local_files: Option<&[OvaFile]>,
+) -> Result<bool> {
+    upload
+        .govc
+        .upload_ova(
+            &upload_args.name,
+            &upload_args.ova,
+            upload.import_spec.path(),
+        )
+        .context(error::UploadOvaSnafu)?;
+
+    let local_files = match (upload.govc.content_library(), local_files) {
+        (Some(_), Some(local_files)) => local_files,
+        _ => return Ok(false),
+    };
+    let library_files = upload
+        .govc
+        .library_files(&upload_args.name)
+        .context(error::LibraryFilesSnafu)?;
+    ova::verify(local_files, &library_files).context(error::VerifySnafu)?;
+    Ok(true)
 }
 
 /// Render the import spec template given the current network and template setting.
@@ -185,7 +365,7 @@ mod error {
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
-    pub(crate) enum Error {
+    pub enum Error {
         #[snafu(display("Error building template from '{}': {}", path.display(), source))]
         AddTemplate {
             path: PathBuf,
@@ -208,6 +388,9 @@ mod error {
             source: std::env::VarError,
         },
 
+        #[snafu(display("Failed to upload OVA to datacenters: {}", datacenters))]
+        FailedUploads { datacenters: String },
+
         #[snafu(display("Failed to {} '{}': {}", action, path.display(), source))]
         File {
             action: String,
@@ -218,15 +401,24 @@ mod error {
         #[snafu(display("Error reading config: {}", source))]
         InfraConfig { source: pubsys_config::Error },
 
+        #[snafu(display("Failed to list uploaded files: {}", source))]
+        LibraryFiles { source: crate::vmware::govc::Error },
+
         #[snafu(display("Infra.toml is missing {}", missing))]
         MissingConfig { missing: String },
 
+        #[snafu(display("{}", source))]
+        ReadOva { source: crate::vmware::ova::Error },
+
         #[snafu(display("Error rendering template: {}", source))]
         RenderTemplate { source: tinytemplate::error::Error },
 
         #[snafu(display("Failed to create temporary file: {}", source))]
         TempFile { source: io::Error },
 
+        #[snafu(display("Failed to create thread pool: {}", source))]
+        ThreadPool { source: rayon::ThreadPoolBuildError },
+
         #[snafu(display("Error reading config: {}", source))]
         VmwareConfig {
             source: pubsys_config::vmware::Error,
@@ -234,7 +426,10 @@ mod error {
 
         #[snafu(display("Failed to upload OVA: {}", source))]
         UploadOva { source: crate::vmware::govc::Error },
+
+        #[snafu(display("Failed to verify upload: {}", source))]
+        Verify { source: crate::vmware::ova::Error },
     }
 }
-pub(crate) use error::Error;
+pub use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;